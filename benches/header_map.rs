@@ -0,0 +1,79 @@
+//! Compares building and querying a [`HeaderMap`] for a typical request against a map keyed by
+//! lowercased names. Run with `cargo bench --bench header_map`.
+
+use std::collections::HashMap;
+use std::hint::black_box;
+use std::time::Instant;
+use web_server_tokio::header::HeaderMap;
+
+const ITERATIONS: u32 = 200_000;
+
+/// The headers of a typical browser request.
+const HEADERS: [(&str, &str); 10] = [
+    ("Host", "example.com"),
+    (
+        "User-Agent",
+        "Mozilla/5.0 (X11; Linux x86_64; rv:120.0) Gecko/20100101 Firefox/120.0",
+    ),
+    (
+        "Accept",
+        "text/html,application/xhtml+xml,application/xml;q=0.9,*/*;q=0.8",
+    ),
+    ("Accept-Language", "en-US,en;q=0.5"),
+    ("Accept-Encoding", "gzip, deflate, br"),
+    ("Connection", "keep-alive"),
+    ("Cookie", "session=abc; theme=dark"),
+    ("Upgrade-Insecure-Requests", "1"),
+    ("Sec-Fetch-Dest", "document"),
+    ("Sec-Fetch-Mode", "navigate"),
+];
+
+/// The lookups a request typically goes through while being handled.
+const LOOKUPS: [&str; 5] = [
+    "Content-Length",
+    "Transfer-Encoding",
+    "connection",
+    "Host",
+    "Cookie",
+];
+
+fn header_map() {
+    let mut headers = HeaderMap::new();
+    for (name, value) in HEADERS {
+        headers.append(name, value);
+    }
+    for name in LOOKUPS {
+        black_box(headers.get(name));
+    }
+}
+
+fn hash_map() {
+    let mut headers: HashMap<String, Vec<String>> = HashMap::new();
+    for (name, value) in HEADERS {
+        headers
+            .entry(name.to_ascii_lowercase())
+            .or_default()
+            .push(value.to_string());
+    }
+    for name in LOOKUPS {
+        black_box(
+            headers
+                .get(&name.to_ascii_lowercase())
+                .map(|values| &values[0]),
+        );
+    }
+}
+
+fn measure(name: &str, run: fn()) {
+    let start = Instant::now();
+    for _ in 0..ITERATIONS {
+        run();
+    }
+    let elapsed = start.elapsed() / ITERATIONS;
+    println!("{:<10} {:>6} ns/request", name, elapsed.as_nanos());
+}
+
+fn main() {
+    measure("HeaderMap", header_map);
+    measure("HashMap", hash_map);
+}
@@ -0,0 +1,75 @@
+//! Compares the static page throughput of [`Server::serve_per_core`] on epoll against
+//! [`Server::serve_uring`] on io_uring, with keep-alive clients fetching `hello.html`. Run with
+//! `cargo bench --bench uring --features io-uring`.
+
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::TcpStream;
+use tokio::task::JoinSet;
+use web_server_tokio::config::Config;
+use web_server_tokio::server::{PerCoreSockets, Server};
+
+const THREADS: usize = 2;
+const CLIENTS: usize = 32;
+const REQUESTS: usize = 2_000;
+
+/// Sends requests over one connection and reads every response.
+async fn client(address: SocketAddr) {
+    let mut stream = BufReader::new(TcpStream::connect(address).await.unwrap());
+    let mut line = String::new();
+    for _ in 0..REQUESTS {
+        stream
+            .get_mut()
+            .write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n")
+            .await
+            .unwrap();
+        let mut length = 0;
+        loop {
+            line.clear();
+            stream.read_line(&mut line).await.unwrap();
+            if line == "\r\n" {
+                break;
+            }
+            if let Some(value) = line.strip_prefix("Content-Length: ") {
+                length = value.trim().parse().unwrap();
+            }
+        }
+        let mut body = vec![0; length];
+        stream.read_exact(&mut body).await.unwrap();
+    }
+}
+
+async fn measure(name: &str, serve: fn(&Server, PerCoreSockets) -> std::io::Result<()>) {
+    let sockets = PerCoreSockets::bind(SocketAddr::from(([127, 0, 0, 1], 0)), THREADS).unwrap();
+    let address = sockets.local_addr().unwrap();
+    let server = Server::new(Config::default());
+    let serving = std::thread::spawn({
+        let server = server.clone();
+        move || serve(&server, sockets)
+    });
+    while TcpStream::connect(address).await.is_err() {
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    let start = Instant::now();
+    let mut clients = JoinSet::new();
+    for _ in 0..CLIENTS {
+        clients.spawn(client(address));
+    }
+    while clients.join_next().await.is_some() {}
+    let elapsed = start.elapsed();
+    server.shutdown();
+    serving.join().unwrap().unwrap();
+    let requests = (CLIENTS * REQUESTS) as f64;
+    println!(
+        "{:<8} {:>8.0} requests/s",
+        name,
+        requests / elapsed.as_secs_f64()
+    );
+}
+
+#[tokio::main]
+async fn main() {
+    measure("epoll", Server::serve_per_core).await;
+    measure("io_uring", Server::serve_uring).await;
+}
@@ -0,0 +1,659 @@
+use crate::log;
+use crate::method::Method;
+use crate::request::Request;
+use crate::response::Response;
+use crate::status::StatusCode;
+use crate::tls::Tls;
+use instant_acme::{
+    Account, AccountCredentials, AuthorizationStatus, ChallengeType, Identifier, LetsEncrypt,
+    NewAccount, NewOrder, Order, OrderStatus, RetryPolicy,
+};
+use rcgen::{CertificateParams, CustomExtension, KeyPair};
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use rustls::sign::CertifiedKey;
+use std::collections::HashMap;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::{fs, io, task, time};
+
+/// The path prefix ACME servers fetch HTTP-01 challenge responses from.
+const CHALLENGE_PATH: &str = "/.well-known/acme-challenge/";
+
+/// The time between two checks whether the certificate is due for renewal.
+const CHECK_INTERVAL: Duration = Duration::from_secs(12 * 60 * 60);
+
+/// The time before a failed renewal is tried again.
+const RETRY_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// How an ACME server checks that the server controls its domains.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Challenge {
+    /// Fetches a token below `/.well-known/acme-challenge/` over plain HTTP on port 80.
+    #[default]
+    Http01,
+    /// Performs a TLS handshake offering the `acme-tls/1` protocol on port 443.
+    TlsAlpn01,
+}
+
+impl Challenge {
+    /// The name ACME uses, e.g. `http-01`.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Challenge::Http01 => "http-01",
+            Challenge::TlsAlpn01 => "tls-alpn-01",
+        }
+    }
+}
+
+impl fmt::Display for Challenge {
+    fn fmt(&self, formatter: &mut fmt::Formatter<'_>) -> fmt::Result {
+        formatter.write_str(self.as_str())
+    }
+}
+
+impl FromStr for Challenge {
+    type Err = ();
+
+    /// Parses a challenge name, ignoring ASCII case.
+    fn from_str(name: &str) -> Result<Challenge, ()> {
+        [Challenge::Http01, Challenge::TlsAlpn01]
+            .into_iter()
+            .find(|challenge| challenge.as_str().eq_ignore_ascii_case(name.trim()))
+            .ok_or(())
+    }
+}
+
+/// Obtains certificates for the domains of the server from an ACME certificate authority such
+/// as Let's Encrypt, and renews them before they expire.
+///
+/// The account, certificate chain, and private key are stored in a directory, so restarts reuse
+/// them. Until the first certificate is issued, the directory holds an expired self-signed
+/// placeholder that lets the HTTPS listener start. Challenges are answered by the server's own
+/// listeners: HTTP-01 by [`Acme::respond`] on every listener, TLS-ALPN-01 by the certificate
+/// resolver of [`Tls`]. ACME servers connect to ports 80 and 443, so the listeners must be
+/// reachable there.
+#[derive(Clone)]
+pub struct Acme {
+    domains: Vec<String>,
+    directory: PathBuf,
+    contacts: Vec<String>,
+    directory_url: String,
+    challenge: Challenge,
+    renew_before: Duration,
+    /// The key authorizations of pending HTTP-01 challenges by token.
+    tokens: Arc<Mutex<HashMap<String, String>>>,
+}
+
+impl fmt::Debug for Acme {
+    fn fmt(&self, formatter: &mut fmt::Formatter<'_>) -> fmt::Result {
+        formatter
+            .debug_struct("Acme")
+            .field("domains", &self.domains)
+            .field("directory", &self.directory)
+            .field("contacts", &self.contacts)
+            .field("directory_url", &self.directory_url)
+            .field("challenge", &self.challenge)
+            .field("renew_before", &self.renew_before)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Acme {
+    /// Prepares certificates from Let's Encrypt for some domains, validated with HTTP-01 and
+    /// renewed 30 days before they expire, and writes the placeholder certificate unless a
+    /// certificate is stored already.
+    ///
+    /// # Arguments
+    ///
+    /// * `domains`: The domains the certificate is valid for, e.g. `example.com`.
+    /// * `directory`: The directory storing the account and certificate.
+    ///
+    /// # Returns
+    ///
+    /// The settings, whose files can be handed to [`Tls::new`].
+    ///
+    /// # Errors
+    ///
+    /// Returns [`io::ErrorKind::InvalidInput`] without domains or when a domain is not a valid
+    /// name, and captures IO errors from creating the directory and writing the placeholder.
+    pub fn new(domains: Vec<String>, directory: impl Into<PathBuf>) -> io::Result<Acme> {
+        if domains.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "ACME needs at least one domain",
+            ));
+        }
+        let acme = Acme {
+            domains,
+            directory: directory.into(),
+            contacts: Vec::new(),
+            directory_url: LetsEncrypt::Production.url().to_string(),
+            challenge: Challenge::default(),
+            renew_before: Duration::from_secs(30 * 24 * 60 * 60),
+            tokens: Arc::default(),
+        };
+        std::fs::create_dir_all(&acme.directory)?;
+        if !acme.certificate_path().exists() || !acme.key_path().exists() {
+            acme.write_placeholder()?;
+        }
+        Ok(acme)
+    }
+
+    /// Sets the contacts the certificate authority may notify, e.g. about expiring certificates.
+    ///
+    /// # Arguments
+    ///
+    /// * `contacts`: URLs such as `mailto:admin@example.com`.
+    ///
+    /// # Returns
+    ///
+    /// The settings with the contacts.
+    pub fn with_contacts(mut self, contacts: Vec<String>) -> Acme {
+        self.contacts = contacts;
+        self
+    }
+
+    /// Sets the certificate authority, e.g. the Let's Encrypt staging environment for testing.
+    ///
+    /// # Arguments
+    ///
+    /// * `url`: The URL of the ACME directory.
+    ///
+    /// # Returns
+    ///
+    /// The settings with the certificate authority.
+    pub fn with_directory_url(mut self, url: &str) -> Acme {
+        self.directory_url = url.to_string();
+        self
+    }
+
+    /// Sets how the certificate authority validates the domains.
+    ///
+    /// # Arguments
+    ///
+    /// * `challenge`: The challenge type.
+    ///
+    /// # Returns
+    ///
+    /// The settings with the challenge type.
+    pub fn with_challenge(mut self, challenge: Challenge) -> Acme {
+        self.challenge = challenge;
+        self
+    }
+
+    /// Sets how long before its expiry the certificate is renewed.
+    ///
+    /// # Arguments
+    ///
+    /// * `renew_before`: The time left when renewal starts.
+    ///
+    /// # Returns
+    ///
+    /// The settings with the renewal time.
+    pub fn with_renew_before(mut self, renew_before: Duration) -> Acme {
+        self.renew_before = renew_before;
+        self
+    }
+
+    /// The PEM file with the certificate chain.
+    pub fn certificate_path(&self) -> PathBuf {
+        self.directory.join("cert.pem")
+    }
+
+    /// The PEM file with the private key of the certificate.
+    pub fn key_path(&self) -> PathBuf {
+        self.directory.join("key.pem")
+    }
+
+    /// Answers an ACME server fetching the response to a pending HTTP-01 challenge.
+    ///
+    /// # Arguments
+    ///
+    /// * `request`: The incoming request.
+    ///
+    /// # Returns
+    ///
+    /// The key authorization of the requested token, 404 Not Found for unknown tokens, or
+    /// `None` when the request is not for a challenge.
+    pub fn respond(&self, request: &Request) -> Option<Response> {
+        let token = request.path().strip_prefix(CHALLENGE_PATH)?;
+        if request.method != Method::Get && request.method != Method::Head {
+            return None;
+        }
+        let response = match self.lock_tokens().get(token) {
+            Some(key_authorization) => Response::new(StatusCode::OK, key_authorization.clone())
+                .with_header("Content-Type", "application/octet-stream"),
+            None => Response::new(StatusCode::NOT_FOUND, ""),
+        };
+        Some(response)
+    }
+
+    fn lock_tokens(&self) -> std::sync::MutexGuard<'_, HashMap<String, String>> {
+        self.tokens
+            .lock()
+            .unwrap_or_else(|error| error.into_inner())
+    }
+
+    /// Checks whether the stored certificate expires within the renewal time.
+    ///
+    /// # Arguments
+    ///
+    /// * `now`: The current time.
+    ///
+    /// # Returns
+    ///
+    /// `true` when the certificate should be renewed, which includes the placeholder.
+    ///
+    /// # Errors
+    ///
+    /// Captures IO errors from reading the certificate, and returns
+    /// [`io::ErrorKind::InvalidData`] when it is not a PEM certificate.
+    pub fn renewal_due(&self, now: SystemTime) -> io::Result<bool> {
+        let pem = std::fs::read(self.certificate_path())?;
+        Ok(expiry(&pem)? <= now + self.renew_before)
+    }
+
+    /// Orders a certificate for the domains, answers the challenges, and stores the issued
+    /// certificate before presenting it.
+    ///
+    /// # Arguments
+    ///
+    /// * `tls`: The TLS settings presenting the stored certificate.
+    ///
+    /// # Returns
+    ///
+    /// `Ok(())` once the new certificate is in use.
+    ///
+    /// # Errors
+    ///
+    /// Captures errors talking to the certificate authority, failed validations, and IO errors
+    /// from storing the account and certificate.
+    pub async fn renew(&self, tls: &Tls) -> io::Result<()> {
+        let account = self.account().await?;
+        let identifiers = self
+            .domains
+            .iter()
+            .cloned()
+            .map(Identifier::Dns)
+            .collect::<Vec<_>>();
+        let mut order = account
+            .new_order(&NewOrder::new(&identifiers))
+            .await
+            .map_err(io::Error::other)?;
+        let issued = self.complete(&mut order, tls).await;
+        self.lock_tokens().clear();
+        for domain in &self.domains {
+            tls.set_challenge_certificate(domain, None);
+        }
+        let (chain, key) = issued?;
+        write_private(&self.key_path(), key.as_bytes()).await?;
+        write_private(&self.certificate_path(), chain.as_bytes()).await?;
+        tls.reload()
+    }
+
+    /// Answers the challenges of an order and waits for the certificate.
+    ///
+    /// # Arguments
+    ///
+    /// * `order`: The new order.
+    /// * `tls`: The TLS settings answering TLS-ALPN-01 challenges.
+    ///
+    /// # Returns
+    ///
+    /// The PEM certificate chain and private key.
+    ///
+    /// # Errors
+    ///
+    /// The errors of [`Acme::renew`].
+    async fn complete(&self, order: &mut Order, tls: &Tls) -> io::Result<(String, String)> {
+        let kind = match self.challenge {
+            Challenge::Http01 => ChallengeType::Http01,
+            Challenge::TlsAlpn01 => ChallengeType::TlsAlpn01,
+        };
+        let mut authorizations = order.authorizations();
+        while let Some(authorization) = authorizations.next().await {
+            let mut authorization = authorization.map_err(io::Error::other)?;
+            if authorization.status == AuthorizationStatus::Valid {
+                continue;
+            }
+            let mut challenge = authorization.challenge(kind.clone()).ok_or_else(|| {
+                io::Error::other(format!("no {} challenge offered", self.challenge))
+            })?;
+            let key_authorization = challenge.key_authorization();
+            match self.challenge {
+                Challenge::Http01 => {
+                    self.lock_tokens().insert(
+                        challenge.token.clone(),
+                        key_authorization.as_str().to_string(),
+                    );
+                }
+                Challenge::TlsAlpn01 => {
+                    let domain = challenge.identifier().to_string();
+                    let certificate =
+                        challenge_certificate(&domain, key_authorization.digest().as_ref())?;
+                    tls.set_challenge_certificate(&domain, Some(Arc::new(certificate)));
+                }
+            }
+            challenge.set_ready().await.map_err(io::Error::other)?;
+        }
+        let status = order
+            .poll_ready(&RetryPolicy::default())
+            .await
+            .map_err(io::Error::other)?;
+        if status != OrderStatus::Ready {
+            return Err(io::Error::other(format!(
+                "the order is {:?} instead of ready",
+                status
+            )));
+        }
+        let key = order.finalize().await.map_err(io::Error::other)?;
+        let chain = order
+            .poll_certificate(&RetryPolicy::default())
+            .await
+            .map_err(io::Error::other)?;
+        Ok((chain, key))
+    }
+
+    /// Restores the stored ACME account, or registers a new one and stores it.
+    ///
+    /// # Returns
+    ///
+    /// The account.
+    ///
+    /// # Errors
+    ///
+    /// The errors of [`Acme::renew`], and [`io::ErrorKind::InvalidData`] when the stored account
+    /// cannot be read.
+    async fn account(&self) -> io::Result<Account> {
+        let path = self.directory.join("account.json");
+        let builder = Account::builder().map_err(io::Error::other)?;
+        match fs::read(&path).await {
+            Ok(json) => {
+                let credentials = serde_json::from_slice::<AccountCredentials>(&json)
+                    .map_err(|error| io::Error::new(io::ErrorKind::InvalidData, error))?;
+                builder
+                    .from_credentials(credentials)
+                    .await
+                    .map_err(io::Error::other)
+            }
+            Err(error) if error.kind() == io::ErrorKind::NotFound => {
+                let contacts = self.contacts.iter().map(String::as_str).collect::<Vec<_>>();
+                let new_account = NewAccount {
+                    contact: &contacts,
+                    terms_of_service_agreed: true,
+                    only_return_existing: false,
+                };
+                let (account, credentials) = builder
+                    .create(&new_account, self.directory_url.clone(), None)
+                    .await
+                    .map_err(io::Error::other)?;
+                let json = serde_json::to_vec(&credentials).map_err(io::Error::other)?;
+                write_private(&path, &json).await?;
+                log::info(format_args!(
+                    "registered an ACME account at {}",
+                    account.id()
+                ));
+                Ok(account)
+            }
+            Err(error) => Err(error),
+        }
+    }
+
+    /// Writes a self-signed certificate for the domains that expired long ago, so HTTPS can
+    /// start before the first certificate is issued and the first check renews it.
+    fn write_placeholder(&self) -> io::Result<()> {
+        let invalid = |error: rcgen::Error| io::Error::new(io::ErrorKind::InvalidInput, error);
+        let mut params = CertificateParams::new(self.domains.clone()).map_err(invalid)?;
+        params.not_after = rcgen::date_time_ymd(1975, 1, 2);
+        let key_pair = KeyPair::generate().map_err(invalid)?;
+        let certificate = params.self_signed(&key_pair).map_err(invalid)?;
+        std::fs::write(self.key_path(), key_pair.serialize_pem())?;
+        std::fs::write(self.certificate_path(), certificate.pem())
+    }
+
+    /// Checks the certificate every 12 hours on a background task and renews it when due,
+    /// retrying failures after an hour. The first check happens right away.
+    ///
+    /// # Arguments
+    ///
+    /// * `tls`: The TLS settings presenting the stored certificate.
+    ///
+    /// # Returns
+    ///
+    /// The task.
+    pub fn spawn_renewal(&self, tls: Tls) -> task::JoinHandle<()> {
+        let acme = self.clone();
+        tokio::spawn(async move {
+            loop {
+                let wait = match acme.renewal_due(SystemTime::now()) {
+                    Ok(false) => CHECK_INTERVAL,
+                    Ok(true) => match acme.renew(&tls).await {
+                        Ok(()) => {
+                            log::info(format_args!(
+                                "renewed the certificate for {}",
+                                acme.domains.join(", ")
+                            ));
+                            CHECK_INTERVAL
+                        }
+                        Err(error) => {
+                            log::error(format_args!(
+                                "renewing the certificate for {} failed: {}",
+                                acme.domains.join(", "),
+                                error
+                            ));
+                            RETRY_INTERVAL
+                        }
+                    },
+                    Err(error) => {
+                        log::error(format_args!(
+                            "reading {} failed: {}",
+                            acme.certificate_path().display(),
+                            error
+                        ));
+                        RETRY_INTERVAL
+                    }
+                };
+                time::sleep(wait).await;
+            }
+        })
+    }
+}
+
+/// Reads when the leaf of a PEM certificate chain expires.
+///
+/// # Arguments
+///
+/// * `pem`: The certificate chain.
+///
+/// # Returns
+///
+/// The end of the validity period.
+///
+/// # Errors
+///
+/// Returns [`io::ErrorKind::InvalidData`] when the leaf is not a PEM certificate.
+fn expiry(pem: &[u8]) -> io::Result<SystemTime> {
+    let invalid = |error: &dyn fmt::Display| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("invalid certificate: {}", error),
+        )
+    };
+    let (_, pem) = x509_parser::pem::parse_x509_pem(pem).map_err(|error| invalid(&error))?;
+    let certificate = pem.parse_x509().map_err(|error| invalid(&error))?;
+    let seconds = certificate.validity().not_after.timestamp();
+    Ok(match u64::try_from(seconds) {
+        Ok(seconds) => UNIX_EPOCH + Duration::from_secs(seconds),
+        Err(_) => UNIX_EPOCH,
+    })
+}
+
+/// Builds the self-signed certificate answering a TLS-ALPN-01 challenge, see RFC 8737.
+///
+/// # Arguments
+///
+/// * `domain`: The domain being validated.
+/// * `digest`: The SHA-256 digest of the key authorization.
+///
+/// # Returns
+///
+/// The certificate and its key.
+///
+/// # Errors
+///
+/// Returns [`io::ErrorKind::InvalidInput`] when the domain is not a valid name.
+fn challenge_certificate(domain: &str, digest: &[u8]) -> io::Result<CertifiedKey> {
+    let invalid =
+        |error: &dyn fmt::Display| io::Error::new(io::ErrorKind::InvalidInput, error.to_string());
+    let mut params =
+        CertificateParams::new(vec![domain.to_string()]).map_err(|error| invalid(&error))?;
+    params.custom_extensions = vec![CustomExtension::new_acme_identifier(digest)];
+    let key_pair = KeyPair::generate().map_err(|error| invalid(&error))?;
+    let certificate = params
+        .self_signed(&key_pair)
+        .map_err(|error| invalid(&error))?;
+    CertifiedKey::from_der(
+        vec![CertificateDer::from(certificate.der().to_vec())],
+        PrivateKeyDer::try_from(key_pair.serialize_der()).map_err(|error| invalid(&error))?,
+        &rustls::crypto::ring::default_provider(),
+    )
+    .map_err(|error| invalid(&error))
+}
+
+/// Replaces a file readable only by its owner in one step, so readers never see half of it.
+///
+/// # Arguments
+///
+/// * `path`: The file.
+/// * `contents`: The new contents.
+///
+/// # Errors
+///
+/// Captures IO errors from writing and renaming.
+async fn write_private(path: &Path, contents: &[u8]) -> io::Result<()> {
+    let temporary = path.with_extension("tmp");
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    options.mode(0o600);
+    let mut file = options.open(&temporary).await?;
+    io::AsyncWriteExt::write_all(&mut file, contents).await?;
+    file.sync_all().await?;
+    fs::rename(&temporary, path).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn acme(directory: &Path) -> Acme {
+        Acme::new(vec!["localhost".to_string()], directory).unwrap()
+    }
+
+    fn request(method: Method, path: &str) -> Request {
+        Request {
+            method,
+            target: path.parse().unwrap(),
+            ..Request::default()
+        }
+    }
+
+    /// It starts from an expired placeholder that HTTPS can present and renews it right away
+    #[test]
+    fn placeholder() {
+        let directory = tempfile::tempdir().unwrap();
+        let acme = acme(directory.path());
+        Tls::new(acme.certificate_path(), acme.key_path()).unwrap();
+        assert!(acme.renewal_due(SystemTime::now()).unwrap());
+    }
+
+    /// It renews certificates once they expire within the renewal time
+    #[test]
+    fn renewal_due() {
+        let directory = tempfile::tempdir().unwrap();
+        let acme = acme(directory.path());
+        let issued = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+        std::fs::write(acme.certificate_path(), issued.cert.pem()).unwrap();
+        let expires = expiry(issued.cert.pem().as_bytes()).unwrap();
+        assert!(!acme.renewal_due(SystemTime::now()).unwrap());
+        assert!(!acme
+            .renewal_due(expires - Duration::from_secs(31 * 24 * 60 * 60))
+            .unwrap());
+        assert!(acme
+            .renewal_due(expires - Duration::from_secs(29 * 24 * 60 * 60))
+            .unwrap());
+
+        std::fs::write(acme.certificate_path(), "not a certificate").unwrap();
+        let error = acme.renewal_due(SystemTime::now()).unwrap_err();
+        assert_eq!(io::ErrorKind::InvalidData, error.kind());
+    }
+
+    /// It answers pending HTTP-01 challenges with their key authorization
+    #[test]
+    fn http_challenge() {
+        let directory = tempfile::tempdir().unwrap();
+        let acme = acme(directory.path());
+        acme.lock_tokens()
+            .insert("token".to_string(), "token.thumbprint".to_string());
+        for method in [Method::Get, Method::Head] {
+            let response = acme
+                .respond(&request(method, "/.well-known/acme-challenge/token"))
+                .unwrap();
+            assert_eq!(StatusCode::OK, response.status);
+            assert_eq!(&b"token.thumbprint"[..], &response.body[..]);
+        }
+    }
+
+    /// It answers 404 for tokens of no pending challenge
+    #[test]
+    fn challenge_miss() {
+        let directory = tempfile::tempdir().unwrap();
+        let acme = acme(directory.path());
+        let path = "/.well-known/acme-challenge/token";
+        let response = acme.respond(&request(Method::Get, path)).unwrap();
+        assert_eq!(StatusCode::NOT_FOUND, response.status);
+        acme.lock_tokens()
+            .insert("token".to_string(), "token.thumbprint".to_string());
+        let response = acme
+            .respond(&request(Method::Get, "/.well-known/acme-challenge/other"))
+            .unwrap();
+        assert_eq!(StatusCode::NOT_FOUND, response.status);
+    }
+
+    /// It leaves other paths and methods to the other handlers
+    #[test]
+    fn other_requests() {
+        let directory = tempfile::tempdir().unwrap();
+        let acme = acme(directory.path());
+        acme.lock_tokens()
+            .insert("token".to_string(), "token.thumbprint".to_string());
+        assert!(acme.respond(&request(Method::Get, "/token")).is_none());
+        assert!(acme
+            .respond(&request(Method::Post, "/.well-known/acme-challenge/token"))
+            .is_none());
+    }
+
+    /// It keeps the current certificate when the certificate authority cannot be reached
+    #[tokio::test]
+    async fn failed_renewal() {
+        let directory = tempfile::tempdir().unwrap();
+        let acme = acme(directory.path()).with_directory_url("http://127.0.0.1:9/directory");
+        let tls = Tls::new(acme.certificate_path(), acme.key_path()).unwrap();
+        let placeholder = std::fs::read(acme.certificate_path()).unwrap();
+        assert!(acme.renew(&tls).await.is_err());
+        assert_eq!(placeholder, std::fs::read(acme.certificate_path()).unwrap());
+        assert!(acme.lock_tokens().is_empty());
+        assert!(!directory.path().join("account.json").exists());
+    }
+
+    /// It parses challenge names whatever their case
+    #[test]
+    fn challenge_names() {
+        assert_eq!(Ok(Challenge::Http01), "http-01".parse());
+        assert_eq!(Ok(Challenge::TlsAlpn01), " TLS-ALPN-01".parse());
+        assert_eq!(Err(()), "dns-01".parse::<Challenge>());
+    }
+}
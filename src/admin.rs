@@ -0,0 +1,958 @@
+use crate::body::BodyReader;
+use crate::config::Config;
+use crate::connection::{BufferPool, Connection};
+use crate::log::{self, Level};
+use crate::method::Method;
+use crate::response::Response;
+use crate::router::Router;
+use crate::server::Server;
+use crate::status::StatusCode;
+use crate::StreamAdapter;
+use std::fmt::Write;
+use tokio::{io, net};
+
+/// The largest request body the admin API reads.
+const MAX_BODY_SIZE: u64 = 64 * 1024;
+
+/// Builds the admin endpoints for a server.
+///
+/// * `GET /config`: The active configuration, with secrets redacted.
+/// * `GET /routes`: The routes of the application and the built-in handlers, or with
+///   `?path=<path>&method=<method>`, `GET` by default, which of them answers such a request.
+/// * `GET /stats`: Connection and request counters, and those of the concurrency limit and
+///   load shedding.
+/// * `GET /log-level` and `PUT /log-level`: Reads or changes the level, e.g. `debug`.
+/// * `POST /reload`: Reloads the configuration, answering 400 with the reason when the new one is
+///   invalid and stays inactive.
+/// * `POST /tls/reload`: Reads the TLS certificate and key files again, answering 400 with the
+///   reason when they are invalid and the current certificate stays in use.
+/// * `POST /drain`: Stops accepting connections and lets open ones finish.
+/// * `POST /shutdown`: Stops accepting connections and closes open ones.
+/// * `PUT /upstreams/:address/weight`: Sets the weight of an upstream of the proxy, a
+///   non-negative integer, until the configuration is reloaded, see
+///   [`crate::proxy::Proxy::set_weight`].
+/// * `GET /captures` and `DELETE /captures`: Lists the captured exchanges, from the oldest, or
+///   drops them, see [`crate::capture`].
+/// * `GET /maintenance`, `PUT /maintenance`, and `DELETE /maintenance`: Reads whether the server
+///   is in maintenance, turns it `on` or `off` whatever the configuration says, or follows the
+///   configuration again, see [`crate::maintenance`].
+/// * `GET /api-keys`: Lists the API keys with their usage, see
+///   [`crate::config::Config::api_keys`].
+/// * `POST /api-keys`: Issues an API key from `<name> [<requests per minute>]`, answering with
+///   its ID and secret, which is not shown again.
+/// * `DELETE /api-keys/:id`: Revokes an API key.
+/// * `GET /profile?seconds=<n>`: Samples the CPU usage of the process for `n` seconds, 10 by
+///   default, and answers with a flamegraph as SVG, or 204 when the process was idle. Needs the
+///   `profiling` feature on Unix and an admin token, see
+///   [`crate::config::AdminConfig::token`].
+///
+/// # Arguments
+///
+/// * `server`: The server the endpoints inspect and control.
+///
+/// # Returns
+///
+/// A router answering the admin endpoints.
+pub fn router(server: &Server) -> Router {
+    let config = server.clone();
+    let routes = server.clone();
+    let stats = server.clone();
+    let captures = server.clone();
+    let clear = server.clone();
+    let maintenance = server.clone();
+    let switch = server.clone();
+    let unswitch = server.clone();
+    let reload = server.clone();
+    let drain = server.clone();
+    let shutdown = server.clone();
+    let weights = server.clone();
+    #[cfg(feature = "tls")]
+    let tls = server.clone();
+    #[cfg(all(feature = "profiling", unix))]
+    let profile = server.clone();
+    #[cfg(feature = "auth")]
+    let (keys, issue, revoke) = (server.clone(), server.clone(), server.clone());
+    let router = Router::new()
+        .route(Method::Get, "/config", move |_| {
+            let body = format!("{:#?}\n", config.config());
+            async move { Ok(text(body)) }
+        })
+        .route(
+            Method::Get,
+            "/routes",
+            move |request: crate::request::Request| {
+                let config = routes.config();
+                let method = request.target.query_param("method");
+                let response = match request.target.query_param("path") {
+                    None => text(describe_routes(&config)),
+                    Some(path) => match method.as_deref().unwrap_or("GET").parse() {
+                        Ok(method) => text(explain_route(&config, method, &path)),
+                        Err(_) => Response::new(StatusCode::BAD_REQUEST, "invalid method\n"),
+                    },
+                };
+                async move { Ok(response) }
+            },
+        )
+        .route(Method::Get, "/stats", move |_| {
+            let body = describe_stats(&stats);
+            async move { Ok(text(body)) }
+        })
+        .route(Method::Get, "/captures", move |_| {
+            let body = captures
+                .config()
+                .captures
+                .entries()
+                .iter()
+                .map(|captured| format!("{}\n", captured))
+                .collect::<String>();
+            async move { Ok(text(body)) }
+        })
+        .route(Method::Delete, "/captures", move |_| {
+            clear.config().captures.clear();
+            async { Ok(Response::new(StatusCode::NO_CONTENT, "")) }
+        })
+        .route(Method::Get, "/maintenance", move |_| {
+            let config = maintenance.config();
+            let active = config.maintenance_switch.is_active(&config.maintenance);
+            let source = match config.maintenance_switch.get() {
+                Some(_) => "admin",
+                None => "configuration",
+            };
+            let body = format!("{} ({})\n", if active { "on" } else { "off" }, source);
+            async move { Ok(text(body)) }
+        })
+        .route(
+            Method::Put,
+            "/maintenance",
+            move |request: crate::request::Request| {
+                let active = match request.body.trim_ascii() {
+                    b"on" => Some(true),
+                    b"off" => Some(false),
+                    _ => None,
+                };
+                let response = match active {
+                    Some(active) => {
+                        switch.config().maintenance_switch.set(Some(active));
+                        log::info(format_args!(
+                            "maintenance turned {}",
+                            if active { "on" } else { "off" }
+                        ));
+                        Response::new(StatusCode::NO_CONTENT, "")
+                    }
+                    None => Response::new(StatusCode::BAD_REQUEST, "expected on or off\n"),
+                };
+                async move { Ok(response) }
+            },
+        )
+        .route(Method::Delete, "/maintenance", move |_| {
+            unswitch.config().maintenance_switch.set(None);
+            log::info("maintenance follows the configuration again");
+            async { Ok(Response::new(StatusCode::NO_CONTENT, "")) }
+        })
+        .route(Method::Get, "/log-level", |_| async {
+            Ok(text(format!("{}\n", log::level())))
+        })
+        .route(
+            Method::Put,
+            "/log-level",
+            |request: crate::request::Request| async move {
+                let level = std::str::from_utf8(&request.body)
+                    .ok()
+                    .and_then(|body| body.parse::<Level>().ok());
+                Ok(match level {
+                    Some(level) => {
+                        log::set_level(level);
+                        log::info(format_args!("log level changed to {}", level));
+                        Response::new(StatusCode::NO_CONTENT, "")
+                    }
+                    None => Response::new(
+                        StatusCode::BAD_REQUEST,
+                        "expected one of error, warn, info, debug\n",
+                    ),
+                })
+            },
+        )
+        .route(Method::Post, "/reload", move |_| {
+            let response = match reload.reload() {
+                Ok(()) => Response::new(StatusCode::NO_CONTENT, ""),
+                Err(error) => Response::new(StatusCode::BAD_REQUEST, format!("{}\n", error)),
+            };
+            async { Ok(response) }
+        })
+        .route(Method::Post, "/drain", move |_| {
+            log::info("draining on request of the admin API");
+            drain.drain();
+            async { Ok(Response::new(StatusCode::ACCEPTED, "")) }
+        })
+        .route(Method::Post, "/shutdown", move |_| {
+            log::info("shutting down on request of the admin API");
+            shutdown.shutdown();
+            async { Ok(Response::new(StatusCode::ACCEPTED, "")) }
+        })
+        .route(
+            Method::Put,
+            "/upstreams/:address/weight",
+            move |request: crate::request::Request| {
+                let address: String =
+                    crate::extract::param(&request.params, "address").unwrap_or_default();
+                let weight = std::str::from_utf8(&request.body)
+                    .ok()
+                    .and_then(|body| body.trim().parse::<u32>().ok());
+                let response = match (weights.config().proxy.as_ref(), weight) {
+                    (None, _) => Response::new(StatusCode::NOT_FOUND, "the proxy is not enabled\n"),
+                    (Some(_), None) => {
+                        Response::new(StatusCode::BAD_REQUEST, "expected a non-negative integer\n")
+                    }
+                    (Some(proxy), Some(weight)) if proxy.set_weight(&address, weight) => {
+                        log::info(format_args!(
+                            "set the weight of the upstream {} to {}",
+                            address, weight
+                        ));
+                        Response::new(StatusCode::NO_CONTENT, "")
+                    }
+                    (Some(_), Some(_)) => {
+                        Response::new(StatusCode::NOT_FOUND, "no such upstream\n")
+                    }
+                };
+                async move { Ok(response) }
+            },
+        );
+    #[cfg(feature = "tls")]
+    let router = router.route(Method::Post, "/tls/reload", move |_| {
+        let response = match tls.config().tls.as_ref().map(|tls| tls.reload()) {
+            Some(Ok(())) => {
+                log::info("reloaded the TLS certificate on request of the admin API");
+                Response::new(StatusCode::NO_CONTENT, "")
+            }
+            Some(Err(error)) => Response::new(StatusCode::BAD_REQUEST, format!("{}\n", error)),
+            None => Response::new(StatusCode::NOT_FOUND, "TLS is not enabled\n"),
+        };
+        async { Ok(response) }
+    });
+    #[cfg(feature = "auth")]
+    let router = router
+        .route(Method::Get, "/api-keys", move |_| {
+            let api_keys = keys.config().api_keys.clone();
+            async move {
+                let Some(api_keys) = api_keys else {
+                    return Ok(api_keys_disabled());
+                };
+                let mut body = String::new();
+                for key in api_keys.store().list().await? {
+                    let usage = api_keys.usage(&key.id);
+                    let rate = match key.requests_per_minute {
+                        Some(limit) => format!("{}/min", limit),
+                        None => "unlimited".to_string(),
+                    };
+                    let _ = writeln!(
+                        body,
+                        "{} {} rate={} requests={} limited={} roles={}",
+                        key.id,
+                        key.name,
+                        rate,
+                        usage.requests,
+                        usage.limited,
+                        key.roles.join(",")
+                    );
+                }
+                Ok(text(body))
+            }
+        })
+        .route(
+            Method::Post,
+            "/api-keys",
+            move |request: crate::request::Request| {
+                let api_keys = issue.config().api_keys.clone();
+                async move {
+                    let Some(api_keys) = api_keys else {
+                        return Ok(api_keys_disabled());
+                    };
+                    let body = String::from_utf8_lossy(&request.body).into_owned();
+                    let parsed = match body.split_whitespace().collect::<Vec<_>>()[..] {
+                        [name] => Some((name, None)),
+                        [name, limit] => limit.parse().ok().map(|limit| (name, Some(limit))),
+                        _ => None,
+                    };
+                    let Some((name, limit)) = parsed else {
+                        return Ok(Response::new(
+                            StatusCode::BAD_REQUEST,
+                            "expected <name> [<requests per minute>]\n",
+                        ));
+                    };
+                    Ok(match api_keys.store().create(name, limit).await {
+                        Ok((key, secret)) => {
+                            log::info(format_args!("issued the API key {} to {}", key.id, name));
+                            Response::new(StatusCode::CREATED, format!("{} {}\n", key.id, secret))
+                        }
+                        Err(error) if error.kind() == io::ErrorKind::Unsupported => {
+                            Response::new(StatusCode::METHOD_NOT_ALLOWED, format!("{}\n", error))
+                        }
+                        Err(error) => return Err(error),
+                    })
+                }
+            },
+        )
+        .route(
+            Method::Delete,
+            "/api-keys/:id",
+            move |request: crate::request::Request| {
+                let api_keys = revoke.config().api_keys.clone();
+                async move {
+                    let Some(api_keys) = api_keys else {
+                        return Ok(api_keys_disabled());
+                    };
+                    let id: String =
+                        crate::extract::param(&request.params, "id").unwrap_or_default();
+                    Ok(match api_keys.store().revoke(&id).await {
+                        Ok(true) => {
+                            log::info(format_args!("revoked the API key {}", id));
+                            Response::new(StatusCode::NO_CONTENT, "")
+                        }
+                        Ok(false) => Response::new(StatusCode::NOT_FOUND, "no such API key\n"),
+                        Err(error) if error.kind() == io::ErrorKind::Unsupported => {
+                            Response::new(StatusCode::METHOD_NOT_ALLOWED, format!("{}\n", error))
+                        }
+                        Err(error) => return Err(error),
+                    })
+                }
+            },
+        );
+    #[cfg(all(feature = "profiling", unix))]
+    let router = router.route(
+        Method::Get,
+        "/profile",
+        move |request: crate::request::Request| {
+            let protected = profile
+                .config()
+                .admin
+                .as_ref()
+                .is_some_and(|admin| admin.token.is_some());
+            async move {
+                if !protected {
+                    return Ok(Response::new(
+                        StatusCode::FORBIDDEN,
+                        "profiles need WEB_SERVER_ADMIN_TOKEN\n",
+                    ));
+                }
+                let seconds = match request.target.query_param("seconds") {
+                    Some(seconds) => seconds.parse().ok(),
+                    None => Some(10),
+                };
+                let Some(duration) = seconds
+                    .map(std::time::Duration::from_secs)
+                    .filter(|duration| *duration <= crate::profile::MAX_DURATION)
+                else {
+                    return Ok(Response::new(
+                        StatusCode::BAD_REQUEST,
+                        "expected seconds up to 60\n",
+                    ));
+                };
+                log::info(format_args!("profiling for {:?}", duration));
+                Ok(match crate::profile::flamegraph(duration).await {
+                    Ok(svg) if svg.is_empty() => Response::new(StatusCode::NO_CONTENT, ""),
+                    Ok(svg) => Response::new(StatusCode::OK, svg)
+                        .with_header("Content-Type", "image/svg+xml"),
+                    Err(error) if error.kind() == io::ErrorKind::ResourceBusy => {
+                        Response::new(StatusCode::CONFLICT, format!("{}\n", error))
+                    }
+                    Err(error) => {
+                        log::error(format_args!("profiling failed: {}", error));
+                        Response::new(StatusCode::INTERNAL_SERVER_ERROR, "")
+                    }
+                })
+            }
+        },
+    );
+    router
+}
+
+/// Serves the admin API until the server stops, one request per connection. The API stays
+/// reachable while the server drains. Requests changing state that carry an `Origin` header are
+/// refused with 403, since they come from a web page rather than an operator.
+///
+/// # Arguments
+///
+/// * `listener`: The admin listener, see [`crate::config::AdminConfig::address`].
+/// * `server`: The server the endpoints inspect and control.
+///
+/// # Returns
+///
+/// `Ok(())` once the server reaches [`crate::server::Phase::Stopped`].
+///
+/// # Errors
+///
+/// Returns [`io::ErrorKind::InvalidInput`] without serving when the admin API would be reachable
+/// from other machines without a token, see [`crate::config::AdminConfig::is_exposed`]. Errors of
+/// single admin connections are logged.
+pub async fn serve(listener: net::TcpListener, server: Server) -> io::Result<()> {
+    if server
+        .config()
+        .admin
+        .as_ref()
+        .is_some_and(|admin| admin.is_exposed())
+    {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "the admin API requires a token beyond the loopback interface",
+        ));
+    }
+    let router = router(&server);
+    let pool = BufferPool::new(1);
+    loop {
+        let (stream, peer) = tokio::select! {
+            accepted = listener.accept() => match accepted {
+                Ok(accepted) => accepted,
+                Err(error) => {
+                    log::error(format_args!("accepting an admin connection failed: {}", error));
+                    continue;
+                }
+            },
+            _ = server.stopped() => return Ok(()),
+        };
+        let connection = Connection::new(stream, &pool).with_peer(peer);
+        let router = router.clone();
+        let token = server
+            .config()
+            .admin
+            .as_ref()
+            .and_then(|admin| admin.token.clone());
+        tokio::spawn(async move {
+            if let Err(error) = handle(Box::new(connection), &router, token.as_deref()).await {
+                log::debug(format_args!(
+                    "admin connection from {} ended: {}",
+                    peer, error
+                ));
+            }
+        });
+    }
+}
+
+/// Answers one admin request and closes the connection.
+///
+/// # Arguments
+///
+/// * `stream`: The accepted admin connection.
+/// * `router`: The admin endpoints.
+/// * `token`: The token requests must carry, if any.
+///
+/// # Returns
+///
+/// `Ok(())` once the response is written.
+///
+/// # Errors
+///
+/// Captures IO errors from reading the request and writing the response.
+async fn handle(
+    mut stream: Box<dyn StreamAdapter>,
+    router: &Router,
+    token: Option<&str>,
+) -> io::Result<()> {
+    let mut request = stream.read_request().await?;
+    let response = if let Some(status) = request.head_error() {
+        Response::new(status, "")
+    } else if request.method == Method::default() {
+        Response::new(StatusCode::BAD_REQUEST, "")
+    } else if !authorized(&request, token) {
+        Response::new(StatusCode::UNAUTHORIZED, "").with_header("WWW-Authenticate", "Bearer")
+    } else if cross_site(&request) {
+        Response::new(StatusCode::FORBIDDEN, "")
+    } else if request.headers.contains("Transfer-Encoding") {
+        Response::new(StatusCode::LENGTH_REQUIRED, "")
+    } else if request.content_length() > MAX_BODY_SIZE {
+        Response::new(StatusCode::PAYLOAD_TOO_LARGE, "")
+    } else {
+        request.body = BodyReader::new(stream.as_mut(), request.content_length())
+            .read_all()
+            .await?;
+        match router.dispatch(&request).await? {
+            Some(response) => response,
+            None if router.allowed_methods(request.path()).is_empty() => {
+                Response::new(StatusCode::NOT_FOUND, "")
+            }
+            None => Response::new(StatusCode::METHOD_NOT_ALLOWED, ""),
+        }
+    };
+    let response = response.with_header("Connection", "close");
+    stream.write_response(&response.to_bytes()).await
+}
+
+/// Checks the bearer token of an admin request, comparing in constant time so the response
+/// time does not reveal how much of a guess was right.
+///
+/// # Arguments
+///
+/// * `request`: The admin request.
+/// * `token`: The expected token, `None` to accept every request.
+///
+/// # Returns
+///
+/// True when no token is configured or the request carries it.
+fn authorized(request: &crate::request::Request, token: Option<&str>) -> bool {
+    let Some(token) = token else {
+        return true;
+    };
+    let Some(given) = request
+        .header("Authorization")
+        .and_then(|value| value.strip_prefix("Bearer "))
+    else {
+        return false;
+    };
+    crate::constant_time_eq(given.trim().as_bytes(), token.as_bytes())
+}
+
+/// Checks whether an admin request changing state comes from a web page. Browsers send an
+/// `Origin` header with every such request, so a page cannot make a local browser stop, drain, or
+/// reconfigure a loopback admin API without a token. Command-line clients send none.
+///
+/// # Arguments
+///
+/// * `request`: The admin request.
+///
+/// # Returns
+///
+/// True when the method is not `GET`, `HEAD`, or `OPTIONS` and the request carries an `Origin`.
+fn cross_site(request: &crate::request::Request) -> bool {
+    !matches!(request.method, Method::Get | Method::Head | Method::Options)
+        && request.headers.contains("Origin")
+}
+
+/// Lists the routes of the application and the path prefixes of the built-in handlers.
+///
+/// # Arguments
+///
+/// * `config`: The active configuration.
+///
+/// # Returns
+///
+/// One route per line, e.g. `GET /users app::list_users` or `* /api/ (proxy)`, see
+/// [`crate::router::RouteInfo`].
+pub fn describe_routes(config: &Config) -> String {
+    let mut routes = String::new();
+    for route in config.router.routes() {
+        // Writing into a String cannot fail
+        let _ = writeln!(routes, "{}", route);
+    }
+    let mut builtin = |methods: &str, route: &str, name: &str| {
+        let _ = writeln!(routes, "{} {} ({})", methods, route, name);
+    };
+    if let Some(metrics) = &config.metrics {
+        builtin("GET,HEAD", &metrics.route, "metrics");
+    }
+    if let Some(proxy) = &config.proxy {
+        builtin("*", proxy.route(), "proxy");
+    }
+    if let Some(fastcgi) = &config.fastcgi {
+        builtin("*", fastcgi.route(), "fastcgi");
+    }
+    if let Some(cgi) = &config.cgi {
+        builtin("*", cgi.route(), "cgi");
+    }
+    if let Some(upload) = &config.upload {
+        builtin("PUT,POST", &upload.route, "upload");
+    }
+    if let Some(tus) = &config.tus {
+        builtin("*", &tus.route, "tus");
+    }
+    if config.webdav.is_some() {
+        builtin("WebDAV", "/", "webdav");
+    }
+    routes
+}
+
+/// Explains which handler answers a request, following the order handlers are tried in, to
+/// find out why a request was not answered as expected.
+///
+/// # Arguments
+///
+/// * `config`: The active configuration.
+/// * `method`: The method of the request.
+/// * `path`: The path of the request, without query.
+///
+/// # Returns
+///
+/// A line naming the route of the application or the built-in handler, preceded by a line
+/// listing the registered methods when only the method does not match a route.
+fn explain_route(config: &Config, method: Method, path: &str) -> String {
+    let request = crate::request::Request {
+        method,
+        target: path.parse().unwrap_or_default(),
+        ..crate::request::Request::default()
+    };
+    let mut explanation = String::new();
+    if let Some(route) = config
+        .router
+        .routes()
+        .into_iter()
+        .find(|route| route.method == request.method && route.pattern == request.path())
+    {
+        let _ = writeln!(explanation, "{} {} -> {}", request.method, path, route);
+        return explanation;
+    }
+    let registered = config.router.allowed_methods(request.path());
+    if !registered.is_empty() {
+        let methods: Vec<_> = registered.iter().map(Method::as_str).collect();
+        let _ = writeln!(
+            explanation,
+            "{} is only registered for {}",
+            path,
+            methods.join(", ")
+        );
+    }
+    let handler = match crate::metrics::route(&request, config) {
+        "static" => format!("files under {}", config.document_root.display()),
+        "webdav" => "webdav".to_string(),
+        route => format!("the built-in handler at {}", route),
+    };
+    let _ = writeln!(explanation, "{} {} -> {}", request.method, path, handler);
+    explanation
+}
+
+/// Formats the counters of a server, one `name value` pair per line, followed by a summary line
+/// per route and method.
+fn describe_stats(server: &Server) -> String {
+    let stats = server.stats().snapshot();
+    let mut described = String::new();
+    // Writing into a String cannot fail
+    let _ = writeln!(described, "phase {:?}", server.phase());
+    let _ = writeln!(
+        described,
+        "connections_accepted {}",
+        stats.connections_accepted
+    );
+    let _ = writeln!(described, "connections_active {}", stats.connections_active);
+    let _ = writeln!(
+        described,
+        "tls_handshake_failures {}",
+        stats.tls_handshake_failures
+    );
+    let _ = writeln!(
+        described,
+        "connections_timed_out {}",
+        stats.connections_timed_out
+    );
+    let _ = writeln!(described, "requests {}", stats.requests);
+    let _ = writeln!(described, "reused_requests {}", stats.reused_requests);
+    for (class, count) in stats.responses.iter().enumerate() {
+        let _ = writeln!(described, "responses_{}xx {}", class + 1, count);
+    }
+    if let Some(limit) = &server.config().concurrency {
+        let concurrency = limit.stats();
+        let _ = writeln!(described, "concurrency_running {}", concurrency.running);
+        let _ = writeln!(described, "concurrency_queued {}", concurrency.queued);
+        let _ = writeln!(described, "concurrency_shed {}", concurrency.shed);
+    }
+    if let Some(shedder) = &server.config().load_shedding {
+        let shedding = shedder.stats();
+        let _ = writeln!(described, "shedding_fraction {:.2}", shedding.fraction);
+        if let Some(p99) = shedding.p99 {
+            let _ = writeln!(described, "shedding_p99_ms {}", p99.as_millis());
+        }
+        let _ = writeln!(described, "shedding_shed {}", shedding.shed);
+    }
+    for (route, stats) in server.stats().routes() {
+        for (method, counters) in &stats.methods {
+            let _ = writeln!(
+                described,
+                "route {} {} requests={} 4xx={} 5xx={} bytes_in={} bytes_out={}",
+                method,
+                route,
+                counters.requests,
+                counters.client_errors,
+                counters.server_errors,
+                counters.bytes_in,
+                counters.bytes_out
+            );
+        }
+    }
+    described
+}
+
+/// Creates a 200 plain-text response.
+/// The answer of the API key endpoints when the application checks none.
+#[cfg(feature = "auth")]
+fn api_keys_disabled() -> Response {
+    Response::new(StatusCode::NOT_FOUND, "API keys are not enabled\n")
+}
+
+fn text(body: impl Into<String>) -> Response {
+    Response::new(StatusCode::OK, body.into())
+        .with_header("Content-Type", "text/plain; charset=utf-8")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{AdminConfig, WebDavConfig};
+    use crate::metrics::Exchange;
+    use crate::request::Request;
+    use crate::server::Phase;
+    use std::time::Duration;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    fn request(method: Method, path: &str, body: &'static str) -> Request {
+        Request {
+            method,
+            target: path.parse().unwrap(),
+            body: body.into(),
+            ..Request::default()
+        }
+    }
+
+    /// It reports stats and routes, redacts secrets, and controls the server
+    #[tokio::test]
+    async fn endpoints() {
+        let config = Config {
+            router: Router::new().route(Method::Get, "/users", |_| async {
+                Ok(Response::new(StatusCode::OK, ""))
+            }),
+            webdav: Some(WebDavConfig {
+                username: "admin".to_string(),
+                password: "hunter2".to_string(),
+            }),
+            ..Config::default()
+        };
+        let server = Server::new(config);
+        server.stats().record_request();
+        server.stats().record_route(&Exchange {
+            route: "/users",
+            method: &Method::Get,
+            status: StatusCode::NOT_FOUND,
+            bytes_in: 0,
+            bytes_out: 9,
+            duration: Duration::from_millis(3),
+        });
+        let router = router(&server);
+
+        let stats = router
+            .dispatch(&request(Method::Get, "/stats", ""))
+            .await
+            .unwrap()
+            .unwrap();
+        let stats = String::from_utf8(stats.body.to_vec()).unwrap();
+        assert!(stats.contains("phase Running\n"));
+        assert!(stats.contains("requests 1\n"));
+        assert!(stats.contains("route GET /users requests=1 4xx=1 5xx=0 bytes_in=0 bytes_out=9\n"));
+
+        let routes = router
+            .dispatch(&request(Method::Get, "/routes", ""))
+            .await
+            .unwrap()
+            .unwrap();
+        let routes = String::from_utf8(routes.body.to_vec()).unwrap();
+        assert!(
+            routes.starts_with("GET /users web_server_tokio::admin::tests::"),
+            "{}",
+            routes
+        );
+        assert!(routes.ends_with("\nWebDAV / (webdav)\n"), "{}", routes);
+        let explained = router
+            .dispatch(&request(Method::Get, "/routes?path=/users&method=POST", ""))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            "/users is only registered for GET\nPOST /users -> files under .\n".as_bytes(),
+            &explained.body[..]
+        );
+
+        let config = router
+            .dispatch(&request(Method::Get, "/config", ""))
+            .await
+            .unwrap()
+            .unwrap();
+        let config = String::from_utf8(config.body.to_vec()).unwrap();
+        assert!(config.contains("admin"));
+        assert!(!config.contains("hunter2"));
+
+        let invalid = router
+            .dispatch(&request(Method::Put, "/log-level", "loud"))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(StatusCode::BAD_REQUEST, invalid.status);
+
+        router
+            .dispatch(&request(Method::Put, "/maintenance", "on\n"))
+            .await
+            .unwrap();
+        let maintenance = router
+            .dispatch(&request(Method::Get, "/maintenance", ""))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(b"on (admin)\n", &maintenance.body[..]);
+        server.replace(Config::default());
+        let config = server.config();
+        assert!(config.maintenance_switch.is_active(&config.maintenance));
+
+        router
+            .dispatch(&request(Method::Post, "/drain", ""))
+            .await
+            .unwrap();
+        assert_eq!(Phase::Draining, server.phase());
+        router
+            .dispatch(&request(Method::Post, "/shutdown", ""))
+            .await
+            .unwrap();
+        assert_eq!(Phase::Stopped, server.phase());
+    }
+
+    /// It reports a keep-alive connection closed by the header timeout in the stats
+    #[tokio::test(start_paused = true)]
+    async fn timed_out_connection() {
+        let server = Server::new(Config {
+            header_timeout: Some(Duration::from_secs(5)),
+            ..Config::default()
+        });
+        let listener = net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        tokio::spawn({
+            let server = server.clone();
+            async move { server.serve(listener).await }
+        });
+        let mut client = net::TcpStream::connect(address).await.unwrap();
+        client.write_all(b"GET / HTTP/1.1\r\n\r\n").await.unwrap();
+        let mut response = Vec::new();
+        client.read_to_end(&mut response).await.unwrap();
+        assert!(response.starts_with(b"HTTP/1.1 200 OK\r\n"));
+
+        let stats = router(&server)
+            .dispatch(&request(Method::Get, "/stats", ""))
+            .await
+            .unwrap()
+            .unwrap();
+        let stats = String::from_utf8(stats.body.to_vec()).unwrap();
+        assert!(stats.contains("requests 1\n"), "{}", stats);
+        assert!(stats.contains("connections_timed_out 1\n"), "{}", stats);
+    }
+
+    /// It changes the weight of an upstream of the proxy and refuses unknown upstreams and
+    /// invalid weights
+    #[tokio::test]
+    async fn upstream_weight() {
+        let server = Server::new(Config {
+            proxy: Some(crate::proxy::Proxy::new("/api", "127.0.0.1:9")),
+            ..Config::default()
+        });
+        let router = router(&server);
+        let status = |path: &str, body: &'static str| {
+            let request = request(Method::Put, path, body);
+            let router = &router;
+            async move { router.dispatch(&request).await.unwrap().unwrap().status }
+        };
+        assert_eq!(
+            StatusCode::NO_CONTENT,
+            status("/upstreams/127.0.0.1:9/weight", "3\n").await
+        );
+        let proxy = server.config().proxy.clone().unwrap();
+        assert_eq!(3, proxy.upstream_stats()[0].weight);
+        assert_eq!(
+            StatusCode::BAD_REQUEST,
+            status("/upstreams/127.0.0.1:9/weight", "-1").await
+        );
+        assert_eq!(
+            StatusCode::NOT_FOUND,
+            status("/upstreams/127.0.0.1:8/weight", "1").await
+        );
+        assert_eq!(3, proxy.upstream_stats()[0].weight);
+    }
+
+    /// It issues, lists, and revokes API keys
+    #[cfg(feature = "auth")]
+    #[tokio::test]
+    async fn api_keys() {
+        use crate::auth::{ApiKeys, MemoryKeyStore};
+        let server = Server::new(Config::default());
+        let router = router(&server);
+        let disabled = router
+            .dispatch(&request(Method::Get, "/api-keys", ""))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(StatusCode::NOT_FOUND, disabled.status);
+
+        server.replace(Config {
+            api_keys: Some(ApiKeys::new(MemoryKeyStore::new())),
+            ..Config::default()
+        });
+        let issued = router
+            .dispatch(&request(Method::Post, "/api-keys", "ci 60\n"))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(StatusCode::CREATED, issued.status);
+        let issued = String::from_utf8(issued.body.to_vec()).unwrap();
+        let id = issued.split(' ').next().unwrap();
+        let listed = router
+            .dispatch(&request(Method::Get, "/api-keys", ""))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            format!("{} ci rate=60/min requests=0 limited=0 roles=\n", id).as_bytes(),
+            &listed.body[..]
+        );
+        let path = format!("/api-keys/{}", id);
+        let revoke = Request {
+            method: Method::Delete,
+            target: path.parse().unwrap(),
+            ..Request::default()
+        };
+        let revoked = router.dispatch(&revoke).await.unwrap().unwrap();
+        assert_eq!(StatusCode::NO_CONTENT, revoked.status);
+        let again = router.dispatch(&revoke).await.unwrap().unwrap();
+        assert_eq!(StatusCode::NOT_FOUND, again.status);
+    }
+
+    /// It refuses requests changing state from web pages
+    #[test]
+    fn cross_site_requests() {
+        let mut shutdown = request(Method::Post, "/shutdown", "");
+        assert!(!cross_site(&shutdown));
+        shutdown.headers.append("Origin", "https://example.com");
+        assert!(cross_site(&shutdown));
+        let mut stats = request(Method::Get, "/stats", "");
+        stats.headers.append("Origin", "https://example.com");
+        assert!(!cross_site(&stats));
+    }
+
+    /// It checks bearer tokens and refuses profiles without one
+    #[tokio::test]
+    async fn token() {
+        let mut authorization = request(Method::Get, "/stats", "");
+        assert!(authorized(&authorization, None));
+        assert!(!authorized(&authorization, Some("s3cret")));
+        authorization
+            .headers
+            .append("Authorization", "Bearer s3cre");
+        assert!(!authorized(&authorization, Some("s3cret")));
+        authorization
+            .headers
+            .insert("Authorization", "Bearer s3cret");
+        assert!(authorized(&authorization, Some("s3cret")));
+
+        let config = Config {
+            admin: Some(AdminConfig {
+                token: Some("s3cret".to_string()),
+                ..AdminConfig::default()
+            }),
+            ..Config::default()
+        };
+        assert!(!format!("{:?}", config.admin).contains("s3cret"));
+        #[cfg(all(feature = "profiling", unix))]
+        {
+            let unprotected = router(&Server::new(Config::default()))
+                .dispatch(&request(Method::Get, "/profile?seconds=1", ""))
+                .await
+                .unwrap()
+                .unwrap();
+            assert_eq!(StatusCode::FORBIDDEN, unprotected.status);
+            let invalid = router(&Server::new(config))
+                .dispatch(&request(Method::Get, "/profile?seconds=600", ""))
+                .await
+                .unwrap()
+                .unwrap();
+            assert_eq!(StatusCode::BAD_REQUEST, invalid.status);
+        }
+    }
+}
@@ -0,0 +1,483 @@
+//! Authentication through pluggable providers: an [`Authenticator`] reads the credentials of a
+//! request, Basic or Bearer from the `Authorization` header or the subject stored in its
+//! session at login, asks its [`AuthProvider`]s who they belong to, and attaches the resulting
+//! [`Identity`] to the request for handlers and later middleware.
+
+pub mod api_key;
+pub mod file;
+#[cfg(feature = "oidc")]
+pub mod oidc;
+
+use crate::extract::{FromRequest, Rejection, RejectionKind};
+use crate::request::Request;
+use crate::response::Response;
+use crate::router::{Handler, Router};
+use crate::session::{Session, SessionStore};
+use crate::status::StatusCode;
+use async_trait::async_trait;
+use base64::Engine;
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use tokio::io;
+
+pub use api_key::{ApiKey, ApiKeys, KeyFile, KeyStore, MemoryKeyStore};
+pub use file::{Htpasswd, UserFile};
+
+/// The session value holding the subject of the logged in identity, see [`login`].
+pub const SESSION_SUBJECT: &str = "auth.subject";
+
+/// Who a request was made by, attached to authenticated requests as an extension.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Identity {
+    /// The stable name of the user or client, e.g. the user name.
+    pub subject: String,
+    /// The roles granted, e.g. `admin`.
+    pub roles: Vec<String>,
+    /// Further details from the provider, e.g. `email`.
+    pub attributes: BTreeMap<String, String>,
+}
+
+impl Identity {
+    /// Creates an identity without roles or attributes.
+    pub fn new(subject: impl Into<String>) -> Identity {
+        Identity {
+            subject: subject.into(),
+            ..Identity::default()
+        }
+    }
+
+    /// Grants a role.
+    pub fn with_role(mut self, role: impl Into<String>) -> Identity {
+        self.roles.push(role.into());
+        self
+    }
+
+    /// Adds an attribute, replacing any value it had.
+    pub fn with_attribute(mut self, name: impl Into<String>, value: impl Into<String>) -> Identity {
+        self.attributes.insert(name.into(), value.into());
+        self
+    }
+
+    /// Checks whether a role is granted.
+    pub fn has_role(&self, role: &str) -> bool {
+        self.roles.iter().any(|granted| granted == role)
+    }
+}
+
+/// The identity of an authenticated request. Rejected with 401 Unauthorized for anonymous
+/// requests, which handlers accepting them take as `Option<Identity>`.
+impl FromRequest for Identity {
+    fn from_request(request: &Request) -> Result<Identity, Rejection> {
+        request
+            .extensions()
+            .get::<Identity>()
+            .cloned()
+            .ok_or_else(|| {
+                Rejection::new(
+                    RejectionKind::Unauthenticated,
+                    StatusCode::UNAUTHORIZED,
+                    "the request is not authenticated",
+                )
+            })
+    }
+}
+
+/// What a client presented to prove who it is.
+#[derive(Clone, PartialEq, Eq)]
+pub enum Credentials {
+    /// A user name and password from `Authorization: Basic`.
+    Basic {
+        /// The user name.
+        username: String,
+        /// The password.
+        password: String,
+    },
+    /// A token from `Authorization: Bearer`.
+    Bearer(String),
+}
+
+impl Credentials {
+    /// Reads the credentials of the `Authorization` header of a request.
+    ///
+    /// # Returns
+    ///
+    /// The credentials, or `None` without the header or for another or malformed scheme.
+    pub fn from_request(request: &Request) -> Option<Credentials> {
+        let (scheme, value) = request.header("Authorization")?.trim().split_once(' ')?;
+        let value = value.trim();
+        if scheme.eq_ignore_ascii_case("Bearer") {
+            return Some(Credentials::Bearer(value.to_string()));
+        }
+        if !scheme.eq_ignore_ascii_case("Basic") {
+            return None;
+        }
+        let decoded = base64::engine::general_purpose::STANDARD
+            .decode(value)
+            .ok()?;
+        let (username, password) = std::str::from_utf8(&decoded).ok()?.split_once(':')?;
+        Some(Credentials::Basic {
+            username: username.to_string(),
+            password: password.to_string(),
+        })
+    }
+}
+
+/// Hides the password and token.
+impl std::fmt::Debug for Credentials {
+    fn fmt(&self, formatter: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Credentials::Basic { username, .. } => formatter
+                .debug_struct("Basic")
+                .field("username", username)
+                .finish_non_exhaustive(),
+            Credentials::Bearer(_) => formatter.write_str("Bearer(..)"),
+        }
+    }
+}
+
+/// Checks credentials against a source of users or clients.
+#[async_trait]
+pub trait AuthProvider: Send + Sync {
+    /// Checks credentials.
+    ///
+    /// # Arguments
+    ///
+    /// * `credentials`: What the client presented.
+    ///
+    /// # Returns
+    ///
+    /// The identity they prove, or `None` when they are wrong or of a kind the provider does
+    /// not check, leaving them to the next provider.
+    ///
+    /// # Errors
+    ///
+    /// Propagates IO errors from reaching the source, which answer 500 Internal Server Error.
+    async fn verify(&self, credentials: &Credentials) -> io::Result<Option<Identity>>;
+
+    /// Loads the identity of a subject authenticated earlier, e.g. stored in a session at
+    /// login.
+    ///
+    /// # Arguments
+    ///
+    /// * `subject`: The [`Identity::subject`] the provider returned.
+    ///
+    /// # Returns
+    ///
+    /// The current identity, or `None` when the provider no longer knows the subject.
+    ///
+    /// # Errors
+    ///
+    /// Propagates IO errors from reaching the source.
+    async fn load(&self, subject: &str) -> io::Result<Option<Identity>>;
+}
+
+/// A provider shared with other parts of the application, e.g. an [`oidc::Oidc`] that also
+/// answers its routes.
+#[async_trait]
+impl<P: AuthProvider + ?Sized> AuthProvider for Arc<P> {
+    async fn verify(&self, credentials: &Credentials) -> io::Result<Option<Identity>> {
+        (**self).verify(credentials).await
+    }
+
+    async fn load(&self, subject: &str) -> io::Result<Option<Identity>> {
+        (**self).load(subject).await
+    }
+}
+
+/// Authenticates requests with providers asked in the order they were added.
+#[derive(Clone)]
+pub struct Authenticator {
+    providers: Vec<Arc<dyn AuthProvider>>,
+    sessions: Option<SessionStore>,
+    realm: String,
+}
+
+impl Authenticator {
+    /// Creates an authenticator without providers, which authenticates nobody.
+    ///
+    /// # Arguments
+    ///
+    /// * `realm`: The realm named in `WWW-Authenticate` challenges, e.g. the site name.
+    pub fn new(realm: impl Into<String>) -> Authenticator {
+        Authenticator {
+            providers: Vec::new(),
+            sessions: None,
+            realm: realm.into(),
+        }
+    }
+
+    /// Asks a provider after the ones added before.
+    pub fn with_provider(mut self, provider: impl AuthProvider + 'static) -> Authenticator {
+        self.providers.push(Arc::new(provider));
+        self
+    }
+
+    /// Authenticates requests without an `Authorization` header by the subject stored in
+    /// their session with [`login`].
+    pub fn with_sessions(mut self, sessions: SessionStore) -> Authenticator {
+        self.sessions = Some(sessions);
+        self
+    }
+
+    /// Finds who made a request.
+    ///
+    /// # Arguments
+    ///
+    /// * `request`: The request.
+    ///
+    /// # Returns
+    ///
+    /// `Ok(Some(identity))` for an authenticated request, `Ok(None)` for an anonymous one, and
+    /// `Err` with the 401 Unauthorized response challenging the client when its credentials
+    /// are wrong.
+    ///
+    /// # Errors
+    ///
+    /// Propagates IO errors from the providers, answered by the caller.
+    pub async fn authenticate(
+        &self,
+        request: &Request,
+    ) -> io::Result<Result<Option<Identity>, Response>> {
+        if request.headers.contains("Authorization") {
+            if let Some(credentials) = Credentials::from_request(request) {
+                for provider in &self.providers {
+                    if let Some(identity) = provider.verify(&credentials).await? {
+                        return Ok(Ok(Some(identity)));
+                    }
+                }
+            }
+            return Ok(Err(self.challenge()));
+        }
+        let Some(sessions) = &self.sessions else {
+            return Ok(Ok(None));
+        };
+        let session = sessions.load(request);
+        let Some(subject) = session.get(SESSION_SUBJECT) else {
+            return Ok(Ok(None));
+        };
+        for provider in &self.providers {
+            if let Some(identity) = provider.load(subject).await? {
+                return Ok(Ok(Some(identity)));
+            }
+        }
+        Ok(Ok(None))
+    }
+
+    /// The 401 Unauthorized response asking for Basic or Bearer credentials.
+    pub fn challenge(&self) -> Response {
+        let realm = self.realm.replace('"', "");
+        Response::new(StatusCode::UNAUTHORIZED, "")
+            .with_header("WWW-Authenticate", format!("Basic realm=\"{}\"", realm))
+            .with_header("WWW-Authenticate", format!("Bearer realm=\"{}\"", realm))
+    }
+}
+
+/// Stores the subject of an identity in a session after checking a login form, giving the
+/// session a new ID since its privileges change.
+///
+/// # Arguments
+///
+/// * `sessions`: The store the session was loaded from.
+/// * `session`: The session of the login request, saved afterwards.
+/// * `identity`: The identity a provider returned for the submitted credentials.
+pub fn login(sessions: &SessionStore, session: &mut Session, identity: &Identity) {
+    sessions.rotate(session);
+    session.insert(SESSION_SUBJECT, identity.subject.clone());
+}
+
+/// Removes the subject from a session, giving it a new ID.
+pub fn logout(sessions: &SessionStore, session: &mut Session) {
+    sessions.rotate(session);
+    session.remove(SESSION_SUBJECT);
+}
+
+impl Router {
+    /// Authenticates the requests of every handler registered so far, attaching the
+    /// [`Identity`] of authenticated requests. Anonymous requests reach the handlers without
+    /// one, while requests with wrong credentials are answered with 401 Unauthorized.
+    ///
+    /// # Arguments
+    ///
+    /// * `authenticator`: The providers and session store, shared with other routers.
+    ///
+    /// # Returns
+    ///
+    /// The router with authenticated handlers.
+    pub fn with_authentication(self, authenticator: Arc<Authenticator>) -> Router {
+        self.map_handlers("authentication", |handler| {
+            Arc::new(Authenticated {
+                authenticator: Arc::clone(&authenticator),
+                handler,
+            })
+        })
+    }
+}
+
+/// Attaches the identity of requests before calling a wrapped handler.
+struct Authenticated {
+    authenticator: Arc<Authenticator>,
+    handler: Arc<dyn Handler>,
+}
+
+#[async_trait]
+impl Handler for Authenticated {
+    async fn call(&self, mut request: Request) -> io::Result<Response> {
+        match self.authenticator.authenticate(&request).await? {
+            Ok(Some(identity)) => {
+                request.extensions_mut().insert(identity);
+            }
+            Ok(None) => {}
+            Err(challenge) => return Ok(challenge),
+        }
+        self.handler.call(request).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::extract::with_extractors;
+    use crate::method::Method;
+
+    struct Tokens;
+
+    #[async_trait]
+    impl AuthProvider for Tokens {
+        async fn verify(&self, credentials: &Credentials) -> io::Result<Option<Identity>> {
+            Ok(match credentials {
+                Credentials::Bearer(token) if token == "t0ken" => {
+                    Some(Identity::new("ci").with_role("deploy"))
+                }
+                _ => None,
+            })
+        }
+
+        async fn load(&self, _: &str) -> io::Result<Option<Identity>> {
+            Ok(None)
+        }
+    }
+
+    fn router(sessions: &SessionStore) -> Router {
+        let users: Htpasswd = "ada:{SHA}qUqP5cyxm6YcTAhz05Hph5gvu9M=".parse().unwrap();
+        let authenticator = Authenticator::new("example")
+            .with_provider(users)
+            .with_provider(Tokens)
+            .with_sessions(sessions.clone());
+        Router::new()
+            .route(
+                Method::Get,
+                "/me",
+                with_extractors(|identity: Option<Identity>| async move {
+                    identity.map_or("anonymous".to_string(), |identity| identity.subject)
+                }),
+            )
+            .with_authentication(Arc::new(authenticator))
+    }
+
+    async fn me(router: &Router, authorization: Option<&str>, cookie: Option<&str>) -> Response {
+        let mut request = Request {
+            method: Method::Get,
+            target: "/me".parse().unwrap(),
+            ..Request::default()
+        };
+        if let Some(authorization) = authorization {
+            request.headers.append("Authorization", authorization);
+        }
+        if let Some(cookie) = cookie {
+            request.headers.append("Cookie", cookie);
+        }
+        let handler = router.find(&Method::Get, "/me").unwrap();
+        handler.call(request).await.unwrap()
+    }
+
+    /// It reads Basic and Bearer credentials and ignores other or malformed schemes
+    #[test]
+    fn credentials() {
+        let mut request = Request::default();
+        assert_eq!(None, Credentials::from_request(&request));
+        request
+            .headers
+            .insert("Authorization", "Basic YWRhOnRlc3Q=");
+        assert_eq!(
+            Some(Credentials::Basic {
+                username: "ada".to_string(),
+                password: "test".to_string(),
+            }),
+            Credentials::from_request(&request)
+        );
+        request.headers.insert("Authorization", "bearer t0ken");
+        assert_eq!(
+            Some(Credentials::Bearer("t0ken".to_string())),
+            Credentials::from_request(&request)
+        );
+        request.headers.insert("Authorization", "Basic not-base64");
+        assert_eq!(None, Credentials::from_request(&request));
+        request.headers.insert("Authorization", "Digest x");
+        assert_eq!(None, Credentials::from_request(&request));
+        let basic = Credentials::Basic {
+            username: "ada".to_string(),
+            password: "test".to_string(),
+        };
+        assert!(!format!("{:?}", basic).contains("test"));
+    }
+
+    /// It lets requests without credentials through without an identity
+    #[tokio::test]
+    async fn anonymous() {
+        let router = router(&SessionStore::new());
+        assert_eq!(b"anonymous".to_vec(), me(&router, None, None).await.body);
+    }
+
+    /// It attaches the identity of the first provider that accepts the credentials
+    #[tokio::test]
+    async fn providers() {
+        let router = router(&SessionStore::new());
+        assert_eq!(
+            b"ada".to_vec(),
+            me(&router, Some("Basic YWRhOnRlc3Q="), None).await.body
+        );
+        assert_eq!(
+            b"ci".to_vec(),
+            me(&router, Some("Bearer t0ken"), None).await.body
+        );
+    }
+
+    /// It challenges wrong credentials for both schemes
+    #[tokio::test]
+    async fn challenge() {
+        let router = router(&SessionStore::new());
+        let denied = me(&router, Some("Basic YWRhOndyb25n"), None).await;
+        assert_eq!(StatusCode::UNAUTHORIZED, denied.status);
+        assert_eq!(
+            vec!["Basic realm=\"example\"", "Bearer realm=\"example\""],
+            denied
+                .headers
+                .get_all("WWW-Authenticate")
+                .collect::<Vec<_>>()
+        );
+        let unknown = me(&router, Some("Digest x"), None).await;
+        assert_eq!(StatusCode::UNAUTHORIZED, unknown.status);
+    }
+
+    /// It attaches the identity of the subject logged in to the session until logout
+    #[tokio::test]
+    async fn sessions() {
+        let sessions = SessionStore::new();
+        let router = router(&sessions);
+        let mut session = Session::default();
+        login(&sessions, &mut session, &Identity::new("ada"));
+        let cookie = sessions.persist(session).unwrap();
+        let cookie = cookie.split(';').next().unwrap();
+        assert_eq!(b"ada".to_vec(), me(&router, None, Some(cookie)).await.body);
+
+        let mut request = Request::default();
+        request.headers.append("Cookie", cookie);
+        let mut session = sessions.load(&request);
+        logout(&sessions, &mut session);
+        assert_eq!(None, session.get(SESSION_SUBJECT));
+        assert_eq!(
+            b"anonymous".to_vec(),
+            me(&router, None, Some(cookie)).await.body
+        );
+    }
+}
@@ -0,0 +1,564 @@
+//! API keys for programs calling the application, sent in the [`HEADER`] header or a query
+//! parameter and checked against a [`KeyStore`]. Each key has an optional rate limit and usage
+//! counters, and authenticated requests carry the [`Identity`] of their key.
+
+use super::Identity;
+use crate::rate_limit::{Bucket, Decision, Quota};
+use crate::request::Request;
+use crate::response::Response;
+use crate::router::{Handler, Router};
+use crate::status::StatusCode;
+use async_trait::async_trait;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use ring::digest;
+use std::collections::HashMap;
+use std::fmt;
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::{Instant, SystemTime};
+use tokio::io;
+
+/// The header carrying the key.
+pub const HEADER: &str = "X-Api-Key";
+
+/// A key as its store describes it, without the secret.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ApiKey {
+    /// The stable ID naming the key in the admin API and the usage counters.
+    pub id: String,
+    /// Who the key was issued to, the [`Identity::subject`] of its requests.
+    pub name: String,
+    /// The roles granted to its requests.
+    pub roles: Vec<String>,
+    /// The requests allowed per minute, or `None` for no limit. The limit is a token bucket
+    /// allowing that many requests at once, refilled evenly over a minute.
+    pub requests_per_minute: Option<u32>,
+}
+
+impl ApiKey {
+    /// The identity of the requests made with the key, with its ID as the `api_key`
+    /// attribute.
+    pub fn identity(&self) -> Identity {
+        Identity {
+            roles: self.roles.clone(),
+            ..Identity::new(self.name.as_str())
+        }
+        .with_attribute("api_key", self.id.as_str())
+    }
+}
+
+/// Where keys are kept. Stores only keep a hash of each secret.
+#[async_trait]
+pub trait KeyStore: Send + Sync {
+    /// Finds the key of a secret.
+    ///
+    /// # Errors
+    ///
+    /// Propagates IO errors from reaching the store.
+    async fn find(&self, secret: &str) -> io::Result<Option<ApiKey>>;
+
+    /// Lists the keys, ordered by ID.
+    ///
+    /// # Errors
+    ///
+    /// Propagates IO errors from reaching the store.
+    async fn list(&self) -> io::Result<Vec<ApiKey>>;
+
+    /// Issues a new key.
+    ///
+    /// # Arguments
+    ///
+    /// * `name`: Who the key is issued to.
+    /// * `requests_per_minute`: The rate limit, if any.
+    ///
+    /// # Returns
+    ///
+    /// The key and its secret, which is only known at this point.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`io::ErrorKind::Unsupported`] unless the store is managed at runtime.
+    async fn create(
+        &self,
+        name: &str,
+        requests_per_minute: Option<u32>,
+    ) -> io::Result<(ApiKey, String)> {
+        let _ = (name, requests_per_minute);
+        Err(unsupported())
+    }
+
+    /// Revokes a key.
+    ///
+    /// # Returns
+    ///
+    /// Whether the key existed.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`io::ErrorKind::Unsupported`] unless the store is managed at runtime.
+    async fn revoke(&self, id: &str) -> io::Result<bool> {
+        let _ = id;
+        Err(unsupported())
+    }
+}
+
+/// Keys kept in memory and managed at runtime, e.g. through the admin API. Clones share the
+/// same keys.
+#[derive(Clone, Debug, Default)]
+pub struct MemoryKeyStore {
+    /// The keys by the hash of their secret.
+    keys: Arc<Mutex<HashMap<String, ApiKey>>>,
+}
+
+impl MemoryKeyStore {
+    /// Creates an empty store.
+    pub fn new() -> MemoryKeyStore {
+        MemoryKeyStore::default()
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, HashMap<String, ApiKey>> {
+        self.keys.lock().unwrap_or_else(|error| error.into_inner())
+    }
+}
+
+#[async_trait]
+impl KeyStore for MemoryKeyStore {
+    async fn find(&self, secret: &str) -> io::Result<Option<ApiKey>> {
+        Ok(self.lock().get(&hash(secret)).cloned())
+    }
+
+    async fn list(&self) -> io::Result<Vec<ApiKey>> {
+        let mut keys: Vec<ApiKey> = self.lock().values().cloned().collect();
+        keys.sort_by(|a, b| a.id.cmp(&b.id));
+        Ok(keys)
+    }
+
+    async fn create(
+        &self,
+        name: &str,
+        requests_per_minute: Option<u32>,
+    ) -> io::Result<(ApiKey, String)> {
+        let secret = URL_SAFE_NO_PAD.encode(rand::random::<[u8; 32]>());
+        let id: String = rand::random::<[u8; 6]>()
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect();
+        let key = ApiKey {
+            id,
+            name: name.to_string(),
+            roles: Vec::new(),
+            requests_per_minute,
+        };
+        self.lock().insert(hash(&secret), key.clone());
+        Ok((key, secret))
+    }
+
+    async fn revoke(&self, id: &str) -> io::Result<bool> {
+        let mut keys = self.lock();
+        let before = keys.len();
+        keys.retain(|_, key| key.id != id);
+        Ok(keys.len() < before)
+    }
+}
+
+/// The keys of a TOML file with a table per key named by its ID, holding the hex encoded
+/// SHA-256 hash of its secret, e.g. from `printf %s "$KEY" | sha256sum`, who it was issued to,
+/// its roles, and its rate limit, e.g. `[ci]`, `sha256 = "9f86d0..."`, `name = "ci"`,
+/// `roles = ["deploy"]`, `requests_per_minute = 600`.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct KeyFile {
+    keys: HashMap<String, ApiKey>,
+}
+
+impl KeyFile {
+    /// Reads a file.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`io::ErrorKind::InvalidData`] for invalid TOML or keys without a SHA-256
+    /// hash, and captures IO errors from reading it.
+    pub fn read(path: &Path) -> io::Result<KeyFile> {
+        std::fs::read_to_string(path)?
+            .parse()
+            .map_err(|error: io::Error| {
+                io::Error::new(
+                    error.kind(),
+                    format!("invalid key file {}: {}", path.display(), error),
+                )
+            })
+    }
+}
+
+impl FromStr for KeyFile {
+    type Err = io::Error;
+
+    fn from_str(text: &str) -> io::Result<KeyFile> {
+        let invalid = |message: String| io::Error::new(io::ErrorKind::InvalidData, message);
+        let table: toml::Table = text
+            .parse()
+            .map_err(|error| invalid(format!("{}", error)))?;
+        let mut keys = HashMap::new();
+        for (id, entry) in table {
+            let toml::Value::Table(entry) = entry else {
+                return Err(invalid(format!("key {} is not a table", id)));
+            };
+            let mut key = ApiKey {
+                id: id.clone(),
+                name: id.clone(),
+                roles: Vec::new(),
+                requests_per_minute: None,
+            };
+            let mut secret_hash = None;
+            for (field, value) in entry {
+                match (field.as_str(), value) {
+                    ("sha256", toml::Value::String(hex)) => {
+                        let valid = hex.len() == 64 && hex.bytes().all(|b| b.is_ascii_hexdigit());
+                        if !valid {
+                            return Err(invalid(format!("the sha256 of {} is not a hash", id)));
+                        }
+                        secret_hash = Some(hex.to_ascii_lowercase());
+                    }
+                    ("name", toml::Value::String(name)) => key.name = name,
+                    ("roles", toml::Value::Array(roles)) => {
+                        for role in roles {
+                            let toml::Value::String(role) = role else {
+                                return Err(invalid(format!("a role of {} is not text", id)));
+                            };
+                            key.roles.push(role);
+                        }
+                    }
+                    ("requests_per_minute", toml::Value::Integer(limit)) => {
+                        key.requests_per_minute = Some(u32::try_from(limit).map_err(|_| {
+                            invalid(format!("the rate limit of {} is out of range", id))
+                        })?);
+                    }
+                    (field, _) => return Err(invalid(format!("invalid {} of {}", field, id))),
+                }
+            }
+            let secret_hash =
+                secret_hash.ok_or_else(|| invalid(format!("key {} has no sha256", id)))?;
+            keys.insert(secret_hash, key);
+        }
+        Ok(KeyFile { keys })
+    }
+}
+
+#[async_trait]
+impl KeyStore for KeyFile {
+    async fn find(&self, secret: &str) -> io::Result<Option<ApiKey>> {
+        Ok(self.keys.get(&hash(secret)).cloned())
+    }
+
+    async fn list(&self) -> io::Result<Vec<ApiKey>> {
+        let mut keys: Vec<ApiKey> = self.keys.values().cloned().collect();
+        keys.sort_by(|a, b| a.id.cmp(&b.id));
+        Ok(keys)
+    }
+}
+
+/// How much a key was used since the server started.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct KeyUsage {
+    /// Requests let through.
+    pub requests: u64,
+    /// Requests refused by the rate limit.
+    pub limited: u64,
+    /// When the key was last used.
+    pub last_used: Option<SystemTime>,
+}
+
+/// The usage and rate limit bucket of a key.
+#[derive(Clone, Copy, Debug, Default)]
+struct Usage {
+    total: KeyUsage,
+    bucket: Option<Bucket>,
+}
+
+/// Checks the API keys of requests, see [`Router::with_api_keys`]. Clones share the store and
+/// the usage counters, e.g. the copy in [`crate::config::Config::api_keys`] that the admin API
+/// manages.
+#[derive(Clone)]
+pub struct ApiKeys {
+    store: Arc<dyn KeyStore>,
+    query_param: Option<String>,
+    usage: Arc<Mutex<HashMap<String, Usage>>>,
+}
+
+impl ApiKeys {
+    /// Checks keys sent in the [`HEADER`] header against a store.
+    pub fn new(store: impl KeyStore + 'static) -> ApiKeys {
+        ApiKeys {
+            store: Arc::new(store),
+            query_param: None,
+            usage: Arc::default(),
+        }
+    }
+
+    /// Also accepts keys in a query parameter, e.g. `api_key`, for clients that cannot set
+    /// headers. Such keys end up in access logs and browser histories.
+    pub fn with_query_param(mut self, name: impl Into<String>) -> ApiKeys {
+        self.query_param = Some(name.into());
+        self
+    }
+
+    /// The store of the keys.
+    pub fn store(&self) -> &dyn KeyStore {
+        self.store.as_ref()
+    }
+
+    /// How much a key was used.
+    pub fn usage(&self, id: &str) -> KeyUsage {
+        self.lock()
+            .get(id)
+            .map(|usage| usage.total)
+            .unwrap_or_default()
+    }
+
+    /// Checks the key of a request and counts its use.
+    ///
+    /// # Returns
+    ///
+    /// The identity of the key, or the response refusing the request: 401 Unauthorized
+    /// without a known key, or 429 Too Many Requests with `Retry-After` and the `RateLimit-*`
+    /// headers beyond its rate limit.
+    ///
+    /// # Errors
+    ///
+    /// Propagates IO errors from the store.
+    pub async fn check(&self, request: &Request) -> io::Result<Result<Identity, Response>> {
+        let secret = match request.header(HEADER) {
+            Some(secret) => Some(secret.to_string()),
+            None => self
+                .query_param
+                .as_ref()
+                .and_then(|name| request.target.query_param(name)),
+        };
+        let key = match secret {
+            Some(secret) => self.store.find(&secret).await?,
+            None => None,
+        };
+        let Some(key) = key else {
+            return Ok(Err(Response::new(
+                StatusCode::UNAUTHORIZED,
+                "a valid API key is required\n",
+            )));
+        };
+        if let Some(refused) = self.count(&key, Instant::now()) {
+            return Ok(Err(refused.apply(Response::new(
+                StatusCode::TOO_MANY_REQUESTS,
+                "the rate limit of the API key is exceeded\n",
+            ))));
+        }
+        Ok(Ok(key.identity()))
+    }
+
+    /// Counts a request with a key against its rate limit.
+    ///
+    /// # Returns
+    ///
+    /// The state of the bucket of the key when it refuses this request.
+    fn count(&self, key: &ApiKey, now: Instant) -> Option<Decision> {
+        let mut usage = self.lock();
+        let usage = usage.entry(key.id.clone()).or_default();
+        if let Some(limit) = key.requests_per_minute {
+            let quota = Quota::per_minute(limit);
+            let decision = usage
+                .bucket
+                .get_or_insert_with(|| Bucket::full(&quota, now))
+                .take(&quota, now);
+            if !decision.allowed {
+                usage.total.limited += 1;
+                return Some(decision);
+            }
+        }
+        usage.total.requests += 1;
+        usage.total.last_used = Some(SystemTime::now());
+        None
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, HashMap<String, Usage>> {
+        self.usage.lock().unwrap_or_else(|error| error.into_inner())
+    }
+}
+
+impl fmt::Debug for ApiKeys {
+    fn fmt(&self, formatter: &mut fmt::Formatter<'_>) -> fmt::Result {
+        formatter
+            .debug_struct("ApiKeys")
+            .field("query_param", &self.query_param)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Router {
+    /// Requires an API key on the requests of every handler registered so far, attaching the
+    /// [`Identity`] of the key, see [`ApiKeys::check`].
+    ///
+    /// # Arguments
+    ///
+    /// * `keys`: The keys, shared with other routers and [`crate::config::Config::api_keys`].
+    ///
+    /// # Returns
+    ///
+    /// The router with protected handlers.
+    pub fn with_api_keys(self, keys: ApiKeys) -> Router {
+        self.map_handlers("api_keys", |handler| {
+            Arc::new(KeyChecked {
+                keys: keys.clone(),
+                handler,
+            })
+        })
+    }
+}
+
+/// Checks the API key of requests before calling a wrapped handler.
+struct KeyChecked {
+    keys: ApiKeys,
+    handler: Arc<dyn Handler>,
+}
+
+#[async_trait]
+impl Handler for KeyChecked {
+    async fn call(&self, mut request: Request) -> io::Result<Response> {
+        match self.keys.check(&request).await? {
+            Ok(identity) => {
+                request.extensions_mut().insert(identity);
+                self.handler.call(request).await
+            }
+            Err(refusal) => Ok(refusal),
+        }
+    }
+}
+
+/// The hex encoded SHA-256 hash of a secret.
+fn hash(secret: &str) -> String {
+    digest::digest(&digest::SHA256, secret.as_bytes())
+        .as_ref()
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+fn unsupported() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::Unsupported,
+        "the key store is not managed at runtime",
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::method::Method;
+
+    fn router(keys: &ApiKeys) -> Router {
+        Router::new()
+            .route(Method::Get, "/builds", |request: Request| async move {
+                let identity = request.extensions().get::<Identity>().unwrap();
+                Ok(Response::new(StatusCode::OK, identity.subject.clone()))
+            })
+            .with_api_keys(keys.clone())
+    }
+
+    async fn get(router: &Router, target: &str, secret: Option<&str>) -> Response {
+        let mut request = Request {
+            method: Method::Get,
+            target: target.parse().unwrap(),
+            ..Request::default()
+        };
+        if let Some(secret) = secret {
+            request.headers.append(HEADER, secret);
+        }
+        let handler = router.find(&Method::Get, "/builds").unwrap();
+        handler.call(request).await.unwrap()
+    }
+
+    /// It accepts a known key from the header and refuses missing and unknown ones
+    #[tokio::test]
+    async fn header() {
+        let store = MemoryKeyStore::new();
+        let (_, secret) = store.create("ci", None).await.unwrap();
+        let router = router(&ApiKeys::new(store));
+        assert_eq!(
+            StatusCode::UNAUTHORIZED,
+            get(&router, "/builds", None).await.status
+        );
+        assert_eq!(
+            StatusCode::UNAUTHORIZED,
+            get(&router, "/builds", Some("wrong")).await.status
+        );
+        assert_eq!(
+            b"ci".to_vec(),
+            get(&router, "/builds", Some(&secret)).await.body
+        );
+    }
+
+    /// It accepts a known key from the query parameter when one is configured
+    #[tokio::test]
+    async fn query() {
+        let store = MemoryKeyStore::new();
+        let (_, secret) = store.create("ci", None).await.unwrap();
+        let query = format!("/builds?api_key={}", secret);
+        let without = router(&ApiKeys::new(store.clone()));
+        assert_eq!(
+            StatusCode::UNAUTHORIZED,
+            get(&without, &query, None).await.status
+        );
+        let with = router(&ApiKeys::new(store).with_query_param("api_key"));
+        assert_eq!(StatusCode::OK, get(&with, &query, None).await.status);
+    }
+
+    /// It counts the use of a key and enforces its rate limit
+    #[tokio::test]
+    async fn rate_limit() {
+        let store = MemoryKeyStore::new();
+        let (limited, secret) = store.create("ci", Some(2)).await.unwrap();
+        let keys = ApiKeys::new(store);
+        let router = router(&keys);
+        for _ in 0..2 {
+            assert_eq!(
+                StatusCode::OK,
+                get(&router, "/builds", Some(&secret)).await.status
+            );
+        }
+        let refused = get(&router, "/builds", Some(&secret)).await;
+        assert_eq!(StatusCode::TOO_MANY_REQUESTS, refused.status);
+        assert_eq!(Some("30"), refused.header("Retry-After"));
+        assert_eq!(Some("0"), refused.header("RateLimit-Remaining"));
+        let usage = keys.usage(&limited.id);
+        assert_eq!((2, 1), (usage.requests, usage.limited));
+    }
+
+    /// It refuses revoked keys
+    #[tokio::test]
+    async fn revocation() {
+        let store = MemoryKeyStore::new();
+        let (key, secret) = store.create("ci", None).await.unwrap();
+        let router = router(&ApiKeys::new(store.clone()));
+        assert!(store.revoke(&key.id).await.unwrap());
+        assert!(!store.revoke(&key.id).await.unwrap());
+        assert_eq!(
+            StatusCode::UNAUTHORIZED,
+            get(&router, "/builds", Some(&secret)).await.status
+        );
+    }
+
+    /// It finds keys of a key file by their hashed secret and cannot revoke them
+    #[tokio::test]
+    async fn key_file() {
+        let file: KeyFile = format!(
+            "[deploy]\nsha256 = \"{}\"\nroles = [\"deploy\"]\n",
+            hash("from-file")
+        )
+        .parse()
+        .unwrap();
+        assert_eq!(
+            Some(vec!["deploy".to_string()]),
+            file.find("from-file").await.unwrap().map(|key| key.roles)
+        );
+        assert!(file.find("other").await.unwrap().is_none());
+        assert!(file.revoke("deploy").await.is_err());
+    }
+}
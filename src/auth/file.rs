@@ -0,0 +1,297 @@
+//! Users listed in files, checked by the passwords of Basic credentials.
+
+use super::{AuthProvider, Credentials, Identity};
+use async_trait::async_trait;
+use base64::Engine;
+use ring::digest;
+use std::collections::HashMap;
+use std::path::Path;
+use std::str::FromStr;
+use tokio::io;
+
+/// A password hash as written by `htpasswd`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum PasswordHash {
+    /// `$2y$...` from `htpasswd -B`, and the `$2a$` and `$2b$` variants.
+    Bcrypt(String),
+    /// `{SHA}` followed by the base64 encoded SHA-1 digest, from `htpasswd -s`.
+    Sha1(Vec<u8>),
+}
+
+impl PasswordHash {
+    /// Checks a password against the hash, hashing bcrypt passwords on a blocking thread
+    /// since that is slow on purpose.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`io::ErrorKind::Other`] when the blocking thread panicked.
+    async fn verify(&self, password: &str) -> io::Result<bool> {
+        match self {
+            PasswordHash::Bcrypt(hash) => {
+                let (hash, password) = (hash.clone(), password.to_string());
+                tokio::task::spawn_blocking(move || {
+                    bcrypt::verify(password, &hash).unwrap_or(false)
+                })
+                .await
+                .map_err(io::Error::other)
+            }
+            PasswordHash::Sha1(expected) => {
+                Ok(
+                    digest::digest(&digest::SHA1_FOR_LEGACY_USE_ONLY, password.as_bytes()).as_ref()
+                        == expected.as_slice(),
+                )
+            }
+        }
+    }
+}
+
+impl FromStr for PasswordHash {
+    type Err = io::Error;
+
+    /// Parses a bcrypt or `{SHA}` hash, the formats other than the MD5 and crypt ones that
+    /// are too weak to keep.
+    fn from_str(hash: &str) -> io::Result<PasswordHash> {
+        if ["$2a$", "$2b$", "$2y$"]
+            .iter()
+            .any(|prefix| hash.starts_with(prefix))
+        {
+            return Ok(PasswordHash::Bcrypt(hash.to_string()));
+        }
+        hash.strip_prefix("{SHA}")
+            .and_then(|digest| {
+                base64::engine::general_purpose::STANDARD
+                    .decode(digest)
+                    .ok()
+            })
+            .map(PasswordHash::Sha1)
+            .ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "the password hash is neither bcrypt nor {SHA}",
+                )
+            })
+    }
+}
+
+/// The users of an Apache `htpasswd` file, one `name:hash` per line, e.g. from
+/// `htpasswd -B -c users.htpasswd ada`. Identities have no roles.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Htpasswd {
+    users: HashMap<String, PasswordHash>,
+}
+
+impl Htpasswd {
+    /// Reads a file.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`io::ErrorKind::InvalidData`] for lines without a bcrypt or `{SHA}` hash, and
+    /// captures IO errors from reading it.
+    pub fn read(path: &Path) -> io::Result<Htpasswd> {
+        std::fs::read_to_string(path)?
+            .parse()
+            .map_err(|error: io::Error| invalid(path, error))
+    }
+}
+
+impl FromStr for Htpasswd {
+    type Err = io::Error;
+
+    /// Parses the lines of a file, skipping empty ones and comments starting with `#`.
+    fn from_str(text: &str) -> io::Result<Htpasswd> {
+        let mut users = HashMap::new();
+        for (number, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let parsed = line
+                .split_once(':')
+                .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "no colon"))
+                .and_then(|(name, hash)| Ok((name.to_string(), hash.parse()?)));
+            let (name, hash) = parsed.map_err(|error| {
+                io::Error::new(error.kind(), format!("line {}: {}", number + 1, error))
+            })?;
+            users.insert(name, hash);
+        }
+        Ok(Htpasswd { users })
+    }
+}
+
+#[async_trait]
+impl AuthProvider for Htpasswd {
+    async fn verify(&self, credentials: &Credentials) -> io::Result<Option<Identity>> {
+        let Credentials::Basic { username, password } = credentials else {
+            return Ok(None);
+        };
+        let Some(hash) = self.users.get(username) else {
+            return Ok(None);
+        };
+        Ok(hash
+            .verify(password)
+            .await?
+            .then(|| Identity::new(username.as_str())))
+    }
+
+    async fn load(&self, subject: &str) -> io::Result<Option<Identity>> {
+        Ok(self
+            .users
+            .contains_key(subject)
+            .then(|| Identity::new(subject)))
+    }
+}
+
+/// A user of a [`UserFile`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct User {
+    password: PasswordHash,
+    identity: Identity,
+}
+
+/// The users of a TOML file with a table per user, holding its password hash as in an
+/// `htpasswd` file, its roles, and its attributes, e.g.
+/// `[ada]`, `password = "$2y$05$..."`, `roles = ["admin"]`, `email = "ada@example.org"`.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct UserFile {
+    users: HashMap<String, User>,
+}
+
+impl UserFile {
+    /// Reads a file.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`io::ErrorKind::InvalidData`] for invalid TOML or users without a bcrypt or
+    /// `{SHA}` password hash, and captures IO errors from reading it.
+    pub fn read(path: &Path) -> io::Result<UserFile> {
+        std::fs::read_to_string(path)?
+            .parse()
+            .map_err(|error: io::Error| invalid(path, error))
+    }
+}
+
+impl FromStr for UserFile {
+    type Err = io::Error;
+
+    fn from_str(text: &str) -> io::Result<UserFile> {
+        let invalid = |message: String| io::Error::new(io::ErrorKind::InvalidData, message);
+        let table: toml::Table = text
+            .parse()
+            .map_err(|error| invalid(format!("{}", error)))?;
+        let mut users = HashMap::new();
+        for (name, user) in table {
+            let toml::Value::Table(user) = user else {
+                return Err(invalid(format!("user {} is not a table", name)));
+            };
+            let mut password = None;
+            let mut identity = Identity::new(name.as_str());
+            for (key, value) in user {
+                match (key.as_str(), value) {
+                    ("password", toml::Value::String(hash)) => password = Some(hash),
+                    ("roles", toml::Value::Array(roles)) => {
+                        for role in roles {
+                            let toml::Value::String(role) = role else {
+                                return Err(invalid(format!("a role of {} is not text", name)));
+                            };
+                            identity.roles.push(role);
+                        }
+                    }
+                    (_, toml::Value::String(value)) => {
+                        identity.attributes.insert(key, value);
+                    }
+                    (key, _) => return Err(invalid(format!("invalid {} of {}", key, name))),
+                }
+            }
+            let password = password
+                .ok_or_else(|| invalid(format!("user {} has no password", name)))?
+                .parse()
+                .map_err(|error| invalid(format!("user {}: {}", name, error)))?;
+            users.insert(name, User { password, identity });
+        }
+        Ok(UserFile { users })
+    }
+}
+
+#[async_trait]
+impl AuthProvider for UserFile {
+    async fn verify(&self, credentials: &Credentials) -> io::Result<Option<Identity>> {
+        let Credentials::Basic { username, password } = credentials else {
+            return Ok(None);
+        };
+        let Some(user) = self.users.get(username) else {
+            return Ok(None);
+        };
+        Ok(user
+            .password
+            .verify(password)
+            .await?
+            .then(|| user.identity.clone()))
+    }
+
+    async fn load(&self, subject: &str) -> io::Result<Option<Identity>> {
+        Ok(self.users.get(subject).map(|user| user.identity.clone()))
+    }
+}
+
+/// Names the file in an error from parsing it.
+fn invalid(path: &Path, error: io::Error) -> io::Error {
+    io::Error::new(
+        error.kind(),
+        format!("invalid user file {}: {}", path.display(), error),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// It checks passwords with bcrypt and SHA-1 hashes and loads roles and attributes
+    #[tokio::test]
+    async fn user_files() {
+        let basic = |username: &str, password: &str| Credentials::Basic {
+            username: username.to_string(),
+            password: password.to_string(),
+        };
+        let hash = bcrypt::hash("secret", 4).unwrap();
+        let htpasswd: Htpasswd = format!(
+            "# users\nada:{}\ngrace:{{SHA}}qUqP5cyxm6YcTAhz05Hph5gvu9M=\n",
+            hash
+        )
+        .parse()
+        .unwrap();
+        assert_eq!(
+            Some(Identity::new("ada")),
+            htpasswd.verify(&basic("ada", "secret")).await.unwrap()
+        );
+        assert_eq!(None, htpasswd.verify(&basic("ada", "test")).await.unwrap());
+        assert!(htpasswd
+            .verify(&basic("grace", "test"))
+            .await
+            .unwrap()
+            .is_some());
+        assert!("ada:$apr1$salt$hash".parse::<Htpasswd>().is_err());
+
+        let users: UserFile = format!(
+            "[ada]\npassword = \"{}\"\nroles = [\"admin\"]\nemail = \"ada@example.org\"\n",
+            hash
+        )
+        .parse()
+        .unwrap();
+        let ada = Identity::new("ada")
+            .with_role("admin")
+            .with_attribute("email", "ada@example.org");
+        assert_eq!(
+            Some(ada.clone()),
+            users.verify(&basic("ada", "secret")).await.unwrap()
+        );
+        assert_eq!(Some(ada), users.load("ada").await.unwrap());
+        assert_eq!(
+            None,
+            users
+                .verify(&Credentials::Bearer("secret".to_string()))
+                .await
+                .unwrap()
+        );
+        assert!("[ada]\nroles = []".parse::<UserFile>().is_err());
+    }
+}
@@ -0,0 +1,958 @@
+//! Single sign-on through an OpenID Connect provider with the authorization code flow.
+//!
+//! The login route sends the browser to the provider with a random state, nonce, and PKCE
+//! challenge kept in its session. The provider sends it back to the callback route with a code,
+//! which the server exchanges for an ID token. Once the signature and claims of the token check
+//! out, the subject is stored in the session with [`super::login`], and [`Oidc`] as an
+//! [`AuthProvider`] of an [`super::Authenticator`] turns it into the [`Identity`] of later
+//! requests.
+
+use super::{AuthProvider, Credentials, Identity};
+use crate::error::server_error;
+use crate::log;
+use crate::method::Method;
+use crate::path::percent_encode;
+use crate::proxy::{read_response, MAX_RESPONSE_SIZE};
+use crate::request::Request;
+use crate::response::Response;
+use crate::router::Router;
+use crate::session::SessionStore;
+use crate::status::StatusCode;
+use crate::uri::Uri;
+use async_trait::async_trait;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use ring::digest;
+use ring::signature::{self, RsaPublicKeyComponents, UnparsedPublicKey};
+use rustls::pki_types::ServerName;
+use rustls::{ClientConfig, RootCertStore};
+use serde_json::Value;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::io::{self, AsyncRead, AsyncWrite, AsyncWriteExt};
+use tokio::{net, time};
+use tokio_rustls::TlsConnector;
+
+/// How far the clocks of the server and the provider may disagree when checking the expiry
+/// of ID tokens.
+const CLOCK_SKEW: Duration = Duration::from_secs(60);
+
+/// How long an exchange with the provider may take.
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How many identities of logged in subjects are remembered. Beyond it the least recently used
+/// is forgotten, and its sessions are anonymous until the subject logs in again.
+const MAX_IDENTITIES: usize = 10_000;
+
+/// The session values of a login in progress.
+const STATE: &str = "oidc.state";
+const NONCE: &str = "oidc.nonce";
+const VERIFIER: &str = "oidc.verifier";
+const RETURN_TO: &str = "oidc.return_to";
+
+/// The client registration at an OpenID Connect provider.
+#[derive(Clone, PartialEq, Eq)]
+pub struct OidcConfig {
+    /// The issuer, e.g. `https://accounts.example.com`, whose
+    /// `/.well-known/openid-configuration` names the endpoints. Plain `http` is only accepted
+    /// for loopback addresses.
+    pub issuer: String,
+    /// The client ID.
+    pub client_id: String,
+    /// The client secret, sent with HTTP Basic authentication to the token endpoint.
+    pub client_secret: String,
+    /// The absolute URL of the callback route registered at the provider, e.g.
+    /// `https://app.example.com/auth/callback`. Its path is routed by [`Router::with_oidc`].
+    pub redirect_uri: String,
+    /// The scopes requested, `openid`, `email`, and `profile` by default.
+    pub scopes: Vec<String>,
+    /// The path of the login route, `/login` by default. A `return_to` query parameter with a
+    /// local path sets where the browser goes after the login, `/` by default.
+    pub login_path: String,
+}
+
+impl OidcConfig {
+    /// Creates a configuration with the default scopes and login path.
+    pub fn new(
+        issuer: impl Into<String>,
+        client_id: impl Into<String>,
+        client_secret: impl Into<String>,
+        redirect_uri: impl Into<String>,
+    ) -> OidcConfig {
+        OidcConfig {
+            issuer: issuer.into(),
+            client_id: client_id.into(),
+            client_secret: client_secret.into(),
+            redirect_uri: redirect_uri.into(),
+            scopes: ["openid", "email", "profile"].map(String::from).to_vec(),
+            login_path: "/login".to_string(),
+        }
+    }
+}
+
+/// Hides the client secret.
+impl std::fmt::Debug for OidcConfig {
+    fn fmt(&self, formatter: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        formatter
+            .debug_struct("OidcConfig")
+            .field("issuer", &self.issuer)
+            .field("client_id", &self.client_id)
+            .field("redirect_uri", &self.redirect_uri)
+            .field("scopes", &self.scopes)
+            .field("login_path", &self.login_path)
+            .finish_non_exhaustive()
+    }
+}
+
+/// The endpoints of a provider from its discovery document.
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct Metadata {
+    issuer: String,
+    authorization_endpoint: String,
+    token_endpoint: String,
+    jwks_uri: String,
+}
+
+/// A public key of the provider signing ID tokens.
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Jwk {
+    /// An RSA key for `RS256`, with its big-endian modulus and exponent.
+    Rsa {
+        kid: Option<String>,
+        n: Vec<u8>,
+        e: Vec<u8>,
+    },
+    /// A P-256 key for `ES256`, as an uncompressed point.
+    Ec { kid: Option<String>, point: Vec<u8> },
+}
+
+impl Jwk {
+    /// Parses a key of a JWK set, skipping kinds other than RSA and P-256 and keys for
+    /// encryption.
+    fn parse(key: &Value) -> Option<Jwk> {
+        let field = |name: &str| {
+            key.get(name)
+                .and_then(Value::as_str)
+                .and_then(|value| URL_SAFE_NO_PAD.decode(value).ok())
+        };
+        if key.get("use").and_then(Value::as_str).unwrap_or("sig") != "sig" {
+            return None;
+        }
+        let kid = key.get("kid").and_then(Value::as_str).map(String::from);
+        match key.get("kty").and_then(Value::as_str)? {
+            "RSA" => Some(Jwk::Rsa {
+                kid,
+                n: field("n")?,
+                e: field("e")?,
+            }),
+            "EC" if key.get("crv").and_then(Value::as_str) == Some("P-256") => {
+                let point = [&[0x04][..], &field("x")?, &field("y")?].concat();
+                Some(Jwk::Ec { kid, point })
+            }
+            _ => None,
+        }
+    }
+
+    fn kid(&self) -> Option<&str> {
+        match self {
+            Jwk::Rsa { kid, .. } | Jwk::Ec { kid, .. } => kid.as_deref(),
+        }
+    }
+
+    /// Checks a signature made with the algorithm of the token header.
+    fn verify(&self, algorithm: &str, message: &[u8], signature: &[u8]) -> bool {
+        match (self, algorithm) {
+            (Jwk::Rsa { n, e, .. }, "RS256") => RsaPublicKeyComponents { n, e }
+                .verify(&signature::RSA_PKCS1_2048_8192_SHA256, message, signature)
+                .is_ok(),
+            (Jwk::Ec { point, .. }, "ES256") => {
+                UnparsedPublicKey::new(&signature::ECDSA_P256_SHA256_FIXED, point)
+                    .verify(message, signature)
+                    .is_ok()
+            }
+            _ => false,
+        }
+    }
+}
+
+/// Logs users in through an OpenID Connect provider and remembers who logged in.
+pub struct Oidc {
+    config: OidcConfig,
+    metadata: Metadata,
+    /// The signing keys, fetched again when a token names an unknown one.
+    keys: RwLock<Vec<Jwk>>,
+    sessions: SessionStore,
+    /// The identities of the subjects that logged in, for [`AuthProvider::load`].
+    identities: Mutex<Identities>,
+    tls: TlsConnector,
+}
+
+impl Oidc {
+    /// Fetches the discovery document and signing keys of a provider.
+    ///
+    /// # Arguments
+    ///
+    /// * `config`: The client registration.
+    /// * `sessions`: The store keeping logins in progress and the subjects logged in, shared
+    ///   with the [`super::Authenticator`].
+    ///
+    /// # Errors
+    ///
+    /// Returns [`io::ErrorKind::InvalidData`] when the provider answers with an error or a
+    /// document missing endpoints or naming another issuer, and captures IO errors from
+    /// reaching it.
+    pub async fn discover(config: OidcConfig, sessions: SessionStore) -> io::Result<Oidc> {
+        let mut roots = RootCertStore::empty();
+        for certificate in rustls_native_certs::load_native_certs().certs {
+            // Unusable system certificates only make their sites unreachable
+            let _ = roots.add(certificate);
+        }
+        let tls =
+            ClientConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+                .with_safe_default_protocol_versions()
+                .map_err(io::Error::other)?
+                .with_root_certificates(roots)
+                .with_no_client_auth();
+        let tls = TlsConnector::from(Arc::new(tls));
+
+        let url = format!(
+            "{}/.well-known/openid-configuration",
+            config.issuer.trim_end_matches('/')
+        );
+        let document = json(fetch(&tls, Method::Get, &url, &[], b"").await?, &url)?;
+        let endpoint = |name: &str| {
+            document
+                .get(name)
+                .and_then(Value::as_str)
+                .map(String::from)
+                .ok_or_else(|| invalid(format!("the discovery document has no {}", name)))
+        };
+        let metadata = Metadata {
+            issuer: endpoint("issuer")?,
+            authorization_endpoint: endpoint("authorization_endpoint")?,
+            token_endpoint: endpoint("token_endpoint")?,
+            jwks_uri: endpoint("jwks_uri")?,
+        };
+        if metadata.issuer.trim_end_matches('/') != config.issuer.trim_end_matches('/') {
+            return Err(invalid(format!(
+                "the discovery document names the issuer {}",
+                metadata.issuer
+            )));
+        }
+        let oidc = Oidc {
+            config,
+            metadata,
+            keys: RwLock::new(Vec::new()),
+            sessions,
+            identities: Mutex::new(Identities::new(MAX_IDENTITIES)),
+            tls,
+        };
+        oidc.refresh_keys().await?;
+        Ok(oidc)
+    }
+
+    /// Sends the browser to the provider, see [`OidcConfig::login_path`].
+    ///
+    /// # Arguments
+    ///
+    /// * `request`: The request for the login route.
+    ///
+    /// # Returns
+    ///
+    /// The 302 Found redirect to the authorization endpoint, with a session cookie when the
+    /// client had no session yet.
+    pub fn login(&self, request: &Request) -> Response {
+        let mut session = self.sessions.load(request);
+        let state = random_token();
+        let nonce = random_token();
+        let verifier = random_token();
+        let challenge =
+            URL_SAFE_NO_PAD.encode(digest::digest(&digest::SHA256, verifier.as_bytes()));
+        let return_to = request
+            .target
+            .query_param("return_to")
+            .filter(|path| local_path(path))
+            .unwrap_or_else(|| "/".to_string());
+        let parameters = [
+            ("response_type", "code"),
+            ("client_id", &self.config.client_id),
+            ("redirect_uri", &self.config.redirect_uri),
+            ("scope", &self.config.scopes.join(" ")),
+            ("state", &state),
+            ("nonce", &nonce),
+            ("code_challenge", &challenge),
+            ("code_challenge_method", "S256"),
+        ];
+        let separator = match self.metadata.authorization_endpoint.contains('?') {
+            true => '&',
+            false => '?',
+        };
+        let location = format!(
+            "{}{}{}",
+            self.metadata.authorization_endpoint,
+            separator,
+            form(&parameters)
+        );
+        session.insert(STATE, state);
+        session.insert(NONCE, nonce);
+        session.insert(VERIFIER, verifier);
+        session.insert(RETURN_TO, return_to);
+        self.sessions.save(
+            session,
+            Response::new(StatusCode::FOUND, "").with_header("Location", location),
+        )
+    }
+
+    /// Completes a login when the provider sends the browser back, see
+    /// [`OidcConfig::redirect_uri`].
+    ///
+    /// # Arguments
+    ///
+    /// * `request`: The request for the callback route.
+    ///
+    /// # Returns
+    ///
+    /// The 302 Found redirect to the `return_to` path of the login, with the cookie of the
+    /// session under its new ID; 400 Bad Request when the state does not match a login of the
+    /// session or the provider reports an error; 401 Unauthorized for an invalid ID token; and
+    /// 502 Bad Gateway when the token exchange fails.
+    pub async fn callback(&self, request: &Request) -> Response {
+        let mut session = self.sessions.load(request);
+        let state = session.remove(STATE);
+        let nonce = session.remove(NONCE);
+        let verifier = session.remove(VERIFIER);
+        let return_to = session.remove(RETURN_TO);
+        let (Some(state), Some(nonce), Some(verifier), Some(return_to)) =
+            (state, nonce, verifier, return_to)
+        else {
+            return Response::new(StatusCode::BAD_REQUEST, "no login is in progress\n");
+        };
+        if request.target.query_param("state").as_deref() != Some(state.as_str()) {
+            return Response::new(StatusCode::BAD_REQUEST, "the login state does not match\n");
+        }
+        let Some(code) = request.target.query_param("code") else {
+            let error = request.target.query_param("error").unwrap_or_default();
+            return Response::new(
+                StatusCode::BAD_REQUEST,
+                format!("the login failed: {}\n", error),
+            );
+        };
+        let token = match self.exchange(&code, &verifier).await {
+            Ok(token) => token,
+            Err(error) => {
+                return server_error(
+                    StatusCode::BAD_GATEWAY,
+                    &format!("exchanging an OpenID Connect code failed: {}", error),
+                )
+            }
+        };
+        let identity = match self.validate(&token, &nonce, SystemTime::now()).await {
+            Ok(identity) => identity,
+            Err(error) => {
+                log::warn(format_args!(
+                    "rejected an OpenID Connect ID token: {}",
+                    error
+                ));
+                return Response::new(StatusCode::UNAUTHORIZED, "the ID token is invalid\n");
+            }
+        };
+        self.identities
+            .lock()
+            .unwrap_or_else(|error| error.into_inner())
+            .insert(identity.clone());
+        super::login(&self.sessions, &mut session, &identity);
+        self.sessions.save(
+            session,
+            Response::new(StatusCode::FOUND, "").with_header("Location", return_to),
+        )
+    }
+
+    /// Exchanges a code for the ID token at the token endpoint.
+    async fn exchange(&self, code: &str, verifier: &str) -> io::Result<String> {
+        let body = form(&[
+            ("grant_type", "authorization_code"),
+            ("code", code),
+            ("redirect_uri", &self.config.redirect_uri),
+            ("code_verifier", verifier),
+        ]);
+        let secret = format!(
+            "{}:{}",
+            form_component(&self.config.client_id),
+            form_component(&self.config.client_secret)
+        );
+        let authorization = format!(
+            "Basic {}",
+            base64::engine::general_purpose::STANDARD.encode(secret)
+        );
+        let url = &self.metadata.token_endpoint;
+        let headers = [
+            ("Content-Type", "application/x-www-form-urlencoded"),
+            ("Authorization", authorization.as_str()),
+            ("Accept", "application/json"),
+        ];
+        let response = fetch(&self.tls, Method::Post, url, &headers, body.as_bytes()).await?;
+        json(response, url)?
+            .get("id_token")
+            .and_then(Value::as_str)
+            .map(String::from)
+            .ok_or_else(|| invalid("the token response has no id_token".to_string()))
+    }
+
+    /// Checks the signature and claims of an ID token.
+    ///
+    /// # Returns
+    ///
+    /// The identity of the subject, with the `email`, `name`, and `preferred_username`
+    /// claims as attributes.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`io::ErrorKind::InvalidData`] naming the check that failed.
+    async fn validate(&self, token: &str, nonce: &str, now: SystemTime) -> io::Result<Identity> {
+        let mut parts = token.split('.');
+        let (Some(header), Some(claims), Some(signature), None) =
+            (parts.next(), parts.next(), parts.next(), parts.next())
+        else {
+            return Err(invalid("the ID token is not a signed JWT".to_string()));
+        };
+        let decode = |part: &str| {
+            URL_SAFE_NO_PAD
+                .decode(part)
+                .map_err(|_| invalid("the ID token is not base64".to_string()))
+        };
+        let parse = |part: &str| {
+            serde_json::from_slice::<Value>(&decode(part)?)
+                .map_err(|_| invalid("the ID token is not JSON".to_string()))
+        };
+        let (header, claims) = (parse(header)?, parse(claims)?);
+        let algorithm = header.get("alg").and_then(Value::as_str).unwrap_or("none");
+        let kid = header.get("kid").and_then(Value::as_str);
+        let message = &token[..token.len() - signature.len() - 1];
+        let signature = decode(signature)?;
+        if !self.verify_signature(algorithm, kid, message.as_bytes(), &signature) {
+            self.refresh_keys().await?;
+            if !self.verify_signature(algorithm, kid, message.as_bytes(), &signature) {
+                return Err(invalid("the ID token signature is invalid".to_string()));
+            }
+        }
+
+        let claim = |name: &str| claims.get(name).and_then(Value::as_str);
+        if claim("iss") != Some(self.metadata.issuer.as_str()) {
+            return Err(invalid("the ID token is from another issuer".to_string()));
+        }
+        let audience = match claims.get("aud") {
+            Some(Value::String(audience)) => *audience == self.config.client_id,
+            Some(Value::Array(audiences)) => audiences
+                .iter()
+                .any(|audience| audience.as_str() == Some(&self.config.client_id)),
+            _ => false,
+        };
+        if !audience {
+            return Err(invalid("the ID token is for another client".to_string()));
+        }
+        let expiry = claims
+            .get("exp")
+            .and_then(Value::as_u64)
+            .map(|exp| UNIX_EPOCH + Duration::from_secs(exp) + CLOCK_SKEW);
+        if expiry.is_none_or(|expiry| expiry <= now) {
+            return Err(invalid("the ID token expired".to_string()));
+        }
+        if claim("nonce") != Some(nonce) {
+            return Err(invalid("the ID token nonce does not match".to_string()));
+        }
+        let subject =
+            claim("sub").ok_or_else(|| invalid("the ID token has no subject".to_string()))?;
+        let mut identity = Identity::new(subject);
+        for name in ["email", "name", "preferred_username"] {
+            if let Some(value) = claim(name) {
+                identity = identity.with_attribute(name, value);
+            }
+        }
+        Ok(identity)
+    }
+
+    /// Checks a signature with the key the token names, or with every key when it names none.
+    fn verify_signature(
+        &self,
+        algorithm: &str,
+        kid: Option<&str>,
+        message: &[u8],
+        signature: &[u8],
+    ) -> bool {
+        self.keys
+            .read()
+            .unwrap_or_else(|error| error.into_inner())
+            .iter()
+            .filter(|key| kid.is_none() || key.kid() == kid)
+            .any(|key| key.verify(algorithm, message, signature))
+    }
+
+    /// Fetches the signing keys again, e.g. after the provider rotated them.
+    async fn refresh_keys(&self) -> io::Result<()> {
+        let url = &self.metadata.jwks_uri;
+        let document = json(fetch(&self.tls, Method::Get, url, &[], b"").await?, url)?;
+        let keys: Vec<Jwk> = document
+            .get("keys")
+            .and_then(Value::as_array)
+            .map(|keys| keys.iter().filter_map(Jwk::parse).collect())
+            .unwrap_or_default();
+        if keys.is_empty() {
+            return Err(invalid(format!("{} has no RS256 or ES256 key", url)));
+        }
+        *self.keys.write().unwrap_or_else(|error| error.into_inner()) = keys;
+        Ok(())
+    }
+}
+
+/// The identities of the subjects that logged in, forgetting the least recently used beyond a
+/// capacity so that logins over a long uptime do not pile up.
+struct Identities {
+    /// The identity of each subject with the tick it was last used at.
+    entries: HashMap<String, (Identity, u64)>,
+    tick: u64,
+    capacity: usize,
+}
+
+impl Identities {
+    fn new(capacity: usize) -> Identities {
+        Identities {
+            entries: HashMap::new(),
+            tick: 0,
+            capacity,
+        }
+    }
+
+    /// Remembers the identity of a subject that logged in, replacing the one it had.
+    fn insert(&mut self, identity: Identity) {
+        self.tick += 1;
+        self.entries
+            .insert(identity.subject.clone(), (identity, self.tick));
+        if self.entries.len() > self.capacity {
+            let oldest = self
+                .entries
+                .iter()
+                .min_by_key(|(_, (_, used))| *used)
+                .map(|(subject, _)| subject.clone());
+            if let Some(oldest) = oldest {
+                self.entries.remove(&oldest);
+            }
+        }
+    }
+
+    /// Finds the identity of a subject, marking it used.
+    fn get(&mut self, subject: &str) -> Option<Identity> {
+        self.tick += 1;
+        let (identity, used) = self.entries.get_mut(subject)?;
+        *used = self.tick;
+        Some(identity.clone())
+    }
+}
+
+impl std::fmt::Debug for Oidc {
+    fn fmt(&self, formatter: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        formatter
+            .debug_struct("Oidc")
+            .field("config", &self.config)
+            .field("metadata", &self.metadata)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Loads the identities of the subjects that logged in since the server started, up to the
+/// most recently used [`MAX_IDENTITIES`]. Credentials are left to other providers.
+#[async_trait]
+impl AuthProvider for Oidc {
+    async fn verify(&self, _: &Credentials) -> io::Result<Option<Identity>> {
+        Ok(None)
+    }
+
+    async fn load(&self, subject: &str) -> io::Result<Option<Identity>> {
+        Ok(self
+            .identities
+            .lock()
+            .unwrap_or_else(|error| error.into_inner())
+            .get(subject))
+    }
+}
+
+impl Router {
+    /// Adds the login route and the callback route of an OpenID Connect provider.
+    ///
+    /// # Arguments
+    ///
+    /// * `oidc`: The provider, also added to the [`super::Authenticator`] of the routes that
+    ///   need the identity.
+    ///
+    /// # Returns
+    ///
+    /// The router with both routes.
+    ///
+    /// # Panics
+    ///
+    /// Panics when [`OidcConfig::redirect_uri`] is not an absolute URL or a route conflicts
+    /// with an existing one, like [`Router::route`].
+    pub fn with_oidc(self, oidc: Arc<Oidc>) -> Router {
+        let callback = oidc
+            .config
+            .redirect_uri
+            .parse::<Uri>()
+            .ok()
+            .filter(|uri| uri.authority().is_some())
+            .map(|uri| uri.path().to_string())
+            .expect("the OpenID Connect redirect URI is not an absolute URL");
+        let login = oidc.config.login_path.clone();
+        let login_oidc = Arc::clone(&oidc);
+        self.route(Method::Get, &login, move |request: Request| {
+            let response = login_oidc.login(&request);
+            async move { Ok(response) }
+        })
+        .route(Method::Get, &callback, move |request: Request| {
+            let oidc = Arc::clone(&oidc);
+            async move { Ok(oidc.callback(&request).await) }
+        })
+    }
+}
+
+/// Makes a random URL-safe value for the state, nonce, and PKCE verifier.
+fn random_token() -> String {
+    URL_SAFE_NO_PAD.encode(rand::random::<[u8; 32]>())
+}
+
+/// Encodes parameters as `application/x-www-form-urlencoded`.
+fn form(parameters: &[(&str, &str)]) -> String {
+    parameters
+        .iter()
+        .map(|(name, value)| format!("{}={}", form_component(name), form_component(value)))
+        .collect::<Vec<_>>()
+        .join("&")
+}
+
+/// Percent-encodes a name or value, including `/`.
+fn form_component(component: &str) -> String {
+    percent_encode(component).replace('/', "%2F")
+}
+
+/// Parses a successful JSON response.
+fn json(response: Response, url: &str) -> io::Result<Value> {
+    if !response.status.is_success() {
+        return Err(invalid(format!("{} answered {}", url, response.status)));
+    }
+    serde_json::from_slice(&response.body)
+        .map_err(|error| invalid(format!("{} did not answer JSON: {}", url, error)))
+}
+
+/// Sends a request to the provider on a new connection.
+///
+/// # Errors
+///
+/// Returns [`io::ErrorKind::InvalidInput`] for a URL other than `https` or `http` on a
+/// loopback address, [`io::ErrorKind::TimedOut`] after [`TIMEOUT`], and captures IO errors and
+/// malformed responses from the exchange.
+async fn fetch(
+    tls: &TlsConnector,
+    method: Method,
+    url: &str,
+    headers: &[(&str, &str)],
+    body: &[u8],
+) -> io::Result<Response> {
+    let uri: Uri = url.parse()?;
+    let unsupported = || {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("{} is neither https nor http on a loopback address", url),
+        )
+    };
+    let authority = uri.authority().ok_or_else(unsupported)?;
+    let (host, port) = match authority.rsplit_once(':') {
+        Some((host, port)) if !port.contains(']') => {
+            (host, port.parse::<u16>().map_err(|_| unsupported())?)
+        }
+        _ => (authority, 0),
+    };
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    let mut head = format!(
+        "{} {}{} HTTP/1.1\r\nHost: {}\r\nConnection: close\r\nContent-Length: {}\r\n",
+        method.as_str(),
+        match uri.path() {
+            "" => "/",
+            path => path,
+        },
+        uri.query()
+            .map(|query| format!("?{}", query))
+            .unwrap_or_default(),
+        authority,
+        body.len()
+    );
+    for (name, value) in headers {
+        head.push_str(&format!("{}: {}\r\n", name, value));
+    }
+    head.push_str("\r\n");
+    let exchange = async {
+        match uri.scheme() {
+            Some("https") => {
+                let name = ServerName::try_from(host.to_string()).map_err(|_| unsupported())?;
+                let port = if port == 0 { 443 } else { port };
+                let stream = net::TcpStream::connect((host, port)).await?;
+                send(tls.connect(name, stream).await?, &head, body).await
+            }
+            Some("http") if is_loopback(host) => {
+                let port = if port == 0 { 80 } else { port };
+                send(net::TcpStream::connect((host, port)).await?, &head, body).await
+            }
+            _ => Err(unsupported()),
+        }
+    };
+    time::timeout(TIMEOUT, exchange)
+        .await
+        .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, format!("{} timed out", url)))?
+}
+
+/// Writes a request and reads the response on a connection.
+async fn send<S>(mut stream: S, head: &str, body: &[u8]) -> io::Result<Response>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send,
+{
+    stream.write_all(head.as_bytes()).await?;
+    stream.write_all(body).await?;
+    stream.flush().await?;
+    let (response, _) =
+        read_response(&mut io::BufReader::new(stream), false, MAX_RESPONSE_SIZE).await?;
+    Ok(response)
+}
+
+/// Checks whether a host names this machine, the only one reached over plain HTTP.
+fn is_loopback(host: &str) -> bool {
+    host == "localhost"
+        || host
+            .parse::<std::net::IpAddr>()
+            .is_ok_and(|address| address.is_loopback())
+}
+
+fn invalid(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+/// Checks whether a `return_to` target stays on this site: a path starting with a single `/`,
+/// without backslashes, which browsers read like `/` so that `/\evil.example` leaves the site,
+/// and without control characters, which could split the `Location` header.
+fn local_path(path: &str) -> bool {
+    path.starts_with('/')
+        && !path.starts_with("//")
+        && !path.contains('\\')
+        && !path.chars().any(char::is_control)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::Authenticator;
+    use ring::rand::SystemRandom;
+    use ring::signature::{EcdsaKeyPair, KeyPair, ECDSA_P256_SHA256_FIXED_SIGNING};
+    use tokio::io::AsyncReadExt;
+
+    /// Starts a provider issuing ID tokens with a P-256 key for the nonce shared with the
+    /// test.
+    async fn provider(nonce: Arc<Mutex<String>>) -> String {
+        let listener = net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let issuer = format!("http://{}", listener.local_addr().unwrap());
+        let random = SystemRandom::new();
+        let pkcs8 =
+            EcdsaKeyPair::generate_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, &random).unwrap();
+        let key =
+            EcdsaKeyPair::from_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, pkcs8.as_ref(), &random)
+                .unwrap();
+        let point = key.public_key().as_ref();
+        let jwks = serde_json::json!({"keys": [{
+            "kty": "EC", "crv": "P-256", "kid": "k1",
+            "x": URL_SAFE_NO_PAD.encode(&point[1..33]),
+            "y": URL_SAFE_NO_PAD.encode(&point[33..]),
+        }]});
+        let discovery = serde_json::json!({
+            "issuer": issuer,
+            "authorization_endpoint": format!("{}/authorize", issuer),
+            "token_endpoint": format!("{}/token", issuer),
+            "jwks_uri": format!("{}/jwks", issuer),
+        });
+        let issued = issuer.clone();
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                let mut stream = io::BufReader::new(stream);
+                let request = Request::read_from(&mut stream).await.unwrap();
+                let mut body = vec![0; request.content_length() as usize];
+                stream.read_exact(&mut body).await.unwrap();
+                let body = String::from_utf8(body).unwrap();
+                let answer = match request.path() {
+                    "/.well-known/openid-configuration" => discovery.clone(),
+                    "/jwks" => jwks.clone(),
+                    "/token" if body.contains("code=good") && body.contains("code_verifier=") => {
+                        let header = serde_json::json!({"alg": "ES256", "kid": "k1"});
+                        let exp = SystemTime::now().duration_since(UNIX_EPOCH).unwrap() + TIMEOUT;
+                        let claims = serde_json::json!({
+                            "iss": issued, "aud": "app", "sub": "u-42",
+                            "email": "ada@example.org", "exp": exp.as_secs(),
+                            "nonce": nonce.lock().unwrap().clone(),
+                        });
+                        let message = format!(
+                            "{}.{}",
+                            URL_SAFE_NO_PAD.encode(header.to_string()),
+                            URL_SAFE_NO_PAD.encode(claims.to_string())
+                        );
+                        let signature = key.sign(&random, message.as_bytes()).unwrap();
+                        let token = format!("{}.{}", message, URL_SAFE_NO_PAD.encode(signature));
+                        serde_json::json!({"id_token": token, "token_type": "Bearer"})
+                    }
+                    _ => serde_json::json!({"error": "invalid_grant"}),
+                };
+                let response = Response::new(StatusCode::OK, answer.to_string());
+                let _ = stream.get_mut().write_all(&response.to_bytes()).await;
+            }
+        });
+        issuer
+    }
+
+    /// Discovers the test provider, returning the nonce it signs into ID tokens, the session
+    /// store, the client, and a router with its routes.
+    async fn client() -> (Arc<Mutex<String>>, SessionStore, Arc<Oidc>, Router) {
+        let nonce = Arc::new(Mutex::new(String::new()));
+        let issuer = provider(Arc::clone(&nonce)).await;
+        let sessions = SessionStore::new();
+        let config = OidcConfig::new(issuer.as_str(), "app", "s3cret", "https://app.example/cb");
+        let oidc = Arc::new(Oidc::discover(config, sessions.clone()).await.unwrap());
+        let router = Router::new().with_oidc(Arc::clone(&oidc));
+        (nonce, sessions, oidc, router)
+    }
+
+    async fn get(router: &Router, target: &str, cookie: Option<&str>) -> Response {
+        let mut request = Request {
+            method: Method::Get,
+            target: target.parse().unwrap(),
+            ..Request::default()
+        };
+        if let Some(cookie) = cookie {
+            request.headers.append("Cookie", cookie);
+        }
+        let handler = router.find(&Method::Get, request.path()).unwrap();
+        handler.call(request).await.unwrap()
+    }
+
+    fn cookie(response: &Response) -> String {
+        let value = response.header("Set-Cookie").unwrap();
+        value.split(';').next().unwrap().to_string()
+    }
+
+    /// Starts a login, returning its session cookie and state after sharing its nonce with the
+    /// provider.
+    async fn start(router: &Router, nonce: &Mutex<String>, return_to: &str) -> (String, String) {
+        let target = format!("/login?return_to={}", return_to);
+        let response = get(router, &target, None).await;
+        let location: Uri = response.header("Location").unwrap().parse().unwrap();
+        *nonce.lock().unwrap() = location.query_param("nonce").unwrap();
+        (cookie(&response), location.query_param("state").unwrap())
+    }
+
+    /// It sends the browser to the authorization endpoint with a PKCE challenge
+    #[tokio::test]
+    async fn login() {
+        let (_, _, _, router) = client().await;
+        let response = get(&router, "/login?return_to=/reports", None).await;
+        assert_eq!(StatusCode::FOUND, response.status);
+        assert!(response.header("Set-Cookie").is_some());
+        let location: Uri = response.header("Location").unwrap().parse().unwrap();
+        assert_eq!("/authorize", location.path());
+        assert_eq!(
+            Some("https://app.example/cb"),
+            location.query_param("redirect_uri").as_deref()
+        );
+        assert_eq!(
+            Some("S256"),
+            location.query_param("code_challenge_method").as_deref()
+        );
+        assert!(location.query_param("state").is_some());
+        assert!(location.query_param("nonce").is_some());
+    }
+
+    /// It only returns to local paths after a login
+    #[test]
+    fn return_to() {
+        assert!(local_path("/reports?year=2026"));
+        assert!(!local_path("//evil.example"));
+        assert!(!local_path("/\\evil.example"));
+        assert!(!local_path("/\r\nSet-Cookie: a=b"));
+    }
+
+    /// It refuses callbacks without a login in progress or with another state
+    #[tokio::test]
+    async fn state() {
+        let (nonce, _, _, router) = client().await;
+        let unknown = get(&router, "/cb?code=good&state=forged", None).await;
+        assert_eq!(StatusCode::BAD_REQUEST, unknown.status);
+        let (login_cookie, _) = start(&router, &nonce, "/").await;
+        let forged = get(&router, "/cb?code=good&state=forged", Some(&login_cookie)).await;
+        assert_eq!(StatusCode::BAD_REQUEST, forged.status);
+    }
+
+    /// It answers 502 when the provider refuses to exchange the code
+    #[tokio::test]
+    async fn exchange_failure() {
+        let (nonce, _, _, router) = client().await;
+        let (login_cookie, state) = start(&router, &nonce, "/").await;
+        let target = format!("/cb?code=bad&state={}", state);
+        let response = get(&router, &target, Some(&login_cookie)).await;
+        assert_eq!(StatusCode::BAD_GATEWAY, response.status);
+    }
+
+    /// It answers 401 for an ID token issued for another nonce
+    #[tokio::test]
+    async fn invalid_token() {
+        let (nonce, _, _, router) = client().await;
+        let (login_cookie, state) = start(&router, &nonce, "/").await;
+        *nonce.lock().unwrap() = "replayed".to_string();
+        let target = format!("/cb?code=good&state={}", state);
+        let response = get(&router, &target, Some(&login_cookie)).await;
+        assert_eq!(StatusCode::UNAUTHORIZED, response.status);
+    }
+
+    /// It validates the ID token at the callback and authenticates the session afterwards
+    #[tokio::test]
+    async fn callback() {
+        let (nonce, sessions, oidc, router) = client().await;
+        let (login_cookie, state) = start(&router, &nonce, "//evil.example").await;
+        let target = format!("/cb?code=good&state={}", state);
+        let response = get(&router, &target, Some(&login_cookie)).await;
+        assert_eq!(StatusCode::FOUND, response.status);
+        assert_eq!(Some("/"), response.header("Location"));
+        let session_cookie = cookie(&response);
+        assert_ne!(login_cookie, session_cookie);
+
+        let authenticator = Authenticator::new("app")
+            .with_provider(Arc::clone(&oidc))
+            .with_sessions(sessions);
+        let mut request = Request::default();
+        request.headers.append("Cookie", session_cookie);
+        let identity = authenticator.authenticate(&request).await.unwrap().unwrap();
+        let identity = identity.unwrap();
+        assert_eq!("u-42", identity.subject);
+        assert_eq!(
+            Some("ada@example.org"),
+            identity.attributes.get("email").map(String::as_str)
+        );
+    }
+
+    /// It forgets the least recently used identities beyond its capacity
+    #[test]
+    fn identities() {
+        let mut identities = Identities::new(2);
+        identities.insert(Identity::new("a"));
+        identities.insert(Identity::new("b"));
+        assert!(identities.get("a").is_some());
+        identities.insert(Identity::new("c"));
+        assert_eq!(2, identities.entries.len());
+        assert!(identities.get("b").is_none());
+        assert!(identities.get("a").is_some());
+        assert!(identities.get("c").is_some());
+        identities.insert(Identity::new("c").with_role("admin"));
+        assert_eq!(2, identities.entries.len());
+        assert!(identities.get("c").unwrap().has_role("admin"));
+    }
+}
@@ -0,0 +1,92 @@
+use crate::StreamAdapter;
+use bytes::{Bytes, BytesMut};
+use tokio::io;
+
+/// Reads a request body of known length from a stream without buffering all of it.
+pub struct BodyReader<'a> {
+    stream: &'a mut dyn StreamAdapter,
+    remaining: u64,
+}
+
+impl<'a> BodyReader<'a> {
+    /// Creates a reader for the next `length` bytes of `stream`.
+    ///
+    /// # Arguments
+    ///
+    /// * `stream`: A stream positioned right after the request head.
+    /// * `length`: The body length announced by `Content-Length`.
+    ///
+    /// # Returns
+    ///
+    /// A reader that stops after `length` bytes.
+    pub fn new(stream: &'a mut dyn StreamAdapter, length: u64) -> BodyReader<'a> {
+        BodyReader {
+            stream,
+            remaining: length,
+        }
+    }
+
+    /// The number of body bytes not read yet.
+    pub fn remaining(&self) -> u64 {
+        self.remaining
+    }
+
+    /// Reads the next chunk of the body.
+    ///
+    /// # Arguments
+    ///
+    /// * `buf`: The buffer to fill.
+    ///
+    /// # Returns
+    ///
+    /// The number of bytes read, or zero once the whole body was read.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`io::ErrorKind::UnexpectedEof`] when the stream ends before the announced length,
+    /// and captures IO errors from reading the stream.
+    pub async fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.remaining == 0 || buf.is_empty() {
+            return Ok(0);
+        }
+        let limit = buf
+            .len()
+            .min(usize::try_from(self.remaining).unwrap_or(usize::MAX));
+        let count = self.stream.read_body(&mut buf[..limit]).await?;
+        if count == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "stream ended before the announced Content-Length",
+            ));
+        }
+        self.remaining -= count as u64;
+        Ok(count)
+    }
+
+    /// Reads the rest of the body into memory.
+    ///
+    /// # Returns
+    ///
+    /// The body bytes not read yet.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`io::ErrorKind::UnexpectedEof`] when the stream ends before the announced length,
+    /// [`io::ErrorKind::InvalidInput`] when the length does not fit into memory, and captures IO
+    /// errors from reading the stream.
+    pub async fn read_all(mut self) -> io::Result<Bytes> {
+        let length = usize::try_from(self.remaining).map_err(|_| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "body too large to read into memory",
+            )
+        })?;
+        // Reading straight into the final allocation lets handlers slice it without copies
+        let mut body = BytesMut::zeroed(length);
+        let mut filled = 0;
+        while filled < length {
+            filled += self.read(&mut body[filled..]).await?;
+        }
+        Ok(body.freeze())
+    }
+}
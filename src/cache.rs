@@ -0,0 +1,611 @@
+use crate::header::HeaderMap;
+use crate::method::Method;
+use crate::request::Request;
+use crate::response::Response;
+use crate::router::{Handler, Router};
+use crate::status::StatusCode;
+use async_trait::async_trait;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, Instant, SystemTime};
+use tokio::io;
+
+/// Statuses a shared cache may store without being told so explicitly.
+const CACHEABLE: [StatusCode; 7] = [
+    StatusCode::OK,
+    StatusCode::NON_AUTHORITATIVE_INFORMATION,
+    StatusCode::NO_CONTENT,
+    StatusCode::MULTIPLE_CHOICES,
+    StatusCode::MOVED_PERMANENTLY,
+    StatusCode::NOT_FOUND,
+    StatusCode::GONE,
+];
+
+/// A hint a handler attaches to its response with [`Response::with_extension`] to tell the
+/// [`ResponseCache`] how to store it whatever its `Cache-Control` header says to other caches,
+/// e.g. to cache a page that browsers must revalidate. Statuses that are not cacheable by default
+/// and responses setting cookies are still never stored.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CacheHint {
+    /// Never store the response.
+    NoStore,
+    /// Store the response for this long.
+    MaxAge(Duration),
+}
+
+/// A bounded in-memory cache of complete responses to `GET` requests, shared by every handler
+/// wrapped through [`Router::cached`].
+///
+/// Responses are stored when their `Cache-Control` header gives them a lifetime through
+/// `s-maxage` or `max-age`, or when they carry `Expires`, and never when they are `private`,
+/// `no-store`, `no-cache`, set cookies, or vary on `*`. Entries are keyed by method, target,
+/// and the values of the request headers named in `Vary`. The least recently used entry is
+/// evicted once the capacity is reached.
+#[derive(Clone, Debug)]
+pub struct ResponseCache {
+    store: Arc<Mutex<Store>>,
+}
+
+/// Counters describing how well a [`ResponseCache`] performs.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct CacheStats {
+    /// Requests answered from the cache.
+    pub hits: u64,
+    /// Cacheable requests passed on to the handler.
+    pub misses: u64,
+    /// Entries evicted to make room for new ones.
+    pub evictions: u64,
+    /// Entries currently stored.
+    pub entries: usize,
+}
+
+impl CacheStats {
+    /// The share of cacheable requests answered from the cache.
+    ///
+    /// # Returns
+    ///
+    /// A value between 0 and 1, or 0 before the first request.
+    pub fn hit_rate(&self) -> f64 {
+        let total = self.hits + self.misses;
+        if total == 0 {
+            0.0
+        } else {
+            self.hits as f64 / total as f64
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Store {
+    capacity: usize,
+    /// The request header names a target varies on, taken from its latest stored response.
+    vary: HashMap<String, Vec<String>>,
+    entries: HashMap<String, Entry>,
+    /// Keys by the tick they were last used at, oldest first.
+    recency: BTreeMap<u64, String>,
+    tick: u64,
+    stats: CacheStats,
+}
+
+#[derive(Debug)]
+struct Entry {
+    path: String,
+    response: Response,
+    stored: Instant,
+    expires: Instant,
+    tick: u64,
+}
+
+impl ResponseCache {
+    /// Creates an empty cache.
+    ///
+    /// # Arguments
+    ///
+    /// * `capacity`: The number of responses kept, counting each `Vary` variant separately.
+    ///
+    /// # Returns
+    ///
+    /// A cache without entries.
+    pub fn new(capacity: usize) -> ResponseCache {
+        ResponseCache {
+            store: Arc::new(Mutex::new(Store {
+                capacity,
+                vary: HashMap::new(),
+                entries: HashMap::new(),
+                recency: BTreeMap::new(),
+                tick: 0,
+                stats: CacheStats::default(),
+            })),
+        }
+    }
+
+    /// The counters collected so far.
+    pub fn stats(&self) -> CacheStats {
+        let store = self.lock();
+        CacheStats {
+            entries: store.entries.len(),
+            ..store.stats
+        }
+    }
+
+    /// Removes every stored response for a path, whatever its query or variant.
+    ///
+    /// # Arguments
+    ///
+    /// * `path`: The percent-encoded request path, e.g. `/articles/1`.
+    ///
+    /// # Returns
+    ///
+    /// The number of removed entries.
+    pub fn purge(&self, path: &str) -> usize {
+        let mut store = self.lock();
+        let keys: Vec<String> = store
+            .entries
+            .iter()
+            .filter(|(_, entry)| entry.path == path)
+            .map(|(key, _)| key.clone())
+            .collect();
+        for key in &keys {
+            store.remove(key);
+        }
+        keys.len()
+    }
+
+    /// Removes every stored response.
+    pub fn purge_all(&self) {
+        let mut store = self.lock();
+        store.vary.clear();
+        store.entries.clear();
+        store.recency.clear();
+    }
+
+    /// Finds a fresh stored response for a request.
+    ///
+    /// # Arguments
+    ///
+    /// * `request`: The request, which must be cacheable, see [`cacheable_request`].
+    ///
+    /// # Returns
+    ///
+    /// A copy of the stored response with an `Age` header, or `None` after counting a miss.
+    fn lookup(&self, request: &Request) -> Option<Response> {
+        let mut store = self.lock();
+        let base = base_key(request);
+        let names = store.vary.get(&base).cloned().unwrap_or_default();
+        let key = variant_key(base, &names, request);
+        let now = Instant::now();
+        let fresh = match store.entries.get(&key) {
+            Some(entry) => entry.expires > now,
+            None => false,
+        };
+        if !fresh {
+            store.remove(&key);
+            store.stats.misses += 1;
+            return None;
+        }
+        store.stats.hits += 1;
+        let tick = store.next_tick();
+        let entry = store.entries.get_mut(&key)?;
+        let previous = std::mem::replace(&mut entry.tick, tick);
+        let response = entry.response.clone().with_header(
+            "Age",
+            now.duration_since(entry.stored).as_secs().to_string(),
+        );
+        store.recency.remove(&previous);
+        store.recency.insert(tick, key);
+        Some(response)
+    }
+
+    /// Stores a response when it may be reused for later requests.
+    ///
+    /// # Arguments
+    ///
+    /// * `request`: The request the response answers.
+    /// * `response`: The response from the wrapped handler.
+    fn insert(&self, request: &Request, response: &Response) {
+        let Some((lifetime, names)) = storable(response) else {
+            return;
+        };
+        let mut store = self.lock();
+        if store.capacity == 0 {
+            return;
+        }
+        let base = base_key(request);
+        let key = variant_key(base.clone(), &names, request);
+        store.vary.insert(base, names);
+        store.remove(&key);
+        while store.entries.len() >= store.capacity {
+            let Some((_, oldest)) = store.recency.pop_first() else {
+                break;
+            };
+            store.remove(&oldest);
+            store.stats.evictions += 1;
+        }
+        let tick = store.next_tick();
+        let stored = Instant::now();
+        store.recency.insert(tick, key.clone());
+        store.entries.insert(
+            key,
+            Entry {
+                path: request.path().to_string(),
+                response: response.clone(),
+                stored,
+                expires: stored + lifetime,
+                tick,
+            },
+        );
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Store> {
+        // Every update leaves the store consistent, so a panic elsewhere does not corrupt it
+        self.store
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+impl Store {
+    fn next_tick(&mut self) -> u64 {
+        self.tick += 1;
+        self.tick
+    }
+
+    fn remove(&mut self, key: &str) {
+        if let Some(entry) = self.entries.remove(key) {
+            self.recency.remove(&entry.tick);
+        }
+    }
+}
+
+impl Router {
+    /// Wraps every handler registered so far in a response cache. Handlers registered
+    /// afterwards are not wrapped, so caching only applies to the routes above it.
+    ///
+    /// # Arguments
+    ///
+    /// * `cache`: The cache, which may be shared with other routers and kept to read its
+    ///   [`ResponseCache::stats`] or purge entries.
+    ///
+    /// # Returns
+    ///
+    /// The router with cached handlers.
+    pub fn cached(self, cache: &ResponseCache) -> Router {
+        self.map_handlers("cache", |handler| {
+            Arc::new(CachedHandler {
+                handler,
+                cache: cache.clone(),
+            })
+        })
+    }
+}
+
+/// Answers cacheable requests from a [`ResponseCache`] and fills it from a wrapped handler.
+struct CachedHandler {
+    handler: Arc<dyn Handler>,
+    cache: ResponseCache,
+}
+
+#[async_trait]
+impl Handler for CachedHandler {
+    async fn call(&self, request: Request) -> io::Result<Response> {
+        if !cacheable_request(&request) {
+            return self.handler.call(request).await;
+        }
+        let revalidate = has_directive(&request, "no-cache");
+        if !revalidate {
+            if let Some(response) = self.cache.lookup(&request) {
+                return Ok(response);
+            }
+        }
+        let key = request.clone();
+        let response = self.handler.call(request).await?;
+        self.cache.insert(&key, &response);
+        Ok(response)
+    }
+}
+
+/// Checks whether a request may be answered from and stored in a shared cache: a `GET` without
+/// credentials or `Cache-Control: no-store`.
+///
+/// # Arguments
+///
+/// * `request`: The request.
+///
+/// # Returns
+///
+/// `true` when the cache may be used.
+pub(crate) fn cacheable_request(request: &Request) -> bool {
+    request.method == Method::Get
+        && !request.headers.contains("Authorization")
+        && !has_directive(request, "no-store")
+}
+
+/// Checks a request's `Cache-Control` header for a directive without a value.
+///
+/// # Arguments
+///
+/// * `request`: The request.
+/// * `name`: The directive, e.g. `no-cache`.
+///
+/// # Returns
+///
+/// `true` when the directive is listed.
+pub(crate) fn has_directive(request: &Request, name: &str) -> bool {
+    request.headers.has_token("Cache-Control", name)
+}
+
+/// Checks whether a shared cache may store a response.
+///
+/// # Arguments
+///
+/// * `response`: The response.
+///
+/// # Returns
+///
+/// How long the response stays fresh and the request header names it varies on, or `None`
+/// when the response must not be stored.
+pub(crate) fn storable(response: &Response) -> Option<(Duration, Vec<String>)> {
+    if response.headers.contains("Set-Cookie") {
+        return None;
+    }
+    let lifetime = lifetime(response)?;
+    match response.merged_vary() {
+        Some(vary) if vary == "*" => None,
+        Some(vary) => Some((
+            lifetime,
+            vary.split(',')
+                .map(|name| name.trim().to_string())
+                .collect(),
+        )),
+        None => Some((lifetime, Vec::new())),
+    }
+}
+
+/// Lists the directives of `Cache-Control` headers.
+///
+/// # Arguments
+///
+/// * `headers`: The request or response headers.
+///
+/// # Returns
+///
+/// Lowercased directive names with their unquoted values, if any.
+pub(crate) fn directives(headers: &HeaderMap) -> impl Iterator<Item = (String, Option<&str>)> {
+    headers
+        .get_all("Cache-Control")
+        .flat_map(|value| value.split(','))
+        .map(|directive| match directive.split_once('=') {
+            Some((name, value)) => (
+                name.trim().to_ascii_lowercase(),
+                Some(value.trim().trim_matches('"')),
+            ),
+            None => (directive.trim().to_ascii_lowercase(), None),
+        })
+}
+
+/// Determines how long a response stays fresh in a shared cache.
+///
+/// # Arguments
+///
+/// * `response`: The response.
+///
+/// # Returns
+///
+/// The lifetime from a [`CacheHint`], `s-maxage`, `max-age`, or `Expires` in that order, or
+/// `None` when the response must not be stored.
+fn lifetime(response: &Response) -> Option<Duration> {
+    if !CACHEABLE.contains(&response.status) {
+        return None;
+    }
+    match response.extensions().get::<CacheHint>() {
+        Some(CacheHint::NoStore) => return None,
+        Some(CacheHint::MaxAge(lifetime)) => return Some(*lifetime).filter(|l| !l.is_zero()),
+        None => {}
+    }
+    let mut max_age = None;
+    let mut shared_max_age = None;
+    for (name, value) in directives(&response.headers) {
+        let seconds = value.and_then(|value| value.parse().ok());
+        match name.as_str() {
+            "no-store" | "no-cache" | "private" => return None,
+            "max-age" => max_age = seconds,
+            "s-maxage" => shared_max_age = seconds,
+            _ => {}
+        }
+    }
+    if let Some(seconds) = shared_max_age.or(max_age) {
+        return Some(Duration::from_secs(seconds)).filter(|lifetime| !lifetime.is_zero());
+    }
+    let expires = httpdate::parse_http_date(response.header("Expires")?).ok()?;
+    expires.duration_since(SystemTime::now()).ok()
+}
+
+/// Keys a request by its method, host, path, and query, so virtual hosts and proxied sites do
+/// not share entries.
+///
+/// # Arguments
+///
+/// * `request`: The request.
+///
+/// # Returns
+///
+/// A key like `GET example.com /articles?page=2`, with the host from an absolute-form target or
+/// else `Host`, lowercased.
+fn base_key(request: &Request) -> String {
+    let host = request
+        .target
+        .authority()
+        .or_else(|| request.headers.host())
+        .unwrap_or_default()
+        .to_ascii_lowercase();
+    let mut key = format!("{} {} {}", request.method, host, request.target.path());
+    if let Some(query) = request.target.query() {
+        key.push('?');
+        key.push_str(query);
+    }
+    key
+}
+
+/// Extends a key with the request's values of the headers a response varies on.
+///
+/// # Arguments
+///
+/// * `base`: The key from [`base_key`].
+/// * `names`: The `Vary` header names.
+/// * `request`: The request.
+///
+/// # Returns
+///
+/// A key that differs for requests that may receive different responses.
+fn variant_key(mut base: String, names: &[String], request: &Request) -> String {
+    for name in names {
+        base.push('\n');
+        base.push_str(&request.headers.get_all(name).collect::<Vec<_>>().join(", "));
+    }
+    base
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    fn request(target: &str, headers: &[(&str, &str)]) -> Request {
+        Request {
+            method: Method::Get,
+            target: target.parse().unwrap(),
+            headers: headers.iter().copied().collect(),
+            ..Request::default()
+        }
+    }
+
+    fn counting_router(cache: &ResponseCache, calls: &Arc<AtomicUsize>) -> Router {
+        let calls = Arc::clone(calls);
+        Router::new()
+            .route(Method::Get, "/page", move |request: Request| {
+                let calls = Arc::clone(&calls);
+                async move {
+                    let count = calls.fetch_add(1, Ordering::SeqCst);
+                    let language = request
+                        .header("Accept-Language")
+                        .unwrap_or("en")
+                        .to_string();
+                    let control = if request.target.query() == Some("private") {
+                        "private, max-age=60"
+                    } else {
+                        "max-age=60"
+                    };
+                    Ok(
+                        Response::new(StatusCode::OK, format!("{} {}", language, count))
+                            .with_header("Cache-Control", control)
+                            .with_vary("Accept-Language"),
+                    )
+                }
+            })
+            .cached(cache)
+    }
+
+    async fn body(router: &Router, request: Request) -> String {
+        let response = router.dispatch(&request).await.unwrap().unwrap();
+        String::from_utf8(response.body.to_vec()).unwrap()
+    }
+
+    /// It replays fresh responses per `Vary` variant and counts hits and misses
+    #[tokio::test]
+    async fn hits() {
+        let cache = ResponseCache::new(8);
+        let calls = Arc::new(AtomicUsize::new(0));
+        let router = counting_router(&cache, &calls);
+        assert_eq!("en 0", body(&router, request("/page", &[])).await);
+        assert_eq!("en 0", body(&router, request("/page", &[])).await);
+        let german = [("Accept-Language", "de")];
+        assert_eq!("de 1", body(&router, request("/page", &german)).await);
+        assert_eq!("de 1", body(&router, request("/page", &german)).await);
+        let response = router
+            .dispatch(&request("/page", &[]))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(Some("0"), response.header("Age"));
+        let stats = cache.stats();
+        assert_eq!((3, 2, 2), (stats.hits, stats.misses, stats.entries));
+        assert_eq!(0.6, stats.hit_rate());
+    }
+
+    /// It keeps the responses of different hosts apart
+    #[tokio::test]
+    async fn hosts() {
+        let cache = ResponseCache::new(8);
+        let calls = Arc::new(AtomicUsize::new(0));
+        let router = counting_router(&cache, &calls);
+        let first = [("Host", "a.example")];
+        assert_eq!("en 0", body(&router, request("/page", &first)).await);
+        let second = [("Host", "b.example")];
+        assert_eq!("en 1", body(&router, request("/page", &second)).await);
+        let upper = [("Host", "A.Example")];
+        assert_eq!("en 0", body(&router, request("/page", &upper)).await);
+        let absolute = request("http://b.example/page", &first);
+        assert_eq!("en 1", body(&router, absolute).await);
+    }
+
+    /// It bypasses the cache for private responses and requests that ask for fresh content
+    #[tokio::test]
+    async fn cache_control() {
+        let cache = ResponseCache::new(8);
+        let calls = Arc::new(AtomicUsize::new(0));
+        let router = counting_router(&cache, &calls);
+        assert_eq!("en 0", body(&router, request("/page?private", &[])).await);
+        assert_eq!("en 1", body(&router, request("/page?private", &[])).await);
+        assert_eq!("en 2", body(&router, request("/page", &[])).await);
+        let no_cache = [("Cache-Control", "no-cache")];
+        assert_eq!("en 3", body(&router, request("/page", &no_cache)).await);
+        assert_eq!("en 3", body(&router, request("/page", &[])).await);
+        let no_store = [("Cache-Control", "no-store")];
+        assert_eq!("en 4", body(&router, request("/page", &no_store)).await);
+    }
+
+    /// It stores responses as the hints of their handlers say rather than their headers
+    #[tokio::test]
+    async fn cache_hint() {
+        let cache = ResponseCache::new(8);
+        let calls = Arc::new(AtomicUsize::new(0));
+        let counted = Arc::clone(&calls);
+        let router = Router::new()
+            .route(Method::Get, "/page", move |request: Request| {
+                let count = counted.fetch_add(1, Ordering::SeqCst);
+                let hint = match request.target.query() {
+                    Some("no-store") => CacheHint::NoStore,
+                    _ => CacheHint::MaxAge(Duration::from_secs(60)),
+                };
+                async move {
+                    Ok(Response::new(StatusCode::OK, count.to_string())
+                        .with_header("Cache-Control", "no-cache")
+                        .with_extension(hint))
+                }
+            })
+            .cached(&cache);
+        assert_eq!("0", body(&router, request("/page", &[])).await);
+        assert_eq!("0", body(&router, request("/page", &[])).await);
+        assert_eq!("1", body(&router, request("/page?no-store", &[])).await);
+        assert_eq!("2", body(&router, request("/page?no-store", &[])).await);
+    }
+
+    /// It evicts the least recently used entry and purges entries by path
+    #[tokio::test]
+    async fn eviction() {
+        let cache = ResponseCache::new(2);
+        let calls = Arc::new(AtomicUsize::new(0));
+        let router = counting_router(&cache, &calls);
+        assert_eq!("en 0", body(&router, request("/page?a", &[])).await);
+        assert_eq!("en 1", body(&router, request("/page?b", &[])).await);
+        assert_eq!("en 0", body(&router, request("/page?a", &[])).await);
+        assert_eq!("en 2", body(&router, request("/page?c", &[])).await);
+        assert_eq!("en 0", body(&router, request("/page?a", &[])).await);
+        assert_eq!("en 3", body(&router, request("/page?b", &[])).await);
+        assert_eq!(2, cache.stats().evictions);
+        assert_eq!(2, cache.purge("/page"));
+        assert_eq!("en 4", body(&router, request("/page?a", &[])).await);
+        cache.purge_all();
+        assert_eq!(0, cache.stats().entries);
+    }
+}
@@ -0,0 +1,200 @@
+//! Canary routing: requests carrying a chosen header or cookie go to an alternate handler, e.g.
+//! the upstreams of a new backend version, while everything else keeps going to the stable one.
+
+use crate::log;
+use crate::request::Request;
+use crate::response::Response;
+use crate::router::Handler;
+use async_trait::async_trait;
+use std::fmt;
+use std::str::FromStr;
+use std::sync::Arc;
+use tokio::io;
+
+/// What marks a request for the canary.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum CanaryRule {
+    /// A header with this name, and this value when given, e.g. `X-Canary: 1`.
+    Header {
+        /// The header name, compared case-insensitively.
+        name: String,
+        /// The value, compared exactly, or `None` for any value.
+        value: Option<String>,
+    },
+    /// A cookie with this name, and this value when given, e.g. `canary=1`.
+    Cookie {
+        /// The cookie name, compared exactly.
+        name: String,
+        /// The value, compared exactly, or `None` for any value.
+        value: Option<String>,
+    },
+}
+
+impl CanaryRule {
+    /// Parses a rule for a header from `name=value` or `name`, e.g. `X-Canary=1`.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`io::ErrorKind::InvalidInput`] for an empty name.
+    pub fn header(rule: &str) -> io::Result<CanaryRule> {
+        let (name, value) = split(rule)?;
+        Ok(CanaryRule::Header { name, value })
+    }
+
+    /// Parses a rule for a cookie from `name=value` or `name`, e.g. `canary=1`.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`io::ErrorKind::InvalidInput`] for an empty name.
+    pub fn cookie(rule: &str) -> io::Result<CanaryRule> {
+        let (name, value) = split(rule)?;
+        Ok(CanaryRule::Cookie { name, value })
+    }
+
+    /// Checks whether a request is marked for the canary.
+    pub fn matches(&self, request: &Request) -> bool {
+        let (found, value) = match self {
+            CanaryRule::Header { name, value } => (request.headers.get(name), value),
+            CanaryRule::Cookie { name, value } => (request.cookie(name), value),
+        };
+        match (found, value) {
+            (Some(found), Some(value)) => found.trim() == value,
+            (found, None) => found.is_some(),
+            (None, Some(_)) => false,
+        }
+    }
+}
+
+/// Splits `name=value` or `name` into its parts.
+fn split(rule: &str) -> io::Result<(String, Option<String>)> {
+    let (name, value) = match rule.split_once('=') {
+        Some((name, value)) => (name.trim(), Some(value.trim().to_string())),
+        None => (rule.trim(), None),
+    };
+    if name.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("invalid canary rule: {}", rule),
+        ));
+    }
+    Ok((name.to_string(), value))
+}
+
+impl FromStr for CanaryRule {
+    type Err = io::Error;
+
+    /// Parses `header:<name>[=<value>]` or `cookie:<name>[=<value>]`.
+    fn from_str(rule: &str) -> io::Result<CanaryRule> {
+        if let Some(rule) = rule.strip_prefix("header:") {
+            CanaryRule::header(rule)
+        } else if let Some(rule) = rule.strip_prefix("cookie:") {
+            CanaryRule::cookie(rule)
+        } else {
+            Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("invalid canary rule: {}", rule),
+            ))
+        }
+    }
+}
+
+impl fmt::Display for CanaryRule {
+    fn fmt(&self, formatter: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let (kind, name, value) = match self {
+            CanaryRule::Header { name, value } => ("header", name, value),
+            CanaryRule::Cookie { name, value } => ("cookie", name, value),
+        };
+        write!(formatter, "{}:{}", kind, name)?;
+        if let Some(value) = value {
+            write!(formatter, "={}", value)?;
+        }
+        Ok(())
+    }
+}
+
+/// A handler passing requests matching any of its rules to a canary handler and the others to
+/// a stable one, for registering on a [`crate::router::Router`]. The reverse proxy has the same
+/// routing built in, see [`crate::proxy::Proxy::with_canary`].
+#[derive(Clone)]
+pub struct Canary {
+    rules: Vec<CanaryRule>,
+    stable: Arc<dyn Handler>,
+    canary: Arc<dyn Handler>,
+}
+
+impl Canary {
+    /// Creates a canary without rules, which sends every request to the stable handler.
+    ///
+    /// # Arguments
+    ///
+    /// * `stable`: The handler for unmarked requests.
+    /// * `canary`: The handler for requests matching a rule.
+    ///
+    /// # Returns
+    ///
+    /// The canary handler.
+    pub fn new(stable: impl Handler + 'static, canary: impl Handler + 'static) -> Canary {
+        Canary {
+            rules: Vec::new(),
+            stable: Arc::new(stable),
+            canary: Arc::new(canary),
+        }
+    }
+
+    /// Sends the requests matching a rule to the canary handler.
+    pub fn with_rule(mut self, rule: CanaryRule) -> Canary {
+        self.rules.push(rule);
+        self
+    }
+
+    /// Checks whether a request goes to the canary handler.
+    pub fn matches(&self, request: &Request) -> bool {
+        self.rules.iter().any(|rule| rule.matches(request))
+    }
+}
+
+#[async_trait]
+impl Handler for Canary {
+    async fn call(&self, request: Request) -> io::Result<Response> {
+        if self.matches(&request) {
+            log::record("canary", true);
+            self.canary.call(request).await
+        } else {
+            self.stable.call(request).await
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::status::StatusCode;
+
+    /// It sends requests with a matching header or cookie to the canary handler
+    #[tokio::test]
+    async fn canary() {
+        let canary = Canary::new(
+            |_| async { Ok(Response::new(StatusCode::OK, "stable")) },
+            |_| async { Ok(Response::new(StatusCode::OK, "canary")) },
+        )
+        .with_rule("header:X-Canary=1".parse().unwrap())
+        .with_rule("cookie:beta".parse().unwrap());
+        let call = |headers: &[(&str, &str)]| {
+            let request = Request {
+                headers: headers.iter().copied().collect(),
+                ..Request::default()
+            };
+            let canary = canary.clone();
+            async move { canary.call(request).await.unwrap().body }
+        };
+        assert_eq!("stable", call(&[]).await);
+        assert_eq!("canary", call(&[("x-canary", "1")]).await);
+        assert_eq!("stable", call(&[("X-Canary", "0")]).await);
+        assert_eq!("canary", call(&[("Cookie", "a=b; beta=")]).await);
+        assert!("query:x".parse::<CanaryRule>().is_err());
+        assert_eq!(
+            "cookie:beta",
+            CanaryRule::cookie("beta").unwrap().to_string()
+        );
+    }
+}
@@ -0,0 +1,328 @@
+//! Recording the headers and the start of the bodies of chosen exchanges, so that what a
+//! misbehaving client really sent and received can be looked at through the admin API.
+
+use crate::header::HeaderMap;
+use crate::method::Method;
+use crate::request::Request;
+use crate::response::Response;
+use crate::status::StatusCode;
+use bytes::Bytes;
+use std::collections::VecDeque;
+use std::fmt;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+
+/// Headers whose values are replaced in captures, since they carry credentials.
+const SECRET_HEADERS: [&str; 4] = [
+    "Authorization",
+    "Proxy-Authorization",
+    "Cookie",
+    "Set-Cookie",
+];
+
+/// Which exchanges are captured and how much of them is kept.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Capture {
+    /// Path prefixes whose requests are captured, e.g. `/api/orders`.
+    pub paths: Vec<String>,
+    /// Request IDs whose requests are captured, matching the `X-Request-Id` header or the
+    /// trace ID of the request.
+    pub request_ids: Vec<String>,
+    /// The bytes kept of each body, the rest is dropped.
+    pub max_body_size: usize,
+    /// The exchanges kept, the oldest is dropped for a new one.
+    pub capacity: usize,
+}
+
+impl Capture {
+    /// Creates settings capturing nothing, the first 4 KiB of each body, and up to 100 exchanges.
+    pub fn new() -> Capture {
+        Capture {
+            paths: Vec::new(),
+            request_ids: Vec::new(),
+            max_body_size: 4096,
+            capacity: 100,
+        }
+    }
+
+    /// Captures the requests below a path prefix.
+    pub fn with_path(mut self, path: impl Into<String>) -> Capture {
+        self.paths.push(path.into());
+        self
+    }
+
+    /// Captures the requests with a request ID.
+    pub fn with_request_id(mut self, id: impl Into<String>) -> Capture {
+        self.request_ids.push(id.into());
+        self
+    }
+
+    /// Checks whether a request is captured.
+    ///
+    /// # Arguments
+    ///
+    /// * `request`: The request about to be handled, with its trace context.
+    ///
+    /// # Returns
+    ///
+    /// `true` when its path is below one of [`Capture::paths`] or it carries one of
+    /// [`Capture::request_ids`].
+    pub fn matches(&self, request: &Request) -> bool {
+        let path = request.path();
+        let below = |prefix: &String| {
+            path.strip_prefix(prefix.trim_end_matches('/'))
+                .is_some_and(|rest| rest.is_empty() || rest.starts_with('/'))
+        };
+        if self.paths.iter().any(below) {
+            return true;
+        }
+        let header = request.headers.get("X-Request-Id");
+        let trace_id = request
+            .trace
+            .as_ref()
+            .map(|trace| trace.trace_id().to_string());
+        self.request_ids
+            .iter()
+            .any(|id| header == Some(id.as_str()) || trace_id.as_ref() == Some(id))
+    }
+}
+
+impl Default for Capture {
+    fn default() -> Capture {
+        Capture::new()
+    }
+}
+
+/// One captured exchange, with credentials redacted and bodies truncated.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Captured {
+    /// When the request arrived.
+    pub time: SystemTime,
+    /// The address of the client, when known.
+    pub peer: Option<SocketAddr>,
+    /// The trace ID of the request, see [`crate::trace::TraceContext`].
+    pub trace_id: Option<String>,
+    /// The request method.
+    pub method: Method,
+    /// The request target.
+    pub target: String,
+    /// The request headers.
+    pub request_headers: HeaderMap,
+    /// The start of the request body.
+    pub request_body: Bytes,
+    /// The length of the whole request body the handler read.
+    pub request_body_size: u64,
+    /// The response status.
+    pub status: StatusCode,
+    /// The response headers.
+    pub response_headers: HeaderMap,
+    /// The start of the response body.
+    pub response_body: Bytes,
+    /// The length of the whole response body.
+    pub response_body_size: usize,
+}
+
+impl Captured {
+    /// Captures an exchange.
+    ///
+    /// # Arguments
+    ///
+    /// * `time`: When the request arrived.
+    /// * `request`: The request.
+    /// * `request_body`: The start of the request body as the handler read it.
+    /// * `request_body_size`: The length of the whole request body the handler read.
+    /// * `response`: The response.
+    /// * `max_body_size`: The bytes kept of each body.
+    ///
+    /// # Returns
+    ///
+    /// The capture to pass to [`Captures::push`].
+    pub fn new(
+        time: SystemTime,
+        request: &Request,
+        request_body: Bytes,
+        request_body_size: u64,
+        response: &Response,
+        max_body_size: usize,
+    ) -> Captured {
+        Captured {
+            time,
+            peer: request.peer,
+            trace_id: request
+                .trace
+                .as_ref()
+                .map(|trace| trace.trace_id().to_string()),
+            method: request.method.clone(),
+            target: request.target.to_string(),
+            request_headers: redact(&request.headers),
+            request_body,
+            request_body_size,
+            status: response.status,
+            response_headers: redact(&response.headers),
+            response_body: response
+                .body
+                .slice(..response.body.len().min(max_body_size)),
+            response_body_size: response.body.len(),
+        }
+    }
+}
+
+/// Copies headers with the values of [`SECRET_HEADERS`] replaced.
+fn redact(headers: &HeaderMap) -> HeaderMap {
+    let mut redacted = HeaderMap::new();
+    for (name, value) in headers.iter() {
+        let secret = SECRET_HEADERS
+            .iter()
+            .any(|secret| secret.eq_ignore_ascii_case(name));
+        redacted.append(name, if secret { "[redacted]" } else { value });
+    }
+    redacted
+}
+
+impl fmt::Display for Captured {
+    /// Writes the exchange like on the wire, after a line with its time, client, and trace,
+    /// with bodies as text and a note where they were truncated.
+    fn fmt(&self, formatter: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(formatter, "# {}", httpdate::fmt_http_date(self.time))?;
+        if let Some(peer) = self.peer {
+            write!(formatter, " from {}", peer)?;
+        }
+        if let Some(trace_id) = &self.trace_id {
+            write!(formatter, " trace_id={}", trace_id)?;
+        }
+        writeln!(formatter, "\n{} {}", self.method, self.target)?;
+        write_message(
+            formatter,
+            &self.request_headers,
+            &self.request_body,
+            self.request_body_size as usize,
+        )?;
+        writeln!(formatter, "{}", self.status)?;
+        write_message(
+            formatter,
+            &self.response_headers,
+            &self.response_body,
+            self.response_body_size,
+        )
+    }
+}
+
+fn write_message(
+    formatter: &mut fmt::Formatter<'_>,
+    headers: &HeaderMap,
+    body: &[u8],
+    size: usize,
+) -> fmt::Result {
+    for (name, value) in headers.iter() {
+        writeln!(formatter, "{}: {}", name, value)?;
+    }
+    writeln!(formatter)?;
+    if !body.is_empty() {
+        writeln!(formatter, "{}", String::from_utf8_lossy(body))?;
+    }
+    if body.len() < size {
+        writeln!(formatter, "[{} of {} bytes]", body.len(), size)?;
+    }
+    Ok(())
+}
+
+/// The latest captured exchanges, shared by every clone, e.g. the copy in
+/// [`crate::config::Config::captures`] and the one the admin API reads.
+#[derive(Clone, Default)]
+pub struct Captures {
+    entries: Arc<Mutex<VecDeque<Captured>>>,
+}
+
+impl Captures {
+    /// Adds an exchange, dropping the oldest ones beyond a capacity.
+    ///
+    /// # Arguments
+    ///
+    /// * `captured`: The exchange.
+    /// * `capacity`: The exchanges kept, see [`Capture::capacity`].
+    pub fn push(&self, captured: Captured, capacity: usize) {
+        let mut entries = self.lock();
+        entries.push_back(captured);
+        while entries.len() > capacity {
+            entries.pop_front();
+        }
+    }
+
+    /// The captured exchanges, from the oldest to the latest.
+    pub fn entries(&self) -> Vec<Captured> {
+        self.lock().iter().cloned().collect()
+    }
+
+    /// Drops every captured exchange.
+    pub fn clear(&self) {
+        self.lock().clear();
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, VecDeque<Captured>> {
+        self.entries
+            .lock()
+            .unwrap_or_else(|error| error.into_inner())
+    }
+}
+
+impl fmt::Debug for Captures {
+    /// Writes the number of exchanges only, since they hold request data.
+    fn fmt(&self, formatter: &mut fmt::Formatter<'_>) -> fmt::Result {
+        formatter
+            .debug_struct("Captures")
+            .field("len", &self.lock().len())
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// It matches paths and request IDs, redacts credentials, and keeps the latest exchanges
+    #[test]
+    fn capture() {
+        let capture = Capture::new()
+            .with_path("/api/orders")
+            .with_request_id("abc");
+        let mut request = Request {
+            target: "/api/orders/7?x=1".parse().unwrap(),
+            ..Request::default()
+        };
+        request.headers.insert("Authorization", "Bearer secret");
+        assert!(capture.matches(&request));
+        request.target = "/api/ordersx".parse().unwrap();
+        assert!(!capture.matches(&request));
+        request.headers.insert("X-Request-Id", "abc");
+        assert!(capture.matches(&request));
+
+        let response = Response::new(StatusCode::OK, "hello world");
+        let captures = Captures::default();
+        for size in [1, 2, 3] {
+            let captured = Captured::new(
+                SystemTime::UNIX_EPOCH,
+                &request,
+                Bytes::from_static(b"ab"),
+                size,
+                &response,
+                5,
+            );
+            captures.push(captured, 2);
+        }
+        let entries = captures.entries();
+        assert_eq!(
+            vec![2, 3],
+            entries
+                .iter()
+                .map(|entry| entry.request_body_size)
+                .collect::<Vec<_>>()
+        );
+        let text = entries[1].to_string();
+        assert!(text.contains("Authorization: [redacted]\n"), "{}", text);
+        assert!(text.contains("ab\n[2 of 3 bytes]\n"), "{}", text);
+        assert!(text.contains("200 OK\n"), "{}", text);
+        assert!(text.contains("hello\n[5 of 11 bytes]\n"), "{}", text);
+        assert!(!format!("{:?}", captures).contains("secret"));
+    }
+}
@@ -0,0 +1,448 @@
+use crate::log;
+use crate::path::{percent_decode, resolve};
+use crate::request::Request;
+use crate::response::Response;
+use crate::router::Handler;
+use crate::status::StatusCode;
+use async_trait::async_trait;
+use bytes::Bytes;
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{self, AsyncWriteExt};
+use tokio::process::Command;
+use tokio::sync::Semaphore;
+use tokio::time;
+
+/// Request headers turned into variables under names scripts get from the gateway, or that
+/// let a client set the proxy of the application, see httpoxy.
+const RESERVED_HEADERS: [&str; 3] = ["Content-Type", "Content-Length", "Proxy"];
+
+/// A gateway running scripts in a directory as CGI/1.1 programs (RFC 3875), one process per
+/// request.
+///
+/// It is consulted right after the FastCGI gateway when present in
+/// [`crate::config::Config::cgi`], and can also be registered on a [`crate::router::Router`]
+/// like any other [`Handler`]. Every executable file in the directory can be run by any client,
+/// so only scripts meant to be served belong there.
+#[derive(Clone, Debug)]
+pub struct Cgi {
+    route: String,
+    directory: PathBuf,
+    timeout: Duration,
+    processes: Arc<Semaphore>,
+}
+
+impl Cgi {
+    /// Creates a gateway running at most 16 scripts at a time, each for at most 30 seconds.
+    ///
+    /// # Arguments
+    ///
+    /// * `route`: The path prefix of scripts, e.g. `/cgi-bin`.
+    /// * `directory`: The directory holding the scripts.
+    ///
+    /// # Returns
+    ///
+    /// A gateway for the scripts in the directory.
+    pub fn new(route: &str, directory: impl Into<PathBuf>) -> Cgi {
+        Cgi {
+            route: route.to_string(),
+            directory: directory.into(),
+            timeout: Duration::from_secs(30),
+            processes: Arc::new(Semaphore::new(16)),
+        }
+    }
+
+    /// Sets how long a script may run before it is killed and the request answered with 504
+    /// Gateway Timeout.
+    ///
+    /// # Arguments
+    ///
+    /// * `timeout`: The limit for the whole run of the script.
+    ///
+    /// # Returns
+    ///
+    /// The gateway with the new timeout.
+    pub fn with_timeout(mut self, timeout: Duration) -> Cgi {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Sets how many scripts may run at a time, answering further requests with 503 Service
+    /// Unavailable until one ends.
+    ///
+    /// # Arguments
+    ///
+    /// * `processes`: The most script processes at a time.
+    ///
+    /// # Returns
+    ///
+    /// The gateway with the new limit.
+    pub fn with_max_processes(mut self, processes: usize) -> Cgi {
+        self.processes = Arc::new(Semaphore::new(processes));
+        self
+    }
+
+    /// The directory holding the scripts.
+    pub fn directory(&self) -> &Path {
+        &self.directory
+    }
+
+    /// The path prefix of scripts, as passed to the constructor.
+    pub fn route(&self) -> &str {
+        &self.route
+    }
+
+    /// Checks whether a request is at or below the gateway route.
+    ///
+    /// # Arguments
+    ///
+    /// * `request`: The incoming request.
+    ///
+    /// # Returns
+    ///
+    /// True when the path continues the route with a `/`.
+    pub fn matches(&self, request: &Request) -> bool {
+        let route = self.route.trim_end_matches('/');
+        request
+            .path()
+            .strip_prefix(route)
+            .is_some_and(|rest| rest.starts_with('/'))
+    }
+
+    /// Finds the script a request path names, the shortest path below the route that is a
+    /// file.
+    ///
+    /// # Arguments
+    ///
+    /// * `path`: The percent-encoded request path.
+    ///
+    /// # Returns
+    ///
+    /// The percent-encoded script name and path info with the file system path of the script,
+    /// or `None` when the path names no file below the directory.
+    async fn script<'a>(&self, path: &'a str) -> Option<(&'a str, &'a str, PathBuf)> {
+        let route = self.route.trim_end_matches('/');
+        let rest = path.strip_prefix(route)?;
+        let ends = rest
+            .match_indices('/')
+            .skip(1)
+            .map(|(index, _)| index)
+            .chain([rest.len()]);
+        for end in ends {
+            let file = resolve(&self.directory, &rest[..end])?;
+            if tokio::fs::metadata(&file)
+                .await
+                .is_ok_and(|metadata| metadata.is_file())
+            {
+                let split = route.len() + end;
+                return Some((&path[..split], &path[split..], file));
+            }
+        }
+        None
+    }
+
+    /// Runs the script a request names with the request body on its standard input.
+    ///
+    /// # Arguments
+    ///
+    /// * `request`: The request with its body read.
+    ///
+    /// # Returns
+    ///
+    /// The standard output of the script, whose standard error goes to the log.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`io::ErrorKind::NotFound`] for a path that cannot be decoded or names no
+    /// script, and captures IO errors from starting the script and exchanging data with it.
+    async fn run(&self, request: &Request) -> io::Result<Vec<u8>> {
+        let not_found = || io::Error::from(io::ErrorKind::NotFound);
+        let (script, rest, file) = self.script(request.path()).await.ok_or_else(not_found)?;
+        let script_name = percent_decode(script).ok_or_else(not_found)?;
+        let path_info = percent_decode(rest).ok_or_else(not_found)?;
+        let mut command = Command::new(&file);
+        command
+            .env_clear()
+            .envs(meta_variables(request, &script_name, &path_info, &file))
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .kill_on_drop(true);
+        if let Some(path) = std::env::var_os("PATH") {
+            command.env("PATH", path);
+        }
+        if let Some(directory) = file.parent() {
+            command.current_dir(directory);
+        }
+        let mut child = command.spawn()?;
+        if let Some(mut stdin) = child.stdin.take() {
+            let body = request.body.clone();
+            // A script may answer without reading its input, which then fails to write
+            tokio::spawn(async move {
+                let _ = stdin.write_all(&body).await;
+            });
+        }
+        let output = child.wait_with_output().await?;
+        for line in String::from_utf8_lossy(&output.stderr).lines() {
+            log::warn(format_args!("{}: {}", script_name, line));
+        }
+        Ok(output.stdout)
+    }
+}
+
+/// Implementing the [`Handler`] trait for the [`Cgi`] struct, answering scripts that fail to
+/// start, run too long, or print malformed output with server errors.
+#[async_trait]
+impl Handler for Cgi {
+    async fn call(&self, request: Request) -> io::Result<Response> {
+        let Ok(_process) = self.processes.try_acquire() else {
+            return Ok(
+                Response::new(StatusCode::SERVICE_UNAVAILABLE, "").with_header("Retry-After", "1")
+            );
+        };
+        Ok(
+            match time::timeout(self.timeout, self.run(&request)).await {
+                Ok(Ok(output)) => parse_output(&output).unwrap_or_else(|error| {
+                    log::warn(format_args!(
+                        "CGI script for {} printed malformed output: {}",
+                        request.target, error
+                    ));
+                    Response::new(StatusCode::BAD_GATEWAY, "")
+                }),
+                Ok(Err(error)) if error.kind() == io::ErrorKind::NotFound => {
+                    Response::new(StatusCode::NOT_FOUND, "")
+                }
+                Ok(Err(error)) => {
+                    log::warn(format_args!(
+                        "CGI script for {} failed: {}",
+                        request.target, error
+                    ));
+                    Response::new(StatusCode::BAD_GATEWAY, "")
+                }
+                Err(_) => {
+                    log::warn(format_args!(
+                        "CGI script for {} ran longer than {:?}",
+                        request.target, self.timeout
+                    ));
+                    Response::new(StatusCode::GATEWAY_TIMEOUT, "")
+                }
+            },
+        )
+    }
+}
+
+/// Describes a request to a script with the meta-variables of CGI/1.1 (RFC 3875), passing
+/// every header as an `HTTP_` variable.
+///
+/// # Arguments
+///
+/// * `request`: The request with its body read.
+/// * `script_name`: The decoded path naming the script.
+/// * `path_info`: The decoded rest of the path after the script name.
+/// * `script_filename`: The file system path of the script.
+///
+/// # Returns
+///
+/// The variable names with their values, with repeated headers joined by commas.
+pub(crate) fn meta_variables(
+    request: &Request,
+    script_name: &str,
+    path_info: &str,
+    script_filename: &Path,
+) -> Vec<(String, String)> {
+    let host = request.header("Host").unwrap_or_default();
+    let (server_name, server_port) = match host.rsplit_once(':') {
+        Some((name, port)) if port.bytes().all(|byte| byte.is_ascii_digit()) => (name, port),
+        _ => (host, "80"),
+    };
+    let mut variables = vec![
+        ("GATEWAY_INTERFACE", "CGI/1.1".to_string()),
+        ("SERVER_SOFTWARE", "web_server_tokio".to_string()),
+        ("SERVER_PROTOCOL", request.version.to_string()),
+        ("SERVER_NAME", server_name.to_string()),
+        ("SERVER_PORT", server_port.to_string()),
+        ("REQUEST_METHOD", request.method.as_str().to_string()),
+        ("REQUEST_URI", request.target.to_string()),
+        ("SCRIPT_NAME", script_name.to_string()),
+        ("SCRIPT_FILENAME", script_filename.display().to_string()),
+        ("PATH_INFO", path_info.to_string()),
+        (
+            "QUERY_STRING",
+            request.target.query().unwrap_or_default().to_string(),
+        ),
+        ("CONTENT_LENGTH", request.body.len().to_string()),
+    ];
+    if let Some(content_type) = request.header("Content-Type") {
+        variables.push(("CONTENT_TYPE", content_type.to_string()));
+    }
+    if let Some(peer) = request.peer {
+        variables.push(("REMOTE_ADDR", peer.ip().to_string()));
+        variables.push(("REMOTE_PORT", peer.port().to_string()));
+    }
+    let mut variables: Vec<(String, String)> = variables
+        .into_iter()
+        .map(|(name, value)| (name.to_string(), value))
+        .collect();
+    for (name, value) in request.headers.iter() {
+        if RESERVED_HEADERS
+            .iter()
+            .any(|reserved| reserved.eq_ignore_ascii_case(name))
+        {
+            continue;
+        }
+        let name = format!("HTTP_{}", name.to_ascii_uppercase().replace('-', "_"));
+        match variables.iter_mut().find(|(existing, _)| *existing == name) {
+            Some((_, existing)) => {
+                existing.push_str(", ");
+                existing.push_str(value);
+            }
+            None => variables.push((name, value.to_string())),
+        }
+    }
+    variables
+}
+
+/// Parses the output of a CGI/1.1 script: header lines, an empty line, and the body.
+///
+/// # Arguments
+///
+/// * `output`: The whole output of the script.
+///
+/// # Returns
+///
+/// The response with the status from a `Status` header, 302 Found for a `Location` without
+/// one, and 200 OK otherwise.
+///
+/// # Errors
+///
+/// Returns [`io::ErrorKind::InvalidData`] when the output has no empty line ending the
+/// headers or a header line or `Status` value is malformed.
+pub(crate) fn parse_output(output: &[u8]) -> io::Result<Response> {
+    let invalid = |message| io::Error::new(io::ErrorKind::InvalidData, message);
+    let (head, body) = [&b"\r\n\r\n"[..], b"\n\n"]
+        .iter()
+        .filter_map(|separator| {
+            let index = output
+                .windows(separator.len())
+                .position(|window| window == *separator)?;
+            Some((&output[..index], &output[index + separator.len()..]))
+        })
+        .min_by_key(|(head, _)| head.len())
+        .ok_or_else(|| invalid("CGI output without an end of headers"))?;
+    let head = std::str::from_utf8(head).map_err(|_| invalid("CGI headers not UTF-8"))?;
+
+    let mut response = Response::new(StatusCode::OK, Bytes::copy_from_slice(body));
+    let mut status = None;
+    for line in head.lines() {
+        let (name, value) = line
+            .split_once(':')
+            .ok_or_else(|| invalid("malformed CGI header"))?;
+        let value = value.trim();
+        if name.eq_ignore_ascii_case("Status") {
+            status = value
+                .split_whitespace()
+                .next()
+                .and_then(|code| code.parse().ok())
+                .and_then(StatusCode::from_u16);
+            if status.is_none() {
+                return Err(invalid("malformed CGI status"));
+            }
+        } else if !name.eq_ignore_ascii_case("Content-Length")
+            && !name.eq_ignore_ascii_case("Transfer-Encoding")
+            && !name.eq_ignore_ascii_case("Connection")
+        {
+            response.headers.append(name.trim(), value);
+        }
+    }
+    response.status = match status {
+        Some(status) => status,
+        None if response.headers.contains("Location") => StatusCode::FOUND,
+        None => StatusCode::OK,
+    };
+    Ok(response)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::PermissionsExt;
+
+    /// Writes an executable shell script into the directory.
+    fn script(directory: &Path, name: &str, source: &str) {
+        let path = directory.join(name);
+        std::fs::write(&path, source).unwrap();
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o755)).unwrap();
+    }
+
+    /// It runs the script a path names with the request in its environment and on stdin
+    #[tokio::test]
+    async fn run() {
+        let directory = tempfile::tempdir().unwrap();
+        script(
+            directory.path(),
+            "echo.sh",
+            "#!/bin/sh\nprintf 'Status: 201 Created\\r\\nX-Path: %s\\r\\n\\r\\n' \"$PATH_INFO\"\n\
+             printf '%s %s ' \"$SCRIPT_NAME\" \"$QUERY_STRING\"\ncat\n",
+        );
+        let cgi = Cgi::new("/cgi-bin", directory.path());
+        let request = Request {
+            target: "/cgi-bin/echo.sh/a%20b?x=1".parse().unwrap(),
+            body: Bytes::from_static(b"body"),
+            ..Request::default()
+        };
+        assert!(cgi.matches(&request));
+        let response = cgi.call(request).await.unwrap();
+        assert_eq!(StatusCode::CREATED, response.status);
+        assert_eq!(Some("/a b"), response.header("X-Path"));
+        assert_eq!(
+            Bytes::from_static(b"/cgi-bin/echo.sh x=1 body"),
+            response.body
+        );
+
+        let missing = Request {
+            target: "/cgi-bin/missing.sh".parse().unwrap(),
+            ..Request::default()
+        };
+        assert_eq!(
+            StatusCode::NOT_FOUND,
+            cgi.call(missing).await.unwrap().status
+        );
+    }
+
+    /// It kills scripts running too long and refuses requests beyond the process limit
+    #[tokio::test]
+    async fn limits() {
+        let directory = tempfile::tempdir().unwrap();
+        script(directory.path(), "slow.sh", "#!/bin/sh\nsleep 5\n");
+        let cgi = Cgi::new("/cgi-bin", directory.path())
+            .with_timeout(Duration::from_millis(100))
+            .with_max_processes(1);
+        let request = Request {
+            target: "/cgi-bin/slow.sh".parse().unwrap(),
+            ..Request::default()
+        };
+        let held = cgi.processes.clone().try_acquire_owned().unwrap();
+        assert_eq!(
+            StatusCode::SERVICE_UNAVAILABLE,
+            cgi.call(request.clone()).await.unwrap().status
+        );
+        drop(held);
+        assert_eq!(
+            StatusCode::GATEWAY_TIMEOUT,
+            cgi.call(request).await.unwrap().status
+        );
+    }
+
+    /// It parses script output, redirecting for a bare `Location`
+    #[test]
+    fn output() {
+        let response = parse_output(b"Location: /b\nContent-Length: 9\n\n").unwrap();
+        assert_eq!(StatusCode::FOUND, response.status);
+        assert_eq!(Some("/b"), response.header("Location"));
+        assert_eq!(None, response.header("Content-Length"));
+        assert!(parse_output(b"Status: nope\r\n\r\n").is_err());
+        assert!(parse_output(b"no end of headers").is_err());
+    }
+}
@@ -0,0 +1,260 @@
+//! Validating a configuration without serving it, for `web_server_tokio check --config <path>`:
+//! the keys parse, the files and directories it names exist, the TLS certificate and key load,
+//! the routes of the built-in handlers do not collide, and the upstreams resolve.
+
+use crate::config::{variable_name, Config};
+use crate::log::Output;
+use std::collections::HashMap;
+use std::env;
+use std::fmt;
+use std::path::Path;
+use tokio::{io, net};
+
+/// Something wrong with a configuration, and where it is set.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Problem {
+    /// The line of the configuration file, e.g. `server.toml:12`, or the environment variable
+    /// setting the invalid value.
+    pub location: String,
+    /// What is wrong.
+    pub message: String,
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, formatter: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(formatter, "{}: {}", self.location, self.message)
+    }
+}
+
+/// Checks the configuration the server would build from the environment and a configuration
+/// file, without binding any sockets.
+///
+/// # Arguments
+///
+/// * `path`: The configuration file, or `None` for the environment alone.
+///
+/// # Returns
+///
+/// The problems found, empty when the configuration is valid. A configuration that does not
+/// parse yields a single problem, since later keys are not read.
+///
+/// # Errors
+///
+/// Captures IO errors from reading the configuration file.
+pub async fn check(path: Option<&Path>) -> io::Result<Vec<Problem>> {
+    let (text, loaded) = match path {
+        Some(path) => (
+            std::fs::read_to_string(path).map_err(|error| {
+                io::Error::new(
+                    error.kind(),
+                    format!("reading {} failed: {}", path.display(), error),
+                )
+            })?,
+            Config::from_file(path),
+        ),
+        None => (String::new(), Config::from_env()),
+    };
+    let locator = Locator {
+        file: path.map(|path| path.display().to_string()),
+        lines: key_lines(&text),
+    };
+    let config = match loaded {
+        Ok(config) => config,
+        Err(error) => {
+            let message = error.to_string();
+            let variable = first_variable(&message).map(String::from);
+            return Ok(vec![locator.problem(variable.as_deref(), message)]);
+        }
+    };
+    let mut problems = Vec::new();
+    let mut require = |variable: &str, path: &Path, directory: bool| {
+        let found = match std::fs::metadata(path) {
+            Ok(metadata) if metadata.is_dir() == directory => return,
+            Ok(_) if directory => "is not a directory",
+            Ok(_) => "is a directory",
+            Err(_) => "does not exist",
+        };
+        problems.push(locator.problem(Some(variable), format!("{} {}", path.display(), found)));
+    };
+    require("WEB_SERVER_ROOT", &config.document_root, true);
+    if let Some(template) = config
+        .markdown
+        .as_ref()
+        .and_then(|markdown| markdown.template.as_ref())
+    {
+        require("WEB_SERVER_MARKDOWN_TEMPLATE", template, false);
+    }
+    if let Some(cgi) = &config.cgi {
+        require("WEB_SERVER_CGI_DIR", cgi.directory(), true);
+    }
+    let error_log = match &config.log_output {
+        Output::File(path) => Some(("WEB_SERVER_ERROR_LOG", path)),
+        _ => None,
+    };
+    let access_log = config
+        .access_log
+        .as_ref()
+        .map(|path| ("WEB_SERVER_ACCESS_LOG", path));
+    for (variable, log) in error_log.into_iter().chain(access_log) {
+        if let Some(parent) = log.parent().filter(|parent| !parent.as_os_str().is_empty()) {
+            require(variable, parent, true);
+        }
+    }
+    problems.extend(check_routes(&config, &locator));
+    if let Some(proxy) = &config.proxy {
+        for upstream in proxy.upstream_stats() {
+            if let Err(error) = net::lookup_host(&upstream.address).await {
+                problems.push(locator.problem(
+                    Some("WEB_SERVER_PROXY_UPSTREAM"),
+                    format!("upstream {} does not resolve: {}", upstream.address, error),
+                ));
+            }
+        }
+    }
+    Ok(problems)
+}
+
+/// Checks that the routes of the built-in handlers are paths and that no two of them share one,
+/// since only the first would ever be reached.
+fn check_routes(config: &Config, locator: &Locator) -> Vec<Problem> {
+    let mut routes = Vec::new();
+    if let Some(metrics) = &config.metrics {
+        routes.push(("WEB_SERVER_METRICS_ROUTE", metrics.route.as_str()));
+    }
+    if let Some(proxy) = &config.proxy {
+        routes.push(("WEB_SERVER_PROXY_ROUTE", proxy.route()));
+    }
+    if let Some(fastcgi) = &config.fastcgi {
+        routes.push(("WEB_SERVER_FASTCGI_ROUTE", fastcgi.route()));
+    }
+    if let Some(cgi) = &config.cgi {
+        routes.push(("WEB_SERVER_CGI_ROUTE", cgi.route()));
+    }
+    if let Some(upload) = &config.upload {
+        routes.push(("WEB_SERVER_UPLOAD_ROUTE", upload.route.as_str()));
+    }
+    if let Some(tus) = &config.tus {
+        routes.push(("WEB_SERVER_TUS_ROUTE", tus.route.as_str()));
+    }
+    let mut problems = Vec::new();
+    for (index, (variable, route)) in routes.iter().enumerate() {
+        if !route.starts_with('/') {
+            problems.push(locator.problem(
+                Some(variable),
+                format!("route {} does not start with /", route),
+            ));
+        }
+        let normalized = |route: &str| route.trim_end_matches('/').to_string();
+        if let Some((earlier, _)) = routes[..index]
+            .iter()
+            .find(|(_, earlier)| normalized(earlier) == normalized(route))
+        {
+            problems.push(locator.problem(
+                Some(variable),
+                format!("route {} is already taken by {}", route, earlier),
+            ));
+        }
+    }
+    problems
+}
+
+/// Finds where the variables of a configuration are set.
+struct Locator {
+    /// The configuration file as shown to the user, if any.
+    file: Option<String>,
+    /// The line of each variable set in the file, see [`key_lines`].
+    lines: HashMap<String, usize>,
+}
+
+impl Locator {
+    /// Creates a problem located at the variable it is about: the environment variable when it
+    /// is set, since it wins over the file, else the line of the file setting the key.
+    fn problem(&self, variable: Option<&str>, message: String) -> Problem {
+        let location = match variable {
+            Some(variable) if env::var_os(variable).is_some() => variable.to_string(),
+            Some(variable) => match (&self.file, self.lines.get(variable)) {
+                (Some(file), Some(line)) => format!("{}:{}", file, line),
+                (Some(file), None) => file.clone(),
+                (None, _) => variable.to_string(),
+            },
+            None => self
+                .file
+                .clone()
+                .unwrap_or_else(|| "environment".to_string()),
+        };
+        Problem { location, message }
+    }
+}
+
+/// Maps the variables a configuration file sets to the lines setting them, for the plain
+/// `[table]` headers and `key = value` lines that configuration files consist of.
+fn key_lines(text: &str) -> HashMap<String, usize> {
+    let mut prefix = "WEB_SERVER".to_string();
+    let mut lines = HashMap::new();
+    for (index, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if let Some(table) = line
+            .strip_prefix('[')
+            .and_then(|line| line.strip_suffix(']'))
+        {
+            prefix = table
+                .split('.')
+                .fold("WEB_SERVER".to_string(), |prefix, key| {
+                    variable_name(&prefix, key.trim().trim_matches('"'))
+                });
+        } else if let Some((key, _)) = line.split_once('=').filter(|_| !line.starts_with('#')) {
+            let name = variable_name(&prefix, key.trim().trim_matches('"'));
+            lines.entry(name).or_insert(index + 1);
+        }
+    }
+    lines
+}
+
+/// The first `WEB_SERVER_*` variable an error message names, which is the one it is about.
+fn first_variable(message: &str) -> Option<&str> {
+    let start = message.find("WEB_SERVER_")?;
+    let rest = &message[start..];
+    let end = rest
+        .find(|c: char| !(c.is_ascii_uppercase() || c.is_ascii_digit() || c == '_'))
+        .unwrap_or(rest.len());
+    Some(&rest[..end])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// It locates invalid values and missing files at the lines of the file setting them
+    #[tokio::test]
+    async fn check() {
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("server.toml");
+        let write = |text: &str| std::fs::write(&path, text).unwrap();
+
+        write("root = \".\"\n\n[proxy]\n# slow\ntimeout_secs = \"soon\"\nupstream = \"a:1\"\n");
+        let problems = super::check(Some(&path)).await.unwrap();
+        assert_eq!(1, problems.len(), "{:?}", problems);
+        assert_eq!(format!("{}:5", path.display()), problems[0].location);
+
+        write(
+            "root = \"missing\"\n[upload]\ndir = \".\"\nroute = \"/files\"\n[tus]\ndir = \".\"\n",
+        );
+        let problems = super::check(Some(&path)).await.unwrap();
+        assert_eq!(
+            vec![
+                format!("{}:1: missing does not exist", path.display()),
+                format!(
+                    "{}: route /files is already taken by WEB_SERVER_UPLOAD_ROUTE",
+                    path.display()
+                ),
+            ],
+            problems.iter().map(Problem::to_string).collect::<Vec<_>>()
+        );
+
+        write("root = \".\"\n");
+        assert_eq!(
+            Vec::<Problem>::new(),
+            super::check(Some(&path)).await.unwrap()
+        );
+    }
+}
@@ -0,0 +1,193 @@
+//! A global cap on the requests handled at a time. Requests beyond the cap wait in a bounded
+//! queue, in arrival order, for a bounded time, and are answered with 503 Service Unavailable
+//! once the queue is full or their wait is over, so that bursts are smoothed without the
+//! memory of waiting requests growing without bounds.
+
+use crate::response::Response;
+use crate::status::StatusCode;
+use std::fmt;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tokio::time;
+
+/// The counters of a [`ConcurrencyLimit`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ConcurrencyStats {
+    /// Requests being handled.
+    pub running: usize,
+    /// Requests waiting for their turn.
+    pub queued: usize,
+    /// Requests answered with 503 since the server started.
+    pub shed: u64,
+}
+
+/// The state shared by the clones of a limit.
+#[derive(Debug)]
+struct Shared {
+    slots: Arc<Semaphore>,
+    queued: AtomicUsize,
+    shed: AtomicU64,
+}
+
+/// Caps the requests handled at a time, see [`crate::config::Config::concurrency`]. Clones
+/// share the slots and the queue.
+#[derive(Clone)]
+pub struct ConcurrencyLimit {
+    max_running: usize,
+    max_queued: usize,
+    max_wait: Duration,
+    shared: Arc<Shared>,
+}
+
+impl ConcurrencyLimit {
+    /// Handles up to a number of requests at a time, queueing as many more for up to a second.
+    pub fn new(max_running: usize) -> ConcurrencyLimit {
+        ConcurrencyLimit {
+            max_running,
+            max_queued: max_running,
+            max_wait: Duration::from_secs(1),
+            shared: Arc::new(Shared {
+                slots: Arc::new(Semaphore::new(max_running)),
+                queued: AtomicUsize::new(0),
+                shed: AtomicU64::new(0),
+            }),
+        }
+    }
+
+    /// Queues up to this many requests beyond the cap, or none so that they are shed at once.
+    pub fn with_max_queued(mut self, max_queued: usize) -> ConcurrencyLimit {
+        self.max_queued = max_queued;
+        self
+    }
+
+    /// Sheds queued requests still waiting after this long.
+    pub fn with_max_wait(mut self, max_wait: Duration) -> ConcurrencyLimit {
+        self.max_wait = max_wait;
+        self
+    }
+
+    /// The current counters.
+    pub fn stats(&self) -> ConcurrencyStats {
+        ConcurrencyStats {
+            running: self.max_running - self.shared.slots.available_permits(),
+            queued: self.shared.queued.load(Ordering::Relaxed),
+            shed: self.shared.shed.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Waits for a slot to handle a request in.
+    ///
+    /// # Returns
+    ///
+    /// The slot, held until it is dropped, or `None` when the request is shed because the
+    /// queue is full or the wait took too long.
+    pub async fn acquire(&self) -> Option<OwnedSemaphorePermit> {
+        if let Ok(slot) = self.shared.slots.clone().try_acquire_owned() {
+            return Some(slot);
+        }
+        let queued = Queued::enter(&self.shared, self.max_queued);
+        let slot = match queued {
+            Some(_) => time::timeout(self.max_wait, self.shared.slots.clone().acquire_owned())
+                .await
+                .ok()
+                .and_then(Result::ok),
+            None => None,
+        };
+        if slot.is_none() {
+            self.shared.shed.fetch_add(1, Ordering::Relaxed);
+        }
+        slot
+    }
+
+    /// The response to a shed request, asking the client to retry in a second.
+    pub fn shed_response() -> Response {
+        Response::new(
+            StatusCode::SERVICE_UNAVAILABLE,
+            "the server is busy, please retry later\n",
+        )
+        .with_header("Retry-After", "1")
+    }
+}
+
+impl fmt::Debug for ConcurrencyLimit {
+    fn fmt(&self, formatter: &mut fmt::Formatter<'_>) -> fmt::Result {
+        formatter
+            .debug_struct("ConcurrencyLimit")
+            .field("max_running", &self.max_running)
+            .field("max_queued", &self.max_queued)
+            .field("max_wait", &self.max_wait)
+            .finish_non_exhaustive()
+    }
+}
+
+/// A place in the queue, left when dropped, also when the connection of the waiting request
+/// closes.
+struct Queued<'a> {
+    queued: &'a AtomicUsize,
+}
+
+impl<'a> Queued<'a> {
+    /// Takes a place in the queue if one is free.
+    fn enter(shared: &'a Shared, max_queued: usize) -> Option<Queued<'a>> {
+        shared
+            .queued
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |queued| {
+                (queued < max_queued).then_some(queued + 1)
+            })
+            .ok()
+            .map(|_| Queued {
+                queued: &shared.queued,
+            })
+    }
+}
+
+impl Drop for Queued<'_> {
+    fn drop(&mut self) {
+        self.queued.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// It queues requests beyond the cap in order and sheds them once the queue is full or
+    /// their wait is over
+    #[tokio::test]
+    async fn concurrency_limit() {
+        let limit = ConcurrencyLimit::new(1)
+            .with_max_queued(1)
+            .with_max_wait(Duration::from_millis(50));
+        let running = limit.acquire().await.unwrap();
+        let waiting = tokio::spawn({
+            let limit = limit.clone();
+            async move { limit.acquire().await.is_some() }
+        });
+        while limit.stats().queued == 0 {
+            tokio::task::yield_now().await;
+        }
+        assert!(limit.acquire().await.is_none());
+        assert_eq!(
+            ConcurrencyStats {
+                running: 1,
+                queued: 1,
+                shed: 1
+            },
+            limit.stats()
+        );
+        drop(running);
+        assert!(waiting.await.unwrap());
+
+        let running = limit.acquire().await.unwrap();
+        assert!(limit.acquire().await.is_none());
+        assert_eq!((0, 2), (limit.stats().queued, limit.stats().shed));
+        drop(running);
+        assert_eq!(0, limit.stats().running);
+        assert_eq!(
+            StatusCode::SERVICE_UNAVAILABLE,
+            ConcurrencyLimit::shed_response().status
+        );
+    }
+}
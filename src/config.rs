@@ -0,0 +1,2017 @@
+#[cfg(feature = "acme")]
+use crate::acme::Acme;
+#[cfg(feature = "auth")]
+use crate::auth::ApiKeys;
+use crate::canary::CanaryRule;
+use crate::capture::{Capture, Captures};
+use crate::cgi::Cgi;
+use crate::concurrency::ConcurrencyLimit;
+use crate::fastcgi::FastCgi;
+#[cfg(feature = "grpc")]
+use crate::grpc::GrpcProxy;
+use crate::log::file::Rotation;
+use crate::log::format::AccessFormat;
+use crate::log::{Level, Output};
+use crate::maintenance::{Maintenance, MaintenanceSwitch};
+use crate::method::Method;
+use crate::proxy::cache::DiskCache;
+use crate::proxy::{Affinity, CircuitBreaker, HealthCheck, Proxy, RetryPolicy, Strategy};
+use crate::redirect::Redirect;
+use crate::rewrite::Rewrite;
+use crate::router::Router;
+use crate::sandbox::Sandbox;
+#[cfg(feature = "scripting")]
+use crate::script::ScriptHooks;
+use crate::server::ServerStats;
+use crate::shedding::LoadShedder;
+use crate::split::{SplitRule, Stickiness};
+use crate::status::StatusCode;
+#[cfg(feature = "tls")]
+use crate::tls::{TicketKeys, Tls, TlsVersion};
+use crate::trace::Sampling;
+#[cfg(feature = "wasm")]
+use crate::wasm::WasmPlugin;
+use std::collections::HashMap;
+use std::env;
+use std::ffi::OsString;
+use std::fmt;
+use std::fs;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tokio::io;
+
+/// Settings shared by every connection the server handles.
+#[derive(Clone, Debug)]
+pub struct Config {
+    /// The directory that files are served from and that WebDAV operates on.
+    pub document_root: PathBuf,
+    /// Enables the WebDAV methods when present.
+    pub webdav: Option<WebDavConfig>,
+    /// Enables the upload handler when present.
+    pub upload: Option<UploadConfig>,
+    /// Enables the tus resumable upload protocol when present.
+    pub tus: Option<TusConfig>,
+    /// Enables rendering Markdown files below the document root when present.
+    pub markdown: Option<MarkdownConfig>,
+    /// Serves language variants such as `hello.de.html` chosen by `Accept-Language`.
+    pub language_variants: bool,
+    /// Finds files below the document root whatever the case of the request path, using the
+    /// case stored on disk, see [`crate::path::match_case`].
+    pub case_insensitive_paths: bool,
+    /// Handlers registered by the application, consulted before the built-in handlers.
+    pub router: Router,
+    /// Rewrites request paths in order before anything handles them, see [`crate::rewrite`].
+    pub rewrites: Vec<Rewrite>,
+    /// Answers the matching requests with redirects before routing, the first matching rule
+    /// winning, see [`crate::redirect`].
+    pub redirects: Vec<Redirect>,
+    /// Runs the hooks of a Rhai script on every request after the rewrites and on every
+    /// response before it is sent when present.
+    #[cfg(feature = "scripting")]
+    pub scripts: Option<ScriptHooks>,
+    /// Runs the header callbacks of WebAssembly plugins in order on every request after the
+    /// script and in reverse order on every response before the script.
+    #[cfg(feature = "wasm")]
+    pub plugins: Vec<WasmPlugin>,
+    /// Serves metrics of the enabled components when present, consulted right after the
+    /// router.
+    pub metrics: Option<MetricsConfig>,
+    /// Forwards requests below its route to an upstream server when present, consulted right
+    /// after the metrics.
+    pub proxy: Option<Proxy>,
+    /// Relays HTTP/2 connections to a gRPC backend when present, see [`crate::grpc`]. Without
+    /// it, clients speaking HTTP/2 are refused. Relayed calls bypass the maintenance page, load
+    /// shedding, the concurrency limit, and the router with its rate limits.
+    #[cfg(feature = "grpc")]
+    pub grpc: Option<GrpcProxy>,
+    /// Runs scripts below its route on a FastCGI application when present, consulted right
+    /// after the proxy.
+    pub fastcgi: Option<FastCgi>,
+    /// Runs scripts below its route as CGI programs when present, consulted right after the
+    /// FastCGI gateway.
+    pub cgi: Option<Cgi>,
+    /// The largest body in bytes read into memory for a handler registered on the router,
+    /// unless its route sets another, see [`Router::with_limits`], and for the proxy, FastCGI,
+    /// and CGI. Uploads and tus stream their bodies to disk up to their own `max_size`.
+    pub max_body_size: u64,
+    /// How long reading the body and handling a request may take for a handler registered on
+    /// the router, unless its route sets another, and for the proxy, FastCGI, CGI, WebDAV,
+    /// uploads, and tus, or `None` for no limit.
+    pub request_timeout: Option<Duration>,
+    /// How long a connection may wait for the request line and headers of its next request,
+    /// idle keep-alive connections included, before it is closed, or `None` for no limit.
+    pub header_timeout: Option<Duration>,
+    /// The methods refused before routing.
+    pub methods: MethodPolicy,
+    /// Answers requests with ambiguous framing, see [`crate::request::Request::violation`], with
+    /// 400 Bad Request instead of handling them.
+    pub strict: bool,
+    /// Serves the admin API on a separate listener when present.
+    pub admin: Option<AdminConfig>,
+    /// The API keys the application checks with [`Router::with_api_keys`], listed and, for a
+    /// store managed at runtime, issued and revoked through the admin API.
+    #[cfg(feature = "auth")]
+    pub api_keys: Option<ApiKeys>,
+    /// The least severe level logged at startup, which the admin API can change later.
+    pub log_level: Level,
+    /// Where log messages go, see [`crate::log::set_sink`]. Only read at startup.
+    pub log_output: Output,
+    /// The file a line per handled request is written to, see
+    /// [`crate::log::set_access_log`]. Only read at startup.
+    pub access_log: Option<PathBuf>,
+    /// The template of the access log lines.
+    pub access_log_format: AccessFormat,
+    /// When the error and access logs rotate. Only read at startup.
+    pub log_rotation: Rotation,
+    /// Which traces the server records and marks as sampled for upstreams.
+    pub trace_sampling: Sampling,
+    /// Logs requests taking longer than this with their details, whatever the log level.
+    pub slow_request: Option<Duration>,
+    /// Records the matching exchanges into [`Config::captures`] when present.
+    pub capture: Option<Capture>,
+    /// The latest captured exchanges, shared by every clone of the configuration.
+    pub captures: Captures,
+    /// How the server answers while in maintenance.
+    pub maintenance: Maintenance,
+    /// Turns maintenance on or off whatever [`Maintenance::enabled`] says, shared by every clone
+    /// of the configuration.
+    pub maintenance_switch: MaintenanceSwitch,
+    /// How long a draining server waits for open connections before closing them.
+    pub drain_timeout: Duration,
+    /// Caps the requests handled at a time when present, past the redirects and maintenance
+    /// pages, shared by every clone of the configuration.
+    pub concurrency: Option<ConcurrencyLimit>,
+    /// Sheds requests while the handlers of routes, the proxy, FastCGI, or CGI are slow when
+    /// present, before they take a slot of
+    /// [`Config::concurrency`], shared by every clone of the configuration.
+    pub load_shedding: Option<LoadShedder>,
+    /// Serves on this many threads with a single-threaded runtime each, see
+    /// [`crate::server::Server::serve_per_core`], instead of one multi-threaded runtime, where
+    /// `Some(0)` means one thread per CPU core. Only read at startup.
+    pub thread_per_core: Option<usize>,
+    /// The worker threads of the runtime the binary builds, see
+    /// [`crate::server::Server::with_worker_threads`]. Only read at startup.
+    pub worker_threads: Option<usize>,
+    /// The blocking threads of the runtime the binary builds, see
+    /// [`crate::server::Server::with_max_blocking_threads`]. Only read at startup.
+    pub max_blocking_threads: Option<usize>,
+    /// Serves the threads of [`Config::thread_per_core`] with io_uring, see
+    /// [`crate::server::Server::serve_uring`]. Only read at startup.
+    #[cfg(all(feature = "io-uring", target_os = "linux"))]
+    pub io_uring: bool,
+    /// The user the binary serves as once its listeners are bound, see
+    /// [`crate::privileges::drop_privileges`]. Only read at startup.
+    pub user: Option<String>,
+    /// The group the binary serves as, the primary group of [`Config::user`] by default. Only
+    /// read at startup.
+    pub group: Option<String>,
+    /// Confines the file access of the binary once it started, see [`Sandbox::apply`]. Only
+    /// read at startup.
+    pub sandbox: Option<Sandbox>,
+    /// Connection and request counters, shared by every clone of the configuration.
+    pub stats: ServerStats,
+    /// Serves HTTPS on a separate listener when present.
+    #[cfg(feature = "tls")]
+    pub tls: Option<Tls>,
+    /// Obtains and renews the certificate of [`Config::tls`] from an ACME certificate authority
+    /// when present.
+    #[cfg(feature = "acme")]
+    pub acme: Option<Acme>,
+}
+
+/// Methods the server refuses before any handler sees the request.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MethodPolicy {
+    /// When present, every other method is answered with 501 Not Implemented.
+    pub allowed: Option<Vec<Method>>,
+    /// Methods answered with 405 Method Not Allowed.
+    pub disabled: Vec<Method>,
+}
+
+impl Default for MethodPolicy {
+    /// Disables `TRACE` and `CONNECT`, which the server never implements.
+    fn default() -> MethodPolicy {
+        MethodPolicy {
+            allowed: None,
+            disabled: vec![Method::Trace, Method::Connect],
+        }
+    }
+}
+
+impl MethodPolicy {
+    /// Checks a request method against the policy.
+    ///
+    /// # Arguments
+    ///
+    /// * `method`: The request method.
+    ///
+    /// # Returns
+    ///
+    /// The status refusing the method, or `None` when the method may be routed.
+    pub fn refusal(&self, method: &Method) -> Option<StatusCode> {
+        if self.disabled.iter().any(|disabled| disabled == method) {
+            Some(StatusCode::METHOD_NOT_ALLOWED)
+        } else if self
+            .allowed
+            .as_ref()
+            .is_some_and(|allowed| !allowed.iter().any(|allowed| allowed == method))
+        {
+            Some(StatusCode::NOT_IMPLEMENTED)
+        } else {
+            None
+        }
+    }
+
+    /// Checks whether a method passes the policy.
+    ///
+    /// # Arguments
+    ///
+    /// * `method`: The request method.
+    ///
+    /// # Returns
+    ///
+    /// `true` unless [`MethodPolicy::refusal`] refuses the method.
+    pub fn permits(&self, method: &Method) -> bool {
+        self.refusal(method).is_none()
+    }
+}
+
+/// Settings for the optional WebDAV share.
+#[derive(Clone, PartialEq, Eq)]
+pub struct WebDavConfig {
+    /// The user name required through Basic authentication.
+    pub username: String,
+    /// The password required through Basic authentication.
+    pub password: String,
+}
+
+impl fmt::Debug for WebDavConfig {
+    /// Leaves out the password, since the configuration is shown by the admin API.
+    fn fmt(&self, formatter: &mut fmt::Formatter<'_>) -> fmt::Result {
+        formatter
+            .debug_struct("WebDavConfig")
+            .field("username", &self.username)
+            .field("password", &"<redacted>")
+            .finish()
+    }
+}
+
+/// Settings for the optional upload handler.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct UploadConfig {
+    /// The path prefix that PUT and POST uploads are accepted under, e.g. `/upload`.
+    pub route: String,
+    /// The directory that uploaded files are written to.
+    pub directory: PathBuf,
+    /// The largest accepted request body in bytes.
+    pub max_size: u64,
+}
+
+/// Settings for the optional tus resumable upload endpoint.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TusConfig {
+    /// The path that uploads are created at and below which they are addressed, e.g. `/files`.
+    pub route: String,
+    /// The directory holding upload data and their `.info` files.
+    pub directory: PathBuf,
+    /// The largest accepted `Upload-Length` in bytes.
+    pub max_size: u64,
+    /// How long an unfinished upload is kept after its creation or last append.
+    pub expiration: Duration,
+}
+
+/// Settings for the optional metrics endpoint.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MetricsConfig {
+    /// The path the metrics are served at, e.g. `/metrics`.
+    pub route: String,
+}
+
+/// Settings for the optional admin listener, see [`crate::admin`].
+#[derive(Clone, PartialEq, Eq)]
+pub struct AdminConfig {
+    /// The address the admin API listens on, separate from the public listener.
+    pub address: SocketAddr,
+    /// The token every admin request must carry as `Authorization: Bearer <token>`, required
+    /// for CPU profiles.
+    pub token: Option<String>,
+}
+
+impl AdminConfig {
+    /// Whether the admin API is reachable from other machines without a token, which the
+    /// server refuses since the API can drain it and reveals its configuration.
+    pub fn is_exposed(&self) -> bool {
+        self.token.is_none() && !self.address.ip().is_loopback()
+    }
+}
+
+impl Default for AdminConfig {
+    /// Listens on `127.0.0.1:7879`, reachable from the local machine only, without a token.
+    fn default() -> AdminConfig {
+        AdminConfig {
+            address: SocketAddr::from(([127, 0, 0, 1], 7879)),
+            token: None,
+        }
+    }
+}
+
+/// Redacts the token, since the admin API shows the configuration.
+impl fmt::Debug for AdminConfig {
+    fn fmt(&self, formatter: &mut fmt::Formatter<'_>) -> fmt::Result {
+        formatter
+            .debug_struct("AdminConfig")
+            .field("address", &self.address)
+            .field("token", &self.token.as_ref().map(|_| "<redacted>"))
+            .finish()
+    }
+}
+
+/// Settings for the optional Markdown rendering mode.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct MarkdownConfig {
+    /// An HTML file whose `{{title}}` and `{{content}}` placeholders are replaced by the page
+    /// title and rendered Markdown. A minimal built-in page is used when absent.
+    pub template: Option<PathBuf>,
+}
+
+impl Default for Config {
+    /// Serves the current directory with WebDAV, uploads, and the admin API disabled and strict
+    /// parsing enabled.
+    fn default() -> Config {
+        Config {
+            document_root: PathBuf::from("."),
+            webdav: None,
+            upload: None,
+            tus: None,
+            markdown: None,
+            language_variants: false,
+            case_insensitive_paths: false,
+            router: Router::new(),
+            rewrites: Vec::new(),
+            redirects: Vec::new(),
+            #[cfg(feature = "scripting")]
+            scripts: None,
+            #[cfg(feature = "wasm")]
+            plugins: Vec::new(),
+            metrics: None,
+            proxy: None,
+            #[cfg(feature = "grpc")]
+            grpc: None,
+            fastcgi: None,
+            cgi: None,
+            max_body_size: 1024 * 1024,
+            request_timeout: None,
+            header_timeout: Some(Duration::from_secs(60)),
+            methods: MethodPolicy::default(),
+            strict: true,
+            admin: None,
+            #[cfg(feature = "auth")]
+            api_keys: None,
+            log_level: Level::Info,
+            log_output: Output::Stderr,
+            access_log: None,
+            access_log_format: AccessFormat::default(),
+            log_rotation: Rotation::default(),
+            trace_sampling: Sampling::default(),
+            slow_request: None,
+            capture: None,
+            captures: Captures::default(),
+            maintenance: Maintenance::new(),
+            maintenance_switch: MaintenanceSwitch::default(),
+            drain_timeout: Duration::from_secs(30),
+            concurrency: None,
+            load_shedding: None,
+            thread_per_core: None,
+            worker_threads: None,
+            max_blocking_threads: None,
+            user: None,
+            group: None,
+            sandbox: None,
+            #[cfg(all(feature = "io-uring", target_os = "linux"))]
+            io_uring: false,
+            stats: ServerStats::default(),
+            #[cfg(feature = "tls")]
+            tls: None,
+            #[cfg(feature = "acme")]
+            acme: None,
+        }
+    }
+}
+
+impl Config {
+    /// Builds a configuration from `WEB_SERVER_*` environment variables, falling back to the
+    /// TOML file named by `WEB_SERVER_CONFIG` and then to [`Config::default`] for anything unset.
+    ///
+    /// A key of the file stands for the variable of the same name in upper case without the
+    /// `WEB_SERVER_` prefix, and a table adds its name to the keys inside it, so `root = "/srv"`
+    /// sets `WEB_SERVER_ROOT` and `upstream` in a `[proxy]` table sets
+    /// `WEB_SERVER_PROXY_UPSTREAM`. Booleans stand for `1` and `0`, arrays for comma-separated
+    /// lists. Strings of the file may refer to environment variables as `${NAME}`, or
+    /// `${NAME:-default}` for a fallback when `NAME` is unset or empty, e.g.
+    /// `password = "${WEBDAV_PASSWORD}"` or `timeout_secs = "${TIMEOUT:-30}"`, and `$$` stands
+    /// for a `$`.
+    ///
+    /// * `WEB_SERVER_CONFIG`: The configuration file, read again on every
+    ///   [`crate::server::Server::reload`].
+    /// * `WEB_SERVER_ROOT`: The document root.
+    /// * `WEB_SERVER_WEBDAV_USERNAME` and `WEB_SERVER_WEBDAV_PASSWORD`: Enable WebDAV behind
+    ///   Basic authentication with these credentials.
+    /// * `WEB_SERVER_UPLOAD_DIR`: Enables uploads into this directory.
+    /// * `WEB_SERVER_UPLOAD_ROUTE`: The upload path prefix, `/upload` by default.
+    /// * `WEB_SERVER_UPLOAD_MAX_SIZE`: The upload size limit in bytes, 10 MiB by default.
+    /// * `WEB_SERVER_TUS_DIR`: Enables tus resumable uploads stored in this directory.
+    /// * `WEB_SERVER_TUS_ROUTE`: The tus path, `/files` by default.
+    /// * `WEB_SERVER_TUS_MAX_SIZE`: The tus size limit in bytes, 1 GiB by default.
+    /// * `WEB_SERVER_TUS_EXPIRATION_SECS`: Seconds an unfinished tus upload is kept, one day by
+    ///   default.
+    /// * `WEB_SERVER_MARKDOWN`: Set to `1` to render `.md` files as HTML.
+    /// * `WEB_SERVER_MARKDOWN_TEMPLATE`: The HTML template wrapping rendered Markdown.
+    /// * `WEB_SERVER_LANGUAGE_VARIANTS`: Set to `1` to serve pages in the preferred language.
+    /// * `WEB_SERVER_CASE_INSENSITIVE_PATHS`: Set to `1` to find files below the document root
+    ///   whatever the case of the request path, e.g. for content migrated from Windows.
+    /// * `WEB_SERVER_REWRITE_1`, `WEB_SERVER_REWRITE_2`, ...: Rewrite rules applied in this
+    ///   order to request paths before routing, as `<regex> <replacement>` followed by `last`,
+    ///   the default, to stop once the rule matched, or `continue`, e.g.
+    ///   `^/blog/(\d+)$ /posts/$1`. Numbered from 1 without gaps, e.g. from a `[rewrite]` table
+    ///   with the keys `1`, `2`, and so on.
+    /// * `WEB_SERVER_SCRIPT`: A Rhai script whose hooks inspect and change every request and
+    ///   response with the `scripting` feature, see [`ScriptHooks`].
+    /// * `WEB_SERVER_SCRIPT_MAX_OPERATIONS`: The operations a hook may run, 100000 by default.
+    /// * `WEB_SERVER_SCRIPT_TIMEOUT_MS`: Milliseconds a hook may run, 10 by default.
+    /// * `WEB_SERVER_WASM_PLUGIN_1`, `WEB_SERVER_WASM_PLUGIN_2`, ...: proxy-wasm modules run in
+    ///   this order with the `wasm` feature, as `<path>` optionally followed by a space and the
+    ///   configuration of the plugin, see [`WasmPlugin`]. Numbered from 1 without gaps like the
+    ///   rewrite rules.
+    /// * `WEB_SERVER_WASM_MAX_FUEL`: The fuel a plugin callback may consume, roughly one unit
+    ///   per instruction, 10000000 by default.
+    /// * `WEB_SERVER_REDIRECT_1`, `WEB_SERVER_REDIRECT_2`, ...: Redirect rules checked in this
+    ///   order after the rewrites, as `<exact|prefix|regex> <from> <to>` optionally followed by
+    ///   `permanent`, the default, `temporary`, or a redirect status code, e.g.
+    ///   `prefix /docs /manual` or `regex ^/u/(\d+)$ /users/$1 308`. Numbered from 1 without
+    ///   gaps like the rewrite rules.
+    /// * `WEB_SERVER_METRICS_ROUTE`: Serves metrics at this path, e.g. `/metrics`.
+    /// * `WEB_SERVER_PROXY_UPSTREAM`: Enables the reverse proxy to these comma-separated
+    ///   addresses, e.g. `127.0.0.1:8080,127.0.0.1:8081`, each optionally followed by `=` and
+    ///   its weight, e.g. `127.0.0.1:8080=90,127.0.0.1:8081=10`.
+    /// * `WEB_SERVER_PROXY_STRATEGY`: `round-robin` by default, or `least-connections`.
+    /// * `WEB_SERVER_PROXY_ROUTE`: The proxied path prefix, `/` by default.
+    /// * `WEB_SERVER_PROXY_TIMEOUT_SECS`: Seconds an upstream exchange may take, 30 by default.
+    /// * `WEB_SERVER_PROXY_MAX_RESPONSE_SIZE`: The largest upstream body in bytes, 64 MiB by
+    ///   default, above which the response is 502.
+    /// * `WEB_SERVER_PROXY_CACHE_DIR`: Caches upstream responses in this directory.
+    /// * `WEB_SERVER_PROXY_HEALTH_PATH`: Enables health checks of the upstreams at this path.
+    /// * `WEB_SERVER_PROXY_HEALTH_INTERVAL_SECS`: Seconds between health checks, 10 by default.
+    /// * `WEB_SERVER_PROXY_HEALTHY_THRESHOLD`: Passes returning an upstream to rotation, 2 by
+    ///   default.
+    /// * `WEB_SERVER_PROXY_UNHEALTHY_THRESHOLD`: Failures taking an upstream out of rotation, 3
+    ///   by default.
+    /// * `WEB_SERVER_PROXY_BREAKER_ERROR_RATE`: Enables circuit breakers opening at this share
+    ///   of failed requests, e.g. `0.5`.
+    /// * `WEB_SERVER_PROXY_BREAKER_MIN_REQUESTS`: Requests per window before a circuit may
+    ///   open, 20 by default.
+    /// * `WEB_SERVER_PROXY_BREAKER_COOLDOWN_SECS`: Seconds an open circuit refuses requests, 30
+    ///   by default.
+    /// * `WEB_SERVER_PROXY_RETRIES`: Enables retrying failed idempotent requests this many times.
+    /// * `WEB_SERVER_PROXY_RETRY_TIMEOUT_SECS`: Seconds each try may take, the proxy timeout by
+    ///   default.
+    /// * `WEB_SERVER_PROXY_RETRY_BUDGET`: The share of requests that may be retries, `0.2` by
+    ///   default.
+    /// * `WEB_SERVER_PROXY_AFFINITY`: Keeps clients on one upstream, by a cookie with
+    ///   `cookie:<name>` or by their IP address with `client-address`.
+    /// * `WEB_SERVER_GRPC_UPSTREAM`: Relays HTTP/2 connections to the gRPC backend at this
+    ///   address with the `grpc` feature, e.g. `127.0.0.1:50051`. Offering `h2` through
+    ///   `WEB_SERVER_TLS_ALPN` relays TLS clients negotiating HTTP/2 as well. Relayed calls
+    ///   bypass maintenance, load shedding, and the concurrency limit.
+    /// * `WEB_SERVER_GRPC_CONNECT_TIMEOUT_MS`: Milliseconds connecting to the backend may take,
+    ///   5000 by default.
+    /// * `WEB_SERVER_PROXY_CANARY_UPSTREAM`: Forwards requests marked for the canary to these
+    ///   comma-separated addresses instead, with the timeout and health checks of the proxy.
+    /// * `WEB_SERVER_PROXY_CANARY_HEADER`: Marks requests with this header for the canary, as
+    ///   `<name>=<value>`, e.g. `X-Canary=1`, or `<name>` for any value.
+    /// * `WEB_SERVER_PROXY_CANARY_COOKIE`: Marks requests with this cookie for the canary, as
+    ///   `<name>=<value>` or `<name>`.
+    /// * `WEB_SERVER_PROXY_SPLIT_UPSTREAM`: Forwards the requests of a share of clients to these
+    ///   comma-separated addresses instead, with the timeout and health checks of the proxy.
+    /// * `WEB_SERVER_PROXY_SPLIT_PERCENT`: The percentage of clients in that share, from 0 to
+    ///   100.
+    /// * `WEB_SERVER_PROXY_SPLIT_STICKY`: Keeps clients in or out of the share by their IP
+    ///   address with `client-address`, the default, or by a cookie with `cookie:<name>`.
+    /// * `WEB_SERVER_PROXY_MIRROR_UPSTREAM`: Also sends a copy of every proxied request to these
+    ///   comma-separated addresses in the background, discarding their responses.
+    /// * `WEB_SERVER_PROXY_MIRROR_MAX_IN_FLIGHT`: The most mirrored copies in flight, 64 by
+    ///   default, beyond which copies are dropped.
+    /// * `WEB_SERVER_FASTCGI_ADDRESS`: Enables running `.php` scripts on a FastCGI application at
+    ///   this address, e.g. `127.0.0.1:9000` or `unix:/run/php/php-fpm.sock`.
+    /// * `WEB_SERVER_FASTCGI_ROUTE`: The path prefix of scripts, `/` by default.
+    /// * `WEB_SERVER_FASTCGI_ROOT`: The script directory as the application sees it, the
+    ///   document root by default.
+    /// * `WEB_SERVER_FASTCGI_INDEX`: The script for paths ending in `/`, `index.php` by default.
+    /// * `WEB_SERVER_FASTCGI_TIMEOUT_SECS`: Seconds a script may take, 30 by default.
+    /// * `WEB_SERVER_CGI_DIR`: Enables running the executable files in this directory as CGI
+    ///   scripts.
+    /// * `WEB_SERVER_CGI_ROUTE`: The path prefix of CGI scripts, `/cgi-bin` by default.
+    /// * `WEB_SERVER_CGI_TIMEOUT_SECS`: Seconds a CGI script may run, 30 by default.
+    /// * `WEB_SERVER_CGI_MAX_PROCESSES`: CGI scripts running at a time, 16 by default.
+    /// * `WEB_SERVER_MAX_BODY_SIZE`: The body size limit in bytes for router handlers, the
+    ///   proxy, FastCGI, and CGI, 1 MiB by default. Routes can set another, see
+    ///   [`Router::with_limits`]. Uploads and tus have their own limits.
+    /// * `WEB_SERVER_REQUEST_TIMEOUT_SECS`: Seconds reading the body and handling a request may
+    ///   take before it is answered with 408 Request Timeout, for every handler but the static
+    ///   files and Markdown pages. Routes can set another.
+    /// * `WEB_SERVER_HEADER_TIMEOUT_SECS`: Seconds a connection may wait for the request line and
+    ///   headers of its next request before it is closed, 60 by default, or `0` for no limit.
+    /// * `WEB_SERVER_ALLOWED_METHODS`: A comma-separated allow-list of methods, all methods by
+    ///   default.
+    /// * `WEB_SERVER_DISABLED_METHODS`: A comma-separated list of refused methods, `TRACE,CONNECT`
+    ///   by default.
+    /// * `WEB_SERVER_STRICT`: Set to `0` to handle requests with ambiguous framing.
+    /// * `WEB_SERVER_ADMIN`: Set to `1` to serve the admin API on `127.0.0.1:7879`.
+    /// * `WEB_SERVER_ADMIN_ADDRESS`: Serves the admin API on this address instead, which requires
+    ///   a token unless it is a loopback address.
+    /// * `WEB_SERVER_ADMIN_TOKEN`: Requires `Authorization: Bearer <token>` on every admin
+    ///   request, and enables CPU profiles with the `profiling` feature.
+    /// * `WEB_SERVER_LOG_LEVEL`: `error`, `warn`, `info` (the default), or `debug`, which also
+    ///   logs every connection and request with their fields. `RUST_LOG` is used when it is
+    ///   unset and holds one of these levels.
+    /// * `WEB_SERVER_LOG_OUTPUT`: Where log messages go: `stderr` (the default), `stdout`,
+    ///   `file`, `syslog`, or `journald`.
+    /// * `WEB_SERVER_ERROR_LOG`: Writes log messages to this file, implying
+    ///   `WEB_SERVER_LOG_OUTPUT=file`.
+    /// * `WEB_SERVER_SYSLOG_ADDRESS`: The syslog daemon, `udp://<address>` or the path of a Unix
+    ///   socket, `/dev/log` by default.
+    /// * `WEB_SERVER_SYSLOG_FACILITY`: The syslog facility, e.g. `local0`, `daemon` by default.
+    /// * `WEB_SERVER_ACCESS_LOG`: Writes a line per handled request to this file.
+    /// * `WEB_SERVER_ACCESS_LOG_FORMAT`: The template of those lines with nginx-style variables,
+    ///   e.g. `$remote_addr $status $request_time $upstream_addr`, the combined format by
+    ///   default, see [`AccessFormat`].
+    /// * `WEB_SERVER_LOG_MAX_SIZE`: Rotates the logs before they grow past this many bytes.
+    /// * `WEB_SERVER_LOG_ROTATE_SECS`: Rotates the logs once they are this many seconds old.
+    /// * `WEB_SERVER_LOG_KEEP`: The rotated files kept per log, 7 by default. `SIGUSR1` reopens
+    ///   the logs after an external tool such as logrotate moved them.
+    /// * `WEB_SERVER_TRACE_SAMPLER`: Which traces are recorded: `always`, `never`,
+    ///   `ratio:<share>`, e.g. `ratio:0.1`, or one of those prefixed with `parent:` to follow
+    ///   the caller, `parent:always` by default. Only recorded requests are logged when they
+    ///   finish.
+    /// * `WEB_SERVER_TRACE_SAMPLER_ROUTES`: Other samplers for path prefixes, as comma-separated
+    ///   `route=sampler` entries, e.g. `/health=never,/api=parent:ratio:0.5`.
+    /// * `WEB_SERVER_SLOW_REQUEST_MS`: Logs a warning with the route, the sizes of the request
+    ///   and the upstream timing for requests taking longer than this many milliseconds, also
+    ///   once while they still run, whatever the log level.
+    /// * `WEB_SERVER_CAPTURE_PATHS`: Records the headers and the start of the bodies of requests
+    ///   below these comma-separated path prefixes and of their responses, for the admin API.
+    /// * `WEB_SERVER_CAPTURE_REQUEST_IDS`: Also records requests with these comma-separated
+    ///   `X-Request-Id` headers or trace IDs.
+    /// * `WEB_SERVER_CAPTURE_MAX_BODY_SIZE`: The bytes kept of each captured body, 4096 by
+    ///   default.
+    /// * `WEB_SERVER_CAPTURE_CAPACITY`: The captured exchanges kept, 100 by default.
+    /// * `WEB_SERVER_MAINTENANCE_ENABLED`: Set to `1` to answer requests with 503 Service
+    ///   Unavailable and a maintenance page, which the admin API can also turn on and off.
+    /// * `WEB_SERVER_MAINTENANCE_ALLOW`: Comma-separated path prefixes still handled in
+    ///   maintenance.
+    /// * `WEB_SERVER_MAINTENANCE_HEALTH`: Comma-separated path prefixes of health checks.
+    /// * `WEB_SERVER_MAINTENANCE_HEALTHY`: Set to `0` to answer health checks with 503 in
+    ///   maintenance, taking the server out of rotation, instead of handling them as usual.
+    /// * `WEB_SERVER_MAINTENANCE_PAGE`: The HTML file served in maintenance, a built-in page by
+    ///   default.
+    /// * `WEB_SERVER_MAINTENANCE_RETRY_AFTER_SECS`: The `Retry-After` of responses in
+    ///   maintenance.
+    /// * `WEB_SERVER_DRAIN_TIMEOUT_SECS`: Seconds a draining server waits for open connections,
+    ///   30 by default.
+    /// * `WEB_SERVER_MAX_CONCURRENT`: Handles this many requests at a time and queues the
+    ///   others.
+    /// * `WEB_SERVER_MAX_QUEUED`: The requests queued beyond that, answered with 503 Service
+    ///   Unavailable once the queue is full, as many as handled at a time by default.
+    /// * `WEB_SERVER_QUEUE_TIMEOUT_MS`: Milliseconds a queued request waits before it is
+    ///   answered with 503, 1000 by default.
+    /// * `WEB_SERVER_SHED_P99_MS`: Answers a growing share of requests with 503 Service
+    ///   Unavailable while the 99th percentile of the handler latencies exceeds this many
+    ///   milliseconds, see [`LoadShedder`].
+    /// * `WEB_SERVER_SHED_EXPENSIVE`: Comma-separated path prefixes refused entirely while
+    ///   shedding.
+    /// * `WEB_SERVER_SHED_EXEMPT`: Comma-separated path prefixes never shed, e.g. health checks.
+    /// * `WEB_SERVER_WORKER_THREADS`: The worker threads of the runtime, one per CPU core by
+    ///   default.
+    /// * `WEB_SERVER_MAX_BLOCKING_THREADS`: The most threads the runtime starts for blocking
+    ///   work, 512 by default.
+    /// * `WEB_SERVER_THREAD_PER_CORE`: Serves on this many threads with a runtime and listener
+    ///   each, or `0` for one per CPU core.
+    /// * `WEB_SERVER_IO_URING`: Set to `1` to serve those threads with io_uring, with the
+    ///   `io-uring` feature on Linux.
+    /// * `WEB_SERVER_USER` and `WEB_SERVER_GROUP`: The account to switch to once the listeners
+    ///   are bound, e.g. `www-data`, when started as root to bind ports 80 and 443.
+    /// * `WEB_SERVER_SANDBOX`: `chroot` to confine the binary to the document root, or
+    ///   `landlock` to confine it to the configured files and directories through Landlock.
+    /// * `WEB_SERVER_SANDBOX_READ` and `WEB_SERVER_SANDBOX_WRITE`: Further comma-separated paths
+    ///   Landlock grants reading or writing, e.g. the interpreters of CGI scripts.
+    /// * `WEB_SERVER_TLS_CERT` and `WEB_SERVER_TLS_KEY`: Enable HTTPS with the certificate chain
+    ///   and private key in these PEM files.
+    /// * `WEB_SERVER_TLS_ADDRESS`: The HTTPS address, `127.0.0.1:7443` by default.
+    /// * `WEB_SERVER_TLS_HOSTS`: Further certificates chosen by the host name clients ask for,
+    ///   as comma-separated `name=directory` entries, e.g. `example.org=/etc/tls/example.org`,
+    ///   with `cert.pem` and `key.pem` in each directory. A name like `*.example.org` stands
+    ///   for the subdomains.
+    /// * `WEB_SERVER_TLS_ALPN`: The comma-separated protocols offered through ALPN, `http/1.1` by
+    ///   default, or empty to skip ALPN.
+    /// * `WEB_SERVER_TLS_CLIENT_CA`: Verifies client certificates against the authorities in
+    ///   this PEM file.
+    /// * `WEB_SERVER_TLS_CLIENT_AUTH`: `required` (the default) to refuse clients without a
+    ///   certificate, or `optional`.
+    /// * `WEB_SERVER_TLS_OCSP`: Staples OCSP responses to the certificates named in these
+    ///   comma-separated `name=seconds` entries, where `default` names the default certificate
+    ///   and the seconds pass between two fetches, e.g. `default=3600,example.org=3600`.
+    /// * `WEB_SERVER_TLS_MIN_VERSION` and `WEB_SERVER_TLS_MAX_VERSION`: The lowest and highest
+    ///   TLS versions negotiated, `1.2` and `1.3` by default.
+    /// * `WEB_SERVER_TLS_CIPHER_SUITES`: The comma-separated IANA names of the cipher suites
+    ///   offered, most preferred first, all safe ones by default.
+    /// * `WEB_SERVER_TLS_KX_GROUPS`: The comma-separated names of the key exchange groups
+    ///   offered, e.g. `X25519,secp256r1`, all safe ones by default.
+    /// * `WEB_SERVER_TLS_SESSION_CACHE`: The number of sessions cached for resumption, 256 by
+    ///   default, or `0` for none.
+    /// * `WEB_SERVER_TLS_TICKET_KEYS`: A file with session ticket keys shared by every instance,
+    ///   one base64 encoded 32-byte key per line, newest first.
+    /// * `WEB_SERVER_TLS_TICKET_ROTATION_SECS`: Seconds between two random session ticket keys
+    ///   without `WEB_SERVER_TLS_TICKET_KEYS`, 21600 by default, or `0` to issue no tickets.
+    /// * `WEB_SERVER_TLS_WATCH_SECS`: Seconds between checks of the certificate files for
+    ///   changes, 60 by default, or `0` to reload them only with the configuration.
+    /// * `WEB_SERVER_TLS_HANDSHAKE_TIMEOUT_SECS`: Seconds a client may take to complete the TLS
+    ///   handshake before it is disconnected, 10 by default, or `0` for no limit.
+    /// * `WEB_SERVER_ACME_DOMAINS`: Enables HTTPS with a certificate for these comma-separated
+    ///   domains from an ACME certificate authority, instead of `WEB_SERVER_TLS_CERT`.
+    /// * `WEB_SERVER_ACME_DIR`: The directory storing the ACME account and certificate, `acme`
+    ///   by default.
+    /// * `WEB_SERVER_ACME_CONTACT`: Comma-separated contact URLs for the ACME account, e.g.
+    ///   `mailto:admin@example.com`.
+    /// * `WEB_SERVER_ACME_DIRECTORY_URL`: The ACME directory, Let's Encrypt by default.
+    /// * `WEB_SERVER_ACME_CHALLENGE`: `http-01` (the default) or `tls-alpn-01`.
+    /// * `WEB_SERVER_ACME_RENEW_BEFORE_DAYS`: Days before expiry the certificate is renewed, 30
+    ///   by default.
+    ///
+    /// # Returns
+    ///
+    /// The configuration or an error describing the invalid variable.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`io::ErrorKind::InvalidInput`] when only one of the WebDAV credentials is set, the
+    /// admin API listens beyond the loopback interface without a token, a numeric variable, method
+    /// list, proxy strategy, trace sampler, TLS host or version, OCSP entry, ACME domain, or script
+    /// cannot be parsed, the TLS versions and algorithms leave nothing to negotiate, or a string of
+    /// the file refers to an unset variable without a default, [`io::ErrorKind::InvalidData`] when
+    /// the configuration file is not TOML or the TLS files hold no usable certificate or key,
+    /// [`io::ErrorKind::Unsupported`] for an ALPN protocol the server cannot serve or a TLS
+    /// algorithm it does not implement, and captures IO errors from reading the configuration, TLS,
+    /// and script files and opening the proxy cache directory.
+    pub fn from_env() -> io::Result<Config> {
+        match env::var_os("WEB_SERVER_CONFIG") {
+            Some(path) => Config::from_file(Path::new(&path)),
+            None => Config::from_vars(&Vars {
+                environment: true,
+                file: HashMap::new(),
+            }),
+        }
+    }
+
+    /// Builds a configuration like [`Config::from_env`], from a configuration file given
+    /// explicitly instead of through `WEB_SERVER_CONFIG`.
+    ///
+    /// # Arguments
+    ///
+    /// * `path`: The TOML file.
+    ///
+    /// # Returns
+    ///
+    /// The configuration or an error describing the invalid key.
+    ///
+    /// # Errors
+    ///
+    /// Returns the errors of [`Config::from_env`].
+    pub fn from_file(path: &Path) -> io::Result<Config> {
+        let text = fs::read_to_string(path).map_err(|error| {
+            io::Error::new(
+                error.kind(),
+                format!("reading {} failed: {}", path.display(), error),
+            )
+        })?;
+        Config::from_vars(&Vars {
+            environment: true,
+            file: flatten_toml(&text, &|name| env::var(name).ok())?,
+        })
+    }
+
+    /// Builds a configuration from the text of a configuration file alone, ignoring the
+    /// environment, see [`Config::from_env`] for the keys.
+    ///
+    /// # Arguments
+    ///
+    /// * `text`: The TOML text, e.g. `root = "/srv/www"`.
+    ///
+    /// # Returns
+    ///
+    /// The configuration or an error describing the invalid key.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`io::ErrorKind::InvalidData`] when the text is not TOML, and otherwise the errors
+    /// of [`Config::from_env`].
+    pub fn from_toml(text: &str) -> io::Result<Config> {
+        Config::from_vars(&Vars {
+            environment: false,
+            file: flatten_toml(text, &|_| None)?,
+        })
+    }
+
+    /// Builds a configuration from variables, see [`Config::from_env`].
+    fn from_vars(vars: &Vars) -> io::Result<Config> {
+        let mut config = Config::default();
+        if let Some(root) = vars.var_os("WEB_SERVER_ROOT") {
+            config.document_root = PathBuf::from(root);
+        }
+        config.webdav = match (
+            vars.var("WEB_SERVER_WEBDAV_USERNAME"),
+            vars.var("WEB_SERVER_WEBDAV_PASSWORD"),
+        ) {
+            (Ok(username), Ok(password)) => Some(WebDavConfig { username, password }),
+            (Err(_), Err(_)) => None,
+            _ => return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "WEB_SERVER_WEBDAV_USERNAME and WEB_SERVER_WEBDAV_PASSWORD must be set together",
+            )),
+        };
+        if let Some(directory) = vars.var_os("WEB_SERVER_UPLOAD_DIR") {
+            config.upload = Some(UploadConfig {
+                route: vars
+                    .var("WEB_SERVER_UPLOAD_ROUTE")
+                    .unwrap_or_else(|_| "/upload".to_string()),
+                directory: PathBuf::from(directory),
+                max_size: vars
+                    .parse("WEB_SERVER_UPLOAD_MAX_SIZE")?
+                    .unwrap_or(10 * 1024 * 1024),
+            });
+        }
+        if let Some(directory) = vars.var_os("WEB_SERVER_TUS_DIR") {
+            config.tus = Some(TusConfig {
+                route: vars
+                    .var("WEB_SERVER_TUS_ROUTE")
+                    .unwrap_or_else(|_| "/files".to_string()),
+                directory: PathBuf::from(directory),
+                max_size: vars
+                    .parse("WEB_SERVER_TUS_MAX_SIZE")?
+                    .unwrap_or(1024 * 1024 * 1024),
+                expiration: Duration::from_secs(
+                    vars.parse("WEB_SERVER_TUS_EXPIRATION_SECS")?
+                        .unwrap_or(24 * 60 * 60),
+                ),
+            });
+        }
+        if vars
+            .var("WEB_SERVER_MARKDOWN")
+            .is_ok_and(|value| value == "1")
+        {
+            config.markdown = Some(MarkdownConfig {
+                template: vars
+                    .var_os("WEB_SERVER_MARKDOWN_TEMPLATE")
+                    .map(PathBuf::from),
+            });
+        }
+        config.language_variants = vars
+            .var("WEB_SERVER_LANGUAGE_VARIANTS")
+            .is_ok_and(|value| value == "1");
+        config.case_insensitive_paths = vars
+            .var("WEB_SERVER_CASE_INSENSITIVE_PATHS")
+            .is_ok_and(|value| value == "1");
+        config.rewrites = vars.numbered("WEB_SERVER_REWRITE")?;
+        config.redirects = vars.numbered("WEB_SERVER_REDIRECT")?;
+        #[cfg(feature = "scripting")]
+        if let Some(path) = vars.var_os("WEB_SERVER_SCRIPT") {
+            let source = fs::read_to_string(&path).map_err(|error| {
+                io::Error::new(
+                    error.kind(),
+                    format!("reading {} failed: {}", Path::new(&path).display(), error),
+                )
+            })?;
+            let mut scripts = ScriptHooks::new(&source)?;
+            if let Some(operations) = vars.parse("WEB_SERVER_SCRIPT_MAX_OPERATIONS")? {
+                scripts = scripts.with_max_operations(operations);
+            }
+            if let Some(timeout) = vars.parse("WEB_SERVER_SCRIPT_TIMEOUT_MS")? {
+                scripts = scripts.with_timeout(Duration::from_millis(timeout));
+            }
+            config.scripts = Some(scripts);
+        }
+        #[cfg(feature = "wasm")]
+        {
+            let max_fuel: Option<u64> = vars.parse("WEB_SERVER_WASM_MAX_FUEL")?;
+            let plugins: Vec<WasmPlugin> = vars.numbered("WEB_SERVER_WASM_PLUGIN")?;
+            config.plugins = plugins
+                .into_iter()
+                .map(|plugin| match max_fuel {
+                    Some(max_fuel) => plugin.with_max_fuel(max_fuel),
+                    None => plugin,
+                })
+                .collect();
+        }
+        if let Ok(route) = vars.var("WEB_SERVER_METRICS_ROUTE") {
+            config.metrics = Some(MetricsConfig { route });
+        }
+        if let Ok(upstreams) = vars.var("WEB_SERVER_PROXY_UPSTREAM") {
+            let route = vars
+                .var("WEB_SERVER_PROXY_ROUTE")
+                .unwrap_or_else(|_| "/".to_string());
+            let mut weighted = Vec::new();
+            for upstream in upstreams.split(',').map(str::trim) {
+                weighted.push(match upstream.split_once('=') {
+                    Some((address, weight)) => match weight.parse::<u32>() {
+                        Ok(weight) => (address, weight),
+                        Err(_) => {
+                            return Err(io::Error::new(
+                                io::ErrorKind::InvalidInput,
+                                format!(
+                                    "WEB_SERVER_PROXY_UPSTREAM has an invalid weight: {}",
+                                    upstream
+                                ),
+                            ))
+                        }
+                    },
+                    None => (upstream, 1),
+                });
+            }
+            let mut proxy = Proxy::new(&route, weighted[0].0);
+            for (address, _) in &weighted[1..] {
+                proxy = proxy.with_upstream(*address);
+            }
+            for (address, weight) in &weighted {
+                proxy.set_weight(address, *weight);
+            }
+            proxy = match vars.var("WEB_SERVER_PROXY_STRATEGY").as_deref() {
+                Ok("round-robin") | Err(_) => proxy.with_strategy(Strategy::RoundRobin),
+                Ok("least-connections") => proxy.with_strategy(Strategy::LeastConnections),
+                Ok(strategy) => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        format!(
+                            "WEB_SERVER_PROXY_STRATEGY has an invalid value: {}",
+                            strategy
+                        ),
+                    ))
+                }
+            };
+            let timeout = vars
+                .parse("WEB_SERVER_PROXY_TIMEOUT_SECS")?
+                .map(Duration::from_secs);
+            if let Some(timeout) = timeout {
+                proxy = proxy.with_timeout(timeout);
+            }
+            if let Some(size) = vars.parse("WEB_SERVER_PROXY_MAX_RESPONSE_SIZE")? {
+                proxy = proxy.with_max_response_size(size);
+            }
+            if let Some(directory) = vars.var_os("WEB_SERVER_PROXY_CACHE_DIR") {
+                proxy = proxy.with_cache(DiskCache::open(directory)?);
+            }
+            let mut health_check = None;
+            if let Ok(path) = vars.var("WEB_SERVER_PROXY_HEALTH_PATH") {
+                let defaults = HealthCheck::default();
+                health_check = Some(HealthCheck {
+                    path,
+                    interval: vars
+                        .parse("WEB_SERVER_PROXY_HEALTH_INTERVAL_SECS")?
+                        .map_or(defaults.interval, Duration::from_secs),
+                    healthy_threshold: vars
+                        .parse("WEB_SERVER_PROXY_HEALTHY_THRESHOLD")?
+                        .unwrap_or(defaults.healthy_threshold),
+                    unhealthy_threshold: vars
+                        .parse("WEB_SERVER_PROXY_UNHEALTHY_THRESHOLD")?
+                        .unwrap_or(defaults.unhealthy_threshold),
+                    ..defaults
+                });
+            }
+            if let Some(check) = &health_check {
+                proxy = proxy.with_health_check(check.clone());
+            }
+            if let Some(error_rate) = vars.parse("WEB_SERVER_PROXY_BREAKER_ERROR_RATE")? {
+                let defaults = CircuitBreaker::default();
+                proxy = proxy.with_circuit_breaker(CircuitBreaker {
+                    error_rate,
+                    minimum_requests: vars
+                        .parse("WEB_SERVER_PROXY_BREAKER_MIN_REQUESTS")?
+                        .unwrap_or(defaults.minimum_requests),
+                    cooldown: vars
+                        .parse("WEB_SERVER_PROXY_BREAKER_COOLDOWN_SECS")?
+                        .map_or(defaults.cooldown, Duration::from_secs),
+                    ..defaults
+                });
+            }
+            if let Some(max_retries) = vars.parse("WEB_SERVER_PROXY_RETRIES")? {
+                let defaults = RetryPolicy::default();
+                proxy = proxy.with_retry(RetryPolicy {
+                    max_retries,
+                    per_try_timeout: vars
+                        .parse("WEB_SERVER_PROXY_RETRY_TIMEOUT_SECS")?
+                        .map(Duration::from_secs),
+                    budget_ratio: vars
+                        .parse("WEB_SERVER_PROXY_RETRY_BUDGET")?
+                        .unwrap_or(defaults.budget_ratio),
+                    ..defaults
+                });
+            }
+            match vars.var("WEB_SERVER_PROXY_AFFINITY").as_deref() {
+                Err(_) => {}
+                Ok("client-address") => proxy = proxy.with_affinity(Affinity::ClientAddress),
+                Ok(affinity) => match affinity.strip_prefix("cookie:") {
+                    Some(name) if !name.is_empty() => {
+                        proxy = proxy.with_affinity(Affinity::Cookie(name.to_string()))
+                    }
+                    _ => {
+                        return Err(io::Error::new(
+                            io::ErrorKind::InvalidInput,
+                            format!(
+                                "WEB_SERVER_PROXY_AFFINITY has an invalid value: {}",
+                                affinity
+                            ),
+                        ))
+                    }
+                },
+            }
+            let pool = |upstreams: String| {
+                let mut upstreams = upstreams.split(',').map(str::trim);
+                let mut pool = Proxy::new(&route, upstreams.next().unwrap_or_default());
+                for upstream in upstreams {
+                    pool = pool.with_upstream(upstream);
+                }
+                if let Some(timeout) = timeout {
+                    pool = pool.with_timeout(timeout);
+                }
+                if let Some(check) = &health_check {
+                    pool = pool.with_health_check(check.clone());
+                }
+                pool
+            };
+            if let Ok(upstreams) = vars.var("WEB_SERVER_PROXY_CANARY_UPSTREAM") {
+                let mut rules = Vec::new();
+                if let Ok(rule) = vars.var("WEB_SERVER_PROXY_CANARY_HEADER") {
+                    rules.push(CanaryRule::header(&rule).map_err(|error| {
+                        io::Error::new(
+                            error.kind(),
+                            format!("WEB_SERVER_PROXY_CANARY_HEADER: {}", error),
+                        )
+                    })?);
+                }
+                if let Ok(rule) = vars.var("WEB_SERVER_PROXY_CANARY_COOKIE") {
+                    rules.push(CanaryRule::cookie(&rule).map_err(|error| {
+                        io::Error::new(
+                            error.kind(),
+                            format!("WEB_SERVER_PROXY_CANARY_COOKIE: {}", error),
+                        )
+                    })?);
+                }
+                if rules.is_empty() {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        "WEB_SERVER_PROXY_CANARY_UPSTREAM needs WEB_SERVER_PROXY_CANARY_HEADER or \
+                         WEB_SERVER_PROXY_CANARY_COOKIE",
+                    ));
+                }
+                proxy = proxy.with_canary(rules, pool(upstreams));
+            }
+            if let Ok(upstreams) = vars.var("WEB_SERVER_PROXY_SPLIT_UPSTREAM") {
+                let percent = vars
+                    .parse::<u8>("WEB_SERVER_PROXY_SPLIT_PERCENT")?
+                    .filter(|percent| *percent <= 100)
+                    .ok_or_else(|| {
+                        io::Error::new(
+                            io::ErrorKind::InvalidInput,
+                            "WEB_SERVER_PROXY_SPLIT_UPSTREAM needs WEB_SERVER_PROXY_SPLIT_PERCENT \
+                             from 0 to 100",
+                        )
+                    })?;
+                let stickiness = match vars.var("WEB_SERVER_PROXY_SPLIT_STICKY").as_deref() {
+                    Ok("client-address") | Err(_) => Stickiness::ClientAddress,
+                    Ok(sticky) => match sticky.strip_prefix("cookie:") {
+                        Some(name) if !name.is_empty() => Stickiness::Cookie(name.to_string()),
+                        _ => {
+                            return Err(io::Error::new(
+                                io::ErrorKind::InvalidInput,
+                                format!(
+                                    "WEB_SERVER_PROXY_SPLIT_STICKY has an invalid value: {}",
+                                    sticky
+                                ),
+                            ))
+                        }
+                    },
+                };
+                let rule = SplitRule {
+                    percent,
+                    stickiness,
+                };
+                proxy = proxy.with_split(rule, pool(upstreams));
+            }
+            if let Ok(upstreams) = vars.var("WEB_SERVER_PROXY_MIRROR_UPSTREAM") {
+                proxy = proxy.with_mirror(pool(upstreams));
+            }
+            if let Some(max) = vars.parse("WEB_SERVER_PROXY_MIRROR_MAX_IN_FLIGHT")? {
+                proxy = proxy.with_max_mirrored(max);
+            }
+            config.proxy = Some(proxy);
+        }
+        #[cfg(feature = "grpc")]
+        if let Ok(upstream) = vars.var("WEB_SERVER_GRPC_UPSTREAM") {
+            let mut grpc = GrpcProxy::new(upstream);
+            if let Some(timeout) = vars.parse("WEB_SERVER_GRPC_CONNECT_TIMEOUT_MS")? {
+                grpc = grpc.with_connect_timeout(Duration::from_millis(timeout));
+            }
+            config.grpc = Some(grpc);
+        }
+        if let Ok(address) = vars.var("WEB_SERVER_FASTCGI_ADDRESS") {
+            let route = vars
+                .var("WEB_SERVER_FASTCGI_ROUTE")
+                .unwrap_or_else(|_| "/".to_string());
+            let root = vars
+                .var_os("WEB_SERVER_FASTCGI_ROOT")
+                .map_or_else(|| config.document_root.clone(), PathBuf::from);
+            let mut fastcgi = FastCgi::new(&route, &address, root);
+            if let Ok(index) = vars.var("WEB_SERVER_FASTCGI_INDEX") {
+                fastcgi = fastcgi.with_index(&index);
+            }
+            if let Some(timeout) = vars.parse("WEB_SERVER_FASTCGI_TIMEOUT_SECS")? {
+                fastcgi = fastcgi.with_timeout(Duration::from_secs(timeout));
+            }
+            config.fastcgi = Some(fastcgi);
+        }
+        if let Some(directory) = vars.var_os("WEB_SERVER_CGI_DIR") {
+            let route = vars
+                .var("WEB_SERVER_CGI_ROUTE")
+                .unwrap_or_else(|_| "/cgi-bin".to_string());
+            let mut cgi = Cgi::new(&route, directory);
+            if let Some(timeout) = vars.parse("WEB_SERVER_CGI_TIMEOUT_SECS")? {
+                cgi = cgi.with_timeout(Duration::from_secs(timeout));
+            }
+            if let Some(processes) = vars.parse("WEB_SERVER_CGI_MAX_PROCESSES")? {
+                cgi = cgi.with_max_processes(processes);
+            }
+            config.cgi = Some(cgi);
+        }
+        if let Some(max_body_size) = vars.parse("WEB_SERVER_MAX_BODY_SIZE")? {
+            config.max_body_size = max_body_size;
+        }
+        config.request_timeout = vars
+            .parse("WEB_SERVER_REQUEST_TIMEOUT_SECS")?
+            .map(Duration::from_secs);
+        if let Some(seconds) = vars.parse("WEB_SERVER_HEADER_TIMEOUT_SECS")? {
+            config.header_timeout =
+                Some(Duration::from_secs(seconds)).filter(|timeout| !timeout.is_zero());
+        }
+        if let Ok(allowed) = vars.var("WEB_SERVER_ALLOWED_METHODS") {
+            config.methods.allowed = Some(parse_methods("WEB_SERVER_ALLOWED_METHODS", &allowed)?);
+        }
+        if let Ok(disabled) = vars.var("WEB_SERVER_DISABLED_METHODS") {
+            config.methods.disabled = parse_methods("WEB_SERVER_DISABLED_METHODS", &disabled)?;
+        }
+        config.strict = !vars
+            .var("WEB_SERVER_STRICT")
+            .is_ok_and(|value| value == "0");
+        if let Some(address) = vars.parse("WEB_SERVER_ADMIN_ADDRESS")? {
+            config.admin = Some(AdminConfig {
+                address,
+                ..AdminConfig::default()
+            });
+        } else if vars.var("WEB_SERVER_ADMIN").is_ok_and(|value| value == "1") {
+            config.admin = Some(AdminConfig::default());
+        }
+        if let (Some(admin), Ok(token)) = (&mut config.admin, vars.var("WEB_SERVER_ADMIN_TOKEN")) {
+            admin.token = Some(token).filter(|token| !token.is_empty());
+        }
+        if let Some(admin) = config.admin.as_ref().filter(|admin| admin.is_exposed()) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "WEB_SERVER_ADMIN_TOKEN is required for the admin API on {}, which is not \
+                     a loopback address",
+                    admin.address
+                ),
+            ));
+        }
+        if let Some(level) = vars.parse("WEB_SERVER_LOG_LEVEL")? {
+            config.log_level = level;
+        } else if let Some(Ok(level)) = vars.var("RUST_LOG").ok().map(|value| value.parse()) {
+            config.log_level = level;
+        }
+        config.log_output = match (
+            vars.var("WEB_SERVER_LOG_OUTPUT").as_deref(),
+            vars.var_os("WEB_SERVER_ERROR_LOG"),
+        ) {
+            (Err(_) | Ok("file"), Some(path)) => Output::File(PathBuf::from(path)),
+            (Err(_) | Ok("stderr"), None) => Output::Stderr,
+            (Ok("stdout"), None) => Output::Stdout,
+            (Ok("syslog"), None) => Output::Syslog(
+                vars.parse("WEB_SERVER_SYSLOG_ADDRESS")?.unwrap_or_default(),
+                vars.parse("WEB_SERVER_SYSLOG_FACILITY")?
+                    .unwrap_or_default(),
+            ),
+            (Ok("journald"), None) => Output::Journald,
+            (Ok("file"), None) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "WEB_SERVER_LOG_OUTPUT=file needs WEB_SERVER_ERROR_LOG",
+                ))
+            }
+            (Ok(output), _) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("WEB_SERVER_LOG_OUTPUT has an invalid value: {}", output),
+                ))
+            }
+        };
+        config.access_log = vars.var_os("WEB_SERVER_ACCESS_LOG").map(PathBuf::from);
+        if let Ok(template) = vars.var("WEB_SERVER_ACCESS_LOG_FORMAT") {
+            config.access_log_format = template.parse().map_err(|error| {
+                io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("WEB_SERVER_ACCESS_LOG_FORMAT is invalid: {}", error),
+                )
+            })?;
+        }
+        config.log_rotation = Rotation {
+            max_size: vars.parse("WEB_SERVER_LOG_MAX_SIZE")?,
+            interval: vars
+                .parse("WEB_SERVER_LOG_ROTATE_SECS")?
+                .map(Duration::from_secs),
+            keep: vars
+                .parse("WEB_SERVER_LOG_KEEP")?
+                .unwrap_or(config.log_rotation.keep),
+        };
+        if let Some(sampler) = vars.parse("WEB_SERVER_TRACE_SAMPLER")? {
+            config.trace_sampling = Sampling::new(sampler);
+        }
+        if let Ok(entries) = vars.var("WEB_SERVER_TRACE_SAMPLER_ROUTES") {
+            for entry in entries.split(',').map(str::trim) {
+                let (route, sampler) = entry
+                    .split_once('=')
+                    .and_then(|(route, sampler)| Some((route.trim(), sampler.parse().ok()?)))
+                    .filter(|(route, _)| route.starts_with('/'))
+                    .ok_or_else(|| {
+                        io::Error::new(
+                            io::ErrorKind::InvalidInput,
+                            format!(
+                                "WEB_SERVER_TRACE_SAMPLER_ROUTES has an invalid entry: {}",
+                                entry
+                            ),
+                        )
+                    })?;
+                config.trace_sampling = config.trace_sampling.with_route(route, sampler);
+            }
+        }
+        config.slow_request = vars
+            .parse("WEB_SERVER_SLOW_REQUEST_MS")?
+            .map(Duration::from_millis);
+        let list = |name: &str| -> Vec<String> {
+            vars.var(name)
+                .map(|value| {
+                    value
+                        .split(',')
+                        .map(str::trim)
+                        .filter(|item| !item.is_empty())
+                        .map(String::from)
+                        .collect()
+                })
+                .unwrap_or_default()
+        };
+        config.maintenance = Maintenance {
+            enabled: vars
+                .var("WEB_SERVER_MAINTENANCE_ENABLED")
+                .is_ok_and(|value| value == "1"),
+            allow: list("WEB_SERVER_MAINTENANCE_ALLOW"),
+            health: list("WEB_SERVER_MAINTENANCE_HEALTH"),
+            healthy: vars
+                .var("WEB_SERVER_MAINTENANCE_HEALTHY")
+                .map_or(true, |value| value != "0"),
+            page: match vars.var("WEB_SERVER_MAINTENANCE_PAGE") {
+                Ok(path) => fs::read_to_string(&path).map_err(|error| {
+                    io::Error::new(
+                        error.kind(),
+                        format!(
+                            "WEB_SERVER_MAINTENANCE_PAGE: reading {} failed: {}",
+                            path, error
+                        ),
+                    )
+                })?,
+                Err(_) => Maintenance::new().page,
+            },
+            retry_after: vars
+                .parse("WEB_SERVER_MAINTENANCE_RETRY_AFTER_SECS")?
+                .map(Duration::from_secs),
+        };
+        if let Some(target) = vars.parse("WEB_SERVER_SHED_P99_MS")? {
+            let mut shedder = LoadShedder::new(Duration::from_millis(target));
+            for prefix in list("WEB_SERVER_SHED_EXPENSIVE") {
+                shedder = shedder.with_expensive(prefix);
+            }
+            for prefix in list("WEB_SERVER_SHED_EXEMPT") {
+                shedder = shedder.with_exempt(prefix);
+            }
+            config.load_shedding = Some(shedder);
+        }
+        let paths = list("WEB_SERVER_CAPTURE_PATHS");
+        let request_ids = list("WEB_SERVER_CAPTURE_REQUEST_IDS");
+        if !paths.is_empty() || !request_ids.is_empty() {
+            let defaults = Capture::new();
+            config.capture = Some(Capture {
+                paths,
+                request_ids,
+                max_body_size: vars
+                    .parse("WEB_SERVER_CAPTURE_MAX_BODY_SIZE")?
+                    .unwrap_or(defaults.max_body_size),
+                capacity: vars
+                    .parse("WEB_SERVER_CAPTURE_CAPACITY")?
+                    .unwrap_or(defaults.capacity),
+            });
+        }
+        if let Some(timeout) = vars.parse("WEB_SERVER_DRAIN_TIMEOUT_SECS")? {
+            config.drain_timeout = Duration::from_secs(timeout);
+        }
+        if let Some(max_running) = vars.parse("WEB_SERVER_MAX_CONCURRENT")? {
+            let mut limit = ConcurrencyLimit::new(max_running);
+            if let Some(max_queued) = vars.parse("WEB_SERVER_MAX_QUEUED")? {
+                limit = limit.with_max_queued(max_queued);
+            }
+            if let Some(timeout) = vars.parse("WEB_SERVER_QUEUE_TIMEOUT_MS")? {
+                limit = limit.with_max_wait(Duration::from_millis(timeout));
+            }
+            config.concurrency = Some(limit);
+        }
+        if let Some(threads) = vars.parse("WEB_SERVER_THREAD_PER_CORE")? {
+            config.thread_per_core = Some(threads);
+        }
+        config.user = vars.var("WEB_SERVER_USER").ok();
+        config.group = vars.var("WEB_SERVER_GROUP").ok();
+        config.sandbox = match vars.var("WEB_SERVER_SANDBOX").as_deref() {
+            Err(_) => None,
+            Ok("chroot") => Some(Sandbox::Chroot(config.document_root.clone())),
+            Ok("landlock") => Some(landlock_paths(vars, &config)),
+            Ok(mode) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("WEB_SERVER_SANDBOX has an invalid value: {}", mode),
+                ))
+            }
+        };
+        if let Some(threads) = vars.parse("WEB_SERVER_WORKER_THREADS")? {
+            config.worker_threads = Some(threads);
+        }
+        if let Some(threads) = vars.parse("WEB_SERVER_MAX_BLOCKING_THREADS")? {
+            config.max_blocking_threads = Some(threads);
+        }
+        #[cfg(all(feature = "io-uring", target_os = "linux"))]
+        if vars
+            .var("WEB_SERVER_IO_URING")
+            .is_ok_and(|value| value == "1")
+        {
+            config.io_uring = true;
+        }
+        #[cfg(feature = "acme")]
+        if let Ok(domains) = vars.var("WEB_SERVER_ACME_DOMAINS") {
+            let split_list = |value: &str| {
+                value
+                    .split(',')
+                    .map(str::trim)
+                    .filter(|item| !item.is_empty())
+                    .map(String::from)
+                    .collect()
+            };
+            let directory = vars
+                .var_os("WEB_SERVER_ACME_DIR")
+                .unwrap_or_else(|| "acme".into());
+            let mut acme = Acme::new(split_list(&domains), directory)?;
+            if let Ok(contacts) = vars.var("WEB_SERVER_ACME_CONTACT") {
+                acme = acme.with_contacts(split_list(&contacts));
+            }
+            if let Ok(url) = vars.var("WEB_SERVER_ACME_DIRECTORY_URL") {
+                acme = acme.with_directory_url(&url);
+            }
+            if let Some(challenge) = vars.parse("WEB_SERVER_ACME_CHALLENGE")? {
+                acme = acme.with_challenge(challenge);
+            }
+            if let Some(days) = vars.parse::<u64>("WEB_SERVER_ACME_RENEW_BEFORE_DAYS")? {
+                acme = acme.with_renew_before(Duration::from_secs(days * 24 * 60 * 60));
+            }
+            config.acme = Some(acme);
+        }
+        #[cfg(feature = "tls")]
+        {
+            let files = match (
+                vars.var_os("WEB_SERVER_TLS_CERT"),
+                vars.var_os("WEB_SERVER_TLS_KEY"),
+            ) {
+                (Some(certificate), Some(key)) => {
+                    Some((PathBuf::from(certificate), PathBuf::from(key)))
+                }
+                (None, None) => None,
+                _ => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        "WEB_SERVER_TLS_CERT and WEB_SERVER_TLS_KEY must be set together",
+                    ))
+                }
+            };
+            #[cfg(feature = "acme")]
+            let files = match (files, &config.acme) {
+                (Some(_), Some(_)) => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        "WEB_SERVER_ACME_DOMAINS and WEB_SERVER_TLS_CERT cannot be combined",
+                    ))
+                }
+                (None, Some(acme)) => Some((acme.certificate_path(), acme.key_path())),
+                (files, None) => files,
+            };
+            config.tls = match files {
+                Some((certificate, key)) => {
+                    let mut tls = Tls::new(certificate, key)?;
+                    if let Some(address) = vars.parse("WEB_SERVER_TLS_ADDRESS")? {
+                        tls = tls.with_address(address);
+                    }
+                    if let Some(seconds) = vars.parse("WEB_SERVER_TLS_WATCH_SECS")? {
+                        tls = tls.with_watch_interval(
+                            Some(Duration::from_secs(seconds))
+                                .filter(|interval| !interval.is_zero()),
+                        );
+                    }
+                    if let Some(seconds) = vars.parse("WEB_SERVER_TLS_HANDSHAKE_TIMEOUT_SECS")? {
+                        tls = tls.with_handshake_timeout(
+                            Some(Duration::from_secs(seconds)).filter(|timeout| !timeout.is_zero()),
+                        );
+                    }
+                    if let Ok(hosts) = vars.var("WEB_SERVER_TLS_HOSTS") {
+                        for host in hosts.split(',').map(str::trim) {
+                            let (name, directory) = host
+                                .split_once('=')
+                                .filter(|(name, _)| !name.trim().is_empty())
+                                .ok_or_else(|| {
+                                    io::Error::new(
+                                        io::ErrorKind::InvalidInput,
+                                        format!(
+                                            "WEB_SERVER_TLS_HOSTS has an invalid host: {}",
+                                            host
+                                        ),
+                                    )
+                                })?;
+                            let directory = Path::new(directory.trim());
+                            tls = tls.with_host(
+                                name,
+                                directory.join("cert.pem"),
+                                directory.join("key.pem"),
+                            )?;
+                        }
+                    }
+                    if let Ok(entries) = vars.var("WEB_SERVER_TLS_OCSP") {
+                        for entry in entries.split(',').map(str::trim) {
+                            let (name, refresh) = entry
+                                .split_once('=')
+                                .and_then(|(name, seconds)| {
+                                    let seconds = seconds.trim().parse().ok()?;
+                                    Some((name.trim(), Duration::from_secs(seconds)))
+                                })
+                                .filter(|(_, refresh)| !refresh.is_zero())
+                                .ok_or_else(|| {
+                                    io::Error::new(
+                                        io::ErrorKind::InvalidInput,
+                                        format!(
+                                            "WEB_SERVER_TLS_OCSP has an invalid entry: {}",
+                                            entry
+                                        ),
+                                    )
+                                })?;
+                            let host = Some(name).filter(|name| *name != "default");
+                            tls = tls.with_ocsp_stapling(host, refresh)?;
+                        }
+                    }
+                    let min_version = vars.parse("WEB_SERVER_TLS_MIN_VERSION")?;
+                    let max_version = vars.parse("WEB_SERVER_TLS_MAX_VERSION")?;
+                    if min_version.is_some() || max_version.is_some() {
+                        tls = tls.with_versions(
+                            min_version.unwrap_or(TlsVersion::Tls12),
+                            max_version.unwrap_or(TlsVersion::Tls13),
+                        )?;
+                    }
+                    if let Ok(names) = vars.var("WEB_SERVER_TLS_CIPHER_SUITES") {
+                        let names = names
+                            .split(',')
+                            .map(str::trim)
+                            .filter(|name| !name.is_empty())
+                            .collect::<Vec<_>>();
+                        tls = tls.with_cipher_suites(&names)?;
+                    }
+                    if let Ok(names) = vars.var("WEB_SERVER_TLS_KX_GROUPS") {
+                        let names = names
+                            .split(',')
+                            .map(str::trim)
+                            .filter(|name| !name.is_empty())
+                            .collect::<Vec<_>>();
+                        tls = tls.with_kx_groups(&names)?;
+                    }
+                    if let Some(capacity) = vars.parse("WEB_SERVER_TLS_SESSION_CACHE")? {
+                        tls = tls.with_session_cache(capacity)?;
+                    }
+                    if let Some(keys) = vars.var_os("WEB_SERVER_TLS_TICKET_KEYS") {
+                        tls = tls.with_session_tickets(TicketKeys::Shared(PathBuf::from(keys)))?;
+                    } else if let Some(seconds) =
+                        vars.parse("WEB_SERVER_TLS_TICKET_ROTATION_SECS")?
+                    {
+                        tls = tls.with_session_tickets(match Duration::from_secs(seconds) {
+                            rotation if rotation.is_zero() => TicketKeys::Disabled,
+                            rotation => TicketKeys::Rotating(rotation),
+                        })?;
+                    }
+                    if let Ok(protocols) = vars.var("WEB_SERVER_TLS_ALPN") {
+                        let protocols = protocols
+                            .split(',')
+                            .map(str::trim)
+                            .filter(|protocol| !protocol.is_empty())
+                            .collect::<Vec<_>>();
+                        tls = tls.with_alpn_protocols(&protocols)?;
+                    }
+                    if let Some(ca) = vars.var_os("WEB_SERVER_TLS_CLIENT_CA") {
+                        let mode = vars
+                            .parse("WEB_SERVER_TLS_CLIENT_AUTH")?
+                            .unwrap_or_default();
+                        tls = tls.with_client_auth(ca, mode)?;
+                    }
+                    Some(tls)
+                }
+                None if vars.var_os("WEB_SERVER_TLS_HOSTS").is_some() => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        "WEB_SERVER_TLS_HOSTS needs a default certificate",
+                    ))
+                }
+                None => None,
+            };
+        }
+        Ok(config)
+    }
+}
+
+/// The variables a configuration is built from: `WEB_SERVER_*` environment variables, falling
+/// back to the keys of a configuration file.
+struct Vars {
+    /// Whether environment variables are consulted at all.
+    environment: bool,
+    /// The keys of the configuration file as variable names, see [`flatten_toml`].
+    file: HashMap<String, String>,
+}
+
+impl Vars {
+    /// Looks up a variable like [`env::var`].
+    fn var(&self, name: &str) -> Result<String, env::VarError> {
+        let from_env = if self.environment {
+            env::var(name)
+        } else {
+            Err(env::VarError::NotPresent)
+        };
+        from_env.or_else(|error| self.file.get(name).cloned().ok_or(error))
+    }
+
+    /// Looks up a variable like [`env::var_os`].
+    fn var_os(&self, name: &str) -> Option<OsString> {
+        self.environment
+            .then(|| env::var_os(name))
+            .flatten()
+            .or_else(|| self.file.get(name).map(OsString::from))
+    }
+
+    /// Reads and parses the variables numbered from 1 until the first unset one, e.g.
+    /// `WEB_SERVER_REWRITE_1` and `WEB_SERVER_REWRITE_2`.
+    ///
+    /// # Arguments
+    ///
+    /// * `prefix`: The variable names without `_` and the number.
+    ///
+    /// # Returns
+    ///
+    /// The parsed values in order.
+    ///
+    /// # Errors
+    ///
+    /// Returns the error of the first value that cannot be parsed, prefixed with its name.
+    fn numbered<T: std::str::FromStr<Err = io::Error>>(&self, prefix: &str) -> io::Result<Vec<T>> {
+        let mut values = Vec::new();
+        for number in 1.. {
+            let name = format!("{}_{}", prefix, number);
+            let Ok(value) = self.var(&name) else {
+                break;
+            };
+            let value = value.parse().map_err(|error: io::Error| {
+                io::Error::new(error.kind(), format!("{}: {}", name, error))
+            })?;
+            values.push(value);
+        }
+        Ok(values)
+    }
+
+    /// Reads and parses an optional variable.
+    ///
+    /// # Arguments
+    ///
+    /// * `name`: The variable name.
+    ///
+    /// # Returns
+    ///
+    /// The parsed value, or `None` when the variable is unset.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`io::ErrorKind::InvalidInput`] when the value cannot be parsed.
+    fn parse<T: std::str::FromStr>(&self, name: &str) -> io::Result<Option<T>> {
+        match self.var(name) {
+            Ok(value) => value.parse().map(Some).map_err(|_| {
+                io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("{} has an invalid value: {}", name, value),
+                )
+            }),
+            Err(_) => Ok(None),
+        }
+    }
+}
+
+/// Looks up an environment variable referred to by a configuration file.
+type Lookup<'a> = &'a dyn Fn(&str) -> Option<String>;
+
+/// Turns the keys of a TOML configuration file into the names of the environment variables they
+/// stand for: `root` becomes `WEB_SERVER_ROOT` and `strategy` in the `[proxy]` table becomes
+/// `WEB_SERVER_PROXY_STRATEGY`. Booleans become `1` or `0` and arrays comma-separated lists.
+///
+/// # Arguments
+///
+/// * `text`: The TOML text.
+/// * `lookup`: Resolves the `${NAME}` references in strings, see [`interpolate`].
+///
+/// # Returns
+///
+/// The values by variable name.
+///
+/// # Errors
+///
+/// Returns [`io::ErrorKind::InvalidData`] when the text is not TOML or an array holds tables,
+/// and the errors of [`interpolate`].
+fn flatten_toml(text: &str, lookup: Lookup<'_>) -> io::Result<HashMap<String, String>> {
+    let table: toml::Table = text
+        .parse()
+        .map_err(|error| io::Error::new(io::ErrorKind::InvalidData, error))?;
+    let mut vars = HashMap::new();
+    flatten_table("WEB_SERVER", &table, lookup, &mut vars)?;
+    Ok(vars)
+}
+
+/// Adds the keys of one table to `vars`, see [`flatten_toml`].
+fn flatten_table(
+    prefix: &str,
+    table: &toml::Table,
+    lookup: Lookup<'_>,
+    vars: &mut HashMap<String, String>,
+) -> io::Result<()> {
+    for (key, value) in table {
+        let name = variable_name(prefix, key);
+        if let toml::Value::Table(table) = value {
+            flatten_table(&name, table, lookup, vars)?;
+            continue;
+        }
+        let value = match value {
+            toml::Value::Array(items) => items
+                .iter()
+                .map(|item| scalar(&name, item, lookup))
+                .collect::<io::Result<Vec<_>>>()?
+                .join(","),
+            value => scalar(&name, value, lookup)?,
+        };
+        vars.insert(name, value);
+    }
+    Ok(())
+}
+
+/// The variable a key of a configuration file stands for, see [`flatten_toml`].
+///
+/// # Arguments
+///
+/// * `prefix`: `WEB_SERVER` or the variable of the table holding the key.
+/// * `key`: The key, e.g. `timeout-secs`.
+///
+/// # Returns
+///
+/// The variable name, e.g. `WEB_SERVER_PROXY_TIMEOUT_SECS`.
+pub(crate) fn variable_name(prefix: &str, key: &str) -> String {
+    format!("{}_{}", prefix, key.to_ascii_uppercase().replace('-', "_"))
+}
+
+/// Formats a TOML scalar the way it would be written in an environment variable.
+fn scalar(name: &str, value: &toml::Value, lookup: Lookup<'_>) -> io::Result<String> {
+    match value {
+        toml::Value::String(value) => interpolate(name, value, lookup),
+        toml::Value::Boolean(value) => Ok(if *value { "1" } else { "0" }.to_string()),
+        toml::Value::Integer(value) => Ok(value.to_string()),
+        toml::Value::Float(value) => Ok(value.to_string()),
+        toml::Value::Datetime(value) => Ok(value.to_string()),
+        toml::Value::Array(_) | toml::Value::Table(_) => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("{} must not nest arrays or tables in an array", name),
+        )),
+    }
+}
+
+/// Replaces the `${NAME}` and `${NAME:-default}` references of a string from a configuration
+/// file with the values of the variables, and `$$` with `$`.
+///
+/// # Arguments
+///
+/// * `name`: The variable the string is the value of, for errors.
+/// * `value`: The string.
+/// * `lookup`: Resolves a variable, `None` when it is unset.
+///
+/// # Returns
+///
+/// The string with the references replaced.
+///
+/// # Errors
+///
+/// Returns [`io::ErrorKind::InvalidInput`] for a reference without a closing brace or to an
+/// unset variable without a default.
+fn interpolate(name: &str, value: &str, lookup: Lookup<'_>) -> io::Result<String> {
+    let invalid = |message: String| {
+        io::Error::new(io::ErrorKind::InvalidInput, format!("{} {}", name, message))
+    };
+    let mut interpolated = String::with_capacity(value.len());
+    let mut rest = value;
+    while let Some(start) = rest.find('$') {
+        interpolated.push_str(&rest[..start]);
+        rest = &rest[start + 1..];
+        if let Some(after) = rest.strip_prefix('$') {
+            interpolated.push('$');
+            rest = after;
+            continue;
+        }
+        let Some(reference) = rest.strip_prefix('{') else {
+            interpolated.push('$');
+            continue;
+        };
+        let end = reference
+            .find('}')
+            .ok_or_else(|| invalid(format!("has an unclosed reference: ${}", rest)))?;
+        let (variable, default) = match reference[..end].split_once(":-") {
+            Some((variable, default)) => (variable, Some(default)),
+            None => (&reference[..end], None),
+        };
+        let resolved = lookup(variable).filter(|value| !value.is_empty() || default.is_none());
+        match (resolved, default) {
+            (Some(resolved), _) => interpolated.push_str(&resolved),
+            (None, Some(default)) => interpolated.push_str(default),
+            (None, None) => {
+                return Err(invalid(format!(
+                    "refers to the unset variable {}",
+                    variable
+                )))
+            }
+        }
+        rest = &reference[end + 1..];
+    }
+    interpolated.push_str(rest);
+    Ok(interpolated)
+}
+
+/// Lists the paths a configuration reads and writes for [`Sandbox::Landlock`]: the document
+/// root, which WebDAV writes to, the configuration, template, script, certificate, and key
+/// files, the upload, tus, proxy cache, ACME, and log directories, and the paths of
+/// `WEB_SERVER_SANDBOX_READ` and `WEB_SERVER_SANDBOX_WRITE`.
+///
+/// # Arguments
+///
+/// * `vars`: The variables the configuration was built from.
+/// * `config`: The configuration built so far, with the document root and its handlers.
+///
+/// # Returns
+///
+/// The sandbox granting the paths.
+fn landlock_paths(vars: &Vars, config: &Config) -> Sandbox {
+    let mut read = Vec::new();
+    let mut write = Vec::new();
+    match config.webdav {
+        Some(_) => write.push(config.document_root.clone()),
+        None => read.push(config.document_root.clone()),
+    }
+    read.extend(
+        config
+            .markdown
+            .as_ref()
+            .and_then(|markdown| markdown.template.clone()),
+    );
+    write.extend(
+        config
+            .upload
+            .as_ref()
+            .map(|upload| upload.directory.clone()),
+    );
+    write.extend(config.tus.as_ref().map(|tus| tus.directory.clone()));
+    for name in [
+        "WEB_SERVER_CONFIG",
+        "WEB_SERVER_CGI_DIR",
+        "WEB_SERVER_TLS_CERT",
+        "WEB_SERVER_TLS_KEY",
+        "WEB_SERVER_TLS_CLIENT_CA",
+        "WEB_SERVER_TLS_TICKET_KEYS",
+    ] {
+        read.extend(vars.var_os(name).map(PathBuf::from));
+    }
+    if let Ok(hosts) = vars.var("WEB_SERVER_TLS_HOSTS") {
+        read.extend(
+            hosts
+                .split(',')
+                .filter_map(|host| host.split_once('='))
+                .map(|(_, directory)| PathBuf::from(directory.trim())),
+        );
+    }
+    write.extend(vars.var_os("WEB_SERVER_PROXY_CACHE_DIR").map(PathBuf::from));
+    // Rotation renames the logs and creates new ones next to them
+    let error_log = match &config.log_output {
+        Output::File(path) => Some(path),
+        _ => None,
+    };
+    for log in [error_log, config.access_log.as_ref()]
+        .into_iter()
+        .flatten()
+    {
+        write.push(match log.parent() {
+            Some(parent) if !parent.as_os_str().is_empty() => parent.to_path_buf(),
+            _ => PathBuf::from("."),
+        });
+    }
+    if vars.var_os("WEB_SERVER_ACME_DOMAINS").is_some() {
+        write.push(
+            vars.var_os("WEB_SERVER_ACME_DIR")
+                .map_or_else(|| PathBuf::from("acme"), PathBuf::from),
+        );
+    }
+    let split = |name: &str| {
+        vars.var(name)
+            .into_iter()
+            .flat_map(|paths| {
+                paths
+                    .split(',')
+                    .map(str::trim)
+                    .filter(|path| !path.is_empty())
+                    .map(PathBuf::from)
+                    .collect::<Vec<_>>()
+            })
+            .collect::<Vec<_>>()
+    };
+    read.extend(split("WEB_SERVER_SANDBOX_READ"));
+    write.extend(split("WEB_SERVER_SANDBOX_WRITE"));
+    Sandbox::Landlock { read, write }
+}
+
+/// Parses a comma-separated list of methods.
+///
+/// # Arguments
+///
+/// * `name`: The variable name, used in the error message.
+/// * `value`: The variable value, e.g. `GET, HEAD`.
+///
+/// # Returns
+///
+/// The methods in order.
+///
+/// # Errors
+///
+/// Returns [`io::ErrorKind::InvalidInput`] when an item is not a method token.
+fn parse_methods(name: &str, value: &str) -> io::Result<Vec<Method>> {
+    value
+        .split(',')
+        .map(str::trim)
+        .filter(|item| !item.is_empty())
+        .map(|item| {
+            item.parse().map_err(|_| {
+                io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("{} has an invalid method: {}", name, item),
+                )
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::trace::Sampler;
+
+    /// It maps keys, tables, booleans, and arrays of a configuration file onto the variables
+    #[test]
+    fn from_toml() {
+        let config = Config::from_toml(
+            "root = \"/srv\"\nlanguage-variants = true\ndisabled_methods = [\"TRACE\", \"PUT\"]\n\
+             max_concurrent = 8\nqueue_timeout_ms = 250\nshed_p99_ms = 200\n\
+             shed_exempt = [\"/health\"]\n[upload]\ndir = \"/tmp/up\"\nmax_size = 5\n",
+        )
+        .unwrap();
+        assert_eq!(PathBuf::from("/srv"), config.document_root);
+        assert!(config.language_variants);
+        assert_eq!(vec![Method::Trace, Method::Put], config.methods.disabled);
+        let concurrency = format!("{:?}", config.concurrency.unwrap());
+        assert!(concurrency.contains("max_running: 8, max_queued: 8, max_wait: 250ms"));
+        let shedding = format!("{:?}", config.load_shedding.unwrap());
+        assert!(shedding.contains("target: 200ms"));
+        assert!(shedding.contains("exempt: [\"/health\"]"));
+        let upload = config.upload.unwrap();
+        assert_eq!(PathBuf::from("/tmp/up"), upload.directory);
+        assert_eq!(5, upload.max_size);
+        assert_eq!(
+            io::ErrorKind::InvalidData,
+            Config::from_toml("root = ").unwrap_err().kind()
+        );
+        assert_eq!(
+            io::ErrorKind::InvalidInput,
+            Config::from_toml("[upload]\ndir = \"a\"\nmax_size = \"big\"")
+                .unwrap_err()
+                .kind()
+        );
+    }
+
+    /// It replaces variable references in strings, falling back to their defaults
+    #[test]
+    fn interpolate() {
+        let lookup = |name: &str| match name {
+            "PORT" => Some("8080".to_string()),
+            "EMPTY" => Some(String::new()),
+            _ => None,
+        };
+        assert_eq!(
+            "127.0.0.1:8080 /srv $5 x",
+            super::interpolate(
+                "WEB_SERVER_X",
+                "127.0.0.1:${PORT} ${ROOT:-/srv} $$5 ${EMPTY:-x}",
+                &lookup
+            )
+            .unwrap()
+        );
+        for value in ["${ROOT}", "${PORT"] {
+            assert_eq!(
+                io::ErrorKind::InvalidInput,
+                super::interpolate("WEB_SERVER_X", value, &lookup)
+                    .unwrap_err()
+                    .kind()
+            );
+        }
+        let config = Config::from_toml("[upload]\ndir = \"${UPLOADS:-/tmp/up}\"\n").unwrap();
+        assert_eq!(PathBuf::from("/tmp/up"), config.upload.unwrap().directory);
+    }
+
+    /// It grants Landlock the configured paths and rejects unknown sandboxes
+    #[test]
+    fn sandbox() {
+        let config = Config::from_toml(
+            "root = \"/srv\"\nsandbox = \"landlock\"\nsandbox_read = [\"/usr/bin\"]\n\
+             [upload]\ndir = \"/tmp/up\"\n",
+        )
+        .unwrap();
+        assert_eq!(
+            Some(Sandbox::Landlock {
+                read: vec![PathBuf::from("/srv"), PathBuf::from("/usr/bin")],
+                write: vec![PathBuf::from("/tmp/up")],
+            }),
+            config.sandbox
+        );
+        let config = Config::from_toml("root = \"/srv\"\nsandbox = \"chroot\"").unwrap();
+        assert_eq!(Some(Sandbox::Chroot(PathBuf::from("/srv"))), config.sandbox);
+        assert_eq!(
+            io::ErrorKind::InvalidInput,
+            Config::from_toml("sandbox = \"jail\"").unwrap_err().kind()
+        );
+    }
+    /// It enables captures for path prefixes or request IDs with their limits
+    #[test]
+    fn capture() {
+        assert_eq!(
+            None,
+            Config::from_toml("[capture]\ncapacity = 5")
+                .unwrap()
+                .capture
+        );
+        let config =
+            Config::from_toml("[capture]\npaths = [\"/api\"]\nmax_body_size = 16\n").unwrap();
+        assert_eq!(
+            Some(Capture {
+                max_body_size: 16,
+                ..Capture::new().with_path("/api")
+            }),
+            config.capture
+        );
+    }
+    /// It reads the maintenance settings and page, and rejects a missing page
+    #[test]
+    fn maintenance() {
+        let directory = tempfile::tempdir().unwrap();
+        let page = directory.path().join("maintenance.html");
+        std::fs::write(&page, "<p>back soon</p>").unwrap();
+        let config = Config::from_toml(&format!(
+            "[maintenance]\nenabled = true\nallow = [\"/status\"]\nhealth = [\"/health\"]\n\
+             healthy = false\npage = \"{}\"\nretry_after_secs = 60\n",
+            page.display()
+        ))
+        .unwrap();
+        assert_eq!(
+            Maintenance {
+                enabled: true,
+                healthy: false,
+                page: "<p>back soon</p>".to_string(),
+                retry_after: Some(Duration::from_secs(60)),
+                ..Maintenance::new()
+                    .with_allowed("/status")
+                    .with_health("/health")
+            },
+            config.maintenance
+        );
+        let error = Config::from_toml("[maintenance]\npage = \"/missing.html\"\n").unwrap_err();
+        assert!(error.to_string().starts_with("WEB_SERVER_MAINTENANCE_PAGE"));
+    }
+    /// It adds the canary and split pools to the proxy and rejects incomplete ones
+    #[test]
+    fn proxy_pools() {
+        let config = Config::from_toml(
+            "[proxy]\nupstream = \"a:1\"\ncanary_upstream = \"b:1\"\n\
+             canary_header = \"X-Canary=1\"\nsplit_upstream = [\"c:1\", \"d:1\"]\n\
+             split_percent = 10\nsplit_sticky = \"cookie:ab\"\n",
+        )
+        .unwrap();
+        let addresses: Vec<String> = config
+            .proxy
+            .unwrap()
+            .upstream_stats()
+            .into_iter()
+            .map(|stats| stats.address)
+            .collect();
+        assert_eq!(vec!["a:1", "b:1", "c:1", "d:1"], addresses);
+        for toml in [
+            "[proxy]\nupstream = \"a:1\"\ncanary_upstream = \"b:1\"\n",
+            "[proxy]\nupstream = \"a:1\"\nsplit_upstream = \"b:1\"\nsplit_percent = 101\n",
+        ] {
+            let error = Config::from_toml(toml).unwrap_err();
+            assert_eq!(io::ErrorKind::InvalidInput, error.kind(), "{}", error);
+        }
+    }
+    /// It reads numbered rewrite and redirect rules in order and names the invalid one
+    #[test]
+    fn rewrites() {
+        let config = Config::from_toml(
+            "[rewrite]\n2 = '^/b /c continue'\n1 = '^/a /b continue'\n4 = '^/d /e'\n",
+        )
+        .unwrap();
+        assert_eq!(
+            vec![
+                Rewrite::new("^/a", "/b").unwrap().with_continue(),
+                Rewrite::new("^/b", "/c").unwrap().with_continue(),
+            ],
+            config.rewrites
+        );
+        let error = Config::from_toml("[rewrite]\n1 = '^/(a /b'\n").unwrap_err();
+        assert!(
+            error.to_string().starts_with("WEB_SERVER_REWRITE_1: "),
+            "{}",
+            error
+        );
+        let config = Config::from_toml("[redirect]\n1 = 'exact /a /b 307'\n").unwrap();
+        assert_eq!(
+            vec!["exact /a /b 307".parse::<Redirect>().unwrap()],
+            config.redirects
+        );
+    }
+    /// It reads the trace sampler with its route overrides and rejects unknown samplers
+    #[test]
+    fn trace_sampling() {
+        let config = Config::from_toml(
+            "[trace]\nsampler = \"ratio:0.5\"\nsampler_routes = [\"/health=never\"]\n",
+        )
+        .unwrap();
+        assert_eq!(
+            Sampling::new(Sampler::Ratio(0.5)).with_route("/health", Sampler::Never),
+            config.trace_sampling
+        );
+        for toml in [
+            "[trace]\nsampler = \"sometimes\"",
+            "[trace]\nsampler_routes = \"health=never\"",
+        ] {
+            assert_eq!(
+                io::ErrorKind::InvalidInput,
+                Config::from_toml(toml).unwrap_err().kind()
+            );
+        }
+    }
+
+    /// It refuses an admin API beyond the loopback interface without a token
+    #[test]
+    fn admin_token() {
+        let error = Config::from_toml("[admin]\naddress = \"0.0.0.0:7879\"\n").unwrap_err();
+        assert_eq!(io::ErrorKind::InvalidInput, error.kind());
+        let config =
+            Config::from_toml("[admin]\naddress = \"0.0.0.0:7879\"\ntoken = \"secret\"\n").unwrap();
+        assert!(!config.admin.unwrap().is_exposed());
+        let config = Config::from_toml("[admin]\naddress = \"[::1]:7879\"\n").unwrap();
+        assert_eq!(None, config.admin.unwrap().token);
+    }
+}
@@ -0,0 +1,311 @@
+use crate::request::{ClientCertificate, Request};
+use crate::StreamAdapter;
+use async_trait::async_trait;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{ready, Context, Poll};
+use tokio::io::{self, AsyncBufRead, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf};
+
+/// The size of every read buffer handed out by a [`BufferPool`].
+const BUFFER_SIZE: usize = 8 * 1024;
+
+/// Read buffers returned by closed connections, handed out again to new ones so that accepting
+/// a connection does not allocate and zero a fresh buffer.
+#[derive(Clone, Debug)]
+pub struct BufferPool {
+    idle: Arc<Mutex<Vec<Vec<u8>>>>,
+    max_idle: usize,
+}
+
+impl Default for BufferPool {
+    /// Keeps up to 64 idle buffers.
+    fn default() -> BufferPool {
+        BufferPool::new(64)
+    }
+}
+
+impl BufferPool {
+    /// Creates an empty pool.
+    ///
+    /// # Arguments
+    ///
+    /// * `max_idle`: The number of returned buffers kept; further ones are freed.
+    ///
+    /// # Returns
+    ///
+    /// A pool without buffers, which allocates until connections return some.
+    pub fn new(max_idle: usize) -> BufferPool {
+        BufferPool {
+            idle: Arc::new(Mutex::new(Vec::new())),
+            max_idle,
+        }
+    }
+
+    /// The number of buffers waiting to be reused.
+    pub fn idle(&self) -> usize {
+        self.idle.lock().map_or(0, |idle| idle.len())
+    }
+
+    /// Takes an idle buffer or allocates a new one.
+    fn take(&self) -> Vec<u8> {
+        self.idle
+            .lock()
+            .ok()
+            .and_then(|mut idle| idle.pop())
+            .unwrap_or_else(|| vec![0; BUFFER_SIZE])
+    }
+
+    /// Keeps a buffer for reuse unless the pool is full.
+    fn give(&self, buffer: Vec<u8>) {
+        if let Ok(mut idle) = self.idle.lock() {
+            if idle.len() < self.max_idle {
+                idle.push(buffer);
+            }
+        }
+    }
+}
+
+/// A buffered connection that holds one read buffer from a [`BufferPool`] for its whole
+/// lifetime, so bytes buffered past one request remain available to the next, and returns the
+/// buffer to the pool when dropped.
+pub struct Connection<S> {
+    stream: S,
+    buffer: Vec<u8>,
+    start: usize,
+    end: usize,
+    pool: BufferPool,
+    peer: Option<SocketAddr>,
+    client_certificate: Option<ClientCertificate>,
+}
+
+impl<S> Connection<S> {
+    /// Wraps a stream.
+    ///
+    /// # Arguments
+    ///
+    /// * `stream`: The accepted stream.
+    /// * `pool`: The pool lending the read buffer.
+    ///
+    /// # Returns
+    ///
+    /// The buffered connection.
+    pub fn new(stream: S, pool: &BufferPool) -> Connection<S> {
+        Connection {
+            stream,
+            buffer: pool.take(),
+            start: 0,
+            end: 0,
+            pool: pool.clone(),
+            peer: None,
+            client_certificate: None,
+        }
+    }
+
+    /// Records the address of the client, see [`StreamAdapter::peer_addr`].
+    ///
+    /// # Arguments
+    ///
+    /// * `peer`: The remote address returned when the stream was accepted.
+    ///
+    /// # Returns
+    ///
+    /// The connection reporting the address.
+    pub fn with_peer(mut self, peer: SocketAddr) -> Connection<S> {
+        self.peer = Some(peer);
+        self
+    }
+
+    /// Records the verified certificate of the client, see
+    /// [`StreamAdapter::client_certificate`].
+    ///
+    /// # Arguments
+    ///
+    /// * `certificate`: The identity taken from the TLS handshake.
+    ///
+    /// # Returns
+    ///
+    /// The connection reporting the identity.
+    pub fn with_client_certificate(mut self, certificate: ClientCertificate) -> Connection<S> {
+        self.client_certificate = Some(certificate);
+        self
+    }
+}
+
+#[cfg(feature = "grpc")]
+impl<S: AsyncRead + Unpin> Connection<S> {
+    /// The bytes read from the stream but not consumed yet.
+    pub(crate) fn buffered(&self) -> &[u8] {
+        &self.buffer[self.start..self.end]
+    }
+
+    /// Reads more bytes after those buffered without consuming any, e.g. to look at the start
+    /// of a connection that arrives in several segments.
+    ///
+    /// # Returns
+    ///
+    /// The number of bytes read, zero once the stream ended or the buffer is full.
+    ///
+    /// # Errors
+    ///
+    /// Captures IO errors from reading the stream.
+    pub(crate) async fn fill_more(&mut self) -> io::Result<usize> {
+        self.buffer.copy_within(self.start..self.end, 0);
+        self.end -= self.start;
+        self.start = 0;
+        let count = self.stream.read(&mut self.buffer[self.end..]).await?;
+        self.end += count;
+        Ok(count)
+    }
+}
+
+impl<S> Drop for Connection<S> {
+    fn drop(&mut self) {
+        self.pool.give(std::mem::take(&mut self.buffer));
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Connection<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        context: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        // Large reads skip the buffer once it is drained, as `tokio::io::BufReader` does
+        if this.start == this.end && buf.remaining() >= this.buffer.len() {
+            return Pin::new(&mut this.stream).poll_read(context, buf);
+        }
+        let available = ready!(Pin::new(&mut *this).poll_fill_buf(context))?;
+        let count = available.len().min(buf.remaining());
+        buf.put_slice(&available[..count]);
+        Pin::new(this).consume(count);
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncBufRead for Connection<S> {
+    fn poll_fill_buf(self: Pin<&mut Self>, context: &mut Context<'_>) -> Poll<io::Result<&[u8]>> {
+        let this = self.get_mut();
+        if this.start == this.end {
+            let mut read_buf = ReadBuf::new(&mut this.buffer);
+            ready!(Pin::new(&mut this.stream).poll_read(context, &mut read_buf))?;
+            this.start = 0;
+            this.end = read_buf.filled().len();
+        }
+        Poll::Ready(Ok(&this.buffer[this.start..this.end]))
+    }
+
+    fn consume(self: Pin<&mut Self>, amount: usize) {
+        let this = self.get_mut();
+        this.start = (this.start + amount).min(this.end);
+    }
+}
+
+/// Writes go straight to the stream, e.g. for protocols that take over the connection after the
+/// bytes buffered so far.
+impl<S: AsyncWrite + Unpin> AsyncWrite for Connection<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        context: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.get_mut().stream).poll_write(context, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, context: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().stream).poll_flush(context)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, context: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().stream).poll_shutdown(context)
+    }
+}
+
+/// Implementing the [`StreamAdapter`] trait for a [`Connection`] over any byte stream, e.g. a
+/// [`tokio::net::TcpStream`].
+#[async_trait]
+impl<S: AsyncRead + AsyncWrite + Unpin + Send> StreamAdapter for Connection<S> {
+    /// Reads the request line and headers of the request.
+    ///
+    /// # Returns
+    ///
+    /// The parsed head of the request.
+    async fn read_request(&mut self) -> io::Result<Request> {
+        Request::read_from(self).await
+    }
+
+    /// Reads raw bytes following the request head.
+    ///
+    /// # Arguments
+    ///
+    /// * `buf`: The buffer to fill.
+    ///
+    /// # Returns
+    ///
+    /// The number of bytes read, or zero at the end of the stream.
+    async fn read_body(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.read(buf).await
+    }
+
+    /// Writes the response to the client.
+    ///
+    /// # Arguments
+    ///
+    /// * `response`: The response to write to the client.
+    ///
+    /// # Returns
+    ///
+    /// The result of the write_all function.
+    async fn write_response(&mut self, response: &[u8]) -> io::Result<()> {
+        self.stream.write_all(response).await
+    }
+
+    /// The address recorded by [`Connection::with_peer`].
+    fn peer_addr(&self) -> Option<SocketAddr> {
+        self.peer
+    }
+
+    /// The identity recorded by [`Connection::with_client_certificate`].
+    fn client_certificate(&self) -> Option<ClientCertificate> {
+        self.client_certificate.clone()
+    }
+
+    /// Shuts the stream down for writing.
+    async fn close(&mut self) -> io::Result<()> {
+        self.stream.shutdown().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// It keeps bytes buffered past one request for the next and reuses the buffer afterwards
+    #[tokio::test]
+    async fn pooled_buffer() {
+        let pool = BufferPool::new(1);
+        let (mut client, server) = io::duplex(64);
+        client
+            .write_all(b"GET /a HTTP/1.1\r\n\r\nPOST /b HTTP/1.1\r\nContent-Length: 2\r\n\r\nhi")
+            .await
+            .unwrap();
+        drop(client);
+        let mut connection = Connection::new(server, &pool);
+        assert_eq!("/a", connection.read_request().await.unwrap().path());
+        assert_eq!("/b", connection.read_request().await.unwrap().path());
+        let mut body = [0; 8];
+        assert_eq!(2, connection.read_body(&mut body).await.unwrap());
+        assert_eq!(b"hi", &body[..2]);
+        assert_eq!(0, pool.idle());
+        drop(connection);
+        assert_eq!(1, pool.idle());
+
+        let first = Connection::new(io::empty(), &pool);
+        let second = Connection::new(io::empty(), &pool);
+        assert_eq!(0, pool.idle());
+        drop(first);
+        drop(second);
+        assert_eq!(1, pool.idle());
+    }
+}
@@ -0,0 +1,357 @@
+//! Conversions between this crate's [`Request`] and [`Response`] and the types of the
+//! [http](https://docs.rs/http) crate, enabled by the `http` feature, so libraries written
+//! against `http::Request<Bytes>` and `http::Response<Bytes>` work with handlers of this crate.
+//!
+//! The conversions are fallible because the two sides accept different values, e.g. `http`
+//! rejects control characters in header values that this crate keeps as received.
+//!
+//! Handlers can also be written against the `http` types alone and registered through
+//! [`Router::route_http`], without touching the types of this crate.
+
+use crate::extensions::Extensions;
+use crate::header::HeaderMap;
+use crate::request::{ClientCertificate, Request, State};
+use crate::response::Response;
+use crate::router::{Handler, Router};
+use crate::status::StatusCode;
+use crate::trace::TraceContext;
+use crate::version::Version;
+use async_trait::async_trait;
+use bytes::Bytes;
+use std::future::Future;
+use std::net::SocketAddr;
+use tokio::io;
+
+/// Wraps a conversion error as [`io::ErrorKind::InvalidData`].
+///
+/// # Arguments
+///
+/// * `error`: The error reported by the http crate.
+///
+/// # Returns
+///
+/// The IO error.
+fn invalid(error: impl std::error::Error + Send + Sync + 'static) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, error)
+}
+
+/// Copies headers into an http header map.
+///
+/// # Arguments
+///
+/// * `headers`: The headers of this crate.
+///
+/// # Returns
+///
+/// The same headers in the same order.
+///
+/// # Errors
+///
+/// Returns [`io::ErrorKind::InvalidData`] for names or values that `http` rejects.
+fn to_http_headers(headers: &HeaderMap) -> io::Result<http::HeaderMap> {
+    let mut converted = http::HeaderMap::with_capacity(headers.len());
+    for (name, value) in headers {
+        converted.append(
+            http::HeaderName::from_bytes(name.as_bytes()).map_err(invalid)?,
+            http::HeaderValue::from_str(value).map_err(invalid)?,
+        );
+    }
+    Ok(converted)
+}
+
+/// Copies headers from an http header map.
+///
+/// # Arguments
+///
+/// * `headers`: The http headers.
+///
+/// # Returns
+///
+/// The same headers, grouped by name as `http` stores them.
+///
+/// # Errors
+///
+/// Returns [`io::ErrorKind::InvalidData`] for values that are not visible ASCII.
+fn from_http_headers(headers: &http::HeaderMap) -> io::Result<HeaderMap> {
+    headers
+        .iter()
+        .map(|(name, value)| {
+            Ok((
+                name.as_str().to_string(),
+                value.to_str().map_err(invalid)?.to_string(),
+            ))
+        })
+        .collect()
+}
+
+impl From<Version> for http::Version {
+    fn from(version: Version) -> http::Version {
+        match version {
+            Version::Http10 => http::Version::HTTP_10,
+            Version::Http11 => http::Version::HTTP_11,
+            Version::Http2 => http::Version::HTTP_2,
+            Version::Http3 => http::Version::HTTP_3,
+        }
+    }
+}
+
+/// Maps HTTP/0.9, which this crate does not support, to HTTP/1.0.
+impl From<http::Version> for Version {
+    fn from(version: http::Version) -> Version {
+        match version {
+            http::Version::HTTP_2 => Version::Http2,
+            http::Version::HTTP_3 => Version::Http3,
+            http::Version::HTTP_11 => Version::Http11,
+            _ => Version::Http10,
+        }
+    }
+}
+
+impl From<StatusCode> for http::StatusCode {
+    fn from(status: StatusCode) -> http::StatusCode {
+        // Both sides only hold codes in 100..=999
+        http::StatusCode::from_u16(status.as_u16()).unwrap_or(http::StatusCode::OK)
+    }
+}
+
+impl From<http::StatusCode> for StatusCode {
+    fn from(status: http::StatusCode) -> StatusCode {
+        StatusCode::from_u16(status.as_u16()).unwrap_or(StatusCode::OK)
+    }
+}
+
+impl TryFrom<Request> for http::Request<Bytes> {
+    type Error = io::Error;
+
+    fn try_from(request: Request) -> io::Result<http::Request<Bytes>> {
+        let mut converted = http::Request::new(request.body);
+        *converted.method_mut() =
+            http::Method::from_bytes(request.method.as_str().as_bytes()).map_err(invalid)?;
+        *converted.uri_mut() = request
+            .target
+            .to_string()
+            .parse::<http::Uri>()
+            .map_err(invalid)?;
+        *converted.version_mut() = request.version.into();
+        *converted.headers_mut() = to_http_headers(&request.headers)?;
+        if let Some(peer) = request.peer {
+            converted.extensions_mut().insert(peer);
+        }
+        if let Some(certificate) = request.client_certificate {
+            converted.extensions_mut().insert(certificate);
+        }
+        if let Some(trace) = request.trace {
+            converted.extensions_mut().insert(trace);
+        }
+        converted.extensions_mut().insert(request.extensions);
+        converted.extensions_mut().insert(request.state);
+        Ok(converted)
+    }
+}
+
+impl TryFrom<http::Request<Bytes>> for Request {
+    type Error = io::Error;
+
+    fn try_from(request: http::Request<Bytes>) -> io::Result<Request> {
+        let (parts, body) = request.into_parts();
+        Ok(Request {
+            method: parts.method.as_str().parse()?,
+            target: parts.uri.to_string().parse()?,
+            version: parts.version.into(),
+            headers: from_http_headers(&parts.headers)?,
+            body,
+            violation: None,
+            peer: parts.extensions.get::<SocketAddr>().copied(),
+            client_certificate: parts.extensions.get::<ClientCertificate>().cloned(),
+            trace: parts.extensions.get::<TraceContext>().cloned(),
+            params: Vec::new(),
+            extensions: parts
+                .extensions
+                .get::<Extensions>()
+                .cloned()
+                .unwrap_or_default(),
+            state: parts.extensions.get::<State>().cloned().unwrap_or_default(),
+        })
+    }
+}
+
+/// Sends the entries of [`Response::vary`] as one merged `Vary` header.
+impl TryFrom<Response> for http::Response<Bytes> {
+    type Error = io::Error;
+
+    fn try_from(response: Response) -> io::Result<http::Response<Bytes>> {
+        let vary = response.merged_vary();
+        let mut headers = response.headers;
+        match vary {
+            Some(vary) => headers.insert("Vary", vary),
+            None => {
+                headers.remove("Vary");
+            }
+        }
+        let mut converted = http::Response::new(response.body);
+        *converted.status_mut() = response.status.into();
+        *converted.headers_mut() = to_http_headers(&headers)?;
+        converted.extensions_mut().insert(response.extensions);
+        Ok(converted)
+    }
+}
+
+/// Drops `Content-Length`, which [`Response::to_bytes`] computes from the body.
+impl TryFrom<http::Response<Bytes>> for Response {
+    type Error = io::Error;
+
+    fn try_from(response: http::Response<Bytes>) -> io::Result<Response> {
+        let (parts, body) = response.into_parts();
+        let mut converted = Response::new(parts.status.into(), body);
+        converted.headers = from_http_headers(&parts.headers)?;
+        converted.headers.remove("Content-Length");
+        if let Some(extensions) = parts.extensions.get::<Extensions>() {
+            converted.extensions = extensions.clone();
+        }
+        Ok(converted)
+    }
+}
+
+/// A [`Handler`] written against the http crate: it takes an `http::Request<Bytes>` and returns
+/// an `http::Response<Bytes>`.
+pub struct HttpHandler<F>(pub F);
+
+#[async_trait]
+impl<F, Fut> Handler for HttpHandler<F>
+where
+    F: Fn(http::Request<Bytes>) -> Fut + Send + Sync,
+    Fut: Future<Output = io::Result<http::Response<Bytes>>> + Send,
+{
+    /// Answers requests that `http` cannot represent with 400 Bad Request and responses that
+    /// this crate cannot send with 500 Internal Server Error.
+    async fn call(&self, request: Request) -> io::Result<Response> {
+        let request = match http::Request::try_from(request) {
+            Ok(request) => request,
+            Err(_) => return Ok(Response::new(StatusCode::BAD_REQUEST, "")),
+        };
+        let response = (self.0)(request).await?;
+        Ok(Response::try_from(response)
+            .unwrap_or_else(|_| Response::new(StatusCode::INTERNAL_SERVER_ERROR, "")))
+    }
+}
+
+impl Router {
+    /// Registers a handler written against the http crate, see [`Router::route`].
+    ///
+    /// # Arguments
+    ///
+    /// * `method`: The request method, e.g. `http::Method::GET`.
+    /// * `path`: The exact request path without query, e.g. `/users`.
+    /// * `handler`: An async function from `http::Request<Bytes>` to `http::Response<Bytes>`.
+    ///
+    /// # Returns
+    ///
+    /// The router including the new route.
+    pub fn route_http<F, Fut>(self, method: http::Method, path: &str, handler: F) -> Router
+    where
+        F: Fn(http::Request<Bytes>) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = io::Result<http::Response<Bytes>>> + Send,
+    {
+        // http only accepts valid method tokens, so parsing cannot fail
+        let method = method.as_str().parse().unwrap_or_default();
+        self.route(method, path, HttpHandler(handler))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::method::Method;
+
+    /// It converts requests to http types and back without losing anything
+    #[test]
+    fn request_round_trip() {
+        let mut state = State::default();
+        state.insert(std::sync::Arc::new(7_u8));
+        let mut extensions = Extensions::new();
+        extensions.insert(8_u8);
+        let request = Request {
+            method: Method::Extension("PROPFIND".to_string()),
+            target: "/a%20b?x=1".parse().unwrap(),
+            version: Version::Http10,
+            headers: [("Depth", "1"), ("X-A", "1"), ("x-a", "2")]
+                .into_iter()
+                .collect(),
+            body: Bytes::from_static(b"<propfind/>"),
+            violation: None,
+            peer: Some("127.0.0.1:4000".parse().unwrap()),
+            client_certificate: Some(ClientCertificate {
+                subject: "CN=alice".to_string(),
+                names: vec!["alice@example.org".to_string()],
+                der: Bytes::from_static(b"der"),
+            }),
+            trace: Some(TraceContext::new()),
+            params: Vec::new(),
+            extensions,
+            state,
+        };
+        let converted = http::Request::<Bytes>::try_from(request.clone()).unwrap();
+        assert_eq!("PROPFIND", converted.method().as_str());
+        assert_eq!(Some("x=1"), converted.uri().query());
+        assert_eq!(http::Version::HTTP_10, converted.version());
+        assert_eq!(2, converted.headers().get_all("X-A").iter().count());
+        let back = Request::try_from(converted).unwrap();
+        assert_eq!(request.target, back.target);
+        assert_eq!(request.body, back.body);
+        assert_eq!(request.peer, back.peer);
+        assert_eq!(request.client_certificate, back.client_certificate);
+        assert_eq!(request.trace, back.trace);
+        assert_eq!(Some("1"), back.header("depth"));
+        assert_eq!(
+            vec!["1", "2"],
+            back.headers.get_all("x-a").collect::<Vec<_>>()
+        );
+    }
+
+    /// It converts responses to http types and back, merging `Vary`
+    #[test]
+    fn response_round_trip() {
+        let response = Response::new(StatusCode::CREATED, "done")
+            .with_header("Location", "/a")
+            .with_vary("Accept")
+            .with_extension(7_u8);
+        let converted = http::Response::<Bytes>::try_from(response).unwrap();
+        assert_eq!(http::StatusCode::CREATED, converted.status());
+        assert_eq!("Accept", converted.headers()["vary"]);
+        let back = Response::try_from(converted).unwrap();
+        assert_eq!(StatusCode::CREATED, back.status);
+        assert_eq!(Some("/a"), back.header("Location"));
+        assert_eq!(b"done".to_vec(), back.body);
+        assert_eq!(Some(&7), back.extensions().get::<u8>());
+
+        let mut invalid = Response::new(StatusCode::OK, "");
+        invalid.headers.append("X-Bad", "a\u{1}b");
+        assert!(http::Response::<Bytes>::try_from(invalid).is_err());
+    }
+
+    /// It routes requests to handlers written against the http crate
+    #[tokio::test]
+    async fn http_handler() {
+        let router = Router::new().route_http(
+            http::Method::POST,
+            "/echo",
+            |request: http::Request<Bytes>| async move {
+                Ok(http::Response::builder()
+                    .status(http::StatusCode::ACCEPTED)
+                    .header("X-Path", request.uri().path())
+                    .body(request.into_body())
+                    .unwrap())
+            },
+        );
+        let request = Request {
+            method: Method::Post,
+            target: "/echo".parse().unwrap(),
+            body: Bytes::from_static(b"hi"),
+            ..Request::default()
+        };
+        let response = router.dispatch(&request).await.unwrap().unwrap();
+        assert_eq!(StatusCode::ACCEPTED, response.status);
+        assert_eq!(Some("/echo"), response.header("X-Path"));
+        assert_eq!(b"hi".to_vec(), response.body);
+    }
+}
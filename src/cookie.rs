@@ -0,0 +1,284 @@
+//! Cookies that clients cannot tamper with: signed cookies carry their value in the clear with
+//! an HMAC-SHA256 tag, and private cookies encrypt it with AES-256-GCM, both bound to the cookie
+//! name so that a value cannot be moved to another cookie.
+//!
+//! A [`CookieJar`] holds several keys so that they can be rotated without logging everyone
+//! out: the first key signs and encrypts new cookies and all of them verify and decrypt, so a
+//! rotation adds a new key in front and removes the last one once the cookies it made expired.
+
+use crate::request::Request;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use ring::aead::{Aad, LessSafeKey, Nonce, UnboundKey, AES_256_GCM, NONCE_LEN};
+use ring::hmac;
+use ring::rand::{SecureRandom, SystemRandom};
+use std::fmt;
+use std::str::FromStr;
+use tokio::io;
+
+/// The shortest secret a [`Key`] accepts, in bytes.
+pub const MIN_SECRET_LENGTH: usize = 32;
+
+/// The keys signing and encrypting cookies, both derived from one secret.
+pub struct Key {
+    signing: hmac::Key,
+    encryption: LessSafeKey,
+}
+
+impl Key {
+    /// Derives the keys from a secret shared by every instance of the application.
+    ///
+    /// # Arguments
+    ///
+    /// * `secret`: At least [`MIN_SECRET_LENGTH`] random bytes, e.g. from
+    ///   `openssl rand -base64 32`.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`io::ErrorKind::InvalidInput`] when the secret is too short.
+    pub fn new(secret: &[u8]) -> io::Result<Key> {
+        if secret.len() < MIN_SECRET_LENGTH {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "a cookie key needs at least {} bytes, got {}",
+                    MIN_SECRET_LENGTH,
+                    secret.len()
+                ),
+            ));
+        }
+        let master = hmac::Key::new(hmac::HMAC_SHA256, secret);
+        let derive = |purpose: &[u8]| hmac::sign(&master, purpose);
+        let encryption = UnboundKey::new(&AES_256_GCM, derive(b"cookie encryption").as_ref())
+            .map_err(|_| io::Error::other("invalid cookie encryption key"))?;
+        Ok(Key {
+            signing: hmac::Key::new(hmac::HMAC_SHA256, derive(b"cookie signing").as_ref()),
+            encryption: LessSafeKey::new(encryption),
+        })
+    }
+
+    /// Derives the keys from a random secret, for cookies that only this instance reads until
+    /// it stops.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`io::ErrorKind::Other`] when the system has no randomness.
+    pub fn generate() -> io::Result<Key> {
+        let mut secret = [0; MIN_SECRET_LENGTH];
+        SystemRandom::new()
+            .fill(&mut secret)
+            .map_err(|_| io::Error::other("no randomness for a cookie key"))?;
+        Key::new(&secret)
+    }
+}
+
+impl fmt::Debug for Key {
+    fn fmt(&self, formatter: &mut fmt::Formatter<'_>) -> fmt::Result {
+        formatter.debug_struct("Key").finish_non_exhaustive()
+    }
+}
+
+impl FromStr for Key {
+    type Err = io::Error;
+
+    /// Decodes a base64 encoded secret.
+    fn from_str(secret: &str) -> io::Result<Key> {
+        let secret = base64::engine::general_purpose::STANDARD
+            .decode(secret.trim())
+            .map_err(|error| {
+                io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("invalid cookie key: {}", error),
+                )
+            })?;
+        Key::new(&secret)
+    }
+}
+
+/// Signs, verifies, encrypts, and decrypts cookie values with rotating keys.
+#[derive(Debug)]
+pub struct CookieJar {
+    keys: Vec<Key>,
+}
+
+impl CookieJar {
+    /// Creates a jar.
+    ///
+    /// # Arguments
+    ///
+    /// * `keys`: The keys, the first making new cookies and all of them reading cookies.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`io::ErrorKind::InvalidInput`] without a key.
+    pub fn new(keys: Vec<Key>) -> io::Result<CookieJar> {
+        if keys.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "a cookie jar needs at least one key",
+            ));
+        }
+        Ok(CookieJar { keys })
+    }
+
+    /// Signs a value, which stays readable by the client.
+    ///
+    /// # Arguments
+    ///
+    /// * `name`: The name of the cookie, so the value is only valid in this cookie.
+    /// * `value`: The value, made of characters allowed in cookie values.
+    ///
+    /// # Returns
+    ///
+    /// The value followed by a dot and the base64 encoded tag.
+    pub fn sign(&self, name: &str, value: &str) -> String {
+        let tag = hmac::sign(&self.keys[0].signing, &signed_message(name, value));
+        format!("{}.{}", value, URL_SAFE_NO_PAD.encode(tag.as_ref()))
+    }
+
+    /// Checks the tag of a value made by [`CookieJar::sign`] with any of the keys.
+    ///
+    /// # Arguments
+    ///
+    /// * `name`: The name of the cookie.
+    /// * `signed`: The value the client sent.
+    ///
+    /// # Returns
+    ///
+    /// The value without the tag, or `None` when the tag is missing or wrong.
+    pub fn verify<'a>(&self, name: &str, signed: &'a str) -> Option<&'a str> {
+        let (value, tag) = signed.rsplit_once('.')?;
+        let tag = URL_SAFE_NO_PAD.decode(tag).ok()?;
+        let message = signed_message(name, value);
+        self.keys
+            .iter()
+            .any(|key| hmac::verify(&key.signing, &message, &tag).is_ok())
+            .then_some(value)
+    }
+
+    /// Encrypts a value so that the client can neither read nor change it.
+    ///
+    /// # Arguments
+    ///
+    /// * `name`: The name of the cookie, so the value is only valid in this cookie.
+    /// * `value`: The value, any text.
+    ///
+    /// # Returns
+    ///
+    /// The base64 encoded nonce, ciphertext, and tag, or `None` when the system has no
+    /// randomness for the nonce.
+    pub fn encrypt(&self, name: &str, value: &str) -> Option<String> {
+        let mut nonce = [0; NONCE_LEN];
+        SystemRandom::new().fill(&mut nonce).ok()?;
+        let mut sealed = value.as_bytes().to_vec();
+        self.keys[0]
+            .encryption
+            .seal_in_place_append_tag(
+                Nonce::assume_unique_for_key(nonce),
+                Aad::from(name.as_bytes()),
+                &mut sealed,
+            )
+            .ok()?;
+        Some(URL_SAFE_NO_PAD.encode([&nonce[..], &sealed].concat()))
+    }
+
+    /// Decrypts a value made by [`CookieJar::encrypt`] with any of the keys.
+    ///
+    /// # Arguments
+    ///
+    /// * `name`: The name of the cookie.
+    /// * `encrypted`: The value the client sent.
+    ///
+    /// # Returns
+    ///
+    /// The value, or `None` when no key decrypts it.
+    pub fn decrypt(&self, name: &str, encrypted: &str) -> Option<String> {
+        let encrypted = URL_SAFE_NO_PAD.decode(encrypted).ok()?;
+        let (nonce, sealed) = encrypted.split_at_checked(NONCE_LEN)?;
+        self.keys.iter().find_map(|key| {
+            let mut plain = sealed.to_vec();
+            let length = key
+                .encryption
+                .open_in_place(
+                    Nonce::try_assume_unique_for_key(nonce).ok()?,
+                    Aad::from(name.as_bytes()),
+                    &mut plain,
+                )
+                .ok()?
+                .len();
+            plain.truncate(length);
+            String::from_utf8(plain).ok()
+        })
+    }
+
+    /// Finds a signed cookie of a request, see [`CookieJar::verify`].
+    pub fn signed<'a>(&self, request: &'a Request, name: &str) -> Option<&'a str> {
+        self.verify(name, request.cookie(name)?)
+    }
+
+    /// Finds a private cookie of a request, see [`CookieJar::decrypt`].
+    pub fn private(&self, request: &Request, name: &str) -> Option<String> {
+        self.decrypt(name, request.cookie(name)?)
+    }
+}
+
+impl FromStr for CookieJar {
+    type Err = io::Error;
+
+    /// Parses comma-separated base64 encoded secrets, the newest first, e.g. from an
+    /// environment variable.
+    fn from_str(keys: &str) -> io::Result<CookieJar> {
+        let keys = keys
+            .split(',')
+            .filter(|key| !key.trim().is_empty())
+            .map(str::parse)
+            .collect::<io::Result<Vec<Key>>>()?;
+        CookieJar::new(keys)
+    }
+}
+
+/// The message a tag covers, the name and the value as in the `Cookie` header.
+fn signed_message(name: &str, value: &str) -> Vec<u8> {
+    format!("{}={}", name, value).into_bytes()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// It reads values signed or encrypted with any key but refuses changed or moved values
+    #[test]
+    fn cookie_jar() {
+        let old = "AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA=";
+        let new = "AQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQE=";
+        let before: CookieJar = old.parse().unwrap();
+        let after: CookieJar = format!("{},{}", new, old).parse().unwrap();
+
+        let signed = before.sign("user", "ada");
+        assert!(signed.starts_with("ada."));
+        assert_eq!(Some("ada"), after.verify("user", &signed));
+        assert_eq!(None, after.verify("admin", &signed));
+        let forged = signed.replacen("ada", "eve", 1);
+        assert_eq!(None, after.verify("user", &forged));
+        assert_eq!(
+            None,
+            new.parse::<CookieJar>().unwrap().verify("user", &signed)
+        );
+
+        let encrypted = before.encrypt("cart", "3 apples; 2 pears").unwrap();
+        assert!(!encrypted.contains("apples"));
+        let mut request = Request::default();
+        request
+            .headers
+            .append("Cookie", format!("cart={}; user={}", encrypted, signed));
+        assert_eq!(
+            Some("3 apples; 2 pears".to_string()),
+            after.private(&request, "cart")
+        );
+        assert_eq!(Some("ada"), after.signed(&request, "user"));
+        assert_eq!(None, after.decrypt("user", &encrypted));
+
+        assert!("c2hvcnQ=".parse::<CookieJar>().is_err());
+        assert!("".parse::<CookieJar>().is_err());
+    }
+}
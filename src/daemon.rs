@@ -0,0 +1,155 @@
+//! Running detached from the terminal for classic init scripts: [`detach`] turns the process
+//! into a daemon and [`PidFile`] tells the script which process to signal.
+
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::os::fd::AsRawFd;
+use std::path::{Path, PathBuf};
+
+/// Detaches the process from its terminal with the classic double fork: the first child starts
+/// a new session and the second, which can never acquire a terminal again, carries on while
+/// both parents exit. Standard input then reads nothing and standard output and error append
+/// to the log file. The working directory stays, so relative paths keep working. Call it
+/// before starting any threads, e.g. the runtime, since only the calling thread survives a fork.
+///
+/// # Arguments
+///
+/// * `log_file`: The file standard output and error are appended to, or `None` to discard
+///   them.
+///
+/// # Returns
+///
+/// `Ok(())` in the detached process; the original process exits.
+///
+/// # Errors
+///
+/// Captures IO errors from opening the log file, forking, and starting the session.
+pub fn detach(log_file: Option<&Path>) -> io::Result<()> {
+    // Opening first reports a bad path on the terminal rather than losing it
+    let output = match log_file {
+        Some(path) => OpenOptions::new().create(true).append(true).open(path)?,
+        None => OpenOptions::new().write(true).open("/dev/null")?,
+    };
+    let input = File::open("/dev/null")?;
+    fork_and_exit_parent()?;
+    // SAFETY: `setsid` has no memory safety requirements
+    if unsafe { libc::setsid() } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    fork_and_exit_parent()?;
+    for (file, target) in [(&input, 0), (&output, 1), (&output, 2)] {
+        // SAFETY: Both descriptors are open, and replacing the standard streams is the intent
+        if unsafe { libc::dup2(file.as_raw_fd(), target) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+/// Forks and lets only the child return.
+fn fork_and_exit_parent() -> io::Result<()> {
+    // SAFETY: The process has a single thread, so the child gets a consistent copy
+    match unsafe { libc::fork() } {
+        -1 => Err(io::Error::last_os_error()),
+        0 => Ok(()),
+        // SAFETY: `_exit` skips destructors and atexit handlers, which belong to the child now
+        _ => unsafe { libc::_exit(0) },
+    }
+}
+
+/// A file holding the ID of the running process, removed again when dropped.
+#[derive(Debug)]
+pub struct PidFile {
+    path: PathBuf,
+}
+
+impl PidFile {
+    /// Writes the ID of the current process to a file, refusing to when the file names another
+    /// running process, unless that process is upgrading to this one.
+    ///
+    /// # Arguments
+    ///
+    /// * `path`: The file, e.g. `/run/web_server_tokio.pid`.
+    ///
+    /// # Returns
+    ///
+    /// The file, removed when dropped if it still names this process.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`io::ErrorKind::AlreadyExists`] when another server is running, and captures
+    /// IO errors from writing the file.
+    pub fn create(path: impl Into<PathBuf>) -> io::Result<PidFile> {
+        let path = path.into();
+        let upgrading = std::env::var_os(crate::upgrade::READY_VAR).is_some();
+        if let Some(pid) = read_pid(&path) {
+            if !upgrading && pid != std::process::id() && running(pid) {
+                return Err(io::Error::new(
+                    io::ErrorKind::AlreadyExists,
+                    format!("{} names the running process {}", path.display(), pid),
+                ));
+            }
+        }
+        let temporary = path.with_extension("pid.tmp");
+        let mut file = File::create(&temporary)?;
+        writeln!(file, "{}", std::process::id())?;
+        fs::rename(&temporary, &path)?;
+        Ok(PidFile { path })
+    }
+
+    /// The path of the file.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for PidFile {
+    fn drop(&mut self) {
+        // A process this one upgraded to may have replaced the file already
+        if read_pid(&self.path) == Some(std::process::id()) {
+            let _ = fs::remove_file(&self.path);
+        }
+    }
+}
+
+/// Reads the process ID from a PID file, `None` when there is no valid one.
+fn read_pid(path: &Path) -> Option<u32> {
+    fs::read_to_string(path).ok()?.trim().parse().ok()
+}
+
+/// Checks whether a process exists, by sending it no signal.
+fn running(pid: u32) -> bool {
+    let Ok(pid) = libc::pid_t::try_from(pid) else {
+        return false;
+    };
+    // SAFETY: Signal 0 only checks for the process
+    let result = unsafe { libc::kill(pid, 0) };
+    result == 0 || io::Error::last_os_error().raw_os_error() == Some(libc::EPERM)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// It writes its ID, refuses a file naming another running process, and cleans up
+    #[test]
+    fn pid_file() {
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("server.pid");
+        let pid_file = PidFile::create(&path).unwrap();
+        assert_eq!(Some(std::process::id()), read_pid(pid_file.path()));
+        drop(pid_file);
+        assert!(!path.exists());
+
+        // The parent of the test runner is running but is not this process
+        let parent = std::os::unix::process::parent_id();
+        fs::write(&path, format!("{}\n", parent)).unwrap();
+        let error = PidFile::create(&path).unwrap_err();
+        assert_eq!(io::ErrorKind::AlreadyExists, error.kind());
+
+        // A process that is gone left a stale file behind
+        fs::write(&path, "999999999\n").unwrap();
+        drop(PidFile::create(&path).unwrap());
+        assert!(!path.exists());
+    }
+}
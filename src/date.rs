@@ -0,0 +1,108 @@
+use std::cell::RefCell;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+thread_local! {
+    /// The second last formatted on this thread and its `Date` header value.
+    static CURRENT: RefCell<(u64, String)> = const { RefCell::new((u64::MAX, String::new())) };
+}
+
+/// Passes the current time formatted as an HTTP date, e.g. `Sun, 06 Nov 1994 08:49:37 GMT`, to
+/// a closure. The value is formatted at most once per second and thread.
+///
+/// # Arguments
+///
+/// * `f`: The closure receiving the formatted date.
+///
+/// # Returns
+///
+/// The result of `f`.
+pub fn with_current<R>(f: impl FnOnce(&str) -> R) -> R {
+    let now = SystemTime::now();
+    let second = now
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_secs())
+        .unwrap_or(0);
+    CURRENT.with(|current| {
+        let mut current = current.borrow_mut();
+        if current.0 != second {
+            *current = (second, httpdate::fmt_http_date(now));
+        }
+        f(&current.1)
+    })
+}
+
+/// A point in time broken into its UTC calendar fields.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) struct Civil {
+    pub year: i64,
+    /// From 1 for January to 12.
+    pub month: u8,
+    pub day: u8,
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8,
+    pub millisecond: u16,
+}
+
+impl Civil {
+    /// Breaks a time into calendar fields, treating times before 1970 as 1970.
+    pub(crate) fn new(time: SystemTime) -> Civil {
+        let elapsed = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+        let seconds = elapsed.as_secs();
+        let (days, second) = ((seconds / 86_400) as i64, seconds % 86_400);
+        // After Howard Hinnant's `civil_from_days`, with years starting in March
+        let shifted = days + 719_468;
+        let era = shifted.div_euclid(146_097);
+        let day_of_era = shifted.rem_euclid(146_097);
+        let year_of_era =
+            (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+        let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+        let month_index = (5 * day_of_year + 2) / 153;
+        let month = if month_index < 10 {
+            month_index + 3
+        } else {
+            month_index - 9
+        };
+        Civil {
+            year: year_of_era + era * 400 + i64::from(month <= 2),
+            month: month as u8,
+            day: (day_of_year - (153 * month_index + 2) / 5 + 1) as u8,
+            hour: (second / 3600) as u8,
+            minute: (second / 60 % 60) as u8,
+            second: (second % 60) as u8,
+            millisecond: elapsed.subsec_millis() as u16,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// It formats a parseable HTTP date close to the current time
+    #[test]
+    fn with_current() {
+        let parsed = super::with_current(|date| httpdate::parse_http_date(date).unwrap());
+        let drift = SystemTime::now().duration_since(parsed).unwrap();
+        assert!(drift.as_secs() < 2);
+        assert_eq!(29, super::with_current(str::len));
+    }
+
+    /// It breaks times into calendar fields across leap days
+    #[test]
+    fn civil() {
+        let time = UNIX_EPOCH + std::time::Duration::from_millis(951_827_696_789);
+        assert_eq!(
+            Civil {
+                year: 2000,
+                month: 2,
+                day: 29,
+                hour: 12,
+                minute: 34,
+                second: 56,
+                millisecond: 789,
+            },
+            Civil::new(time)
+        );
+    }
+}
@@ -0,0 +1,135 @@
+//! Loading `.env` files, which set the `WEB_SERVER_*` variables of a local setup without
+//! exporting each of them by hand.
+
+use std::env;
+use std::fs;
+use std::path::Path;
+use tokio::io;
+
+/// Parses the assignments of a `.env` file: `NAME=value` lines, optionally starting with
+/// `export`, with blank lines and lines starting with `#` ignored. Values may be quoted, with
+/// `\n`, `\"`, and `\\` escapes inside double quotes, and unquoted values end at a ` #` comment.
+///
+/// # Arguments
+///
+/// * `text`: The contents of the file.
+///
+/// # Returns
+///
+/// The names and values in the order of the file.
+///
+/// # Errors
+///
+/// Returns [`io::ErrorKind::InvalidData`] with the line number for a line that is not an
+/// assignment or a quoted value without its closing quote.
+pub fn parse(text: &str) -> io::Result<Vec<(String, String)>> {
+    let mut assignments = Vec::new();
+    for (index, line) in text.lines().enumerate() {
+        let invalid = |message: &str| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("line {}: {}", index + 1, message),
+            )
+        };
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let line = line.strip_prefix("export ").unwrap_or(line);
+        let (name, value) = line
+            .split_once('=')
+            .ok_or_else(|| invalid("expected NAME=value"))?;
+        let name = name.trim();
+        if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+            return Err(invalid("invalid variable name"));
+        }
+        let value = value.trim();
+        let value = if let Some(quoted) = value.strip_prefix('"') {
+            let mut unescaped = String::new();
+            let mut chars = quoted.chars();
+            loop {
+                match chars.next() {
+                    Some('"') => break,
+                    Some('\\') => match chars.next() {
+                        Some('n') => unescaped.push('\n'),
+                        Some(other) => unescaped.push(other),
+                        None => return Err(invalid("unclosed double quote")),
+                    },
+                    Some(other) => unescaped.push(other),
+                    None => return Err(invalid("unclosed double quote")),
+                }
+            }
+            unescaped
+        } else if let Some(quoted) = value.strip_prefix('\'') {
+            let end = quoted
+                .find('\'')
+                .ok_or_else(|| invalid("unclosed single quote"))?;
+            quoted[..end].to_string()
+        } else {
+            let end = value.find(" #").unwrap_or(value.len());
+            value[..end].trim_end().to_string()
+        };
+        assignments.push((name.to_string(), value));
+    }
+    Ok(assignments)
+}
+
+/// Sets the variables of a `.env` file that the environment does not set already, so that
+/// exported variables still win. Must run before the process starts any threads, since other
+/// threads may read the environment meanwhile.
+///
+/// # Arguments
+///
+/// * `path`: The file, e.g. `.env`.
+///
+/// # Returns
+///
+/// The number of variables set.
+///
+/// # Errors
+///
+/// Returns the errors of [`parse`] with the path, and captures IO errors from reading the file.
+pub fn load(path: &Path) -> io::Result<usize> {
+    let text = fs::read_to_string(path).map_err(|error| {
+        io::Error::new(
+            error.kind(),
+            format!("reading {} failed: {}", path.display(), error),
+        )
+    })?;
+    let assignments = parse(&text)
+        .map_err(|error| io::Error::new(error.kind(), format!("{}: {}", path.display(), error)))?;
+    let mut set = 0;
+    for (name, value) in assignments {
+        if env::var_os(&name).is_none() {
+            env::set_var(name, value);
+            set += 1;
+        }
+    }
+    Ok(set)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// It parses assignments with exports, quotes, escapes, and comments
+    #[test]
+    fn parse() {
+        let text = "# local setup\n\nexport WEB_SERVER_ROOT=/srv # docs\nA=\"x \\\"y\\\"\\nz\"\n\
+                    B='$HOME #1'\nC=\n";
+        assert_eq!(
+            vec![
+                ("WEB_SERVER_ROOT".to_string(), "/srv".to_string()),
+                ("A".to_string(), "x \"y\"\nz".to_string()),
+                ("B".to_string(), "$HOME #1".to_string()),
+                ("C".to_string(), String::new()),
+            ],
+            super::parse(text).unwrap()
+        );
+        for text in ["A", "A B=1", "A=\"open"] {
+            let error = super::parse(text).unwrap_err();
+            assert_eq!(io::ErrorKind::InvalidData, error.kind());
+            assert!(error.to_string().starts_with("line 1: "), "{}", error);
+        }
+    }
+}
@@ -0,0 +1,144 @@
+//! Mapping the errors of fallible handlers to responses: each error type chooses its status and
+//! body through [`ResponseError`], and server errors share a body that the application sets
+//! once with [`set_server_error_body`], e.g. a JSON envelope.
+
+use crate::log;
+use crate::response::Response;
+use crate::status::StatusCode;
+use std::error::Error;
+use std::fmt;
+use std::sync::{Arc, RwLock};
+use tokio::io;
+
+/// An error a handler taking extractors returns as the `Err` of a `Result`, see
+/// [`crate::extract::with_extractors`].
+pub trait ResponseError: fmt::Display {
+    /// The status code answered, 500 Internal Server Error unless overridden.
+    fn status(&self) -> StatusCode {
+        StatusCode::INTERNAL_SERVER_ERROR
+    }
+
+    /// Creates the response for the error.
+    ///
+    /// # Returns
+    ///
+    /// By default, for a server error the logged and hidden message answered through
+    /// [`server_error`], and otherwise the message as plain text.
+    fn error_response(&self) -> Response {
+        let status = self.status();
+        if status.is_server_error() {
+            return server_error(status, &self.to_string());
+        }
+        Response::new(status, format!("{}\n", self))
+            .with_header("Content-Type", "text/plain; charset=utf-8")
+    }
+}
+
+/// Builds the response to a server error from its status and message, see
+/// [`set_server_error_body`].
+pub type ServerErrorBody = Box<dyn Fn(StatusCode, &str) -> Response + Send + Sync>;
+
+/// The body of server errors, empty when `None`, see [`set_server_error_body`].
+static SERVER_ERROR_BODY: RwLock<Option<Arc<ServerErrorBody>>> = RwLock::new(None);
+
+/// Sets how every server error returned by a handler is answered. The response keeps the
+/// status of the error whatever the function sets.
+///
+/// # Arguments
+///
+/// * `body`: Builds the response from the status and the message, which usually describes
+///   internals that clients should not see, or `None` for an empty body.
+pub fn set_server_error_body(body: Option<ServerErrorBody>) {
+    *SERVER_ERROR_BODY
+        .write()
+        .unwrap_or_else(|error| error.into_inner()) = body.map(Arc::new);
+}
+
+/// Logs a server error and answers it as [`set_server_error_body`] says.
+///
+/// # Arguments
+///
+/// * `status`: The status code, e.g. [`StatusCode::INTERNAL_SERVER_ERROR`].
+/// * `message`: What went wrong, logged.
+///
+/// # Returns
+///
+/// The response with the status.
+pub fn server_error(status: StatusCode, message: &str) -> Response {
+    log::error(format_args!("handling a request failed: {}", message));
+    let body = SERVER_ERROR_BODY
+        .read()
+        .unwrap_or_else(|error| error.into_inner())
+        .clone();
+    match body {
+        Some(body) => Response {
+            status,
+            ..body(status, message)
+        },
+        None => Response::new(status, ""),
+    }
+}
+
+impl ResponseError for io::Error {}
+
+impl ResponseError for Box<dyn Error + Send + Sync> {}
+
+/// An empty response with the status, or the body of server errors.
+impl ResponseError for StatusCode {
+    fn status(&self) -> StatusCode {
+        *self
+    }
+
+    fn error_response(&self) -> Response {
+        if self.is_server_error() {
+            return server_error(*self, &self.to_string());
+        }
+        Response::new(*self, "")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::response::IntoResponse;
+
+    /// It answers errors with their status, hiding server errors behind the global body
+    #[test]
+    fn error_response() {
+        #[derive(Debug)]
+        enum AppError {
+            NotFound(u64),
+            Database,
+        }
+        impl fmt::Display for AppError {
+            fn fmt(&self, formatter: &mut fmt::Formatter<'_>) -> fmt::Result {
+                match self {
+                    AppError::NotFound(id) => write!(formatter, "no user {}", id),
+                    AppError::Database => formatter.write_str("the database is down"),
+                }
+            }
+        }
+        impl ResponseError for AppError {
+            fn status(&self) -> StatusCode {
+                match self {
+                    AppError::NotFound(_) => StatusCode::NOT_FOUND,
+                    AppError::Database => StatusCode::SERVICE_UNAVAILABLE,
+                }
+            }
+        }
+        let result: Result<String, AppError> = Err(AppError::NotFound(7));
+        let response = result.into_response();
+        assert_eq!(StatusCode::NOT_FOUND, response.status);
+        assert_eq!(b"no user 7\n".to_vec(), response.body);
+        let response = AppError::Database.error_response();
+        assert_eq!(StatusCode::SERVICE_UNAVAILABLE, response.status);
+        assert!(response.body.is_empty());
+        set_server_error_body(Some(Box::new(|status, _| {
+            Response::new(StatusCode::OK, format!("{{\"error\":{}}}", status.as_u16()))
+        })));
+        let response = AppError::Database.error_response();
+        set_server_error_body(None);
+        assert_eq!(StatusCode::SERVICE_UNAVAILABLE, response.status);
+        assert_eq!(b"{\"error\":503}".to_vec(), response.body);
+    }
+}
@@ -0,0 +1,150 @@
+//! Typed extension maps carried by requests and responses, so that middleware can attach data
+//! for the handlers after it, e.g. the authenticated user, and handlers metadata for the
+//! middleware finalizing their responses, without global state.
+
+use std::any::{Any, TypeId};
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::Arc;
+
+/// A value stored in [`Extensions`], cloned when written to while shared. Since an
+/// `Arc<dyn Value>` is a value itself, calls go through the `dyn Value` explicitly.
+trait Value: Any + Send + Sync {
+    fn clone_value(&self) -> Arc<dyn Value>;
+    fn as_any(&self) -> &dyn Any;
+    fn as_any_mut(&mut self) -> &mut dyn Any;
+}
+
+impl<T: Clone + Send + Sync + 'static> Value for T {
+    fn clone_value(&self) -> Arc<dyn Value> {
+        Arc::new(self.clone())
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+}
+
+/// A map holding at most one value per type. Clones share the values until either writes to
+/// one through [`Extensions::get_mut`].
+#[derive(Clone, Default)]
+pub struct Extensions {
+    values: HashMap<TypeId, Arc<dyn Value>>,
+}
+
+impl Extensions {
+    /// Creates an empty map.
+    pub fn new() -> Extensions {
+        Extensions::default()
+    }
+
+    /// Stores a value, replacing the value of the same type.
+    ///
+    /// # Arguments
+    ///
+    /// * `value`: The value, usually of a type of the middleware storing it so that nothing
+    ///   else replaces it by accident.
+    ///
+    /// # Returns
+    ///
+    /// The value replaced, if any.
+    pub fn insert<T: Clone + Send + Sync + 'static>(&mut self, value: T) -> Option<T> {
+        let replaced = self.values.insert(TypeId::of::<T>(), Arc::new(value))?;
+        (*replaced).as_any().downcast_ref::<T>().cloned()
+    }
+
+    /// Finds the value of a type.
+    pub fn get<T: 'static>(&self) -> Option<&T> {
+        self.values
+            .get(&TypeId::of::<T>())
+            .and_then(|value| (**value).as_any().downcast_ref())
+    }
+
+    /// Finds the value of a type for changing it.
+    pub fn get_mut<T: 'static>(&mut self) -> Option<&mut T> {
+        let value = self.values.get_mut(&TypeId::of::<T>())?;
+        if Arc::get_mut(value).is_none() {
+            *value = (**value).clone_value();
+        }
+        Arc::get_mut(value)?.as_any_mut().downcast_mut()
+    }
+
+    /// Removes the value of a type.
+    ///
+    /// # Returns
+    ///
+    /// The value removed, if any.
+    pub fn remove<T: Clone + 'static>(&mut self) -> Option<T> {
+        let removed = self.values.remove(&TypeId::of::<T>())?;
+        (*removed).as_any().downcast_ref::<T>().cloned()
+    }
+
+    /// Whether a value of a type is stored.
+    pub fn contains<T: 'static>(&self) -> bool {
+        self.values.contains_key(&TypeId::of::<T>())
+    }
+
+    /// The number of values.
+    pub fn len(&self) -> usize {
+        self.values.len()
+    }
+
+    /// Whether no value is stored.
+    pub fn is_empty(&self) -> bool {
+        self.values.is_empty()
+    }
+}
+
+impl fmt::Debug for Extensions {
+    fn fmt(&self, formatter: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(formatter, "Extensions({} values)", self.values.len())
+    }
+}
+
+/// Maps are equal when they share the same values.
+impl PartialEq for Extensions {
+    fn eq(&self, other: &Extensions) -> bool {
+        self.values.len() == other.values.len()
+            && self.values.iter().all(|(type_id, value)| {
+                other
+                    .values
+                    .get(type_id)
+                    .is_some_and(|other| Arc::ptr_eq(value, other))
+            })
+    }
+}
+
+impl Eq for Extensions {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// It keeps one value per type and copies shared values before changing them
+    #[test]
+    fn extensions() {
+        #[derive(Clone, Debug, PartialEq)]
+        struct User(String);
+        let mut extensions = Extensions::new();
+        assert_eq!(None, extensions.insert(User("alice".to_string())));
+        extensions.insert(7_u32);
+        assert_eq!(Some(&User("alice".to_string())), extensions.get::<User>());
+        let shared = extensions.clone();
+        assert_eq!(shared, extensions);
+        extensions
+            .get_mut::<User>()
+            .unwrap()
+            .0
+            .push_str("@example.org");
+        assert_eq!("alice", shared.get::<User>().unwrap().0);
+        assert_eq!("alice@example.org", extensions.get::<User>().unwrap().0);
+        assert_ne!(shared, extensions);
+        assert_eq!(Some(7), extensions.remove::<u32>());
+        assert!(!extensions.contains::<u32>());
+        assert_eq!(1, extensions.len());
+    }
+}
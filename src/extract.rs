@@ -0,0 +1,844 @@
+//! Extractors parsing parts of a request into typed handler arguments, e.g. the parameters of
+//! the route, the query, or a JSON body, so that handlers registered on the
+//! [`crate::router::Router`] declare what they need instead of digging through the request, and
+//! malformed requests are answered on their behalf.
+
+use crate::error::ResponseError;
+use crate::header::HeaderMap;
+use crate::method::Method;
+use crate::path::percent_decode;
+use crate::request::Request;
+use crate::response::{IntoResponse, Response};
+use crate::router::{Handler, Router};
+use crate::status::StatusCode;
+use async_trait::async_trait;
+use bytes::Bytes;
+use std::any::Any;
+use std::fmt;
+use std::future::Future;
+use std::marker::PhantomData;
+use std::net::SocketAddr;
+use std::str::FromStr;
+use std::sync::{Arc, RwLock};
+use tokio::io;
+
+/// What an extractor found wrong with a request, for rejection formats that answer with codes
+/// of their own, see [`set_rejection_format`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RejectionKind {
+    /// A route parameter does not parse, see [`Path`].
+    Path,
+    /// A query parameter is missing or does not parse, see [`Query`].
+    Query,
+    /// The body is not UTF-8 text.
+    Body,
+    /// The body is not JSON of the expected shape, or not labeled as JSON.
+    Json,
+    /// The body is larger than [`crate::config::Config::max_body_size`] or the limit of the
+    /// route, see [`crate::router::RouteLimits`].
+    BodyTooLarge,
+    /// The body is chunked, which handlers on the router do not accept.
+    LengthRequired,
+    /// The route does not share the state of a type, see [`Shared`].
+    State,
+    /// No middleware attached an extension of a type, see [`Extension`].
+    Extension,
+    /// The client address is unknown, see [`ClientAddress`].
+    ClientAddress,
+    /// No middleware authenticated the request, see `crate::auth::Identity`.
+    Unauthenticated,
+    /// Reading the body and handling the request took longer than
+    /// [`crate::config::Config::request_timeout`] or the timeout of the route.
+    Timeout,
+}
+
+impl RejectionKind {
+    /// The kind as a snake case code, e.g. `body_too_large`.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            RejectionKind::Path => "path",
+            RejectionKind::Query => "query",
+            RejectionKind::Body => "body",
+            RejectionKind::Json => "json",
+            RejectionKind::BodyTooLarge => "body_too_large",
+            RejectionKind::LengthRequired => "length_required",
+            RejectionKind::State => "state",
+            RejectionKind::Extension => "extension",
+            RejectionKind::ClientAddress => "client_address",
+            RejectionKind::Unauthenticated => "unauthenticated",
+            RejectionKind::Timeout => "timeout",
+        }
+    }
+}
+
+/// Why an extractor could not build its value from a request, answered instead of calling the
+/// handler.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Rejection {
+    /// What is wrong.
+    pub kind: RejectionKind,
+    /// The status code, e.g. [`StatusCode::BAD_REQUEST`] for a malformed request or
+    /// [`StatusCode::INTERNAL_SERVER_ERROR`] for a route missing its state.
+    pub status: StatusCode,
+    /// The reason, sent to the client as plain text unless a rejection format says otherwise.
+    pub reason: String,
+}
+
+impl Rejection {
+    /// Creates a rejection.
+    ///
+    /// # Arguments
+    ///
+    /// * `kind`: What is wrong.
+    /// * `status`: The status code answered.
+    /// * `reason`: What is wrong in words.
+    ///
+    /// # Returns
+    ///
+    /// The rejection.
+    pub fn new(kind: RejectionKind, status: StatusCode, reason: impl Into<String>) -> Rejection {
+        Rejection {
+            kind,
+            status,
+            reason: reason.into(),
+        }
+    }
+
+    /// Creates a rejection blaming the client.
+    ///
+    /// # Arguments
+    ///
+    /// * `kind`: What is wrong.
+    /// * `reason`: What is wrong in words.
+    ///
+    /// # Returns
+    ///
+    /// A 400 Bad Request rejection.
+    pub fn bad_request(kind: RejectionKind, reason: impl Into<String>) -> Rejection {
+        Rejection::new(kind, StatusCode::BAD_REQUEST, reason)
+    }
+
+    /// Creates the default response telling the client why its request was rejected.
+    ///
+    /// # Returns
+    ///
+    /// A response with the status and the reason as plain text.
+    pub fn response(&self) -> Response {
+        Response::new(self.status, format!("{}\n", self.reason))
+            .with_header("Content-Type", "text/plain; charset=utf-8")
+    }
+}
+
+impl fmt::Display for Rejection {
+    fn fmt(&self, formatter: &mut fmt::Formatter<'_>) -> fmt::Result {
+        formatter.write_str(&self.reason)
+    }
+}
+
+impl std::error::Error for Rejection {}
+
+/// Builds the response to a rejection, e.g. an error envelope in the format of an API.
+pub type RejectionFormat = Arc<dyn Fn(&Rejection) -> Response + Send + Sync>;
+
+/// The format of every rejection, [`Rejection::response`] when `None`, see
+/// [`set_rejection_format`].
+static REJECTION_FORMAT: RwLock<Option<RejectionFormat>> = RwLock::new(None);
+
+/// Sets how rejections are answered unless their route says otherwise, see
+/// [`Router::with_rejection_format`]. The format also answers the bodies the server refuses
+/// before calling a handler on the router, which are otherwise empty.
+///
+/// # Arguments
+///
+/// * `format`: Builds the response to a rejection, or `None` for [`Rejection::response`].
+pub fn set_rejection_format(format: Option<RejectionFormat>) {
+    *REJECTION_FORMAT
+        .write()
+        .unwrap_or_else(|error| error.into_inner()) = format;
+}
+
+/// The format a route set with [`Router::with_rejection_format`], kept in the state of its
+/// requests.
+struct ScopedFormat(RejectionFormat);
+
+/// Answers a rejection in the format of its route, as set by
+/// [`Router::with_rejection_format`] or [`set_rejection_format`].
+///
+/// # Returns
+///
+/// The response, or `None` when neither sets a format.
+pub(crate) fn custom_rejection(request: &Request, rejection: &Rejection) -> Option<Response> {
+    if let Some(ScopedFormat(format)) = request.state.get::<ScopedFormat>() {
+        return Some(format(rejection));
+    }
+    let format = REJECTION_FORMAT
+        .read()
+        .unwrap_or_else(|error| error.into_inner())
+        .clone()?;
+    Some(format(rejection))
+}
+
+/// Answers a rejection in the format of its route, as set by
+/// [`Router::with_rejection_format`] or [`set_rejection_format`], falling back to
+/// [`Rejection::response`].
+///
+/// # Arguments
+///
+/// * `request`: The rejected request.
+/// * `rejection`: Why it was rejected.
+///
+/// # Returns
+///
+/// The response.
+pub fn reject(request: &Request, rejection: &Rejection) -> Response {
+    custom_rejection(request, rejection).unwrap_or_else(|| rejection.response())
+}
+
+impl Router {
+    /// Answers the rejections of every handler registered so far in a format of their own,
+    /// instead of the one of [`set_rejection_format`]. A format set on a router mounted with
+    /// [`Router::nest`] takes precedence over the one of the outer router.
+    ///
+    /// # Arguments
+    ///
+    /// * `format`: Builds the response to a rejection.
+    ///
+    /// # Returns
+    ///
+    /// The router with the format.
+    pub fn with_rejection_format(
+        self,
+        format: impl Fn(&Rejection) -> Response + Send + Sync + 'static,
+    ) -> Router {
+        let format: Arc<dyn Any + Send + Sync> = Arc::new(ScopedFormat(Arc::new(format)));
+        self.map_handlers("rejection", |handler| {
+            Arc::new(Formatted {
+                format: Arc::clone(&format),
+                handler,
+            })
+        })
+    }
+}
+
+/// Adds the rejection format of a route to the state of its requests.
+struct Formatted {
+    format: Arc<dyn Any + Send + Sync>,
+    handler: Arc<dyn Handler>,
+}
+
+#[async_trait]
+impl Handler for Formatted {
+    async fn call(&self, mut request: Request) -> io::Result<Response> {
+        request.state.insert(Arc::clone(&self.format));
+        self.handler.call(request).await
+    }
+}
+
+/// Builds a handler argument from a request, see [`with_extractors`].
+pub trait FromRequest: Sized {
+    /// Extracts the value.
+    ///
+    /// # Arguments
+    ///
+    /// * `request`: The routed request, with its body read.
+    ///
+    /// # Returns
+    ///
+    /// The value.
+    ///
+    /// # Errors
+    ///
+    /// Returns the rejection answered instead of calling the handler.
+    fn from_request(request: &Request) -> Result<Self, Rejection>;
+}
+
+/// The whole request, for handlers that need more than their other arguments.
+impl FromRequest for Request {
+    fn from_request(request: &Request) -> Result<Request, Rejection> {
+        Ok(request.clone())
+    }
+}
+
+impl FromRequest for Method {
+    fn from_request(request: &Request) -> Result<Method, Rejection> {
+        Ok(request.method.clone())
+    }
+}
+
+impl FromRequest for HeaderMap {
+    fn from_request(request: &Request) -> Result<HeaderMap, Rejection> {
+        Ok(request.headers.clone())
+    }
+}
+
+/// The body as sent.
+impl FromRequest for Bytes {
+    fn from_request(request: &Request) -> Result<Bytes, Rejection> {
+        Ok(request.body.clone())
+    }
+}
+
+/// The body as text, rejected with 400 Bad Request when it is not UTF-8.
+impl FromRequest for String {
+    fn from_request(request: &Request) -> Result<String, Rejection> {
+        String::from_utf8(request.body.to_vec())
+            .map_err(|_| Rejection::bad_request(RejectionKind::Body, "the body is not UTF-8"))
+    }
+}
+
+/// The value, or `None` instead of a rejection.
+impl<T: FromRequest> FromRequest for Option<T> {
+    fn from_request(request: &Request) -> Result<Option<T>, Rejection> {
+        Ok(T::from_request(request).ok())
+    }
+}
+
+/// The value or the rejection, for handlers answering rejections themselves.
+impl<T: FromRequest> FromRequest for Result<T, Rejection> {
+    fn from_request(request: &Request) -> Result<Result<T, Rejection>, Rejection> {
+        Ok(T::from_request(request))
+    }
+}
+
+/// Builds a value from named parameters, see [`Path`] and [`Query`].
+///
+/// Scalars like `u64` or `String` take the only parameter, tuples take the parameters in
+/// order, `Vec<(String, String)>` takes them all, and structs implement it by looking their
+/// fields up by name with [`param`].
+pub trait FromParams: Sized {
+    /// Parses the parameters.
+    ///
+    /// # Arguments
+    ///
+    /// * `params`: The names of the parameters with their decoded values, in order.
+    ///
+    /// # Returns
+    ///
+    /// The value.
+    ///
+    /// # Errors
+    ///
+    /// Returns the reason a parameter is missing or invalid, sent to the client.
+    fn from_params(params: &[(String, String)]) -> Result<Self, String>;
+}
+
+/// Parses one decoded parameter.
+fn parse<T: FromStr>(name: &str, value: &str) -> Result<T, String>
+where
+    T::Err: fmt::Display,
+{
+    value
+        .parse()
+        .map_err(|error| format!("invalid parameter {}: {}", name, error))
+}
+
+/// Parses the parameter with a name, for implementing [`FromParams`] on structs.
+///
+/// # Arguments
+///
+/// * `params`: The parameters passed to [`FromParams::from_params`].
+/// * `name`: The name, e.g. `id` for `/users/:id` or `?id=7`.
+///
+/// # Returns
+///
+/// The parsed value.
+///
+/// # Errors
+///
+/// Returns the reason when there is no such parameter or its value does not parse.
+pub fn param<T: FromStr>(params: &[(String, String)], name: &str) -> Result<T, String>
+where
+    T::Err: fmt::Display,
+{
+    let (_, value) = params
+        .iter()
+        .find(|(found, _)| found == name)
+        .ok_or_else(|| format!("missing parameter {}", name))?;
+    parse(name, value)
+}
+
+/// Checks that there are as many parameters as a type takes.
+fn expect_count(params: &[(String, String)], count: usize) -> Result<(), String> {
+    if params.len() == count {
+        Ok(())
+    } else {
+        Err(format!(
+            "expected {} parameters, found {}",
+            count,
+            params.len()
+        ))
+    }
+}
+
+/// Implements [`FromParams`] for scalars taking the only parameter.
+macro_rules! scalar_params {
+    ($($type:ty),+) => {
+        $(
+            impl FromParams for $type {
+                fn from_params(params: &[(String, String)]) -> Result<$type, String> {
+                    expect_count(params, 1)?;
+                    parse(&params[0].0, &params[0].1)
+                }
+            }
+        )+
+    };
+}
+
+scalar_params!(
+    u8, u16, u32, u64, u128, usize, i8, i16, i32, i64, i128, isize, f32, f64, bool, char, String
+);
+
+/// Implements [`FromParams`] for tuples taking the parameters in order.
+macro_rules! tuple_params {
+    ($count:literal: $($type:ident $index:tt),+) => {
+        impl<$($type: FromStr),+> FromParams for ($($type,)+)
+        where
+            $($type::Err: fmt::Display,)+
+        {
+            fn from_params(params: &[(String, String)]) -> Result<($($type,)+), String> {
+                expect_count(params, $count)?;
+                Ok(($(parse(&params[$index].0, &params[$index].1)?,)+))
+            }
+        }
+    };
+}
+
+tuple_params!(1: A 0);
+tuple_params!(2: A 0, B 1);
+tuple_params!(3: A 0, B 1, C 2);
+tuple_params!(4: A 0, B 1, C 2, D 3);
+
+impl FromParams for Vec<(String, String)> {
+    fn from_params(params: &[(String, String)]) -> Result<Vec<(String, String)>, String> {
+        Ok(params.to_vec())
+    }
+}
+
+/// The parameters of the route pattern a request matched, parsed into a typed value, e.g.
+/// `Path<u64>` for `/users/:id`, or `Path<(String, u64)>` for `/orgs/:org/users/:id`. Rejected
+/// with 400 Bad Request when a parameter is not percent-encoded UTF-8 or does not parse.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Path<T>(pub T);
+
+impl<T: FromParams> FromRequest for Path<T> {
+    fn from_request(request: &Request) -> Result<Path<T>, Rejection> {
+        let params = request
+            .params
+            .iter()
+            .map(|(name, value)| match percent_decode(value) {
+                Some(value) => Ok((name.clone(), value)),
+                None => Err(format!("invalid parameter {}", name)),
+            })
+            .collect::<Result<Vec<_>, String>>()
+            .map_err(|reason| Rejection::bad_request(RejectionKind::Path, reason))?;
+        T::from_params(&params)
+            .map(Path)
+            .map_err(|reason| Rejection::bad_request(RejectionKind::Path, reason))
+    }
+}
+
+/// The parameters of the query string parsed into a typed value, usually a struct implementing
+/// [`FromParams`] with [`param`]. Rejected with 400 Bad Request when a parameter is missing or
+/// does not parse.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Query<T>(pub T);
+
+impl<T: FromParams> FromRequest for Query<T> {
+    fn from_request(request: &Request) -> Result<Query<T>, Rejection> {
+        T::from_params(&request.target.query_pairs())
+            .map(Query)
+            .map_err(|reason| Rejection::bad_request(RejectionKind::Query, reason))
+    }
+}
+
+/// A clone of the value of a type shared with the route by [`crate::router::Router::with_state`],
+/// e.g. `Shared<Arc<Pool>>`. Rejected with 500 Internal Server Error when no scope of the route
+/// shares one.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Shared<T>(pub T);
+
+impl<T: Clone + Send + Sync + 'static> FromRequest for Shared<T> {
+    fn from_request(request: &Request) -> Result<Shared<T>, Rejection> {
+        match request.state.get::<T>() {
+            Some(state) => Ok(Shared(state.clone())),
+            None => Err(Rejection::new(
+                RejectionKind::State,
+                StatusCode::INTERNAL_SERVER_ERROR,
+                format!("no {} is shared with the route", std::any::type_name::<T>()),
+            )),
+        }
+    }
+}
+
+/// A clone of the extension of a type attached to the request, e.g. by middleware, see
+/// [`Request::extensions`]. Rejected with 500 Internal Server Error when there is none.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Extension<T>(pub T);
+
+impl<T: Clone + Send + Sync + 'static> FromRequest for Extension<T> {
+    fn from_request(request: &Request) -> Result<Extension<T>, Rejection> {
+        match request.extensions().get::<T>() {
+            Some(extension) => Ok(Extension(extension.clone())),
+            None => Err(Rejection::new(
+                RejectionKind::Extension,
+                StatusCode::INTERNAL_SERVER_ERROR,
+                format!(
+                    "no {} is attached to the request",
+                    std::any::type_name::<T>()
+                ),
+            )),
+        }
+    }
+}
+
+/// The address of the client connection. Rejected with 500 Internal Server Error when the
+/// connection does not know it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ClientAddress(pub SocketAddr);
+
+impl FromRequest for ClientAddress {
+    fn from_request(request: &Request) -> Result<ClientAddress, Rejection> {
+        request.peer.map(ClientAddress).ok_or_else(|| {
+            Rejection::new(
+                RejectionKind::ClientAddress,
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "the client address is unknown",
+            )
+        })
+    }
+}
+
+/// A JSON body deserialized into a typed value, enabled by the `json` feature. Rejected with
+/// 415 Unsupported Media Type unless the `Content-Type` is `application/json`, and with 400 Bad
+/// Request when the body does not deserialize.
+#[cfg(feature = "json")]
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Json<T>(pub T);
+
+#[cfg(feature = "json")]
+impl<T: serde::de::DeserializeOwned> FromRequest for Json<T> {
+    fn from_request(request: &Request) -> Result<Json<T>, Rejection> {
+        if request.headers.content_type() != Some("application/json") {
+            return Err(Rejection::new(
+                RejectionKind::Json,
+                StatusCode::UNSUPPORTED_MEDIA_TYPE,
+                "expected a Content-Type of application/json",
+            ));
+        }
+        serde_json::from_slice(&request.body)
+            .map(Json)
+            .map_err(|error| {
+                Rejection::bad_request(RejectionKind::Json, format!("invalid JSON body: {}", error))
+            })
+    }
+}
+
+/// The value serialized as a JSON body with 200 OK, or an empty 500 Internal Server Error when
+/// it does not serialize.
+#[cfg(feature = "json")]
+impl<T: serde::Serialize> IntoResponse for Json<T> {
+    fn into_response(self) -> Response {
+        match serde_json::to_vec(&self.0) {
+            Ok(body) => {
+                Response::new(StatusCode::OK, body).with_header("Content-Type", "application/json")
+            }
+            Err(error) => {
+                crate::error::server_error(StatusCode::INTERNAL_SERVER_ERROR, &error.to_string())
+            }
+        }
+    }
+}
+
+/// The response of the rejection in the format of [`set_rejection_format`].
+impl IntoResponse for Rejection {
+    fn into_response(self) -> Response {
+        self.error_response()
+    }
+}
+
+/// The response of the rejection in the format of [`set_rejection_format`], for handlers
+/// returning rejections of their own.
+impl ResponseError for Rejection {
+    fn status(&self) -> StatusCode {
+        self.status
+    }
+
+    fn error_response(&self) -> Response {
+        reject(&Request::default(), self)
+    }
+}
+
+/// A handler whose arguments are extracted from the request, see [`with_extractors`].
+pub struct Extracted<Args, F> {
+    handler: F,
+    args: PhantomData<fn() -> Args>,
+}
+
+/// Adapts an async function or closure taking up to six arguments implementing [`FromRequest`]
+/// into a [`Handler`], e.g. `router.route(Method::Get, "/users/:id", with_extractors(get_user))`
+/// for `async fn get_user(Path(id): Path<u64>, Query(page): Query<Page>)`. The arguments are
+/// extracted in order, and the first rejection is answered without calling the function. The
+/// function returns anything implementing [`IntoResponse`], including a `Result` whose error
+/// implements [`ResponseError`], where an [`io::Error`] becomes a 500 Internal Server Error
+/// rather than ending the connection.
+///
+/// # Arguments
+///
+/// * `handler`: The function taking the extracted arguments.
+///
+/// # Returns
+///
+/// The handler to register.
+pub fn with_extractors<Args, F>(handler: F) -> Extracted<Args, F> {
+    Extracted {
+        handler,
+        args: PhantomData,
+    }
+}
+
+/// Adapts a handler to receive the parameters of its route parsed besides the request, like
+/// [`with_extractors`] for a function taking a [`Path`] and the [`Request`].
+///
+/// # Arguments
+///
+/// * `handler`: The handler taking the parameters and the request.
+///
+/// # Returns
+///
+/// The handler to register.
+pub fn with_path<T, F, Fut>(handler: F) -> Extracted<(Path<T>, Request), F>
+where
+    T: FromParams + Send,
+    F: Fn(Path<T>, Request) -> Fut + Send + Sync,
+    Fut: Future<Output = io::Result<Response>> + Send,
+{
+    with_extractors(handler)
+}
+
+/// Implements [`Handler`] for functions taking extractors.
+macro_rules! extracted_handler {
+    ($($arg:ident),*) => {
+        #[async_trait]
+        impl<F, Fut, $($arg),*> Handler for Extracted<($($arg,)*), F>
+        where
+            F: Fn($($arg),*) -> Fut + Send + Sync,
+            Fut: Future + Send,
+            Fut::Output: IntoResponse,
+            $($arg: FromRequest + Send,)*
+        {
+            #[allow(non_snake_case, unused_variables)]
+            async fn call(&self, request: Request) -> io::Result<Response> {
+                $(
+                    let $arg = match $arg::from_request(&request) {
+                        Ok(value) => value,
+                        Err(rejection) => return Ok(reject(&request, &rejection)),
+                    };
+                )*
+                Ok((self.handler)($($arg),*).await.into_response())
+            }
+        }
+    };
+}
+
+extracted_handler!();
+extracted_handler!(A);
+extracted_handler!(A, B);
+extracted_handler!(A, B, C);
+extracted_handler!(A, B, C, D);
+extracted_handler!(A, B, C, D, E);
+extracted_handler!(A, B, C, D, E, G);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::method::Method;
+    use crate::router::MatchedPath;
+
+    /// It parses scalars, tuples, and structs from the captured parameters
+    #[test]
+    fn path() {
+        struct User {
+            org: String,
+            id: u64,
+        }
+        impl FromParams for User {
+            fn from_params(params: &[(String, String)]) -> Result<User, String> {
+                Ok(User {
+                    org: param(params, "org")?,
+                    id: param(params, "id")?,
+                })
+            }
+        }
+        let request = |params: &[(&str, &str)]| Request {
+            params: params
+                .iter()
+                .map(|(name, value)| (name.to_string(), value.to_string()))
+                .collect(),
+            ..Request::default()
+        };
+        assert_eq!(
+            Ok(Path(7)),
+            Path::<u64>::from_request(&request(&[("id", "7")]))
+        );
+        let both = request(&[("org", "a%20b"), ("id", "7")]);
+        assert_eq!(
+            Ok(Path(("a b".to_string(), 7))),
+            Path::<(String, u8)>::from_request(&both)
+        );
+        let Ok(Path(user)) = Path::<User>::from_request(&both) else {
+            panic!("the user did not parse");
+        };
+        assert_eq!(("a b", 7), (user.org.as_str(), user.id));
+        let rejection = Path::<u64>::from_request(&request(&[("id", "x")])).unwrap_err();
+        assert_eq!(
+            "invalid parameter id: invalid digit found in string",
+            rejection.to_string()
+        );
+        assert_eq!(StatusCode::BAD_REQUEST, rejection.response().status);
+        assert!(Path::<u64>::from_request(&both).is_err());
+    }
+
+    /// It calls functions with the arguments extracted in order, answering the first rejection
+    #[tokio::test]
+    async fn with_extractors() {
+        #[derive(Clone, Debug)]
+        struct Greeting(&'static str);
+        let router = Router::new()
+            .route(
+                Method::Post,
+                "/users/:id",
+                super::with_extractors(
+                    |Path(id): Path<u64>,
+                     Query(page): Query<Vec<(String, String)>>,
+                     Shared(Greeting(greeting)): Shared<Greeting>,
+                     body: String| async move {
+                        format!("{} {} {:?} {}", greeting, id, page, body)
+                    },
+                ),
+            )
+            .route(
+                Method::Get,
+                "/whoami",
+                super::with_extractors(
+                    |address: Option<ClientAddress>, Extension(MatchedPath(pattern))| async move {
+                        format!("{:?} {}", address, pattern)
+                    },
+                ),
+            )
+            .with_state(Greeting("hello"));
+        let call = |method: Method, target: &str, body: &'static [u8]| {
+            let request = Request {
+                method,
+                target: target.parse().unwrap(),
+                body: Bytes::from_static(body),
+                ..Request::default()
+            };
+            let router = router.clone();
+            async move { router.dispatch(&request).await.unwrap().unwrap() }
+        };
+        let response = call(Method::Post, "/users/7?page=2", b"hi").await;
+        assert_eq!(b"hello 7 [(\"page\", \"2\")] hi".to_vec(), response.body);
+        let response = call(Method::Post, "/users/x", b"hi").await;
+        assert_eq!(
+            b"invalid parameter id: invalid digit found in string\n".to_vec(),
+            response.body
+        );
+        let response = call(Method::Post, "/users/7", b"\xff").await;
+        assert_eq!(StatusCode::BAD_REQUEST, response.status);
+        assert_eq!(
+            b"None /whoami".to_vec(),
+            call(Method::Get, "/whoami", b"").await.body
+        );
+        let unshared = Request::default();
+        let rejection = Shared::<Greeting>::from_request(&unshared).unwrap_err();
+        assert_eq!(StatusCode::INTERNAL_SERVER_ERROR, rejection.status);
+    }
+
+    /// It answers rejections in the format of the innermost scope setting one
+    #[tokio::test]
+    async fn rejection_format() {
+        let users = Router::new()
+            .route(
+                Method::Get,
+                "/:id",
+                super::with_extractors(|Path(id): Path<u64>| async move { id.to_string() }),
+            )
+            .with_rejection_format(|rejection| {
+                let body = format!("{} {}", rejection.kind.as_str(), rejection.status.as_u16());
+                Response::new(rejection.status, body)
+            });
+        let router = Router::new()
+            .route(
+                Method::Get,
+                "/:name",
+                super::with_extractors(|Query(page): Query<u8>| async move { page.to_string() }),
+            )
+            .nest("/users", users)
+            .with_rejection_format(|rejection| Response::new(rejection.status, "outer"));
+        let call = |target: &str| {
+            let request = Request {
+                method: Method::Get,
+                target: target.parse().unwrap(),
+                ..Request::default()
+            };
+            let router = router.clone();
+            async move { router.dispatch(&request).await.unwrap().unwrap() }
+        };
+        let response = call("/users/x").await;
+        assert_eq!(StatusCode::BAD_REQUEST, response.status);
+        assert_eq!(b"path 400".to_vec(), response.body);
+        assert_eq!(b"outer".to_vec(), call("/a?page=x").await.body);
+        assert_eq!(b"2".to_vec(), call("/a?page=2").await.body);
+        assert_eq!(
+            vec!["rejection", "rejection"],
+            router.routes()[1].middleware
+        );
+    }
+
+    /// It deserializes JSON bodies, rejects other media types, and serializes responses
+    #[cfg(feature = "json")]
+    #[test]
+    fn json() {
+        let request = |content_type: &str, body: &'static str| Request {
+            headers: [("Content-Type", content_type)].into_iter().collect(),
+            body: Bytes::from_static(body.as_bytes()),
+            ..Request::default()
+        };
+        let Json(value) =
+            Json::<serde_json::Value>::from_request(&request("application/json", r#"{"a":1}"#))
+                .unwrap();
+        assert_eq!(serde_json::json!({"a": 1}), value);
+        let response = Json(value).into_response();
+        assert_eq!(Some("application/json"), response.header("Content-Type"));
+        assert_eq!(b"{\"a\":1}".to_vec(), response.body);
+        let rejection =
+            Json::<serde_json::Value>::from_request(&request("text/plain", "{}")).unwrap_err();
+        assert_eq!(StatusCode::UNSUPPORTED_MEDIA_TYPE, rejection.status);
+        let rejection =
+            Json::<serde_json::Value>::from_request(&request("application/json", "{")).unwrap_err();
+        assert_eq!(StatusCode::BAD_REQUEST, rejection.status);
+    }
+
+    /// It answers 400 Bad Request without calling the handler when the parameters do not parse
+    #[tokio::test]
+    async fn with_path() {
+        let router = Router::new().route(
+            Method::Get,
+            "/users/:id",
+            super::with_path(|Path(id): Path<u64>, _| async move {
+                Ok(Response::new(StatusCode::OK, format!("user {}", id)))
+            }),
+        );
+        let call = |target: &str| {
+            let request = Request {
+                method: Method::Get,
+                target: target.parse().unwrap(),
+                ..Request::default()
+            };
+            let router = router.clone();
+            async move { router.dispatch(&request).await.unwrap().unwrap() }
+        };
+        assert_eq!(b"user 7".to_vec(), call("/users/7").await.body);
+        assert_eq!(StatusCode::BAD_REQUEST, call("/users/me").await.status);
+    }
+}
@@ -0,0 +1,462 @@
+use crate::cgi::{meta_variables, parse_output};
+use crate::log;
+use crate::path::{percent_decode, resolve};
+use crate::request::Request;
+use crate::response::Response;
+use crate::router::Handler;
+use crate::status::StatusCode;
+use async_trait::async_trait;
+use bytes::{BufMut, Bytes, BytesMut};
+use std::path::PathBuf;
+use std::time::Duration;
+use tokio::io::{self, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net;
+use tokio::time;
+
+/// The record types a responder exchanges, see the FastCGI 1.0 specification.
+const BEGIN_REQUEST: u8 = 1;
+const END_REQUEST: u8 = 3;
+const PARAMS: u8 = 4;
+const STDIN: u8 = 5;
+const STDOUT: u8 = 6;
+const STDERR: u8 = 7;
+
+/// The role asking the application to produce a response.
+const RESPONDER: u16 = 1;
+
+/// The id of the only request sent over each connection.
+const REQUEST_ID: u16 = 1;
+
+/// The most content one record carries.
+const MAX_CONTENT: usize = u16::MAX as usize;
+
+/// Where a FastCGI application listens.
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Backend {
+    Tcp(String),
+    Unix(PathBuf),
+}
+
+/// A gateway forwarding requests for scripts at or below a route to a FastCGI application such
+/// as php-fpm, over a new connection for each request.
+///
+/// It is consulted right after the proxy when present in [`crate::config::Config::fastcgi`],
+/// and can also be registered on a [`crate::router::Router`] like any other [`Handler`]. The
+/// body is sent in records as the application reads it, while the output is collected before
+/// it is answered because a [`Response`] holds its body in memory.
+#[derive(Clone, Debug)]
+pub struct FastCgi {
+    route: String,
+    backend: Backend,
+    root: PathBuf,
+    extension: String,
+    index: String,
+    timeout: Duration,
+}
+
+impl FastCgi {
+    /// Creates a gateway for `.php` scripts, with `index.php` for directories, that gives up on
+    /// the application after 30 seconds.
+    ///
+    /// # Arguments
+    ///
+    /// * `route`: The path prefix forwarded, e.g. `/blog`, or `/` for every request.
+    /// * `address`: The application address, e.g. `127.0.0.1:9000`, or a Unix socket path
+    ///   after `unix:`, e.g. `unix:/run/php/php-fpm.sock`.
+    /// * `root`: The directory holding the scripts as the application sees it.
+    ///
+    /// # Returns
+    ///
+    /// A gateway to the application.
+    pub fn new(route: &str, address: &str, root: impl Into<PathBuf>) -> FastCgi {
+        let backend = match address.strip_prefix("unix:") {
+            Some(path) => Backend::Unix(PathBuf::from(path)),
+            None => Backend::Tcp(address.to_string()),
+        };
+        FastCgi {
+            route: route.to_string(),
+            backend,
+            root: root.into(),
+            extension: ".php".to_string(),
+            index: "index.php".to_string(),
+            timeout: Duration::from_secs(30),
+        }
+    }
+
+    /// Sets the file name extension of scripts, `.php` by default.
+    ///
+    /// # Arguments
+    ///
+    /// * `extension`: The extension including its dot, e.g. `.fcgi`.
+    ///
+    /// # Returns
+    ///
+    /// The gateway with the new extension.
+    pub fn with_extension(mut self, extension: &str) -> FastCgi {
+        self.extension = extension.to_string();
+        self
+    }
+
+    /// Sets the script run for paths ending in `/`, `index.php` by default.
+    ///
+    /// # Arguments
+    ///
+    /// * `index`: The script file name.
+    ///
+    /// # Returns
+    ///
+    /// The gateway with the new index script.
+    pub fn with_index(mut self, index: &str) -> FastCgi {
+        self.index = index.to_string();
+        self
+    }
+
+    /// Sets how long a request may take before it is answered with 504 Gateway Timeout.
+    ///
+    /// # Arguments
+    ///
+    /// * `timeout`: The limit for connecting, sending, and reading the whole output.
+    ///
+    /// # Returns
+    ///
+    /// The gateway with the new timeout.
+    pub fn with_timeout(mut self, timeout: Duration) -> FastCgi {
+        self.timeout = timeout;
+        self
+    }
+
+    /// The path prefix of scripts, as passed to the constructor.
+    pub fn route(&self) -> &str {
+        &self.route
+    }
+
+    /// Checks whether a request is for a script at or below the gateway route.
+    ///
+    /// # Arguments
+    ///
+    /// * `request`: The incoming request.
+    ///
+    /// # Returns
+    ///
+    /// True when the path continues the route with a `/` and names a script, either through a
+    /// segment ending in the extension or by ending in `/`.
+    pub fn matches(&self, request: &Request) -> bool {
+        let route = self.route.trim_end_matches('/');
+        request
+            .path()
+            .strip_prefix(route)
+            .is_some_and(|rest| rest.is_empty() || rest.starts_with('/'))
+            && self.script(request.path()).is_some()
+    }
+
+    /// Splits a request path into the script and the path info following it.
+    ///
+    /// # Arguments
+    ///
+    /// * `path`: The percent-encoded request path.
+    ///
+    /// # Returns
+    ///
+    /// The percent-encoded script name, e.g. `/blog/index.php`, with the percent-encoded path
+    /// info, e.g. `/2024/hello`, or `None` when the path names no script.
+    fn script(&self, path: &str) -> Option<(String, String)> {
+        let end = path
+            .match_indices(&self.extension)
+            .map(|(index, _)| index + self.extension.len())
+            .find(|end| path[*end..].is_empty() || path[*end..].starts_with('/'));
+        match end {
+            Some(end) => Some((path[..end].to_string(), path[end..].to_string())),
+            None if path.ends_with('/') => {
+                Some((path.to_string() + self.index.as_str(), String::new()))
+            }
+            None => None,
+        }
+    }
+
+    /// Runs a request through the application.
+    ///
+    /// # Arguments
+    ///
+    /// * `request`: The request with its body read.
+    ///
+    /// # Returns
+    ///
+    /// The application output, the script's response in CGI form.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`io::ErrorKind::NotFound`] for a path that cannot be decoded or names no script
+    /// below the root,
+    /// [`io::ErrorKind::InvalidData`] for malformed records, and captures IO errors from
+    /// connecting, writing, and reading.
+    async fn run(&self, request: &Request) -> io::Result<Bytes> {
+        let not_found = || io::Error::from(io::ErrorKind::NotFound);
+        let (script, rest) = self.script(request.path()).ok_or_else(not_found)?;
+        let script_filename = resolve(&self.root, &script).ok_or_else(not_found)?;
+        let script_name = percent_decode(&script).ok_or_else(not_found)?;
+        let path_info = percent_decode(&rest).ok_or_else(not_found)?;
+        let variables = meta_variables(request, &script_name, &path_info, &script_filename);
+        match &self.backend {
+            Backend::Tcp(address) => {
+                exchange(net::TcpStream::connect(address).await?, &variables, request).await
+            }
+            #[cfg(unix)]
+            Backend::Unix(path) => {
+                exchange(net::UnixStream::connect(path).await?, &variables, request).await
+            }
+            #[cfg(not(unix))]
+            Backend::Unix(_) => Err(io::Error::from(io::ErrorKind::Unsupported)),
+        }
+    }
+}
+
+/// Implementing the [`Handler`] trait for the [`FastCgi`] struct, answering failures to reach
+/// the application or understand its output with gateway errors.
+#[async_trait]
+impl Handler for FastCgi {
+    async fn call(&self, request: Request) -> io::Result<Response> {
+        Ok(
+            match time::timeout(self.timeout, self.run(&request)).await {
+                Ok(Ok(output)) => parse_output(&output).unwrap_or_else(|error| {
+                    log::warn(format_args!(
+                        "FastCGI application answered {} with malformed output: {}",
+                        request.target, error
+                    ));
+                    Response::new(StatusCode::BAD_GATEWAY, "")
+                }),
+                Ok(Err(error)) if error.kind() == io::ErrorKind::NotFound => {
+                    Response::new(StatusCode::NOT_FOUND, "")
+                }
+                Ok(Err(error)) => {
+                    log::warn(format_args!(
+                        "FastCGI application failed on {}: {}",
+                        request.target, error
+                    ));
+                    Response::new(StatusCode::BAD_GATEWAY, "")
+                }
+                Err(_) => {
+                    log::warn(format_args!(
+                        "FastCGI application did not answer {} within {:?}",
+                        request.target, self.timeout
+                    ));
+                    Response::new(StatusCode::GATEWAY_TIMEOUT, "")
+                }
+            },
+        )
+    }
+}
+
+/// Sends one request over a connection and reads its output until the application ends it.
+///
+/// # Errors
+///
+/// Returns [`io::ErrorKind::InvalidData`] for malformed records or an application that
+/// refuses the request, and captures IO errors from writing and reading.
+async fn exchange<S>(
+    mut stream: S,
+    variables: &[(String, String)],
+    request: &Request,
+) -> io::Result<Bytes>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut head = BytesMut::new();
+    // Without the keep-connection flag, the application closes the connection when done
+    let mut begin = [0; 8];
+    begin[..2].copy_from_slice(&RESPONDER.to_be_bytes());
+    put_record(&mut head, BEGIN_REQUEST, &begin);
+
+    let mut params = BytesMut::new();
+    for (name, value) in variables {
+        put_length(&mut params, name.len());
+        put_length(&mut params, value.len());
+        params.put_slice(name.as_bytes());
+        params.put_slice(value.as_bytes());
+    }
+    for chunk in params.chunks(MAX_CONTENT) {
+        put_record(&mut head, PARAMS, chunk);
+    }
+    put_record(&mut head, PARAMS, &[]);
+    stream.write_all(&head).await?;
+
+    let mut record = BytesMut::new();
+    for chunk in request.body.chunks(MAX_CONTENT) {
+        record.clear();
+        put_record(&mut record, STDIN, chunk);
+        stream.write_all(&record).await?;
+    }
+    record.clear();
+    put_record(&mut record, STDIN, &[]);
+    stream.write_all(&record).await?;
+
+    let mut output = BytesMut::new();
+    loop {
+        let mut header = [0; 8];
+        stream.read_exact(&mut header).await?;
+        let id = u16::from_be_bytes([header[2], header[3]]);
+        let length = usize::from(u16::from_be_bytes([header[4], header[5]]));
+        let mut content = vec![0; length + usize::from(header[6])];
+        stream.read_exact(&mut content).await?;
+        content.truncate(length);
+        if id != REQUEST_ID {
+            continue;
+        }
+        match header[1] {
+            STDOUT => output.put_slice(&content),
+            STDERR => {
+                for line in String::from_utf8_lossy(&content).lines() {
+                    log::warn(format_args!("FastCGI application: {}", line));
+                }
+            }
+            END_REQUEST => {
+                // The protocol status follows the four bytes of the application's exit status
+                return match content.get(4) {
+                    Some(0) => Ok(output.freeze()),
+                    _ => Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        "FastCGI request refused",
+                    )),
+                };
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Appends a record with its header and no padding.
+fn put_record(buffer: &mut BytesMut, kind: u8, content: &[u8]) {
+    buffer.put_u8(1);
+    buffer.put_u8(kind);
+    buffer.put_u16(REQUEST_ID);
+    buffer.put_u16(content.len() as u16);
+    buffer.put_u8(0);
+    buffer.put_u8(0);
+    buffer.put_slice(content);
+}
+
+/// Appends the length of a name or value, in one byte below 128 and in four otherwise.
+fn put_length(buffer: &mut BytesMut, length: usize) {
+    if length < 128 {
+        buffer.put_u8(length as u8);
+    } else {
+        buffer.put_u32(length as u32 | 0x8000_0000);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::sync::mpsc;
+
+    /// Starts an application answering each request with `output` on stdout and reporting the
+    /// parameters and stdin it received.
+    async fn application(
+        output: &'static [u8],
+    ) -> (
+        String,
+        mpsc::UnboundedReceiver<(Vec<(String, String)>, Vec<u8>)>,
+    ) {
+        let listener = net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let (sender, receiver) = mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                let mut params = Vec::new();
+                let mut stdin = Vec::new();
+                loop {
+                    let mut header = [0; 8];
+                    stream.read_exact(&mut header).await.unwrap();
+                    let length = usize::from(u16::from_be_bytes([header[4], header[5]]));
+                    let mut content = vec![0; length + usize::from(header[6])];
+                    stream.read_exact(&mut content).await.unwrap();
+                    match header[1] {
+                        PARAMS => params.extend_from_slice(&content),
+                        STDIN if length == 0 => break,
+                        STDIN => stdin.extend_from_slice(&content),
+                        _ => {}
+                    }
+                }
+                let mut pairs = Vec::new();
+                let mut rest = &params[..];
+                while !rest.is_empty() {
+                    let (name, value) = (usize::from(rest[0]), usize::from(rest[1]));
+                    let pair = &rest[2..2 + name + value];
+                    pairs.push((
+                        String::from_utf8(pair[..name].to_vec()).unwrap(),
+                        String::from_utf8(pair[name..].to_vec()).unwrap(),
+                    ));
+                    rest = &rest[2 + name + value..];
+                }
+                sender.send((pairs, stdin)).unwrap();
+                let mut reply = BytesMut::new();
+                put_record(&mut reply, STDOUT, output);
+                put_record(&mut reply, END_REQUEST, &[0; 8]);
+                stream.write_all(&reply).await.unwrap();
+            }
+        });
+        (address, receiver)
+    }
+
+    /// It passes the request as parameters and stdin and answers with the script's output
+    #[tokio::test]
+    async fn forward() {
+        let (address, mut received) =
+            application(b"Status: 201 Created\r\nX-A: 1\r\n\r\nhello").await;
+        let gateway = FastCgi::new("/blog", &address, "/srv/www");
+        let mut request = Request {
+            target: "/blog/index.php/2024/a%20b?x=1".parse().unwrap(),
+            body: Bytes::from_static(b"body"),
+            peer: Some("192.0.2.7:50000".parse().unwrap()),
+            ..Request::default()
+        };
+        request.headers.append("Host", "example.com:8080");
+        request.headers.append("Accept", "a");
+        request.headers.append("accept", "b");
+        request.headers.append("Proxy", "evil");
+        assert!(gateway.matches(&request));
+        let response = gateway.call(request).await.unwrap();
+        assert_eq!(StatusCode::CREATED, response.status);
+        assert_eq!(Some("1"), response.header("X-A"));
+        assert_eq!(Bytes::from_static(b"hello"), response.body);
+
+        let (params, stdin) = received.recv().await.unwrap();
+        assert_eq!(b"body", &stdin[..]);
+        let param = |name: &str| {
+            params
+                .iter()
+                .find(|(key, _)| key == name)
+                .map(|(_, value)| value.as_str())
+        };
+        assert_eq!(Some("/blog/index.php"), param("SCRIPT_NAME"));
+        assert_eq!(Some("/srv/www/blog/index.php"), param("SCRIPT_FILENAME"));
+        assert_eq!(Some("/2024/a b"), param("PATH_INFO"));
+        assert_eq!(Some("x=1"), param("QUERY_STRING"));
+        assert_eq!(Some("example.com"), param("SERVER_NAME"));
+        assert_eq!(Some("8080"), param("SERVER_PORT"));
+        assert_eq!(Some("192.0.2.7"), param("REMOTE_ADDR"));
+        assert_eq!(Some("4"), param("CONTENT_LENGTH"));
+        assert_eq!(Some("a, b"), param("HTTP_ACCEPT"));
+        assert_eq!(None, param("HTTP_PROXY"));
+    }
+
+    /// It only forwards paths naming a script, with the index script for directories
+    #[test]
+    fn matches() {
+        let gateway = FastCgi::new("/blog", "unix:/run/php.sock", "/srv/www");
+        let matches = |target: &str| {
+            gateway.matches(&Request {
+                target: target.parse().unwrap(),
+                ..Request::default()
+            })
+        };
+        assert!(matches("/blog/"));
+        assert!(matches("/blog/a.php"));
+        assert!(!matches("/blog/a.phps"));
+        assert!(!matches("/blog/style.css"));
+        assert!(!matches("/blogs/a.php"));
+        assert_eq!(
+            Some(("/blog/index.php".to_string(), String::new())),
+            gateway.script("/blog/")
+        );
+    }
+}
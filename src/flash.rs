@@ -0,0 +1,87 @@
+//! One-shot messages carried across a redirect, e.g. "Saved" after a form POST.
+//!
+//! A handler answering a POST calls [`redirect`] to queue a message and send the client to the
+//! page that shows it. That page calls [`take`], which returns the messages and removes them, so
+//! reloading it does not show them again.
+
+use crate::request::Request;
+use crate::response::Response;
+use crate::session::{Flash, SessionStore};
+use crate::status::StatusCode;
+
+/// Queues a flash message and redirects with 303 See Other, so the browser follows with a GET.
+///
+/// # Arguments
+///
+/// * `store`: The session store.
+/// * `request`: The request being answered, usually a POST.
+/// * `location`: Where to send the client.
+/// * `level`: A category for styling, e.g. `success` or `error`.
+/// * `message`: The message shown to the user.
+///
+/// # Returns
+///
+/// The redirect response, with a session cookie when the client had no session yet.
+pub fn redirect(
+    store: &SessionStore,
+    request: &Request,
+    location: &str,
+    level: &str,
+    message: impl Into<String>,
+) -> Response {
+    let mut session = store.load(request);
+    session.flash(level, message);
+    store.save(
+        session,
+        Response::new(StatusCode::SEE_OTHER, "").with_header("Location", location),
+    )
+}
+
+/// Reads and clears the flash messages of the request's session.
+///
+/// # Arguments
+///
+/// * `store`: The session store.
+/// * `request`: The request being answered.
+///
+/// # Returns
+///
+/// The queued messages in the order they were added, or nothing without a session.
+pub fn take(store: &SessionStore, request: &Request) -> Vec<Flash> {
+    let mut session = store.load(request);
+    let flashes = session.take_flashes();
+    if !flashes.is_empty() {
+        store.persist(session);
+    }
+    flashes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// It shows a message once after the redirect and never again
+    #[test]
+    fn redirect_after_post() {
+        let store = SessionStore::new();
+        let response = redirect(&store, &Request::default(), "/items", "success", "Saved");
+        assert_eq!(StatusCode::SEE_OTHER, response.status);
+        assert_eq!(Some("/items"), response.header("Location"));
+
+        let cookie = response
+            .header("Set-Cookie")
+            .map(|value| value.split(';').next().unwrap().to_string())
+            .unwrap();
+        let mut follow_up = Request::default();
+        follow_up.headers.append("Cookie", cookie);
+        let flashes = take(&store, &follow_up);
+        assert_eq!(
+            vec![Flash {
+                level: "success".to_string(),
+                message: "Saved".to_string()
+            }],
+            flashes
+        );
+        assert!(take(&store, &follow_up).is_empty());
+    }
+}
@@ -0,0 +1,531 @@
+//! Passes HTTP/2 connections through to a gRPC backend, so that the server can sit in front of
+//! gRPC services next to the sites it serves over HTTP/1.1. Clients reach the HTTP/2 path with
+//! prior knowledge on the plain listener, as gRPC clients do without TLS, or through ALPN `h2`
+//! on the TLS listener once it is offered, e.g. with `WEB_SERVER_TLS_ALPN`. Every stream is
+//! relayed to one HTTP/2 connection to the backend per client connection, with headers,
+//! `content-type: application/grpc`, and trailers such as `grpc-status` unchanged. Messages are
+//! only taken from one side as fast as the other side's flow control window accepts them, so
+//! slow receivers slow down senders instead of filling memory.
+//!
+//! Relayed streams bypass what [`crate::handle_stream`] applies to HTTP/1.1 requests: the
+//! maintenance page, load shedding, the concurrency limit, rate limiting, and every other
+//! middleware of the router. Only the request and response counters of
+//! [`crate::server::ServerStats`] see them.
+
+use crate::config::Config;
+use crate::connection::Connection;
+use crate::log;
+use crate::status::StatusCode;
+use bytes::Bytes;
+use h2::{client, server, Reason, RecvStream, SendStream};
+use http::uri::{Authority, Scheme, Uri};
+use std::future::poll_fn;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{self, AsyncRead, AsyncWrite};
+use tokio::sync::Mutex;
+use tokio::task::JoinSet;
+use tokio::{net, time};
+
+/// The connection preface of HTTP/2 clients with prior knowledge.
+const PREFACE: &[u8] = b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n";
+
+/// The gRPC status telling clients that the backend cannot be reached.
+const UNAVAILABLE: &str = "14";
+
+/// Relays HTTP/2 streams to a gRPC backend, see [`crate::config::Config::grpc`].
+#[derive(Clone, Debug)]
+pub struct GrpcProxy {
+    upstream: String,
+    connect_timeout: Duration,
+}
+
+impl GrpcProxy {
+    /// Relays streams to a backend speaking HTTP/2 without TLS, giving up connecting after 5
+    /// seconds.
+    ///
+    /// # Arguments
+    ///
+    /// * `upstream`: The address of the backend as `host:port`, e.g. `127.0.0.1:50051`.
+    pub fn new(upstream: impl Into<String>) -> GrpcProxy {
+        GrpcProxy {
+            upstream: upstream.into(),
+            connect_timeout: Duration::from_secs(5),
+        }
+    }
+
+    /// Sets how long connecting to the backend may take before streams fail with the gRPC
+    /// status `UNAVAILABLE`.
+    pub fn with_connect_timeout(mut self, timeout: Duration) -> GrpcProxy {
+        self.connect_timeout = timeout;
+        self
+    }
+
+    /// Opens an HTTP/2 connection to the backend, driven by a task of its own that ends once
+    /// every handle opening streams on it is dropped.
+    ///
+    /// # Returns
+    ///
+    /// The handle opening streams on the connection and whether it closed since.
+    async fn connect(&self) -> io::Result<Upstream> {
+        let stream = time::timeout(
+            self.connect_timeout,
+            net::TcpStream::connect(&self.upstream),
+        )
+        .await
+        .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "connecting timed out"))??;
+        stream.set_nodelay(true)?;
+        let (sender, connection) = client::handshake(stream).await.map_err(into_io)?;
+        let closed = Arc::new(AtomicBool::new(false));
+        tokio::spawn({
+            let closed = Arc::clone(&closed);
+            async move {
+                if let Err(error) = connection.await {
+                    log::debug(format_args!("the gRPC backend connection ended: {}", error));
+                }
+                closed.store(true, Ordering::Relaxed);
+            }
+        });
+        Ok((sender, closed))
+    }
+
+    /// Hands out the connection to the backend shared by the streams of one client connection,
+    /// opening it for the first stream and again once it closed. Streams arriving meanwhile
+    /// wait for that attempt instead of opening connections of their own.
+    ///
+    /// # Arguments
+    ///
+    /// * `upstream`: The connection shared by the streams.
+    ///
+    /// # Returns
+    ///
+    /// The handle opening a stream on the connection.
+    ///
+    /// # Errors
+    ///
+    /// Captures the errors of connecting to the backend.
+    async fn sender(
+        &self,
+        upstream: &Mutex<Option<Upstream>>,
+    ) -> io::Result<client::SendRequest<Bytes>> {
+        let mut upstream = upstream.lock().await;
+        if let Some((sender, closed)) = upstream.as_ref() {
+            if !closed.load(Ordering::Relaxed) {
+                return Ok(sender.clone());
+            }
+        }
+        let connected = self.connect().await?;
+        Ok(upstream.insert(connected).0.clone())
+    }
+}
+
+/// A connection to the backend and whether it closed.
+type Upstream = (client::SendRequest<Bytes>, Arc<AtomicBool>);
+
+/// Reads until the bytes a client sent first either are the preface of HTTP/2 with prior
+/// knowledge or cannot become it anymore, so a preface split across segments is recognized
+/// too. Nothing is consumed.
+///
+/// # Arguments
+///
+/// * `connection`: The connection right after it was accepted.
+///
+/// # Returns
+///
+/// `true` when the connection starts with the preface.
+///
+/// # Errors
+///
+/// Captures IO errors from reading the connection.
+pub(crate) async fn starts_with_preface<S: AsyncRead + Unpin>(
+    connection: &mut Connection<S>,
+) -> io::Result<bool> {
+    loop {
+        let buffered = connection.buffered();
+        if buffered.len() >= PREFACE.len() || !PREFACE.starts_with(buffered) {
+            return Ok(buffered.starts_with(PREFACE));
+        }
+        if connection.fill_more().await? == 0 {
+            return Ok(false);
+        }
+    }
+}
+
+/// Serves an HTTP/2 connection by relaying every stream to the backend, each in a task of its
+/// own so that a stream waiting for the backend holds up no other.
+///
+/// # Arguments
+///
+/// * `stream`: The client connection, right before the connection preface.
+/// * `proxy`: The backend.
+/// * `config`: The settings counting requests and responses.
+///
+/// # Returns
+///
+/// `Ok(())` once the client closed the connection and its last stream ended.
+///
+/// # Errors
+///
+/// Captures HTTP/2 protocol errors of the client connection, while errors of single streams
+/// only reset those.
+pub(crate) async fn serve<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: S,
+    proxy: &GrpcProxy,
+    config: &Config,
+) -> io::Result<()> {
+    let mut connection = server::handshake(stream).await.map_err(into_io)?;
+    let mut tasks = JoinSet::new();
+    let upstream = Arc::new(Mutex::new(None));
+    while let Some(accepted) = connection.accept().await {
+        let (request, mut respond) = accepted.map_err(into_io)?;
+        while tasks.try_join_next().is_some() {}
+        config.stats.record_request();
+        let stats = config.stats.clone();
+        let proxy = proxy.clone();
+        let upstream = Arc::clone(&upstream);
+        tasks.spawn(async move {
+            let sender = match proxy.sender(&upstream).await {
+                Ok(sender) => sender,
+                Err(error) => {
+                    log::error(format_args!(
+                        "connecting to the gRPC backend {} failed: {}",
+                        proxy.upstream, error
+                    ));
+                    stats.record_response(StatusCode::OK);
+                    let _ = respond.send_response(unavailable(), true);
+                    return;
+                }
+            };
+            let path = request.uri().path().to_string();
+            match relay(sender, request, respond, &proxy.upstream).await {
+                Ok(status) => {
+                    stats.record_response(status);
+                    log::debug(format_args!("relayed {} with {}", path, status.as_u16()));
+                }
+                Err(error) => {
+                    stats.record_response(StatusCode::BAD_GATEWAY);
+                    log::debug(format_args!("relaying {} failed: {}", path, error));
+                }
+            }
+        });
+    }
+    // Streams still open when the client stops opening new ones keep the connection alive
+    poll_fn(|context| connection.poll_closed(context))
+        .await
+        .map_err(into_io)
+}
+
+/// Relays one stream to the backend and its response back.
+///
+/// # Returns
+///
+/// The status of the response, which is 200 OK for every gRPC call whatever its outcome.
+///
+/// # Errors
+///
+/// Captures the errors of either side, after resetting the stream on the other one.
+async fn relay(
+    sender: client::SendRequest<Bytes>,
+    request: http::Request<RecvStream>,
+    mut respond: server::SendResponse<Bytes>,
+    upstream: &str,
+) -> Result<StatusCode, h2::Error> {
+    let (mut parts, mut body) = request.into_parts();
+    let authority = match parts.uri.authority() {
+        Some(authority) => authority.clone(),
+        None => upstream
+            .parse::<Authority>()
+            .map_err(|_| Reason::INTERNAL_ERROR)?,
+    };
+    let mut uri = Uri::builder().scheme(Scheme::HTTP).authority(authority);
+    if let Some(path) = parts.uri.path_and_query() {
+        uri = uri.path_and_query(path.clone());
+    }
+    parts.uri = uri.build().map_err(|_| Reason::PROTOCOL_ERROR)?;
+    let end_of_stream = body.is_end_stream();
+    let mut sender = match sender.ready().await {
+        Ok(sender) => sender,
+        Err(error) => {
+            let _ = respond.send_response(unavailable(), true);
+            return Err(error);
+        }
+    };
+    let (response, mut upstream_body) =
+        match sender.send_request(http::Request::from_parts(parts, ()), end_of_stream) {
+            Ok(sent) => sent,
+            Err(error) => {
+                let _ = respond.send_response(unavailable(), true);
+                return Err(error);
+            }
+        };
+    let request_body = async {
+        if !end_of_stream {
+            copy(&mut body, &mut upstream_body).await?;
+        }
+        Ok::<_, h2::Error>(())
+    };
+    let response_body = async {
+        let response = match response.await {
+            Ok(response) => response,
+            Err(error) => {
+                let _ = respond.send_response(unavailable(), true);
+                return Err(error);
+            }
+        };
+        let (parts, mut body) = response.into_parts();
+        let status = StatusCode::from_u16(parts.status.as_u16()).unwrap_or(StatusCode::BAD_GATEWAY);
+        let end_of_stream = body.is_end_stream();
+        let mut downstream =
+            respond.send_response(http::Response::from_parts(parts, ()), end_of_stream)?;
+        if !end_of_stream {
+            copy(&mut body, &mut downstream).await?;
+        }
+        Ok(status)
+    };
+    // The backend may answer before the request ends, e.g. with an error, which ends the call
+    let (request_body, response_body) = tokio::join!(request_body, response_body);
+    let status = response_body?;
+    if let Err(error) = request_body {
+        log::debug(format_args!(
+            "relaying the request body ended early: {}",
+            error
+        ));
+    }
+    Ok(status)
+}
+
+/// Copies the data and trailers of one side of a stream to the other.
+///
+/// The capacity of the receiving window is released only once the data was handed to the
+/// sending side, which only accepts as much as its own window allows, so that flow control
+/// reaches from one peer to the other.
+///
+/// # Errors
+///
+/// Captures the errors of either side and resets `to` with the reason.
+async fn copy(from: &mut RecvStream, to: &mut SendStream<Bytes>) -> Result<(), h2::Error> {
+    let copied = async {
+        while let Some(data) = from.data().await {
+            let mut data = data?;
+            while !data.is_empty() {
+                to.reserve_capacity(data.len());
+                let capacity = poll_fn(|context| to.poll_capacity(context))
+                    .await
+                    .ok_or(Reason::CANCEL)??;
+                let chunk = data.split_to(capacity.min(data.len()));
+                from.flow_control().release_capacity(chunk.len())?;
+                to.send_data(chunk, false)?;
+            }
+        }
+        match from.trailers().await? {
+            Some(trailers) => to.send_trailers(trailers),
+            None => to.send_data(Bytes::new(), true),
+        }
+    };
+    copied.await.inspect_err(|error| {
+        to.send_reset(error.reason().unwrap_or(Reason::CANCEL));
+    })
+}
+
+/// A trailers-only gRPC response failing the call with `UNAVAILABLE`.
+fn unavailable() -> http::Response<()> {
+    let mut response = http::Response::new(());
+    let headers = response.headers_mut();
+    headers.insert(
+        http::header::CONTENT_TYPE,
+        http::HeaderValue::from_static("application/grpc"),
+    );
+    headers.insert("grpc-status", http::HeaderValue::from_static(UNAVAILABLE));
+    headers.insert(
+        "grpc-message",
+        http::HeaderValue::from_static("the backend is unavailable"),
+    );
+    response
+}
+
+fn into_io(error: h2::Error) -> io::Error {
+    let message = error.to_string();
+    error
+        .into_io()
+        .unwrap_or_else(|| io::Error::new(io::ErrorKind::InvalidData, message))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::Server;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tonic::transport::Channel;
+    use tonic_health::pb::health_check_response::ServingStatus as Reported;
+    use tonic_health::pb::health_client::HealthClient;
+    use tonic_health::pb::HealthCheckRequest;
+    use tonic_health::ServingStatus;
+
+    /// Serves a config on a local port.
+    async fn serve(config: Config) -> (Server, std::net::SocketAddr) {
+        let server = Server::new(config);
+        let listener = net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        tokio::spawn({
+            let server = server.clone();
+            async move { server.serve(listener).await }
+        });
+        (server, address)
+    }
+
+    async fn connect(address: std::net::SocketAddr) -> Channel {
+        Channel::from_shared(format!("http://{}", address))
+            .unwrap()
+            .connect()
+            .await
+            .unwrap()
+    }
+
+    fn proxy(upstream: std::net::SocketAddr) -> Config {
+        Config {
+            grpc: Some(GrpcProxy::new(upstream.to_string())),
+            ..Config::default()
+        }
+    }
+
+    /// It relays unary and streaming calls to a tonic service with their statuses, keeps
+    /// serving HTTP/1.1 on the same port, and fails calls with UNAVAILABLE without a backend
+    #[tokio::test]
+    async fn tonic() {
+        let (reporter, service) = tonic_health::server::health_reporter();
+        reporter
+            .set_service_status("web_server", ServingStatus::Serving)
+            .await;
+        let backend = net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let backend_address = backend.local_addr().unwrap();
+        tokio::spawn(
+            tonic::transport::Server::builder()
+                .add_service(service)
+                .serve_with_incoming(tonic::transport::server::TcpIncoming::from(backend)),
+        );
+        let (server, address) = serve(proxy(backend_address)).await;
+        let check = |service: &str| HealthCheckRequest {
+            service: service.to_string(),
+        };
+
+        let mut client = HealthClient::new(connect(address).await);
+        let response = client.check(check("web_server")).await.unwrap();
+        assert_eq!(Reported::Serving as i32, response.into_inner().status);
+        let status = client.check(check("missing")).await.unwrap_err();
+        assert_eq!(tonic::Code::NotFound, status.code());
+        let mut updates = client
+            .watch(check("web_server"))
+            .await
+            .unwrap()
+            .into_inner();
+        let update = updates.message().await.unwrap().unwrap();
+        assert_eq!(Reported::Serving as i32, update.status);
+        reporter
+            .set_service_status("web_server", ServingStatus::NotServing)
+            .await;
+        let update = updates.message().await.unwrap().unwrap();
+        assert_eq!(Reported::NotServing as i32, update.status);
+
+        let mut http1 = net::TcpStream::connect(address).await.unwrap();
+        http1
+            .write_all(b"GET /missing HTTP/1.1\r\nConnection: close\r\n\r\n")
+            .await
+            .unwrap();
+        let mut response = String::new();
+        http1.read_to_string(&mut response).await.unwrap();
+        assert!(response.starts_with("HTTP/1.1 404 Not Found"));
+
+        let closed = net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let closed_address = closed.local_addr().unwrap();
+        drop(closed);
+        server.replace(proxy(closed_address));
+        let mut client = HealthClient::new(connect(address).await);
+        let status = client.check(check("web_server")).await.unwrap_err();
+        assert_eq!(tonic::Code::Unavailable, status.code());
+    }
+
+    /// It recognizes a preface arriving in several segments without consuming it, and tells
+    /// other starts apart as soon as they differ
+    #[tokio::test]
+    async fn preface() {
+        let pool = crate::connection::BufferPool::default();
+        let (mut client, server) = io::duplex(64);
+        let mut connection = Connection::new(server, &pool);
+        client.write_all(&PREFACE[..10]).await.unwrap();
+        let checking = tokio::spawn(async move {
+            let preface = starts_with_preface(&mut connection).await.unwrap();
+            (preface, connection.buffered().len())
+        });
+        time::sleep(Duration::from_millis(10)).await;
+        client.write_all(&PREFACE[10..]).await.unwrap();
+        assert_eq!((true, PREFACE.len()), checking.await.unwrap());
+
+        let (mut client, server) = io::duplex(64);
+        let mut connection = Connection::new(server, &pool);
+        client.write_all(b"PRI / HTTP/1.1\r\n").await.unwrap();
+        assert!(!starts_with_preface(&mut connection).await.unwrap());
+    }
+
+    /// It relays bodies larger than the flow control windows in both directions, with trailers
+    #[tokio::test]
+    async fn flow_control() {
+        let backend = net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let backend_address = backend.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (stream, _) = backend.accept().await.unwrap();
+            let mut connection = server::handshake(stream).await.unwrap();
+            while let Some(accepted) = connection.accept().await {
+                let (request, mut respond) = accepted.unwrap();
+                tokio::spawn(async move {
+                    let mut body = request.into_body();
+                    let mut response = respond
+                        .send_response(http::Response::new(()), false)
+                        .unwrap();
+                    copy(&mut body, &mut response).await.unwrap();
+                });
+            }
+        });
+        let (_server, address) = serve(proxy(backend_address)).await;
+
+        let stream = net::TcpStream::connect(address).await.unwrap();
+        let (sender, connection) = client::handshake(stream).await.unwrap();
+        tokio::spawn(connection);
+        let mut sender = sender.ready().await.unwrap();
+        let request = http::Request::post(format!("http://{}/echo", address))
+            .body(())
+            .unwrap();
+        let (response, mut request_body) = sender.send_request(request, false).unwrap();
+        let sent: Bytes = (0..1 << 20).map(|index| index as u8).collect();
+        let sending = tokio::spawn({
+            let sent = sent.clone();
+            async move {
+                let mut trailers = http::HeaderMap::new();
+                trailers.insert("grpc-status", http::HeaderValue::from_static("0"));
+                for chunk in sent.chunks(16 * 1024) {
+                    request_body.reserve_capacity(chunk.len());
+                    poll_fn(|context| request_body.poll_capacity(context))
+                        .await
+                        .unwrap()
+                        .unwrap();
+                    request_body
+                        .send_data(Bytes::copy_from_slice(chunk), false)
+                        .unwrap();
+                }
+                request_body.send_trailers(trailers).unwrap();
+            }
+        });
+
+        let mut body = response.await.unwrap().into_body();
+        let mut received = Vec::new();
+        while let Some(data) = body.data().await {
+            let data = data.unwrap();
+            body.flow_control().release_capacity(data.len()).unwrap();
+            received.extend_from_slice(&data);
+        }
+        sending.await.unwrap();
+        assert_eq!(sent, received);
+        let trailers = body.trailers().await.unwrap().unwrap();
+        assert_eq!("0", trailers["grpc-status"]);
+    }
+}
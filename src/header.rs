@@ -0,0 +1,277 @@
+use smallvec::SmallVec;
+use std::slice;
+
+/// The number of headers stored without a heap allocation for the list itself.
+const INLINE_HEADERS: usize = 16;
+
+/// Header fields in the order they were received or added, looked up ignoring ASCII case.
+///
+/// A name may occur several times, e.g. `Set-Cookie` or `Vary`; [`HeaderMap::get`] returns the
+/// first value and [`HeaderMap::get_all`] every value.
+///
+/// Up to 16 fields are kept inline, which covers typical requests; more spill to the heap.
+/// Lookups scan linearly, which beats hashing at these sizes, see `benches/header_map.rs`.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct HeaderMap {
+    entries: SmallVec<[(String, String); INLINE_HEADERS]>,
+}
+
+impl HeaderMap {
+    /// Creates an empty map.
+    pub fn new() -> HeaderMap {
+        HeaderMap::default()
+    }
+
+    /// Finds the first value of a header.
+    ///
+    /// # Arguments
+    ///
+    /// * `name`: The header name, compared ignoring ASCII case.
+    ///
+    /// # Returns
+    ///
+    /// The first matching value, if any.
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.entries
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// Finds every value of a header.
+    ///
+    /// # Arguments
+    ///
+    /// * `name`: The header name, compared ignoring ASCII case.
+    ///
+    /// # Returns
+    ///
+    /// The matching values in insertion order.
+    pub fn get_all<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a str> + 'a {
+        self.entries
+            .iter()
+            .filter(move |(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// Checks whether a header is present.
+    ///
+    /// # Arguments
+    ///
+    /// * `name`: The header name, compared ignoring ASCII case.
+    ///
+    /// # Returns
+    ///
+    /// `true` when at least one value is present.
+    pub fn contains(&self, name: &str) -> bool {
+        self.get(name).is_some()
+    }
+
+    /// Adds a value after all existing ones, keeping earlier values of the same name.
+    ///
+    /// # Arguments
+    ///
+    /// * `name`: The header name.
+    /// * `value`: The header value.
+    pub fn append(&mut self, name: impl Into<String>, value: impl Into<String>) {
+        self.entries.push((name.into(), value.into()));
+    }
+
+    /// Replaces every value of a header with a single one, at the position of the first value
+    /// or at the end when the header is absent.
+    ///
+    /// # Arguments
+    ///
+    /// * `name`: The header name.
+    /// * `value`: The header value.
+    pub fn insert(&mut self, name: impl Into<String>, value: impl Into<String>) {
+        let name = name.into();
+        let mut value = Some(value.into());
+        self.entries.retain_mut(|(key, existing)| {
+            if !key.eq_ignore_ascii_case(&name) {
+                return true;
+            }
+            match value.take() {
+                Some(value) => {
+                    *existing = value;
+                    true
+                }
+                None => false,
+            }
+        });
+        if let Some(value) = value {
+            self.entries.push((name, value));
+        }
+    }
+
+    /// Removes every value of a header.
+    ///
+    /// # Arguments
+    ///
+    /// * `name`: The header name, compared ignoring ASCII case.
+    ///
+    /// # Returns
+    ///
+    /// `true` when at least one value was removed.
+    pub fn remove(&mut self, name: &str) -> bool {
+        let before = self.entries.len();
+        self.entries
+            .retain(|(key, _)| !key.eq_ignore_ascii_case(name));
+        self.entries.len() != before
+    }
+
+    /// Removes every header.
+    pub fn clear(&mut self) {
+        self.entries.clear();
+    }
+
+    /// The number of name and value pairs.
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Checks whether the map holds no headers.
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Iterates over name and value pairs in insertion order.
+    pub fn iter(&self) -> Iter<'_> {
+        Iter {
+            entries: self.entries.iter(),
+        }
+    }
+
+    /// The value most recently added, used to continue a folded header line.
+    pub(crate) fn last_value_mut(&mut self) -> Option<&mut String> {
+        self.entries.last_mut().map(|(_, value)| value)
+    }
+
+    /// Checks whether a comma-separated header such as `Connection` lists a token.
+    ///
+    /// # Arguments
+    ///
+    /// * `name`: The header name, compared ignoring ASCII case.
+    /// * `token`: The token to look for, e.g. `close`, compared ignoring ASCII case.
+    ///
+    /// # Returns
+    ///
+    /// `true` when any value of the header lists the token.
+    pub fn has_token(&self, name: &str, token: &str) -> bool {
+        self.get_all(name)
+            .flat_map(|value| value.split(','))
+            .any(|item| item.trim().eq_ignore_ascii_case(token))
+    }
+
+    /// The value of `Content-Length`.
+    ///
+    /// # Returns
+    ///
+    /// The length in bytes, or `None` when absent or not a number.
+    pub fn content_length(&self) -> Option<u64> {
+        self.get("Content-Length")?.parse().ok()
+    }
+
+    /// The media type of `Content-Type` without parameters, e.g. `text/html` for
+    /// `text/html; charset=utf-8`.
+    pub fn content_type(&self) -> Option<&str> {
+        self.get("Content-Type")
+            .map(|value| value.split(';').next().unwrap_or_default().trim())
+    }
+
+    /// The value of `Host`.
+    pub fn host(&self) -> Option<&str> {
+        self.get("Host")
+    }
+}
+
+/// An iterator over the name and value pairs of a [`HeaderMap`].
+pub struct Iter<'a> {
+    entries: slice::Iter<'a, (String, String)>,
+}
+
+impl<'a> Iterator for Iter<'a> {
+    type Item = (&'a str, &'a str);
+
+    fn next(&mut self) -> Option<(&'a str, &'a str)> {
+        self.entries
+            .next()
+            .map(|(name, value)| (name.as_str(), value.as_str()))
+    }
+}
+
+impl<'a> IntoIterator for &'a HeaderMap {
+    type Item = (&'a str, &'a str);
+    type IntoIter = Iter<'a>;
+
+    fn into_iter(self) -> Iter<'a> {
+        self.iter()
+    }
+}
+
+impl<N: Into<String>, V: Into<String>> FromIterator<(N, V)> for HeaderMap {
+    fn from_iter<I: IntoIterator<Item = (N, V)>>(iter: I) -> HeaderMap {
+        HeaderMap {
+            entries: iter
+                .into_iter()
+                .map(|(name, value)| (name.into(), value.into()))
+                .collect(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// It looks up single and repeated headers ignoring case and keeps insertion order
+    #[test]
+    fn lookup() {
+        let mut headers: HeaderMap = [("Vary", "Accept"), ("Host", "a"), ("vary", "Origin")]
+            .into_iter()
+            .collect();
+        assert_eq!(Some("Accept"), headers.get("VARY"));
+        assert_eq!(
+            vec!["Accept", "Origin"],
+            headers.get_all("Vary").collect::<Vec<_>>()
+        );
+        headers.insert("VARY", "*");
+        headers.append("Set-Cookie", "a=1");
+        assert_eq!(
+            vec![("Vary", "*"), ("Host", "a"), ("Set-Cookie", "a=1")],
+            headers.iter().collect::<Vec<_>>()
+        );
+        assert!(headers.remove("host"));
+        assert!(!headers.contains("Host"));
+        assert_eq!(2, headers.len());
+    }
+
+    /// It reads common headers through typed accessors
+    #[test]
+    fn typed() {
+        let headers: HeaderMap = [
+            ("Content-Length", "12"),
+            ("Content-Type", "text/html; charset=utf-8"),
+            ("Connection", "keep-alive, Upgrade"),
+        ]
+        .into_iter()
+        .collect();
+        assert_eq!(Some(12), headers.content_length());
+        assert_eq!(Some("text/html"), headers.content_type());
+        assert!(headers.has_token("connection", "upgrade"));
+        assert!(!headers.has_token("Connection", "close"));
+        assert_eq!(None, headers.host());
+    }
+
+    /// It keeps typical header counts inline and spills to the heap beyond them
+    #[test]
+    fn inline() {
+        let mut headers: HeaderMap = (0..INLINE_HEADERS)
+            .map(|index| (format!("X-{}", index), "a"))
+            .collect();
+        assert!(!headers.entries.spilled());
+        headers.append("X-Extra", "b");
+        assert!(headers.entries.spilled());
+        assert_eq!(Some("b"), headers.get("x-extra"));
+    }
+}
@@ -1,17 +1,117 @@
+#[cfg(feature = "acme")]
+pub mod acme;
+pub mod admin;
+#[cfg(feature = "auth")]
+pub mod auth;
+pub mod body;
+pub mod cache;
+pub mod canary;
+pub mod capture;
+pub mod cgi;
+pub mod check;
+pub mod concurrency;
+pub mod config;
+pub mod connection;
+#[cfg(feature = "http")]
+pub mod convert;
+#[cfg(feature = "cookie")]
+pub mod cookie;
+#[cfg(unix)]
+pub mod daemon;
+pub mod date;
+pub mod dotenv;
+pub mod error;
+pub mod extensions;
+pub mod extract;
+pub mod fastcgi;
+pub mod flash;
+#[cfg(feature = "grpc")]
+pub mod grpc;
+pub mod header;
+pub mod log;
+pub mod maintenance;
+pub mod markdown;
+pub mod method;
+pub mod metrics;
+pub mod negotiate;
+pub mod path;
+#[cfg(unix)]
+pub mod privileges;
+#[cfg(all(feature = "profiling", unix))]
+pub mod profile;
+pub mod proxy;
+pub mod rate_limit;
+pub mod redirect;
+pub mod request;
+pub mod response;
+pub mod rewrite;
+pub mod router;
+pub mod sandbox;
+#[cfg(feature = "scripting")]
+pub mod script;
+pub mod server;
+#[cfg(feature = "tower")]
+pub mod service;
+pub mod session;
+pub mod shedding;
+pub mod split;
+pub mod status;
+#[cfg(feature = "tls")]
+pub mod tls;
+pub mod trace;
+pub mod tus;
+#[cfg(unix)]
+pub mod upgrade;
+pub mod upload;
+pub mod uri;
+#[cfg(all(feature = "io-uring", target_os = "linux"))]
+mod uring;
+pub mod version;
+#[cfg(feature = "wasm")]
+pub mod wasm;
+pub mod webdav;
+
 use async_trait::async_trait;
-use tokio::io::{AsyncBufReadExt, AsyncWriteExt};
-use tokio::{fs, io, net, time};
+use bytes::{Bytes, BytesMut};
+use capture::Captured;
+use config::Config;
+use log::format::AccessEntry;
+use log::{Level, Span};
+use method::Method;
+use metrics::Exchange;
+use request::{ClientCertificate, Request};
+use response::Response;
+use router::RouteLimits;
+use status::StatusCode;
+use std::future::Future;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::{io, net, time};
+use trace::TraceContext;
 
-/// Enables [`handle_stream`] to work with [`net::TcpStream`] for release
+/// Enables [`handle_stream`] to work with a buffered [`net::TcpStream`] for release
 /// and mock struct implementations for testing.
 #[async_trait]
 pub trait StreamAdapter: Send {
-    /// Reads the first line of the request.
+    /// Reads the request line and headers of the request.
+    ///
+    /// # Returns
+    ///
+    /// The parsed head of the request.
+    async fn read_request(&mut self) -> io::Result<Request>;
+
+    /// Reads raw bytes following the request head.
+    ///
+    /// # Arguments
+    ///
+    /// * `buf`: The buffer to fill.
     ///
     /// # Returns
     ///
-    /// A string of the first line of the request.
-    async fn read_request(&mut self) -> io::Result<String>;
+    /// The number of bytes read, or zero at the end of the stream.
+    async fn read_body(&mut self, buf: &mut [u8]) -> io::Result<usize>;
 
     /// Writes the response to the client.
     ///
@@ -23,22 +123,60 @@ pub trait StreamAdapter: Send {
     ///
     /// The result of the write_all function.
     async fn write_response(&mut self, response: &[u8]) -> io::Result<()>;
+
+    /// The address of the client.
+    ///
+    /// # Returns
+    ///
+    /// The remote address, or `None` when the stream does not know it.
+    fn peer_addr(&self) -> Option<SocketAddr> {
+        None
+    }
+
+    /// The verified certificate of the client.
+    ///
+    /// # Returns
+    ///
+    /// The identity, or `None` when the stream is not mutual TLS.
+    fn client_certificate(&self) -> Option<ClientCertificate> {
+        None
+    }
+
+    /// Ends the connection after the last response, e.g. with a TLS `close_notify` alert.
+    ///
+    /// # Returns
+    ///
+    /// `Ok(())` once the stream is shut down for writing.
+    async fn close(&mut self) -> io::Result<()> {
+        Ok(())
+    }
 }
 
-/// Implementing the [`StreamAdapter`] trait for the [`net::TcpStream`] struct.
+/// Implementing the [`StreamAdapter`] trait for a [`net::TcpStream`] wrapped in a
+/// [`io::BufReader`]. The reader lives as long as the connection, so bytes buffered past the
+/// request head remain available to [`StreamAdapter::read_body`].
 #[async_trait]
-impl StreamAdapter for net::TcpStream {
-    /// Reads the first line of the request.
+impl StreamAdapter for io::BufReader<net::TcpStream> {
+    /// Reads the request line and headers of the request.
     ///
     /// # Returns
     ///
-    /// A string of the first line of the request.
-    async fn read_request(&mut self) -> io::Result<String> {
-        Ok(io::BufReader::new(&mut self)
-            .lines()
-            .next_line()
-            .await?
-            .unwrap_or_default())
+    /// The parsed head of the request.
+    async fn read_request(&mut self) -> io::Result<Request> {
+        Request::read_from(self).await
+    }
+
+    /// Reads raw bytes following the request head.
+    ///
+    /// # Arguments
+    ///
+    /// * `buf`: The buffer to fill.
+    ///
+    /// # Returns
+    ///
+    /// The number of bytes read, or zero at the end of the stream.
+    async fn read_body(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.read(buf).await
     }
 
     /// Writes the response to the client.
@@ -53,15 +191,27 @@ impl StreamAdapter for net::TcpStream {
     async fn write_response(&mut self, response: &[u8]) -> io::Result<()> {
         Ok(self.write_all(response).await?)
     }
+
+    /// The remote address of the TCP connection.
+    fn peer_addr(&self) -> Option<SocketAddr> {
+        self.get_ref().peer_addr().ok()
+    }
 }
 
-/// It reads a request from the stream, then it either returns a 200 OK response with the contents of
-/// `hello.html` or a 404 NOT FOUND response with the contents of `404.html`.
+/// It reads requests from the stream until the client closes it, and answers each by either
+/// a WebDAV method against the document root, streaming an upload to disk, serving the tus
+/// resumable upload protocol, or rendering a Markdown file (when enabled in `config`), with a 200
+/// OK response with the contents of `hello.html`, or a 404 Not Found response with the contents
+/// of `404.html`. A connection is closed after the current response instead of being reused when
+/// the client asks for it, or when the end of the request body is not known for certain, so that
+/// leftover body bytes are never parsed as another request. A connection that does not send the
+/// head of its next request within [`Config::header_timeout`] is closed.
 /// Coupled to [`StreamAdapter`] to enable test doubles.
 ///
 /// # Arguments
 ///
 /// * `stream`: An incoming stream.
+/// * `config`: The settings shared by all connections.
 ///
 /// # Returns
 ///
@@ -72,25 +222,639 @@ impl StreamAdapter for net::TcpStream {
 /// Captures IO errors from any of the following:
 /// * Reading request line from stream
 /// * Reading contents for response from a file
+/// * Streaming an upload body to a file
 /// * Writing response to stream
-pub async fn handle_stream(mut stream: Box<dyn StreamAdapter>) -> io::Result<()> {
-    let request = stream.read_request().await?;
-    let (status_line, file_name) = match request.as_str() {
-        "GET / HTTP/1.1" => ("HTTP/1.1 200 OK", "hello.html"),
-        "GET /sleep HTTP/1.1" => {
-            time::sleep(time::Duration::from_secs(5)).await;
-            ("HTTP/1.1 200 OK", "hello.html")
+pub async fn handle_stream(mut stream: Box<dyn StreamAdapter>, config: &Config) -> io::Result<()> {
+    let mut reused = false;
+    let mut buffer = BytesMut::new();
+    loop {
+        let mut request = match config.header_timeout {
+            Some(timeout) => match time::timeout(timeout, stream.read_request()).await {
+                Ok(request) => request?,
+                Err(_) => {
+                    config.stats.record_timeouts(1);
+                    return stream.close().await;
+                }
+            },
+            None => stream.read_request().await?,
+        };
+        request.peer = stream.peer_addr();
+        request.client_certificate = stream.client_certificate();
+        let mut rewritten_from = None;
+        match rewrite::rewrite(&config.rewrites, &request.target) {
+            Ok(Some(target)) => {
+                rewritten_from = Some(std::mem::replace(&mut request.target, target));
+            }
+            Ok(None) => {}
+            Err(error) => log::error(format_args!(
+                "rewriting {} failed, handling it unchanged: {}",
+                request.target, error
+            )),
+        }
+        let mut trace = TraceContext::from_headers(&request.headers);
+        trace.sampled = config.trace_sampling.sample(request.path(), &trace);
+        let sampled = trace.sampled;
+        if request.method == Method::default() && request.violation.is_none() {
+            // The client closed the connection before sending another request
+            return Ok(());
+        }
+        config.stats.record_request();
+        if reused {
+            config.stats.record_reuse();
+        }
+        let (start, started) = (SystemTime::now(), Instant::now());
+        let mut counting = CountingStream {
+            stream: stream.as_mut(),
+            body_read: 0,
+            captured: Vec::new(),
+            capture_limit: 0,
+        };
+        let span = Arc::new(
+            Span::new("request")
+                .with_field("method", &request.method)
+                .with_field("path", request.path())
+                .with_field("trace_id", trace.trace_id())
+                .with_field("span_id", trace.span_id()),
+        );
+        if let Some(original) = rewritten_from {
+            span.record("rewritten_from", original);
+        }
+        request.trace = Some(trace);
+        let capture = config
+            .capture
+            .as_ref()
+            .filter(|capture| capture.matches(&request));
+        if let Some(capture) = capture {
+            counting.capture_limit = capture.max_body_size;
+        }
+        #[cfg(feature = "scripting")]
+        let answered = config
+            .scripts
+            .as_ref()
+            .filter(|_| request.head_error().is_none())
+            .and_then(|scripts| scripts.on_request(&mut request));
+        #[cfg(not(feature = "scripting"))]
+        let answered = None;
+        #[cfg(feature = "wasm")]
+        let (plugins, answered) = match answered {
+            None if request.head_error().is_none() => {
+                wasm::on_request(&config.plugins, &mut request)
+            }
+            answered => (Vec::new(), answered),
+        };
+        let mut response = match answered {
+            Some(response) => response,
+            None => {
+                span.clone()
+                    .run(watch_slow(
+                        respond(&request, &mut counting, config),
+                        config.slow_request,
+                    ))
+                    .await?
+            }
+        };
+        #[cfg(feature = "wasm")]
+        {
+            response = wasm::on_response(plugins, response);
+        }
+        #[cfg(feature = "scripting")]
+        if let Some(scripts) = &config.scripts {
+            response = scripts.on_response(&request, response);
+        }
+        let body_consumed = counting.body_read;
+        let captured_body = Bytes::from(counting.captured);
+        let parsed = request.head_error().is_none();
+        let persistent = request.version.is_persistent_by_default();
+        let keep_alive = if persistent {
+            !request.headers.has_token("Connection", "close")
+        } else {
+            request.headers.has_token("Connection", "keep-alive")
+        };
+        let closes = response.headers.has_token("Connection", "close");
+        let reusable = parsed
+            && keep_alive
+            && !closes
+            && body_framed(&request)
+            && body_consumed == request.content_length();
+        if reusable && !persistent {
+            response = response.with_header("Connection", "keep-alive");
+        } else if !reusable && !closes && persistent && parsed {
+            response = response.with_header("Connection", "close");
+        }
+        config.stats.record_response(response.status);
+        if let Some(capture) = capture {
+            config.captures.push(
+                Captured::new(
+                    start,
+                    &request,
+                    captured_body,
+                    body_consumed,
+                    &response,
+                    capture.max_body_size,
+                ),
+                capture.capacity,
+            );
+        }
+        buffer.clear();
+        response.write_to(&mut buffer, request.method == Method::Head);
+        stream.write_response(&buffer).await?;
+        let duration = started.elapsed();
+        config.stats.record_route(&Exchange {
+            route: metrics::route(&request, config),
+            method: &request.method,
+            status: response.status,
+            bytes_in: body_consumed,
+            bytes_out: response.body.len() as u64,
+            duration,
+        });
+        span.record("status", response.status.as_u16());
+        span.record("latency", format_args!("{:?}", duration));
+        if config
+            .slow_request
+            .is_some_and(|threshold| duration > threshold)
+        {
+            span.clone().in_scope(|| {
+                log::always(
+                    Level::Warn,
+                    format_args!(
+                        "slow request: route={} query_bytes={} headers={} bytes_in={} bytes_out={}",
+                        metrics::route(&request, config),
+                        request.target.query().map_or(0, str::len),
+                        request.headers.len(),
+                        body_consumed,
+                        response.body.len()
+                    ),
+                )
+            });
+        }
+        if sampled {
+            span.in_scope(|| log::debug("request finished"));
+        }
+        if log::access_enabled() {
+            log::access(config.access_log_format.format(&AccessEntry {
+                request: &request,
+                response: &response,
+                start,
+                duration,
+            }));
+        }
+        if !reusable {
+            return stream.close().await;
+        }
+        reused = true;
+    }
+}
+
+/// Runs the handler of a request, warning once when it is still running after the threshold of
+/// [`Config::slow_request`] so that requests which never finish are noticed too.
+///
+/// # Arguments
+///
+/// * `handler`: The future answering the request, inside its span.
+/// * `threshold`: How long the request may take, or `None` to not watch it.
+///
+/// # Returns
+///
+/// The output of the handler.
+async fn watch_slow<F: Future>(handler: F, threshold: Option<Duration>) -> F::Output {
+    let Some(threshold) = threshold else {
+        return handler.await;
+    };
+    tokio::pin!(handler);
+    tokio::select! {
+        output = &mut handler => return output,
+        () = time::sleep(threshold) => {}
+    }
+    log::always(
+        Level::Warn,
+        format_args!("slow request still running after {:?}", threshold),
+    );
+    handler.await
+}
+
+/// Checks whether the end of a request body is known without any doubt: no irregular framing,
+/// no `Transfer-Encoding`, and either no or a valid `Content-Length`.
+///
+/// # Arguments
+///
+/// * `request`: The request whose body follows on the stream.
+///
+/// # Returns
+///
+/// `true` when [`Request::content_length`] is exactly the number of body bytes.
+fn body_framed(request: &Request) -> bool {
+    request.violation.is_none()
+        && !request.headers.contains("Transfer-Encoding")
+        && (!request.headers.contains("Content-Length")
+            || request.headers.content_length().is_some())
+}
+
+/// Counts the body bytes a handler reads, so [`handle_stream`] knows whether the next request
+/// starts right after them, and keeps the first of them for a [`Captured`] exchange.
+struct CountingStream<'a> {
+    stream: &'a mut dyn StreamAdapter,
+    body_read: u64,
+    captured: Vec<u8>,
+    capture_limit: usize,
+}
+
+/// Implementing the [`StreamAdapter`] trait for the [`CountingStream`] struct by delegating to the
+/// wrapped stream.
+#[async_trait]
+impl StreamAdapter for CountingStream<'_> {
+    async fn read_request(&mut self) -> io::Result<Request> {
+        self.stream.read_request().await
+    }
+
+    async fn read_body(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let count = self.stream.read_body(buf).await?;
+        self.body_read += count as u64;
+        let kept = count.min(self.capture_limit.saturating_sub(self.captured.len()));
+        self.captured.extend_from_slice(&buf[..kept]);
+        Ok(count)
+    }
+
+    async fn write_response(&mut self, response: &[u8]) -> io::Result<()> {
+        self.stream.write_response(response).await
+    }
+}
+
+/// Picks the handler enabled in `config` that accepts the request, defaulting to
+/// [`serve_page`].
+///
+/// # Arguments
+///
+/// * `request`: The incoming request.
+/// * `stream`: The stream positioned at the start of the request body.
+/// * `config`: The settings shared by all connections.
+///
+/// # Returns
+///
+/// The response produced by the chosen handler.
+///
+/// # Errors
+///
+/// Captures IO errors from the chosen handler.
+async fn respond(
+    request: &Request,
+    stream: &mut dyn StreamAdapter,
+    config: &Config,
+) -> io::Result<Response> {
+    if let Some(status) = request.head_error() {
+        // Neither the end of the head nor the body is known, so no request may follow
+        return Ok(Response::new(status, "").with_header("Connection", "close"));
+    }
+    if config.strict && request.violation.is_some() {
+        // The body cannot be skipped reliably, so the connection must not carry another request
+        return Ok(Response::new(StatusCode::BAD_REQUEST, "").with_header("Connection", "close"));
+    }
+    if !request.version.is_http1() {
+        // HTTP/2 and later need their own framing, which is only negotiated during the handshake
+        return Ok(Response::new(StatusCode::HTTP_VERSION_NOT_SUPPORTED, "")
+            .with_header("Connection", "close"));
+    }
+    if let Some(status) = config.methods.refusal(&request.method) {
+        let mut response = Response::new(status, "");
+        if status == StatusCode::METHOD_NOT_ALLOWED {
+            response = response.with_header("Allow", allow(&allowed_methods(request, config)));
         }
-        _ => ("HTTP/1.1 404 NOT FOUND", "404.html"),
+        return Ok(response);
+    }
+    #[cfg(feature = "acme")]
+    if let Some(response) = config.acme.as_ref().and_then(|acme| acme.respond(request)) {
+        return Ok(response);
+    }
+    if config.maintenance_switch.is_active(&config.maintenance) {
+        if let Some(response) = config.maintenance.respond(request) {
+            return Ok(response);
+        }
+    }
+    if let Some(response) = config
+        .redirects
+        .iter()
+        .find_map(|redirect| redirect.respond(request))
+    {
+        return Ok(response);
+    }
+    if let Some(response) = config
+        .load_shedding
+        .as_ref()
+        .and_then(|shedder| shedder.check(request))
+    {
+        return Ok(response);
+    }
+    // Held until the response is ready, whichever handler produces it
+    let _slot = match &config.concurrency {
+        Some(limit) => match limit.acquire().await {
+            Some(slot) => Some(slot),
+            None => return Ok(concurrency::ConcurrencyLimit::shed_response()),
+        },
+        None => None,
     };
-    let contents = fs::read_to_string(file_name).await?;
-    let response = format!(
-        "{}\r\nContent-Length: {}\r\n\r\n{}",
-        status_line,
-        contents.len(),
-        contents
+    if let Some(found) = config.router.lookup(&request.method, request.path()) {
+        let routed = found.routed(request);
+        let handler = found.handler.as_ref();
+        return call_with_body(handler, &routed, stream, config, found.limits).await;
+    }
+    if let Some(metrics) = &config.metrics {
+        if metrics::matches(request, metrics) {
+            return Ok(metrics::handle(config));
+        }
+    }
+    if let Some(proxy) = config.proxy.as_ref().filter(|proxy| proxy.matches(request)) {
+        return call_with_body(proxy, request, stream, config, RouteLimits::default()).await;
+    }
+    if let Some(fastcgi) = config
+        .fastcgi
+        .as_ref()
+        .filter(|fastcgi| fastcgi.matches(request))
+    {
+        return call_with_body(fastcgi, request, stream, config, RouteLimits::default()).await;
+    }
+    if let Some(cgi) = config.cgi.as_ref().filter(|cgi| cgi.matches(request)) {
+        return call_with_body(cgi, request, stream, config, RouteLimits::default()).await;
+    }
+    if let Some(webdav) = &config.webdav {
+        if webdav::is_webdav_method(&request.method) {
+            let handled = webdav::handle(request, webdav, &config.document_root);
+            return within_request_timeout(request, config, handled).await;
+        }
+    }
+    if let Some(upload) = &config.upload {
+        if upload::matches(request, upload) {
+            let handled = upload::handle(request, stream, upload);
+            return within_request_timeout(request, config, handled).await;
+        }
+    }
+    if let Some(tus) = &config.tus {
+        if tus::matches(request, tus) {
+            let handled = tus::handle(request, stream, tus);
+            return within_request_timeout(request, config, handled).await;
+        }
+    }
+    if let Some(markdown) = &config.markdown {
+        let root = &config.document_root;
+        let case_insensitive = config.case_insensitive_paths;
+        if let Some(response) = markdown::handle(request, markdown, root, case_insensitive).await? {
+            return Ok(response);
+        }
+    }
+    if request.method == Method::Options {
+        let allowed = allowed_methods(request, config);
+        if !allowed.is_empty() {
+            return Ok(
+                Response::new(StatusCode::NO_CONTENT, "").with_header("Allow", allow(&allowed))
+            );
+        }
+    }
+    serve_page(request, config).await
+}
+
+/// Reads the body into memory and passes the request to a handler.
+///
+/// # Arguments
+///
+/// * `handler`: The handler answering the request.
+/// * `request`: The incoming request.
+/// * `stream`: The stream positioned at the start of the body.
+/// * `config`: The settings holding the body size limit and the timeout.
+/// * `limits`: The limits of the route overriding those of `config`.
+///
+/// # Returns
+///
+/// The handler's response, 411 for a body without `Content-Length`, 413 above
+/// [`Config::max_body_size`], or 408 after [`Config::request_timeout`].
+///
+/// # Errors
+///
+/// Captures IO errors from reading the body and propagates those of the handler, which are
+/// logged as they point at a failure of the server rather than the client.
+async fn call_with_body(
+    handler: &dyn router::Handler,
+    request: &Request,
+    stream: &mut dyn StreamAdapter,
+    config: &Config,
+    limits: RouteLimits,
+) -> io::Result<Response> {
+    if request.headers.contains("Transfer-Encoding") {
+        let rejection = extract::Rejection::new(
+            extract::RejectionKind::LengthRequired,
+            StatusCode::LENGTH_REQUIRED,
+            "the body must have a Content-Length",
+        );
+        return Ok(extract::custom_rejection(request, &rejection)
+            .unwrap_or_else(|| Response::new(StatusCode::LENGTH_REQUIRED, "")));
+    }
+    let max_body_size = limits.max_body_size.unwrap_or(config.max_body_size);
+    if request.content_length() > max_body_size {
+        let rejection = extract::Rejection::new(
+            extract::RejectionKind::BodyTooLarge,
+            StatusCode::PAYLOAD_TOO_LARGE,
+            format!("the body is larger than {} bytes", max_body_size),
+        );
+        return Ok(extract::custom_rejection(request, &rejection)
+            .unwrap_or_else(|| Response::new(StatusCode::PAYLOAD_TOO_LARGE, "")));
+    }
+    let handled = async {
+        let mut routed = request.clone();
+        routed.body = body::BodyReader::new(stream, request.content_length())
+            .read_all()
+            .await?;
+        let called = Instant::now();
+        let response = handler.call(routed).await;
+        if let Some(shedder) = &config.load_shedding {
+            shedder.observe(called.elapsed());
+        }
+        response.inspect_err(|error| {
+            log::error(format_args!(
+                "handling {} {} failed: {}",
+                request.method, request.target, error
+            ));
+        })
+    };
+    let Some(timeout) = limits.timeout.or(config.request_timeout) else {
+        return handled.await;
+    };
+    match time::timeout(timeout, handled).await {
+        Ok(response) => response,
+        Err(_) => {
+            // The handler was cut off, so it took at least this long
+            if let Some(shedder) = &config.load_shedding {
+                shedder.observe(timeout);
+            }
+            Ok(timed_out(request, timeout))
+        }
+    }
+}
+
+/// Handles a request that streams its own body, such as an upload, within
+/// [`Config::request_timeout`].
+///
+/// # Arguments
+///
+/// * `request`: The incoming request.
+/// * `config`: The settings holding the timeout.
+/// * `handled`: Reads the body and produces the response.
+///
+/// # Returns
+///
+/// The response of `handled`, or 408 after the timeout.
+///
+/// # Errors
+///
+/// Propagates the errors of `handled`.
+async fn within_request_timeout(
+    request: &Request,
+    config: &Config,
+    handled: impl Future<Output = io::Result<Response>>,
+) -> io::Result<Response> {
+    let Some(timeout) = config.request_timeout else {
+        return handled.await;
+    };
+    match time::timeout(timeout, handled).await {
+        Ok(response) => response,
+        Err(_) => Ok(timed_out(request, timeout)),
+    }
+}
+
+/// The 408 Request Timeout response for a request cut off after `timeout`, closing the
+/// connection since the rest of the body may still be on its way.
+fn timed_out(request: &Request, timeout: Duration) -> Response {
+    let rejection = extract::Rejection::new(
+        extract::RejectionKind::Timeout,
+        StatusCode::REQUEST_TIMEOUT,
+        format!("the request took longer than {:?}", timeout),
     );
-    stream.write_response(response.as_bytes()).await
+    extract::custom_rejection(request, &rejection)
+        .unwrap_or_else(|| Response::new(StatusCode::REQUEST_TIMEOUT, ""))
+        .with_header("Connection", "close")
+}
+
+/// Collects the methods answered at the request path by the router, the fixed pages, and the
+/// upload route.
+///
+/// # Arguments
+///
+/// * `request`: The incoming request.
+/// * `config`: The settings holding the router, the enabled handlers, and the method policy.
+///
+/// # Returns
+///
+/// The allowed methods including `OPTIONS`, or nothing when no handler serves the path. Methods
+/// refused by [`Config::methods`] are left out.
+fn allowed_methods(request: &Request, config: &Config) -> Vec<Method> {
+    let mut allowed = config.router.allowed_methods(request.path());
+    if matches!(request.path(), "/" | "/sleep") {
+        allowed.extend([Method::Get, Method::Head]);
+    }
+    if config
+        .metrics
+        .as_ref()
+        .is_some_and(|metrics| metrics.route == request.path())
+    {
+        allowed.extend([Method::Get, Method::Head]);
+    }
+    if let Some(upload) = &config.upload {
+        let probe = Request {
+            method: Method::Put,
+            ..request.clone()
+        };
+        if upload::matches(&probe, upload) {
+            allowed.extend([Method::Put, Method::Post]);
+        }
+    }
+    if allowed.is_empty() {
+        return allowed;
+    }
+    let mut unique: Vec<Method> = Vec::new();
+    for method in allowed.into_iter().chain([Method::Options]) {
+        if !unique.contains(&method) && config.methods.permits(&method) {
+            unique.push(method);
+        }
+    }
+    unique
+}
+
+/// Formats methods as the value of an `Allow` header.
+///
+/// # Arguments
+///
+/// * `methods`: The allowed methods.
+///
+/// # Returns
+///
+/// The methods separated by commas, e.g. `GET, OPTIONS`.
+fn allow(methods: &[Method]) -> String {
+    methods
+        .iter()
+        .map(Method::as_str)
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// Compares secrets in a time that does not depend on where they differ, so that clients
+/// cannot guess one byte by byte from response times.
+///
+/// # Returns
+///
+/// `true` when both hold the same bytes.
+pub(crate) fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len()
+        && a.iter()
+            .zip(b)
+            .fold(0, |difference, (a, b)| difference | (a ^ b))
+            == 0
+}
+
+/// Answers `GET` and `HEAD` on the fixed routes with `hello.html` and everything else with `404.html`. With
+/// [`Config::language_variants`], a variant such as `hello.de.html` preferred by the
+/// `Accept-Language` header is served instead.
+///
+/// # Arguments
+///
+/// * `request`: The incoming request.
+/// * `config`: The settings locating the document root.
+///
+/// # Returns
+///
+/// A 200 OK or 404 Not Found response carrying the contents of the matching page.
+///
+/// # Errors
+///
+/// Captures IO errors from listing variants and reading the page from the document root.
+async fn serve_page(request: &Request, config: &Config) -> io::Result<Response> {
+    let (status, file_name) = match (&request.method, request.path()) {
+        (Method::Get | Method::Head, "/") => (StatusCode::OK, "hello.html"),
+        (Method::Get | Method::Head, "/sleep") => {
+            time::sleep(time::Duration::from_secs(5)).await;
+            (StatusCode::OK, "hello.html")
+        }
+        _ => (StatusCode::NOT_FOUND, "404.html"),
+    };
+    let mut path = config.document_root.join(file_name);
+    let mut language = None;
+    let mut varies = false;
+    if config.language_variants {
+        if let Some(variant) =
+            negotiate::select_variant(&path, request.header("Accept-Language")).await?
+        {
+            path = variant.path;
+            language = variant.language;
+            varies = true;
+        }
+    }
+    #[cfg(all(feature = "io-uring", target_os = "linux"))]
+    let contents = uring::read_to_string(&path).await?;
+    #[cfg(not(all(feature = "io-uring", target_os = "linux")))]
+    let contents = tokio::fs::read_to_string(path).await?;
+    let mut response = Response::new(status, contents);
+    if let Some(language) = language {
+        response.headers.append("Content-Language", language);
+    }
+    if varies {
+        response.add_vary("Accept-Language");
+    }
+    Ok(response)
 }
 
 #[cfg(test)]
@@ -131,16 +895,60 @@ mod tests {
     /// Implementing the [`StreamAdapter`] trait for the [`NoErrorMockStream`] struct.
     #[async_trait]
     impl StreamAdapter for NoErrorMockStream {
-        async fn read_request(&mut self) -> io::Result<String> {
-            Ok(self.request.to_string())
+        async fn read_request(&mut self) -> io::Result<Request> {
+            let mut bytes = self.request.as_bytes();
+            let request = Request::read_from(&mut bytes).await?;
+            self.request = &self.request[self.request.len() - bytes.len()..];
+            Ok(request)
+        }
+
+        async fn read_body(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let count = self.request.as_bytes().read(buf).await?;
+            self.request = &self.request[count..];
+            Ok(count)
         }
 
         async fn write_response(&mut self, response: &[u8]) -> io::Result<()> {
-            assert_eq!(self.expected_response.as_bytes(), response);
+            let response = String::from_utf8(response.to_vec()).unwrap();
+            let (head, rest) = response.split_once("\r\n\r\n").unwrap();
+            let head: Vec<&str> = head
+                .split("\r\n")
+                .filter(|line| match line.strip_prefix("Date: ") {
+                    Some(date) => httpdate::parse_http_date(date).is_err(),
+                    None => true,
+                })
+                .collect();
+            assert_eq!(
+                self.expected_response,
+                format!("{}\r\n\r\n{}", head.join("\r\n"), rest)
+            );
             Ok(())
         }
     }
 
+    struct CountingMockStream {
+        inner: NoErrorMockStream,
+        writes: std::sync::Arc<std::sync::atomic::AtomicUsize>,
+    }
+
+    /// Implementing the [`StreamAdapter`] trait for the [`CountingMockStream`] struct.
+    #[async_trait]
+    impl StreamAdapter for CountingMockStream {
+        async fn read_request(&mut self) -> io::Result<Request> {
+            self.inner.read_request().await
+        }
+
+        async fn read_body(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.inner.read_body(buf).await
+        }
+
+        async fn write_response(&mut self, response: &[u8]) -> io::Result<()> {
+            self.writes
+                .fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+            self.inner.write_response(response).await
+        }
+    }
+
     enum ErrorLocation {
         Request,
         Response,
@@ -154,13 +962,19 @@ mod tests {
     /// Implementing the [`StreamAdapter`] trait for the [`ErrorMockStream`] struct.
     #[async_trait]
     impl StreamAdapter for ErrorMockStream {
-        async fn read_request(&mut self) -> io::Result<String> {
+        async fn read_request(&mut self) -> io::Result<Request> {
             match self.error_location {
                 ErrorLocation::Request => Err(io::Error::from(self.error_kind)),
-                ErrorLocation::Response => Ok("GET / HTTP/1.1".to_string()),
+                ErrorLocation::Response => {
+                    Request::read_from(&mut "GET / HTTP/1.1".as_bytes()).await
+                }
             }
         }
 
+        async fn read_body(&mut self, _buf: &mut [u8]) -> io::Result<usize> {
+            Ok(0)
+        }
+
         async fn write_response(&mut self, _response: &[u8]) -> io::Result<()> {
             match self.error_location {
                 ErrorLocation::Response => Err(io::Error::from(self.error_kind)),
@@ -182,8 +996,9 @@ mod tests {
                 HELLO_HTML
             ),
         };
-        let ok = handle_stream(Box::new(mock_stream)).await.unwrap();
-        assert_eq!((), ok);
+        handle_stream(Box::new(mock_stream), &Config::default())
+            .await
+            .unwrap();
     }
 
     /// It creates a mock stream that sends a request for `/sleep` and expects a response with the
@@ -201,10 +1016,11 @@ mod tests {
             ),
         };
         let minimum_instant = time::Instant::now() + time::Duration::from_secs(5);
-        let ok = handle_stream(Box::new(mock_stream)).await.unwrap();
+        handle_stream(Box::new(mock_stream), &Config::default())
+            .await
+            .unwrap();
         let now = time::Instant::now();
         assert!(now >= minimum_instant);
-        assert_eq!((), ok);
     }
 
     /// It creates a mock stream, passes it to the `handle_stream` function, and asserts that the result
@@ -212,16 +1028,435 @@ mod tests {
     #[tokio::test]
     async fn not_found() {
         let mock_stream = NoErrorMockStream {
-            request: "",
+            request: "GET /missing HTTP/1.1",
+            expected_response: format!(
+                "{}\r\nContent-Length: {}\r\n\r\n{}",
+                "HTTP/1.1 404 Not Found",
+                FOUR04_HTML.len(),
+                FOUR04_HTML
+            ),
+        };
+        handle_stream(Box::new(mock_stream), &Config::default())
+            .await
+            .unwrap();
+    }
+
+    /// It creates a mock stream with a WebDAV method while WebDAV is disabled and expects the
+    /// contents of `FOUR04_HTML`
+    #[tokio::test]
+    async fn webdav_disabled() {
+        let mock_stream = NoErrorMockStream {
+            request: "DELETE /hello.html HTTP/1.1",
+            expected_response: format!(
+                "{}\r\nContent-Length: {}\r\n\r\n{}",
+                "HTTP/1.1 404 Not Found",
+                FOUR04_HTML.len(),
+                FOUR04_HTML
+            ),
+        };
+        handle_stream(Box::new(mock_stream), &Config::default())
+            .await
+            .unwrap();
+    }
+
+    /// It answers OPTIONS on registered paths with the methods from the router and fixed pages
+    #[tokio::test]
+    async fn options() {
+        let mut config = Config {
+            router: router::Router::new().route(Method::Delete, "/", |_| async {
+                Ok(Response::new(StatusCode::NO_CONTENT, ""))
+            }),
+            ..Config::default()
+        };
+        let mock_stream = NoErrorMockStream {
+            request: "OPTIONS / HTTP/1.1",
+            expected_response:
+                "HTTP/1.1 204 No Content\r\nAllow: DELETE, GET, HEAD, OPTIONS\r\n\r\n".to_string(),
+        };
+        handle_stream(Box::new(mock_stream), &config).await.unwrap();
+        let mock_stream = NoErrorMockStream {
+            request: "OPTIONS /missing HTTP/1.1",
+            expected_response: format!(
+                "{}\r\nContent-Length: {}\r\n\r\n{}",
+                "HTTP/1.1 404 Not Found",
+                FOUR04_HTML.len(),
+                FOUR04_HTML
+            ),
+        };
+        handle_stream(Box::new(mock_stream), &config).await.unwrap();
+        config.router = config.router.route(Method::Options, "/", |_| async {
+            Ok(Response::new(StatusCode::OK, "custom"))
+        });
+        let mock_stream = NoErrorMockStream {
+            request: "OPTIONS / HTTP/1.1",
+            expected_response: "HTTP/1.1 200 OK\r\nContent-Length: 6\r\n\r\ncustom".to_string(),
+        };
+        handle_stream(Box::new(mock_stream), &config).await.unwrap();
+    }
+
+    /// It reads the body into memory for router handlers
+    #[tokio::test]
+    async fn routed_body() {
+        let config = Config {
+            router: router::Router::new().route(
+                Method::Post,
+                "/echo",
+                |request: Request| async move { Ok(Response::new(StatusCode::OK, request.body)) },
+            ),
+            ..Config::default()
+        };
+        let mock_stream = NoErrorMockStream {
+            request: "POST /echo HTTP/1.1\r\nContent-Length: 5\r\n\r\nhello",
+            expected_response: "HTTP/1.1 200 OK\r\nContent-Length: 5\r\n\r\nhello".to_string(),
+        };
+        handle_stream(Box::new(mock_stream), &config).await.unwrap();
+        let config = Config {
+            max_body_size: 4,
+            ..config
+        };
+        let mock_stream = NoErrorMockStream {
+            request: "POST /echo HTTP/1.1\r\nContent-Length: 5\r\n\r\nhello",
+            expected_response:
+                "HTTP/1.1 413 Payload Too Large\r\nContent-Length: 0\r\nConnection: close\r\n\r\n"
+                    .to_string(),
+        };
+        handle_stream(Box::new(mock_stream), &config).await.unwrap();
+    }
+
+    /// It answers from the request hook of a script and passes other responses through the
+    /// response hook
+    #[cfg(feature = "scripting")]
+    #[tokio::test]
+    async fn scripts() {
+        let config = Config {
+            scripts: Some(
+                script::ScriptHooks::new(
+                    "fn on_request() { if this.path == \"/old\" { return redirect(\"/new\"); } }\n\
+                     fn on_response(request) { this.headers[\"x-hooked\"] = request.path; }",
+                )
+                .unwrap(),
+            ),
+            ..Config::default()
+        };
+        let mock_stream = NoErrorMockStream {
+            request: "GET /old HTTP/1.1\r\n\r\n",
+            expected_response:
+                "HTTP/1.1 302 Found\r\nContent-Length: 0\r\nlocation: /new\r\nx-hooked: /old\r\n\r\n"
+                    .to_string(),
+        };
+        handle_stream(Box::new(mock_stream), &config).await.unwrap();
+    }
+
+    /// It answers from the request callback of a WebAssembly plugin
+    #[cfg(feature = "wasm")]
+    #[tokio::test]
+    async fn plugins() {
+        let plugin = r#"(module
+            (import "env" "proxy_send_local_response"
+                (func $respond (param i32 i32 i32 i32 i32 i32 i32 i32) (result i32)))
+            (memory (export "memory") 1)
+            (func (export "proxy_abi_version_0_2_1"))
+            (func (export "proxy_on_request_headers") (param i32 i32 i32) (result i32)
+                (drop (call $respond (i32.const 403) (i32.const 0) (i32.const 0) (i32.const 0)
+                    (i32.const 0) (i32.const 0) (i32.const 0) (i32.const -1)))
+                (i32.const 1)))"#;
+        let config = Config {
+            plugins: vec![wasm::WasmPlugin::new("deny", plugin.as_bytes()).unwrap()],
+            ..Config::default()
+        };
+        let mock_stream = NoErrorMockStream {
+            request: "GET / HTTP/1.1\r\n\r\n",
+            expected_response: "HTTP/1.1 403 Forbidden\r\nContent-Length: 0\r\n\r\n".to_string(),
+        };
+        handle_stream(Box::new(mock_stream), &config).await.unwrap();
+    }
+
+    /// It applies the body size limit and timeout of a route instead of the global ones
+    #[tokio::test]
+    async fn route_limits() {
+        let echo =
+            |request: Request| async move { Ok(Response::new(StatusCode::OK, request.body)) };
+        let config = Config {
+            router: router::Router::new()
+                .route(Method::Post, "/upload", echo)
+                .with_limits(router::RouteLimits {
+                    max_body_size: Some(16),
+                    timeout: None,
+                })
+                .route(Method::Post, "/echo", echo)
+                .route(Method::Get, "/slow", |_| async {
+                    time::sleep(Duration::from_secs(5)).await;
+                    Ok(Response::new(StatusCode::OK, ""))
+                })
+                .with_limits(router::RouteLimits {
+                    max_body_size: Some(4),
+                    timeout: Some(Duration::from_millis(10)),
+                }),
+            max_body_size: 64,
+            ..Config::default()
+        };
+        let mock_stream = NoErrorMockStream {
+            request: "POST /upload HTTP/1.1\r\nContent-Length: 5\r\n\r\nhello",
+            expected_response: "HTTP/1.1 200 OK\r\nContent-Length: 5\r\n\r\nhello".to_string(),
+        };
+        handle_stream(Box::new(mock_stream), &config).await.unwrap();
+        let mock_stream = NoErrorMockStream {
+            request: "POST /echo HTTP/1.1\r\nContent-Length: 5\r\n\r\nhello",
+            expected_response:
+                "HTTP/1.1 413 Payload Too Large\r\nContent-Length: 0\r\nConnection: close\r\n\r\n"
+                    .to_string(),
+        };
+        handle_stream(Box::new(mock_stream), &config).await.unwrap();
+        let mock_stream = NoErrorMockStream {
+            request: "GET /slow HTTP/1.1\r\n\r\n",
+            expected_response:
+                "HTTP/1.1 408 Request Timeout\r\nContent-Length: 0\r\nConnection: close\r\n\r\n"
+                    .to_string(),
+        };
+        handle_stream(Box::new(mock_stream), &config).await.unwrap();
+    }
+
+    /// It applies the global timeout to uploads and removes the partial file
+    #[tokio::test]
+    async fn upload_timeout() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+        let directory = tempfile::tempdir().unwrap();
+        let config = Config {
+            upload: Some(config::UploadConfig {
+                route: "/upload".to_string(),
+                directory: directory.path().to_path_buf(),
+                max_size: 1024,
+            }),
+            request_timeout: Some(Duration::from_millis(50)),
+            ..Config::default()
+        };
+        let listener = net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut client = net::TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        client
+            .write_all(b"PUT /upload/file.txt HTTP/1.1\r\nContent-Length: 10\r\n\r\nabc")
+            .await
+            .unwrap();
+        let (stream, _) = listener.accept().await.unwrap();
+        handle_stream(Box::new(io::BufReader::new(stream)), &config)
+            .await
+            .unwrap();
+        let mut response = String::new();
+        client.read_to_string(&mut response).await.unwrap();
+        assert!(response.starts_with("HTTP/1.1 408 Request Timeout\r\n"));
+        assert_eq!(0, std::fs::read_dir(directory.path()).unwrap().count());
+    }
+
+    /// It refuses disabled methods with 405 and methods outside the allow-list with 501
+    #[tokio::test]
+    async fn method_policy() {
+        let mock_stream = NoErrorMockStream {
+            request: "TRACE / HTTP/1.1",
+            expected_response: "HTTP/1.1 405 Method Not Allowed\r\nContent-Length: 0\r\nAllow: GET, HEAD, OPTIONS\r\n\r\n".to_string(),
+        };
+        handle_stream(Box::new(mock_stream), &Config::default())
+            .await
+            .unwrap();
+        let config = Config {
+            methods: config::MethodPolicy {
+                allowed: Some(vec![Method::Get]),
+                disabled: Vec::new(),
+            },
+            ..Config::default()
+        };
+        let mock_stream = NoErrorMockStream {
+            request: "OPTIONS / HTTP/1.1",
+            expected_response: "HTTP/1.1 501 Not Implemented\r\nContent-Length: 0\r\n\r\n"
+                .to_string(),
+        };
+        handle_stream(Box::new(mock_stream), &config).await.unwrap();
+    }
+
+    /// It rejects ambiguous framing in strict mode and tolerates it otherwise
+    #[tokio::test]
+    async fn strict() {
+        let mock_stream = NoErrorMockStream {
+            request: "GET / HTTP/1.1\r\nContent-Length: 1\r\nContent-Length: 2\r\n\r\n",
+            expected_response:
+                "HTTP/1.1 400 Bad Request\r\nContent-Length: 0\r\nConnection: close\r\n\r\n"
+                    .to_string(),
+        };
+        handle_stream(Box::new(mock_stream), &Config::default())
+            .await
+            .unwrap();
+        let config = Config {
+            strict: false,
+            ..Config::default()
+        };
+        let mock_stream = NoErrorMockStream {
+            request: "GET / HTTP/1.1\nHost: a\n\n",
+            expected_response: format!(
+                "{}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                "HTTP/1.1 200 OK",
+                HELLO_HTML.len(),
+                HELLO_HTML
+            ),
+        };
+        handle_stream(Box::new(mock_stream), &config).await.unwrap();
+    }
+
+    /// It answers a malformed request line with 400 and closes the connection
+    #[tokio::test]
+    async fn malformed_request() {
+        let mock_stream = NoErrorMockStream {
+            request: "GARBAGE\r\n\r\nGET / HTTP/1.1\r\n\r\n",
+            expected_response:
+                "HTTP/1.1 400 Bad Request\r\nContent-Length: 0\r\nConnection: close\r\n\r\n"
+                    .to_string(),
+        };
+        let writes = std::sync::Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let counting = CountingMockStream {
+            inner: mock_stream,
+            writes: std::sync::Arc::clone(&writes),
+        };
+        handle_stream(Box::new(counting), &Config::default())
+            .await
+            .unwrap();
+        assert_eq!(1, writes.load(std::sync::atomic::Ordering::SeqCst));
+    }
+
+    /// It answers pipelined requests on one connection and counts the second as reusing it
+    #[tokio::test]
+    async fn keep_alive() {
+        let mock_stream = NoErrorMockStream {
+            request: "GET / HTTP/1.1\r\n\r\nGET / HTTP/1.1\r\n\r\n",
+            expected_response: format!(
+                "{}\r\nContent-Length: {}\r\n\r\n{}",
+                "HTTP/1.1 200 OK",
+                HELLO_HTML.len(),
+                HELLO_HTML
+            ),
+        };
+        let writes = std::sync::Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let counting = CountingMockStream {
+            inner: mock_stream,
+            writes: std::sync::Arc::clone(&writes),
+        };
+        let config = Config::default();
+        handle_stream(Box::new(counting), &config).await.unwrap();
+        assert_eq!(2, writes.load(std::sync::atomic::Ordering::SeqCst));
+        let stats = config.stats.snapshot();
+        assert_eq!((2, 1), (stats.requests, stats.reused_requests));
+    }
+
+    /// It answers HEAD like GET without the body, so a pipelined request after it stays in sync
+    #[tokio::test]
+    async fn head() {
+        let listener = net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut client = net::TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (stream, _) = listener.accept().await.unwrap();
+        let server = tokio::spawn(async move {
+            handle_stream(Box::new(io::BufReader::new(stream)), &Config::default()).await
+        });
+        client
+            .write_all(b"HEAD / HTTP/1.1\r\n\r\nGET / HTTP/1.1\r\nConnection: close\r\n\r\n")
+            .await
+            .unwrap();
+        let mut responses = String::new();
+        client.read_to_string(&mut responses).await.unwrap();
+        server.await.unwrap().unwrap();
+        let (head, rest) = responses.split_once("\r\n\r\n").unwrap();
+        assert!(head.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(head.contains(&format!("Content-Length: {}\r\n", HELLO_HTML.len())));
+        assert!(rest.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(rest.ends_with(HELLO_HTML));
+    }
+
+    /// It keeps HTTP/1.0 connections alive only when asked to and refuses HTTP/2 requests
+    #[tokio::test]
+    async fn versions() {
+        let mock_stream = NoErrorMockStream {
+            request: "GET / HTTP/1.0\r\nConnection: keep-alive\r\n\r\nGET / HTTP/1.0\r\nConnection: keep-alive\r\n\r\n",
+            expected_response: format!(
+                "{}\r\nContent-Length: {}\r\nConnection: keep-alive\r\n\r\n{}",
+                "HTTP/1.1 200 OK",
+                HELLO_HTML.len(),
+                HELLO_HTML
+            ),
+        };
+        let writes = std::sync::Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let counting = CountingMockStream {
+            inner: mock_stream,
+            writes: std::sync::Arc::clone(&writes),
+        };
+        handle_stream(Box::new(counting), &Config::default())
+            .await
+            .unwrap();
+        assert_eq!(2, writes.load(std::sync::atomic::Ordering::SeqCst));
+        let mock_stream = NoErrorMockStream {
+            request: "GET / HTTP/1.0\r\n\r\nGET / HTTP/1.0\r\n\r\n",
             expected_response: format!(
                 "{}\r\nContent-Length: {}\r\n\r\n{}",
-                "HTTP/1.1 404 NOT FOUND",
+                "HTTP/1.1 200 OK",
+                HELLO_HTML.len(),
+                HELLO_HTML
+            ),
+        };
+        handle_stream(Box::new(mock_stream), &Config::default())
+            .await
+            .unwrap();
+        let mock_stream = NoErrorMockStream {
+            request: "PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n",
+            expected_response: "HTTP/1.1 505 HTTP Version Not Supported\r\nContent-Length: 0\r\nConnection: close\r\n\r\n".to_string(),
+        };
+        handle_stream(Box::new(mock_stream), &Config::default())
+            .await
+            .unwrap();
+    }
+
+    /// It closes the connection instead of parsing an unread body as the next request
+    #[tokio::test]
+    async fn poisoned_connection() {
+        let mock_stream = NoErrorMockStream {
+            request: "POST / HTTP/1.1\r\nContent-Length: 18\r\n\r\nGET / HTTP/1.1\r\n\r\n",
+            expected_response: format!(
+                "{}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                "HTTP/1.1 404 Not Found",
                 FOUR04_HTML.len(),
                 FOUR04_HTML
             ),
         };
-        let ok = handle_stream(Box::new(mock_stream)).await.unwrap();
-        assert_eq!((), ok);
+        let writes = std::sync::Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let counting = CountingMockStream {
+            inner: mock_stream,
+            writes: std::sync::Arc::clone(&writes),
+        };
+        handle_stream(Box::new(counting), &Config::default())
+            .await
+            .unwrap();
+        assert_eq!(1, writes.load(std::sync::atomic::Ordering::SeqCst));
+    }
+
+    /// It closes a keep-alive connection that sends no further request within the header timeout
+    #[tokio::test(start_paused = true)]
+    async fn header_timeout() {
+        let listener = net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut client = net::TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (stream, _) = listener.accept().await.unwrap();
+        let config = Config {
+            header_timeout: Some(Duration::from_secs(5)),
+            ..Config::default()
+        };
+        let server = tokio::spawn(async move {
+            handle_stream(Box::new(io::BufReader::new(stream)), &config).await
+        });
+        client.write_all(b"GET / HTTP/1.1\r\n\r\n").await.unwrap();
+        let started = time::Instant::now();
+        let mut response = Vec::new();
+        client.read_to_end(&mut response).await.unwrap();
+        assert!(started.elapsed() >= Duration::from_secs(5));
+        assert!(response.starts_with(b"HTTP/1.1 200 OK\r\n"));
+        server.await.unwrap().unwrap();
     }
 
     /// It creates a mock stream, passes it to the `handle_stream` function, and asserts that the result
@@ -231,9 +1466,11 @@ mod tests {
         let kind = io::ErrorKind::NotFound;
         let mock_stream = ErrorMockStream {
             error_location: ErrorLocation::Request,
-            error_kind: kind.clone(),
+            error_kind: kind,
         };
-        let error = handle_stream(Box::new(mock_stream)).await.unwrap_err();
+        let error = handle_stream(Box::new(mock_stream), &Config::default())
+            .await
+            .unwrap_err();
         assert_eq!(kind, error.kind());
     }
 
@@ -244,9 +1481,11 @@ mod tests {
         let kind = io::ErrorKind::NotFound;
         let mock_stream = ErrorMockStream {
             error_location: ErrorLocation::Response,
-            error_kind: kind.clone(),
+            error_kind: kind,
         };
-        let error = handle_stream(Box::new(mock_stream)).await.unwrap_err();
+        let error = handle_stream(Box::new(mock_stream), &Config::default())
+            .await
+            .unwrap_err();
         assert_eq!(kind, error.kind());
     }
 }
@@ -0,0 +1,320 @@
+pub mod file;
+pub mod format;
+#[cfg(unix)]
+pub mod journald;
+pub mod span;
+pub mod syslog;
+
+use crate::date;
+use file::{LogFile, Rotation};
+use std::fmt;
+use std::io;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU8, Ordering};
+use std::sync::{Arc, RwLock};
+
+pub use span::{record, Span};
+
+/// How severe a log message is, from the most to the least severe.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Level {
+    /// A failure that ends a connection or a background task.
+    Error,
+    /// Something unexpected that the server recovered from.
+    Warn,
+    /// Routine events such as startup and shutdown.
+    Info,
+    /// Details useful while diagnosing a single request.
+    Debug,
+}
+
+impl Level {
+    const ALL: [Level; 4] = [Level::Error, Level::Warn, Level::Info, Level::Debug];
+
+    /// The lowercase name, e.g. `warn`.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Level::Error => "error",
+            Level::Warn => "warn",
+            Level::Info => "info",
+            Level::Debug => "debug",
+        }
+    }
+}
+
+impl fmt::Display for Level {
+    fn fmt(&self, formatter: &mut fmt::Formatter<'_>) -> fmt::Result {
+        formatter.write_str(self.as_str())
+    }
+}
+
+impl FromStr for Level {
+    type Err = ();
+
+    /// Parses a level name, ignoring ASCII case.
+    fn from_str(name: &str) -> Result<Level, ()> {
+        Level::ALL
+            .into_iter()
+            .find(|level| level.as_str().eq_ignore_ascii_case(name.trim()))
+            .ok_or(())
+    }
+}
+
+/// The least severe level written, shared by the whole process so it can be changed while the
+/// server runs.
+static LEVEL: AtomicU8 = AtomicU8::new(Level::Info as u8);
+
+/// The least severe level currently written.
+pub fn level() -> Level {
+    Level::ALL[usize::from(LEVEL.load(Ordering::Relaxed))]
+}
+
+/// Changes the least severe level written.
+///
+/// # Arguments
+///
+/// * `level`: The new level, e.g. [`Level::Debug`] to see everything.
+pub fn set_level(level: Level) {
+    LEVEL.store(level as u8, Ordering::Relaxed);
+}
+
+/// Checks whether messages of a level are written.
+///
+/// # Arguments
+///
+/// * `level`: The level of a message about to be logged.
+///
+/// # Returns
+///
+/// `true` when the level is at least as severe as [`level`].
+pub fn enabled(level: Level) -> bool {
+    level <= self::level()
+}
+
+/// Where log messages go, see [`set_sink`].
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub enum Output {
+    /// Standard error, the default.
+    #[default]
+    Stderr,
+    /// Standard output.
+    Stdout,
+    /// A file, each line starting with the time.
+    File(PathBuf),
+    /// A syslog daemon, see [`syslog::Syslog`].
+    Syslog(syslog::Target, syslog::Facility),
+    /// The systemd journal, see [`journald::Journald`].
+    Journald,
+}
+
+/// An opened [`Output`].
+#[derive(Debug)]
+pub enum Sink {
+    /// Standard error.
+    Stderr,
+    /// Standard output.
+    Stdout,
+    /// A file, each line starting with the time.
+    File(LogFile),
+    /// A syslog daemon.
+    Syslog(syslog::Syslog),
+    /// The systemd journal.
+    #[cfg(unix)]
+    Journald(journald::Journald),
+}
+
+impl Sink {
+    /// Opens an output.
+    ///
+    /// # Arguments
+    ///
+    /// * `output`: Where messages go.
+    /// * `rotation`: When a file rotates.
+    ///
+    /// # Returns
+    ///
+    /// The sink to pass to [`set_sink`].
+    ///
+    /// # Errors
+    ///
+    /// Returns [`io::ErrorKind::Unsupported`] for journald on other platforms than Unix, and
+    /// captures IO errors from opening the file or connecting to the daemon.
+    pub fn open(output: &Output, rotation: Rotation) -> io::Result<Sink> {
+        Ok(match output {
+            Output::Stderr => Sink::Stderr,
+            Output::Stdout => Sink::Stdout,
+            Output::File(path) => Sink::File(LogFile::open(path, rotation)?),
+            Output::Syslog(target, facility) => {
+                Sink::Syslog(syslog::Syslog::connect(target, *facility)?)
+            }
+            #[cfg(unix)]
+            Output::Journald => Sink::Journald(journald::Journald::connect()?),
+            #[cfg(not(unix))]
+            Output::Journald => {
+                return Err(io::Error::new(
+                    io::ErrorKind::Unsupported,
+                    "journald needs Unix",
+                ))
+            }
+        })
+    }
+
+    /// Writes a message.
+    fn write(&self, level: Level, message: &dyn fmt::Display) -> io::Result<()> {
+        match self {
+            Sink::Stderr => eprintln!("[{}] {}", level, message),
+            Sink::Stdout => println!("[{}] {}", level, message),
+            Sink::File(file) => {
+                return date::with_current(|date| {
+                    file.write_line(format_args!("[{}] [{}] {}", date, level, message))
+                })
+            }
+            Sink::Syslog(syslog) => return syslog.send(level, message),
+            #[cfg(unix)]
+            Sink::Journald(journald) => return journald.send(level, message),
+        }
+        Ok(())
+    }
+}
+
+/// Where messages are written, stderr when `None`, see [`set_sink`].
+static SINK: RwLock<Option<Arc<Sink>>> = RwLock::new(None);
+
+/// The file every handled request is written to, see [`set_access_log`].
+static ACCESS_LOG: RwLock<Option<Arc<LogFile>>> = RwLock::new(None);
+
+/// Writes the messages of [`log`] somewhere else than stderr.
+///
+/// # Arguments
+///
+/// * `sink`: Where messages go from now on.
+pub fn set_sink(sink: Sink) {
+    *SINK.write().unwrap_or_else(|error| error.into_inner()) = Some(Arc::new(sink));
+}
+
+/// Writes a line per handled request to a file through [`access`].
+///
+/// # Arguments
+///
+/// * `file`: The file, or `None` to stop logging requests.
+pub fn set_access_log(file: Option<LogFile>) {
+    *ACCESS_LOG
+        .write()
+        .unwrap_or_else(|error| error.into_inner()) = file.map(Arc::new);
+}
+
+fn current<T>(sink: &RwLock<Option<Arc<T>>>) -> Option<Arc<T>> {
+    sink.read()
+        .unwrap_or_else(|error| error.into_inner())
+        .clone()
+}
+
+/// Reopens the error and access log files at their paths, e.g. on `SIGUSR1` after logrotate
+/// moved them, see [`LogFile::reopen`].
+///
+/// # Errors
+///
+/// Captures IO errors from opening the files.
+pub fn reopen() -> io::Result<()> {
+    if let Some(Sink::File(file)) = current(&SINK).as_deref() {
+        file.reopen()?;
+    }
+    if let Some(file) = current(&ACCESS_LOG) {
+        file.reopen()?;
+    }
+    Ok(())
+}
+
+/// Writes a message to the sink of [`set_sink`] unless its level is filtered out, falling back
+/// to stderr when the sink fails. Messages logged inside a [`Span`] are prefixed with it.
+///
+/// # Arguments
+///
+/// * `level`: The severity of the message.
+/// * `message`: The message, written on one line after the level.
+pub fn log(level: Level, message: impl fmt::Display) {
+    if enabled(level) {
+        always(level, message);
+    }
+}
+
+/// Writes a message like [`log`] whatever level [`set_level`] chose, for reports an operator
+/// asked for separately, such as slow requests.
+///
+/// # Arguments
+///
+/// * `level`: The severity written with the message.
+/// * `message`: The message, written on one line after the level.
+pub fn always(level: Level, message: impl fmt::Display) {
+    match Span::current() {
+        Some(span) => write(level, &format_args!("{}: {}", span, message)),
+        None => write(level, &message),
+    }
+}
+
+fn write(level: Level, message: &dyn fmt::Display) {
+    let sink = current(&SINK);
+    let sink = sink.as_deref().unwrap_or(&Sink::Stderr);
+    if let Err(error) = sink.write(level, message) {
+        eprintln!("[{}] {}", level, message);
+        eprintln!("[error] writing the log failed: {}", error);
+    }
+}
+
+/// Checks whether [`access`] writes anywhere, so callers skip formatting lines otherwise.
+pub fn access_enabled() -> bool {
+    current(&ACCESS_LOG).is_some()
+}
+
+/// Writes a line to the access log.
+///
+/// # Arguments
+///
+/// * `message`: The line describing a handled request, see [`format::AccessFormat`].
+pub fn access(message: impl fmt::Display) {
+    if let Some(file) = current(&ACCESS_LOG) {
+        if let Err(failure) = file.write_line(message) {
+            error(format_args!(
+                "writing {} failed: {}",
+                file.path().display(),
+                failure
+            ));
+        }
+    }
+}
+
+/// Writes a message at [`Level::Error`], see [`log`].
+pub fn error(message: impl fmt::Display) {
+    log(Level::Error, message);
+}
+
+/// Writes a message at [`Level::Warn`], see [`log`].
+pub fn warn(message: impl fmt::Display) {
+    log(Level::Warn, message);
+}
+
+/// Writes a message at [`Level::Info`], see [`log`].
+pub fn info(message: impl fmt::Display) {
+    log(Level::Info, message);
+}
+
+/// Writes a message at [`Level::Debug`], see [`log`].
+pub fn debug(message: impl fmt::Display) {
+    log(Level::Debug, message);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// It parses level names ignoring case and orders levels by severity
+    #[test]
+    fn levels() {
+        assert_eq!(Ok(Level::Warn), "WARN".parse());
+        assert_eq!(Ok(Level::Debug), " debug".parse());
+        assert_eq!(Err(()), "verbose".parse::<Level>());
+        assert!(Level::Error < Level::Debug);
+        assert_eq!("info", Level::Info.to_string());
+    }
+}
@@ -0,0 +1,219 @@
+//! Log files that rotate themselves by size or age, or are reopened after an external tool
+//! such as logrotate moved them.
+
+use std::fmt::{self, Write as _};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, MutexGuard};
+use std::time::{Duration, SystemTime};
+
+/// When a [`LogFile`] starts over and how many old files it keeps.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Rotation {
+    /// Rotates before a line would grow the file past this many bytes.
+    pub max_size: Option<u64>,
+    /// Rotates before writing to a file created this long ago.
+    pub interval: Option<Duration>,
+    /// The rotated files kept as `<path>.1` (the newest) to `<path>.<keep>`, deleting older
+    /// ones.
+    pub keep: usize,
+}
+
+impl Default for Rotation {
+    /// Never rotates by itself and keeps seven files when rotated through [`LogFile::rotate`].
+    fn default() -> Rotation {
+        Rotation {
+            max_size: None,
+            interval: None,
+            keep: 7,
+        }
+    }
+}
+
+/// A file lines are appended to, shared by every thread logging to it.
+#[derive(Debug)]
+pub struct LogFile {
+    path: PathBuf,
+    rotation: Rotation,
+    state: Mutex<State>,
+}
+
+/// The open file and what rotation needs to know about it.
+#[derive(Debug)]
+struct State {
+    file: File,
+    size: u64,
+    created: SystemTime,
+}
+
+impl State {
+    /// Opens the file at a path for appending, creating it when missing.
+    fn open(path: &Path) -> io::Result<State> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let metadata = file.metadata()?;
+        Ok(State {
+            size: metadata.len(),
+            // File systems without creation times restart the interval with the process
+            created: metadata.created().unwrap_or_else(|_| SystemTime::now()),
+            file,
+        })
+    }
+}
+
+impl LogFile {
+    /// Opens a log file for appending, creating it when missing.
+    ///
+    /// # Arguments
+    ///
+    /// * `path`: The file, e.g. `/var/log/web_server_tokio/access.log`.
+    /// * `rotation`: When the file rotates by itself.
+    ///
+    /// # Returns
+    ///
+    /// The log file.
+    ///
+    /// # Errors
+    ///
+    /// Captures IO errors from opening the file.
+    pub fn open(path: impl Into<PathBuf>, rotation: Rotation) -> io::Result<LogFile> {
+        let path = path.into();
+        let state = State::open(&path)?;
+        Ok(LogFile {
+            path,
+            rotation,
+            state: Mutex::new(state),
+        })
+    }
+
+    /// The path of the file.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Appends a line, rotating the file first when it is due.
+    ///
+    /// # Arguments
+    ///
+    /// * `line`: The line, without the trailing newline.
+    ///
+    /// # Errors
+    ///
+    /// Captures IO errors from rotating and writing the file.
+    pub fn write_line(&self, line: impl fmt::Display) -> io::Result<()> {
+        let mut text = String::new();
+        let _ = writeln!(text, "{}", line);
+        let mut state = self.lock();
+        let length = text.len() as u64;
+        let full = self
+            .rotation
+            .max_size
+            .is_some_and(|max_size| state.size > 0 && state.size + length > max_size);
+        let old = self.rotation.interval.is_some_and(|interval| {
+            state
+                .created
+                .elapsed()
+                .is_ok_and(|elapsed| elapsed >= interval)
+        });
+        if full || old {
+            self.rotate_locked(&mut state)?;
+        }
+        state.file.write_all(text.as_bytes())?;
+        state.size += length;
+        Ok(())
+    }
+
+    /// Renames the file to `<path>.1`, shifting older files up to [`Rotation::keep`], and
+    /// starts a new one.
+    ///
+    /// # Errors
+    ///
+    /// Captures IO errors from renaming and opening the files.
+    pub fn rotate(&self) -> io::Result<()> {
+        self.rotate_locked(&mut self.lock())
+    }
+
+    /// Opens the file at the path again, e.g. after logrotate renamed it, continuing a file
+    /// that still exists.
+    ///
+    /// # Errors
+    ///
+    /// Captures IO errors from opening the file, in which case the old one stays in use.
+    pub fn reopen(&self) -> io::Result<()> {
+        *self.lock() = State::open(&self.path)?;
+        Ok(())
+    }
+
+    fn rotate_locked(&self, state: &mut State) -> io::Result<()> {
+        let numbered = |number: usize| {
+            let mut path = self.path.clone().into_os_string();
+            path.push(format!(".{}", number));
+            PathBuf::from(path)
+        };
+        if self.rotation.keep == 0 {
+            match fs::remove_file(&self.path) {
+                Err(error) if error.kind() != io::ErrorKind::NotFound => return Err(error),
+                _ => {}
+            }
+        } else {
+            for number in (1..self.rotation.keep).rev() {
+                match fs::rename(numbered(number), numbered(number + 1)) {
+                    Err(error) if error.kind() != io::ErrorKind::NotFound => return Err(error),
+                    _ => {}
+                }
+            }
+            match fs::rename(&self.path, numbered(1)) {
+                // Someone else moved the file already, e.g. logrotate before signaling
+                Err(error) if error.kind() != io::ErrorKind::NotFound => return Err(error),
+                _ => {}
+            }
+        }
+        // A new file is created at once, so a crash right after leaves no gap in the names
+        *state = State::open(&self.path)?;
+        state.created = SystemTime::now();
+        Ok(())
+    }
+
+    fn lock(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap_or_else(|error| error.into_inner())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// It rotates by size, keeps the configured number of old files, and reopens moved files
+    #[test]
+    fn rotate() {
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("access.log");
+        let rotation = Rotation {
+            max_size: Some(10),
+            keep: 2,
+            ..Rotation::default()
+        };
+        let file = LogFile::open(&path, rotation).unwrap();
+        for line in ["first", "second", "third", "fourth"] {
+            file.write_line(line).unwrap();
+        }
+        assert_eq!("fourth\n", fs::read_to_string(&path).unwrap());
+        assert_eq!(
+            "third\n",
+            fs::read_to_string(directory.path().join("access.log.1")).unwrap()
+        );
+        assert_eq!(
+            "second\n",
+            fs::read_to_string(directory.path().join("access.log.2")).unwrap()
+        );
+        assert!(!directory.path().join("access.log.3").exists());
+
+        let moved = directory.path().join("moved.log");
+        fs::rename(&path, &moved).unwrap();
+        file.write_line("ok").unwrap();
+        file.reopen().unwrap();
+        file.write_line("fresh").unwrap();
+        assert_eq!("fourth\nok\n", fs::read_to_string(&moved).unwrap());
+        assert_eq!("fresh\n", fs::read_to_string(&path).unwrap());
+    }
+}
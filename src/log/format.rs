@@ -0,0 +1,365 @@
+//! Access log lines built from nginx-style templates such as
+//! `$remote_addr "$request" $status $request_time $upstream_addr`.
+
+use crate::date::Civil;
+use crate::request::Request;
+use crate::response::Response;
+use std::fmt::{self, Write as _};
+use std::str::FromStr;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::io;
+
+/// The months as abbreviated in `$time_local`.
+const MONTHS: [&str; 12] = [
+    "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+];
+
+/// A template for the lines of the access log. Variables start with `$` and may be wrapped in
+/// braces to separate them from following text, e.g. `${status}ms`; every value that is
+/// missing or empty is written as `-`.
+///
+/// * `$remote_addr`, `$remote_port`: The client address.
+/// * `$request`: The request line, e.g. `GET /index.html HTTP/1.1`.
+/// * `$request_method`, `$request_uri`, `$uri`, `$args`, `$server_protocol`: Parts of the
+///   request line, `$uri` being the path and `$args` the query.
+/// * `$request_length`: The request body size in bytes.
+/// * `$status`, `$body_bytes_sent`: The response status code and body size in bytes.
+/// * `$request_time`: Seconds from reading the request to writing the response, with
+///   milliseconds.
+/// * `$time_local`, `$time_iso8601`, `$msec`: When the request arrived, as
+///   `10/Oct/2000:13:55:36 +0000`, `2000-10-10T13:55:36+00:00`, or Unix seconds with
+///   milliseconds.
+/// * `$upstream_addr`: The upstream that answered a proxied request.
+/// * `$http_<name>`, `$sent_http_<name>`: A request or response header, with `_` standing for
+///   `-`, e.g. `$http_user_agent`.
+/// * `$log_<name>`: A field a handler attached to its response through [`LogFields`], e.g.
+///   `$log_audit`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct AccessFormat {
+    parts: Vec<Part>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Part {
+    Literal(String),
+    Variable(Variable),
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Variable {
+    RemoteAddr,
+    RemotePort,
+    Request,
+    RequestMethod,
+    RequestUri,
+    Uri,
+    Args,
+    ServerProtocol,
+    RequestLength,
+    Status,
+    BodyBytesSent,
+    RequestTime,
+    TimeLocal,
+    TimeIso8601,
+    Msec,
+    UpstreamAddr,
+    RequestHeader(String),
+    ResponseHeader(String),
+    LogField(String),
+}
+
+impl Variable {
+    fn parse(name: &str) -> Option<Variable> {
+        let header = |name: &str| name.replace('_', "-");
+        Some(match name {
+            "remote_addr" => Variable::RemoteAddr,
+            "remote_port" => Variable::RemotePort,
+            "request" => Variable::Request,
+            "request_method" => Variable::RequestMethod,
+            "request_uri" => Variable::RequestUri,
+            "uri" => Variable::Uri,
+            "args" => Variable::Args,
+            "server_protocol" => Variable::ServerProtocol,
+            "request_length" => Variable::RequestLength,
+            "status" => Variable::Status,
+            "body_bytes_sent" => Variable::BodyBytesSent,
+            "request_time" => Variable::RequestTime,
+            "time_local" => Variable::TimeLocal,
+            "time_iso8601" => Variable::TimeIso8601,
+            "msec" => Variable::Msec,
+            "upstream_addr" => Variable::UpstreamAddr,
+            _ => match (
+                name.strip_prefix("http_"),
+                name.strip_prefix("sent_http_"),
+                name.strip_prefix("log_"),
+            ) {
+                (Some(name), _, _) if !name.is_empty() => Variable::RequestHeader(header(name)),
+                (_, Some(name), _) if !name.is_empty() => Variable::ResponseHeader(header(name)),
+                (_, _, Some(name)) if !name.is_empty() => Variable::LogField(name.to_string()),
+                _ => return None,
+            },
+        })
+    }
+}
+
+/// Fields a handler attaches to its response with [`Response::with_extension`] for the access
+/// log to write through `$log_<name>` variables, e.g. audit tags, see [`AccessFormat`].
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct LogFields(Vec<(String, String)>);
+
+impl LogFields {
+    /// Creates an empty set of fields.
+    pub fn new() -> LogFields {
+        LogFields::default()
+    }
+
+    /// Sets a field, replacing any value it had.
+    ///
+    /// # Arguments
+    ///
+    /// * `name`: The name in the variable, e.g. `audit` for `$log_audit`.
+    /// * `value`: The value written.
+    ///
+    /// # Returns
+    ///
+    /// The fields including the new one.
+    pub fn with(mut self, name: impl Into<String>, value: impl Into<String>) -> LogFields {
+        let name = name.into();
+        self.0.retain(|(existing, _)| *existing != name);
+        self.0.push((name, value.into()));
+        self
+    }
+
+    /// Finds the value of a field.
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.0
+            .iter()
+            .find(|(existing, _)| existing == name)
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+/// What an access log line describes.
+#[derive(Clone, Copy, Debug)]
+pub struct AccessEntry<'a> {
+    /// The handled request.
+    pub request: &'a Request,
+    /// The response written for it.
+    pub response: &'a Response,
+    /// When the request was read.
+    pub start: SystemTime,
+    /// How long the request took until the response was written.
+    pub duration: Duration,
+}
+
+impl Default for AccessFormat {
+    /// The combined format of Apache and nginx without the user:
+    /// `$remote_addr - - [$time_local] "$request" $status $body_bytes_sent "$http_referer"
+    /// "$http_user_agent"`.
+    fn default() -> AccessFormat {
+        "$remote_addr - - [$time_local] \"$request\" $status $body_bytes_sent \"$http_referer\" \
+         \"$http_user_agent\""
+            .parse()
+            .unwrap_or(AccessFormat { parts: Vec::new() })
+    }
+}
+
+impl FromStr for AccessFormat {
+    type Err = io::Error;
+
+    /// Parses a template, see [`AccessFormat`].
+    ///
+    /// # Errors
+    ///
+    /// Returns [`io::ErrorKind::InvalidInput`] for an unknown variable or a `$` without a
+    /// name.
+    fn from_str(template: &str) -> io::Result<AccessFormat> {
+        let invalid = |message: String| io::Error::new(io::ErrorKind::InvalidInput, message);
+        let mut parts = Vec::new();
+        let mut literal = String::new();
+        let mut rest = template;
+        while let Some(dollar) = rest.find('$') {
+            literal.push_str(&rest[..dollar]);
+            rest = &rest[dollar + 1..];
+            let (name, after) = match rest.strip_prefix('{') {
+                Some(braced) => {
+                    let end = braced
+                        .find('}')
+                        .ok_or_else(|| invalid(format!("unclosed variable in {}", template)))?;
+                    (&braced[..end], &braced[end + 1..])
+                }
+                None => {
+                    let end = rest
+                        .find(|c: char| !c.is_ascii_alphanumeric() && c != '_')
+                        .unwrap_or(rest.len());
+                    (&rest[..end], &rest[end..])
+                }
+            };
+            let variable = Variable::parse(name)
+                .ok_or_else(|| invalid(format!("unknown log variable ${}", name)))?;
+            if !literal.is_empty() {
+                parts.push(Part::Literal(std::mem::take(&mut literal)));
+            }
+            parts.push(Part::Variable(variable));
+            rest = after;
+        }
+        literal.push_str(rest);
+        if !literal.is_empty() {
+            parts.push(Part::Literal(literal));
+        }
+        Ok(AccessFormat { parts })
+    }
+}
+
+impl AccessFormat {
+    /// Formats the line describing a request.
+    ///
+    /// # Arguments
+    ///
+    /// * `entry`: The request, its response, and their timing.
+    ///
+    /// # Returns
+    ///
+    /// The line without a trailing newline.
+    pub fn format(&self, entry: &AccessEntry<'_>) -> String {
+        let mut line = String::new();
+        for part in &self.parts {
+            match part {
+                Part::Literal(text) => line.push_str(text),
+                Part::Variable(variable) => {
+                    let start = line.len();
+                    let _ = write_variable(&mut line, variable, entry);
+                    if line.len() == start {
+                        line.push('-');
+                    }
+                }
+            }
+        }
+        line
+    }
+}
+
+/// Appends the value of a variable, nothing when it has none.
+fn write_variable(line: &mut String, variable: &Variable, entry: &AccessEntry<'_>) -> fmt::Result {
+    let AccessEntry {
+        request, response, ..
+    } = entry;
+    match variable {
+        Variable::RemoteAddr => {
+            if let Some(peer) = request.peer {
+                write!(line, "{}", peer.ip())?;
+            }
+        }
+        Variable::RemotePort => {
+            if let Some(peer) = request.peer {
+                write!(line, "{}", peer.port())?;
+            }
+        }
+        Variable::Request => write!(
+            line,
+            "{} {} {}",
+            request.method, request.target, request.version
+        )?,
+        Variable::RequestMethod => write!(line, "{}", request.method)?,
+        Variable::RequestUri => write!(line, "{}", request.target)?,
+        Variable::Uri => line.push_str(request.path()),
+        Variable::Args => line.push_str(request.target.query().unwrap_or_default()),
+        Variable::ServerProtocol => write!(line, "{}", request.version)?,
+        Variable::RequestLength => write!(line, "{}", request.content_length())?,
+        Variable::Status => write!(line, "{}", response.status.as_u16())?,
+        Variable::BodyBytesSent => write!(line, "{}", response.body.len())?,
+        Variable::RequestTime => write!(line, "{:.3}", entry.duration.as_secs_f64())?,
+        Variable::TimeLocal => {
+            let civil = Civil::new(entry.start);
+            write!(
+                line,
+                "{:02}/{}/{:04}:{:02}:{:02}:{:02} +0000",
+                civil.day,
+                MONTHS[usize::from(civil.month - 1)],
+                civil.year,
+                civil.hour,
+                civil.minute,
+                civil.second
+            )?;
+        }
+        Variable::TimeIso8601 => {
+            let civil = Civil::new(entry.start);
+            write!(
+                line,
+                "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}+00:00",
+                civil.year, civil.month, civil.day, civil.hour, civil.minute, civil.second
+            )?;
+        }
+        Variable::Msec => {
+            let elapsed = entry.start.duration_since(UNIX_EPOCH).unwrap_or_default();
+            write!(line, "{}.{:03}", elapsed.as_secs(), elapsed.subsec_millis())?;
+        }
+        Variable::UpstreamAddr => {
+            if let Some(upstream) = &response.upstream {
+                line.push_str(upstream);
+            }
+        }
+        Variable::RequestHeader(name) => {
+            if let Some(value) = request.headers.get(name) {
+                line.push_str(value);
+            }
+        }
+        Variable::ResponseHeader(name) => {
+            if let Some(value) = response.header(name) {
+                line.push_str(value);
+            }
+        }
+        Variable::LogField(name) => {
+            let fields = response.extensions().get::<LogFields>();
+            if let Some(value) = fields.and_then(|fields| fields.get(name)) {
+                line.push_str(value);
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::method::Method;
+    use crate::status::StatusCode;
+
+    /// It fills in variables, writes missing values as dashes, and rejects unknown variables
+    #[test]
+    fn format() {
+        let mut request = Request {
+            method: Method::Get,
+            ..Request::default()
+        };
+        request.target = "/search?q=rust".parse().unwrap();
+        request.peer = Some("192.0.2.7:50123".parse().unwrap());
+        request.headers.append("User-Agent", "curl/8.0");
+        let mut response = Response::new(StatusCode::OK, "hello");
+        response.upstream = Some("10.0.0.2:8080".to_string());
+        let response = response.with_extension(LogFields::new().with("audit", "search"));
+        let entry = AccessEntry {
+            request: &request,
+            response: &response,
+            start: UNIX_EPOCH + Duration::from_secs(971_186_136),
+            duration: Duration::from_millis(1_250),
+        };
+        let format: AccessFormat =
+            "$remote_addr [$time_local] \"$request\" $status ${body_bytes_sent}B $request_time \
+             $upstream_addr $uri?$args \"$http_user_agent\" $http_referer $log_audit $log_user"
+                .parse()
+                .unwrap();
+        assert_eq!(
+            "192.0.2.7 [10/Oct/2000:13:55:36 +0000] \"GET /search?q=rust HTTP/1.1\" 200 5B \
+             1.250 10.0.0.2:8080 /search?q=rust \"curl/8.0\" - search -",
+            format.format(&entry)
+        );
+        assert!(AccessFormat::default()
+            .format(&entry)
+            .ends_with("\"GET /search?q=rust HTTP/1.1\" 200 5 \"-\" \"curl/8.0\""));
+
+        for template in ["$nope", "${status", "cost: $"] {
+            assert!(template.parse::<AccessFormat>().is_err(), "{}", template);
+        }
+    }
+}
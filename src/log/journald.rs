@@ -0,0 +1,105 @@
+//! Sending log messages to the systemd journal through its native protocol, which keeps the
+//! priority and program name as fields instead of parsing them out of a line.
+
+use super::syslog::severity;
+use super::Level;
+use std::fmt;
+use std::os::unix::net::UnixDatagram;
+use std::path::Path;
+use tokio::io;
+
+/// The socket journald receives native messages on.
+pub const SOCKET: &str = "/run/systemd/journal/socket";
+
+/// A connection to journald.
+#[derive(Debug)]
+pub struct Journald {
+    socket: UnixDatagram,
+}
+
+impl Journald {
+    /// Connects to journald at [`SOCKET`].
+    ///
+    /// # Returns
+    ///
+    /// The connection.
+    ///
+    /// # Errors
+    ///
+    /// Captures IO errors from connecting, e.g. on systems without systemd.
+    pub fn connect() -> io::Result<Journald> {
+        Journald::connect_to(Path::new(SOCKET))
+    }
+
+    fn connect_to(path: &Path) -> io::Result<Journald> {
+        let socket = UnixDatagram::unbound()?;
+        socket.connect(path)?;
+        Ok(Journald { socket })
+    }
+
+    /// Sends a message with its level as `PRIORITY` and the server as `SYSLOG_IDENTIFIER`.
+    /// Messages must fit into a single datagram, a few hundred kilobytes by default.
+    ///
+    /// # Arguments
+    ///
+    /// * `level`: The severity of the message.
+    /// * `message`: The message, which may span several lines.
+    ///
+    /// # Errors
+    ///
+    /// Captures IO errors from sending, e.g. when journald restarted.
+    pub fn send(&self, level: Level, message: impl fmt::Display) -> io::Result<()> {
+        self.socket.send(&entry(level, message))?;
+        Ok(())
+    }
+}
+
+/// Serializes the fields of a journal entry.
+fn entry(level: Level, message: impl fmt::Display) -> Vec<u8> {
+    let mut entry = Vec::new();
+    field(&mut entry, "PRIORITY", &severity(level).to_string());
+    field(&mut entry, "SYSLOG_IDENTIFIER", env!("CARGO_PKG_NAME"));
+    field(&mut entry, "MESSAGE", &message.to_string());
+    entry
+}
+
+/// Appends a field as `NAME=value`, or in the length-prefixed form for a value spanning lines.
+fn field(entry: &mut Vec<u8>, name: &str, value: &str) {
+    entry.extend_from_slice(name.as_bytes());
+    if value.contains('\n') {
+        entry.push(b'\n');
+        entry.extend_from_slice(&(value.len() as u64).to_le_bytes());
+    } else {
+        entry.push(b'=');
+    }
+    entry.extend_from_slice(value.as_bytes());
+    entry.push(b'\n');
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// It sends the priority, identifier, and message, length-prefixing values with newlines
+    #[test]
+    fn send() {
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("socket");
+        let journal = UnixDatagram::bind(&path).unwrap();
+        let journald = Journald::connect_to(&path).unwrap();
+        journald.send(Level::Error, "accept failed").unwrap();
+        let mut buffer = [0; 1024];
+        let length = journal.recv(&mut buffer).unwrap();
+        assert_eq!(
+            b"PRIORITY=3\nSYSLOG_IDENTIFIER=web_server_tokio\nMESSAGE=accept failed\n".as_slice(),
+            &buffer[..length]
+        );
+
+        let mut expected = b"MESSAGE\n".to_vec();
+        expected.extend_from_slice(&3u64.to_le_bytes());
+        expected.extend_from_slice(b"a\nb\n");
+        let mut entry = Vec::new();
+        field(&mut entry, "MESSAGE", "a\nb");
+        assert_eq!(expected, entry);
+    }
+}
@@ -0,0 +1,188 @@
+//! Spans giving log messages the context they happen in, such as the connection and request a
+//! handler serves, so the messages of one request can be told apart from those of others.
+
+use std::fmt;
+use std::future::Future;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+tokio::task_local! {
+    /// The innermost span of the running task.
+    static CURRENT: Arc<Span>;
+}
+
+/// The identifier of the next span.
+static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+
+/// A named unit of work with fields, e.g. `connection` with the address of the client. Every
+/// message logged while a span is current is prefixed with it and its parents, as
+/// `connection{id=1 peer=192.0.2.7:50123}:request{method=GET path=/}: message`.
+#[derive(Debug)]
+pub struct Span {
+    name: &'static str,
+    parent: Option<Arc<Span>>,
+    fields: Mutex<Vec<(&'static str, String)>>,
+}
+
+impl Span {
+    /// Creates a span inside the current one, if any.
+    ///
+    /// # Arguments
+    ///
+    /// * `name`: What the span stands for, e.g. `request`.
+    ///
+    /// # Returns
+    ///
+    /// The span, which [`Span::run`] makes current.
+    pub fn new(name: &'static str) -> Span {
+        Span {
+            name,
+            parent: Span::current(),
+            fields: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Creates a span with a field `id` numbering the spans of the process, which tells apart
+    /// units of work whose other fields are the same, e.g. two connections from one client.
+    ///
+    /// # Arguments
+    ///
+    /// * `name`: What the span stands for, e.g. `connection`.
+    ///
+    /// # Returns
+    ///
+    /// The span, which [`Span::run`] makes current.
+    pub fn numbered(name: &'static str) -> Span {
+        Span::new(name).with_field("id", NEXT_ID.fetch_add(1, Ordering::Relaxed))
+    }
+
+    /// Adds a field, see [`Span::record`].
+    pub fn with_field(self, name: &'static str, value: impl fmt::Display) -> Span {
+        self.record(name, value);
+        self
+    }
+
+    /// The innermost span of the running task.
+    ///
+    /// # Returns
+    ///
+    /// The span, or `None` outside of [`Span::run`].
+    pub fn current() -> Option<Arc<Span>> {
+        CURRENT.try_with(Arc::clone).ok()
+    }
+
+    /// Sets a field, replacing an earlier value of the same name. Values with spaces or quotes
+    /// are quoted.
+    ///
+    /// # Arguments
+    ///
+    /// * `name`: The name of the field, e.g. `status`.
+    /// * `value`: Its value.
+    pub fn record(&self, name: &'static str, value: impl fmt::Display) {
+        let mut value = value.to_string();
+        if value.is_empty() || value.contains(|c: char| c.is_whitespace() || c == '"') {
+            value = format!("{:?}", value);
+        }
+        let mut fields = self
+            .fields
+            .lock()
+            .unwrap_or_else(|error| error.into_inner());
+        match fields.iter_mut().find(|(known, _)| *known == name) {
+            Some((_, old)) => *old = value,
+            None => fields.push((name, value)),
+        }
+    }
+
+    /// Runs a future with this span as the current one.
+    ///
+    /// # Arguments
+    ///
+    /// * `future`: The work the span stands for.
+    ///
+    /// # Returns
+    ///
+    /// The output of the future.
+    pub async fn run<F: Future>(self: Arc<Self>, future: F) -> F::Output {
+        CURRENT.scope(self, future).await
+    }
+
+    /// Calls a function with this span as the current one, e.g. to log a message about a unit
+    /// of work after it ended.
+    ///
+    /// # Arguments
+    ///
+    /// * `function`: The function.
+    ///
+    /// # Returns
+    ///
+    /// What the function returns.
+    pub fn in_scope<R>(self: Arc<Self>, function: impl FnOnce() -> R) -> R {
+        CURRENT.sync_scope(self, function)
+    }
+}
+
+impl fmt::Display for Span {
+    fn fmt(&self, formatter: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(parent) = &self.parent {
+            write!(formatter, "{}:", parent)?;
+        }
+        formatter.write_str(self.name)?;
+        let fields = self
+            .fields
+            .lock()
+            .unwrap_or_else(|error| error.into_inner());
+        for (index, (name, value)) in fields.iter().enumerate() {
+            let separator = if index == 0 { '{' } else { ' ' };
+            write!(formatter, "{}{}={}", separator, name, value)?;
+        }
+        if !fields.is_empty() {
+            formatter.write_str("}")?;
+        }
+        Ok(())
+    }
+}
+
+/// Sets a field of the current span, e.g. for a handler to name the user it authenticated.
+/// Does nothing outside of a span.
+///
+/// # Arguments
+///
+/// * `name`: The name of the field.
+/// * `value`: Its value.
+pub fn record(name: &'static str, value: impl fmt::Display) {
+    if let Some(span) = Span::current() {
+        span.record(name, value);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// It nests spans, replaces recorded fields, and quotes values with spaces
+    #[tokio::test]
+    async fn nest() {
+        let connection = Arc::new(Span::new("connection").with_field("peer", "192.0.2.7:50123"));
+        let request = connection
+            .run(async {
+                let request = Arc::new(Span::new("request").with_field("method", "GET"));
+                request
+                    .clone()
+                    .run(async {
+                        record("status", 404);
+                        record("status", 200);
+                        record("user", "Jane Doe");
+                    })
+                    .await;
+                request
+            })
+            .await;
+        assert_eq!(
+            "connection{peer=192.0.2.7:50123}:request{method=GET status=200 user=\"Jane Doe\"}",
+            request.to_string()
+        );
+        assert!(Span::current().is_none());
+        let numbered = Span::numbered("connection").to_string();
+        assert!(numbered.starts_with("connection{id="), "{}", numbered);
+    }
+}
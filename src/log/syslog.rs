@@ -0,0 +1,281 @@
+//! Sending log messages to a syslog daemon as RFC 5424 datagrams, over UDP or a Unix socket
+//! such as `/dev/log`.
+
+use super::Level;
+use crate::date::Civil;
+use std::fmt::{self, Write as _};
+use std::net::{SocketAddr, UdpSocket};
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::SystemTime;
+use tokio::io;
+
+/// Where a [`Syslog`] sends its messages.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Target {
+    /// A daemon listening for UDP datagrams, usually on port 514.
+    Udp(SocketAddr),
+    /// A local daemon listening on a Unix datagram socket, usually `/dev/log`.
+    Unix(PathBuf),
+}
+
+impl Default for Target {
+    /// The local daemon at `/dev/log`.
+    fn default() -> Target {
+        Target::Unix(PathBuf::from("/dev/log"))
+    }
+}
+
+impl FromStr for Target {
+    type Err = ();
+
+    /// Parses `udp://<address>`, e.g. `udp://10.0.0.5:514`, or the path of a Unix socket.
+    fn from_str(value: &str) -> Result<Target, ()> {
+        match value.strip_prefix("udp://") {
+            Some(address) => address.parse().map(Target::Udp).map_err(|_| ()),
+            None if !value.is_empty() => Ok(Target::Unix(PathBuf::from(value))),
+            None => Err(()),
+        }
+    }
+}
+
+/// The kind of program a message comes from, which syslog daemons route by.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Facility(u8);
+
+impl Facility {
+    /// System daemons, the default of the server.
+    pub const DAEMON: Facility = Facility(3);
+
+    const NAMES: [(&'static str, u8); 20] = [
+        ("kern", 0),
+        ("user", 1),
+        ("mail", 2),
+        ("daemon", 3),
+        ("auth", 4),
+        ("syslog", 5),
+        ("lpr", 6),
+        ("news", 7),
+        ("uucp", 8),
+        ("cron", 9),
+        ("authpriv", 10),
+        ("ftp", 11),
+        ("local0", 16),
+        ("local1", 17),
+        ("local2", 18),
+        ("local3", 19),
+        ("local4", 20),
+        ("local5", 21),
+        ("local6", 22),
+        ("local7", 23),
+    ];
+}
+
+impl Default for Facility {
+    fn default() -> Facility {
+        Facility::DAEMON
+    }
+}
+
+impl FromStr for Facility {
+    type Err = ();
+
+    /// Parses a facility name, e.g. `local0`, ignoring ASCII case.
+    fn from_str(name: &str) -> Result<Facility, ()> {
+        Facility::NAMES
+            .iter()
+            .find(|(known, _)| known.eq_ignore_ascii_case(name.trim()))
+            .map(|(_, code)| Facility(*code))
+            .ok_or(())
+    }
+}
+
+/// The syslog severity of a level.
+pub(crate) fn severity(level: Level) -> u8 {
+    match level {
+        Level::Error => 3,
+        Level::Warn => 4,
+        Level::Info => 6,
+        Level::Debug => 7,
+    }
+}
+
+/// A connection to a syslog daemon.
+#[derive(Debug)]
+pub struct Syslog {
+    socket: Socket,
+    facility: Facility,
+    hostname: String,
+    app_name: String,
+}
+
+#[derive(Debug)]
+enum Socket {
+    Udp(UdpSocket),
+    #[cfg(unix)]
+    Unix(std::os::unix::net::UnixDatagram),
+}
+
+impl Syslog {
+    /// Connects to a syslog daemon.
+    ///
+    /// # Arguments
+    ///
+    /// * `target`: Where the daemon listens.
+    /// * `facility`: The facility of every message.
+    ///
+    /// # Returns
+    ///
+    /// The connection, sending messages as `web_server_tokio`.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`io::ErrorKind::Unsupported`] for a Unix socket on other platforms, and
+    /// captures IO errors from connecting, e.g. when no daemon listens on the socket.
+    pub fn connect(target: &Target, facility: Facility) -> io::Result<Syslog> {
+        let socket = match target {
+            Target::Udp(address) => {
+                let local = match address {
+                    SocketAddr::V4(_) => SocketAddr::from(([0, 0, 0, 0], 0)),
+                    SocketAddr::V6(_) => SocketAddr::from(([0u16; 8], 0)),
+                };
+                let socket = UdpSocket::bind(local)?;
+                socket.connect(address)?;
+                Socket::Udp(socket)
+            }
+            #[cfg(unix)]
+            Target::Unix(path) => {
+                let socket = std::os::unix::net::UnixDatagram::unbound()?;
+                socket.connect(path)?;
+                Socket::Unix(socket)
+            }
+            #[cfg(not(unix))]
+            Target::Unix(_) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::Unsupported,
+                    "syslog over a Unix socket needs Unix",
+                ))
+            }
+        };
+        Ok(Syslog {
+            socket,
+            facility,
+            hostname: hostname().unwrap_or_else(|| "-".to_string()),
+            app_name: env!("CARGO_PKG_NAME").to_string(),
+        })
+    }
+
+    /// Sends a message.
+    ///
+    /// # Arguments
+    ///
+    /// * `level`: The severity of the message.
+    /// * `message`: The message.
+    ///
+    /// # Errors
+    ///
+    /// Captures IO errors from sending, e.g. when the daemon restarted.
+    pub fn send(&self, level: Level, message: impl fmt::Display) -> io::Result<()> {
+        let datagram = self.format(level, SystemTime::now(), message);
+        match &self.socket {
+            Socket::Udp(socket) => socket.send(datagram.as_bytes())?,
+            #[cfg(unix)]
+            Socket::Unix(socket) => socket.send(datagram.as_bytes())?,
+        };
+        Ok(())
+    }
+
+    /// Formats a message as an RFC 5424 datagram without structured data.
+    fn format(&self, level: Level, time: SystemTime, message: impl fmt::Display) -> String {
+        let mut datagram = String::new();
+        let _ = write!(
+            datagram,
+            "<{}>1 {} {} {} {} - - {}",
+            u16::from(self.facility.0) * 8 + u16::from(severity(level)),
+            Timestamp(time),
+            self.hostname,
+            self.app_name,
+            std::process::id(),
+            message
+        );
+        datagram
+    }
+}
+
+/// Formats a time as an RFC 3339 timestamp in UTC with milliseconds, e.g.
+/// `2024-05-01T12:30:00.250Z`.
+struct Timestamp(SystemTime);
+
+impl fmt::Display for Timestamp {
+    fn fmt(&self, formatter: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let civil = Civil::new(self.0);
+        write!(
+            formatter,
+            "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+            civil.year,
+            civil.month,
+            civil.day,
+            civil.hour,
+            civil.minute,
+            civil.second,
+            civil.millisecond
+        )
+    }
+}
+
+/// The host name of the machine, `None` when it cannot be read.
+#[cfg(unix)]
+fn hostname() -> Option<String> {
+    let mut buffer = [0u8; 256];
+    // SAFETY: The buffer is valid for its length, which the call does not exceed
+    if unsafe { libc::gethostname(buffer.as_mut_ptr().cast(), buffer.len()) } != 0 {
+        return None;
+    }
+    let end = buffer.iter().position(|byte| *byte == 0)?;
+    String::from_utf8(buffer[..end].to_vec())
+        .ok()
+        .filter(|name| !name.is_empty())
+}
+
+#[cfg(not(unix))]
+fn hostname() -> Option<String> {
+    std::env::var("COMPUTERNAME").ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::{Duration, UNIX_EPOCH};
+
+    /// It sends RFC 5424 datagrams with the priority of the facility and level
+    #[test]
+    fn send() {
+        let daemon = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let target = format!("udp://{}", daemon.local_addr().unwrap());
+        let facility = "LOCAL3".parse().unwrap();
+        let syslog = Syslog::connect(&target.parse().unwrap(), facility).unwrap();
+        syslog.send(Level::Warn, "upstream down").unwrap();
+        let mut buffer = [0; 1024];
+        let length = daemon.recv(&mut buffer).unwrap();
+        let datagram = std::str::from_utf8(&buffer[..length]).unwrap();
+        assert!(datagram.starts_with("<156>1 "), "{}", datagram);
+        assert!(datagram.ends_with(&format!(
+            " web_server_tokio {} - - upstream down",
+            std::process::id()
+        )));
+
+        let time = UNIX_EPOCH + Duration::from_millis(951_827_696_789);
+        assert_eq!(
+            "<158>1 2000-02-29T12:34:56.789Z host app 1 - - started",
+            Syslog {
+                hostname: "host".to_string(),
+                app_name: "app".to_string(),
+                ..syslog
+            }
+            .format(Level::Info, time, "started")
+            .replace(&format!(" {} ", std::process::id()), " 1 ")
+        );
+        assert_eq!(Err(()), "udp://nowhere".parse::<Target>());
+        assert_eq!(Err(()), "local9".parse::<Facility>());
+    }
+}
@@ -1,44 +1,417 @@
+use clap::{Parser, Subcommand};
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
 use tokio::io;
 use tokio::net;
-use web_server_tokio::handle_stream;
+#[cfg(unix)]
+use tokio::signal;
+use tokio::task;
+use web_server_tokio::config::Config;
+use web_server_tokio::log::file::LogFile;
+use web_server_tokio::log::Sink;
+use web_server_tokio::sandbox;
+use web_server_tokio::server::{PerCoreSockets, Server};
+#[cfg(unix)]
+use web_server_tokio::upgrade::{self, Inherited};
+use web_server_tokio::{admin, check, dotenv, log};
+#[cfg(unix)]
+use web_server_tokio::{daemon, privileges};
 
-/// `main` creates a TCP listener, spawns a task for each incoming connection, and awaits for all tasks
-/// to complete
+/// `main` parses the command line, see [`Cli`], and runs its subcommand, serving by default.
+///
+/// Serving builds a runtime with the thread settings of the configuration, creates a TCP
+/// listener, and serves connections on it until the admin API drains or shuts down the server,
+/// on a listener and runtime per thread with `WEB_SERVER_THREAD_PER_CORE`, through io_uring
+/// with `WEB_SERVER_IO_URING`. `SIGHUP` reloads the configuration, `SIGTERM` drains, and
+/// `SIGUSR1` reopens the log files, and `SIGUSR2` upgrades to the binary at the same path
+/// without dropping connections. With `WEB_SERVER_SANDBOX`, the process confines itself before
+/// starting any threads, see [`sandbox::Sandbox::apply`].
 ///
 /// # Errors
 ///
-/// Captures errors from binding to address `127.0.0.1:7878`. Writes errors from accepting stream
-/// or handling connections to stderr.
-#[tokio::main]
-async fn main() -> io::Result<()> {
-    let listener = net::TcpListener::bind("127.0.0.1:7878").await?;
-    let capacity = 10;
-    let mut tasks = Vec::with_capacity(capacity);
-
-    for count in 1..=capacity {
-        let stream = match listener.accept().await {
-            Ok((stream, _)) => stream,
-            Err(error) => {
-                dbg!(error);
-                continue;
+/// Captures errors from loading the `.env` file, reading the configuration from the
+/// environment, opening the log files or connecting to the log daemon, detaching, writing the
+/// PID file, looking up the account, sandboxing, building the runtime, and binding to the
+/// address, the HTTPS address, or the admin address. Logs errors from accepting streams or
+/// handling connections to stderr. Invalid arguments print the usage and exit with status 2.
+fn main() -> io::Result<()> {
+    let cli = Cli::parse();
+    if let Some(path) = &cli.env_file {
+        // No other thread runs yet that could read the environment meanwhile
+        dotenv::load(path)?;
+    }
+    let config_path = cli.config.clone();
+    match cli.command.unwrap_or(Command::Serve(cli.serve)) {
+        Command::Serve(serve) => self::serve(serve, config_path),
+        Command::Check => check(config_path.as_deref()),
+        Command::Routes => {
+            print!("{}", admin::describe_routes(&load(config_path.as_deref())?));
+            Ok(())
+        }
+        Command::Version => {
+            println!("{} {}", env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION"));
+            Ok(())
+        }
+    }
+}
+
+/// Serves files, WebDAV, uploads, and reverse proxies over HTTP/1.1 and HTTPS.
+#[derive(Debug, Parser)]
+#[command(version, about, args_conflicts_with_subcommands = true)]
+struct Cli {
+    /// Reads the configuration file at this path instead of the one of `WEB_SERVER_CONFIG`.
+    #[arg(long, global = true, value_name = "PATH")]
+    config: Option<PathBuf>,
+    /// Sets the variables of this `.env` file that are not set yet before reading the
+    /// configuration.
+    #[arg(long, global = true, value_name = "PATH")]
+    env_file: Option<PathBuf>,
+    #[command(subcommand)]
+    command: Option<Command>,
+    /// The flags of `serve`, for running without a subcommand.
+    #[command(flatten)]
+    serve: Serve,
+}
+
+/// The subcommands of [`Cli`].
+#[derive(Debug, Subcommand)]
+enum Command {
+    /// Serves until the server is drained or shut down, the default.
+    Serve(Serve),
+    /// Validates the configuration without binding any sockets, exiting with status 1 when it
+    /// has problems.
+    Check,
+    /// Prints the routes of the application and the built-in handlers.
+    Routes,
+    /// Prints the name and version.
+    Version,
+}
+
+/// The flags of [`Command::Serve`], for init scripts on Unix.
+#[derive(Debug, clap::Args)]
+struct Serve {
+    /// The address to listen on for HTTP.
+    #[arg(long, default_value = "127.0.0.1:7878")]
+    address: SocketAddr,
+    /// The document root, instead of the one of `WEB_SERVER_ROOT`.
+    #[arg(long, value_name = "PATH")]
+    root: Option<PathBuf>,
+    /// Detaches from the terminal.
+    #[arg(long)]
+    daemon: bool,
+    /// Writes the ID of the serving process to this file.
+    #[arg(long, value_name = "PATH")]
+    pid_file: Option<PathBuf>,
+    /// Appends the output of a detached server to this file instead of discarding it.
+    #[arg(long, value_name = "PATH")]
+    log_file: Option<PathBuf>,
+}
+
+/// Serves until the server stops, see `main`.
+fn serve(serve: Serve, config_path: Option<PathBuf>) -> io::Result<()> {
+    if let Some(root) = &serve.root {
+        // Like the `.env` file, before any other thread starts, so reloads keep the root
+        std::env::set_var("WEB_SERVER_ROOT", root);
+    }
+    let config = load(config_path.as_deref())?;
+    log::set_level(config.log_level);
+    log::set_sink(Sink::open(&config.log_output, config.log_rotation)?);
+    if let Some(path) = &config.access_log {
+        log::set_access_log(Some(LogFile::open(path, config.log_rotation)?));
+    }
+    #[cfg(unix)]
+    if serve.daemon {
+        daemon::detach(serve.log_file.as_deref())?;
+    }
+    #[cfg(unix)]
+    let _pid_file = serve.pid_file.map(daemon::PidFile::create).transpose()?;
+    #[cfg(not(unix))]
+    if serve.daemon || serve.pid_file.is_some() {
+        return Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "daemons and PID files need Unix",
+        ));
+    }
+    let account = lookup_account(&config)?;
+    if let Some(sandbox) = &config.sandbox {
+        sandbox.apply()?;
+    }
+    let mut server = Server::new(sandbox::rebase(config))
+        .with_loader(move || load(config_path.as_deref()).map(sandbox::rebase));
+    if let Some(threads) = server.config().worker_threads {
+        server = server.with_worker_threads(threads);
+    }
+    if let Some(threads) = server.config().max_blocking_threads {
+        server = server.with_max_blocking_threads(threads);
+    }
+    server.block_on(run(server.clone(), account, serve.address))?
+}
+
+/// Reads the configuration from the environment and the file of `--config`, if any.
+fn load(path: Option<&Path>) -> io::Result<Config> {
+    match path {
+        Some(path) => Config::from_file(path),
+        None => Config::from_env(),
+    }
+}
+
+/// Prints the problems of the configuration, see [`check::check`], and exits with status 1 when
+/// there are any.
+fn check(path: Option<&Path>) -> io::Result<()> {
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()?;
+    let problems = runtime.block_on(check::check(path))?;
+    for problem in &problems {
+        eprintln!("{}", problem);
+    }
+    if !problems.is_empty() {
+        eprintln!("{} problem(s) found", problems.len());
+        std::process::exit(1);
+    }
+    println!("configuration is valid");
+    Ok(())
+}
+
+/// Serves with the server until it stops, on the runtime built by `serve`, with HTTP on the
+/// address.
+async fn run(server: Server, account: Account, address: SocketAddr) -> io::Result<()> {
+    let admin_address = server.config().admin.as_ref().map(|admin| admin.address);
+    let mut inherited = Inherited::from_env()?;
+    #[cfg(unix)]
+    {
+        let mut hangup = signal::unix::signal(signal::unix::SignalKind::hangup())?;
+        let reloading = server.clone();
+        tokio::spawn(async move {
+            while hangup.recv().await.is_some() {
+                // Failures are logged by the server, which keeps the current configuration
+                let _ = reloading.reload();
             }
-        };
-        let task = tokio::spawn(async move {
-            match handle_stream(Box::new(stream)).await {
-                Ok(()) => {
-                    println!("Completed request {}.", count);
-                }
-                Err(error) => {
-                    dbg!(error);
+        });
+        let mut user_defined1 = signal::unix::signal(signal::unix::SignalKind::user_defined1())?;
+        tokio::spawn(async move {
+            while user_defined1.recv().await.is_some() {
+                if let Err(error) = log::reopen() {
+                    log::error(format_args!("reopening the logs failed: {}", error));
                 }
             }
         });
-        tasks.push(task);
+        // Draining lets `main` return and remove the PID file
+        let mut terminate = signal::unix::signal(signal::unix::SignalKind::terminate())?;
+        let draining = server.clone();
+        tokio::spawn(async move {
+            if terminate.recv().await.is_some() {
+                draining.drain();
+            }
+        });
     }
+    let mut passed = Vec::new();
+    if let Some(address) = admin_address {
+        let admin_listener = inherited.listen("admin", address).await?;
+        log::info(format_args!(
+            "admin API listening on {}",
+            admin_listener.local_addr()?
+        ));
+        pass(&mut passed, "admin", &admin_listener)?;
+        let admin_server = server.clone();
+        tokio::spawn(async move {
+            if let Err(error) = admin::serve(admin_listener, admin_server).await {
+                log::error(format_args!("the admin API stopped: {}", error));
+            }
+        });
+    }
+    if let Some(threads) = server.config().thread_per_core {
+        type Serve = fn(&Server, PerCoreSockets) -> io::Result<()>;
+        #[allow(unused_mut)]
+        let mut serve: Serve = Server::serve_per_core;
+        #[cfg(feature = "tls")]
+        #[allow(unused_mut)]
+        let mut serve_tls: Serve = Server::serve_tls_per_core;
+        #[cfg(all(feature = "io-uring", target_os = "linux"))]
+        if server.config().io_uring {
+            serve = Server::serve_uring;
+            #[cfg(feature = "tls")]
+            {
+                serve_tls = Server::serve_tls_uring;
+            }
+        }
+        // The new threads bind next to those of an old process through SO_REUSEPORT
+        let sockets = PerCoreSockets::bind(address, threads)?;
+        log::info(format_args!(
+            "listening on {} with a runtime per thread",
+            sockets.local_addr()?
+        ));
+        #[cfg(feature = "tls")]
+        let tls_sockets = match server.config().tls.as_ref().map(|tls| tls.address()) {
+            Some(address) => {
+                let sockets = PerCoreSockets::bind(address, threads)?;
+                log::info(format_args!(
+                    "listening for HTTPS on {} with a runtime per thread",
+                    sockets.local_addr()?
+                ));
+                Some(sockets)
+            }
+            None => None,
+        };
+        drop_privileges(account)?;
+        upgrade_on_signal(&server, &mut inherited, passed)?;
+        let plain = task::spawn_blocking({
+            let server = server.clone();
+            move || serve(&server, sockets)
+        });
+        #[cfg(feature = "tls")]
+        if let Some(sockets) = tls_sockets {
+            let server = server.clone();
+            let secure = task::spawn_blocking(move || serve_tls(&server, sockets));
+            tokio::try_join!(async { plain.await? }, async { secure.await? })?;
+            return Ok(());
+        }
+        return plain.await?;
+    }
+    let listener = inherited.listen("http", address).await?;
+    log::info(format_args!("listening on {}", listener.local_addr()?));
+    pass(&mut passed, "http", &listener)?;
+    #[cfg(feature = "tls")]
+    if let Some(address) = server.config().tls.as_ref().map(|tls| tls.address()) {
+        let tls_listener = inherited.listen("https", address).await?;
+        log::info(format_args!(
+            "listening for HTTPS on {}",
+            tls_listener.local_addr()?
+        ));
+        pass(&mut passed, "https", &tls_listener)?;
+        drop_privileges(account)?;
+        upgrade_on_signal(&server, &mut inherited, passed)?;
+        tokio::try_join!(server.serve(listener), server.serve_tls(tls_listener))?;
+        return Ok(());
+    }
+    drop_privileges(account)?;
+    upgrade_on_signal(&server, &mut inherited, passed)?;
+    server.serve(listener).await
+}
+
+/// The account to switch to once every listener is bound, looked up before sandboxing.
+#[cfg(unix)]
+type Account = Option<privileges::Account>;
 
-    // Without awaiting for tasks, main thread will exit before slowest task completes
-    for task in tasks {
-        let _ = task.await;
+#[cfg(not(unix))]
+type Account = ();
+
+/// Looks up the configured user and group, see [`privileges::Account::lookup`].
+///
+/// # Errors
+///
+/// The errors of [`privileges::Account::lookup`].
+#[cfg(unix)]
+fn lookup_account(config: &Config) -> io::Result<Account> {
+    privileges::Account::lookup(config.user.as_deref(), config.group.as_deref())
+}
+
+#[cfg(not(unix))]
+fn lookup_account(config: &Config) -> io::Result<Account> {
+    if config.user.is_some() || config.group.is_some() {
+        return Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "dropping privileges needs Unix",
+        ));
     }
     Ok(())
 }
+
+/// Switches to the configured account once every listener is bound, see
+/// [`privileges::drop_privileges`].
+///
+/// # Errors
+///
+/// The errors of [`privileges::Account::switch`].
+#[cfg(unix)]
+fn drop_privileges(account: Account) -> io::Result<()> {
+    account.map_or(Ok(()), |account| account.switch())
+}
+
+#[cfg(not(unix))]
+fn drop_privileges(_account: Account) -> io::Result<()> {
+    Ok(())
+}
+
+/// The listening sockets passed on by an upgrade, see [`upgrade_on_signal`].
+#[cfg(unix)]
+type Passed = Vec<(&'static str, std::os::fd::OwnedFd)>;
+
+#[cfg(not(unix))]
+type Passed = Vec<()>;
+
+/// Keeps a copy of a listening socket to pass on by an upgrade.
+///
+/// # Errors
+///
+/// Captures IO errors from duplicating the socket.
+#[cfg(unix)]
+fn pass(passed: &mut Passed, name: &'static str, listener: &net::TcpListener) -> io::Result<()> {
+    use std::os::fd::AsFd;
+    passed.push((name, listener.as_fd().try_clone_to_owned()?));
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn pass(_passed: &mut Passed, _name: &'static str, _listener: &net::TcpListener) -> io::Result<()> {
+    Ok(())
+}
+
+/// Reports this process ready to the one that upgraded to it, and on `SIGUSR2` upgrades to a
+/// new binary with the passed sockets and drains once it serves, see [`upgrade::upgrade`].
+///
+/// # Errors
+///
+/// Captures IO errors from reporting ready and listening for the signal.
+#[cfg(unix)]
+fn upgrade_on_signal(server: &Server, inherited: &mut Inherited, passed: Passed) -> io::Result<()> {
+    use std::os::fd::AsFd;
+    inherited.ready()?;
+    let mut signal = signal::unix::signal(signal::unix::SignalKind::user_defined2())?;
+    let server = server.clone();
+    tokio::spawn(async move {
+        while signal.recv().await.is_some() {
+            let listeners: Vec<_> = passed
+                .iter()
+                .map(|(name, fd)| (*name, fd.as_fd()))
+                .collect();
+            match upgrade::upgrade(&listeners).await {
+                Ok(()) => {
+                    log::info("the new process serves, draining");
+                    server.drain();
+                    return;
+                }
+                Err(error) => log::error(format_args!("upgrading failed: {}", error)),
+            }
+        }
+    });
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn upgrade_on_signal(
+    _server: &Server,
+    inherited: &mut Inherited,
+    _passed: Passed,
+) -> io::Result<()> {
+    inherited.ready()
+}
+
+/// Stands in for the sockets inherited through an upgrade, which needs Unix.
+#[cfg(not(unix))]
+struct Inherited;
+
+#[cfg(not(unix))]
+impl Inherited {
+    fn from_env() -> io::Result<Inherited> {
+        Ok(Inherited)
+    }
+
+    async fn listen(&mut self, _name: &str, address: SocketAddr) -> io::Result<net::TcpListener> {
+        net::TcpListener::bind(address).await
+    }
+
+    fn ready(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
@@ -0,0 +1,187 @@
+//! Maintenance mode for planned downtime: every request outside an allow-list is answered with
+//! 503 Service Unavailable and a maintenance page, while health checks report what the load
+//! balancer should see.
+
+use crate::request::Request;
+use crate::response::Response;
+use crate::status::StatusCode;
+use std::fmt;
+use std::sync::atomic::{AtomicU8, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// The page served when no other one is configured.
+const DEFAULT_PAGE: &str = "<!DOCTYPE html>\n<html lang=\"en\">\n<head><meta charset=\"utf-8\">\
+                            <title>Down for maintenance</title></head>\n<body><h1>Down for \
+                            maintenance</h1><p>Please try again later.</p></body>\n</html>\n";
+
+/// How the server answers while in maintenance.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Maintenance {
+    /// Starts in maintenance, unless the admin API turns it off, see [`MaintenanceSwitch`].
+    pub enabled: bool,
+    /// Path prefixes still handled as usual, e.g. `/status`.
+    pub allow: Vec<String>,
+    /// Path prefixes of health checks, e.g. `/health`.
+    pub health: Vec<String>,
+    /// Keeps the health checks handled as usual when `true`, so that the load balancer keeps
+    /// sending traffic to the maintenance page, or answers them with an empty 503 when `false`
+    /// to take the server out of rotation.
+    pub healthy: bool,
+    /// The HTML body of the 503 responses.
+    pub page: String,
+    /// The `Retry-After` of the 503 responses.
+    pub retry_after: Option<Duration>,
+}
+
+impl Maintenance {
+    /// Creates settings allowing nothing, with healthy health checks and a built-in page.
+    pub fn new() -> Maintenance {
+        Maintenance {
+            enabled: false,
+            allow: Vec::new(),
+            health: Vec::new(),
+            healthy: true,
+            page: DEFAULT_PAGE.to_string(),
+            retry_after: None,
+        }
+    }
+
+    /// Keeps handling the requests below a path prefix.
+    pub fn with_allowed(mut self, path: impl Into<String>) -> Maintenance {
+        self.allow.push(path.into());
+        self
+    }
+
+    /// Treats the requests below a path prefix as health checks.
+    pub fn with_health(mut self, path: impl Into<String>) -> Maintenance {
+        self.health.push(path.into());
+        self
+    }
+
+    /// Answers a request while in maintenance.
+    ///
+    /// # Arguments
+    ///
+    /// * `request`: The request about to be handled.
+    ///
+    /// # Returns
+    ///
+    /// `None` for requests handled as usual: those below [`Maintenance::allow`], and those below
+    /// [`Maintenance::health`] when [`Maintenance::healthy`]. Otherwise 503 Service Unavailable,
+    /// empty for health checks and with [`Maintenance::page`] for everything else.
+    pub fn respond(&self, request: &Request) -> Option<Response> {
+        let path = request.path();
+        let below = |prefix: &String| {
+            path.strip_prefix(prefix.trim_end_matches('/'))
+                .is_some_and(|rest| rest.is_empty() || rest.starts_with('/'))
+        };
+        let health = self.health.iter().any(below);
+        if (health && self.healthy) || self.allow.iter().any(below) {
+            return None;
+        }
+        let mut response = if health {
+            Response::new(StatusCode::SERVICE_UNAVAILABLE, "")
+        } else {
+            Response::new(StatusCode::SERVICE_UNAVAILABLE, self.page.clone())
+                .with_header("Content-Type", "text/html; charset=utf-8")
+        };
+        response = response.with_header("Cache-Control", "no-store");
+        if let Some(retry_after) = self.retry_after {
+            response = response.with_header("Retry-After", retry_after.as_secs().to_string());
+        }
+        Some(response)
+    }
+}
+
+impl Default for Maintenance {
+    fn default() -> Maintenance {
+        Maintenance::new()
+    }
+}
+
+/// Whether the admin API turned maintenance on or off, shared by every clone, e.g. the copy in
+/// [`crate::config::Config::maintenance_switch`] and the one the admin API writes, so that the
+/// choice outlives configuration reloads.
+#[derive(Clone, Default)]
+pub struct MaintenanceSwitch {
+    state: Arc<AtomicU8>,
+}
+
+impl MaintenanceSwitch {
+    const CONFIGURED: u8 = 0;
+    const ON: u8 = 1;
+    const OFF: u8 = 2;
+
+    /// Overrides [`Maintenance::enabled`], or follows it again with `None`.
+    pub fn set(&self, active: Option<bool>) {
+        let state = match active {
+            None => MaintenanceSwitch::CONFIGURED,
+            Some(true) => MaintenanceSwitch::ON,
+            Some(false) => MaintenanceSwitch::OFF,
+        };
+        self.state.store(state, Ordering::Relaxed);
+    }
+
+    /// The override of the admin API, if any.
+    pub fn get(&self) -> Option<bool> {
+        match self.state.load(Ordering::Relaxed) {
+            MaintenanceSwitch::ON => Some(true),
+            MaintenanceSwitch::OFF => Some(false),
+            _ => None,
+        }
+    }
+
+    /// Whether the server is in maintenance, by the override or else by the settings.
+    pub fn is_active(&self, maintenance: &Maintenance) -> bool {
+        self.get().unwrap_or(maintenance.enabled)
+    }
+}
+
+impl fmt::Debug for MaintenanceSwitch {
+    fn fmt(&self, formatter: &mut fmt::Formatter<'_>) -> fmt::Result {
+        formatter
+            .debug_struct("MaintenanceSwitch")
+            .field("active", &self.get())
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// It answers everything but allowed paths and healthy health checks with 503
+    #[test]
+    fn respond() {
+        let request = |target: &str| Request {
+            target: target.parse().unwrap(),
+            ..Request::default()
+        };
+        let mut maintenance = Maintenance::new()
+            .with_allowed("/status/")
+            .with_health("/health");
+        maintenance.retry_after = Some(Duration::from_secs(120));
+        assert_eq!(None, maintenance.respond(&request("/status")));
+        assert_eq!(None, maintenance.respond(&request("/health?full=1")));
+        let response = maintenance.respond(&request("/statuses")).unwrap();
+        assert_eq!(StatusCode::SERVICE_UNAVAILABLE, response.status);
+        assert_eq!(Some("120"), response.headers.get("Retry-After"));
+        assert!(response.body.starts_with(b"<!DOCTYPE html>"));
+
+        maintenance.healthy = false;
+        let response = maintenance.respond(&request("/health")).unwrap();
+        assert_eq!(StatusCode::SERVICE_UNAVAILABLE, response.status);
+        assert!(response.body.is_empty());
+
+        let switch = MaintenanceSwitch::default();
+        assert!(!switch.is_active(&maintenance));
+        switch.clone().set(Some(true));
+        assert!(switch.is_active(&maintenance));
+        maintenance.enabled = true;
+        switch.set(Some(false));
+        assert!(!switch.is_active(&maintenance));
+        switch.set(None);
+        assert!(switch.is_active(&maintenance));
+    }
+}
@@ -0,0 +1,261 @@
+use crate::config::MarkdownConfig;
+use crate::method::Method;
+use crate::path;
+use crate::request::Request;
+use crate::response::Response;
+use crate::status::StatusCode;
+use std::path::{Path, PathBuf};
+use tokio::{fs, io};
+
+/// The page used when [`MarkdownConfig::template`] is absent.
+pub const DEFAULT_TEMPLATE: &str = "<!DOCTYPE html>
+<html lang=\"en\">
+<head>
+    <meta charset=\"utf-8\">
+    <title>{{title}}</title>
+</head>
+<body>
+{{content}}</body>
+</html>";
+
+/// Renders the Markdown file a GET request maps to.
+///
+/// `/notes.md` and the extensionless `/notes` both map to `notes.md` below the document root,
+/// and a path ending in `/` maps to `index.md` in that directory.
+///
+/// # Arguments
+///
+/// * `request`: The incoming request.
+/// * `config`: The Markdown settings.
+/// * `root`: The document root.
+/// * `case_insensitive`: Finds the file whatever the case of the path, see
+///   [`path::match_case`].
+///
+/// # Returns
+///
+/// A 200 OK HTML page, or `None` when the request does not map to an existing Markdown file.
+///
+/// # Errors
+///
+/// Captures IO errors from reading the Markdown file or the template.
+pub async fn handle(
+    request: &Request,
+    config: &MarkdownConfig,
+    root: &Path,
+    case_insensitive: bool,
+) -> io::Result<Option<Response>> {
+    if request.method != Method::Get {
+        return Ok(None);
+    }
+    let mut source = match markdown_path(root, request.path()) {
+        Some(source) => source,
+        None => return Ok(None),
+    };
+    if case_insensitive {
+        source = path::match_case(root, &source).await;
+    }
+    let markdown = match fs::read_to_string(&source).await {
+        Ok(markdown) => markdown,
+        Err(error)
+            if error.kind() == io::ErrorKind::NotFound
+                || error.kind() == io::ErrorKind::IsADirectory =>
+        {
+            return Ok(None)
+        }
+        Err(error) => return Err(error),
+    };
+    let template = match &config.template {
+        Some(template) => fs::read_to_string(template).await?,
+        None => DEFAULT_TEMPLATE.to_string(),
+    };
+    let title = title(&markdown).unwrap_or_else(|| {
+        source
+            .file_stem()
+            .unwrap_or_default()
+            .to_string_lossy()
+            .into_owned()
+    });
+    let page = render(&template, &title, &markdown);
+    Ok(Some(
+        Response::new(StatusCode::OK, page).with_header("Content-Type", "text/html; charset=utf-8"),
+    ))
+}
+
+/// Places rendered Markdown into a template.
+///
+/// # Arguments
+///
+/// * `template`: HTML containing `{{title}}` and `{{content}}` placeholders.
+/// * `title`: The page title, escaped before insertion.
+/// * `markdown`: The Markdown source.
+///
+/// # Returns
+///
+/// The complete HTML page.
+pub fn render(template: &str, title: &str, markdown: &str) -> String {
+    let mut content = String::new();
+    let parser = pulldown_cmark::Parser::new_ext(markdown, pulldown_cmark::Options::all());
+    pulldown_cmark::html::push_html(&mut content, parser);
+    let title = escape_html(title);
+    // In one pass, so that placeholders inside the title or the content stay as they are
+    let mut page = String::with_capacity(template.len() + title.len() + content.len());
+    let mut rest = template;
+    while let Some(start) = rest.find("{{") {
+        page.push_str(&rest[..start]);
+        rest = &rest[start..];
+        if let Some(after) = rest.strip_prefix("{{title}}") {
+            page.push_str(&title);
+            rest = after;
+        } else if let Some(after) = rest.strip_prefix("{{content}}") {
+            page.push_str(&content);
+            rest = after;
+        } else {
+            page.push_str("{{");
+            rest = &rest[2..];
+        }
+    }
+    page.push_str(rest);
+    page
+}
+
+/// Maps a request path onto the Markdown file below `root` that it names.
+///
+/// # Arguments
+///
+/// * `root`: The document root.
+/// * `request_path`: The percent-encoded request path.
+///
+/// # Returns
+///
+/// The candidate file, or `None` for paths with another extension or that escape `root`.
+fn markdown_path(root: &Path, request_path: &str) -> Option<PathBuf> {
+    let resolved = path::resolve(root, request_path)?;
+    if request_path.ends_with('/') {
+        return Some(resolved.join("index.md"));
+    }
+    match resolved.extension() {
+        Some(extension) if extension == "md" => Some(resolved),
+        Some(_) => None,
+        None => Some(resolved.with_extension("md")),
+    }
+}
+
+/// Uses the text of the first level-one ATX heading as the page title.
+fn title(markdown: &str) -> Option<String> {
+    markdown.lines().find_map(|line| {
+        let heading = line.strip_prefix("# ")?.trim().trim_end_matches('#').trim();
+        if heading.is_empty() {
+            None
+        } else {
+            Some(heading.to_string())
+        }
+    })
+}
+
+/// Escapes the characters that cannot appear verbatim in HTML text.
+fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn get(target: &str) -> Request {
+        Request {
+            method: Method::Get,
+            target: target.parse().unwrap(),
+            ..Request::default()
+        }
+    }
+
+    /// It renders a Markdown file inside the template, titled by its first heading
+    #[tokio::test]
+    async fn render_with_template() {
+        let root = tempfile::tempdir().unwrap();
+        std::fs::write(
+            root.path().join("guide.md"),
+            "# Guide & More\n\nSome *text*.\n",
+        )
+        .unwrap();
+        let template = root.path().join("layout.html");
+        std::fs::write(
+            &template,
+            "<title>{{title}}</title><main>{{content}}</main>",
+        )
+        .unwrap();
+        let config = MarkdownConfig {
+            template: Some(template),
+        };
+        let response = handle(&get("/guide.md"), &config, root.path(), false)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            "<title>Guide &amp; More</title><main><h1>Guide &amp; More</h1>\n\
+<p>Some <em>text</em>.</p>\n</main>",
+            String::from_utf8(response.body.to_vec()).unwrap()
+        );
+        assert_eq!(
+            "<title>{{content}}</title><main><p>{{title}}</p>\n</main>{{other}}",
+            render(
+                "<title>{{title}}</title><main>{{content}}</main>{{other}}",
+                "{{content}}",
+                "{{title}}"
+            )
+        );
+    }
+
+    /// It maps extensionless paths and directories to Markdown files
+    #[tokio::test]
+    async fn extensionless_paths() {
+        let root = tempfile::tempdir().unwrap();
+        std::fs::create_dir(root.path().join("docs")).unwrap();
+        std::fs::write(root.path().join("docs").join("index.md"), "home").unwrap();
+        std::fs::write(root.path().join("docs").join("setup.md"), "steps").unwrap();
+        let config = MarkdownConfig::default();
+        for (target, expected) in [("/docs/", "<p>home</p>"), ("/docs/setup", "<p>steps</p>")] {
+            let response = handle(&get(target), &config, root.path(), false)
+                .await
+                .unwrap()
+                .unwrap();
+            assert!(String::from_utf8(response.body.to_vec())
+                .unwrap()
+                .contains(expected));
+        }
+        let mixed = get("/Docs/SETUP");
+        assert_eq!(
+            None,
+            handle(&mixed, &config, root.path(), false).await.unwrap()
+        );
+        assert!(handle(&mixed, &config, root.path(), true)
+            .await
+            .unwrap()
+            .is_some());
+    }
+
+    /// It leaves other requests to the remaining handlers
+    #[tokio::test]
+    async fn falls_through() {
+        let root = tempfile::tempdir().unwrap();
+        std::fs::write(root.path().join("notes.md"), "text").unwrap();
+        let config = MarkdownConfig::default();
+        for target in ["/missing", "/notes.txt", "/../notes.md"] {
+            assert_eq!(
+                None,
+                handle(&get(target), &config, root.path(), false)
+                    .await
+                    .unwrap()
+            );
+        }
+        let mut post = get("/notes.md");
+        post.method = Method::Post;
+        assert_eq!(
+            None,
+            handle(&post, &config, root.path(), false).await.unwrap()
+        );
+    }
+}
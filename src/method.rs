@@ -0,0 +1,127 @@
+use std::fmt;
+use std::str::FromStr;
+use tokio::io;
+
+/// A request method. Methods are case-sensitive, so `get` is an extension method unlike
+/// [`Method::Get`].
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Method {
+    Get,
+    Head,
+    Post,
+    Put,
+    Delete,
+    Patch,
+    Options,
+    Connect,
+    Trace,
+    /// Any other method token, e.g. WebDAV's `PROPFIND`.
+    Extension(String),
+}
+
+impl Method {
+    /// The method token as sent in the request line, e.g. `GET`.
+    pub fn as_str(&self) -> &str {
+        match self {
+            Method::Get => "GET",
+            Method::Head => "HEAD",
+            Method::Post => "POST",
+            Method::Put => "PUT",
+            Method::Delete => "DELETE",
+            Method::Patch => "PATCH",
+            Method::Options => "OPTIONS",
+            Method::Connect => "CONNECT",
+            Method::Trace => "TRACE",
+            Method::Extension(token) => token,
+        }
+    }
+
+    /// Checks whether the method is safe, i.e. only retrieves state, as GET, HEAD, OPTIONS, and
+    /// TRACE are defined to.
+    pub fn is_safe(&self) -> bool {
+        matches!(
+            self,
+            Method::Get | Method::Head | Method::Options | Method::Trace
+        )
+    }
+
+    /// Checks whether repeating the request has the same effect as sending it once, as for safe
+    /// methods, PUT, and DELETE. Retrying such requests after a lost response is harmless.
+    pub fn is_idempotent(&self) -> bool {
+        self.is_safe() || matches!(self, Method::Put | Method::Delete)
+    }
+}
+
+/// The placeholder held by a request whose request line could not be parsed: an empty
+/// extension method that no handler matches.
+impl Default for Method {
+    fn default() -> Method {
+        Method::Extension(String::new())
+    }
+}
+
+/// Parses a method token.
+///
+/// # Errors
+///
+/// Returns [`io::ErrorKind::InvalidData`] when the token is empty or contains characters that a
+/// method cannot, e.g. whitespace or `/`.
+impl FromStr for Method {
+    type Err = io::Error;
+
+    fn from_str(token: &str) -> io::Result<Method> {
+        let valid = !token.is_empty()
+            && token
+                .bytes()
+                .all(|byte| byte.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&byte));
+        if !valid {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("invalid method: {}", token),
+            ));
+        }
+        Ok(match token {
+            "GET" => Method::Get,
+            "HEAD" => Method::Head,
+            "POST" => Method::Post,
+            "PUT" => Method::Put,
+            "DELETE" => Method::Delete,
+            "PATCH" => Method::Patch,
+            "OPTIONS" => Method::Options,
+            "CONNECT" => Method::Connect,
+            "TRACE" => Method::Trace,
+            _ => Method::Extension(token.to_string()),
+        })
+    }
+}
+
+impl fmt::Display for Method {
+    fn fmt(&self, formatter: &mut fmt::Formatter<'_>) -> fmt::Result {
+        formatter.write_str(self.as_str())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// It parses standard and extension methods case-sensitively and rejects invalid tokens
+    #[test]
+    fn parse() {
+        assert_eq!(Method::Get, "GET".parse().unwrap());
+        assert_eq!(Method::Extension("get".to_string()), "get".parse().unwrap());
+        assert_eq!("PROPFIND", "PROPFIND".parse::<Method>().unwrap().as_str());
+        assert!("".parse::<Method>().is_err());
+        assert!("GET/1".parse::<Method>().is_err());
+    }
+
+    /// It classifies safe and idempotent methods
+    #[test]
+    fn predicates() {
+        assert!(Method::Head.is_safe());
+        assert!(!Method::Put.is_safe());
+        assert!(Method::Put.is_idempotent());
+        assert!(!Method::Post.is_idempotent());
+        assert!(!Method::Extension("MOVE".to_string()).is_idempotent());
+    }
+}
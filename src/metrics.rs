@@ -0,0 +1,677 @@
+use crate::config::{Config, MetricsConfig};
+use crate::method::Method;
+use crate::proxy::{CircuitState, UpstreamStats};
+use crate::request::Request;
+use crate::response::Response;
+use crate::server::StatsSnapshot;
+use crate::status::StatusCode;
+use crate::{tus, upload, webdav};
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::time::Duration;
+
+/// The upper bounds in seconds of the request duration buckets, those of the Prometheus client
+/// libraries.
+pub const LATENCY_BUCKETS: [f64; 11] = [
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+/// Counts of observed durations by bucket, see [`LATENCY_BUCKETS`].
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Histogram {
+    /// The observations per bucket, each counted in the first bucket it fits, without the
+    /// ones above the last bound.
+    pub buckets: [u64; LATENCY_BUCKETS.len()],
+    /// Every observation.
+    pub count: u64,
+    /// The sum of the observations in seconds.
+    pub sum: f64,
+}
+
+impl Histogram {
+    /// Counts a duration.
+    ///
+    /// # Arguments
+    ///
+    /// * `duration`: The observed duration.
+    pub fn observe(&mut self, duration: Duration) {
+        let seconds = duration.as_secs_f64();
+        if let Some(bucket) = LATENCY_BUCKETS.iter().position(|bound| seconds <= *bound) {
+            self.buckets[bucket] += 1;
+        }
+        self.count += 1;
+        self.sum += seconds;
+    }
+}
+
+/// A handled request as counted per route, see [`crate::server::ServerStats::record_route`].
+#[derive(Clone, Copy, Debug)]
+pub struct Exchange<'a> {
+    /// The route pattern, see [`route`].
+    pub route: &'a str,
+    /// The request method.
+    pub method: &'a Method,
+    /// The status of the response.
+    pub status: StatusCode,
+    /// The request body bytes read.
+    pub bytes_in: u64,
+    /// The response body bytes written.
+    pub bytes_out: u64,
+    /// The time from reading the request to writing the response.
+    pub duration: Duration,
+}
+
+/// Counters of the requests of one route and method.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct RouteCounters {
+    /// Requests handled.
+    pub requests: u64,
+    /// Responses with a 4xx status.
+    pub client_errors: u64,
+    /// Responses with a 5xx status.
+    pub server_errors: u64,
+    /// Request body bytes read.
+    pub bytes_in: u64,
+    /// Response body bytes written.
+    pub bytes_out: u64,
+}
+
+/// What the requests of one route pattern took and produced.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct RouteStats {
+    /// Request durations by status class, from 1xx to 5xx.
+    pub latency: [Histogram; 5],
+    /// Counters by method, see [`method_label`].
+    pub methods: BTreeMap<&'static str, RouteCounters>,
+}
+
+impl RouteStats {
+    /// Counts a request of the route.
+    ///
+    /// # Arguments
+    ///
+    /// * `exchange`: The request and its response.
+    pub fn record(&mut self, exchange: &Exchange<'_>) {
+        let code = exchange.status.as_u16();
+        self.latency[usize::from(code / 100).clamp(1, 5) - 1].observe(exchange.duration);
+        let counters = self
+            .methods
+            .entry(method_label(exchange.method))
+            .or_default();
+        counters.requests += 1;
+        counters.client_errors += u64::from((400..500).contains(&code));
+        counters.server_errors += u64::from(code >= 500);
+        counters.bytes_in += exchange.bytes_in;
+        counters.bytes_out += exchange.bytes_out;
+    }
+}
+
+/// Names a method in metric labels, bounding them to the standard and WebDAV methods.
+///
+/// # Arguments
+///
+/// * `method`: The request method.
+///
+/// # Returns
+///
+/// The method token, or `other` for unknown extension methods.
+pub fn method_label(method: &Method) -> &'static str {
+    match method {
+        Method::Get => "GET",
+        Method::Head => "HEAD",
+        Method::Post => "POST",
+        Method::Put => "PUT",
+        Method::Delete => "DELETE",
+        Method::Patch => "PATCH",
+        Method::Options => "OPTIONS",
+        Method::Connect => "CONNECT",
+        Method::Trace => "TRACE",
+        Method::Extension(token) => webdav::METHODS
+            .iter()
+            .find(|known| **known == token.as_str())
+            .copied()
+            .unwrap_or("other"),
+    }
+}
+
+/// A metric of the connections of the whole server.
+struct ServerFamily {
+    name: &'static str,
+    kind: &'static str,
+    help: &'static str,
+    value: fn(&StatsSnapshot) -> u64,
+}
+
+const SERVER_FAMILIES: [ServerFamily; 6] = [
+    ServerFamily {
+        name: "http_connections_active",
+        kind: "gauge",
+        help: "Connections currently open.",
+        value: |stats| stats.connections_active,
+    },
+    ServerFamily {
+        name: "http_connections_accepted_total",
+        kind: "counter",
+        help: "Connections accepted.",
+        value: |stats| stats.connections_accepted,
+    },
+    ServerFamily {
+        name: "http_tls_handshake_failures_total",
+        kind: "counter",
+        help: "Connections whose TLS handshake failed.",
+        value: |stats| stats.tls_handshake_failures,
+    },
+    ServerFamily {
+        name: "http_connections_timed_out_total",
+        kind: "counter",
+        help: "Connections closed because they took too long.",
+        value: |stats| stats.connections_timed_out,
+    },
+    ServerFamily {
+        name: "http_connection_requests_total",
+        kind: "counter",
+        help: "Requests read from connections.",
+        value: |stats| stats.requests,
+    },
+    ServerFamily {
+        name: "http_connection_reused_requests_total",
+        kind: "counter",
+        help: "Requests read from a keep-alive connection that carried an earlier request.",
+        value: |stats| stats.reused_requests,
+    },
+];
+
+/// A metric reported once per route and method.
+struct RouteFamily {
+    name: &'static str,
+    help: &'static str,
+    value: fn(&RouteCounters) -> u64,
+}
+
+const ROUTE_FAMILIES: [RouteFamily; 5] = [
+    RouteFamily {
+        name: "http_requests_total",
+        help: "Requests handled.",
+        value: |counters| counters.requests,
+    },
+    RouteFamily {
+        name: "http_client_errors_total",
+        help: "Responses with a 4xx status.",
+        value: |counters| counters.client_errors,
+    },
+    RouteFamily {
+        name: "http_server_errors_total",
+        help: "Responses with a 5xx status.",
+        value: |counters| counters.server_errors,
+    },
+    RouteFamily {
+        name: "http_request_body_bytes_total",
+        help: "Request body bytes read.",
+        value: |counters| counters.bytes_in,
+    },
+    RouteFamily {
+        name: "http_response_body_bytes_total",
+        help: "Response body bytes written.",
+        value: |counters| counters.bytes_out,
+    },
+];
+
+/// A metric reported once per proxy upstream.
+struct UpstreamFamily {
+    name: &'static str,
+    kind: &'static str,
+    help: &'static str,
+    value: fn(&UpstreamStats) -> u64,
+}
+
+const UPSTREAM_FAMILIES: [UpstreamFamily; 7] = [
+    UpstreamFamily {
+        name: "proxy_upstream_requests_total",
+        kind: "counter",
+        help: "Requests sent to the upstream.",
+        value: |stats| stats.requests,
+    },
+    UpstreamFamily {
+        name: "proxy_upstream_failures_total",
+        kind: "counter",
+        help: "Requests to the upstream that produced no response.",
+        value: |stats| stats.failures,
+    },
+    UpstreamFamily {
+        name: "proxy_upstream_active_requests",
+        kind: "gauge",
+        help: "Requests to the upstream in flight.",
+        value: |stats| stats.active as u64,
+    },
+    UpstreamFamily {
+        name: "proxy_upstream_idle_connections",
+        kind: "gauge",
+        help: "Open connections to the upstream waiting for a request.",
+        value: |stats| stats.idle as u64,
+    },
+    UpstreamFamily {
+        name: "proxy_upstream_healthy",
+        kind: "gauge",
+        help: "Whether health checks keep the upstream in rotation.",
+        value: |stats| u64::from(stats.healthy),
+    },
+    UpstreamFamily {
+        name: "proxy_upstream_weight",
+        kind: "gauge",
+        help: "The share of traffic the upstream receives relative to the others.",
+        value: |stats| u64::from(stats.weight),
+    },
+    UpstreamFamily {
+        name: "proxy_upstream_circuit_state",
+        kind: "gauge",
+        help: "The circuit breaker state: 0 closed, 1 open, 2 half-open.",
+        value: |stats| match stats.circuit {
+            CircuitState::Closed => 0,
+            CircuitState::Open => 1,
+            CircuitState::HalfOpen => 2,
+        },
+    },
+];
+
+/// Names the route a request goes to by its pattern rather than its path, which bounds the
+/// labels of per-route metrics to the configured routes. Follows the order handlers are tried
+/// in.
+///
+/// # Arguments
+///
+/// * `request`: The incoming request.
+/// * `config`: The settings holding the routes.
+///
+/// # Returns
+///
+/// The pattern of a router handler, the path prefix of the proxy, FastCGI, CGI,
+/// upload, or tus handler, the metrics path, `webdav`, or `static` for the document root.
+pub fn route<'a>(request: &'a Request, config: &'a Config) -> &'a str {
+    if let Some(found) = config.router.lookup(&request.method, request.path()) {
+        return found.pattern;
+    }
+    if let Some(metrics) = config
+        .metrics
+        .as_ref()
+        .filter(|metrics| matches(request, metrics))
+    {
+        return &metrics.route;
+    }
+    if let Some(proxy) = config.proxy.as_ref().filter(|proxy| proxy.matches(request)) {
+        return proxy.route();
+    }
+    if let Some(fastcgi) = config
+        .fastcgi
+        .as_ref()
+        .filter(|fastcgi| fastcgi.matches(request))
+    {
+        return fastcgi.route();
+    }
+    if let Some(cgi) = config.cgi.as_ref().filter(|cgi| cgi.matches(request)) {
+        return cgi.route();
+    }
+    if config.webdav.is_some() && webdav::is_webdav_method(&request.method) {
+        return "webdav";
+    }
+    if let Some(upload) = config
+        .upload
+        .as_ref()
+        .filter(|upload| upload::matches(request, upload))
+    {
+        return &upload.route;
+    }
+    if let Some(tus) = config.tus.as_ref().filter(|tus| tus::matches(request, tus)) {
+        return &tus.route;
+    }
+    "static"
+}
+
+/// Checks whether a request asks for the metrics.
+///
+/// # Arguments
+///
+/// * `request`: The incoming request.
+/// * `metrics`: The metrics settings.
+///
+/// # Returns
+///
+/// True for GET and HEAD requests at the metrics route.
+pub fn matches(request: &Request, metrics: &MetricsConfig) -> bool {
+    matches!(request.method, Method::Get | Method::Head) && request.path() == metrics.route
+}
+
+/// Answers a metrics request in the Prometheus text exposition format.
+///
+/// # Arguments
+///
+/// * `config`: The settings holding the components that report metrics.
+///
+/// # Returns
+///
+/// 200 with the current metrics.
+pub fn handle(config: &Config) -> Response {
+    Response::new(StatusCode::OK, render(config))
+        .with_header("Content-Type", "text/plain; version=0.0.4")
+}
+
+/// Renders the metrics of every enabled component.
+///
+/// # Arguments
+///
+/// * `config`: The settings holding the components that report metrics.
+///
+/// # Returns
+///
+/// The metrics in the Prometheus text exposition format.
+pub fn render(config: &Config) -> String {
+    let mut metrics = String::new();
+    let stats = config.stats.snapshot();
+    for family in SERVER_FAMILIES {
+        let _ = writeln!(
+            metrics,
+            "# HELP {} {}\n# TYPE {} {}\n{} {}",
+            family.name,
+            family.help,
+            family.name,
+            family.kind,
+            family.name,
+            (family.value)(&stats)
+        );
+    }
+    let routes = config.stats.routes();
+    if !routes.is_empty() {
+        for family in ROUTE_FAMILIES {
+            let _ = writeln!(
+                metrics,
+                "# HELP {} {}\n# TYPE {} counter",
+                family.name, family.help, family.name
+            );
+            for (route, stats) in &routes {
+                for (method, counters) in &stats.methods {
+                    let _ = writeln!(
+                        metrics,
+                        "{}{{route=\"{}\",method=\"{}\"}} {}",
+                        family.name,
+                        escape_label(route),
+                        method,
+                        (family.value)(counters)
+                    );
+                }
+            }
+        }
+        let name = "http_request_duration_seconds";
+        let _ = writeln!(
+            metrics,
+            "# HELP {} Seconds from reading a request to writing its response.\n# TYPE {} histogram",
+            name, name
+        );
+        for (route, stats) in &routes {
+            let route = escape_label(route);
+            for (class, histogram) in stats.latency.iter().enumerate() {
+                if histogram.count == 0 {
+                    continue;
+                }
+                let labels = format!("route=\"{}\",status=\"{}xx\"", route, class + 1);
+                let mut cumulative = 0;
+                for (bound, count) in LATENCY_BUCKETS.iter().zip(histogram.buckets) {
+                    cumulative += count;
+                    let _ = writeln!(
+                        metrics,
+                        "{}_bucket{{{},le=\"{}\"}} {}",
+                        name, labels, bound, cumulative
+                    );
+                }
+                let _ = writeln!(
+                    metrics,
+                    "{}_bucket{{{},le=\"+Inf\"}} {}\n{}_sum{{{}}} {}\n{}_count{{{}}} {}",
+                    name,
+                    labels,
+                    histogram.count,
+                    name,
+                    labels,
+                    histogram.sum,
+                    name,
+                    labels,
+                    histogram.count
+                );
+            }
+        }
+    }
+    if let Some(proxy) = &config.proxy {
+        let stats = proxy.upstream_stats();
+        for family in UPSTREAM_FAMILIES {
+            // Writing into a String cannot fail
+            let _ = writeln!(
+                metrics,
+                "# HELP {} {}\n# TYPE {} {}",
+                family.name, family.help, family.name, family.kind
+            );
+            for stats in &stats {
+                let _ = writeln!(
+                    metrics,
+                    "{}{{upstream=\"{}\"}} {}",
+                    family.name,
+                    escape_label(&stats.address),
+                    (family.value)(stats)
+                );
+            }
+        }
+    }
+    #[cfg(feature = "runtime-metrics")]
+    if let Ok(handle) = tokio::runtime::Handle::try_current() {
+        render_runtime(&mut metrics, &handle.metrics());
+    }
+    metrics
+}
+
+/// Renders the metrics of the runtime answering the request, the one of its thread with
+/// [`crate::server::Server::serve_per_core`]. The queue of every worker and the blocking pool
+/// are only reported when built with `RUSTFLAGS="--cfg tokio_unstable"`.
+///
+/// # Arguments
+///
+/// * `metrics`: The text the metrics are appended to.
+/// * `runtime`: The metrics of the runtime.
+#[cfg(feature = "runtime-metrics")]
+fn render_runtime(metrics: &mut String, runtime: &tokio::runtime::RuntimeMetrics) {
+    let gauge = |metrics: &mut String, name: &str, help: &str, value: usize| {
+        let _ = writeln!(
+            metrics,
+            "# HELP {} {}\n# TYPE {} gauge\n{} {}",
+            name, help, name, name, value
+        );
+    };
+    let per_worker = |metrics: &mut String,
+                      name: &str,
+                      kind: &str,
+                      help: &str,
+                      value: &dyn Fn(usize) -> String| {
+        let _ = writeln!(
+            metrics,
+            "# HELP {} {}\n# TYPE {} {}",
+            name, help, name, kind
+        );
+        for worker in 0..runtime.num_workers() {
+            let _ = writeln!(
+                metrics,
+                "{}{{worker=\"{}\"}} {}",
+                name,
+                worker,
+                value(worker)
+            );
+        }
+    };
+    gauge(
+        metrics,
+        "tokio_workers",
+        "Worker threads of the runtime.",
+        runtime.num_workers(),
+    );
+    gauge(
+        metrics,
+        "tokio_alive_tasks",
+        "Tasks spawned on the runtime and not yet finished.",
+        runtime.num_alive_tasks(),
+    );
+    gauge(
+        metrics,
+        "tokio_global_queue_depth",
+        "Tasks waiting in the queue shared by the workers.",
+        runtime.global_queue_depth(),
+    );
+    per_worker(
+        metrics,
+        "tokio_worker_busy_seconds_total",
+        "counter",
+        "Seconds the worker spent running tasks, whose rate is its utilization.",
+        &|worker| {
+            runtime
+                .worker_total_busy_duration(worker)
+                .as_secs_f64()
+                .to_string()
+        },
+    );
+    per_worker(
+        metrics,
+        "tokio_worker_parks_total",
+        "counter",
+        "Times the worker went to sleep for lack of tasks.",
+        &|worker| runtime.worker_park_count(worker).to_string(),
+    );
+    #[cfg(tokio_unstable)]
+    {
+        per_worker(
+            metrics,
+            "tokio_worker_local_queue_depth",
+            "gauge",
+            "Tasks waiting in the queue of the worker.",
+            &|worker| runtime.worker_local_queue_depth(worker).to_string(),
+        );
+        gauge(
+            metrics,
+            "tokio_blocking_threads",
+            "Threads of the blocking pool.",
+            runtime.num_blocking_threads(),
+        );
+        gauge(
+            metrics,
+            "tokio_idle_blocking_threads",
+            "Threads of the blocking pool waiting for work.",
+            runtime.num_idle_blocking_threads(),
+        );
+        gauge(
+            metrics,
+            "tokio_blocking_queue_depth",
+            "Blocking tasks waiting for a thread.",
+            runtime.blocking_queue_depth(),
+        );
+    }
+}
+
+/// Escapes a label value, see the Prometheus text exposition format.
+fn escape_label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::proxy::Proxy;
+
+    /// It renders one sample per upstream for every metric family
+    #[test]
+    fn render() {
+        let config = Config {
+            proxy: Some(Proxy::new("/", "a:1").with_upstream("b\"2")),
+            ..Config::default()
+        };
+        let rendered = super::render(&config);
+        assert!(rendered.contains(
+            "# TYPE proxy_upstream_requests_total counter\n\
+             proxy_upstream_requests_total{upstream=\"a:1\"} 0\n\
+             proxy_upstream_requests_total{upstream=\"b\\\"2\"} 0\n"
+        ));
+        assert!(rendered.contains("proxy_upstream_healthy{upstream=\"a:1\"} 1\n"));
+        let rendered = super::render(&Config::default());
+        assert!(!rendered.contains("proxy_upstream"));
+        assert!(
+            rendered.contains("# TYPE http_connections_active gauge\nhttp_connections_active 0\n")
+        );
+    }
+
+    /// It reports the runtime answering the request
+    #[cfg(feature = "runtime-metrics")]
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn runtime() {
+        let rendered = super::render(&Config::default());
+        assert!(rendered.contains("\ntokio_workers 2\n"), "{}", rendered);
+        assert!(rendered.contains("tokio_worker_busy_seconds_total{worker=\"1\"} "));
+    }
+
+    /// It renders counters per route and method and cumulative latency buckets per route and
+    /// status class
+    #[test]
+    fn routes() {
+        let config = Config {
+            proxy: Some(Proxy::new("/api", "a:1")),
+            ..Config::default()
+        };
+        let request = Request {
+            target: "/api/users/7".parse().unwrap(),
+            ..Request::default()
+        };
+        let route = super::route(&request, &config);
+        assert_eq!("/api", route);
+        for (status, millis) in [
+            (StatusCode::OK, 20),
+            (StatusCode::OK, 300),
+            (StatusCode::BAD_GATEWAY, 20_000),
+        ] {
+            config.stats.record_route(&Exchange {
+                route,
+                method: &Method::Get,
+                status,
+                bytes_in: 0,
+                bytes_out: 10,
+                duration: Duration::from_millis(millis),
+            });
+        }
+        let rendered = super::render(&config);
+        assert!(rendered.contains(
+            "http_request_duration_seconds_bucket{route=\"/api\",status=\"2xx\",le=\"0.01\"} 0\n\
+             http_request_duration_seconds_bucket{route=\"/api\",status=\"2xx\",le=\"0.025\"} 1\n"
+        ));
+        assert!(rendered.contains(
+            "http_request_duration_seconds_bucket{route=\"/api\",status=\"2xx\",le=\"+Inf\"} 2\n\
+             http_request_duration_seconds_sum{route=\"/api\",status=\"2xx\"} 0.32\n\
+             http_request_duration_seconds_count{route=\"/api\",status=\"2xx\"} 2\n"
+        ));
+        assert!(rendered.contains(
+            "http_request_duration_seconds_bucket{route=\"/api\",status=\"5xx\",le=\"10\"} 0\n\
+             http_request_duration_seconds_bucket{route=\"/api\",status=\"5xx\",le=\"+Inf\"} 1\n"
+        ));
+        assert!(!rendered.contains("status=\"4xx\""));
+        assert!(rendered.contains("http_requests_total{route=\"/api\",method=\"GET\"} 3\n"));
+        assert!(rendered.contains("http_server_errors_total{route=\"/api\",method=\"GET\"} 1\n"));
+        assert!(rendered.contains("http_client_errors_total{route=\"/api\",method=\"GET\"} 0\n"));
+        assert!(
+            rendered.contains("http_response_body_bytes_total{route=\"/api\",method=\"GET\"} 30\n")
+        );
+        assert_eq!(
+            "other",
+            method_label(&Method::Extension("BREW".to_string()))
+        );
+        assert_eq!(
+            "PROPFIND",
+            method_label(&Method::Extension("PROPFIND".to_string()))
+        );
+        let other = Request {
+            target: "/index.html".parse().unwrap(),
+            ..Request::default()
+        };
+        assert_eq!("static", super::route(&other, &config));
+    }
+}
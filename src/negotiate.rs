@@ -0,0 +1,506 @@
+use crate::request::Request;
+use crate::response::Response;
+use crate::status::StatusCode;
+use std::path::{Path, PathBuf};
+use tokio::{fs, io};
+
+/// The largest quality value, `q=1`, in thousandths.
+const MAX_QUALITY: u16 = 1000;
+
+/// One entry of an `Accept` header, e.g. `text/*;q=0.5`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MediaRange {
+    /// The type, or `*`.
+    pub kind: String,
+    /// The subtype, or `*`.
+    pub subtype: String,
+    /// The quality value in thousandths, so `q=0.5` is `500`.
+    pub quality: u16,
+}
+
+impl MediaRange {
+    /// Rates how specifically this range matches a media type.
+    ///
+    /// # Arguments
+    ///
+    /// * `media_type`: A concrete media type such as `text/html`, parameters ignored.
+    ///
+    /// # Returns
+    ///
+    /// `None` when the range does not match, otherwise 0 for `*/*`, 1 for `type/*`, and 2 for an
+    /// exact match.
+    pub fn specificity(&self, media_type: &str) -> Option<u8> {
+        let essence = media_type.split(';').next().unwrap_or_default().trim();
+        let (kind, subtype) = essence.split_once('/')?;
+        match (self.kind.as_str(), self.subtype.as_str()) {
+            ("*", "*") => Some(0),
+            (range_kind, "*") if range_kind.eq_ignore_ascii_case(kind) => Some(1),
+            (range_kind, range_subtype)
+                if range_kind.eq_ignore_ascii_case(kind)
+                    && range_subtype.eq_ignore_ascii_case(subtype) =>
+            {
+                Some(2)
+            }
+            _ => None,
+        }
+    }
+}
+
+/// Parses an `Accept` header.
+///
+/// # Arguments
+///
+/// * `header`: The header value, e.g. `text/html, application/json;q=0.9, */*;q=0.1`.
+///
+/// # Returns
+///
+/// The media ranges in header order. Malformed entries are skipped.
+pub fn parse_accept(header: &str) -> Vec<MediaRange> {
+    header
+        .split(',')
+        .filter_map(|entry| {
+            let mut parts = entry.split(';');
+            let (kind, subtype) = parts.next()?.trim().split_once('/')?;
+            if kind.is_empty() || subtype.is_empty() {
+                return None;
+            }
+            let mut quality = MAX_QUALITY;
+            for parameter in parts {
+                if let Some((name, value)) = parameter.split_once('=') {
+                    if name.trim().eq_ignore_ascii_case("q") {
+                        quality = parse_quality(value.trim())?;
+                    }
+                }
+            }
+            Some(MediaRange {
+                kind: kind.to_string(),
+                subtype: subtype.to_string(),
+                quality,
+            })
+        })
+        .collect()
+}
+
+/// Parses a quality value such as `0.8` into thousandths.
+///
+/// # Arguments
+///
+/// * `value`: A quality value with at most three decimals between 0 and 1.
+///
+/// # Returns
+///
+/// The quality in thousandths, or `None` when `value` is not a valid quality value.
+pub fn parse_quality(value: &str) -> Option<u16> {
+    let (whole, fraction) = value.split_once('.').unwrap_or((value, ""));
+    if fraction.len() > 3 || !fraction.bytes().all(|byte| byte.is_ascii_digit()) {
+        return None;
+    }
+    let thousandths = format!("{:0<3}", fraction).parse::<u16>().ok()?;
+    match whole {
+        "0" => Some(thousandths),
+        "1" if thousandths == 0 => Some(MAX_QUALITY),
+        _ => None,
+    }
+}
+
+/// Picks the offered media type that the client prefers.
+///
+/// Each offer is rated by the most specific media range matching it. Higher quality wins and
+/// ties go to the earlier offer, so order offers by server preference.
+///
+/// # Arguments
+///
+/// * `accept`: The `Accept` header, if the request has one.
+/// * `offers`: The media types the handler can produce.
+///
+/// # Returns
+///
+/// The chosen offer, the first offer without an `Accept` header, or `None` when the client
+/// accepts none of them.
+pub fn best_match<'a>(accept: Option<&str>, offers: &[&'a str]) -> Option<&'a str> {
+    let ranges = match accept {
+        Some(accept) if !accept.trim().is_empty() => parse_accept(accept),
+        _ => return offers.first().copied(),
+    };
+    let mut best: Option<(&'a str, u16)> = None;
+    for offer in offers {
+        let quality = ranges
+            .iter()
+            .filter_map(|range| Some((range.specificity(offer)?, range.quality)))
+            .max_by_key(|(specificity, _)| *specificity)
+            .map_or(0, |(_, quality)| quality);
+        if quality > 0 && best.is_none_or(|(_, best_quality)| quality > best_quality) {
+            best = Some((offer, quality));
+        }
+    }
+    best.map(|(offer, _)| offer)
+}
+
+/// One entry of an `Accept-Language` header, e.g. `de-CH;q=0.8`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct LanguageRange {
+    /// The language range, e.g. `de-CH`, `de`, or `*`.
+    pub range: String,
+    /// The quality value in thousandths, so `q=0.8` is `800`.
+    pub quality: u16,
+}
+
+impl LanguageRange {
+    /// Rates how closely this range matches a language tag, ignoring ASCII case.
+    ///
+    /// # Arguments
+    ///
+    /// * `tag`: An available language tag such as `en` or `en-US`.
+    ///
+    /// # Returns
+    ///
+    /// `None` when the range does not match, otherwise 0 for `*`, 1 when `tag` is a prefix of
+    /// the range (tag `de` for range `de-CH`), 2 when the range is a prefix of `tag` (range `de`
+    /// for tag `de-CH`), and 3 for an exact match.
+    pub fn specificity(&self, tag: &str) -> Option<u8> {
+        let range = self.range.to_ascii_lowercase();
+        let tag = tag.to_ascii_lowercase();
+        if range == "*" {
+            Some(0)
+        } else if range == tag {
+            Some(3)
+        } else if tag.starts_with(&format!("{}-", range)) {
+            Some(2)
+        } else if range.starts_with(&format!("{}-", tag)) {
+            Some(1)
+        } else {
+            None
+        }
+    }
+}
+
+/// Parses an `Accept-Language` header.
+///
+/// # Arguments
+///
+/// * `header`: The header value, e.g. `de-CH, de;q=0.9, en;q=0.5`.
+///
+/// # Returns
+///
+/// The language ranges in header order. Malformed entries are skipped.
+pub fn parse_accept_language(header: &str) -> Vec<LanguageRange> {
+    header
+        .split(',')
+        .filter_map(|entry| {
+            let mut parts = entry.split(';');
+            let range = parts.next()?.trim();
+            if range.is_empty()
+                || !range
+                    .bytes()
+                    .all(|byte| byte.is_ascii_alphanumeric() || byte == b'-' || byte == b'*')
+            {
+                return None;
+            }
+            let mut quality = MAX_QUALITY;
+            for parameter in parts {
+                if let Some((name, value)) = parameter.split_once('=') {
+                    if name.trim().eq_ignore_ascii_case("q") {
+                        quality = parse_quality(value.trim())?;
+                    }
+                }
+            }
+            Some(LanguageRange {
+                range: range.to_string(),
+                quality,
+            })
+        })
+        .collect()
+}
+
+/// Picks the available language that the client prefers.
+///
+/// Each language is rated by the closest range matching it. Higher quality wins, then closer
+/// matches, then the earlier language in `available`.
+///
+/// # Arguments
+///
+/// * `accept_language`: The `Accept-Language` header, if the request has one.
+/// * `available`: The language tags the resource exists in.
+///
+/// # Returns
+///
+/// The chosen language, or `None` without a header or when no language is acceptable.
+pub fn best_language<'a>(accept_language: Option<&str>, available: &[&'a str]) -> Option<&'a str> {
+    let ranges = parse_accept_language(accept_language?);
+    let mut best: Option<(&'a str, u16, u8)> = None;
+    for tag in available {
+        let rating = ranges
+            .iter()
+            .filter_map(|range| Some((range.quality, range.specificity(tag)?)))
+            .max_by_key(|(_, specificity)| *specificity);
+        if let Some((quality, specificity)) = rating {
+            let better = best.is_none_or(|(_, best_quality, best_specificity)| {
+                (quality, specificity) > (best_quality, best_specificity)
+            });
+            if quality > 0 && better {
+                best = Some((tag, quality, specificity));
+            }
+        }
+    }
+    best.map(|(tag, _, _)| tag)
+}
+
+/// The outcome of [`select_variant`] for a file that has language variants.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Variant {
+    /// The file to serve: the preferred variant, or the original file when none is acceptable.
+    pub path: PathBuf,
+    /// The language of the chosen variant, sent as `Content-Language`.
+    pub language: Option<String>,
+}
+
+/// Chooses among language variants of a file, named like `hello.de.html` for `hello.html`.
+///
+/// # Arguments
+///
+/// * `path`: The file without a language, e.g. `root/hello.html`.
+/// * `accept_language`: The `Accept-Language` header, if the request has one.
+///
+/// # Returns
+///
+/// `None` when the file has no variants, otherwise the file to serve. Either way the response
+/// should record `Accept-Language` through [`Response::add_vary`] as soon as variants exist.
+///
+/// # Errors
+///
+/// Captures IO errors from listing the directory containing `path`.
+pub async fn select_variant(
+    path: &Path,
+    accept_language: Option<&str>,
+) -> io::Result<Option<Variant>> {
+    let (directory, stem, extension) = match (path.parent(), path.file_stem(), path.extension()) {
+        (Some(directory), Some(stem), Some(extension)) => (
+            directory,
+            stem.to_string_lossy(),
+            extension.to_string_lossy(),
+        ),
+        _ => return Ok(None),
+    };
+    let prefix = format!("{}.", stem);
+    let suffix = format!(".{}", extension);
+    let mut languages = Vec::new();
+    let mut entries = fs::read_dir(directory).await?;
+    while let Some(entry) = entries.next_entry().await? {
+        let name = entry.file_name().to_string_lossy().into_owned();
+        if let Some(language) = name
+            .strip_prefix(&prefix)
+            .and_then(|rest| rest.strip_suffix(&suffix))
+        {
+            if !language.is_empty() && !language.contains('.') {
+                languages.push(language.to_string());
+            }
+        }
+    }
+    if languages.is_empty() {
+        return Ok(None);
+    }
+    languages.sort();
+    let tags: Vec<&str> = languages.iter().map(String::as_str).collect();
+    Ok(Some(match best_language(accept_language, &tags) {
+        Some(language) => Variant {
+            path: directory.join(format!("{}{}{}", prefix, language, suffix)),
+            language: Some(language.to_string()),
+        },
+        None => Variant {
+            path: path.to_path_buf(),
+            language: None,
+        },
+    }))
+}
+
+/// A producer of one representation's body.
+type Render = Box<dyn FnOnce() -> Vec<u8> + Send>;
+
+/// Alternative representations of one resource, of which only the negotiated one is rendered.
+#[derive(Default)]
+pub struct Representations {
+    offers: Vec<(&'static str, Render)>,
+}
+
+impl Representations {
+    /// Creates an empty set of representations.
+    pub fn new() -> Representations {
+        Representations::default()
+    }
+
+    /// Adds a representation, in decreasing order of server preference.
+    ///
+    /// # Arguments
+    ///
+    /// * `media_type`: The `Content-Type` of the representation.
+    /// * `render`: Produces the body if this representation is chosen.
+    ///
+    /// # Returns
+    ///
+    /// The representations including the new one.
+    pub fn offer(
+        mut self,
+        media_type: &'static str,
+        render: impl FnOnce() -> Vec<u8> + Send + 'static,
+    ) -> Representations {
+        self.offers.push((media_type, Box::new(render)));
+        self
+    }
+
+    /// Renders the representation the request prefers.
+    ///
+    /// # Arguments
+    ///
+    /// * `request`: The request whose `Accept` header is consulted.
+    ///
+    /// # Returns
+    ///
+    /// 200 with the chosen body and `Content-Type`, or 406 listing the available media types.
+    pub fn respond(self, request: &Request) -> Response {
+        let media_types: Vec<&'static str> = self
+            .offers
+            .iter()
+            .map(|(media_type, _)| *media_type)
+            .collect();
+        let chosen = best_match(request.header("Accept"), &media_types);
+        let response = match self
+            .offers
+            .into_iter()
+            .find(|(media_type, _)| Some(*media_type) == chosen)
+        {
+            Some((media_type, render)) => {
+                Response::new(StatusCode::OK, render()).with_header("Content-Type", media_type)
+            }
+            None => Response::new(
+                StatusCode::NOT_ACCEPTABLE,
+                format!("Available: {}\n", media_types.join(", ")),
+            )
+            .with_header("Content-Type", "text/plain; charset=utf-8"),
+        };
+        response.with_vary("Accept")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// It parses quality values and skips malformed entries
+    #[test]
+    fn parse() {
+        let ranges = parse_accept("text/html, application/*;q=0.25 , bogus, */*;q=1.5, */*;q=0");
+        assert_eq!(
+            vec![
+                MediaRange {
+                    kind: "text".to_string(),
+                    subtype: "html".to_string(),
+                    quality: 1000
+                },
+                MediaRange {
+                    kind: "application".to_string(),
+                    subtype: "*".to_string(),
+                    quality: 250
+                },
+                MediaRange {
+                    kind: "*".to_string(),
+                    subtype: "*".to_string(),
+                    quality: 0
+                },
+            ],
+            ranges
+        );
+    }
+
+    /// It rates offers by their most specific range and breaks ties by offer order
+    #[test]
+    fn best() {
+        let offers = ["application/json", "text/html"];
+        assert_eq!(Some("application/json"), best_match(None, &offers));
+        assert_eq!(Some("text/html"), best_match(Some("text/html"), &offers));
+        assert_eq!(
+            Some("text/html"),
+            best_match(Some("application/json;q=0.5, text/*"), &offers)
+        );
+        assert_eq!(
+            Some("application/json"),
+            best_match(Some("*/*;q=0.8, text/html;q=0.8"), &offers)
+        );
+        assert_eq!(
+            Some("text/html"),
+            best_match(Some("*/*, application/json;q=0"), &offers)
+        );
+        assert_eq!(None, best_match(Some("image/png"), &offers));
+    }
+
+    /// It prefers quality, then closeness, then order among available languages
+    #[test]
+    fn languages() {
+        let available = ["de", "en", "en-GB"];
+        assert_eq!(None, best_language(None, &available));
+        assert_eq!(Some("en-GB"), best_language(Some("en-gb"), &available));
+        assert_eq!(Some("en"), best_language(Some("en"), &available));
+        assert_eq!(
+            Some("de"),
+            best_language(Some("de-CH, en;q=0.9"), &available)
+        );
+        assert_eq!(
+            Some("en"),
+            best_language(Some("fr, en-US;q=0.5"), &available)
+        );
+        assert_eq!(Some("de"), best_language(Some("*;q=0.1"), &available));
+        assert_eq!(None, best_language(Some("fr, *;q=0"), &available));
+    }
+
+    /// It finds `name.<language>.ext` siblings and picks the preferred one
+    #[tokio::test]
+    async fn variants() {
+        let root = tempfile::tempdir().unwrap();
+        let page = root.path().join("index.html");
+        assert_eq!(None, select_variant(&page, Some("de")).await.unwrap());
+        for name in [
+            "index.html",
+            "index.de.html",
+            "index.en.html",
+            "index.en.txt",
+        ] {
+            std::fs::write(root.path().join(name), name).unwrap();
+        }
+        assert_eq!(
+            Some(Variant {
+                path: root.path().join("index.de.html"),
+                language: Some("de".to_string())
+            }),
+            select_variant(&page, Some("de-AT, en;q=0.5"))
+                .await
+                .unwrap()
+        );
+        assert_eq!(
+            Some(Variant {
+                path: page.clone(),
+                language: None
+            }),
+            select_variant(&page, Some("fr")).await.unwrap()
+        );
+    }
+
+    /// It renders only the chosen representation and answers 406 when nothing matches
+    #[test]
+    fn representations() {
+        let mut request = Request::default();
+        request.headers.append("Accept", "application/json");
+        let response = Representations::new()
+            .offer("text/html", || panic!("not chosen"))
+            .offer("application/json", || b"{}".to_vec())
+            .respond(&request);
+        assert_eq!(StatusCode::OK, response.status);
+        assert_eq!(b"{}".to_vec(), response.body);
+        assert_eq!(Some("application/json"), response.header("Content-Type"));
+
+        request.headers.insert("Accept", "image/png");
+        let response = Representations::new()
+            .offer("text/html", Vec::new)
+            .respond(&request);
+        assert_eq!(StatusCode::NOT_ACCEPTABLE, response.status);
+        assert_eq!(b"Available: text/html\n".to_vec(), response.body);
+    }
+}
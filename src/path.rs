@@ -0,0 +1,265 @@
+use std::path::{Component, Path, PathBuf};
+
+/// Decodes `%XX` escapes in a request path.
+///
+/// # Arguments
+///
+/// * `path`: A percent-encoded request path.
+///
+/// # Returns
+///
+/// The decoded path, or `None` when an escape is malformed or the result is not UTF-8.
+pub fn percent_decode(path: &str) -> Option<String> {
+    let bytes = path.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut index = 0;
+    while index < bytes.len() {
+        if bytes[index] == b'%' {
+            let hex = bytes.get(index + 1..index + 3)?;
+            let hex = std::str::from_utf8(hex).ok()?;
+            decoded.push(u8::from_str_radix(hex, 16).ok()?);
+            index += 3;
+        } else {
+            decoded.push(bytes[index]);
+            index += 1;
+        }
+    }
+    String::from_utf8(decoded).ok()
+}
+
+/// Encodes a path so it can be sent back to clients, e.g. in an `href`.
+///
+/// # Arguments
+///
+/// * `path`: A decoded path whose `/` separators are kept as-is.
+///
+/// # Returns
+///
+/// The path with every byte outside the unreserved set and `/` escaped as `%XX`.
+pub fn percent_encode(path: &str) -> String {
+    let mut encoded = String::with_capacity(path.len());
+    for byte in path.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' | b'/' => {
+                encoded.push(byte as char)
+            }
+            _ => encoded.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+    encoded
+}
+
+/// Maps a request path onto a file system path below `root`.
+///
+/// # Arguments
+///
+/// * `root`: The directory that requests are confined to.
+/// * `request_path`: A percent-encoded request path such as `/docs/a%20b.txt`.
+///
+/// # Returns
+///
+/// The file system path, or `None` when the path cannot be decoded or tries to leave `root`
+/// through `..` or an absolute component.
+pub fn resolve(root: &Path, request_path: &str) -> Option<PathBuf> {
+    let decoded = percent_decode(request_path)?;
+    let mut resolved = root.to_path_buf();
+    for component in Path::new(decoded.trim_start_matches('/')).components() {
+        match component {
+            Component::Normal(part) => resolved.push(part),
+            Component::CurDir => {}
+            Component::ParentDir | Component::RootDir | Component::Prefix(_) => return None,
+        }
+    }
+    Some(resolved)
+}
+
+/// Checks whether a path below the root leads through a symbolic link, which could point
+/// outside the root although the path itself stays inside it.
+///
+/// # Arguments
+///
+/// * `root`: The directory paths are confined to, which may itself be a link.
+/// * `path`: A path from [`resolve`] below `root`.
+///
+/// # Returns
+///
+/// True when a component of `path` after `root`, including the last one, is a symbolic link.
+/// Components after a missing one cannot be links.
+///
+/// # Errors
+///
+/// Captures IO errors from reading metadata other than a missing component.
+pub async fn through_symlink(root: &Path, path: &Path) -> std::io::Result<bool> {
+    let Ok(relative) = path.strip_prefix(root) else {
+        return Ok(true);
+    };
+    let mut current = root.to_path_buf();
+    for component in relative.components() {
+        current.push(component);
+        match tokio::fs::symlink_metadata(&current).await {
+            Ok(metadata) if metadata.file_type().is_symlink() => return Ok(true),
+            Ok(_) => {}
+            Err(error) if error.kind() == std::io::ErrorKind::NotFound => return Ok(false),
+            Err(error) => return Err(error),
+        }
+    }
+    Ok(false)
+}
+
+/// Decodes a request path and removes its empty, `.`, and `..` segments the way a file system
+/// would, so that equivalent paths compare equal.
+///
+/// # Arguments
+///
+/// * `path`: A percent-encoded request path such as `/a/./b/../c`.
+///
+/// # Returns
+///
+/// The decoded path starting with `/`, e.g. `/a/c`, or `None` when it cannot be decoded. A `..`
+/// at the root stays at the root.
+pub fn normalize(path: &str) -> Option<String> {
+    let decoded = percent_decode(path)?;
+    let mut segments = Vec::new();
+    for segment in decoded.split('/') {
+        match segment {
+            "" | "." => {}
+            ".." => {
+                segments.pop();
+            }
+            segment => segments.push(segment),
+        }
+    }
+    Some(format!("/{}", segments.join("/")))
+}
+
+/// Checks whether a normalized path is a prefix or one of the paths below it, comparing whole
+/// segments so that `/health` covers `/health/live` but not `/healthz`.
+///
+/// # Arguments
+///
+/// * `path`: A path from [`normalize`].
+/// * `prefix`: A path from [`normalize`], e.g. `/health`.
+///
+/// # Returns
+///
+/// `true` when `path` is `prefix` or below it.
+pub fn is_below(path: &str, prefix: &str) -> bool {
+    match path.strip_prefix(prefix.trim_end_matches('/')) {
+        Some(rest) => rest.is_empty() || rest.starts_with('/'),
+        None => false,
+    }
+}
+
+/// Finds the file a path below `root` names when the case of its components may differ from
+/// the file system, e.g. `Images/Logo.PNG` for `images/logo.png`, for content migrated from
+/// case-insensitive file systems.
+///
+/// # Arguments
+///
+/// * `root`: The directory the path is below, whose own case is kept.
+/// * `path`: A path below `root`, e.g. from [`resolve`].
+///
+/// # Returns
+///
+/// The path with the case of each component as stored, preferring an exact match. Components
+/// that match nothing are kept, so the result may not exist.
+pub async fn match_case(root: &Path, path: &Path) -> PathBuf {
+    let Ok(relative) = path.strip_prefix(root) else {
+        return path.to_path_buf();
+    };
+    let mut matched = root.to_path_buf();
+    for component in relative.components() {
+        let Component::Normal(name) = component else {
+            matched.push(component);
+            continue;
+        };
+        let exact = matched.join(name);
+        if tokio::fs::symlink_metadata(&exact).await.is_ok() {
+            matched = exact;
+            continue;
+        }
+        let wanted = name.to_string_lossy().to_lowercase();
+        let mut found = None;
+        if let Ok(mut entries) = tokio::fs::read_dir(&matched).await {
+            while let Ok(Some(entry)) = entries.next_entry().await {
+                if entry.file_name().to_string_lossy().to_lowercase() == wanted {
+                    found = Some(entry.file_name());
+                    break;
+                }
+            }
+        }
+        matched.push(found.as_deref().unwrap_or(name));
+    }
+    matched
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// It decodes escapes and rejects truncated ones
+    #[test]
+    fn decode() {
+        assert_eq!(Some("/a b/c".to_string()), percent_decode("/a%20b/c"));
+        assert_eq!(None, percent_decode("/a%2"));
+        assert_eq!(None, percent_decode("/a%zz"));
+    }
+
+    /// It escapes reserved bytes but keeps separators
+    #[test]
+    fn encode() {
+        assert_eq!("/a%20b/%C3%A9", percent_encode("/a b/é"));
+    }
+
+    /// It confines resolved paths to the root
+    #[test]
+    fn resolve_below_root() {
+        let root = Path::new("/srv");
+        assert_eq!(
+            Some(PathBuf::from("/srv/a b/c")),
+            resolve(root, "/a%20b/./c")
+        );
+        assert_eq!(Some(PathBuf::from("/srv")), resolve(root, "/"));
+        assert_eq!(None, resolve(root, "/a/../../etc/passwd"));
+        assert_eq!(None, resolve(root, "/a/%2E%2E/b"));
+    }
+
+    /// It removes empty, current, and parent segments after decoding
+    #[test]
+    fn normalize_segments() {
+        assert_eq!(Some("/a/c".to_string()), normalize("/a//./b/../c/"));
+        assert_eq!(
+            Some("/admin".to_string()),
+            normalize("/health/%2E%2E/admin")
+        );
+        assert_eq!(Some("/".to_string()), normalize("/../.."));
+        assert_eq!(None, normalize("/a%zz"));
+    }
+
+    /// It matches prefixes by whole segments
+    #[test]
+    fn below_prefix() {
+        assert!(is_below("/health", "/health"));
+        assert!(is_below("/health/live", "/health"));
+        assert!(is_below("/health/live", "/health/"));
+        assert!(!is_below("/healthz-expensive", "/health"));
+        assert!(!is_below("/admin", "/health"));
+        assert!(is_below("/anything", "/"));
+    }
+
+    /// It finds files whose components differ in case and keeps unknown ones
+    #[tokio::test]
+    async fn match_case() {
+        let root = tempfile::tempdir().unwrap();
+        std::fs::create_dir(root.path().join("Images")).unwrap();
+        std::fs::write(root.path().join("Images/logo.png"), "").unwrap();
+        assert_eq!(
+            root.path().join("Images/logo.png"),
+            super::match_case(root.path(), &root.path().join("images/LOGO.PNG")).await
+        );
+        assert_eq!(
+            root.path().join("Images/missing/a"),
+            super::match_case(root.path(), &root.path().join("IMAGES/missing/a")).await
+        );
+    }
+}
@@ -0,0 +1,214 @@
+//! Dropping root privileges once the listeners are bound, so a server started as root to bind
+//! ports 80 and 443 serves requests as an unprivileged account.
+
+use crate::log;
+use std::ffi::CString;
+use std::io;
+use std::os::raw::c_char;
+
+/// Switches the process to a user and group for good, e.g. `www-data`. Call it after binding
+/// every listener and before serving, since an unprivileged account cannot bind ports below
+/// 1024. Files the server reads later, such as certificates on reload, must be readable by the
+/// account.
+///
+/// # Arguments
+///
+/// * `user`: The name or numeric ID of the user, whose primary group is used without `group`.
+/// * `group`: The name or numeric ID of the group.
+///
+/// # Returns
+///
+/// `Ok(())` once the process runs as the account, right away without either or when it
+/// already does, e.g. after an upgrade.
+///
+/// # Errors
+///
+/// The errors of [`Account::lookup`] and [`Account::switch`].
+pub fn drop_privileges(user: Option<&str>, group: Option<&str>) -> io::Result<()> {
+    match Account::lookup(user, group)? {
+        Some(account) => account.switch(),
+        None => Ok(()),
+    }
+}
+
+/// The IDs of an account to switch to, looked up ahead of time, e.g. before a
+/// [`crate::sandbox::Sandbox`] hides the account database.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Account {
+    /// The user, or `None` to keep the current one.
+    pub uid: Option<libc::uid_t>,
+    /// The group.
+    pub gid: libc::gid_t,
+}
+
+impl Account {
+    /// Looks up a user and group by name or numeric ID.
+    ///
+    /// # Arguments
+    ///
+    /// * `user`: The user, whose primary group is used without `group`.
+    /// * `group`: The group.
+    ///
+    /// # Returns
+    ///
+    /// The account, or `None` without either.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`io::ErrorKind::NotFound`] for an unknown account, and captures errors from
+    /// reading the account database.
+    pub fn lookup(user: Option<&str>, group: Option<&str>) -> io::Result<Option<Account>> {
+        let user = user.map(lookup_user).transpose()?;
+        let gid = match (group, user) {
+            (Some(group), _) => lookup_group(group)?,
+            (None, Some((_, gid))) => gid,
+            (None, None) => return Ok(None),
+        };
+        Ok(Some(Account {
+            uid: user.map(|(uid, _)| uid),
+            gid,
+        }))
+    }
+
+    /// Switches the process to the account, see [`drop_privileges`].
+    ///
+    /// # Errors
+    ///
+    /// Returns [`io::ErrorKind::PermissionDenied`] when the process could regain root
+    /// afterwards, and captures the errors of the system calls, e.g. when the process is not
+    /// root. The process must not serve after an error.
+    pub fn switch(&self) -> io::Result<()> {
+        let Account { uid, gid } = *self;
+        // SAFETY: These calls only read and change the credentials of the process
+        unsafe {
+            if libc::geteuid() == uid.unwrap_or(libc::geteuid()) && libc::getegid() == gid {
+                return Ok(());
+            }
+            // Supplementary groups of root, e.g. `disk`, would survive the switch otherwise
+            if libc::setgroups(1, &gid) != 0 || libc::setgid(gid) != 0 {
+                return Err(io::Error::last_os_error());
+            }
+            if let Some(uid) = uid {
+                if libc::setuid(uid) != 0 {
+                    return Err(io::Error::last_os_error());
+                }
+                if uid != 0 && libc::setuid(0) == 0 {
+                    return Err(io::Error::new(
+                        io::ErrorKind::PermissionDenied,
+                        "the process could regain root after dropping privileges",
+                    ));
+                }
+            }
+            if libc::getegid() != gid || uid.is_some_and(|uid| libc::geteuid() != uid) {
+                return Err(io::Error::new(
+                    io::ErrorKind::PermissionDenied,
+                    "the process kept its privileges",
+                ));
+            }
+        }
+        log::info(format_args!(
+            "serving as user {} and group {}",
+            uid.map_or_else(|| "unchanged".to_string(), |uid| uid.to_string()),
+            gid
+        ));
+        Ok(())
+    }
+}
+
+/// Finds the ID and primary group of a user by name or numeric ID.
+fn lookup_user(user: &str) -> io::Result<(libc::uid_t, libc::gid_t)> {
+    // SAFETY: The entry is plain data the lookup fills in, for which zeros are valid
+    let mut entry: libc::passwd = unsafe { std::mem::zeroed() };
+    let mut buffer = vec![0 as c_char; 16 * 1024];
+    let mut found = std::ptr::null_mut();
+    let name = CString::new(user)?;
+    // SAFETY: Every pointer is valid for the call, and the buffer holds the strings of the entry
+    let code = unsafe {
+        match user.parse() {
+            Ok(uid) => libc::getpwuid_r(
+                uid,
+                &mut entry,
+                buffer.as_mut_ptr(),
+                buffer.len(),
+                &mut found,
+            ),
+            Err(_) => libc::getpwnam_r(
+                name.as_ptr(),
+                &mut entry,
+                buffer.as_mut_ptr(),
+                buffer.len(),
+                &mut found,
+            ),
+        }
+    };
+    if code != 0 {
+        return Err(io::Error::from_raw_os_error(code));
+    }
+    if found.is_null() {
+        return Err(not_found("user", user));
+    }
+    Ok((entry.pw_uid, entry.pw_gid))
+}
+
+/// Finds the ID of a group by name, or takes a numeric ID as is.
+fn lookup_group(group: &str) -> io::Result<libc::gid_t> {
+    if let Ok(gid) = group.parse() {
+        return Ok(gid);
+    }
+    // SAFETY: The entry is plain data the lookup fills in, for which zeros are valid
+    let mut entry: libc::group = unsafe { std::mem::zeroed() };
+    let mut buffer = vec![0 as c_char; 16 * 1024];
+    let mut found = std::ptr::null_mut();
+    let name = CString::new(group)?;
+    // SAFETY: Every pointer is valid for the call, and the buffer holds the strings of the entry
+    let code = unsafe {
+        libc::getgrnam_r(
+            name.as_ptr(),
+            &mut entry,
+            buffer.as_mut_ptr(),
+            buffer.len(),
+            &mut found,
+        )
+    };
+    if code != 0 {
+        return Err(io::Error::from_raw_os_error(code));
+    }
+    if found.is_null() {
+        return Err(not_found("group", group));
+    }
+    Ok(entry.gr_gid)
+}
+
+fn not_found(kind: &str, name: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::NotFound,
+        format!("no {} named {}", kind, name),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// It finds accounts by name or ID and reports unknown ones
+    #[test]
+    fn lookup() {
+        assert_eq!((0, 0), lookup_user("root").unwrap());
+        assert_eq!((0, 0), lookup_user("0").unwrap());
+        assert_eq!(0, lookup_group("root").unwrap());
+        assert_eq!(7, lookup_group("7").unwrap());
+        assert_eq!(
+            io::ErrorKind::NotFound,
+            lookup_user("no-such-user").unwrap_err().kind()
+        );
+        assert_eq!(
+            io::ErrorKind::NotFound,
+            lookup_group("no-such-group").unwrap_err().kind()
+        );
+        assert_eq!(
+            Some(Account { uid: None, gid: 7 }),
+            Account::lookup(None, Some("7")).unwrap()
+        );
+        assert!(drop_privileges(None, None).is_ok());
+    }
+}
@@ -0,0 +1,90 @@
+//! Sampling where the whole process spends CPU time for a while, rendered as a flamegraph, so
+//! hotspots of a running server can be found without deploying an instrumented build.
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+use tokio::{io, time};
+
+/// The longest a profile may run.
+pub const MAX_DURATION: Duration = Duration::from_secs(60);
+
+/// How often the stacks of every thread are sampled per second, off the beat of timers
+/// firing at round frequencies.
+const FREQUENCY: i32 = 99;
+
+/// Whether a profile is running, since the process can only be sampled by one at a time.
+static RUNNING: AtomicBool = AtomicBool::new(false);
+
+/// Samples the stacks of every thread for a while.
+///
+/// # Arguments
+///
+/// * `duration`: How long to sample, at most [`MAX_DURATION`].
+///
+/// # Returns
+///
+/// The flamegraph as an SVG image, empty when no thread ran in the meantime.
+///
+/// # Errors
+///
+/// Returns [`io::ErrorKind::ResourceBusy`] while another profile runs, and
+/// [`io::ErrorKind::Other`] when the profiler cannot start or symbolize the samples.
+pub async fn flamegraph(duration: Duration) -> io::Result<Vec<u8>> {
+    if RUNNING.swap(true, Ordering::AcqRel) {
+        return Err(io::Error::new(
+            io::ErrorKind::ResourceBusy,
+            "another profile is running",
+        ));
+    }
+    let _running = Running;
+    let guard = pprof::ProfilerGuardBuilder::default()
+        .frequency(FREQUENCY)
+        // Unwinding through these while a signal interrupts them can deadlock
+        .blocklist(&["libc", "libgcc", "pthread", "vdso"])
+        .build()
+        .map_err(io::Error::other)?;
+    time::sleep(duration.min(MAX_DURATION)).await;
+    let report = guard.report().build().map_err(io::Error::other)?;
+    drop(guard);
+    let mut svg = Vec::new();
+    report.flamegraph(&mut svg).map_err(io::Error::other)?;
+    Ok(svg)
+}
+
+/// Marks the profile as finished when dropped, also when the request is cancelled.
+struct Running;
+
+impl Drop for Running {
+    fn drop(&mut self) {
+        RUNNING.store(false, Ordering::Release);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// It renders a flamegraph and refuses a second profile while one runs
+    #[tokio::test]
+    async fn flamegraph() {
+        let first = tokio::spawn(super::flamegraph(Duration::from_millis(300)));
+        tokio::task::yield_now().await;
+        let spin = tokio::task::spawn_blocking(|| {
+            let start = std::time::Instant::now();
+            let mut spins = 0u64;
+            while start.elapsed() < Duration::from_millis(400) {
+                for _ in 0..1_000_000 {
+                    spins = std::hint::black_box(spins.wrapping_mul(31).wrapping_add(1));
+                }
+            }
+        });
+        time::sleep(Duration::from_millis(50)).await;
+        let busy = super::flamegraph(Duration::from_millis(10))
+            .await
+            .unwrap_err();
+        assert_eq!(io::ErrorKind::ResourceBusy, busy.kind());
+        let svg = first.await.unwrap().unwrap();
+        spin.await.unwrap();
+        assert!(svg.starts_with(b"<?xml"));
+    }
+}
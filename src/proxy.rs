@@ -0,0 +1,1093 @@
+mod affinity;
+mod breaker;
+pub mod cache;
+mod health;
+mod retry;
+mod upstream;
+
+use crate::cache::cacheable_request;
+use crate::canary::CanaryRule;
+use crate::header::HeaderMap;
+use crate::log;
+use crate::method::Method;
+use crate::request::Request;
+use crate::response::Response;
+use crate::router::Handler;
+use crate::split::SplitRule;
+use crate::status::StatusCode;
+pub use affinity::Affinity;
+use async_trait::async_trait;
+pub use breaker::{CircuitBreaker, CircuitState};
+use bytes::{Bytes, BytesMut};
+use cache::{DiskCache, Lookup};
+pub use health::HealthCheck;
+use retry::RetryBudget;
+pub use retry::RetryPolicy;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::io::{self, AsyncBufReadExt, AsyncReadExt};
+use tokio::sync::Semaphore;
+use tokio::time;
+use upstream::{Balancer, Upstream};
+pub use upstream::{Strategy, UpstreamStats};
+
+/// Headers that only describe one connection and are never forwarded, in either direction.
+const HOP_BY_HOP: [&str; 9] = [
+    "Connection",
+    "Keep-Alive",
+    "Proxy-Connection",
+    "Proxy-Authenticate",
+    "Proxy-Authorization",
+    "TE",
+    "Trailer",
+    "Transfer-Encoding",
+    "Upgrade",
+];
+
+/// The most mirrored copies in flight by default, see [`Proxy::with_max_mirrored`].
+const MAX_MIRRORED: usize = 64;
+
+/// The largest upstream body buffered by default, 64 MiB.
+pub(crate) const MAX_RESPONSE_SIZE: usize = 64 * 1024 * 1024;
+
+/// A reverse proxy forwarding requests at or below a route to a pool of upstream HTTP/1.1
+/// servers, reusing connections to each of them.
+///
+/// It is consulted right after the router when present in [`crate::config::Config::proxy`],
+/// and can also be registered on a [`crate::router::Router`] like any other [`Handler`].
+#[derive(Clone, Debug)]
+pub struct Proxy {
+    route: String,
+    balancer: Balancer,
+    timeout: Duration,
+    max_response_size: usize,
+    cache: Option<DiskCache>,
+    health_check: Option<HealthCheck>,
+    circuit_breaker: Option<CircuitBreaker>,
+    retry: Option<RetryPolicy>,
+    affinity: Option<Affinity>,
+    canary: Option<(Vec<CanaryRule>, Arc<Proxy>)>,
+    split: Option<(SplitRule, Arc<Proxy>)>,
+    mirror: Option<Arc<Proxy>>,
+    /// Permits for mirrored copies in flight, shared by clones.
+    mirrored: Arc<Semaphore>,
+    retry_budget: Arc<RetryBudget>,
+}
+
+impl Proxy {
+    /// Creates a proxy without a cache that gives up on the upstream after 30 seconds and
+    /// answers 502 for upstream bodies above 64 MiB.
+    ///
+    /// # Arguments
+    ///
+    /// * `route`: The path prefix forwarded, e.g. `/api`, or `/` for every request.
+    /// * `upstream`: The upstream address, e.g. `127.0.0.1:8080`.
+    ///
+    /// # Returns
+    ///
+    /// The proxy.
+    pub fn new(route: &str, upstream: impl Into<String>) -> Proxy {
+        Proxy {
+            route: route.to_string(),
+            balancer: Balancer::new(Upstream::new(upstream.into())),
+            timeout: Duration::from_secs(30),
+            max_response_size: MAX_RESPONSE_SIZE,
+            cache: None,
+            health_check: None,
+            circuit_breaker: None,
+            retry: None,
+            affinity: None,
+            canary: None,
+            split: None,
+            mirror: None,
+            mirrored: Arc::new(Semaphore::new(MAX_MIRRORED)),
+            retry_budget: Arc::new(RetryBudget::new()),
+        }
+    }
+
+    /// Adds an upstream to the pool that requests are balanced across.
+    ///
+    /// # Arguments
+    ///
+    /// * `upstream`: The upstream address, e.g. `127.0.0.1:8081`.
+    ///
+    /// # Returns
+    ///
+    /// The proxy with the upstream added.
+    pub fn with_upstream(mut self, upstream: impl Into<String>) -> Proxy {
+        self.balancer
+            .upstreams
+            .push(Arc::new(Upstream::new(upstream.into())));
+        self
+    }
+
+    /// Sets how the upstream for each request is picked, [`Strategy::RoundRobin`] by default.
+    ///
+    /// # Arguments
+    ///
+    /// * `strategy`: The balancing strategy.
+    ///
+    /// # Returns
+    ///
+    /// The proxy with the new strategy.
+    pub fn with_strategy(mut self, strategy: Strategy) -> Proxy {
+        self.balancer.strategy = strategy;
+        self
+    }
+
+    /// Sets the share of traffic an upstream receives relative to the others, 1 by default.
+    ///
+    /// Weights can change while the proxy serves requests, e.g. to shift traffic gradually to a
+    /// new version of a backend, and apply to every clone of the proxy. An upstream with weight
+    /// 0 only receives requests when no other upstream is available.
+    ///
+    /// # Arguments
+    ///
+    /// * `address`: The upstream address as it was added.
+    /// * `weight`: The new weight.
+    ///
+    /// # Returns
+    ///
+    /// Whether an upstream with the address exists.
+    pub fn set_weight(&self, address: &str, weight: u32) -> bool {
+        let upstream = self
+            .balancer
+            .upstreams
+            .iter()
+            .find(|upstream| upstream.address() == address);
+        if let Some(upstream) = upstream {
+            upstream.set_weight(weight);
+        }
+        upstream.is_some()
+    }
+
+    /// The counters of every upstream in the order they were added, followed by those of the
+    /// canary, the split, and the mirror, see [`Proxy::with_canary`], [`Proxy::with_split`], and
+    /// [`Proxy::with_mirror`].
+    pub fn upstream_stats(&self) -> Vec<UpstreamStats> {
+        let mut stats: Vec<UpstreamStats> = self
+            .balancer
+            .upstreams
+            .iter()
+            .map(|upstream| upstream.stats())
+            .collect();
+        if let Some((_, canary)) = &self.canary {
+            stats.extend(canary.upstream_stats());
+        }
+        if let Some((_, alternate)) = &self.split {
+            stats.extend(alternate.upstream_stats());
+        }
+        if let Some(mirror) = &self.mirror {
+            stats.extend(mirror.upstream_stats());
+        }
+        stats
+    }
+
+    /// Probes the upstreams in the background once [`Proxy::spawn_health_checks`] is called,
+    /// routing requests only to those that pass.
+    ///
+    /// # Arguments
+    ///
+    /// * `check`: The health check settings.
+    ///
+    /// # Returns
+    ///
+    /// The proxy with health checks.
+    pub fn with_health_check(mut self, check: HealthCheck) -> Proxy {
+        self.health_check = Some(check);
+        self
+    }
+
+    /// Opens a circuit per upstream once it fails too often, so requests fail fast instead of
+    /// piling onto a dying backend.
+    ///
+    /// # Arguments
+    ///
+    /// * `breaker`: The circuit breaker settings.
+    ///
+    /// # Returns
+    ///
+    /// The proxy with circuit breakers.
+    pub fn with_circuit_breaker(mut self, breaker: CircuitBreaker) -> Proxy {
+        self.circuit_breaker = Some(breaker);
+        self
+    }
+
+    /// Retries idempotent requests that failed on another upstream. Each proxy, and so each
+    /// route it is registered at, has its own policy and budget.
+    ///
+    /// # Arguments
+    ///
+    /// * `policy`: The retry settings.
+    ///
+    /// # Returns
+    ///
+    /// The proxy retrying failed requests.
+    pub fn with_retry(mut self, policy: RetryPolicy) -> Proxy {
+        self.retry = Some(policy);
+        self
+    }
+
+    /// Keeps clients on the same upstream across requests.
+    ///
+    /// # Arguments
+    ///
+    /// * `affinity`: How clients are pinned to upstreams.
+    ///
+    /// # Returns
+    ///
+    /// The proxy with sticky sessions.
+    pub fn with_affinity(mut self, affinity: Affinity) -> Proxy {
+        self.affinity = Some(affinity);
+        self
+    }
+
+    /// Forwards the requests matching any of a set of rules to another pool of upstreams, e.g.
+    /// a new version of the backend tried with selected traffic.
+    ///
+    /// # Arguments
+    ///
+    /// * `rules`: The headers and cookies marking requests for the canary.
+    /// * `canary`: The proxy for those requests, with its own upstreams and settings.
+    ///
+    /// # Returns
+    ///
+    /// The proxy with a canary.
+    pub fn with_canary(mut self, rules: Vec<CanaryRule>, canary: Proxy) -> Proxy {
+        self.canary = Some((rules, Arc::new(canary)));
+        self
+    }
+
+    /// Forwards the requests of a percentage of clients to another pool of upstreams, each
+    /// client staying on the same pool across requests, for A/B tests and gradual rollouts.
+    /// Requests marked for a canary go to the canary first, see [`Proxy::with_canary`].
+    ///
+    /// # Arguments
+    ///
+    /// * `rule`: The percentage of clients and how they stick to a pool.
+    /// * `alternate`: The proxy for those clients, with its own upstreams and settings.
+    ///
+    /// # Returns
+    ///
+    /// The proxy splitting traffic.
+    pub fn with_split(mut self, rule: SplitRule, alternate: Proxy) -> Proxy {
+        self.split = Some((rule, Arc::new(alternate)));
+        self
+    }
+
+    /// Sends a copy of every request to another pool of upstreams in the background, e.g. to
+    /// soak-test a new backend with production traffic. Its responses are discarded and its
+    /// failures only logged, so clients never wait for it. Copies are dropped while 64 of them
+    /// are in flight, see [`Proxy::with_max_mirrored`].
+    ///
+    /// # Arguments
+    ///
+    /// * `mirror`: The proxy for the copies, with its own upstreams and settings.
+    ///
+    /// # Returns
+    ///
+    /// The proxy mirroring requests.
+    pub fn with_mirror(mut self, mirror: Proxy) -> Proxy {
+        self.mirror = Some(Arc::new(mirror));
+        self
+    }
+
+    /// Sets how many mirrored copies may be in flight, beyond which further copies are dropped
+    /// so that a slow mirror cannot pile up background tasks.
+    ///
+    /// # Arguments
+    ///
+    /// * `max_mirrored`: The most copies in flight.
+    ///
+    /// # Returns
+    ///
+    /// The proxy with the new limit.
+    pub fn with_max_mirrored(mut self, max_mirrored: usize) -> Proxy {
+        self.mirrored = Arc::new(Semaphore::new(max_mirrored));
+        self
+    }
+
+    /// Sets how long an exchange with the upstream may take before it is answered with 504.
+    ///
+    /// # Arguments
+    ///
+    /// * `timeout`: The limit for connecting, sending the request, and reading the response.
+    ///
+    /// # Returns
+    ///
+    /// The proxy with the new timeout.
+    pub fn with_timeout(mut self, timeout: Duration) -> Proxy {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Sets the largest upstream body that is buffered, above which the response is 502.
+    ///
+    /// # Arguments
+    ///
+    /// * `max_response_size`: The limit in bytes.
+    ///
+    /// # Returns
+    ///
+    /// The proxy with the new limit.
+    pub fn with_max_response_size(mut self, max_response_size: usize) -> Proxy {
+        self.max_response_size = max_response_size;
+        self
+    }
+
+    /// Stores cacheable upstream responses on disk, see [`DiskCache`].
+    ///
+    /// # Arguments
+    ///
+    /// * `cache`: The cache.
+    ///
+    /// # Returns
+    ///
+    /// The proxy answering from the cache.
+    pub fn with_cache(mut self, cache: DiskCache) -> Proxy {
+        self.cache = Some(cache);
+        self
+    }
+
+    /// The path prefix forwarded, as passed to the constructor.
+    pub fn route(&self) -> &str {
+        &self.route
+    }
+
+    /// Checks whether a request is at or below the proxy route.
+    ///
+    /// # Arguments
+    ///
+    /// * `request`: The incoming request.
+    ///
+    /// # Returns
+    ///
+    /// True when the path equals the route or continues it with a `/`.
+    pub fn matches(&self, request: &Request) -> bool {
+        let route = self.route.trim_end_matches('/');
+        request
+            .path()
+            .strip_prefix(route)
+            .is_some_and(|rest| rest.is_empty() || rest.starts_with('/'))
+    }
+
+    /// Sends a request upstream, turning failures into gateway errors.
+    ///
+    /// # Arguments
+    ///
+    /// * `request`: The request with its body read.
+    ///
+    /// # Returns
+    ///
+    /// The upstream response after any retries, 502 when the upstream cannot be reached or
+    /// answers with something that is not HTTP, 503 when health checks or open circuits took
+    /// every upstream out of rotation, or 504 when it does not answer in time. Retried
+    /// responses carry the number of retries in `X-Retry-Count`.
+    async fn forward(&self, request: &Request) -> Response {
+        let breaker = self.circuit_breaker.as_ref();
+        let retry = self
+            .retry
+            .as_ref()
+            .filter(|_| request.method.is_idempotent());
+        let mut candidates = self.balancer.candidates();
+        if let Some(affinity) = &self.affinity {
+            affinity.prefer(request, &mut candidates);
+        }
+        let now = Instant::now();
+        if retry.is_some() {
+            self.retry_budget.deposit(now);
+        }
+        let mut available = candidates
+            .iter()
+            .filter(|upstream| upstream.breaker.try_acquire(breaker, now));
+        let Some(first) = available.next() else {
+            let mut response = Response::new(StatusCode::SERVICE_UNAVAILABLE, "");
+            let retry_after = candidates
+                .iter()
+                .filter_map(|upstream| upstream.breaker.retry_after(breaker, now))
+                .min();
+            if let Some(retry_after) = retry_after {
+                let seconds = retry_after.as_secs() + u64::from(retry_after.subsec_nanos() > 0);
+                response = response.with_header("Retry-After", seconds.to_string());
+            }
+            return response;
+        };
+        let timeout = retry
+            .and_then(|retry| retry.per_try_timeout)
+            .unwrap_or(self.timeout);
+        let mut served = first;
+        let mut response = self.attempt(first, request, timeout).await;
+        let mut retries = 0;
+        if let Some(retry) = retry {
+            while response.status.is_server_error()
+                && retries < retry.max_retries
+                && self.retry_budget.withdraw(retry, Instant::now())
+            {
+                let Some(upstream) = available.next() else {
+                    break;
+                };
+                retries += 1;
+                let mut request = request.clone();
+                request.headers.insert("X-Retry-Count", retries.to_string());
+                response = self.attempt(upstream, &request, timeout).await;
+                served = upstream;
+            }
+        }
+        if retries > 0 {
+            response = response.with_header("X-Retry-Count", retries.to_string());
+        }
+        response.upstream = Some(served.address().to_string());
+        log::record("upstream", served.address());
+        log::record("upstream_time", format_args!("{:?}", now.elapsed()));
+        match &self.affinity {
+            Some(affinity) => affinity.pin(request, served, response),
+            None => response,
+        }
+    }
+
+    /// Sends one try of a request to an upstream admitted by its circuit breaker and records
+    /// the outcome there.
+    ///
+    /// # Arguments
+    ///
+    /// * `upstream`: The upstream.
+    /// * `request`: The request with its body read.
+    /// * `timeout`: How long the try may take.
+    ///
+    /// # Returns
+    ///
+    /// The upstream response, 502 when the upstream cannot be reached, answers with something
+    /// that is not HTTP, or with a body above the limit, or 504 when it does not answer in time.
+    async fn attempt(&self, upstream: &Upstream, request: &Request, timeout: Duration) -> Response {
+        let response =
+            match time::timeout(timeout, upstream.send(request, self.max_response_size)).await {
+                Ok(Ok(response)) => response,
+                Ok(Err(error)) => {
+                    log::warn(format_args!(
+                        "upstream {} failed: {}",
+                        upstream.address(),
+                        error
+                    ));
+                    Response::new(StatusCode::BAD_GATEWAY, "")
+                }
+                Err(_) => {
+                    log::warn(format_args!(
+                        "upstream {} did not answer within {:?}",
+                        upstream.address(),
+                        timeout
+                    ));
+                    Response::new(StatusCode::GATEWAY_TIMEOUT, "")
+                }
+            };
+        upstream.breaker.record(
+            self.circuit_breaker.as_ref(),
+            !response.status.is_server_error(),
+            Instant::now(),
+        );
+        response
+    }
+}
+
+/// Implementing the [`Handler`] trait for the [`Proxy`] struct, mirroring requests when
+/// configured and not too many copies are in flight, and passing them to the canary or the split first when one is configured and the
+/// request belongs there.
+#[async_trait]
+impl Handler for Proxy {
+    async fn call(&self, request: Request) -> io::Result<Response> {
+        if let Some(mirror) = &self.mirror {
+            match Arc::clone(&self.mirrored).try_acquire_owned() {
+                Ok(permit) => {
+                    let mirror = Arc::clone(mirror);
+                    let copy = request.clone();
+                    tokio::spawn(async move {
+                        let _permit = permit;
+                        let response = mirror.forward(&copy).await;
+                        if response.status.is_server_error() {
+                            log::warn(format_args!(
+                                "mirroring {} {} failed with {}",
+                                copy.method, copy.target, response.status
+                            ));
+                        }
+                    });
+                }
+                Err(_) => log::debug(format_args!(
+                    "dropped the mirrored copy of {} {}, too many are in flight",
+                    request.method, request.target
+                )),
+            }
+        }
+        if let Some((rules, canary)) = &self.canary {
+            if rules.iter().any(|rule| rule.matches(&request)) {
+                log::record("canary", true);
+                return canary.call(request).await;
+            }
+        }
+        let Some((rule, alternate)) = &self.split else {
+            return self.serve(request).await;
+        };
+        let (to_alternate, cookie) = rule.assign(&request);
+        let response = if to_alternate {
+            log::record("split", "alternate");
+            alternate.call(request).await?
+        } else {
+            self.serve(request).await?
+        };
+        Ok(match cookie {
+            Some(cookie) => response.with_header("Set-Cookie", cookie),
+            None => response,
+        })
+    }
+}
+
+impl Proxy {
+    /// Forwards a request to the own upstreams, answering from the cache when one is configured
+    /// and the response there is fresh or may be served while it revalidates.
+    async fn serve(&self, request: Request) -> io::Result<Response> {
+        let Some(cache) = self.cache.as_ref().filter(|_| cacheable_request(&request)) else {
+            return Ok(self.forward(&request).await);
+        };
+        match cache.lookup(&request).await? {
+            Lookup::Fresh(response) => return Ok(response),
+            Lookup::Stale(response) => {
+                if cache.begin_revalidation(&request) {
+                    let proxy = self.clone();
+                    let cache = cache.clone();
+                    tokio::spawn(async move {
+                        let response = proxy.forward(&request).await;
+                        if let Err(error) = cache.store(&request, &response).await {
+                            log::warn(format_args!(
+                                "caching the response to {} failed: {}",
+                                request.target, error
+                            ));
+                        }
+                        cache.end_revalidation(&request);
+                    });
+                }
+                return Ok(response);
+            }
+            Lookup::Miss => {}
+        }
+        let response = self.forward(&request).await;
+        cache.store(&request, &response).await?;
+        Ok(response)
+    }
+}
+
+/// Serializes the head of a request for the upstream.
+///
+/// # Arguments
+///
+/// * `request`: The request to forward.
+///
+/// # Returns
+///
+/// The request line and headers in origin form.
+fn request_head(request: &Request) -> BytesMut {
+    let mut head = BytesMut::new();
+    head.extend_from_slice(request.method.as_str().as_bytes());
+    head.extend_from_slice(b" ");
+    head.extend_from_slice(request.path().as_bytes());
+    if let Some(query) = request.target.query() {
+        head.extend_from_slice(b"?");
+        head.extend_from_slice(query.as_bytes());
+    }
+    head.extend_from_slice(b" HTTP/1.1\r\n");
+    let mut forwarded = end_to_end(&request.headers);
+    if !forwarded.contains("Host") {
+        if let Some(authority) = request.target.authority() {
+            forwarded.append("Host", authority);
+        }
+    }
+    forwarded.remove("Content-Length");
+    if let Some(trace) = &request.trace {
+        trace.inject(&mut forwarded);
+    }
+    if !request.body.is_empty() || matches!(request.method, Method::Post | Method::Put) {
+        forwarded.append("Content-Length", request.body.len().to_string());
+    }
+    for (name, value) in &forwarded {
+        head.extend_from_slice(name.as_bytes());
+        head.extend_from_slice(b": ");
+        head.extend_from_slice(value.as_bytes());
+        head.extend_from_slice(b"\r\n");
+    }
+    head.extend_from_slice(b"\r\n");
+    head
+}
+
+/// Copies the headers meant for the other end of the connection.
+///
+/// # Arguments
+///
+/// * `headers`: The received headers.
+///
+/// # Returns
+///
+/// The headers without [`HOP_BY_HOP`] ones and without those listed in `Connection`.
+fn end_to_end(headers: &HeaderMap) -> HeaderMap {
+    let listed: Vec<&str> = headers
+        .get_all("Connection")
+        .flat_map(|value| value.split(','))
+        .map(str::trim)
+        .collect();
+    headers
+        .iter()
+        .filter(|(name, _)| {
+            !HOP_BY_HOP
+                .iter()
+                .chain(&listed)
+                .any(|hop| hop.eq_ignore_ascii_case(name))
+        })
+        .collect()
+}
+
+/// Reads a response from the upstream, decoding a chunked body.
+///
+/// # Arguments
+///
+/// * `reader`: The buffered upstream connection positioned at a status line.
+/// * `head`: Whether the request was `HEAD`, whose response never has a body.
+/// * `max_body_size`: The largest body read, e.g. [`MAX_RESPONSE_SIZE`].
+///
+/// # Returns
+///
+/// The response and whether the connection may carry another request afterwards.
+/// `Content-Length` and hop-by-hop headers are dropped, since they are recomputed for the
+/// client.
+///
+/// # Errors
+///
+/// Returns [`io::ErrorKind::InvalidData`] for a malformed status line, header, or chunk, or a
+/// body above `max_body_size`, and [`io::ErrorKind::UnexpectedEof`] when the connection closes
+/// inside the head or body.
+pub(crate) async fn read_response<R>(
+    reader: &mut R,
+    head: bool,
+    max_body_size: usize,
+) -> io::Result<(Response, bool)>
+where
+    R: io::AsyncBufRead + Unpin + Send,
+{
+    let mut line = String::new();
+    read_line(reader, &mut line).await?;
+    let persistent = !line.starts_with("HTTP/1.0");
+    let status = line
+        .strip_prefix("HTTP/1.")
+        .and_then(|rest| rest.split(' ').nth(1))
+        .and_then(|code| code.parse().ok())
+        .and_then(StatusCode::from_u16)
+        .ok_or_else(|| invalid("malformed upstream status line"))?;
+    let mut headers = HeaderMap::new();
+    loop {
+        read_line(reader, &mut line).await?;
+        if line.is_empty() {
+            break;
+        }
+        let (name, value) = line
+            .split_once(':')
+            .ok_or_else(|| invalid("malformed upstream header"))?;
+        headers.append(name.trim(), value.trim());
+    }
+
+    let mut reusable = if persistent {
+        !headers.has_token("Connection", "close")
+    } else {
+        headers.has_token("Connection", "keep-alive")
+    };
+    let body = if head
+        || status.is_informational()
+        || status == StatusCode::NO_CONTENT
+        || status == StatusCode::NOT_MODIFIED
+    {
+        Bytes::new()
+    } else if headers.has_token("Transfer-Encoding", "chunked") {
+        read_chunked(reader, &mut line, max_body_size).await?
+    } else if let Some(length) = headers.get("Content-Length") {
+        let length: usize = length
+            .parse()
+            .map_err(|_| invalid("malformed upstream Content-Length"))?;
+        if length > max_body_size {
+            return Err(too_large(max_body_size));
+        }
+        let mut body = BytesMut::zeroed(length);
+        reader.read_exact(&mut body).await?;
+        body.freeze()
+    } else {
+        // Without framing the body ends with the connection
+        reusable = false;
+        let mut body = Vec::new();
+        let limit = u64::try_from(max_body_size).unwrap_or(u64::MAX);
+        (&mut *reader)
+            .take(limit.saturating_add(1))
+            .read_to_end(&mut body)
+            .await?;
+        if body.len() > max_body_size {
+            return Err(too_large(max_body_size));
+        }
+        Bytes::from(body)
+    };
+
+    let mut response = Response::new(status, body);
+    response.headers = end_to_end(&headers);
+    response.headers.remove("Content-Length");
+    Ok((response, reusable))
+}
+
+/// Reads a chunked body up to and including its trailer section, which is discarded.
+///
+/// # Arguments
+///
+/// * `reader`: The reader positioned at the first chunk size.
+/// * `line`: A buffer for lines.
+/// * `max_body_size`: The largest decoded body read.
+///
+/// # Returns
+///
+/// The decoded body.
+///
+/// # Errors
+///
+/// Returns [`io::ErrorKind::InvalidData`] for a malformed chunk size or chunk ending, or when the
+/// chunks add up to more than `max_body_size`.
+async fn read_chunked<R>(
+    reader: &mut R,
+    line: &mut String,
+    max_body_size: usize,
+) -> io::Result<Bytes>
+where
+    R: io::AsyncBufRead + Unpin + Send,
+{
+    let mut body = BytesMut::new();
+    loop {
+        read_line(reader, line).await?;
+        let size = line.split(';').next().unwrap_or_default().trim();
+        let size = usize::from_str_radix(size, 16).map_err(|_| invalid("malformed chunk size"))?;
+        if size == 0 {
+            break;
+        }
+        let start = body.len();
+        let end = start
+            .checked_add(size)
+            .filter(|end| *end <= max_body_size)
+            .ok_or_else(|| too_large(max_body_size))?;
+        body.resize(end, 0);
+        reader.read_exact(&mut body[start..]).await?;
+        read_line(reader, line).await?;
+        if !line.is_empty() {
+            return Err(invalid("chunk longer than its size"));
+        }
+    }
+    loop {
+        read_line(reader, line).await?;
+        if line.is_empty() {
+            return Ok(body.freeze());
+        }
+    }
+}
+
+/// Reads one line without its line ending.
+///
+/// # Errors
+///
+/// Returns [`io::ErrorKind::UnexpectedEof`] when the connection is closed before the line.
+async fn read_line<R>(reader: &mut R, line: &mut String) -> io::Result<()>
+where
+    R: io::AsyncBufRead + Unpin + Send,
+{
+    line.clear();
+    if reader.read_line(line).await? == 0 {
+        return Err(io::Error::from(io::ErrorKind::UnexpectedEof));
+    }
+    let trimmed = line.trim_end_matches(['\r', '\n']).len();
+    line.truncate(trimmed);
+    Ok(())
+}
+
+/// Hashes bytes with 64-bit FNV-1a, which is small, fast, and stable across builds.
+pub(crate) fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf2_9ce4_8422_2325, |hash, byte| {
+        (hash ^ u64::from(*byte)).wrapping_mul(0x0100_0000_01b3)
+    })
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_string())
+}
+
+fn too_large(max_body_size: usize) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("upstream body larger than {} bytes", max_body_size),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::AsyncWriteExt;
+    use tokio::net;
+
+    /// Starts an upstream that answers every request with the same bytes, keeping connections
+    /// open, and sends back each request head it received through the returned channel.
+    pub(super) async fn upstream(
+        response: &'static str,
+    ) -> (String, tokio::sync::mpsc::UnboundedReceiver<String>) {
+        let listener = net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let (sender, receiver) = tokio::sync::mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                let sender = sender.clone();
+                tokio::spawn(async move {
+                    let mut stream = io::BufReader::new(stream);
+                    loop {
+                        let mut head = String::new();
+                        while stream.read_line(&mut head).await.unwrap_or(0) > 2 {}
+                        if head.is_empty() || sender.send(head).is_err() {
+                            return;
+                        }
+                        if stream
+                            .get_mut()
+                            .write_all(response.as_bytes())
+                            .await
+                            .is_err()
+                        {
+                            return;
+                        }
+                    }
+                });
+            }
+        });
+        (address, receiver)
+    }
+
+    fn request(method: Method, target: &str, headers: &[(&str, &str)]) -> Request {
+        Request {
+            method,
+            target: target.parse().unwrap(),
+            headers: headers.iter().copied().collect(),
+            ..Request::default()
+        }
+    }
+
+    /// It forwards the request without hop-by-hop headers and decodes a chunked response
+    #[tokio::test]
+    async fn forward() {
+        let (address, mut heads) = upstream(
+            "HTTP/1.1 100 Continue\r\n\r\nHTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\
+             Connection: close, X-Hop\r\nX-Hop: 1\r\nX-Kept: 2\r\n\r\n\
+             3;ext=1\r\nabc\r\n2\r\nde\r\n0\r\nX-Trailer: 1\r\n\r\n",
+        )
+        .await;
+        let proxy = Proxy::new("/api", address);
+        let request = request(
+            Method::Get,
+            "/api/items?x=1",
+            &[
+                ("Host", "example"),
+                ("Connection", "X-Secret"),
+                ("X-Secret", "s"),
+                ("Keep-Alive", "5"),
+            ],
+        );
+        assert!(proxy.matches(&request));
+        let response = proxy.call(request).await.unwrap();
+        assert_eq!(StatusCode::OK, response.status);
+        assert_eq!(&b"abcde"[..], &response.body[..]);
+        assert_eq!(
+            vec![("X-Kept", "2")],
+            response.headers.iter().collect::<Vec<_>>()
+        );
+        assert_eq!(
+            "GET /api/items?x=1 HTTP/1.1\r\nHost: example\r\n\r\n",
+            heads.recv().await.unwrap()
+        );
+    }
+
+    /// It balances requests across upstreams and reuses their connections
+    #[tokio::test]
+    async fn balance() {
+        let (first, mut first_heads) =
+            upstream("HTTP/1.1 200 OK\r\nContent-Length: 1\r\n\r\na").await;
+        let (second, mut second_heads) =
+            upstream("HTTP/1.1 200 OK\r\nContent-Length: 1\r\n\r\nb").await;
+        let proxy = Proxy::new("/", first.clone()).with_upstream(second.clone());
+        let mut bodies = Vec::new();
+        for _ in 0..4 {
+            let response = proxy.call(request(Method::Get, "/", &[])).await.unwrap();
+            bodies.push(response.body);
+        }
+        assert_eq!(vec!["a", "b", "a", "b"], bodies);
+        for heads in [&mut first_heads, &mut second_heads] {
+            heads.recv().await.unwrap();
+            heads.recv().await.unwrap();
+        }
+        let stats = |address: String| UpstreamStats {
+            address,
+            requests: 2,
+            failures: 0,
+            active: 0,
+            idle: 1,
+            healthy: true,
+            circuit: CircuitState::Closed,
+            weight: 1,
+        };
+        assert_eq!(vec![stats(first), stats(second)], proxy.upstream_stats());
+    }
+
+    /// It forwards requests marked for the canary to the canary upstreams
+    #[tokio::test]
+    async fn canary() {
+        let (stable, _stable_heads) =
+            upstream("HTTP/1.1 200 OK\r\nContent-Length: 1\r\n\r\na").await;
+        let (canary, _canary_heads) =
+            upstream("HTTP/1.1 200 OK\r\nContent-Length: 1\r\n\r\nb").await;
+        let rules = vec![CanaryRule::header("X-Canary=1").unwrap()];
+        let proxy = Proxy::new("/", stable).with_canary(rules, Proxy::new("/", canary));
+        let marked = request(Method::Get, "/", &[("X-Canary", "1")]);
+        assert_eq!("b", proxy.call(marked).await.unwrap().body);
+        let unmarked = request(Method::Get, "/", &[]);
+        assert_eq!("a", proxy.call(unmarked).await.unwrap().body);
+        assert_eq!(2, proxy.upstream_stats().len());
+    }
+
+    /// It sends a copy of each request to the mirror and answers from the upstream, dropping
+    /// copies while too many are in flight
+    #[tokio::test]
+    async fn mirror() {
+        let (address, _heads) = upstream("HTTP/1.1 200 OK\r\nContent-Length: 1\r\n\r\na").await;
+        let (mirror, mut mirror_heads) =
+            upstream("HTTP/1.1 500 Oops\r\nContent-Length: 0\r\n\r\n").await;
+        let proxy = Proxy::new("/", address).with_mirror(Proxy::new("/", mirror));
+        let response = proxy.call(request(Method::Get, "/a", &[])).await.unwrap();
+        assert_eq!("a", response.body);
+        assert!(mirror_heads
+            .recv()
+            .await
+            .unwrap()
+            .starts_with("GET /a HTTP/1.1\r\n"));
+
+        // The copy to a mirror that never answers holds the only permit
+        let listener = net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let silent = listener.local_addr().unwrap().to_string();
+        let (sender, mut silent_heads) = tokio::sync::mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                let sender = sender.clone();
+                tokio::spawn(async move {
+                    let mut stream = io::BufReader::new(stream);
+                    let mut head = String::new();
+                    while stream.read_line(&mut head).await.unwrap_or(0) > 2 {}
+                    let _ = sender.send(head);
+                    std::future::pending::<()>().await;
+                });
+            }
+        });
+        let proxy = proxy
+            .with_mirror(Proxy::new("/", silent))
+            .with_max_mirrored(1);
+        proxy.call(request(Method::Get, "/b", &[])).await.unwrap();
+        assert!(silent_heads.recv().await.unwrap().starts_with("GET /b "));
+        proxy.call(request(Method::Get, "/c", &[])).await.unwrap();
+        assert!(
+            time::timeout(Duration::from_millis(100), silent_heads.recv())
+                .await
+                .is_err()
+        );
+    }
+
+    /// It fails fast with 503 while the only upstream's circuit is open
+    #[tokio::test]
+    async fn circuit_breaker() {
+        let (address, mut heads) = upstream("HTTP/1.1 500 Oops\r\nContent-Length: 0\r\n\r\n").await;
+        let proxy = Proxy::new("/", address).with_circuit_breaker(CircuitBreaker {
+            minimum_requests: 2,
+            ..CircuitBreaker::default()
+        });
+        for _ in 0..2 {
+            let response = proxy.call(request(Method::Get, "/", &[])).await.unwrap();
+            assert_eq!(StatusCode::INTERNAL_SERVER_ERROR, response.status);
+            heads.recv().await.unwrap();
+        }
+        assert_eq!(CircuitState::Open, proxy.upstream_stats()[0].circuit);
+        let response = proxy.call(request(Method::Get, "/", &[])).await.unwrap();
+        assert_eq!(StatusCode::SERVICE_UNAVAILABLE, response.status);
+        assert_eq!(Some("30"), response.header("Retry-After"));
+        assert!(heads.try_recv().is_err());
+    }
+
+    /// It retries idempotent requests on the next upstream and reports the retries
+    #[tokio::test]
+    async fn retry() {
+        let (failing, mut failing_heads) =
+            upstream("HTTP/1.1 503 Busy\r\nContent-Length: 0\r\n\r\n").await;
+        let (working, mut working_heads) =
+            upstream("HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nok").await;
+        let proxy = Proxy::new("/", failing)
+            .with_upstream(working)
+            .with_retry(RetryPolicy::default());
+        let response = proxy.call(request(Method::Get, "/", &[])).await.unwrap();
+        assert_eq!(StatusCode::OK, response.status);
+        assert_eq!(Some("1"), response.header("X-Retry-Count"));
+        assert!(!failing_heads
+            .recv()
+            .await
+            .unwrap()
+            .contains("X-Retry-Count"));
+        assert!(working_heads
+            .recv()
+            .await
+            .unwrap()
+            .contains("X-Retry-Count: 1\r\n"));
+
+        // Turns alternate, so the POST below starts at the failing upstream and is not retried
+        proxy.call(request(Method::Get, "/", &[])).await.unwrap();
+        working_heads.recv().await.unwrap();
+        let response = proxy.call(request(Method::Post, "/", &[])).await.unwrap();
+        assert_eq!(StatusCode::SERVICE_UNAVAILABLE, response.status);
+        assert_eq!(None, response.header("X-Retry-Count"));
+    }
+
+    /// It only matches paths at or below its route
+    #[test]
+    fn matches() {
+        let proxy = Proxy::new("/api/", "127.0.0.1:1");
+        assert!(proxy.matches(&request(Method::Post, "/api", &[])));
+        assert!(!proxy.matches(&request(Method::Get, "/apis", &[])));
+        assert!(Proxy::new("/", "127.0.0.1:1").matches(&request(Method::Get, "/a", &[])));
+    }
+
+    /// It answers with 502 when the upstream cannot be reached, does not speak HTTP, or sends a
+    /// body above the limit
+    #[tokio::test]
+    async fn bad_gateway() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let closed = listener.local_addr().unwrap().to_string();
+        drop(listener);
+        let proxy = Proxy::new("/", closed);
+        let response = proxy.call(request(Method::Get, "/", &[])).await.unwrap();
+        assert_eq!(StatusCode::BAD_GATEWAY, response.status);
+        assert_eq!(1, proxy.upstream_stats()[0].failures);
+        let (address, _heads) = upstream("SSH-2.0-OpenSSH\r\n").await;
+        let response = Proxy::new("/", address)
+            .call(request(Method::Get, "/", &[]))
+            .await
+            .unwrap();
+        assert_eq!(StatusCode::BAD_GATEWAY, response.status);
+
+        for body in [
+            "HTTP/1.1 200 OK\r\nContent-Length: 18446744073709551615\r\n\r\n",
+            "HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n3\r\nabc\r\n\
+             ffffffffffffffff\r\n",
+            "HTTP/1.1 200 OK\r\nConnection: close\r\n\r\nabcde",
+        ] {
+            let (address, _heads) = upstream(body).await;
+            let response = Proxy::new("/", address)
+                .with_max_response_size(4)
+                .call(request(Method::Get, "/", &[]))
+                .await
+                .unwrap();
+            assert_eq!(StatusCode::BAD_GATEWAY, response.status);
+        }
+    }
+}
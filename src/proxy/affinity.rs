@@ -0,0 +1,153 @@
+use super::fnv1a;
+use super::upstream::Upstream;
+use crate::request::Request;
+use crate::response::Response;
+use std::sync::Arc;
+
+/// Keeps a client on the same upstream across requests, for backends holding session data in
+/// memory, see [`super::Proxy::with_affinity`].
+///
+/// The pinned upstream is only preferred; while health checks or its circuit breaker keep it
+/// out of rotation, requests go to the next upstream in turn.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Affinity {
+    /// Pins a client through a cookie of this name naming the upstream by a hash of its
+    /// address, set on the first response and whenever the client moves to another upstream.
+    Cookie(String),
+    /// Pins a client by its IP address through rendezvous hashing, so taking an upstream out of
+    /// rotation only moves the clients pinned to it.
+    ClientAddress,
+}
+
+impl Affinity {
+    /// Moves the upstream a request is pinned to to the front of the candidates.
+    ///
+    /// # Arguments
+    ///
+    /// * `request`: The incoming request.
+    /// * `candidates`: The upstreams in rotation, in the order the balancer prefers them.
+    pub(super) fn prefer(&self, request: &Request, candidates: &mut [&Arc<Upstream>]) {
+        match self {
+            Affinity::Cookie(name) => {
+                let Some(id) = request.cookie(name) else {
+                    return;
+                };
+                if let Some(index) = candidates.iter().position(|upstream| upstream.id() == id) {
+                    candidates[..=index].rotate_right(1);
+                }
+            }
+            Affinity::ClientAddress => {
+                let Some(peer) = request.peer else {
+                    return;
+                };
+                let ip = peer.ip().to_string();
+                candidates.sort_by_key(|upstream| {
+                    std::cmp::Reverse(fnv1a(format!("{}/{}", ip, upstream.id()).as_bytes()))
+                });
+            }
+        }
+    }
+
+    /// Pins the client to the upstream that answered, unless it is already pinned there.
+    ///
+    /// # Arguments
+    ///
+    /// * `request`: The request that was forwarded.
+    /// * `upstream`: The upstream that answered it.
+    /// * `response`: Its response.
+    ///
+    /// # Returns
+    ///
+    /// The response, with a cookie for [`Affinity::Cookie`] when the pin changes.
+    pub(super) fn pin(
+        &self,
+        request: &Request,
+        upstream: &Upstream,
+        response: Response,
+    ) -> Response {
+        match self {
+            Affinity::Cookie(name)
+                if !response.status.is_server_error()
+                    && request.cookie(name) != Some(upstream.id()) =>
+            {
+                response.with_header(
+                    "Set-Cookie",
+                    format!("{}={}; Path=/; HttpOnly; SameSite=Lax", name, upstream.id()),
+                )
+            }
+            _ => response,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn upstreams() -> Vec<Arc<Upstream>> {
+        ["a:1", "b:1", "c:1"]
+            .map(|address| Arc::new(Upstream::new(address.to_string())))
+            .to_vec()
+    }
+
+    /// It prefers the upstream named by the cookie and sets the cookie when the pin changes
+    #[test]
+    fn cookie() {
+        let upstreams = upstreams();
+        let affinity = Affinity::Cookie("backend".to_string());
+        let request = Request {
+            headers: [("Cookie", format!("backend={}", upstreams[2].id()))]
+                .into_iter()
+                .collect(),
+            ..Request::default()
+        };
+        let mut candidates: Vec<&Arc<Upstream>> = upstreams.iter().collect();
+        affinity.prefer(&request, &mut candidates);
+        let order: Vec<&str> = candidates
+            .iter()
+            .map(|upstream| upstream.address())
+            .collect();
+        assert_eq!(vec!["c:1", "a:1", "b:1"], order);
+        let ok = || Response::new(crate::status::StatusCode::OK, "");
+        assert_eq!(
+            None,
+            affinity
+                .pin(&request, &upstreams[2], ok())
+                .header("Set-Cookie")
+        );
+        assert_eq!(
+            Some(
+                format!(
+                    "backend={}; Path=/; HttpOnly; SameSite=Lax",
+                    upstreams[0].id()
+                )
+                .as_str()
+            ),
+            affinity
+                .pin(&request, &upstreams[0], ok())
+                .header("Set-Cookie")
+        );
+    }
+
+    /// It keeps a client address on the same upstream while others leave rotation
+    #[test]
+    fn client_address() {
+        let upstreams = upstreams();
+        let request = Request {
+            peer: Some("192.0.2.7:50000".parse().unwrap()),
+            ..Request::default()
+        };
+        let mut candidates: Vec<&Arc<Upstream>> = upstreams.iter().collect();
+        Affinity::ClientAddress.prefer(&request, &mut candidates);
+        let pinned = candidates[0].address().to_string();
+        let mut others: Vec<&Arc<Upstream>> = upstreams
+            .iter()
+            .filter(|upstream| {
+                upstream.address() == pinned || upstream.address() == candidates[2].address()
+            })
+            .collect();
+        others.reverse();
+        Affinity::ClientAddress.prefer(&request, &mut others);
+        assert_eq!(pinned, others[0].address());
+    }
+}
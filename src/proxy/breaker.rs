@@ -0,0 +1,224 @@
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Settings for failing fast on upstreams that keep erroring, see
+/// [`super::Proxy::with_circuit_breaker`].
+///
+/// An upstream's circuit opens once at least [`CircuitBreaker::minimum_requests`] requests
+/// within one [`CircuitBreaker::window`] failed at [`CircuitBreaker::error_rate`] or more. An
+/// open circuit takes the upstream out of rotation for [`CircuitBreaker::cooldown`], after
+/// which a single trial request half-opens it: success closes the circuit, failure opens it
+/// again.
+#[derive(Clone, Debug, PartialEq)]
+pub struct CircuitBreaker {
+    /// The share of failed requests, between 0 and 1, that opens the circuit.
+    pub error_rate: f64,
+    /// The requests within a window needed before the error rate is judged.
+    pub minimum_requests: u32,
+    /// How long requests are counted before the counts start over.
+    pub window: Duration,
+    /// How long an open circuit refuses requests before a trial request.
+    pub cooldown: Duration,
+}
+
+impl Default for CircuitBreaker {
+    /// Opens at a 50 % error rate over at least 20 requests in 10 seconds, for 30 seconds.
+    fn default() -> CircuitBreaker {
+        CircuitBreaker {
+            error_rate: 0.5,
+            minimum_requests: 20,
+            window: Duration::from_secs(10),
+            cooldown: Duration::from_secs(30),
+        }
+    }
+}
+
+/// The state of an upstream's circuit.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum CircuitState {
+    /// Requests pass.
+    #[default]
+    Closed,
+    /// Requests are refused until the cooldown ends.
+    Open,
+    /// A trial request is deciding whether the circuit closes again.
+    HalfOpen,
+}
+
+/// The circuit of one upstream.
+#[derive(Debug)]
+pub(super) struct Breaker {
+    state: Mutex<BreakerState>,
+}
+
+#[derive(Debug)]
+struct BreakerState {
+    circuit: CircuitState,
+    window_start: Instant,
+    requests: u32,
+    failures: u32,
+    /// When the circuit last opened, or when the trial request started while half-open.
+    since: Instant,
+}
+
+impl Breaker {
+    pub(super) fn new() -> Breaker {
+        let now = Instant::now();
+        Breaker {
+            state: Mutex::new(BreakerState {
+                circuit: CircuitState::Closed,
+                window_start: now,
+                requests: 0,
+                failures: 0,
+                since: now,
+            }),
+        }
+    }
+
+    pub(super) fn state(&self) -> CircuitState {
+        self.state
+            .lock()
+            .map_or(CircuitState::Closed, |state| state.circuit)
+    }
+
+    /// Asks to send a request through the circuit.
+    ///
+    /// # Arguments
+    ///
+    /// * `settings`: The breaker settings, or `None` when circuits never open.
+    /// * `now`: The current time.
+    ///
+    /// # Returns
+    ///
+    /// `true` when the request may be sent. An open circuit past its cooldown turns half-open
+    /// and admits this request as its trial.
+    pub(super) fn try_acquire(&self, settings: Option<&CircuitBreaker>, now: Instant) -> bool {
+        let (Some(settings), Ok(mut state)) = (settings, self.state.lock()) else {
+            return true;
+        };
+        match state.circuit {
+            CircuitState::Closed => true,
+            // A trial that never reported back must not keep the circuit half-open forever
+            CircuitState::Open | CircuitState::HalfOpen
+                if now.duration_since(state.since) >= settings.cooldown =>
+            {
+                state.circuit = CircuitState::HalfOpen;
+                state.since = now;
+                true
+            }
+            CircuitState::Open | CircuitState::HalfOpen => false,
+        }
+    }
+
+    /// Records the outcome of a request admitted by [`Breaker::try_acquire`].
+    ///
+    /// # Arguments
+    ///
+    /// * `settings`: The breaker settings, or `None` when circuits never open.
+    /// * `success`: Whether the upstream answered without a server error.
+    /// * `now`: The current time.
+    pub(super) fn record(&self, settings: Option<&CircuitBreaker>, success: bool, now: Instant) {
+        let (Some(settings), Ok(mut state)) = (settings, self.state.lock()) else {
+            return;
+        };
+        match state.circuit {
+            CircuitState::HalfOpen if success => {
+                state.circuit = CircuitState::Closed;
+                state.window_start = now;
+                state.requests = 0;
+                state.failures = 0;
+            }
+            CircuitState::HalfOpen => {
+                state.circuit = CircuitState::Open;
+                state.since = now;
+            }
+            CircuitState::Closed => {
+                if now.duration_since(state.window_start) >= settings.window {
+                    state.window_start = now;
+                    state.requests = 0;
+                    state.failures = 0;
+                }
+                state.requests += 1;
+                if !success {
+                    state.failures += 1;
+                }
+                let rate = f64::from(state.failures) / f64::from(state.requests);
+                if state.requests >= settings.minimum_requests && rate >= settings.error_rate {
+                    state.circuit = CircuitState::Open;
+                    state.since = now;
+                }
+            }
+            // A request sent before the circuit opened
+            CircuitState::Open => {}
+        }
+    }
+
+    /// The time left until an open circuit admits a trial request.
+    ///
+    /// # Arguments
+    ///
+    /// * `settings`: The breaker settings, or `None` when circuits never open.
+    /// * `now`: The current time.
+    ///
+    /// # Returns
+    ///
+    /// The remaining cooldown, or `None` unless the circuit is open.
+    pub(super) fn retry_after(
+        &self,
+        settings: Option<&CircuitBreaker>,
+        now: Instant,
+    ) -> Option<Duration> {
+        let settings = settings?;
+        let state = self.state.lock().ok()?;
+        if state.circuit != CircuitState::Open {
+            return None;
+        }
+        Some(
+            settings
+                .cooldown
+                .saturating_sub(now.duration_since(state.since)),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// It opens at the error rate, half-opens for one trial after the cooldown, and closes again
+    #[test]
+    fn transitions() {
+        let settings = CircuitBreaker {
+            error_rate: 0.5,
+            minimum_requests: 4,
+            ..CircuitBreaker::default()
+        };
+        let breaker = Breaker::new();
+        let start = Instant::now();
+        for success in [true, false, true] {
+            assert!(breaker.try_acquire(Some(&settings), start));
+            breaker.record(Some(&settings), success, start);
+        }
+        assert_eq!(CircuitState::Closed, breaker.state());
+        breaker.record(Some(&settings), false, start);
+        assert_eq!(CircuitState::Open, breaker.state());
+        assert!(!breaker.try_acquire(Some(&settings), start));
+        assert_eq!(
+            Some(settings.cooldown),
+            breaker.retry_after(Some(&settings), start)
+        );
+
+        let later = start + settings.cooldown;
+        assert!(breaker.try_acquire(Some(&settings), later));
+        assert_eq!(CircuitState::HalfOpen, breaker.state());
+        assert!(!breaker.try_acquire(Some(&settings), later));
+        breaker.record(Some(&settings), false, later);
+        assert_eq!(CircuitState::Open, breaker.state());
+
+        let latest = later + settings.cooldown;
+        assert!(breaker.try_acquire(Some(&settings), latest));
+        breaker.record(Some(&settings), true, latest);
+        assert_eq!(CircuitState::Closed, breaker.state());
+        assert!(breaker.try_acquire(None, latest));
+    }
+}
@@ -0,0 +1,460 @@
+use super::fnv1a;
+use crate::cache::{directives, storable};
+use crate::header::HeaderMap;
+use crate::request::Request;
+use crate::response::Response;
+use crate::status::StatusCode;
+use std::collections::{BTreeSet, HashMap};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::{fs, io, sync};
+
+/// The name of the index file inside the cache directory.
+const INDEX: &str = "index";
+
+/// An on-disk cache of upstream responses for a [`super::Proxy`], which lets the server act as
+/// a small caching CDN node.
+///
+/// Bodies are stored content-addressed as `<hash>.body` files, so identical bodies behind
+/// different URLs are stored once. An `index` file maps each `METHOD target` to the stored
+/// status, headers, body, and the request header values named in `Vary`; it is rewritten
+/// atomically after every change and loaded again by [`DiskCache::open`]. Only one variant per
+/// target is kept. Freshness follows the upstream `Cache-Control` and `Expires` headers like
+/// [`crate::cache::ResponseCache`], and `stale-while-revalidate` lets a stale response be
+/// served while a single background request refreshes it.
+#[derive(Clone, Debug)]
+pub struct DiskCache {
+    directory: PathBuf,
+    index: Arc<sync::Mutex<HashMap<String, Entry>>>,
+    revalidating: Arc<Mutex<BTreeSet<String>>>,
+}
+
+/// A stored response as recorded in the index.
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct Entry {
+    status: StatusCode,
+    headers: HeaderMap,
+    /// The hash naming the body file.
+    body: String,
+    /// The request header values the response was chosen by.
+    vary: Vec<(String, String)>,
+    stored: u64,
+    expires: u64,
+    stale_until: u64,
+}
+
+/// The result of looking a request up in a [`DiskCache`].
+#[derive(Debug)]
+pub enum Lookup {
+    /// A response within its freshness lifetime.
+    Fresh(Response),
+    /// An expired response that may be served while it is revalidated.
+    Stale(Response),
+    /// Nothing usable is stored.
+    Miss,
+}
+
+impl DiskCache {
+    /// Opens a cache directory, creating it when missing and loading its index.
+    ///
+    /// # Arguments
+    ///
+    /// * `directory`: The directory holding the index and body files.
+    ///
+    /// # Returns
+    ///
+    /// The cache with the entries recorded in the index.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`io::ErrorKind::InvalidData`] for a corrupt index and captures IO errors from
+    /// creating the directory and reading the index.
+    pub fn open(directory: impl Into<PathBuf>) -> io::Result<DiskCache> {
+        let directory = directory.into();
+        std::fs::create_dir_all(&directory)?;
+        let index = match std::fs::read_to_string(directory.join(INDEX)) {
+            Ok(index) => parse_index(&index).ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("corrupt proxy cache index: {}", directory.display()),
+                )
+            })?,
+            Err(error) if error.kind() == io::ErrorKind::NotFound => HashMap::new(),
+            Err(error) => return Err(error),
+        };
+        Ok(DiskCache {
+            directory,
+            index: Arc::new(sync::Mutex::new(index)),
+            revalidating: Arc::new(Mutex::new(BTreeSet::new())),
+        })
+    }
+
+    /// The number of stored responses.
+    pub async fn len(&self) -> usize {
+        self.index.lock().await.len()
+    }
+
+    /// Checks whether no response is stored.
+    pub async fn is_empty(&self) -> bool {
+        self.index.lock().await.is_empty()
+    }
+
+    /// Finds the stored response for a request.
+    ///
+    /// # Arguments
+    ///
+    /// * `request`: A request that may be answered from a shared cache.
+    ///
+    /// # Returns
+    ///
+    /// The stored response with an `Age` header and whether it is still fresh. Entries past
+    /// their `stale-while-revalidate` window are removed.
+    ///
+    /// # Errors
+    ///
+    /// Captures IO errors from reading the body file or updating the index.
+    pub async fn lookup(&self, request: &Request) -> io::Result<Lookup> {
+        let mut index = self.index.lock().await;
+        let key = key(request);
+        let Some(entry) = index.get(&key).cloned() else {
+            return Ok(Lookup::Miss);
+        };
+        if entry
+            .vary
+            .iter()
+            .any(|(name, value)| request_value(request, name) != *value)
+        {
+            return Ok(Lookup::Miss);
+        }
+        let now = unix_now();
+        if now >= entry.stale_until {
+            index.remove(&key);
+            self.save(&index).await?;
+            self.remove_unreferenced(&index, &entry.body).await?;
+            return Ok(Lookup::Miss);
+        }
+        let body = match fs::read(self.body_path(&entry.body)).await {
+            Ok(body) => body,
+            Err(error) if error.kind() == io::ErrorKind::NotFound => {
+                // Someone cleaned the directory behind our back
+                index.remove(&key);
+                self.save(&index).await?;
+                return Ok(Lookup::Miss);
+            }
+            Err(error) => return Err(error),
+        };
+        let mut response = Response::new(entry.status, body);
+        response.headers = entry.headers;
+        response
+            .headers
+            .insert("Age", now.saturating_sub(entry.stored).to_string());
+        Ok(if now < entry.expires {
+            Lookup::Fresh(response)
+        } else {
+            Lookup::Stale(response)
+        })
+    }
+
+    /// Stores an upstream response when its headers allow a shared cache to do so.
+    ///
+    /// # Arguments
+    ///
+    /// * `request`: The request the response answers.
+    /// * `response`: The upstream response.
+    ///
+    /// # Errors
+    ///
+    /// Captures IO errors from writing the body file and the index.
+    pub async fn store(&self, request: &Request, response: &Response) -> io::Result<()> {
+        let Some((lifetime, names)) = storable(response) else {
+            return Ok(());
+        };
+        let body = content_hash(&response.body);
+        let path = self.body_path(&body);
+        if fs::metadata(&path).await.is_err() {
+            let partial = self.directory.join(format!("{}.partial", body));
+            fs::write(&partial, &response.body).await?;
+            fs::rename(&partial, &path).await?;
+        }
+        let stored = unix_now();
+        let expires = stored + lifetime.as_secs();
+        let mut headers = response.headers.clone();
+        headers.remove("Vary");
+        if !names.is_empty() {
+            headers.append("Vary", names.join(", "));
+        }
+        let entry = Entry {
+            status: response.status,
+            headers,
+            body,
+            vary: names
+                .iter()
+                .map(|name| (name.clone(), request_value(request, name)))
+                .collect(),
+            stored,
+            expires,
+            stale_until: expires + stale_while_revalidate(response).as_secs(),
+        };
+        let mut index = self.index.lock().await;
+        let replaced = index.insert(key(request), entry);
+        self.save(&index).await?;
+        if let Some(replaced) = replaced {
+            self.remove_unreferenced(&index, &replaced.body).await?;
+        }
+        Ok(())
+    }
+
+    /// Claims the background revalidation of a stale entry.
+    ///
+    /// # Arguments
+    ///
+    /// * `request`: The request whose stored response is stale.
+    ///
+    /// # Returns
+    ///
+    /// `true` unless another request is already revalidating the entry.
+    pub fn begin_revalidation(&self, request: &Request) -> bool {
+        self.revalidating
+            .lock()
+            .is_ok_and(|mut revalidating| revalidating.insert(key(request)))
+    }
+
+    /// Releases a claim taken by [`DiskCache::begin_revalidation`].
+    ///
+    /// # Arguments
+    ///
+    /// * `request`: The request whose entry was revalidated.
+    pub fn end_revalidation(&self, request: &Request) {
+        if let Ok(mut revalidating) = self.revalidating.lock() {
+            revalidating.remove(&key(request));
+        }
+    }
+
+    /// Rewrites the index next to the old one and renames it into place, so a crash never
+    /// leaves a truncated index behind.
+    async fn save(&self, index: &HashMap<String, Entry>) -> io::Result<()> {
+        let mut contents = String::new();
+        for (key, entry) in index {
+            contents.push_str(&format!(
+                "key={}\nstatus={}\nbody={}\nstored={}\nexpires={}\nstale={}\n",
+                key,
+                entry.status.as_u16(),
+                entry.body,
+                entry.stored,
+                entry.expires,
+                entry.stale_until
+            ));
+            for (name, value) in &entry.vary {
+                contents.push_str(&format!("vary={}: {}\n", name, value));
+            }
+            for (name, value) in &entry.headers {
+                contents.push_str(&format!("header={}: {}\n", name, value));
+            }
+            contents.push('\n');
+        }
+        let partial = self.directory.join(format!("{}.partial", INDEX));
+        fs::write(&partial, contents).await?;
+        fs::rename(&partial, self.directory.join(INDEX)).await
+    }
+
+    /// Removes a body file once no entry refers to it anymore.
+    async fn remove_unreferenced(
+        &self,
+        index: &HashMap<String, Entry>,
+        body: &str,
+    ) -> io::Result<()> {
+        if index.values().any(|entry| entry.body == body) {
+            return Ok(());
+        }
+        match fs::remove_file(self.body_path(body)).await {
+            Err(error) if error.kind() != io::ErrorKind::NotFound => Err(error),
+            _ => Ok(()),
+        }
+    }
+
+    fn body_path(&self, body: &str) -> PathBuf {
+        self.directory.join(format!("{}.body", body))
+    }
+}
+
+/// Parses the index written by [`DiskCache::save`].
+///
+/// # Returns
+///
+/// The entries by key, or `None` when a line or entry is malformed.
+fn parse_index(index: &str) -> Option<HashMap<String, Entry>> {
+    let mut entries = HashMap::new();
+    for block in index.split("\n\n").filter(|block| !block.trim().is_empty()) {
+        let mut key = None;
+        let mut entry = Entry {
+            status: StatusCode::OK,
+            headers: HeaderMap::new(),
+            body: String::new(),
+            vary: Vec::new(),
+            stored: 0,
+            expires: 0,
+            stale_until: 0,
+        };
+        for line in block.lines() {
+            let (field, value) = line.split_once('=')?;
+            match field {
+                "key" => key = Some(value.to_string()),
+                "status" => entry.status = StatusCode::from_u16(value.parse().ok()?)?,
+                "body" if is_hash(value) => entry.body = value.to_string(),
+                "stored" => entry.stored = value.parse().ok()?,
+                "expires" => entry.expires = value.parse().ok()?,
+                "stale" => entry.stale_until = value.parse().ok()?,
+                "vary" => {
+                    let (name, value) = value.split_once(": ")?;
+                    entry.vary.push((name.to_string(), value.to_string()));
+                }
+                "header" => {
+                    let (name, value) = value.split_once(": ")?;
+                    entry.headers.append(name, value);
+                }
+                _ => return None,
+            }
+        }
+        if entry.body.is_empty() {
+            return None;
+        }
+        entries.insert(key?, entry);
+    }
+    Some(entries)
+}
+
+fn key(request: &Request) -> String {
+    format!("{} {}", request.method, request.target)
+}
+
+fn request_value(request: &Request, name: &str) -> String {
+    request.headers.get_all(name).collect::<Vec<_>>().join(", ")
+}
+
+/// The `stale-while-revalidate` window of a response, zero when absent.
+fn stale_while_revalidate(response: &Response) -> Duration {
+    directives(&response.headers)
+        .find(|(name, _)| name == "stale-while-revalidate")
+        .and_then(|(_, value)| value?.parse().ok())
+        .map_or(Duration::ZERO, Duration::from_secs)
+}
+
+/// Names a body file by the hash and the length of the body. The hash only deduplicates
+/// bodies the cache wrote itself, so it does not need to resist deliberate collisions.
+fn content_hash(body: &[u8]) -> String {
+    format!("{:016x}{:08x}", fnv1a(body), body.len() as u32)
+}
+
+/// Checks whether `body` has the shape of a name generated by [`content_hash`].
+fn is_hash(body: &str) -> bool {
+    body.len() == 24 && body.bytes().all(|byte| byte.is_ascii_hexdigit())
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_secs())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::tests::upstream;
+    use super::super::Proxy;
+    use super::*;
+    use crate::method::Method;
+    use crate::router::Handler;
+
+    fn request(target: &str) -> Request {
+        Request {
+            method: Method::Get,
+            target: target.parse().unwrap(),
+            ..Request::default()
+        }
+    }
+
+    /// It answers fresh requests from disk and keeps entries across reopening the directory
+    #[tokio::test]
+    async fn fresh() {
+        let directory = tempfile::tempdir().unwrap();
+        let (address, mut heads) =
+            upstream("HTTP/1.1 200 OK\r\nCache-Control: max-age=60\r\nContent-Length: 2\r\n\r\nhi")
+                .await;
+        let cache = DiskCache::open(directory.path()).unwrap();
+        let proxy = Proxy::new("/", address.clone()).with_cache(cache);
+        assert_eq!(
+            &b"hi"[..],
+            &proxy.call(request("/a")).await.unwrap().body[..]
+        );
+        assert_eq!(
+            &b"hi"[..],
+            &proxy.call(request("/b")).await.unwrap().body[..]
+        );
+        heads.recv().await.unwrap();
+        heads.recv().await.unwrap();
+
+        let reopened = DiskCache::open(directory.path()).unwrap();
+        assert_eq!(2, reopened.len().await);
+        let bodies = std::fs::read_dir(directory.path())
+            .unwrap()
+            .filter(|entry| entry.as_ref().unwrap().path().extension() == Some("body".as_ref()))
+            .count();
+        assert_eq!(1, bodies);
+        let proxy = Proxy::new("/", address).with_cache(reopened);
+        let response = proxy.call(request("/a")).await.unwrap();
+        assert_eq!(Some("max-age=60"), response.header("Cache-Control"));
+        assert!(response.header("Age").is_some());
+        assert!(heads.try_recv().is_err());
+    }
+
+    /// It serves stale responses within `stale-while-revalidate` while refreshing them once
+    #[tokio::test]
+    async fn stale_while_revalidate() {
+        let directory = tempfile::tempdir().unwrap();
+        let (address, mut heads) = upstream(
+            "HTTP/1.1 200 OK\r\nCache-Control: max-age=0, s-maxage=60, stale-while-revalidate=60\r\n\
+             Content-Length: 2\r\n\r\nhi",
+        )
+        .await;
+        let cache = DiskCache::open(directory.path()).unwrap();
+        let proxy = Proxy::new("/", address).with_cache(cache.clone());
+        proxy.call(request("/a")).await.unwrap();
+        heads.recv().await.unwrap();
+        {
+            // Age the entry past its freshness lifetime
+            let mut index = cache.index.lock().await;
+            let entry = index.get_mut("GET /a").unwrap();
+            entry.expires -= 100;
+            entry.stored -= 100;
+        }
+        let response = proxy.call(request("/a")).await.unwrap();
+        let age: u64 = response.header("Age").unwrap().parse().unwrap();
+        assert!(age >= 100);
+        heads.recv().await.unwrap();
+        for _ in 0..100 {
+            if !cache.revalidating.lock().unwrap().contains("GET /a") {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert!(matches!(
+            cache.lookup(&request("/a")).await.unwrap(),
+            Lookup::Fresh(_)
+        ));
+        assert!(heads.try_recv().is_err());
+    }
+
+    /// It does not store responses that forbid shared caching
+    #[tokio::test]
+    async fn private() {
+        let directory = tempfile::tempdir().unwrap();
+        let (address, _heads) = upstream(
+            "HTTP/1.1 200 OK\r\nCache-Control: private, max-age=60\r\nContent-Length: 0\r\n\r\n",
+        )
+        .await;
+        let cache = DiskCache::open(directory.path()).unwrap();
+        let proxy = Proxy::new("/", address).with_cache(cache.clone());
+        proxy.call(request("/a")).await.unwrap();
+        assert!(cache.is_empty().await);
+    }
+}
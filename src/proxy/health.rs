@@ -0,0 +1,139 @@
+use super::upstream::Upstream;
+use super::{read_response, Proxy, MAX_RESPONSE_SIZE};
+use std::time::Duration;
+use tokio::io::{self, AsyncWriteExt};
+use tokio::{net, task, time};
+
+/// Settings for probing the upstreams of a [`Proxy`] in the background.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct HealthCheck {
+    /// The path requested with `GET` on every upstream, e.g. `/healthz`.
+    pub path: String,
+    /// The time between two rounds of checks.
+    pub interval: Duration,
+    /// How long a check may take before it counts as failed.
+    pub timeout: Duration,
+    /// The consecutive passes returning an upstream to rotation.
+    pub healthy_threshold: u32,
+    /// The consecutive failures taking an upstream out of rotation.
+    pub unhealthy_threshold: u32,
+}
+
+impl Default for HealthCheck {
+    /// Checks `/` every 10 seconds with a 2 second timeout, removing an upstream after 3 failures
+    /// and returning it after 2 passes.
+    fn default() -> HealthCheck {
+        HealthCheck {
+            path: "/".to_string(),
+            interval: Duration::from_secs(10),
+            timeout: Duration::from_secs(2),
+            healthy_threshold: 2,
+            unhealthy_threshold: 3,
+        }
+    }
+}
+
+impl Proxy {
+    /// Checks every upstream once, including those of the canary, the split, and the mirror, and
+    /// updates whether it is in rotation.
+    ///
+    /// A check passes when the upstream answers `GET` at [`HealthCheck::path`] with a success
+    /// or redirection status in time. Nothing happens without a [`Proxy::with_health_check`].
+    pub async fn check_health(&self) {
+        let Some(check) = &self.health_check else {
+            return;
+        };
+        for upstream in &self.balancer.upstreams {
+            let passed = matches!(
+                time::timeout(check.timeout, probe(upstream, &check.path)).await,
+                Ok(Ok(true))
+            );
+            upstream.record_check(passed, check.healthy_threshold, check.unhealthy_threshold);
+        }
+        if let Some((_, canary)) = &self.canary {
+            Box::pin(canary.check_health()).await;
+        }
+        if let Some((_, alternate)) = &self.split {
+            Box::pin(alternate.check_health()).await;
+        }
+        if let Some(mirror) = &self.mirror {
+            Box::pin(mirror.check_health()).await;
+        }
+    }
+
+    /// Starts checking the upstreams every [`HealthCheck::interval`] on a background task.
+    ///
+    /// # Returns
+    ///
+    /// The task, or `None` without a [`Proxy::with_health_check`].
+    pub fn spawn_health_checks(&self) -> Option<task::JoinHandle<()>> {
+        let interval = self.health_check.as_ref()?.interval;
+        let proxy = self.clone();
+        Some(tokio::spawn(async move {
+            let mut interval = time::interval(interval);
+            loop {
+                interval.tick().await;
+                proxy.check_health().await;
+            }
+        }))
+    }
+}
+
+/// Requests the health check path over a fresh connection, so a pooled connection that just
+/// broke does not decide the result.
+///
+/// # Returns
+///
+/// Whether the status was a success or redirection.
+///
+/// # Errors
+///
+/// Captures IO errors and malformed responses from the exchange.
+async fn probe(upstream: &Upstream, path: &str) -> io::Result<bool> {
+    let mut stream = io::BufReader::new(net::TcpStream::connect(upstream.address()).await?);
+    let head = format!(
+        "GET {} HTTP/1.1\r\nHost: {}\r\nConnection: close\r\n\r\n",
+        path,
+        upstream.address()
+    );
+    stream.get_mut().write_all(head.as_bytes()).await?;
+    let (response, _) = read_response(&mut stream, false, MAX_RESPONSE_SIZE).await?;
+    Ok(response.status.is_success() || response.status.is_redirection())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::tests::upstream;
+    use super::*;
+    use crate::request::Request;
+    use crate::router::Handler;
+    use crate::status::StatusCode;
+
+    /// It takes failing upstreams out of rotation and answers with 503 when none is left
+    #[tokio::test]
+    async fn check_health() {
+        let (up, _up_heads) = upstream("HTTP/1.1 204 No Content\r\n\r\n").await;
+        let (down, _down_heads) = upstream("HTTP/1.1 500 Oops\r\nContent-Length: 0\r\n\r\n").await;
+        let check = HealthCheck {
+            path: "/healthz".to_string(),
+            healthy_threshold: 1,
+            unhealthy_threshold: 1,
+            ..HealthCheck::default()
+        };
+        let proxy = Proxy::new("/", up)
+            .with_upstream(down.clone())
+            .with_health_check(check.clone());
+        proxy.check_health().await;
+        let healthy: Vec<bool> = proxy
+            .upstream_stats()
+            .iter()
+            .map(|stats| stats.healthy)
+            .collect();
+        assert_eq!(vec![true, false], healthy);
+
+        let proxy = Proxy::new("/", down).with_health_check(check);
+        proxy.check_health().await;
+        let response = proxy.call(Request::default()).await.unwrap();
+        assert_eq!(StatusCode::SERVICE_UNAVAILABLE, response.status);
+    }
+}
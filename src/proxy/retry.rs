@@ -0,0 +1,115 @@
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// How long retries and requests are counted against each other by a retry budget.
+const BUDGET_WINDOW: Duration = Duration::from_secs(10);
+
+/// Settings for retrying failed proxied requests on another upstream, see
+/// [`super::Proxy::with_retry`].
+///
+/// Only idempotent requests are retried, after a connection error, a timeout, or a server
+/// error status from the upstream. Every retry goes to an upstream not tried for the request
+/// yet, and is only sent while retries stay within the budget: at most
+/// [`RetryPolicy::budget_ratio`] of the requests of the last 10 seconds, but always
+/// [`RetryPolicy::min_retries`], so a failing backend does not get multiplied load.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RetryPolicy {
+    /// The retries per request after the first try.
+    pub max_retries: u32,
+    /// How long each try may take, the proxy timeout when absent.
+    pub per_try_timeout: Option<Duration>,
+    /// The share of requests that may be retries.
+    pub budget_ratio: f64,
+    /// The retries allowed per window regardless of the ratio.
+    pub min_retries: u32,
+}
+
+impl Default for RetryPolicy {
+    /// Retries twice, within a budget of 20 % of the requests or at least 10 retries.
+    fn default() -> RetryPolicy {
+        RetryPolicy {
+            max_retries: 2,
+            per_try_timeout: None,
+            budget_ratio: 0.2,
+            min_retries: 10,
+        }
+    }
+}
+
+/// Counts requests and retries within the current window.
+#[derive(Debug)]
+pub(super) struct RetryBudget {
+    state: Mutex<(Instant, u32, u32)>,
+}
+
+impl RetryBudget {
+    pub(super) fn new() -> RetryBudget {
+        RetryBudget {
+            state: Mutex::new((Instant::now(), 0, 0)),
+        }
+    }
+
+    /// Counts a request that may later be retried.
+    pub(super) fn deposit(&self, now: Instant) {
+        if let Ok(mut state) = self.state.lock() {
+            renew(&mut state, now);
+            state.1 += 1;
+        }
+    }
+
+    /// Claims a retry.
+    ///
+    /// # Arguments
+    ///
+    /// * `policy`: The retry settings holding the budget.
+    /// * `now`: The current time.
+    ///
+    /// # Returns
+    ///
+    /// `true` when the retry fits into the budget and was counted.
+    pub(super) fn withdraw(&self, policy: &RetryPolicy, now: Instant) -> bool {
+        let Ok(mut state) = self.state.lock() else {
+            return false;
+        };
+        renew(&mut state, now);
+        let (_, requests, retries) = *state;
+        let allowed = (f64::from(requests) * policy.budget_ratio) as u32;
+        if retries >= allowed.max(policy.min_retries) {
+            return false;
+        }
+        state.2 += 1;
+        true
+    }
+}
+
+/// Starts a new window once the current one has passed.
+fn renew(state: &mut (Instant, u32, u32), now: Instant) {
+    if now.duration_since(state.0) >= BUDGET_WINDOW {
+        *state = (now, 0, 0);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// It allows the minimum retries or the ratio of requests per window, whichever is larger
+    #[test]
+    fn budget() {
+        let policy = RetryPolicy {
+            budget_ratio: 0.5,
+            min_retries: 1,
+            ..RetryPolicy::default()
+        };
+        let budget = RetryBudget::new();
+        let now = Instant::now();
+        assert!(budget.withdraw(&policy, now));
+        assert!(!budget.withdraw(&policy, now));
+        for _ in 0..4 {
+            budget.deposit(now);
+        }
+        assert!(budget.withdraw(&policy, now));
+        assert!(!budget.withdraw(&policy, now));
+        assert!(budget.withdraw(&policy, now + BUDGET_WINDOW));
+    }
+}
@@ -0,0 +1,379 @@
+use super::breaker::{Breaker, CircuitState};
+use super::{fnv1a, read_response, request_head};
+use crate::method::Method;
+use crate::request::Request;
+use crate::response::Response;
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::io::{self, AsyncWriteExt};
+use tokio::net;
+
+/// The number of idle connections kept open per upstream for later requests.
+const MAX_IDLE: usize = 8;
+
+/// How a [`super::Proxy`] picks the upstream for the next request.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Strategy {
+    /// Takes the upstreams in turn, each as often as its weight, spread evenly.
+    #[default]
+    RoundRobin,
+    /// Takes the upstream with the fewest requests in flight for its weight, in turn among
+    /// equals.
+    LeastConnections,
+}
+
+/// Counters describing the traffic sent to one upstream.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct UpstreamStats {
+    /// The upstream address.
+    pub address: String,
+    /// Requests sent, including failed ones.
+    pub requests: u64,
+    /// Requests that failed to produce a response.
+    pub failures: u64,
+    /// Requests currently in flight.
+    pub active: usize,
+    /// Open connections waiting for the next request.
+    pub idle: usize,
+    /// Whether health checks keep the upstream in rotation.
+    pub healthy: bool,
+    /// The state of the upstream's circuit breaker.
+    pub circuit: CircuitState,
+    /// The share of traffic the upstream receives relative to the others.
+    pub weight: u32,
+}
+
+/// One upstream server with its idle connections and counters.
+#[derive(Debug)]
+pub(super) struct Upstream {
+    address: String,
+    id: String,
+    idle: Mutex<Vec<io::BufReader<net::TcpStream>>>,
+    active: AtomicUsize,
+    requests: AtomicU64,
+    failures: AtomicU64,
+    healthy: AtomicBool,
+    /// Consecutive health check results agreeing with each other, positive for passes.
+    streak: AtomicU32,
+    streak_passing: AtomicBool,
+    weight: AtomicU32,
+    pub(super) breaker: Breaker,
+}
+
+/// Decrements the in-flight count of an upstream when a request ends, however it ends.
+struct Active<'a>(&'a AtomicUsize);
+
+impl Drop for Active<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+impl Upstream {
+    pub(super) fn new(address: String) -> Upstream {
+        Upstream {
+            id: format!("{:016x}", fnv1a(address.as_bytes())),
+            address,
+            idle: Mutex::new(Vec::new()),
+            active: AtomicUsize::new(0),
+            requests: AtomicU64::new(0),
+            failures: AtomicU64::new(0),
+            healthy: AtomicBool::new(true),
+            streak: AtomicU32::new(0),
+            streak_passing: AtomicBool::new(true),
+            weight: AtomicU32::new(1),
+            breaker: Breaker::new(),
+        }
+    }
+
+    pub(super) fn address(&self) -> &str {
+        &self.address
+    }
+
+    /// A stable name for the upstream that does not reveal its address to clients.
+    pub(super) fn id(&self) -> &str {
+        &self.id
+    }
+
+    pub(super) fn weight(&self) -> u32 {
+        self.weight.load(Ordering::SeqCst)
+    }
+
+    pub(super) fn set_weight(&self, weight: u32) {
+        self.weight.store(weight, Ordering::SeqCst);
+    }
+
+    pub(super) fn is_healthy(&self) -> bool {
+        self.healthy.load(Ordering::SeqCst)
+    }
+
+    /// Records a health check result, taking the upstream out of rotation after `unhealthy`
+    /// consecutive failures and back after `healthy` consecutive passes.
+    ///
+    /// # Arguments
+    ///
+    /// * `passed`: Whether the check passed.
+    /// * `healthy`: The passes needed to return to rotation.
+    /// * `unhealthy`: The failures needed to leave rotation.
+    pub(super) fn record_check(&self, passed: bool, healthy: u32, unhealthy: u32) {
+        let streak = if self.streak_passing.swap(passed, Ordering::SeqCst) == passed {
+            self.streak.fetch_add(1, Ordering::SeqCst) + 1
+        } else {
+            self.streak.store(1, Ordering::SeqCst);
+            1
+        };
+        if passed && streak >= healthy {
+            self.healthy.store(true, Ordering::SeqCst);
+        } else if !passed && streak >= unhealthy {
+            self.healthy.store(false, Ordering::SeqCst);
+            // Connections to a failing upstream are likely broken as well
+            if let Ok(mut idle) = self.idle.lock() {
+                idle.clear();
+            }
+        }
+    }
+
+    pub(super) fn stats(&self) -> UpstreamStats {
+        UpstreamStats {
+            address: self.address.clone(),
+            requests: self.requests.load(Ordering::SeqCst),
+            failures: self.failures.load(Ordering::SeqCst),
+            active: self.active.load(Ordering::SeqCst),
+            idle: self.idle.lock().map_or(0, |idle| idle.len()),
+            healthy: self.is_healthy(),
+            circuit: self.breaker.state(),
+            weight: self.weight(),
+        }
+    }
+
+    /// Sends a request over an idle connection or a new one, and keeps the connection for
+    /// reuse when the response leaves it in a known state.
+    ///
+    /// # Arguments
+    ///
+    /// * `request`: The request with its body read.
+    /// * `max_body_size`: The largest response body read.
+    ///
+    /// # Returns
+    ///
+    /// The upstream response without hop-by-hop headers.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`io::ErrorKind::InvalidData`] for a malformed response or one with a body above
+    /// the limit, and captures IO errors
+    /// from connecting, writing, and reading.
+    pub(super) async fn send(
+        &self,
+        request: &Request,
+        max_body_size: usize,
+    ) -> io::Result<Response> {
+        self.active.fetch_add(1, Ordering::SeqCst);
+        let _active = Active(&self.active);
+        self.requests.fetch_add(1, Ordering::SeqCst);
+        let idle = self.idle.lock().ok().and_then(|mut idle| idle.pop());
+        let result = match idle {
+            // The upstream may have closed an idle connection just before it was reused, which
+            // is only safe to retry when repeating the request has no additional effect
+            Some(stream) if request.method.is_idempotent() => {
+                match self.exchange(stream, request, max_body_size).await {
+                    Ok(response) => Ok(response),
+                    Err(_) => self.connect_and_exchange(request, max_body_size).await,
+                }
+            }
+            Some(stream) => self.exchange(stream, request, max_body_size).await,
+            None => self.connect_and_exchange(request, max_body_size).await,
+        };
+        if result.is_err() {
+            self.failures.fetch_add(1, Ordering::SeqCst);
+        }
+        result
+    }
+
+    async fn connect_and_exchange(
+        &self,
+        request: &Request,
+        max_body_size: usize,
+    ) -> io::Result<Response> {
+        let stream = io::BufReader::new(net::TcpStream::connect(&self.address).await?);
+        self.exchange(stream, request, max_body_size).await
+    }
+
+    async fn exchange(
+        &self,
+        mut stream: io::BufReader<net::TcpStream>,
+        request: &Request,
+        max_body_size: usize,
+    ) -> io::Result<Response> {
+        stream.get_mut().write_all(&request_head(request)).await?;
+        stream.get_mut().write_all(&request.body).await?;
+        loop {
+            let (response, reusable) =
+                read_response(&mut stream, request.method == Method::Head, max_body_size).await?;
+            // Interim responses such as 100 Continue precede the final one
+            if response.status.is_informational() {
+                continue;
+            }
+            if reusable {
+                if let Ok(mut idle) = self.idle.lock() {
+                    if idle.len() < MAX_IDLE {
+                        idle.push(stream);
+                    }
+                }
+            }
+            return Ok(response);
+        }
+    }
+}
+
+/// Picks upstreams for requests according to a [`Strategy`].
+#[derive(Clone, Debug, Default)]
+pub(super) struct Balancer {
+    pub(super) upstreams: Vec<Arc<Upstream>>,
+    pub(super) strategy: Strategy,
+    next: Arc<AtomicUsize>,
+    /// The current weight of each upstream for smooth weighted round-robin, by index.
+    current: Arc<Mutex<Vec<i64>>>,
+}
+
+impl Balancer {
+    pub(super) fn new(upstream: Upstream) -> Balancer {
+        Balancer {
+            upstreams: vec![Arc::new(upstream)],
+            ..Balancer::default()
+        }
+    }
+
+    /// Orders the healthy upstreams for the next request, the preferred one first.
+    ///
+    /// # Returns
+    ///
+    /// The upstreams in turn, led by the next one by weight for [`Strategy::RoundRobin`] or
+    /// sorted by requests in flight per weight for [`Strategy::LeastConnections`]. Upstreams
+    /// with weight 0 come last, and the list is empty when no upstream is healthy.
+    pub(super) fn candidates(&self) -> Vec<&Arc<Upstream>> {
+        let count = self.upstreams.len();
+        if count == 0 {
+            return Vec::new();
+        }
+        let start = self.next.fetch_add(1, Ordering::SeqCst) % count;
+        let mut candidates: Vec<&Arc<Upstream>> = (0..count)
+            .map(|offset| &self.upstreams[(start + offset) % count])
+            .filter(|upstream| upstream.is_healthy())
+            .collect();
+        match self.strategy {
+            Strategy::RoundRobin => {
+                if let Some(picked) = self.pick_weighted() {
+                    if let Some(index) = candidates
+                        .iter()
+                        .position(|upstream| Arc::ptr_eq(upstream, picked))
+                    {
+                        candidates[..=index].rotate_right(1);
+                    }
+                }
+            }
+            Strategy::LeastConnections => candidates.sort_by_key(|upstream| {
+                let active = upstream.active.load(Ordering::SeqCst) as u128;
+                (active << 32) / u128::from(upstream.weight().max(1))
+            }),
+        }
+        candidates.sort_by_key(|upstream| upstream.weight() == 0);
+        candidates
+    }
+
+    /// Picks the healthy upstream with a weight whose turn it is with smooth weighted
+    /// round-robin, which interleaves upstreams rather than sending each its share in a burst.
+    ///
+    /// # Returns
+    ///
+    /// The picked upstream, if any healthy upstream has a weight.
+    fn pick_weighted(&self) -> Option<&Arc<Upstream>> {
+        let mut current = self.current.lock().ok()?;
+        current.resize(self.upstreams.len(), 0);
+        let mut total = 0;
+        let mut picked: Option<usize> = None;
+        for (index, upstream) in self.upstreams.iter().enumerate() {
+            let weight = i64::from(upstream.weight());
+            if weight == 0 || !upstream.is_healthy() {
+                continue;
+            }
+            current[index] += weight;
+            total += weight;
+            if picked.is_none_or(|picked| current[index] > current[picked]) {
+                picked = Some(index);
+            }
+        }
+        let picked = picked?;
+        current[picked] -= total;
+        Some(&self.upstreams[picked])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// It leaves and rejoins rotation only after enough consecutive check results
+    #[test]
+    fn record_check() {
+        let upstream = Upstream::new("a".to_string());
+        upstream.record_check(false, 2, 3);
+        upstream.record_check(false, 2, 3);
+        upstream.record_check(true, 2, 3);
+        upstream.record_check(false, 2, 3);
+        upstream.record_check(false, 2, 3);
+        assert!(upstream.is_healthy());
+        upstream.record_check(false, 2, 3);
+        assert!(!upstream.is_healthy());
+        upstream.record_check(true, 2, 3);
+        assert!(!upstream.is_healthy());
+        upstream.record_check(true, 2, 3);
+        assert!(upstream.is_healthy());
+    }
+
+    /// It takes upstreams in turn, or the least busy one first
+    #[test]
+    fn candidates() {
+        let mut balancer = Balancer {
+            upstreams: ["a", "b", "c"]
+                .map(|address| Arc::new(Upstream::new(address.to_string())))
+                .to_vec(),
+            ..Balancer::default()
+        };
+        let picked: Vec<String> = (0..4)
+            .map(|_| balancer.candidates()[0].address.clone())
+            .collect();
+        assert_eq!(vec!["a", "b", "c", "a"], picked);
+        balancer.strategy = Strategy::LeastConnections;
+        balancer.upstreams[1].active.store(2, Ordering::SeqCst);
+        balancer.upstreams[2].active.store(1, Ordering::SeqCst);
+        let picked: Vec<String> = (0..2)
+            .map(|_| balancer.candidates()[0].address.clone())
+            .collect();
+        assert_eq!(vec!["a", "a"], picked);
+        balancer.upstreams[0].record_check(false, 1, 1);
+        assert_eq!("c", balancer.candidates()[0].address);
+        assert!(Balancer::default().candidates().is_empty());
+    }
+
+    /// It sends each upstream its share of requests by weight, and none with weight 0
+    #[test]
+    fn weights() {
+        let balancer = Balancer {
+            upstreams: ["a", "b", "c"]
+                .map(|address| Arc::new(Upstream::new(address.to_string())))
+                .to_vec(),
+            ..Balancer::default()
+        };
+        balancer.upstreams[0].set_weight(3);
+        balancer.upstreams[2].set_weight(0);
+        let picked: Vec<String> = (0..8)
+            .map(|_| balancer.candidates()[0].address.clone())
+            .collect();
+        assert_eq!(vec!["a", "a", "b", "a", "a", "a", "b", "a"], picked);
+        assert_eq!("c", balancer.candidates()[2].address);
+        balancer.upstreams[0].record_check(false, 1, 1);
+        balancer.upstreams[1].record_check(false, 1, 1);
+        assert_eq!("c", balancer.candidates()[0].address);
+    }
+}
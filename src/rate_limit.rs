@@ -0,0 +1,476 @@
+//! Rate limiting with token buckets. Each client, API key, user, or route, or a combination of
+//! them, gets a bucket holding up to a burst of tokens that refills at a steady rate, and every
+//! request takes a token. Responses report the bucket in the `RateLimit-Limit`,
+//! `RateLimit-Remaining`, and `RateLimit-Reset` headers of the IETF draft, and requests finding
+//! the bucket empty are answered with 429 Too Many Requests and `Retry-After`.
+
+#[cfg(feature = "auth")]
+use crate::auth::Identity;
+use crate::request::Request;
+use crate::response::Response;
+use crate::router::{Handler, MatchedPath, Router};
+use crate::status::StatusCode;
+use async_trait::async_trait;
+use std::collections::HashMap;
+use std::fmt;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::io;
+
+/// The number of buckets kept before idle ones are purged the first time.
+const PURGE_THRESHOLD: usize = 1024;
+
+/// How many requests a bucket allows: a burst of requests at once, refilled evenly over a
+/// period, e.g. 100 requests per minute allow 100 requests at once and then one every 0.6
+/// seconds.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Quota {
+    burst: u32,
+    period: Duration,
+}
+
+impl Quota {
+    /// Creates a quota.
+    ///
+    /// # Arguments
+    ///
+    /// * `burst`: The requests allowed at once, the `RateLimit-Limit`.
+    /// * `period`: How long an empty bucket takes to refill.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`io::ErrorKind::InvalidInput`] for a zero burst or period.
+    pub fn new(burst: u32, period: Duration) -> io::Result<Quota> {
+        if burst == 0 || period.is_zero() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "a rate limit needs a burst and a period above zero",
+            ));
+        }
+        Ok(Quota { burst, period })
+    }
+
+    /// Allows a number of requests per second, at least one.
+    pub fn per_second(requests: u32) -> Quota {
+        Quota {
+            burst: requests.max(1),
+            period: Duration::from_secs(1),
+        }
+    }
+
+    /// Allows a number of requests per minute, at least one.
+    pub fn per_minute(requests: u32) -> Quota {
+        Quota {
+            burst: requests.max(1),
+            period: Duration::from_secs(60),
+        }
+    }
+
+    /// The requests allowed at once.
+    pub fn burst(&self) -> u32 {
+        self.burst
+    }
+
+    /// The time one token takes to refill.
+    fn interval(&self) -> Duration {
+        self.period / self.burst
+    }
+}
+
+impl FromStr for Quota {
+    type Err = io::Error;
+
+    /// Parses requests per unit, e.g. `10/s`, `100/min`, or `1000/h`.
+    fn from_str(quota: &str) -> io::Result<Quota> {
+        let invalid = || {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("invalid rate limit {}, expected e.g. 100/min", quota),
+            )
+        };
+        let (requests, unit) = quota.trim().split_once('/').ok_or_else(invalid)?;
+        let requests = requests.trim().parse().map_err(|_| invalid())?;
+        let period = match unit.trim() {
+            "s" => Duration::from_secs(1),
+            "min" => Duration::from_secs(60),
+            "h" => Duration::from_secs(3600),
+            _ => return Err(invalid()),
+        };
+        Quota::new(requests, period)
+    }
+}
+
+/// The tokens left for one key.
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl Bucket {
+    /// A full bucket.
+    pub(crate) fn full(quota: &Quota, now: Instant) -> Bucket {
+        Bucket {
+            tokens: f64::from(quota.burst),
+            updated: now,
+        }
+    }
+
+    /// Adds the tokens refilled since the last update.
+    fn refill(&mut self, quota: &Quota, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.updated);
+        let refilled = elapsed.as_secs_f64() / quota.interval().as_secs_f64();
+        self.tokens = (self.tokens + refilled).min(f64::from(quota.burst));
+        self.updated = now;
+    }
+
+    /// Takes a token for a request if one is left.
+    ///
+    /// # Returns
+    ///
+    /// Whether the request is allowed and the state of the bucket after it.
+    pub(crate) fn take(&mut self, quota: &Quota, now: Instant) -> Decision {
+        self.refill(quota, now);
+        let allowed = self.tokens >= 1.0;
+        if allowed {
+            self.tokens -= 1.0;
+        }
+        let interval = quota.interval();
+        Decision {
+            allowed,
+            limit: quota.burst,
+            remaining: self.tokens as u32,
+            reset: interval.mul_f64(f64::from(quota.burst) - self.tokens),
+            retry_after: (!allowed).then(|| interval.mul_f64(1.0 - self.tokens)),
+        }
+    }
+
+    /// Checks whether the bucket refilled completely, so forgetting it changes nothing.
+    fn is_full(&self, quota: &Quota, now: Instant) -> bool {
+        let mut refilled = *self;
+        refilled.refill(quota, now);
+        refilled.tokens >= f64::from(quota.burst)
+    }
+}
+
+/// The outcome of taking a token for a request.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Decision {
+    /// Whether the request may go through.
+    pub allowed: bool,
+    /// The requests allowed at once.
+    pub limit: u32,
+    /// The whole requests left right now.
+    pub remaining: u32,
+    /// The time until the bucket is full again.
+    pub reset: Duration,
+    /// The time until the next request is allowed, when this one is not.
+    pub retry_after: Option<Duration>,
+}
+
+impl Decision {
+    /// Reports the bucket in the `RateLimit-*` headers of a response, and `Retry-After` for a
+    /// refused request. A response already reporting fewer remaining requests, from another
+    /// limit, is left alone so that clients see the limit they hit first.
+    pub fn apply(&self, mut response: Response) -> Response {
+        let reported = response
+            .header("RateLimit-Remaining")
+            .and_then(|remaining| remaining.parse::<u32>().ok());
+        if reported.is_some_and(|reported| reported < self.remaining) {
+            return response;
+        }
+        response
+            .headers
+            .insert("RateLimit-Limit", self.limit.to_string());
+        response
+            .headers
+            .insert("RateLimit-Remaining", self.remaining.to_string());
+        response
+            .headers
+            .insert("RateLimit-Reset", seconds(self.reset).to_string());
+        if let Some(wait) = self.retry_after {
+            response
+                .headers
+                .insert("Retry-After", seconds(wait).max(1).to_string());
+        }
+        response
+    }
+
+    /// The 429 Too Many Requests response refusing a request, with the headers of
+    /// [`Decision::apply`].
+    pub fn refusal(&self) -> Response {
+        self.apply(Response::new(
+            StatusCode::TOO_MANY_REQUESTS,
+            "too many requests, please retry later\n",
+        ))
+    }
+}
+
+/// What requests sharing a bucket have in common.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum KeyBy {
+    /// The IP address of the client.
+    Ip,
+    /// The API key attached by [`Router::with_api_keys`], or the IP address of clients
+    /// without one.
+    #[cfg(feature = "auth")]
+    ApiKey,
+    /// The subject of the identity attached by [`Router::with_authentication`] or
+    /// [`Router::with_api_keys`], or the IP address of anonymous clients.
+    #[cfg(feature = "auth")]
+    User,
+    /// The pattern of the route, e.g. `/users/:id`, or the path outside the router.
+    Route,
+}
+
+impl KeyBy {
+    /// The part of a bucket key taken from a request.
+    fn part(&self, request: &Request) -> String {
+        match self {
+            KeyBy::Ip => match request.peer {
+                Some(peer) => format!("ip:{}", peer.ip()),
+                None => "ip:unknown".to_string(),
+            },
+            #[cfg(feature = "auth")]
+            KeyBy::ApiKey => match request
+                .extensions()
+                .get::<Identity>()
+                .and_then(|identity| identity.attributes.get("api_key"))
+            {
+                Some(id) => format!("key:{}", id),
+                None => KeyBy::Ip.part(request),
+            },
+            #[cfg(feature = "auth")]
+            KeyBy::User => match request.extensions().get::<Identity>() {
+                Some(identity) => format!("user:{}", identity.subject),
+                None => KeyBy::Ip.part(request),
+            },
+            KeyBy::Route => match request.extensions().get::<MatchedPath>() {
+                Some(MatchedPath(pattern)) => format!("route:{}", pattern),
+                None => format!("route:{}", request.path()),
+            },
+        }
+    }
+}
+
+/// The buckets of a [`RateLimit`].
+#[derive(Debug, Default)]
+struct Buckets {
+    buckets: HashMap<String, Bucket>,
+    /// The number of buckets at which full ones are purged next.
+    purge_at: usize,
+}
+
+/// Limits requests with a token bucket per key, see [`Router::with_rate_limit`]. Clones share
+/// the buckets.
+#[derive(Clone)]
+pub struct RateLimit {
+    quota: Quota,
+    key: Vec<KeyBy>,
+    buckets: Arc<Mutex<Buckets>>,
+}
+
+impl RateLimit {
+    /// Limits each client IP address to a quota.
+    pub fn new(quota: Quota) -> RateLimit {
+        RateLimit {
+            quota,
+            key: vec![KeyBy::Ip],
+            buckets: Arc::default(),
+        }
+    }
+
+    /// Gives a bucket to each combination of the parts instead, e.g. `[KeyBy::Route]` for a
+    /// limit per route shared by all clients, or `[KeyBy::Route, KeyBy::User]` for a limit
+    /// per route and user. No parts share one bucket among all requests.
+    pub fn with_key(mut self, key: Vec<KeyBy>) -> RateLimit {
+        self.key = key;
+        self
+    }
+
+    /// The quota of each bucket.
+    pub fn quota(&self) -> Quota {
+        self.quota
+    }
+
+    /// Takes a token from the bucket of a request.
+    pub fn check(&self, request: &Request) -> Decision {
+        self.check_at(request, Instant::now())
+    }
+
+    /// Takes a token from the bucket of a request at a point in time.
+    fn check_at(&self, request: &Request, now: Instant) -> Decision {
+        let key = self
+            .key
+            .iter()
+            .map(|part| part.part(request))
+            .collect::<Vec<String>>()
+            .join(" ");
+        let mut buckets = self.lock();
+        let decision = buckets
+            .buckets
+            .entry(key)
+            .or_insert_with(|| Bucket::full(&self.quota, now))
+            .take(&self.quota, now);
+        if buckets.buckets.len() >= buckets.purge_at.max(PURGE_THRESHOLD) {
+            let quota = self.quota;
+            buckets
+                .buckets
+                .retain(|_, bucket| !bucket.is_full(&quota, now));
+            buckets.purge_at = buckets.buckets.len() * 2;
+        }
+        decision
+    }
+
+    /// Forgets the buckets that refilled completely.
+    ///
+    /// # Returns
+    ///
+    /// The number of buckets forgotten.
+    pub fn purge_idle(&self) -> usize {
+        let now = Instant::now();
+        let mut buckets = self.lock();
+        let before = buckets.buckets.len();
+        buckets
+            .buckets
+            .retain(|_, bucket| !bucket.is_full(&self.quota, now));
+        before - buckets.buckets.len()
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Buckets> {
+        self.buckets
+            .lock()
+            .unwrap_or_else(|error| error.into_inner())
+    }
+}
+
+impl fmt::Debug for RateLimit {
+    fn fmt(&self, formatter: &mut fmt::Formatter<'_>) -> fmt::Result {
+        formatter
+            .debug_struct("RateLimit")
+            .field("quota", &self.quota)
+            .field("key", &self.key)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Router {
+    /// Limits the requests of every handler registered so far, reporting the limit on every
+    /// response, see [`RateLimit::check`]. Limits keyed by API key or user are added before
+    /// [`Router::with_api_keys`] or [`Router::with_authentication`], so that they run inside
+    /// them once the identity is known.
+    ///
+    /// # Arguments
+    ///
+    /// * `limit`: The limit, shared with other routers when cloned.
+    ///
+    /// # Returns
+    ///
+    /// The router with limited handlers.
+    pub fn with_rate_limit(self, limit: RateLimit) -> Router {
+        self.map_handlers("rate_limit", |handler| {
+            Arc::new(Limited {
+                limit: limit.clone(),
+                handler,
+            })
+        })
+    }
+}
+
+/// Takes a token for requests before calling a wrapped handler.
+struct Limited {
+    limit: RateLimit,
+    handler: Arc<dyn Handler>,
+}
+
+#[async_trait]
+impl Handler for Limited {
+    async fn call(&self, request: Request) -> io::Result<Response> {
+        let decision = self.limit.check(&request);
+        if !decision.allowed {
+            return Ok(decision.refusal());
+        }
+        Ok(decision.apply(self.handler.call(request).await?))
+    }
+}
+
+/// Rounds a duration up to whole seconds, as the headers count them.
+fn seconds(duration: Duration) -> u128 {
+    duration.as_millis().div_ceil(1000)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::method::Method;
+
+    /// It refills buckets evenly over the period and reports them in the headers
+    #[test]
+    fn token_bucket() {
+        let quota: Quota = "2/min".parse().unwrap();
+        assert!("0/min".parse::<Quota>().is_err());
+        assert!("2/day".parse::<Quota>().is_err());
+        let limit = RateLimit::new(quota);
+        let mut request = Request {
+            peer: Some("192.0.2.1:4000".parse().unwrap()),
+            ..Request::default()
+        };
+        let start = Instant::now();
+        let first = limit.check_at(&request, start);
+        assert!(first.allowed);
+        assert_eq!((2, 1), (first.limit, first.remaining));
+        let response = first.apply(Response::new(StatusCode::OK, ""));
+        assert_eq!(Some("2"), response.header("RateLimit-Limit"));
+        assert_eq!(Some("1"), response.header("RateLimit-Remaining"));
+        assert_eq!(Some("30"), response.header("RateLimit-Reset"));
+        assert!(limit.check_at(&request, start).allowed);
+
+        let refused = limit.check_at(&request, start + Duration::from_secs(10));
+        assert!(!refused.allowed);
+        assert_eq!(Some("20"), refused.refusal().header("Retry-After"));
+        assert!(
+            limit
+                .check_at(&request, start + Duration::from_secs(30))
+                .allowed
+        );
+        request.peer = Some("192.0.2.2:4000".parse().unwrap());
+        assert!(
+            limit
+                .check_at(&request, start + Duration::from_secs(30))
+                .allowed
+        );
+        assert_eq!(0, limit.purge_idle());
+    }
+
+    /// It limits the requests of each route and client and answers 429 once a bucket is empty
+    #[tokio::test]
+    async fn with_rate_limit() {
+        let limit = RateLimit::new(Quota::per_minute(1)).with_key(vec![KeyBy::Route, KeyBy::Ip]);
+        let router = Router::new()
+            .route(Method::Get, "/users/:id", |_| async {
+                Ok(Response::new(StatusCode::OK, "user"))
+            })
+            .route(Method::Get, "/teams", |_| async {
+                Ok(Response::new(StatusCode::OK, "teams"))
+            })
+            .with_rate_limit(limit);
+        let get = |path: &str| {
+            let found = router.lookup(&Method::Get, path).unwrap();
+            let request = found.routed(&Request {
+                target: path.parse().unwrap(),
+                peer: Some("192.0.2.1:4000".parse().unwrap()),
+                ..Request::default()
+            });
+            let handler = found.handler.clone();
+            async move { handler.call(request).await.unwrap() }
+        };
+        let allowed = get("/users/1").await;
+        assert_eq!(StatusCode::OK, allowed.status);
+        assert_eq!(Some("0"), allowed.header("RateLimit-Remaining"));
+        let refused = get("/users/2").await;
+        assert_eq!(StatusCode::TOO_MANY_REQUESTS, refused.status);
+        assert_eq!(Some("60"), refused.header("Retry-After"));
+        assert_eq!(StatusCode::OK, get("/teams").await.status);
+    }
+}
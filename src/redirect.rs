@@ -0,0 +1,167 @@
+//! Redirect rules from the configuration, answering requests for moved pages before routing.
+
+use crate::request::Request;
+use crate::response::Response;
+use crate::status::StatusCode;
+use regex::Regex;
+use std::borrow::Cow;
+use std::str::FromStr;
+use tokio::io;
+
+/// Which paths a redirect applies to.
+#[derive(Clone, Debug)]
+pub enum Source {
+    /// This path exactly, e.g. `/about.html`.
+    Exact(String),
+    /// This path and the paths below it, keeping the rest of the path, e.g. `/docs` moving
+    /// `/docs/a` to `/manual/a`.
+    Prefix(String),
+    /// The paths matching a pattern, with `$1` or `${name}` in the target standing for its
+    /// groups.
+    Regex(Regex),
+}
+
+/// A rule redirecting matching requests to another location.
+#[derive(Clone, Debug)]
+pub struct Redirect {
+    /// The paths redirected, compared with the still percent-encoded path.
+    pub from: Source,
+    /// The new location, a path or an absolute URL. The query of the request is appended.
+    pub to: String,
+    /// 301 Moved Permanently, 302 Found, 307 Temporary Redirect, or 308 Permanent Redirect.
+    pub status: StatusCode,
+}
+
+impl Redirect {
+    /// Answers a request with a redirect when the rule matches.
+    ///
+    /// # Arguments
+    ///
+    /// * `request`: The request about to be handled.
+    ///
+    /// # Returns
+    ///
+    /// The redirect, or `None` when the path does not match.
+    pub fn respond(&self, request: &Request) -> Option<Response> {
+        let path = request.path();
+        let location = match &self.from {
+            Source::Exact(from) => (path == from).then(|| Cow::from(self.to.as_str()))?,
+            Source::Prefix(from) => {
+                let from = from.trim_end_matches('/');
+                let rest = path
+                    .strip_prefix(from)
+                    .filter(|rest| rest.is_empty() || rest.starts_with('/'))?;
+                Cow::from(format!("{}{}", self.to.trim_end_matches('/'), rest))
+            }
+            Source::Regex(pattern) => {
+                let captures = pattern.captures(path)?;
+                let mut location = String::new();
+                captures.expand(&self.to, &mut location);
+                Cow::from(location)
+            }
+        };
+        let location = match request.target.query() {
+            Some(query) if location.contains('?') => format!("{}&{}", location, query),
+            Some(query) => format!("{}?{}", location, query),
+            None => location.into_owned(),
+        };
+        Some(Response::new(self.status, "").with_header("Location", location))
+    }
+}
+
+impl PartialEq for Redirect {
+    fn eq(&self, other: &Redirect) -> bool {
+        let same_from = match (&self.from, &other.from) {
+            (Source::Exact(a), Source::Exact(b)) | (Source::Prefix(a), Source::Prefix(b)) => a == b,
+            (Source::Regex(a), Source::Regex(b)) => a.as_str() == b.as_str(),
+            _ => false,
+        };
+        same_from && self.to == other.to && self.status == other.status
+    }
+}
+
+impl Eq for Redirect {}
+
+impl FromStr for Redirect {
+    type Err = io::Error;
+
+    /// Parses `<exact|prefix|regex> <from> <to>`, optionally followed by `permanent`, the
+    /// default and 301, `temporary` for 302, or one of the codes `301`, `302`, `307`, and `308`,
+    /// e.g. `regex ^/u/(\d+)$ /users/$1 308`.
+    fn from_str(rule: &str) -> io::Result<Redirect> {
+        let invalid = |reason: &str| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("invalid redirect rule {}: {}", rule, reason),
+            )
+        };
+        let parts: Vec<&str> = rule.split_whitespace().collect();
+        let (kind, from, to, status) = match parts[..] {
+            [kind, from, to] => (kind, from, to, "permanent"),
+            [kind, from, to, status] => (kind, from, to, status),
+            _ => return Err(invalid("expected <kind> <from> <to> [<status>]")),
+        };
+        let from = match kind {
+            "exact" => Source::Exact(from.to_string()),
+            "prefix" => Source::Prefix(from.to_string()),
+            "regex" => {
+                Source::Regex(Regex::new(from).map_err(|error| invalid(&error.to_string()))?)
+            }
+            _ => return Err(invalid("the kind is not exact, prefix, or regex")),
+        };
+        let status = match status {
+            "permanent" | "301" => StatusCode::MOVED_PERMANENTLY,
+            "temporary" | "302" => StatusCode::FOUND,
+            "307" => StatusCode::TEMPORARY_REDIRECT,
+            "308" => StatusCode::PERMANENT_REDIRECT,
+            _ => return Err(invalid("the status is not a redirect")),
+        };
+        Ok(Redirect {
+            from,
+            to: to.to_string(),
+            status,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// It redirects exact paths, prefixes, and patterns, keeping the query
+    #[test]
+    fn respond() {
+        let request = |target: &str| Request {
+            target: target.parse().unwrap(),
+            ..Request::default()
+        };
+        let location = |rule: &str, target: &str| {
+            let response = rule
+                .parse::<Redirect>()
+                .unwrap()
+                .respond(&request(target))?;
+            Some((
+                response.status.as_u16(),
+                response.header("Location")?.to_string(),
+            ))
+        };
+        assert_eq!(
+            Some((301, "/new?x=1".to_string())),
+            location("exact /old /new", "/old?x=1")
+        );
+        assert_eq!(None, location("exact /old /new", "/old/"));
+        assert_eq!(
+            Some((302, "https://example.com/manual/a".to_string())),
+            location(
+                "prefix /docs/ https://example.com/manual temporary",
+                "/docs/a"
+            )
+        );
+        assert_eq!(None, location("prefix /docs /manual", "/docsx"));
+        assert_eq!(
+            Some((308, "/users/7?tab=1&x=1".to_string())),
+            location(r"regex ^/u/(\d+)$ /users/$1?tab=1 308", "/u/7?x=1")
+        );
+        assert!("exact /a /b 200".parse::<Redirect>().is_err());
+    }
+}
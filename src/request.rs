@@ -0,0 +1,496 @@
+use crate::extensions::Extensions;
+use crate::header::HeaderMap;
+use crate::method::Method;
+use crate::status::StatusCode;
+use crate::trace::TraceContext;
+use crate::uri::Uri;
+use crate::version::Version;
+use bytes::Bytes;
+use std::any::Any;
+use std::fmt;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tokio::io;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt};
+
+/// The longest line of a request head in bytes, the request line included.
+pub const MAX_LINE_LENGTH: usize = 8 * 1024;
+
+/// The most header lines a request head may have.
+pub const MAX_HEADERS: usize = 100;
+
+/// The violation of a request line that is not a method token, a target, and a version.
+const MALFORMED_REQUEST_LINE: &str = "malformed request line";
+
+/// The violation of a line of the head that is not UTF-8.
+const NOT_UTF8: &str = "head line not UTF-8";
+
+/// The violation of a request line longer than [`MAX_LINE_LENGTH`].
+const REQUEST_LINE_TOO_LONG: &str = "request line too long";
+
+/// The violation of a header line longer than [`MAX_LINE_LENGTH`].
+const HEADER_TOO_LONG: &str = "header line too long";
+
+/// The violation of a head with more than [`MAX_HEADERS`] header lines.
+const TOO_MANY_HEADERS: &str = "too many headers";
+
+/// The head of a parsed HTTP request: request line and headers.
+///
+/// A head that cannot be read completely, see [`Request::head_error`], stops being read where
+/// it went wrong, leaving the rest of the fields at their defaults.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Request {
+    /// The request method, e.g. `GET` or `PROPFIND`.
+    pub method: Method,
+    /// The request target, e.g. `/docs/a%20b.txt?x=1`.
+    pub target: Uri,
+    /// The protocol version, e.g. [`Version::Http11`].
+    pub version: Version,
+    /// The headers in the order they were received.
+    pub headers: HeaderMap,
+    /// The body, read into memory before the request is passed to a handler registered on the
+    /// [`crate::router::Router`]. Built-in handlers stream the body instead and see it empty.
+    pub body: Bytes,
+    /// The first irregularity in the head that a lenient reader tolerates but that lets two
+    /// parsers disagree about where the request ends, e.g. a bare LF line ending, or why the
+    /// head could not be read completely.
+    pub violation: Option<&'static str>,
+    /// The address of the client, when the connection knows it. Requests converted from the
+    /// `http` crate take it from a [`SocketAddr`] extension.
+    pub peer: Option<SocketAddr>,
+    /// The certificate the client authenticated with, when a TLS listener verifying client
+    /// certificates accepted the connection.
+    pub client_certificate: Option<ClientCertificate>,
+    /// The distributed trace the request is part of, continued from its `traceparent` header
+    /// by the server and passed on to upstreams.
+    pub trace: Option<TraceContext>,
+    /// The names of the parameters and wildcards of the route pattern the request matched
+    /// with the still percent-encoded segments they matched, set by the
+    /// [`crate::router::Router`] and parsed by [`crate::extract::Path`].
+    pub params: Vec<(String, String)>,
+    /// Data attached by the server and middleware for the handlers after them, e.g. the
+    /// [`crate::router::MatchedPath`] of the route.
+    pub extensions: Extensions,
+    /// The values shared with the handlers of a router scope, see
+    /// [`crate::router::Router::with_state`].
+    pub state: State,
+}
+
+/// The identity of a client that presented a verified certificate over mutual TLS.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ClientCertificate {
+    /// The subject distinguished name, e.g. `CN=alice, O=Example`.
+    pub subject: String,
+    /// The DNS names, e-mail addresses, and URIs of the subject alternative names.
+    pub names: Vec<String>,
+    /// The DER encoding of the leaf certificate.
+    pub der: Bytes,
+}
+
+/// Values a router scope shares with its handlers, one per type, e.g. a database pool.
+#[derive(Clone, Default)]
+pub struct State {
+    /// The values, from the outermost scope to the innermost.
+    values: Vec<Arc<dyn Any + Send + Sync>>,
+}
+
+impl State {
+    /// Adds a value, which hides any value of the same type added before.
+    pub fn insert(&mut self, value: Arc<dyn Any + Send + Sync>) {
+        self.values.push(value);
+    }
+
+    /// Finds the value of a type added last.
+    ///
+    /// # Returns
+    ///
+    /// The value, or `None` when no scope of the route shares one of the type.
+    pub fn get<T: Send + Sync + 'static>(&self) -> Option<&T> {
+        self.values
+            .iter()
+            .rev()
+            .find_map(|value| value.downcast_ref::<T>())
+    }
+}
+
+impl fmt::Debug for State {
+    fn fmt(&self, formatter: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(formatter, "State({} values)", self.values.len())
+    }
+}
+
+/// States are equal when they share the same values.
+impl PartialEq for State {
+    fn eq(&self, other: &State) -> bool {
+        self.values.len() == other.values.len()
+            && self
+                .values
+                .iter()
+                .zip(&other.values)
+                .all(|(a, b)| Arc::ptr_eq(a, b))
+    }
+}
+
+impl Eq for State {}
+
+impl Request {
+    /// Reads a request line and headers up to and including the empty line ending the head.
+    ///
+    /// Bare LF line endings and obsolete line folding are accepted, with folded lines appended to
+    /// the previous header value, and are recorded in [`Request::violation`] together with
+    /// conflicting `Content-Length` values and a `Content-Length` next to `Transfer-Encoding`.
+    /// One empty line before the request line is skipped. Reading stops at a malformed request
+    /// line, a line that is not UTF-8 or longer than [`MAX_LINE_LENGTH`], or more than
+    /// [`MAX_HEADERS`] header lines, see [`Request::head_error`].
+    ///
+    /// # Arguments
+    ///
+    /// * `reader`: A buffered reader positioned at the start of a request.
+    ///
+    /// # Returns
+    ///
+    /// The parsed request head. Reaching the end of the stream ends the head early, and before
+    /// the request line leaves the request at its default.
+    ///
+    /// # Errors
+    ///
+    /// Captures IO errors from reading lines from `reader`.
+    pub async fn read_from<R>(reader: &mut R) -> io::Result<Request>
+    where
+        R: io::AsyncBufRead + Unpin + Send,
+    {
+        let mut request = Request::default();
+        let mut line = String::new();
+        let mut read = read_line(reader, &mut line, &mut request.violation).await?;
+        if matches!(read, Line::Read) && line.is_empty() {
+            // Some clients end the body of the previous request with an extra line break
+            read = read_line(reader, &mut line, &mut request.violation).await?;
+        }
+        match read {
+            Line::Read => {}
+            Line::Ended => return Ok(request),
+            Line::Refused(reason) => {
+                request.violation = Some(match reason {
+                    HEADER_TOO_LONG => REQUEST_LINE_TOO_LONG,
+                    reason => reason,
+                });
+                return Ok(request);
+            }
+        }
+        let parts: Vec<&str> = line.split_whitespace().collect();
+        let parsed = match parts[..] {
+            [method, target, version] => (
+                method.parse().ok(),
+                target.parse().ok(),
+                version.parse().ok(),
+            ),
+            _ => (None, None, None),
+        };
+        let (Some(method), Some(target), Some(version)) = parsed else {
+            request.violation = Some(MALFORMED_REQUEST_LINE);
+            return Ok(request);
+        };
+        request.method = method;
+        request.target = target;
+        request.version = version;
+
+        loop {
+            match read_line(reader, &mut line, &mut request.violation).await? {
+                Line::Read if line.is_empty() => break,
+                Line::Read => {}
+                Line::Ended => break,
+                Line::Refused(reason) => {
+                    request.violation = Some(reason);
+                    return Ok(request);
+                }
+            }
+            if request.headers.len() == MAX_HEADERS {
+                request.violation = Some(TOO_MANY_HEADERS);
+                return Ok(request);
+            }
+            if line.starts_with([' ', '\t']) {
+                request.violation.get_or_insert("obsolete line folding");
+                if let Some(value) = request.headers.last_value_mut() {
+                    value.push(' ');
+                    value.push_str(line.trim());
+                }
+            } else if let Some((name, value)) = line.split_once(':') {
+                request.headers.append(name.trim(), value.trim());
+            }
+        }
+
+        let lengths: Vec<&str> = request
+            .headers
+            .get_all("Content-Length")
+            .flat_map(|value| value.split(','))
+            .map(str::trim)
+            .collect();
+        if let Some(first) = lengths.first() {
+            if lengths.iter().any(|length| length != first) {
+                request
+                    .violation
+                    .get_or_insert("conflicting Content-Length values");
+            }
+            if request.headers.contains("Transfer-Encoding") {
+                request
+                    .violation
+                    .get_or_insert("both Content-Length and Transfer-Encoding");
+            }
+        }
+        Ok(request)
+    }
+
+    /// The status answering a head that could not be read completely, whatever
+    /// [`crate::config::Config::strict`] says, as neither its end nor its body is known.
+    ///
+    /// # Returns
+    ///
+    /// 431 Request Header Fields Too Large for a header line longer than [`MAX_LINE_LENGTH`] or
+    /// more than [`MAX_HEADERS`] of them, 400 Bad Request for a malformed or overlong request
+    /// line or a line that is not UTF-8, and `None` for a complete head.
+    pub fn head_error(&self) -> Option<StatusCode> {
+        match self.violation? {
+            HEADER_TOO_LONG | TOO_MANY_HEADERS => Some(StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE),
+            MALFORMED_REQUEST_LINE | REQUEST_LINE_TOO_LONG | NOT_UTF8 => {
+                Some(StatusCode::BAD_REQUEST)
+            }
+            _ => None,
+        }
+    }
+
+    /// Finds the first header with a matching name, ignoring ASCII case.
+    ///
+    /// # Arguments
+    ///
+    /// * `name`: The header name to look up.
+    ///
+    /// # Returns
+    ///
+    /// The value of the first matching header, if any.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.get(name)
+    }
+
+    /// Finds a cookie sent in the `Cookie` header.
+    ///
+    /// # Arguments
+    ///
+    /// * `name`: The cookie name, compared exactly.
+    ///
+    /// # Returns
+    ///
+    /// The value of the first matching cookie, if any.
+    pub fn cookie(&self, name: &str) -> Option<&str> {
+        self.headers
+            .get_all("Cookie")
+            .flat_map(|value| value.split(';'))
+            .find_map(|pair| {
+                let (key, value) = pair.trim().split_once('=')?;
+                if key == name {
+                    Some(value)
+                } else {
+                    None
+                }
+            })
+    }
+
+    /// The data attached by the server and middleware, see [`Request::extensions`].
+    pub fn extensions(&self) -> &Extensions {
+        &self.extensions
+    }
+
+    /// The data attached by the server and middleware, for attaching more.
+    pub fn extensions_mut(&mut self) -> &mut Extensions {
+        &mut self.extensions
+    }
+
+    /// The percent-encoded path of the request target, see [`Uri::path`].
+    pub fn path(&self) -> &str {
+        self.target.path()
+    }
+
+    /// The value of the `Content-Length` header, or zero when absent or invalid.
+    pub fn content_length(&self) -> u64 {
+        self.headers.content_length().unwrap_or(0)
+    }
+}
+
+/// How reading one line of the head ended.
+enum Line {
+    /// The line was read.
+    Read,
+    /// The stream ended before any byte of the line.
+    Ended,
+    /// The line cannot be part of a head, for the reason recorded as the violation.
+    Refused(&'static str),
+}
+
+/// Reads one line of the head without its line ending, reading at most [`MAX_LINE_LENGTH`]
+/// bytes and a line ending.
+///
+/// # Arguments
+///
+/// * `reader`: The reader positioned at the start of a line.
+/// * `line`: Cleared, then filled with the line.
+/// * `violation`: Set to a bare LF violation unless already set when the line ends in LF alone.
+///
+/// # Returns
+///
+/// Whether the line was read, the stream ended before it, or it is too long or not UTF-8.
+///
+/// # Errors
+///
+/// Captures IO errors from reading `reader`.
+async fn read_line<R>(
+    reader: &mut R,
+    line: &mut String,
+    violation: &mut Option<&'static str>,
+) -> io::Result<Line>
+where
+    R: io::AsyncBufRead + Unpin + Send,
+{
+    line.clear();
+    let mut bytes = Vec::new();
+    // Room for the longest line and its line ending, so a longer line is cut short
+    let mut limited = (&mut *reader).take(MAX_LINE_LENGTH as u64 + 2);
+    if limited.read_until(b'\n', &mut bytes).await? == 0 {
+        return Ok(Line::Ended);
+    }
+    let content = bytes.strip_suffix(b"\n").unwrap_or(&bytes);
+    if content.strip_suffix(b"\r").unwrap_or(content).len() > MAX_LINE_LENGTH {
+        return Ok(Line::Refused(HEADER_TOO_LONG));
+    }
+    match String::from_utf8(bytes) {
+        Ok(read) => *line = read,
+        Err(_) => return Ok(Line::Refused(NOT_UTF8)),
+    }
+    if line.ends_with('\n') {
+        line.pop();
+        if line.ends_with('\r') {
+            line.pop();
+        } else {
+            violation.get_or_insert("bare LF line ending");
+        }
+    }
+    Ok(Line::Read)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// It parses a request line and headers, and stops at the empty line
+    #[tokio::test]
+    async fn read_head() {
+        let mut bytes =
+            "PROPFIND /a%20b?x=1 HTTP/1.1\r\nDepth: 1\r\nHost:  example\r\n\r\nbody".as_bytes();
+        let request = Request::read_from(&mut bytes).await.unwrap();
+        assert_eq!("PROPFIND", request.method.as_str());
+        assert_eq!(Some("x=1"), request.target.query());
+        assert_eq!("/a%20b", request.path());
+        assert_eq!(Version::Http11, request.version);
+        assert_eq!(Some("1"), request.header("depth"));
+        assert_eq!(Some("example"), request.header("HOST"));
+        assert_eq!(None, request.header("Destination"));
+    }
+
+    /// It finds cookies across several `Cookie` headers
+    #[tokio::test]
+    async fn cookies() {
+        let mut bytes =
+            "GET / HTTP/1.1\r\nCookie: a=1; session=abc\r\ncookie: b=2\r\n\r\n".as_bytes();
+        let request = Request::read_from(&mut bytes).await.unwrap();
+        assert_eq!(Some("abc"), request.cookie("session"));
+        assert_eq!(Some("2"), request.cookie("b"));
+        assert_eq!(None, request.cookie("Session"));
+    }
+
+    /// It refuses a malformed request line with 400 and leaves its fields empty
+    #[tokio::test]
+    async fn malformed_request_line() {
+        let mut bytes = "GARBAGE\r\nHost: a\r\n\r\n".as_bytes();
+        let request = Request::read_from(&mut bytes).await.unwrap();
+        assert_eq!(Method::default(), request.method);
+        assert_eq!(None, request.header("Host"));
+        assert_eq!(Some(StatusCode::BAD_REQUEST), request.head_error());
+        let mut bytes = "".as_bytes();
+        let request = Request::read_from(&mut bytes).await.unwrap();
+        assert_eq!(Request::default(), request);
+        assert_eq!(None, request.head_error());
+    }
+
+    /// It skips one empty line before the request line
+    #[tokio::test]
+    async fn leading_empty_line() {
+        let mut bytes = "\r\nGET / HTTP/1.1\r\n\r\n".as_bytes();
+        let request = Request::read_from(&mut bytes).await.unwrap();
+        assert_eq!(Method::Get, request.method);
+        assert_eq!(None, request.violation);
+    }
+
+    /// It refuses a header line that is not UTF-8 with 400
+    #[tokio::test]
+    async fn not_utf8() {
+        let mut bytes = &b"GET / HTTP/1.1\r\nX-A: \xff\r\n\r\n"[..];
+        let request = Request::read_from(&mut bytes).await.unwrap();
+        assert_eq!(Some(StatusCode::BAD_REQUEST), request.head_error());
+    }
+
+    /// It refuses overlong lines and too many headers without reading past the limits
+    #[tokio::test]
+    async fn limits() {
+        let long = "a".repeat(MAX_LINE_LENGTH);
+        let head = format!("GET /{} HTTP/1.1\r\n\r\n", long);
+        let request = Request::read_from(&mut head.as_bytes()).await.unwrap();
+        assert_eq!(Some(StatusCode::BAD_REQUEST), request.head_error());
+        let head = format!("GET / HTTP/1.1\r\nX-A: {}\r\n\r\n", long);
+        let mut bytes = head.as_bytes();
+        let request = Request::read_from(&mut bytes).await.unwrap();
+        assert_eq!(
+            Some(StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE),
+            request.head_error()
+        );
+        assert!(!bytes.is_empty());
+        let head = format!(
+            "GET / HTTP/1.1\r\n{}\r\n",
+            "X-A: 1\r\n".repeat(MAX_HEADERS + 1)
+        );
+        let request = Request::read_from(&mut head.as_bytes()).await.unwrap();
+        assert_eq!(
+            Some(StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE),
+            request.head_error()
+        );
+        let line = "a".repeat(MAX_LINE_LENGTH - 5);
+        let head = format!("GET / HTTP/1.1\r\nX-A: {}\r\n\r\n", line);
+        let request = Request::read_from(&mut head.as_bytes()).await.unwrap();
+        assert_eq!(None, request.head_error());
+        assert_eq!(Some(line.as_str()), request.header("X-A"));
+    }
+
+    /// It records ambiguous framing while still parsing the head leniently
+    #[tokio::test]
+    async fn violations() {
+        let mut bytes = "GET / HTTP/1.1\r\nX-A: 1\r\n 2\r\n\r\n".as_bytes();
+        let request = Request::read_from(&mut bytes).await.unwrap();
+        assert_eq!(Some("1 2"), request.header("X-A"));
+        assert_eq!(Some("obsolete line folding"), request.violation);
+        let mut bytes = "GET / HTTP/1.1\nHost: a\n\n".as_bytes();
+        let request = Request::read_from(&mut bytes).await.unwrap();
+        assert_eq!(Some("a"), request.header("Host"));
+        assert_eq!(Some("bare LF line ending"), request.violation);
+        let mut bytes =
+            "POST / HTTP/1.1\r\nContent-Length: 3, 3\r\nContent-Length: 4\r\n\r\n".as_bytes();
+        let request = Request::read_from(&mut bytes).await.unwrap();
+        assert_eq!(Some("conflicting Content-Length values"), request.violation);
+        let mut bytes =
+            "POST / HTTP/1.1\r\nContent-Length: 3\r\nTransfer-Encoding: chunked\r\n\r\n".as_bytes();
+        let request = Request::read_from(&mut bytes).await.unwrap();
+        assert_eq!(
+            Some("both Content-Length and Transfer-Encoding"),
+            request.violation
+        );
+        let mut bytes = "POST / HTTP/1.1\r\nContent-Length: 3, 3\r\n\r\n".as_bytes();
+        let request = Request::read_from(&mut bytes).await.unwrap();
+        assert_eq!(None, request.violation);
+    }
+}
@@ -0,0 +1,477 @@
+use crate::date;
+use crate::error::ResponseError;
+use crate::extensions::Extensions;
+use crate::header::HeaderMap;
+use crate::log;
+use crate::status::StatusCode;
+use bytes::{Bytes, BytesMut};
+use std::fmt::Write;
+
+/// An HTTP response ready to be serialized and written to a client.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Response {
+    /// The status code, e.g. [`StatusCode::OK`].
+    pub status: StatusCode,
+    /// Headers other than `Content-Length`, which is computed for the statuses with a body.
+    pub headers: HeaderMap,
+    /// Request header names the response depends on, merged with any `Vary` entries of
+    /// [`Response::headers`] into a single `Vary` header when serialized.
+    pub vary: Vec<String>,
+    /// The response body, which clones share instead of copying.
+    pub body: Bytes,
+    /// The upstream that produced the response, set by the reverse proxy for the access log
+    /// and never sent to the client.
+    pub upstream: Option<String>,
+    /// Metadata for the middleware and the server finalizing the response, never sent to the
+    /// client, e.g. a [`crate::cache::CacheHint`] or [`crate::log::format::LogFields`].
+    pub extensions: Extensions,
+}
+
+impl Response {
+    /// Creates a response without extra headers.
+    ///
+    /// # Arguments
+    ///
+    /// * `status`: The status code, e.g. [`StatusCode::OK`].
+    /// * `body`: The response body.
+    ///
+    /// # Returns
+    ///
+    /// A response with the given status code and body.
+    pub fn new(status: StatusCode, body: impl Into<Bytes>) -> Response {
+        Response {
+            status,
+            headers: HeaderMap::new(),
+            vary: Vec::new(),
+            body: body.into(),
+            upstream: None,
+            extensions: Extensions::new(),
+        }
+    }
+
+    /// Attaches metadata for the middleware and the server finalizing the response.
+    ///
+    /// # Arguments
+    ///
+    /// * `extension`: The value, replacing the value of the same type.
+    ///
+    /// # Returns
+    ///
+    /// The response with the extension.
+    pub fn with_extension<T: Clone + Send + Sync + 'static>(mut self, extension: T) -> Response {
+        self.extensions.insert(extension);
+        self
+    }
+
+    /// The metadata attached to the response, see [`Response::extensions`].
+    pub fn extensions(&self) -> &Extensions {
+        &self.extensions
+    }
+
+    /// The metadata attached to the response, for attaching more.
+    pub fn extensions_mut(&mut self) -> &mut Extensions {
+        &mut self.extensions
+    }
+
+    /// Appends a header to the response, unless its name or value contains a line break,
+    /// which would let the value add headers of its own, e.g. from a redirect target taken
+    /// from the request.
+    ///
+    /// # Arguments
+    ///
+    /// * `name`: The header name.
+    /// * `value`: The header value.
+    ///
+    /// # Returns
+    ///
+    /// The response with the header appended, or unchanged with a warning logged for a line
+    /// break.
+    pub fn with_header(mut self, name: &str, value: impl Into<String>) -> Response {
+        let value = value.into();
+        if breaks_line(name) || breaks_line(&value) {
+            log::warn(format_args!(
+                "dropped the {} header with a line break",
+                name
+            ));
+            return self;
+        }
+        self.headers.append(name, value);
+        self
+    }
+
+    /// Finds the first header with a matching name, ignoring ASCII case.
+    ///
+    /// # Arguments
+    ///
+    /// * `name`: The header name to look up.
+    ///
+    /// # Returns
+    ///
+    /// The value of the first matching header, if any.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.get(name)
+    }
+
+    /// Records that the response was chosen based on a request header, so shared caches keep
+    /// one copy per value of that header.
+    ///
+    /// # Arguments
+    ///
+    /// * `name`: The request header name, e.g. `Accept`. Duplicates are ignored.
+    pub fn add_vary(&mut self, name: &str) {
+        if !self
+            .vary
+            .iter()
+            .any(|existing| existing.eq_ignore_ascii_case(name))
+        {
+            self.vary.push(name.to_string());
+        }
+    }
+
+    /// Records a request header the response depends on, see [`Response::add_vary`].
+    ///
+    /// # Arguments
+    ///
+    /// * `name`: The request header name, e.g. `Accept`.
+    ///
+    /// # Returns
+    ///
+    /// The response with the header name recorded.
+    pub fn with_vary(mut self, name: &str) -> Response {
+        self.add_vary(name);
+        self
+    }
+
+    /// Merges [`Response::vary`] and any `Vary` entries of [`Response::headers`].
+    ///
+    /// # Returns
+    ///
+    /// The value of the single `Vary` header to send, `*` if any entry is `*`, or `None` when
+    /// the response does not vary.
+    pub fn merged_vary(&self) -> Option<String> {
+        let mut merged: Vec<&str> = Vec::new();
+        let listed = self
+            .headers
+            .get_all("Vary")
+            .flat_map(|value| value.split(','));
+        for name in listed.chain(self.vary.iter().map(String::as_str)) {
+            let name = name.trim();
+            if name == "*" {
+                return Some("*".to_string());
+            }
+            if !name.is_empty()
+                && !merged
+                    .iter()
+                    .any(|existing| existing.eq_ignore_ascii_case(name))
+            {
+                merged.push(name);
+            }
+        }
+        if merged.is_empty() {
+            None
+        } else {
+            Some(merged.join(", "))
+        }
+    }
+
+    /// Serializes the status line, headers, and body, leaving out the body and `Content-Length`
+    /// for the statuses that cannot have them, see [`Response::has_body`].
+    ///
+    /// # Returns
+    ///
+    /// The bytes to write to the client.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut buffer = BytesMut::new();
+        self.write_head(&mut buffer, None);
+        if self.has_body() {
+            buffer.extend_from_slice(&self.body);
+        }
+        buffer.to_vec()
+    }
+
+    /// Serializes the response into a buffer that is reused across responses, adding a `Date`
+    /// header unless [`Response::headers`] already has one.
+    ///
+    /// # Arguments
+    ///
+    /// * `buffer`: The buffer the response is appended to.
+    /// * `head_only`: Leaves out the body while keeping its `Content-Length`, for a response to
+    ///   a `HEAD` request.
+    pub fn write_to(&self, buffer: &mut BytesMut, head_only: bool) {
+        if self.headers.contains("Date") {
+            self.write_head(buffer, None);
+        } else {
+            date::with_current(|date| self.write_head(buffer, Some(date)));
+        }
+        if !head_only && self.has_body() {
+            buffer.extend_from_slice(&self.body);
+        }
+    }
+
+    /// Checks whether the status allows a body, which 1xx, 204 No Content, and 304 Not Modified
+    /// do not. These are sent without a `Content-Length` too, as that of a 304 would have to be
+    /// the length of the body it stands for.
+    fn has_body(&self) -> bool {
+        !self.status.is_informational()
+            && self.status != StatusCode::NO_CONTENT
+            && self.status != StatusCode::NOT_MODIFIED
+    }
+
+    /// Serializes the status line and headers without formatting into intermediate strings.
+    ///
+    /// # Arguments
+    ///
+    /// * `buffer`: The buffer the head is appended to.
+    /// * `date`: The value of a `Date` header written after `Content-Length`, if any.
+    fn write_head(&self, buffer: &mut BytesMut, date: Option<&str>) {
+        let vary = self.merged_vary();
+        let headers_len: usize = self
+            .headers
+            .iter()
+            .map(|(name, value)| name.len() + value.len() + 4)
+            .sum();
+        buffer.reserve(64 + headers_len + date.map_or(0, str::len) + self.body.len());
+        // Writing into a BytesMut cannot fail
+        let _ = write!(buffer, "HTTP/1.1 {}\r\n", self.status);
+        if self.has_body() {
+            let _ = write!(buffer, "Content-Length: {}\r\n", self.body.len());
+        }
+        if let Some(date) = date {
+            put_header(buffer, "Date", date);
+        }
+        for (name, value) in &self.headers {
+            if !name.eq_ignore_ascii_case("Vary") {
+                put_header(buffer, name, value);
+            }
+        }
+        if let Some(vary) = vary {
+            put_header(buffer, "Vary", &vary);
+        }
+        buffer.extend_from_slice(b"\r\n");
+    }
+}
+
+/// Converts the values handlers return into responses, so that a handler taking extractors can
+/// return a `String`, a `(StatusCode, &'static str)`, or a `Result` of either, see
+/// [`crate::extract::with_extractors`].
+pub trait IntoResponse {
+    /// Creates the response.
+    fn into_response(self) -> Response;
+}
+
+impl IntoResponse for Response {
+    fn into_response(self) -> Response {
+        self
+    }
+}
+
+/// An empty response with the status.
+impl IntoResponse for StatusCode {
+    fn into_response(self) -> Response {
+        Response::new(self, "")
+    }
+}
+
+/// 200 OK with the text as `text/plain`.
+impl IntoResponse for String {
+    fn into_response(self) -> Response {
+        Response::new(StatusCode::OK, self).with_header("Content-Type", "text/plain; charset=utf-8")
+    }
+}
+
+/// 200 OK with the text as `text/plain`.
+impl IntoResponse for &'static str {
+    fn into_response(self) -> Response {
+        Response::new(StatusCode::OK, self).with_header("Content-Type", "text/plain; charset=utf-8")
+    }
+}
+
+/// 200 OK with the bytes as `application/octet-stream`.
+impl IntoResponse for Bytes {
+    fn into_response(self) -> Response {
+        Response::new(StatusCode::OK, self).with_header("Content-Type", "application/octet-stream")
+    }
+}
+
+/// 200 OK with the bytes as `application/octet-stream`.
+impl IntoResponse for Vec<u8> {
+    fn into_response(self) -> Response {
+        Bytes::from(self).into_response()
+    }
+}
+
+/// The response of the value with the status replaced.
+impl<T: IntoResponse> IntoResponse for (StatusCode, T) {
+    fn into_response(self) -> Response {
+        let (status, value) = self;
+        Response {
+            status,
+            ..value.into_response()
+        }
+    }
+}
+
+/// The response of the value with the headers appended and the status replaced.
+impl<T: IntoResponse, const N: usize> IntoResponse for (StatusCode, [(&str, &str); N], T) {
+    fn into_response(self) -> Response {
+        let (status, headers, value) = self;
+        let mut response = (status, value).into_response();
+        for (name, value) in headers {
+            response.headers.append(name, value);
+        }
+        response
+    }
+}
+
+/// The response of the value, or of the error as [`ResponseError::error_response`] says.
+impl<T: IntoResponse, E: ResponseError> IntoResponse for Result<T, E> {
+    fn into_response(self) -> Response {
+        match self {
+            Ok(value) => value.into_response(),
+            Err(error) => error.error_response(),
+        }
+    }
+}
+
+/// Appends one header line, skipping a header whose name or value contains a line break,
+/// e.g. one set through [`Response::headers`] directly.
+///
+/// # Arguments
+///
+/// * `buffer`: The buffer the line is appended to.
+/// * `name`: The header name.
+/// * `value`: The header value.
+fn put_header(buffer: &mut BytesMut, name: &str, value: &str) {
+    if breaks_line(name) || breaks_line(value) {
+        return;
+    }
+    buffer.extend_from_slice(name.as_bytes());
+    buffer.extend_from_slice(b": ");
+    buffer.extend_from_slice(value.as_bytes());
+    buffer.extend_from_slice(b"\r\n");
+}
+
+/// Checks whether a header name or value contains CR or LF.
+fn breaks_line(text: &str) -> bool {
+    text.contains(['\r', '\n'])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// It merges recorded names and `Vary` headers into one header without duplicates
+    #[test]
+    fn merged_vary() {
+        let mut response = Response::new(StatusCode::OK, "")
+            .with_header("Vary", "Accept, origin")
+            .with_vary("Accept-Encoding");
+        response.add_vary("accept");
+        response.add_vary("Origin");
+        assert_eq!(
+            b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\nVary: Accept, origin, Accept-Encoding\r\n\r\n"
+                .to_vec(),
+            response.to_bytes()
+        );
+        assert_eq!(Some("*".to_string()), response.with_vary("*").merged_vary());
+        assert_eq!(None, Response::new(StatusCode::OK, "").merged_vary());
+    }
+
+    /// It serializes a response with headers in the same layout as the original formatter
+    #[test]
+    fn to_bytes() {
+        let response = Response::new(StatusCode::CREATED, "done").with_header("Location", "/a");
+        assert_eq!(
+            b"HTTP/1.1 201 Created\r\nContent-Length: 4\r\nLocation: /a\r\n\r\ndone".to_vec(),
+            response.to_bytes()
+        );
+    }
+
+    /// It converts handler return values with their status, headers, and content type
+    #[test]
+    fn into_response() {
+        let response = "hi".into_response();
+        assert_eq!(StatusCode::OK, response.status);
+        assert_eq!(
+            Some("text/plain; charset=utf-8"),
+            response.header("Content-Type")
+        );
+        let response = (StatusCode::CREATED, [("Location", "/a")], vec![1]).into_response();
+        assert_eq!(StatusCode::CREATED, response.status);
+        assert_eq!(Some("/a"), response.header("Location"));
+        assert_eq!(
+            Some("application/octet-stream"),
+            response.header("Content-Type")
+        );
+        let failed: Result<String, StatusCode> = Err(StatusCode::CONFLICT);
+        assert_eq!(
+            Response::new(StatusCode::CONFLICT, ""),
+            failed.into_response()
+        );
+        let failed: std::io::Result<Response> = Err(std::io::Error::other("disk full"));
+        assert_eq!(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            failed.into_response().status
+        );
+    }
+
+    /// It appends a `Date` header when writing into a reused buffer unless one is set
+    #[test]
+    fn write_to() {
+        let mut buffer = BytesMut::new();
+        Response::new(StatusCode::OK, "a").write_to(&mut buffer, false);
+        let written = String::from_utf8(buffer.to_vec()).unwrap();
+        let date = written
+            .lines()
+            .find_map(|line| line.strip_prefix("Date: "))
+            .unwrap();
+        assert!(httpdate::parse_http_date(date).is_ok());
+        assert!(written.starts_with("HTTP/1.1 200 OK\r\nContent-Length: 1\r\nDate: "));
+        assert!(written.ends_with("GMT\r\n\r\na"));
+        buffer.clear();
+        let response = Response::new(StatusCode::OK, "").with_header("Date", "x");
+        response.write_to(&mut buffer, false);
+        assert_eq!(response.to_bytes(), buffer.to_vec());
+    }
+
+    /// It keeps the `Content-Length` of a response to a `HEAD` request but leaves out its body
+    #[test]
+    fn head_only() {
+        let mut buffer = BytesMut::new();
+        let response = Response::new(StatusCode::OK, "abc").with_header("Date", "x");
+        response.write_to(&mut buffer, true);
+        assert_eq!(
+            b"HTTP/1.1 200 OK\r\nContent-Length: 3\r\nDate: x\r\n\r\n".to_vec(),
+            buffer.to_vec()
+        );
+    }
+
+    /// It sends neither a body nor a `Content-Length` with 1xx, 204, and 304 responses
+    #[test]
+    fn bodiless_statuses() {
+        for status in [
+            StatusCode::CONTINUE,
+            StatusCode::NO_CONTENT,
+            StatusCode::NOT_MODIFIED,
+        ] {
+            let response = Response::new(status, "ignored").with_header("ETag", "\"a\"");
+            assert_eq!(
+                format!("HTTP/1.1 {}\r\nETag: \"a\"\r\n\r\n", status).into_bytes(),
+                response.to_bytes()
+            );
+        }
+    }
+
+    /// It refuses header values with line breaks, which would split the response
+    #[test]
+    fn line_breaks() {
+        let mut response = Response::new(StatusCode::FOUND, "")
+            .with_header("Location", "/\r\nSet-Cookie: session=stolen")
+            .with_header("X-Kept", "yes");
+        assert_eq!(None, response.header("Location"));
+        response.headers.append("Location", "/\nSet-Cookie: a=b");
+        let written = String::from_utf8(response.to_bytes().to_vec()).unwrap();
+        assert!(!written.contains("Set-Cookie"));
+        assert!(written.contains("X-Kept: yes\r\n"));
+    }
+}
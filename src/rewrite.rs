@@ -0,0 +1,165 @@
+//! Rewriting request paths with regular expressions before routing, so that legacy URL schemes
+//! reach the handlers of the new ones without code changes.
+
+use crate::uri::Uri;
+use regex::Regex;
+use std::fmt;
+use std::str::FromStr;
+use tokio::io;
+
+/// A rule replacing the paths its pattern matches.
+#[derive(Clone, Debug)]
+pub struct Rewrite {
+    /// The pattern, matched against the still percent-encoded path.
+    pub pattern: Regex,
+    /// The replacement, with `$1` or `${name}` standing for the groups of the pattern. When it
+    /// contains a `?`, the query it sets comes before the original one.
+    pub replacement: String,
+    /// Stops at this rule when it matched, instead of continuing with the next rules on the
+    /// rewritten path.
+    pub last: bool,
+}
+
+impl Rewrite {
+    /// Creates a rule that stops the rewriting once it matched.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`io::ErrorKind::InvalidInput`] for an invalid pattern.
+    pub fn new(pattern: &str, replacement: impl Into<String>) -> io::Result<Rewrite> {
+        let pattern = Regex::new(pattern).map_err(|error| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("invalid rewrite pattern {}: {}", pattern, error),
+            )
+        })?;
+        Ok(Rewrite {
+            pattern,
+            replacement: replacement.into(),
+            last: true,
+        })
+    }
+
+    /// Continues with the next rules once this one matched.
+    pub fn with_continue(mut self) -> Rewrite {
+        self.last = false;
+        self
+    }
+}
+
+impl PartialEq for Rewrite {
+    fn eq(&self, other: &Rewrite) -> bool {
+        self.pattern.as_str() == other.pattern.as_str()
+            && self.replacement == other.replacement
+            && self.last == other.last
+    }
+}
+
+impl Eq for Rewrite {}
+
+impl FromStr for Rewrite {
+    type Err = io::Error;
+
+    /// Parses `<pattern> <replacement>`, optionally followed by `last`, the default, or
+    /// `continue`, e.g. `^/blog/(\d+)$ /posts/$1 last`.
+    fn from_str(rule: &str) -> io::Result<Rewrite> {
+        let mut parts = rule.split_whitespace();
+        let (Some(pattern), Some(replacement)) = (parts.next(), parts.next()) else {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("invalid rewrite rule: {}", rule),
+            ));
+        };
+        let rewrite = Rewrite::new(pattern, replacement)?;
+        match (parts.next(), parts.next()) {
+            (None | Some("last"), None) => Ok(rewrite),
+            (Some("continue"), None) => Ok(rewrite.with_continue()),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("invalid rewrite rule: {}", rule),
+            )),
+        }
+    }
+}
+
+impl fmt::Display for Rewrite {
+    fn fmt(&self, formatter: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let flag = if self.last { "last" } else { "continue" };
+        write!(formatter, "{} {} {}", self.pattern, self.replacement, flag)
+    }
+}
+
+/// Applies rewrite rules to a request target in order.
+///
+/// # Arguments
+///
+/// * `rules`: The rules.
+/// * `target`: The request target.
+///
+/// # Returns
+///
+/// The rewritten target, or `None` when no rule matched.
+///
+/// # Errors
+///
+/// Returns [`io::ErrorKind::InvalidData`] when a replacement produced an invalid target.
+pub fn rewrite(rules: &[Rewrite], target: &Uri) -> io::Result<Option<Uri>> {
+    let mut path = target.path().to_string();
+    let mut query = target.query().map(String::from);
+    let mut matched = false;
+    for rule in rules {
+        if !rule.pattern.is_match(&path) {
+            continue;
+        }
+        matched = true;
+        let replaced = rule.pattern.replace(&path, rule.replacement.as_str());
+        path = match replaced.split_once('?') {
+            Some((replaced, added)) => {
+                query = Some(match query {
+                    Some(query) if !query.is_empty() => format!("{}&{}", added, query),
+                    _ => added.to_string(),
+                });
+                replaced.to_string()
+            }
+            None => replaced.into_owned(),
+        };
+        if rule.last {
+            break;
+        }
+    }
+    if !matched {
+        return Ok(None);
+    }
+    let path_and_query = match query {
+        Some(query) => format!("{}?{}", path, query),
+        None => path,
+    };
+    target.with_path_and_query(&path_and_query).map(Some)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// It applies rules in order, stopping at the last one and keeping the query
+    #[test]
+    fn rewrite() {
+        let rules: Vec<Rewrite> = [
+            r"^/blog/(\d+)$ /posts/$1 continue",
+            r"^/posts/(?P<id>\d+)$ /articles?id=${id}",
+            r"^/articles /never",
+        ]
+        .iter()
+        .map(|rule| rule.parse().unwrap())
+        .collect();
+        let target = |target: &str| target.parse::<Uri>().unwrap();
+        assert_eq!(
+            Some(target("/articles?id=7&x=1")),
+            super::rewrite(&rules, &target("/blog/7?x=1")).unwrap()
+        );
+        assert_eq!(None, super::rewrite(&rules, &target("/blog/x")).unwrap());
+        let invalid = [Rewrite::new("^/a$", "/a b").unwrap()];
+        assert!(super::rewrite(&invalid, &target("/a")).is_err());
+        assert!("^/a /b sometimes".parse::<Rewrite>().is_err());
+    }
+}
@@ -0,0 +1,995 @@
+use crate::method::Method;
+use crate::request::Request;
+use crate::response::Response;
+use crate::status::StatusCode;
+use async_trait::async_trait;
+use std::any::Any;
+use std::fmt;
+use std::future::Future;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io;
+
+/// Answers requests routed to it by a [`Router`].
+#[async_trait]
+pub trait Handler: Send + Sync {
+    /// Produces the response to a request.
+    ///
+    /// # Arguments
+    ///
+    /// * `request`: The routed request.
+    ///
+    /// # Returns
+    ///
+    /// The response to send.
+    ///
+    /// # Errors
+    ///
+    /// Propagates IO errors, which end the connection without a response.
+    async fn call(&self, request: Request) -> io::Result<Response>;
+}
+
+/// Implementing the [`Handler`] trait for async functions and closures taking a [`Request`].
+#[async_trait]
+impl<F, Fut> Handler for F
+where
+    F: Fn(Request) -> Fut + Send + Sync,
+    Fut: Future<Output = io::Result<Response>> + Send,
+{
+    async fn call(&self, request: Request) -> io::Result<Response> {
+        self(request).await
+    }
+}
+
+/// One registered method and path.
+#[derive(Clone)]
+struct Route {
+    method: Method,
+    /// The pattern as registered, e.g. `/users/:id`.
+    path: String,
+    segments: Vec<Segment>,
+    handler: Arc<dyn Handler>,
+    /// The type of the registered handler, see [`RouteInfo::handler`].
+    handler_name: &'static str,
+    /// The middleware wrapped around the handler, from the innermost.
+    middleware: Vec<&'static str>,
+    limits: RouteLimits,
+}
+
+/// Limits of a route overriding the global ones of [`crate::config::Config`], see
+/// [`Router::with_limits`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct RouteLimits {
+    /// The largest body in bytes, or `None` for [`crate::config::Config::max_body_size`].
+    pub max_body_size: Option<u64>,
+    /// How long reading the body and handling the request may take, or `None` for
+    /// [`crate::config::Config::request_timeout`].
+    pub timeout: Option<Duration>,
+}
+
+/// The description of a registered route, see [`Router::routes`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RouteInfo {
+    /// The request method.
+    pub method: Method,
+    /// The path pattern, e.g. `/users/:id`.
+    pub pattern: String,
+    /// The type name of the handler as registered, e.g. `app::list_users`, or a
+    /// `{{closure}}` path for closures.
+    pub handler: &'static str,
+    /// The middleware wrapped around the handler, from the innermost to the outermost, e.g.
+    /// `cache`.
+    pub middleware: Vec<&'static str>,
+}
+
+impl fmt::Display for RouteInfo {
+    /// Writes `GET /users app::list_users`, followed by ` [cache, ...]` with middleware.
+    fn fmt(&self, formatter: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            formatter,
+            "{} {} {}",
+            self.method, self.pattern, self.handler
+        )?;
+        if !self.middleware.is_empty() {
+            write!(formatter, " [{}]", self.middleware.join(", "))?;
+        }
+        Ok(())
+    }
+}
+
+/// How a router treats a path that differs from a registered one only in a trailing slash,
+/// e.g. `/users/` when `/users` is registered. The root path `/` is never changed.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum TrailingSlash {
+    /// Only the registered form matches.
+    #[default]
+    Strict,
+    /// Both forms reach the same handler.
+    Equivalent,
+    /// The other form is redirected to the registered one with this status, usually
+    /// [`StatusCode::MOVED_PERMANENTLY`] or [`StatusCode::PERMANENT_REDIRECT`], which keeps
+    /// the method.
+    Redirect(StatusCode),
+}
+
+/// Maps request methods and path patterns to handlers registered by the application.
+#[derive(Clone, Default)]
+pub struct Router {
+    routes: Vec<Route>,
+    trailing_slash: TrailingSlash,
+    case_insensitive: bool,
+}
+
+impl fmt::Debug for Router {
+    fn fmt(&self, formatter: &mut fmt::Formatter<'_>) -> fmt::Result {
+        formatter
+            .debug_list()
+            .entries(
+                self.routes
+                    .iter()
+                    .map(|route| format!("{} {}", route.method, route.path)),
+            )
+            .finish()
+    }
+}
+
+impl Router {
+    /// Creates a router without routes.
+    pub fn new() -> Router {
+        Router::default()
+    }
+
+    /// Registers a handler.
+    ///
+    /// A pattern is a path whose segments are either matched exactly, `:name` to match any one
+    /// non-empty segment, or `*name` as the last segment to match the rest of the path, e.g.
+    /// `/users/:id` or `/files/*path`. When several patterns match a request, the one whose
+    /// segments are more specific from the left wins: an exact segment over a parameter over a
+    /// wildcard, so that `/users/me` wins over `/users/:id`, and `/files/a/*rest` over
+    /// `/files/*rest`.
+    ///
+    /// # Arguments
+    ///
+    /// * `method`: The request method, e.g. [`Method::Get`].
+    /// * `pattern`: The request path without query, e.g. `/users/:id`.
+    /// * `handler`: The handler answering matching requests.
+    ///
+    /// # Returns
+    ///
+    /// The router including the new route.
+    ///
+    /// # Panics
+    ///
+    /// Panics with the reason when the pattern is invalid or conflicts with a route registered
+    /// earlier, see [`Router::try_route`].
+    pub fn route<H: Handler + 'static>(self, method: Method, pattern: &str, handler: H) -> Router {
+        self.try_route(method, pattern, handler)
+            .unwrap_or_else(|error| panic!("{}", error))
+    }
+
+    /// Registers a handler like [`Router::route`], reporting an invalid or conflicting pattern
+    /// as an error.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`io::ErrorKind::InvalidInput`] for a pattern not starting with `/`, with an
+    /// unnamed parameter or wildcard, or with a wildcard before its last segment, and
+    /// [`io::ErrorKind::AlreadyExists`] when a route for the same method matches exactly the
+    /// same paths, e.g. `/users/:id` and `/users/:name`, since it would never be reached.
+    pub fn try_route<H: Handler + 'static>(
+        self,
+        method: Method,
+        pattern: &str,
+        handler: H,
+    ) -> io::Result<Router> {
+        self.push(Route {
+            method,
+            path: pattern.to_string(),
+            segments: parse_pattern(pattern)?,
+            handler: Arc::new(handler),
+            handler_name: std::any::type_name::<H>(),
+            middleware: Vec::new(),
+            limits: RouteLimits::default(),
+        })
+    }
+
+    /// Mounts the routes of another router below a prefix, e.g. the routes of an API module at
+    /// `/api/v1`, so that `/users/:id` registered on it answers `/api/v1/users/7`, and its `/`
+    /// answers `/api/v1` itself. Its handlers see the path with the prefix stripped and keep the
+    /// middleware and state applied to it, which thereby only apply to its scope, while its
+    /// trailing slash and case settings give way to the ones of this router.
+    ///
+    /// # Arguments
+    ///
+    /// * `prefix`: The path the routes are mounted at, which may contain parameters but no
+    ///   wildcard, e.g. `/orgs/:org`.
+    /// * `router`: The routes to mount.
+    ///
+    /// # Returns
+    ///
+    /// The router including the mounted routes.
+    ///
+    /// # Panics
+    ///
+    /// Panics with the reason when a mounted route would be invalid or conflict with a route
+    /// registered earlier, see [`Router::try_nest`].
+    pub fn nest(self, prefix: &str, router: Router) -> Router {
+        self.try_nest(prefix, router)
+            .unwrap_or_else(|error| panic!("{}", error))
+    }
+
+    /// Mounts the routes of another router like [`Router::nest`], reporting an invalid prefix
+    /// or conflicting route as an error.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`io::ErrorKind::InvalidInput`] for a prefix that is not a pattern or contains a
+    /// wildcard, and the errors of [`Router::try_route`] for the mounted routes.
+    pub fn try_nest(mut self, prefix: &str, router: Router) -> io::Result<Router> {
+        let prefix = prefix.trim_end_matches('/');
+        let depth = if prefix.is_empty() {
+            0
+        } else {
+            let segments = parse_pattern(prefix)?;
+            if segments
+                .iter()
+                .any(|segment| matches!(segment, Segment::Wildcard(_)))
+            {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("invalid prefix {}: it contains a wildcard", prefix),
+                ));
+            }
+            segments.len()
+        };
+        for route in router.routes {
+            let pattern = match route.path.as_str() {
+                "/" if !prefix.is_empty() => prefix.to_string(),
+                path => format!("{}{}", prefix, path),
+            };
+            let handler: Arc<dyn Handler> = match depth {
+                0 => route.handler,
+                depth => Arc::new(Nested {
+                    depth,
+                    handler: route.handler,
+                }),
+            };
+            self = self.push(Route {
+                method: route.method,
+                segments: parse_pattern(&pattern)?,
+                path: pattern,
+                handler,
+                handler_name: route.handler_name,
+                middleware: route.middleware,
+                limits: route.limits,
+            })?;
+        }
+        Ok(self)
+    }
+
+    /// Adds a route unless it conflicts with one registered earlier.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`io::ErrorKind::AlreadyExists`] as described for [`Router::try_route`].
+    fn push(mut self, route: Route) -> io::Result<Router> {
+        if let Some(earlier) = self.routes.iter().find(|earlier| {
+            earlier.method == route.method && same_shape(&earlier.segments, &route.segments)
+        }) {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!(
+                    "route {} {} conflicts with {} {} registered earlier",
+                    route.method, route.path, earlier.method, earlier.path
+                ),
+            ));
+        }
+        self.routes.push(route);
+        Ok(self)
+    }
+
+    /// Wraps every handler registered so far in middleware. Handlers registered afterwards are
+    /// not wrapped, so middleware only applies to the routes above it, or to the scope of a
+    /// router mounted with [`Router::nest`].
+    ///
+    /// # Arguments
+    ///
+    /// * `name`: The name of the middleware listed in [`RouteInfo::middleware`].
+    /// * `wrap`: Builds the new handler from the old one.
+    ///
+    /// # Returns
+    ///
+    /// The router with wrapped handlers.
+    pub fn with_middleware(
+        self,
+        name: &'static str,
+        wrap: impl Fn(Arc<dyn Handler>) -> Arc<dyn Handler>,
+    ) -> Router {
+        self.map_handlers(name, wrap)
+    }
+
+    /// Overrides the global body size limit and timeout for every route registered so far,
+    /// e.g. to accept large uploads slowly on one route while keeping tight limits everywhere
+    /// else. Routes keep the limits set by an earlier call, so that the limits of a few routes
+    /// can be set right after them and those of the rest at the end.
+    ///
+    /// # Arguments
+    ///
+    /// * `limits`: The limits, of which only the ones set apply.
+    ///
+    /// # Returns
+    ///
+    /// The router with limited routes.
+    pub fn with_limits(mut self, limits: RouteLimits) -> Router {
+        for route in &mut self.routes {
+            route.limits = RouteLimits {
+                max_body_size: route.limits.max_body_size.or(limits.max_body_size),
+                timeout: route.limits.timeout.or(limits.timeout),
+            };
+        }
+        self
+    }
+
+    /// Shares a value with every handler registered so far, which finds it in
+    /// [`Request::state`] by its type. A value shared by a router mounted with
+    /// [`Router::nest`] hides a value of the same type shared by the outer router.
+    ///
+    /// # Arguments
+    ///
+    /// * `state`: The value, e.g. a database pool or the settings of a module.
+    ///
+    /// # Returns
+    ///
+    /// The router with handlers seeing the value.
+    pub fn with_state<T: Send + Sync + 'static>(self, state: T) -> Router {
+        let state: Arc<dyn Any + Send + Sync> = Arc::new(state);
+        self.map_handlers("state", |handler| {
+            Arc::new(Stateful {
+                state: Arc::clone(&state),
+                handler,
+            })
+        })
+    }
+
+    /// Sets how paths differing from registered ones only in a trailing slash are treated,
+    /// [`TrailingSlash::Strict`] by default.
+    pub fn with_trailing_slash(mut self, trailing_slash: TrailingSlash) -> Router {
+        self.trailing_slash = trailing_slash;
+        self
+    }
+
+    /// Matches paths whatever the case of their ASCII letters when `true`, e.g. `/Users` for a
+    /// route registered as `/users`, for clients of content migrated from case-insensitive
+    /// servers. Files below the document root have their own setting, see
+    /// [`crate::config::Config::case_insensitive_paths`].
+    pub fn with_case_insensitive(mut self, case_insensitive: bool) -> Router {
+        self.case_insensitive = case_insensitive;
+        self
+    }
+
+    /// Replaces every registered handler, e.g. to wrap it in middleware.
+    ///
+    /// # Arguments
+    ///
+    /// * `middleware`: The name of the middleware listed in [`RouteInfo::middleware`].
+    /// * `wrap`: Builds the new handler from the old one.
+    ///
+    /// # Returns
+    ///
+    /// The router with the new handlers.
+    pub(crate) fn map_handlers(
+        mut self,
+        middleware: &'static str,
+        wrap: impl Fn(Arc<dyn Handler>) -> Arc<dyn Handler>,
+    ) -> Router {
+        for route in &mut self.routes {
+            route.handler = wrap(Arc::clone(&route.handler));
+            route.middleware.push(middleware);
+        }
+        self
+    }
+
+    /// Finds the handler registered for a method and path.
+    ///
+    /// # Arguments
+    ///
+    /// * `method`: The request method.
+    /// * `path`: The request path without query.
+    ///
+    /// # Returns
+    ///
+    /// The handler, or `None` when nothing is registered for both.
+    pub fn find(&self, method: &Method, path: &str) -> Option<Arc<dyn Handler>> {
+        self.lookup(method, path).map(|found| found.handler)
+    }
+
+    /// Finds the route registered for a method and path, see [`Router::route`] for which one
+    /// wins when several match.
+    ///
+    /// # Arguments
+    ///
+    /// * `method`: The request method.
+    /// * `path`: The request path without query.
+    ///
+    /// # Returns
+    ///
+    /// The handler with its pattern and the segments its parameters matched, or `None` when
+    /// nothing is registered for both.
+    pub fn lookup(&self, method: &Method, path: &str) -> Option<RouteMatch<'_>> {
+        let best = |path: &str| {
+            self.routes
+                .iter()
+                .filter(|route| &route.method == method)
+                .filter_map(|route| Some((route, self.capture(&route.segments, path)?)))
+                .min_by_key(|(route, _)| precedence(&route.segments))
+        };
+        if let Some((route, params)) = best(path) {
+            return Some(RouteMatch {
+                handler: Arc::clone(&route.handler),
+                pattern: &route.path,
+                params,
+                limits: route.limits,
+            });
+        }
+        let other = other_slash(path)?;
+        let (route, params) = best(&other)?;
+        let handler: Arc<dyn Handler> = match self.trailing_slash {
+            TrailingSlash::Strict => return None,
+            TrailingSlash::Equivalent => Arc::clone(&route.handler),
+            TrailingSlash::Redirect(status) => Arc::new(SlashRedirect {
+                path: other,
+                status,
+            }),
+        };
+        Some(RouteMatch {
+            handler,
+            pattern: &route.path,
+            params,
+            limits: route.limits,
+        })
+    }
+
+    /// Describes every registered route, e.g. for the `routes` subcommand and the admin API.
+    ///
+    /// # Returns
+    ///
+    /// The routes in registration order.
+    pub fn routes(&self) -> Vec<RouteInfo> {
+        self.routes
+            .iter()
+            .map(|route| RouteInfo {
+                method: route.method.clone(),
+                pattern: route.path.clone(),
+                handler: route.handler_name,
+                middleware: route.middleware.clone(),
+            })
+            .collect()
+    }
+
+    /// Lists the methods registered for a path.
+    ///
+    /// # Arguments
+    ///
+    /// * `path`: The request path without query.
+    ///
+    /// # Returns
+    ///
+    /// The methods of the routes matching the path in registration order, empty when none
+    /// does.
+    pub fn allowed_methods(&self, path: &str) -> Vec<Method> {
+        let registered = |path: &str| -> Vec<Method> {
+            let mut methods: Vec<Method> = Vec::new();
+            for route in &self.routes {
+                if self.capture(&route.segments, path).is_some() && !methods.contains(&route.method)
+                {
+                    methods.push(route.method.clone());
+                }
+            }
+            methods
+        };
+        let methods = registered(path);
+        match other_slash(path) {
+            Some(other) if methods.is_empty() && self.trailing_slash != TrailingSlash::Strict => {
+                registered(&other)
+            }
+            _ => methods,
+        }
+    }
+
+    /// Matches a path against the segments of a pattern, comparing exact segments as
+    /// [`Router::with_case_insensitive`] says.
+    ///
+    /// # Returns
+    ///
+    /// The names of the parameters with the segments they matched, or `None` when the path
+    /// does not match.
+    fn capture(&self, segments: &[Segment], path: &str) -> Option<Vec<(String, String)>> {
+        let mut parts = path.strip_prefix('/')?.split('/');
+        let mut params = Vec::new();
+        for segment in segments {
+            match segment {
+                Segment::Wildcard(name) => {
+                    let rest: Vec<&str> = parts.by_ref().collect();
+                    params.push((name.clone(), rest.join("/")));
+                    return Some(params);
+                }
+                Segment::Param(name) => {
+                    let part = parts.next().filter(|part| !part.is_empty())?;
+                    params.push((name.clone(), part.to_string()));
+                }
+                Segment::Static(expected) => {
+                    let part = parts.next()?;
+                    let same = if self.case_insensitive {
+                        expected.eq_ignore_ascii_case(part)
+                    } else {
+                        expected == part
+                    };
+                    if !same {
+                        return None;
+                    }
+                }
+            }
+        }
+        parts.next().is_none().then_some(params)
+    }
+
+    /// Calls the handler registered for the request.
+    ///
+    /// # Arguments
+    ///
+    /// * `request`: The incoming request.
+    ///
+    /// # Returns
+    ///
+    /// The handler's response, or `None` when no route matches.
+    ///
+    /// # Errors
+    ///
+    /// Propagates IO errors from the handler.
+    pub async fn dispatch(&self, request: &Request) -> io::Result<Option<Response>> {
+        match self.lookup(&request.method, request.path()) {
+            Some(found) => found.handler.call(found.routed(request)).await.map(Some),
+            None => Ok(None),
+        }
+    }
+}
+
+/// Builds a [`Router`] from a list of routes, each a method token, a pattern, and a handler,
+/// e.g. `routes! { GET "/users/:id" => get_user, POST "/users" => create_user }`. Starting
+/// with a router and a semicolon, e.g. `routes! { router; DELETE "/users/:id" => delete_user }`,
+/// adds the routes to it instead of a new one. Any method token is accepted, e.g. `PROPFIND`.
+///
+/// # Panics
+///
+/// Panics like [`Router::route`] when a pattern is invalid or conflicts with an earlier route.
+#[macro_export]
+macro_rules! routes {
+    ($($method:ident $pattern:literal => $handler:expr),* $(,)?) => {
+        $crate::routes! { $crate::router::Router::new(); $($method $pattern => $handler),* }
+    };
+    ($router:expr; $($method:ident $pattern:literal => $handler:expr),* $(,)?) => {{
+        let router: $crate::router::Router = $router;
+        $(
+            let method: $crate::method::Method = stringify!($method)
+                .parse()
+                .expect("a method token");
+            let router = router.route(method, $pattern, $handler);
+        )*
+        router
+    }};
+}
+
+/// Passes requests to the handler of a mounted router with the prefix stripped from the path.
+struct Nested {
+    /// The number of segments of the prefix.
+    depth: usize,
+    handler: Arc<dyn Handler>,
+}
+
+#[async_trait]
+impl Handler for Nested {
+    async fn call(&self, mut request: Request) -> io::Result<Response> {
+        let rest: Vec<&str> = request.path().split('/').skip(1 + self.depth).collect();
+        let path_and_query = match request.target.query() {
+            Some(query) => format!("/{}?{}", rest.join("/"), query),
+            None => format!("/{}", rest.join("/")),
+        };
+        request.target = request.target.with_path_and_query(&path_and_query)?;
+        self.handler.call(request).await
+    }
+}
+
+/// Adds a value shared by a router scope to the state of requests, see [`Router::with_state`].
+struct Stateful {
+    state: Arc<dyn Any + Send + Sync>,
+    handler: Arc<dyn Handler>,
+}
+
+#[async_trait]
+impl Handler for Stateful {
+    async fn call(&self, mut request: Request) -> io::Result<Response> {
+        request.state.insert(Arc::clone(&self.state));
+        self.handler.call(request).await
+    }
+}
+
+/// The pattern of the route a request matched, e.g. `/users/:id`, attached to the
+/// [`Request::extensions`] of routed requests.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MatchedPath(pub String);
+
+/// A route found for a request, see [`Router::lookup`].
+pub struct RouteMatch<'a> {
+    /// The handler answering the request.
+    pub handler: Arc<dyn Handler>,
+    /// The pattern of the route, e.g. `/users/:id`, which bounds the labels of metrics.
+    pub pattern: &'a str,
+    /// The names of the parameters and wildcards of the pattern with the still
+    /// percent-encoded segments they matched, in order, e.g. `("id", "7")`.
+    pub params: Vec<(String, String)>,
+    /// The limits overriding the global ones for the route.
+    pub limits: RouteLimits,
+}
+
+impl RouteMatch<'_> {
+    /// Prepares a request for the handler of the route.
+    ///
+    /// # Arguments
+    ///
+    /// * `request`: The request the route was found for.
+    ///
+    /// # Returns
+    ///
+    /// A copy of the request with the [`Request::params`] of the route and its
+    /// [`MatchedPath`] extension.
+    pub fn routed(&self, request: &Request) -> Request {
+        let mut routed = Request {
+            params: self.params.clone(),
+            ..request.clone()
+        };
+        routed
+            .extensions
+            .insert(MatchedPath(self.pattern.to_string()));
+        routed
+    }
+}
+
+/// One segment of a route pattern.
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Segment {
+    /// Matches this segment exactly.
+    Static(String),
+    /// `:name`, matching any one non-empty segment.
+    Param(String),
+    /// `*name`, matching the rest of the path.
+    Wildcard(String),
+}
+
+/// Splits a route pattern into its segments.
+///
+/// # Errors
+///
+/// Returns [`io::ErrorKind::InvalidInput`] as described for [`Router::try_route`].
+fn parse_pattern(pattern: &str) -> io::Result<Vec<Segment>> {
+    let invalid = |reason: &str| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("invalid route {}: {}", pattern, reason),
+        )
+    };
+    let rest = pattern
+        .strip_prefix('/')
+        .ok_or_else(|| invalid("it does not start with /"))?;
+    let parts: Vec<&str> = rest.split('/').collect();
+    let mut segments = Vec::new();
+    for (index, part) in parts.iter().enumerate() {
+        let segment = if let Some(name) = part.strip_prefix(':') {
+            Segment::Param(name.to_string())
+        } else if let Some(name) = part.strip_prefix('*') {
+            if index + 1 != parts.len() {
+                return Err(invalid("a wildcard must be the last segment"));
+            }
+            Segment::Wildcard(name.to_string())
+        } else {
+            Segment::Static(part.to_string())
+        };
+        if matches!(&segment, Segment::Param(name) | Segment::Wildcard(name) if name.is_empty()) {
+            return Err(invalid("parameters and wildcards need a name"));
+        }
+        segments.push(segment);
+    }
+    Ok(segments)
+}
+
+/// Whether two patterns match exactly the same paths, whatever their parameters are named.
+fn same_shape(a: &[Segment], b: &[Segment]) -> bool {
+    a.len() == b.len()
+        && a.iter().zip(b).all(|pair| match pair {
+            (Segment::Static(a), Segment::Static(b)) => a == b,
+            (Segment::Param(_), Segment::Param(_)) => true,
+            (Segment::Wildcard(_), Segment::Wildcard(_)) => true,
+            _ => false,
+        })
+}
+
+/// Orders patterns matching the same path, the lowest winning: segment by segment from the
+/// left, an exact segment before a parameter before a wildcard.
+fn precedence(segments: &[Segment]) -> Vec<u8> {
+    segments
+        .iter()
+        .map(|segment| match segment {
+            Segment::Static(_) => 0,
+            Segment::Param(_) => 1,
+            Segment::Wildcard(_) => 2,
+        })
+        .collect()
+}
+
+/// The path with a trailing slash added or removed, or `None` for the root path.
+fn other_slash(path: &str) -> Option<String> {
+    if path == "/" || path.is_empty() {
+        None
+    } else if let Some(trimmed) = path.strip_suffix('/') {
+        Some(trimmed.to_string())
+    } else {
+        Some(format!("{}/", path))
+    }
+}
+
+/// Redirects a request to the registered form of its path, see [`TrailingSlash::Redirect`].
+struct SlashRedirect {
+    path: String,
+    status: StatusCode,
+}
+
+#[async_trait]
+impl Handler for SlashRedirect {
+    async fn call(&self, request: Request) -> io::Result<Response> {
+        let location = match request.target.query() {
+            Some(query) => format!("{}?{}", self.path, query),
+            None => self.path.clone(),
+        };
+        Ok(Response::new(self.status, "").with_header("Location", location))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(method: &str, target: &str) -> Request {
+        Request {
+            method: method.parse().unwrap(),
+            target: target.parse().unwrap(),
+            ..Request::default()
+        }
+    }
+
+    async fn hello(request: Request) -> io::Result<Response> {
+        Ok(Response::new(
+            StatusCode::OK,
+            format!("hello {}", request.target),
+        ))
+    }
+
+    /// It dispatches by method and path, ignoring the query string
+    #[tokio::test]
+    async fn dispatch() {
+        let router = Router::new().route(Method::Get, "/hello", hello).route(
+            Method::Post,
+            "/hello",
+            |_| async { Ok(Response::new(StatusCode::CREATED, "")) },
+        );
+        let response = router
+            .dispatch(&request("GET", "/hello?x=1"))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(b"hello /hello?x=1".to_vec(), response.body);
+        let response = router
+            .dispatch(&request("POST", "/hello"))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(StatusCode::CREATED, response.status);
+        assert_eq!(
+            None,
+            router.dispatch(&request("PUT", "/hello")).await.unwrap()
+        );
+        assert_eq!(
+            None,
+            router.dispatch(&request("GET", "/other")).await.unwrap()
+        );
+    }
+
+    /// It lists registered methods per path and rejects conflicting registrations
+    #[test]
+    fn allowed_methods() {
+        let router = Router::new()
+            .route(Method::Get, "/a", hello)
+            .route(Method::Delete, "/a", hello)
+            .route(Method::Post, "/b", hello);
+        assert_eq!(
+            vec![Method::Get, Method::Delete],
+            router.allowed_methods("/a")
+        );
+        assert!(router.allowed_methods("/c").is_empty());
+        assert_eq!(
+            "[\"GET /a\", \"DELETE /a\", \"POST /b\"]",
+            format!("{:?}", router)
+        );
+        let error = router.try_route(Method::Get, "/a", hello).err().unwrap();
+        assert_eq!(io::ErrorKind::AlreadyExists, error.kind());
+        assert_eq!(
+            "route GET /a conflicts with GET /a registered earlier",
+            error.to_string()
+        );
+    }
+
+    /// It prefers exact segments over parameters over wildcards and rejects ambiguous patterns
+    #[test]
+    fn precedence() {
+        let router = Router::new()
+            .route(Method::Get, "/files/*path", hello)
+            .route(Method::Get, "/users/:id", hello)
+            .route(Method::Get, "/users/me", hello)
+            .route(Method::Get, "/files/a/*rest", hello);
+        let found = |path: &str| {
+            let found = router.lookup(&Method::Get, path)?;
+            Some((found.pattern.to_string(), found.params))
+        };
+        let param = |name: &str, value: &str| vec![(name.to_string(), value.to_string())];
+        assert_eq!(Some(("/users/me".to_string(), vec![])), found("/users/me"));
+        assert_eq!(
+            Some(("/users/:id".to_string(), param("id", "7"))),
+            found("/users/7")
+        );
+        assert_eq!(None, found("/users/"));
+        assert_eq!(None, found("/users/7/posts"));
+        assert_eq!(
+            Some(("/files/a/*rest".to_string(), param("rest", "b/c"))),
+            found("/files/a/b/c")
+        );
+        assert_eq!(
+            Some(("/files/*path".to_string(), param("path", "b/c"))),
+            found("/files/b/c")
+        );
+        let conflict = router.clone().try_route(Method::Get, "/users/:name", hello);
+        assert_eq!(io::ErrorKind::AlreadyExists, conflict.err().unwrap().kind());
+        assert!(router
+            .clone()
+            .try_route(Method::Post, "/users/:name", hello)
+            .is_ok());
+        for invalid in ["users", "/users/:", "/files/*path/x"] {
+            let error = router
+                .clone()
+                .try_route(Method::Get, invalid, hello)
+                .err()
+                .unwrap();
+            assert_eq!(io::ErrorKind::InvalidInput, error.kind());
+        }
+    }
+
+    /// It matches the other trailing slash form as the policy says
+    #[tokio::test]
+    async fn trailing_slash() {
+        let router = Router::new()
+            .route(Method::Get, "/a", hello)
+            .route(Method::Get, "/b/", hello);
+        assert!(router.find(&Method::Get, "/a/").is_none());
+        let router = router.with_trailing_slash(TrailingSlash::Equivalent);
+        let response = router
+            .dispatch(&request("GET", "/b"))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(b"hello /b".to_vec(), response.body);
+        assert_eq!(vec![Method::Get], router.allowed_methods("/a/"));
+        let router =
+            router.with_trailing_slash(TrailingSlash::Redirect(StatusCode::PERMANENT_REDIRECT));
+        let response = router
+            .dispatch(&request("GET", "/a/?x=1"))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(StatusCode::PERMANENT_REDIRECT, response.status);
+        assert_eq!(Some("/a?x=1"), response.header("Location"));
+    }
+
+    /// It matches paths whatever their case only when asked to
+    #[test]
+    fn case_insensitive() {
+        let router = Router::new().route(Method::Get, "/users", hello);
+        assert!(router.find(&Method::Get, "/Users").is_none());
+        let router = router.with_case_insensitive(true);
+        assert!(router.find(&Method::Get, "/USERS").is_some());
+        assert_eq!(vec![Method::Get], router.allowed_methods("/Users"));
+    }
+
+    /// It mounts routers below a prefix with their own middleware and state
+    #[tokio::test]
+    async fn nest() {
+        async fn whoami(request: Request) -> io::Result<Response> {
+            let name = request.state.get::<&str>().copied().unwrap_or("nobody");
+            Ok(Response::new(
+                StatusCode::OK,
+                format!("{} {}", name, request.target),
+            ))
+        }
+        let users = Router::new()
+            .route(Method::Get, "/", whoami)
+            .route(Method::Get, "/:id", whoami)
+            .with_state("users")
+            .with_middleware("stamp", |handler| {
+                Arc::new(move |request: Request| {
+                    let handler = Arc::clone(&handler);
+                    async move {
+                        let response = handler.call(request).await?;
+                        Ok(response.with_header("X-Scope", "users"))
+                    }
+                })
+            });
+        let router = Router::new()
+            .route(Method::Get, "/", whoami)
+            .nest("/orgs/:org/users", users)
+            .with_state("root");
+        let call = |target: &str| {
+            let router = router.clone();
+            let request = request("GET", target);
+            async move { router.dispatch(&request).await.unwrap().unwrap() }
+        };
+        let response = call("/orgs/a/users/7?x=1").await;
+        assert_eq!(b"users /7?x=1".to_vec(), response.body);
+        assert_eq!(Some("users"), response.header("X-Scope"));
+        assert_eq!(b"users /".to_vec(), call("/orgs/a/users").await.body);
+        let response = call("/").await;
+        assert_eq!(b"root /".to_vec(), response.body);
+        assert_eq!(None, response.header("X-Scope"));
+        let route = &router.routes()[2];
+        assert_eq!("/orgs/:org/users/:id", route.pattern);
+        assert_eq!(vec!["state", "stamp", "state"], route.middleware);
+        let conflict = router
+            .clone()
+            .try_nest("/", Router::new().route(Method::Get, "/", whoami));
+        assert_eq!(io::ErrorKind::AlreadyExists, conflict.err().unwrap().kind());
+        assert!(router.try_nest("/*rest", Router::new()).is_err());
+    }
+
+    /// It builds routers from lists of methods, patterns, and handlers
+    #[test]
+    fn routes_macro() {
+        let router = crate::routes! {
+            GET "/users/:id" => hello,
+            POST "/users" => |_| async { Ok(Response::new(StatusCode::CREATED, "")) },
+        };
+        let router = crate::routes! { router; PROPFIND "/users" => hello };
+        assert_eq!(
+            "[\"GET /users/:id\", \"POST /users\", \"PROPFIND /users\"]",
+            format!("{:?}", router)
+        );
+        assert_eq!("[]", format!("{:?}", crate::routes! {}));
+    }
+
+    /// It describes routes with the name of their handler and middleware
+    #[test]
+    fn routes() {
+        let router = Router::new()
+            .route(Method::Get, "/a", hello)
+            .map_handlers("outer", |handler| handler)
+            .route(Method::Post, "/b", hello);
+        let routes = router.routes();
+        assert_eq!(
+            "GET /a web_server_tokio::router::tests::hello [outer]",
+            routes[0].to_string()
+        );
+        assert_eq!(
+            RouteInfo {
+                method: Method::Post,
+                pattern: "/b".to_string(),
+                handler: "web_server_tokio::router::tests::hello",
+                middleware: Vec::new(),
+            },
+            routes[1]
+        );
+    }
+}
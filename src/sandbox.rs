@@ -0,0 +1,342 @@
+//! Confining the file access of the binary once it started, limiting what a bug in path
+//! handling, e.g. a traversal past the document root, can reach.
+
+use crate::config::Config;
+use crate::log;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use tokio::io;
+
+/// Set once [`Sandbox::Chroot`] changed the root directory of the process.
+static CHROOTED: AtomicBool = AtomicBool::new(false);
+
+/// System files the server reads without being configured to, e.g. to resolve the host names
+/// of upstreams, granted by [`Sandbox::Landlock`] when they exist.
+const SYSTEM_READ: &[&str] = &[
+    "/etc/resolv.conf",
+    "/etc/hosts",
+    "/etc/nsswitch.conf",
+    "/etc/host.conf",
+    "/etc/gai.conf",
+    "/etc/services",
+    "/dev/urandom",
+];
+
+/// System files the server writes without being configured to, granted by
+/// [`Sandbox::Landlock`] when they exist.
+const SYSTEM_WRITE: &[&str] = &["/dev/null"];
+
+/// A restriction of the files the process can reach, applied once by [`Sandbox::apply`] and
+/// never lifted.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Sandbox {
+    /// Changes the root directory of the process to the document root, so no other file
+    /// exists for it. Needs root, and everything read after startup, such as CGI scripts and
+    /// their interpreters, certificates reloaded from disk, the configuration file on reload,
+    /// or rotated logs, must live below the document root.
+    Chroot(PathBuf),
+    /// Denies access to every file outside the listed ones through Landlock on Linux 5.13 and
+    /// later, needing no privileges. Upgrades through `SIGUSR2` need the binary and its
+    /// libraries listed as readable.
+    Landlock {
+        /// Files and directories that may be read and executed, with everything below them.
+        read: Vec<PathBuf>,
+        /// Files and directories that may be read, written, and changed in any other way, with
+        /// everything below them.
+        write: Vec<PathBuf>,
+    },
+}
+
+impl Sandbox {
+    /// Confines the process. Call it before starting any threads, e.g. the runtime, since
+    /// Landlock only confines the calling thread and those it starts, and after looking up
+    /// anything outside the sandbox, e.g. the account of
+    /// [`crate::privileges::Account::lookup`].
+    ///
+    /// # Returns
+    ///
+    /// `Ok(())` once the process is confined.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`io::ErrorKind::Unsupported`] when the platform or kernel lacks the
+    /// mechanism, [`io::ErrorKind::NotFound`] for a listed path that does not exist, and
+    /// captures the errors of the system calls, e.g. when the process may not `chroot`.
+    pub fn apply(&self) -> io::Result<()> {
+        match self {
+            Sandbox::Chroot(root) => {
+                chroot(root)?;
+                CHROOTED.store(true, Ordering::Relaxed);
+                log::info(format_args!("confined to {}", root.display()));
+            }
+            Sandbox::Landlock { read, write } => {
+                landlock::restrict(read, write)?;
+                log::info(format_args!(
+                    "confined by Landlock to {} readable and {} writable paths",
+                    read.len(),
+                    write.len()
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Moves the paths of a configuration into the root directory [`Sandbox::Chroot`] changed
+/// to, so the document root becomes `/`. Returns the configuration unchanged in any other
+/// case, which makes it safe to wrap every loader of the binary with it.
+///
+/// # Arguments
+///
+/// * `config`: The configuration as read from outside the sandbox.
+///
+/// # Returns
+///
+/// The configuration as seen from inside the sandbox.
+pub fn rebase(mut config: Config) -> Config {
+    if !CHROOTED.load(Ordering::Relaxed) {
+        return config;
+    }
+    let root = config.document_root.clone();
+    let inside = |path: &mut PathBuf| {
+        if let Ok(rest) = path.strip_prefix(&root) {
+            *path = Path::new("/").join(rest);
+        }
+    };
+    if let Some(upload) = config.upload.as_mut() {
+        inside(&mut upload.directory);
+    }
+    if let Some(tus) = config.tus.as_mut() {
+        inside(&mut tus.directory);
+    }
+    if let Some(template) = config
+        .markdown
+        .as_mut()
+        .and_then(|markdown| markdown.template.as_mut())
+    {
+        inside(template);
+    }
+    config.document_root = PathBuf::from("/");
+    config
+}
+
+/// Changes the root directory of the process and moves into it.
+#[cfg(unix)]
+fn chroot(root: &Path) -> io::Result<()> {
+    std::os::unix::fs::chroot(root)?;
+    // The working directory would still reach outside otherwise
+    std::env::set_current_dir("/")
+}
+
+#[cfg(not(unix))]
+fn chroot(_root: &Path) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "chroot needs Unix",
+    ))
+}
+
+#[cfg(target_os = "linux")]
+mod landlock {
+    use super::{SYSTEM_READ, SYSTEM_WRITE};
+    use std::fs::OpenOptions;
+    use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+    use std::os::unix::fs::OpenOptionsExt;
+    use std::path::{Path, PathBuf};
+    use tokio::io;
+
+    // The Landlock ABI of `linux/landlock.h`, which the libc crate does not cover
+    const CREATE_RULESET_VERSION: libc::c_uint = 1;
+    const RULE_PATH_BENEATH: libc::c_int = 1;
+    const EXECUTE: u64 = 1 << 0;
+    const WRITE_FILE: u64 = 1 << 1;
+    const READ_FILE: u64 = 1 << 2;
+    const READ_DIR: u64 = 1 << 3;
+    /// Every right of the first ABI, from `EXECUTE` to `MAKE_SYM`.
+    const ABI_1: u64 = (1 << 13) - 1;
+    const REFER: u64 = 1 << 13;
+    const TRUNCATE: u64 = 1 << 14;
+    /// The rights that apply to files rather than directories.
+    const FILE: u64 = EXECUTE | WRITE_FILE | READ_FILE | TRUNCATE;
+
+    #[repr(C)]
+    struct RulesetAttr {
+        handled_access_fs: u64,
+    }
+
+    #[repr(C, packed)]
+    struct PathBeneathAttr {
+        allowed_access: u64,
+        parent_fd: libc::c_int,
+    }
+
+    /// Confines the calling thread and the threads it starts to the paths.
+    pub(super) fn restrict(read: &[PathBuf], write: &[PathBuf]) -> io::Result<()> {
+        // SAFETY: Asking for the version passes no attributes
+        let abi = unsafe {
+            libc::syscall(
+                libc::SYS_landlock_create_ruleset,
+                std::ptr::null::<RulesetAttr>(),
+                0,
+                CREATE_RULESET_VERSION,
+            )
+        };
+        if abi < 1 {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                format!("Landlock is not available: {}", io::Error::last_os_error()),
+            ));
+        }
+        let mut handled = ABI_1;
+        if abi >= 2 {
+            handled |= REFER;
+        }
+        if abi >= 3 {
+            handled |= TRUNCATE;
+        }
+        let attr = RulesetAttr {
+            handled_access_fs: handled,
+        };
+        // SAFETY: The attributes are valid for the call and their size is passed along
+        let ruleset = unsafe {
+            libc::syscall(
+                libc::SYS_landlock_create_ruleset,
+                &attr,
+                std::mem::size_of::<RulesetAttr>(),
+                0,
+            )
+        };
+        if ruleset < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // SAFETY: The descriptor was just created and belongs to nobody else
+        let ruleset = unsafe { OwnedFd::from_raw_fd(ruleset as libc::c_int) };
+        let system = |paths: &'static [&'static str]| {
+            paths
+                .iter()
+                .map(Path::new)
+                .filter(|path| path.exists())
+                .collect::<Vec<_>>()
+        };
+        let read_access = EXECUTE | READ_FILE | READ_DIR;
+        for path in read.iter().map(PathBuf::as_path).chain(system(SYSTEM_READ)) {
+            allow(&ruleset, path, read_access)?;
+        }
+        for path in write
+            .iter()
+            .map(PathBuf::as_path)
+            .chain(system(SYSTEM_WRITE))
+        {
+            allow(&ruleset, path, handled)?;
+        }
+        // SAFETY: These calls only change the restrictions of the calling thread
+        unsafe {
+            if libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) != 0
+                || libc::syscall(libc::SYS_landlock_restrict_self, ruleset.as_raw_fd(), 0) != 0
+            {
+                return Err(io::Error::last_os_error());
+            }
+        }
+        Ok(())
+    }
+
+    /// Grants rights on a path and everything below it.
+    fn allow(ruleset: &OwnedFd, path: &Path, access: u64) -> io::Result<()> {
+        let file = OpenOptions::new()
+            .read(true)
+            .custom_flags(libc::O_PATH | libc::O_CLOEXEC)
+            .open(path)
+            .map_err(|error| {
+                io::Error::new(
+                    error.kind(),
+                    format!("sandboxing {} failed: {}", path.display(), error),
+                )
+            })?;
+        let allowed_access = if file.metadata()?.is_dir() {
+            access
+        } else {
+            access & FILE
+        };
+        let attr = PathBeneathAttr {
+            allowed_access,
+            parent_fd: file.as_raw_fd(),
+        };
+        // SAFETY: Both descriptors are open and the attributes are valid for the call
+        let result = unsafe {
+            libc::syscall(
+                libc::SYS_landlock_add_rule,
+                ruleset.as_raw_fd(),
+                RULE_PATH_BENEATH,
+                &attr,
+                0,
+            )
+        };
+        if result != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+mod landlock {
+    use std::path::PathBuf;
+    use tokio::io;
+
+    pub(super) fn restrict(_read: &[PathBuf], _write: &[PathBuf]) -> io::Result<()> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "Landlock needs Linux",
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    /// It denies a thread confined by Landlock every file outside the granted ones
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn landlock() {
+        let directory = tempfile::tempdir().unwrap();
+        let readable = directory.path().join("readable");
+        let writable = directory.path().join("writable");
+        let hidden = directory.path().join("hidden.txt");
+        fs::create_dir(&readable).unwrap();
+        fs::create_dir(&writable).unwrap();
+        fs::write(readable.join("page.html"), "page").unwrap();
+        fs::write(&hidden, "secret").unwrap();
+        let sandbox = Sandbox::Landlock {
+            read: vec![readable.clone()],
+            write: vec![writable.clone()],
+        };
+        // Landlock confines the calling thread alone, keeping the other tests unaffected
+        std::thread::spawn(move || {
+            match sandbox.apply() {
+                Err(error) if error.kind() == io::ErrorKind::Unsupported => return,
+                result => result.unwrap(),
+            }
+            assert_eq!(
+                "page",
+                fs::read_to_string(readable.join("page.html")).unwrap()
+            );
+            assert!(fs::write(readable.join("new.html"), "new").is_err());
+            fs::write(writable.join("upload"), "upload").unwrap();
+            fs::rename(writable.join("upload"), writable.join("moved")).unwrap();
+            let error = fs::read_to_string(&hidden).unwrap_err();
+            assert_eq!(io::ErrorKind::PermissionDenied, error.kind());
+        })
+        .join()
+        .unwrap();
+
+        let missing = Sandbox::Landlock {
+            read: vec![directory.path().join("missing")],
+            write: Vec::new(),
+        };
+        std::thread::spawn(move || assert!(missing.apply().is_err()))
+            .join()
+            .unwrap();
+    }
+}
@@ -0,0 +1,560 @@
+//! Hooks written in Rhai that inspect and change requests and responses, e.g. to redirect legacy
+//! paths, add headers, or assign clients to an A/B variant without recompiling the server. Every
+//! call runs within a budget of operations and time, so that a runaway script costs its request
+//! a 500 Internal Server Error instead of stalling the worker thread it runs on.
+
+use crate::header::HeaderMap;
+use crate::log;
+use crate::request::Request;
+use crate::response::Response;
+use crate::status::StatusCode;
+use rhai::{Array, CallFnOptions, Dynamic, Engine, EvalAltResult, FuncArgs, Map, Scope, AST};
+use std::cell::Cell;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::io;
+
+thread_local! {
+    /// When the hook running on this thread has to stop, see [`ScriptHooks::with_timeout`].
+    static DEADLINE: Cell<Option<Instant>> = const { Cell::new(None) };
+}
+
+/// The operations a hook may run by default.
+const MAX_OPERATIONS: u64 = 100_000;
+
+/// A response built by `respond` or `redirect`, distinct from the other values a hook may end
+/// with.
+#[derive(Clone)]
+struct Answer(Map);
+
+/// Hooks defined by a Rhai script, see [`crate::config::Config::scripts`].
+///
+/// The script defines `on_request`, `on_response`, or both, which see the request or the
+/// response as `this`:
+///
+/// ```text
+/// fn on_request() {
+///     if this.path.starts_with("/old/") {
+///         return redirect("/new/" + this.path.sub_string(5));
+///     }
+///     this.headers["x-variant"] = if this.client.ends_with("1") { "b" } else { "a" };
+/// }
+///
+/// fn on_response(request) {
+///     this.headers["x-frame-options"] = "DENY";
+/// }
+/// ```
+///
+/// Requests carry `method`, `path`, `query`, `client`, and `headers`, responses `status` and
+/// `headers`. Header names are lowercase and map to a string, or an array of strings for a
+/// repeated header. `on_request` answers right away when it returns `respond(status, body)` or
+/// `redirect(location)`, and otherwise continues with the changed request.
+/// Statements outside the two functions never run.
+#[derive(Clone)]
+pub struct ScriptHooks {
+    engine: Arc<Engine>,
+    ast: Arc<AST>,
+    max_operations: u64,
+    timeout: Duration,
+    on_request: bool,
+    on_response: bool,
+}
+
+impl ScriptHooks {
+    /// Compiles a script whose hooks may each run 100,000 operations within 10 milliseconds.
+    ///
+    /// # Arguments
+    ///
+    /// * `source`: The Rhai source.
+    ///
+    /// # Returns
+    ///
+    /// The hooks of the script.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`io::ErrorKind::InvalidInput`] when the script does not compile or defines
+    /// neither `on_request()` nor `on_response(request)`.
+    pub fn new(source: &str) -> io::Result<ScriptHooks> {
+        let engine = engine(MAX_OPERATIONS);
+        let ast = engine
+            .compile(source)
+            .map_err(|error| invalid_input(format!("invalid script: {}", error)))?;
+        let defines = |name: &str, parameters: usize| {
+            ast.iter_functions()
+                .any(|function| function.name == name && function.params.len() == parameters)
+        };
+        let (on_request, on_response) = (defines("on_request", 0), defines("on_response", 1));
+        if !on_request && !on_response {
+            return Err(invalid_input(
+                "the script defines neither on_request() nor on_response(request)".to_string(),
+            ));
+        }
+        Ok(ScriptHooks {
+            engine: Arc::new(engine),
+            ast: Arc::new(ast),
+            max_operations: MAX_OPERATIONS,
+            timeout: Duration::from_millis(10),
+            on_request,
+            on_response,
+        })
+    }
+
+    /// Sets how many operations, such as expressions and loop iterations, a hook may run.
+    pub fn with_max_operations(mut self, max_operations: u64) -> ScriptHooks {
+        // Zero would lift the limit
+        self.max_operations = max_operations.max(1);
+        self.engine = Arc::new(engine(self.max_operations));
+        self
+    }
+
+    /// Sets how long a hook may run. Hooks block the worker thread they run on meanwhile.
+    pub fn with_timeout(mut self, timeout: Duration) -> ScriptHooks {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Runs `on_request` on a request before it is handled.
+    ///
+    /// # Arguments
+    ///
+    /// * `request`: The request, changed in place by the hook.
+    ///
+    /// # Returns
+    ///
+    /// `None` to handle the request, or the response the hook answered it with, which is 500
+    /// Internal Server Error when the hook failed or ran out of operations or time.
+    pub fn on_request(&self, request: &mut Request) -> Option<Response> {
+        if !self.on_request {
+            return None;
+        }
+        let mut this = Dynamic::from_map(request_map(request));
+        let outcome = self.call("on_request", &mut this, ()).and_then(|returned| {
+            match returned.try_cast::<Answer>() {
+                Some(Answer(answer)) => response_from(answer).map(Some),
+                None => apply_request(this, request).map(|()| None),
+            }
+        });
+        match outcome {
+            Ok(response) => response,
+            Err(error) => Some(failed("on_request", request, &error)),
+        }
+    }
+
+    /// Runs `on_response` on the response to a request before it is sent.
+    ///
+    /// # Arguments
+    ///
+    /// * `request`: The request, as the handlers saw it.
+    /// * `response`: The response.
+    ///
+    /// # Returns
+    ///
+    /// The response as changed by the hook, or 500 Internal Server Error when the hook failed
+    /// or ran out of operations or time.
+    pub fn on_response(&self, request: &Request, mut response: Response) -> Response {
+        if !self.on_response {
+            return response;
+        }
+        let mut this = Dynamic::from_map(response_map(&response));
+        let outcome = self
+            .call(
+                "on_response",
+                &mut this,
+                (Dynamic::from_map(request_map(request)),),
+            )
+            .and_then(|_| apply_response(this, &mut response));
+        match outcome {
+            Ok(()) => response,
+            Err(error) => failed("on_response", request, &error),
+        }
+    }
+
+    /// Calls a hook with `this` bound, stopping it at the deadline.
+    fn call(
+        &self,
+        name: &str,
+        this: &mut Dynamic,
+        arguments: impl FuncArgs,
+    ) -> Result<Dynamic, String> {
+        DEADLINE.with(|deadline| deadline.set(Some(Instant::now() + self.timeout)));
+        let options = CallFnOptions::new().eval_ast(false).bind_this_ptr(this);
+        let result: Result<Dynamic, Box<EvalAltResult>> = self.engine.call_fn_with_options(
+            options,
+            &mut Scope::new(),
+            &self.ast,
+            name,
+            arguments,
+        );
+        DEADLINE.with(|deadline| deadline.set(None));
+        result.map_err(|error| match *error {
+            EvalAltResult::ErrorTerminated(..) => format!("ran longer than {:?}", self.timeout),
+            error => error.to_string(),
+        })
+    }
+}
+
+impl fmt::Debug for ScriptHooks {
+    fn fmt(&self, formatter: &mut fmt::Formatter<'_>) -> fmt::Result {
+        formatter
+            .debug_struct("ScriptHooks")
+            .field("max_operations", &self.max_operations)
+            .field("timeout", &self.timeout)
+            .field("on_request", &self.on_request)
+            .field("on_response", &self.on_response)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Creates an engine bounding the operations, the call depth, and the size of values, with the
+/// `respond` and `redirect` functions for `on_request`.
+fn engine(max_operations: u64) -> Engine {
+    let mut engine = Engine::new();
+    engine
+        .set_max_operations(max_operations)
+        .set_max_call_levels(32)
+        .set_max_string_size(64 * 1024)
+        .set_max_array_size(1024)
+        .set_max_map_size(1024)
+        .on_progress(|_| {
+            DEADLINE
+                .with(Cell::get)
+                .filter(|deadline| Instant::now() >= *deadline)
+                .map(|_| Dynamic::UNIT)
+        })
+        .register_fn("respond", |status: i64, body: &str| {
+            let mut response = Map::new();
+            response.insert("status".into(), Dynamic::from_int(status));
+            response.insert("headers".into(), Dynamic::from_map(Map::new()));
+            response.insert("body".into(), body.into());
+            Answer(response)
+        })
+        .register_fn("redirect", |location: &str| {
+            let mut headers = Map::new();
+            headers.insert("location".into(), location.into());
+            let mut response = Map::new();
+            response.insert("status".into(), Dynamic::from_int(302));
+            response.insert("headers".into(), Dynamic::from_map(headers));
+            response.insert("body".into(), "".into());
+            Answer(response)
+        })
+        .register_type_with_name::<Answer>("Response");
+    engine
+}
+
+fn request_map(request: &Request) -> Map {
+    let mut map = Map::new();
+    map.insert("method".into(), request.method.as_str().into());
+    map.insert("path".into(), request.target.path().into());
+    map.insert(
+        "query".into(),
+        request.target.query().unwrap_or_default().into(),
+    );
+    let client = request.peer.map(|peer| peer.ip().to_string());
+    map.insert("client".into(), client.unwrap_or_default().into());
+    map.insert("headers".into(), headers_map(&request.headers));
+    map
+}
+
+fn response_map(response: &Response) -> Map {
+    let mut map = Map::new();
+    map.insert(
+        "status".into(),
+        Dynamic::from_int(response.status.as_u16().into()),
+    );
+    map.insert("headers".into(), headers_map(&response.headers));
+    map
+}
+
+/// Groups the values of every header under its lowercase name.
+fn grouped(headers: &HeaderMap) -> BTreeMap<String, Vec<String>> {
+    let mut grouped: BTreeMap<String, Vec<String>> = BTreeMap::new();
+    for (name, value) in headers.iter() {
+        grouped
+            .entry(name.to_ascii_lowercase())
+            .or_default()
+            .push(value.to_string());
+    }
+    grouped
+}
+
+fn headers_map(headers: &HeaderMap) -> Dynamic {
+    let map = grouped(headers)
+        .into_iter()
+        .map(|(name, mut values)| {
+            let value = match values.len() {
+                1 => values.remove(0).into(),
+                _ => Dynamic::from_array(values.into_iter().map(Dynamic::from).collect()),
+            };
+            (name.into(), value)
+        })
+        .collect();
+    Dynamic::from_map(map)
+}
+
+/// Writes the headers a hook changed back, keeping the position and spelling of the rest.
+fn apply_headers(map: &Map, headers: &mut HeaderMap) -> Result<(), String> {
+    let changed = map
+        .get("headers")
+        .and_then(|headers| headers.clone().try_cast::<Map>())
+        .ok_or("headers must be a map")?;
+    let before = grouped(headers);
+    for name in before.keys() {
+        if !changed.keys().any(|key| key.eq_ignore_ascii_case(name)) {
+            headers.remove(name);
+        }
+    }
+    for (name, value) in changed {
+        let values: Vec<String> = match value.clone().try_cast::<Array>() {
+            Some(values) => values.into_iter().map(string).collect::<Result<_, _>>()?,
+            None => vec![string(value)?],
+        };
+        let breaks = |text: &str| text.contains(['\r', '\n']);
+        if name.is_empty() || breaks(&name) || values.iter().any(|value| breaks(value)) {
+            return Err(format!("invalid header {:?}", name.as_str()));
+        }
+        if before.get(&name.to_ascii_lowercase()) == Some(&values) {
+            continue;
+        }
+        match values.as_slice() {
+            [value] => headers.insert(name.as_str(), value.as_str()),
+            _ => {
+                headers.remove(&name);
+                for value in values {
+                    headers.append(name.as_str(), value);
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+fn apply_request(this: Dynamic, request: &mut Request) -> Result<(), String> {
+    let map = this.try_cast::<Map>().ok_or("this must stay a map")?;
+    let field = |name: &str| {
+        map.get(name)
+            .cloned()
+            .map_or_else(|| Err(format!("{} is missing", name)), string)
+    };
+    let method = field("method")?;
+    if method != request.method.as_str() {
+        request.method = method
+            .parse()
+            .map_err(|_| format!("invalid method {:?}", method))?;
+    }
+    let (path, query) = (field("path")?, field("query")?);
+    if path != request.target.path() || query != request.target.query().unwrap_or_default() {
+        let target = match query.as_str() {
+            "" => path,
+            query => format!("{}?{}", path, query),
+        };
+        request.target = target
+            .parse()
+            .map_err(|_| format!("invalid target {:?}", target))?;
+    }
+    apply_headers(&map, &mut request.headers)
+}
+
+fn apply_response(this: Dynamic, response: &mut Response) -> Result<(), String> {
+    let map = this.try_cast::<Map>().ok_or("this must stay a map")?;
+    response.status = status(&map)?;
+    apply_headers(&map, &mut response.headers)
+}
+
+/// Builds the response returned by `respond` or `redirect`.
+fn response_from(map: Map) -> Result<Response, String> {
+    let body = map.get("body").cloned().map_or(Ok(String::new()), string)?;
+    let mut response = Response::new(status(&map)?, body);
+    apply_headers(&map, &mut response.headers)?;
+    Ok(response)
+}
+
+fn status(map: &Map) -> Result<StatusCode, String> {
+    map.get("status")
+        .and_then(|status| status.as_int().ok())
+        .and_then(|status| u16::try_from(status).ok())
+        .and_then(StatusCode::from_u16)
+        .ok_or_else(|| "status must be a valid status code".to_string())
+}
+
+fn string(value: Dynamic) -> Result<String, String> {
+    value
+        .into_string()
+        .map_err(|kind| format!("expected a string, found {}", kind))
+}
+
+/// Logs a failed hook and answers its request with 500 Internal Server Error.
+fn failed(hook: &str, request: &Request, error: &str) -> Response {
+    log::error(format_args!(
+        "the script hook {} failed for {} {}: {}",
+        hook, request.method, request.target, error
+    ));
+    Response::new(StatusCode::INTERNAL_SERVER_ERROR, "")
+}
+
+fn invalid_input(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(target: &str, headers: &[(&str, &str)]) -> Request {
+        Request {
+            target: target.parse().unwrap(),
+            headers: headers.iter().copied().collect(),
+            peer: Some("192.0.2.1:4000".parse().unwrap()),
+            ..Request::default()
+        }
+    }
+
+    /// Redirects `/old/`, answers `/teapot` itself, and otherwise changes the path and headers
+    /// by client, and the response by the request.
+    const HOOKS: &str = r#"
+        fn on_request() {
+            if this.path.starts_with("/old/") {
+                return redirect("/new/" + this.path.sub_string(5));
+            }
+            if this.path == "/teapot" {
+                return respond(418, "short and stout");
+            }
+            this.path = "/v2" + this.path;
+            this.headers["x-variant"] = if this.client.ends_with("1") { "b" } else { "a" };
+            this.headers.remove("x-secret");
+        }
+
+        fn on_response(request) {
+            this.headers["x-frame-options"] = "DENY";
+            if request.headers["x-variant"] == "b" {
+                this.status = 203;
+            }
+        }
+        "#;
+
+    /// Loops forever in the request hook.
+    const LOOPING: &str = "fn on_request() { loop { this.path += \"\"; } }";
+
+    fn changed() -> Request {
+        request(
+            "/items?x=1",
+            &[
+                ("Host", "example"),
+                ("X-Secret", "s"),
+                ("Accept", "a"),
+                ("Accept", "b"),
+            ],
+        )
+    }
+
+    /// It answers requests with the redirects of the request hook
+    #[test]
+    fn redirect() {
+        let hooks = ScriptHooks::new(HOOKS).unwrap();
+        let response = hooks.on_request(&mut request("/old/page", &[])).unwrap();
+        assert_eq!(StatusCode::FOUND, response.status);
+        assert_eq!(Some("/new/page"), response.header("Location"));
+    }
+
+    /// It answers requests with the responses of the request hook
+    #[test]
+    fn respond() {
+        let hooks = ScriptHooks::new(HOOKS).unwrap();
+        let response = hooks.on_request(&mut request("/teapot", &[])).unwrap();
+        assert_eq!(418, response.status.as_u16());
+        assert_eq!("short and stout", response.body);
+    }
+
+    /// It changes the path and headers of requests and keeps the headers it does not touch
+    #[test]
+    fn request_hook() {
+        let hooks = ScriptHooks::new(HOOKS).unwrap();
+        let mut changed = changed();
+        assert_eq!(None, hooks.on_request(&mut changed));
+        assert_eq!("/v2/items?x=1", changed.target.to_string());
+        assert_eq!(
+            vec![
+                ("Host", "example"),
+                ("Accept", "a"),
+                ("Accept", "b"),
+                ("x-variant", "b")
+            ],
+            changed.headers.iter().collect::<Vec<_>>()
+        );
+    }
+
+    /// It changes the status and headers of responses by their request and keeps the headers
+    /// it does not touch
+    #[test]
+    fn response_hook() {
+        let hooks = ScriptHooks::new(HOOKS).unwrap();
+        let mut changed = changed();
+        assert_eq!(None, hooks.on_request(&mut changed));
+        let response = Response::new(StatusCode::OK, "")
+            .with_header("Set-Cookie", "a=1")
+            .with_header("Set-Cookie", "b=2");
+        let response = hooks.on_response(&changed, response);
+        assert_eq!(203, response.status.as_u16());
+        assert_eq!(
+            vec![
+                ("Set-Cookie", "a=1"),
+                ("Set-Cookie", "b=2"),
+                ("x-frame-options", "DENY")
+            ],
+            response.headers.iter().collect::<Vec<_>>()
+        );
+    }
+
+    /// It answers 500 when a hook runs out of operations
+    #[test]
+    fn operation_limit() {
+        let hooks = ScriptHooks::new(LOOPING)
+            .unwrap()
+            .with_max_operations(1_000);
+        let response = hooks.on_request(&mut request("/", &[])).unwrap();
+        assert_eq!(StatusCode::INTERNAL_SERVER_ERROR, response.status);
+    }
+
+    /// It answers 500 when a hook runs out of time
+    #[test]
+    fn time_limit() {
+        let hooks = ScriptHooks::new(LOOPING)
+            .unwrap()
+            .with_max_operations(u64::MAX)
+            .with_timeout(Duration::from_millis(20));
+        let started = Instant::now();
+        let response = hooks.on_request(&mut request("/", &[])).unwrap();
+        assert_eq!(StatusCode::INTERNAL_SERVER_ERROR, response.status);
+        assert!(started.elapsed() < Duration::from_secs(5));
+    }
+
+    /// It answers 500 when a hook throws or sets a header that would split the head
+    #[test]
+    fn failures() {
+        let hooks = ScriptHooks::new("fn on_request() { throw \"broken\"; }").unwrap();
+        let response = hooks.on_request(&mut request("/", &[])).unwrap();
+        assert_eq!(StatusCode::INTERNAL_SERVER_ERROR, response.status);
+
+        let hooks =
+            ScriptHooks::new("fn on_request() { this.headers[\"x\"] = \"a\\r\\nb\"; }").unwrap();
+        let response = hooks.on_request(&mut request("/", &[])).unwrap();
+        assert_eq!(StatusCode::INTERNAL_SERVER_ERROR, response.status);
+    }
+
+    /// It rejects scripts that do not compile or have no hooks with the expected parameters
+    #[test]
+    fn invalid_scripts() {
+        for source in [
+            "fn on_request( {",
+            "fn other() {}",
+            "fn on_request(request) {}",
+        ] {
+            assert_eq!(
+                io::ErrorKind::InvalidInput,
+                ScriptHooks::new(source).unwrap_err().kind()
+            );
+        }
+    }
+}
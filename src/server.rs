@@ -0,0 +1,1071 @@
+use crate::config::Config;
+use crate::connection::{BufferPool, Connection};
+use crate::log::{self, Span};
+use crate::metrics::{Exchange, RouteStats};
+use crate::status::StatusCode;
+use crate::tus;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::future::Future;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard, RwLock};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::runtime::{self, Handle};
+use tokio::sync::watch;
+use tokio::task::{JoinHandle, JoinSet};
+use tokio::{io, net, time};
+
+/// Counters of connections and requests, shared by every clone, e.g. the copy in
+/// [`Config::stats`] and the one the admin API reads.
+#[derive(Clone, Debug, Default)]
+pub struct ServerStats {
+    counters: Arc<Counters>,
+}
+
+#[derive(Debug, Default)]
+struct Counters {
+    accepted: AtomicU64,
+    active: AtomicU64,
+    tls_handshake_failures: AtomicU64,
+    timed_out: AtomicU64,
+    requests: AtomicU64,
+    reused_requests: AtomicU64,
+    /// Responses by status class, from 1xx to 5xx.
+    responses: [AtomicU64; 5],
+    /// Requests by route pattern, see [`crate::metrics::route`].
+    routes: Mutex<BTreeMap<String, RouteStats>>,
+}
+
+/// The values of [`ServerStats`] at one point in time.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct StatsSnapshot {
+    /// Connections accepted since startup.
+    pub connections_accepted: u64,
+    /// Connections currently open.
+    pub connections_active: u64,
+    /// Connections whose TLS handshake failed since startup.
+    pub tls_handshake_failures: u64,
+    /// Connections closed since startup because they took too long: to send the head of a
+    /// request, idle keep-alive connections included, or to finish the TLS handshake, or to end
+    /// while a drain waited for them.
+    pub connections_timed_out: u64,
+    /// Requests read since startup.
+    pub requests: u64,
+    /// Requests read since startup from a connection that carried an earlier request, which
+    /// divided by [`StatsSnapshot::requests`] is the keep-alive reuse rate.
+    pub reused_requests: u64,
+    /// Responses written since startup by status class, from 1xx to 5xx.
+    pub responses: [u64; 5],
+}
+
+impl ServerStats {
+    /// Counts a request read from a connection.
+    pub fn record_request(&self) {
+        self.counters.requests.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Counts a request read from a connection that carried an earlier request.
+    pub fn record_reuse(&self) {
+        self.counters
+            .reused_requests
+            .fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Counts a connection whose TLS handshake failed.
+    pub fn record_tls_handshake_failure(&self) {
+        self.counters
+            .tls_handshake_failures
+            .fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Counts connections closed because they took too long.
+    ///
+    /// # Arguments
+    ///
+    /// * `count`: The number of connections.
+    pub fn record_timeouts(&self, count: u64) {
+        self.counters.timed_out.fetch_add(count, Ordering::Relaxed);
+    }
+
+    /// Counts a response written to a connection.
+    ///
+    /// # Arguments
+    ///
+    /// * `status`: The status of the response.
+    pub fn record_response(&self, status: StatusCode) {
+        let class = usize::from(status.as_u16() / 100).clamp(1, 5) - 1;
+        self.counters.responses[class].fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Counts a request in the stats of its route, see [`RouteStats::record`].
+    ///
+    /// # Arguments
+    ///
+    /// * `exchange`: The request and its response.
+    pub fn record_route(&self, exchange: &Exchange<'_>) {
+        let mut routes = self.lock_routes();
+        match routes.get_mut(exchange.route) {
+            Some(stats) => stats.record(exchange),
+            None => {
+                let mut stats = RouteStats::default();
+                stats.record(exchange);
+                routes.insert(exchange.route.to_string(), stats);
+            }
+        }
+    }
+
+    /// Copies the stats of every route.
+    ///
+    /// # Returns
+    ///
+    /// The stats by route pattern, see [`crate::metrics::route`].
+    pub fn routes(&self) -> BTreeMap<String, RouteStats> {
+        self.lock_routes().clone()
+    }
+
+    fn lock_routes(&self) -> MutexGuard<'_, BTreeMap<String, RouteStats>> {
+        self.counters
+            .routes
+            .lock()
+            .unwrap_or_else(|error| error.into_inner())
+    }
+
+    /// Counts an accepted connection as open until the returned guard is dropped.
+    fn open_connection(&self) -> OpenConnection {
+        self.counters.accepted.fetch_add(1, Ordering::Relaxed);
+        self.counters.active.fetch_add(1, Ordering::Relaxed);
+        OpenConnection {
+            stats: self.clone(),
+        }
+    }
+
+    /// Reads every counter.
+    pub fn snapshot(&self) -> StatsSnapshot {
+        let counters = &self.counters;
+        StatsSnapshot {
+            connections_accepted: counters.accepted.load(Ordering::Relaxed),
+            connections_active: counters.active.load(Ordering::Relaxed),
+            tls_handshake_failures: counters.tls_handshake_failures.load(Ordering::Relaxed),
+            connections_timed_out: counters.timed_out.load(Ordering::Relaxed),
+            requests: counters.requests.load(Ordering::Relaxed),
+            reused_requests: counters.reused_requests.load(Ordering::Relaxed),
+            responses: std::array::from_fn(|class| {
+                counters.responses[class].load(Ordering::Relaxed)
+            }),
+        }
+    }
+}
+
+/// Keeps a connection counted in [`StatsSnapshot::connections_active`] while alive.
+struct OpenConnection {
+    stats: ServerStats,
+}
+
+impl Drop for OpenConnection {
+    fn drop(&mut self) {
+        self.stats.counters.active.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Where a [`Server`] is in its lifecycle.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Phase {
+    /// Accepting and serving connections.
+    Running,
+    /// No longer accepting, waiting up to [`Config::drain_timeout`] for open connections.
+    Draining,
+    /// Every connection is closed or about to be.
+    Stopped,
+}
+
+/// Builds the configuration a [`Server`] switches to on [`Server::reload`].
+pub type Loader = dyn Fn() -> io::Result<Config> + Send + Sync;
+
+/// Accepts connections and serves them with [`crate::handle_stream`] until told to drain or stop.
+/// Clones control the same server, e.g. from the admin API.
+///
+/// The configuration can be replaced while the server runs. Connections accepted afterwards use
+/// the new one, while open connections keep the configuration they were accepted with until
+/// they close. The listener addresses are only read at startup.
+#[derive(Clone)]
+pub struct Server {
+    config: Arc<RwLock<Arc<Config>>>,
+    loader: Option<Arc<Loader>>,
+    /// The background tasks of the active configuration, `None` while not serving.
+    tasks: Arc<Mutex<Option<Vec<JoinHandle<()>>>>>,
+    phase: Arc<watch::Sender<Phase>>,
+    runtime: RuntimeOptions,
+}
+
+/// How [`Server::block_on`] gets its runtime, and how the threads of
+/// [`Server::serve_per_core`] are named and sized.
+#[derive(Clone, Debug, Default)]
+struct RuntimeOptions {
+    worker_threads: Option<usize>,
+    max_blocking_threads: Option<usize>,
+    thread_name: Option<String>,
+    thread_stack_size: Option<usize>,
+    /// Runs on this runtime instead of building one.
+    handle: Option<Handle>,
+}
+
+impl fmt::Debug for Server {
+    fn fmt(&self, formatter: &mut fmt::Formatter<'_>) -> fmt::Result {
+        formatter
+            .debug_struct("Server")
+            .field("config", &self.config())
+            .field("phase", &self.phase())
+            .finish_non_exhaustive()
+    }
+}
+
+impl Server {
+    /// Creates a server that has not started accepting yet.
+    ///
+    /// # Arguments
+    ///
+    /// * `config`: The settings shared by all connections.
+    ///
+    /// # Returns
+    ///
+    /// The server in [`Phase::Running`], which cannot [`Server::reload`] without a loader.
+    pub fn new(config: Config) -> Server {
+        Server {
+            config: Arc::new(RwLock::new(Arc::new(config))),
+            loader: None,
+            tasks: Arc::new(Mutex::new(None)),
+            phase: Arc::new(watch::channel(Phase::Running).0),
+            runtime: RuntimeOptions::default(),
+        }
+    }
+
+    /// Sets the number of threads running async tasks on the runtime of [`Server::block_on`].
+    ///
+    /// # Arguments
+    ///
+    /// * `threads`: The number of worker threads, or `0` for one per CPU core, the default.
+    ///
+    /// # Returns
+    ///
+    /// The server building its runtime with the threads.
+    pub fn with_worker_threads(mut self, threads: usize) -> Server {
+        self.runtime.worker_threads = Some(threads).filter(|threads| *threads > 0);
+        self
+    }
+
+    /// Sets how many threads the runtime of [`Server::block_on`] may start for blocking work,
+    /// such as reading files and running CGI scripts.
+    ///
+    /// # Arguments
+    ///
+    /// * `threads`: The limit, at least 1, and 512 by default.
+    ///
+    /// # Returns
+    ///
+    /// The server building its runtime with the limit.
+    pub fn with_max_blocking_threads(mut self, threads: usize) -> Server {
+        self.runtime.max_blocking_threads = Some(threads.max(1));
+        self
+    }
+
+    /// Names the threads of the runtime of [`Server::block_on`], and those of
+    /// [`Server::serve_per_core`] followed by their index, e.g. in a debugger or `top -H`.
+    ///
+    /// # Arguments
+    ///
+    /// * `name`: The thread name, `web-server` by default.
+    ///
+    /// # Returns
+    ///
+    /// The server naming its threads.
+    pub fn with_thread_name(mut self, name: impl Into<String>) -> Server {
+        self.runtime.thread_name = Some(name.into());
+        self
+    }
+
+    /// Sets the stack size of the threads of the runtime of [`Server::block_on`] and of
+    /// [`Server::serve_per_core`], e.g. for handlers with deep recursion.
+    ///
+    /// # Arguments
+    ///
+    /// * `bytes`: The stack size, 2 MiB by default.
+    ///
+    /// # Returns
+    ///
+    /// The server starting threads with the stack size.
+    pub fn with_thread_stack_size(mut self, bytes: usize) -> Server {
+        self.runtime.thread_stack_size = Some(bytes);
+        self
+    }
+
+    /// Runs [`Server::block_on`] on an existing runtime, e.g. one shared with other services of
+    /// the process, instead of building one. The other runtime settings are then ignored,
+    /// except for the threads of [`Server::serve_per_core`].
+    ///
+    /// # Arguments
+    ///
+    /// * `handle`: The handle of the runtime.
+    ///
+    /// # Returns
+    ///
+    /// The server running on the runtime.
+    pub fn with_runtime(mut self, handle: Handle) -> Server {
+        self.runtime.handle = Some(handle);
+        self
+    }
+
+    /// Runs a future to completion on the runtime of the server, which is built from the
+    /// settings of [`Server::with_worker_threads`] and the like unless
+    /// [`Server::with_runtime`] gave one. Blocks the calling thread, so call it outside an
+    /// async context, e.g. from `main`.
+    ///
+    /// # Arguments
+    ///
+    /// * `future`: The future to run, e.g. one calling [`Server::serve`].
+    ///
+    /// # Returns
+    ///
+    /// The output of the future.
+    ///
+    /// # Errors
+    ///
+    /// Captures IO errors from building the runtime.
+    pub fn block_on<F: Future>(&self, future: F) -> io::Result<F::Output> {
+        if let Some(handle) = &self.runtime.handle {
+            return Ok(handle.block_on(future));
+        }
+        let mut builder = runtime::Builder::new_multi_thread();
+        builder.enable_all().thread_name(self.thread_name());
+        if let Some(threads) = self.runtime.worker_threads {
+            builder.worker_threads(threads);
+        }
+        if let Some(threads) = self.runtime.max_blocking_threads {
+            builder.max_blocking_threads(threads);
+        }
+        if let Some(bytes) = self.runtime.thread_stack_size {
+            builder.thread_stack_size(bytes);
+        }
+        Ok(builder.build()?.block_on(future))
+    }
+
+    /// The name of the threads the server starts, see [`Server::with_thread_name`].
+    fn thread_name(&self) -> &str {
+        self.runtime.thread_name.as_deref().unwrap_or("web-server")
+    }
+
+    /// Sets how [`Server::reload`] builds the new configuration, e.g. [`Config::from_env`].
+    ///
+    /// # Arguments
+    ///
+    /// * `loader`: Builds the complete configuration, including routes registered in code.
+    ///
+    /// # Returns
+    ///
+    /// The server using the loader.
+    pub fn with_loader(
+        mut self,
+        loader: impl Fn() -> io::Result<Config> + Send + Sync + 'static,
+    ) -> Server {
+        self.loader = Some(Arc::new(loader));
+        self
+    }
+
+    /// The settings new connections are served with.
+    pub fn config(&self) -> Arc<Config> {
+        Arc::clone(
+            &self
+                .config
+                .read()
+                .unwrap_or_else(|error| error.into_inner()),
+        )
+    }
+
+    /// The connection and request counters, which survive reloads.
+    pub fn stats(&self) -> ServerStats {
+        self.config().stats.clone()
+    }
+
+    /// Builds a new configuration with the loader and switches to it, see [`Server::replace`].
+    /// The current configuration stays active when the new one cannot be built.
+    ///
+    /// # Returns
+    ///
+    /// `Ok(())` once the new configuration is active.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`io::ErrorKind::Unsupported`] without a [`Server::with_loader`], and propagates
+    /// errors of the loader, e.g. an invalid value in the configuration file.
+    pub fn reload(&self) -> io::Result<()> {
+        let loader = self.loader.as_ref().ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::Unsupported,
+                "the server has no configuration loader",
+            )
+        })?;
+        match loader() {
+            Ok(config) => {
+                self.replace(config);
+                log::info("configuration reloaded");
+                Ok(())
+            }
+            Err(error) => {
+                log::error(format_args!(
+                    "keeping the current configuration, the new one is invalid: {}",
+                    error
+                ));
+                Err(error)
+            }
+        }
+    }
+
+    /// Switches to a new configuration in one step, carrying over the counters and captured
+    /// exchanges, applying its log level, and restarting background tasks such as proxy health
+    /// checks.
+    ///
+    /// # Arguments
+    ///
+    /// * `config`: The settings for connections accepted from now on.
+    pub fn replace(&self, mut config: Config) {
+        let mut current = self
+            .config
+            .write()
+            .unwrap_or_else(|error| error.into_inner());
+        config.stats = current.stats.clone();
+        config.captures = current.captures.clone();
+        config.maintenance_switch = current.maintenance_switch.clone();
+        log::set_level(config.log_level);
+        let config = Arc::new(config);
+        *current = Arc::clone(&config);
+        drop(current);
+        let mut tasks = self.lock_tasks();
+        if tasks.is_some() {
+            stop_tasks(tasks.replace(spawn_tasks(&config)));
+        }
+    }
+
+    fn lock_tasks(&self) -> MutexGuard<'_, Option<Vec<JoinHandle<()>>>> {
+        self.tasks.lock().unwrap_or_else(|error| error.into_inner())
+    }
+
+    /// The current lifecycle phase.
+    pub fn phase(&self) -> Phase {
+        *self.phase.borrow()
+    }
+
+    /// Stops accepting connections and lets open ones finish, for up to
+    /// [`Config::drain_timeout`], before [`Server::serve`] returns.
+    pub fn drain(&self) {
+        self.phase.send_if_modified(|phase| {
+            let running = *phase == Phase::Running;
+            if running {
+                *phase = Phase::Draining;
+            }
+            running
+        });
+    }
+
+    /// Stops accepting connections and closes open ones right away.
+    pub fn shutdown(&self) {
+        self.phase.send_replace(Phase::Stopped);
+    }
+
+    /// Waits until the server reaches [`Phase::Stopped`].
+    pub async fn stopped(&self) {
+        wait_for_stop(&mut self.phase.subscribe()).await;
+    }
+
+    /// Serves connections accepted from a listener until the server drains or shuts down.
+    ///
+    /// # Arguments
+    ///
+    /// * `listener`: The bound listener.
+    ///
+    /// # Returns
+    ///
+    /// `Ok(())` once every connection is closed.
+    ///
+    /// # Errors
+    ///
+    /// Never fails; errors accepting or handling a connection are logged and only end that
+    /// connection.
+    pub async fn serve(&self, listener: net::TcpListener) -> io::Result<()> {
+        self.accept_loop(listener, false).await
+    }
+
+    /// Serves TLS connections accepted from a listener with the certificate of
+    /// [`Config::tls`], like [`Server::serve`]. Connections are closed right away while the
+    /// configuration has no TLS settings.
+    ///
+    /// # Arguments
+    ///
+    /// * `listener`: The bound listener, see [`crate::tls::Tls::address`].
+    ///
+    /// # Returns
+    ///
+    /// `Ok(())` once every connection is closed.
+    ///
+    /// # Errors
+    ///
+    /// Never fails; failed handshakes are logged and only end that connection.
+    #[cfg(feature = "tls")]
+    pub async fn serve_tls(&self, listener: net::TcpListener) -> io::Result<()> {
+        self.accept_loop(listener, true).await
+    }
+
+    /// Serves connections on several threads, each running a single-threaded runtime with its
+    /// own listener bound to the same address with `SO_REUSEPORT` and its own buffer pool. The
+    /// kernel spreads new connections across the listeners and a connection stays on the
+    /// thread that accepted it, which avoids work stealing between cores. Blocks until every
+    /// thread returns, so call it outside an async context, e.g. from
+    /// [`tokio::task::spawn_blocking`].
+    ///
+    /// # Arguments
+    ///
+    /// * `sockets`: The sockets bound for the threads, one thread each.
+    ///
+    /// # Returns
+    ///
+    /// `Ok(())` once the server stopped and every connection is closed.
+    ///
+    /// # Errors
+    ///
+    /// Captures IO errors from starting the threads and runtimes and from listening.
+    pub fn serve_per_core(&self, sockets: PerCoreSockets) -> io::Result<()> {
+        self.per_core(sockets, false, serve_thread)
+    }
+
+    /// Serves TLS connections like [`Server::serve_tls`] on several threads, see
+    /// [`Server::serve_per_core`].
+    ///
+    /// # Arguments
+    ///
+    /// * `sockets`: The sockets bound for the threads, see [`crate::tls::Tls::address`].
+    ///
+    /// # Returns
+    ///
+    /// `Ok(())` once the server stopped and every connection is closed.
+    ///
+    /// # Errors
+    ///
+    /// The errors of [`Server::serve_per_core`].
+    #[cfg(feature = "tls")]
+    pub fn serve_tls_per_core(&self, sockets: PerCoreSockets) -> io::Result<()> {
+        self.per_core(sockets, true, serve_thread)
+    }
+
+    /// Serves each socket on its own thread, see [`Server::serve_per_core`].
+    ///
+    /// # Arguments
+    ///
+    /// * `sockets`: The sockets bound for the threads.
+    /// * `secure`: Whether connections start with a TLS handshake.
+    /// * `run`: Serves one bound socket until the server stops, e.g. [`serve_thread`].
+    pub(crate) fn per_core(
+        &self,
+        sockets: PerCoreSockets,
+        secure: bool,
+        run: fn(Server, net::TcpSocket, bool) -> io::Result<()>,
+    ) -> io::Result<()> {
+        let threads = sockets
+            .sockets
+            .into_iter()
+            .enumerate()
+            .map(|(index, socket)| {
+                let server = self.clone();
+                let mut builder =
+                    std::thread::Builder::new().name(format!("{}-{}", self.thread_name(), index));
+                if let Some(bytes) = self.runtime.thread_stack_size {
+                    builder = builder.stack_size(bytes);
+                }
+                builder.spawn(move || run(server, socket, secure))
+            })
+            .collect::<io::Result<Vec<_>>>()?;
+        let results: Vec<io::Result<()>> = threads
+            .into_iter()
+            .map(|thread| {
+                thread
+                    .join()
+                    .unwrap_or_else(|_| Err(io::Error::other("a serving thread panicked")))
+            })
+            .collect();
+        results.into_iter().collect()
+    }
+
+    /// Accepts connections until the server drains or shuts down, then waits for them, see
+    /// [`Server::serve`].
+    ///
+    /// # Arguments
+    ///
+    /// * `listener`: The bound listener.
+    /// * `secure`: Whether connections start with a TLS handshake.
+    pub(crate) async fn accept_loop<L: Accept>(
+        &self,
+        mut listener: L,
+        secure: bool,
+    ) -> io::Result<()> {
+        let pool = BufferPool::default();
+        self.lock_tasks()
+            .get_or_insert_with(|| spawn_tasks(&self.config()));
+        let mut phase = self.phase.subscribe();
+        let mut connections = JoinSet::new();
+        while *phase.borrow_and_update() == Phase::Running {
+            tokio::select! {
+                accepted = listener.accept() => {
+                    let (stream, peer) = match accepted {
+                        Ok(accepted) => accepted,
+                        Err(error) => {
+                            log::error(format_args!("accepting a connection failed: {}", error));
+                            continue;
+                        }
+                    };
+                    let config = self.config();
+                    let open = config.stats.open_connection();
+                    let pool = pool.clone();
+                    let span = Arc::new(Span::numbered("connection").with_field("peer", peer));
+                    listener.spawn(&mut connections, span.run(async move {
+                        let _open = open;
+                        log::debug("connection opened");
+                        match serve_connection(stream, peer, &pool, &config, secure).await {
+                            Ok(()) => log::debug("connection closed"),
+                            Err(error) => {
+                                if error.kind() == io::ErrorKind::TimedOut {
+                                    config.stats.record_timeouts(1);
+                                }
+                                log::debug(format_args!("connection ended: {}", error));
+                            }
+                        }
+                    }));
+                }
+                Some(_) = connections.join_next() => {}
+                _ = phase.changed() => {}
+            }
+        }
+        drop(listener);
+        if *phase.borrow() == Phase::Draining {
+            log::info(format_args!("draining {} connections", connections.len()));
+            let drained = async { while connections.join_next().await.is_some() {} };
+            let timed_out = tokio::select! {
+                drained = time::timeout(self.config().drain_timeout, drained) => drained.is_err(),
+                _ = wait_for_stop(&mut phase) => false,
+            };
+            if timed_out {
+                self.stats().record_timeouts(connections.len() as u64);
+            }
+        }
+        self.phase.send_replace(Phase::Stopped);
+        connections.shutdown().await;
+        stop_tasks(self.lock_tasks().take());
+        Ok(())
+    }
+}
+
+/// Serves one accepted connection with the handler of the protocol negotiated through ALPN,
+/// which is [`crate::handle_stream`] for HTTP/1.1 and [`crate::grpc`] for HTTP/2.
+///
+/// # Arguments
+///
+/// * `stream`: The accepted connection.
+/// * `peer`: The address of the client.
+/// * `pool`: The pool lending the read buffer.
+/// * `config`: The settings the connection is served with.
+/// * `secure`: Whether to perform a TLS handshake first.
+///
+/// # Returns
+///
+/// `Ok(())` once the connection is closed.
+///
+/// # Errors
+///
+/// Captures handshake failures and the errors of [`crate::handle_stream`].
+async fn serve_connection<S: AsyncRead + AsyncWrite + Unpin + Send + 'static>(
+    stream: S,
+    peer: SocketAddr,
+    pool: &BufferPool,
+    config: &Config,
+    secure: bool,
+) -> io::Result<()> {
+    if secure {
+        #[cfg(feature = "tls")]
+        if let Some(tls) = &config.tls {
+            // A handshake that timed out is counted with the other timeouts by the caller
+            let stream = tls.accept(stream).await.inspect_err(|error| {
+                if error.kind() != io::ErrorKind::TimedOut {
+                    config.stats.record_tls_handshake_failure();
+                }
+            })?;
+            crate::tls::record_session(&stream);
+            #[cfg(feature = "grpc")]
+            if let Some(grpc) = &config.grpc {
+                if stream.get_ref().1.alpn_protocol() == Some(b"h2") {
+                    return crate::grpc::serve(stream, grpc, config).await;
+                }
+            }
+            match stream.get_ref().1.alpn_protocol() {
+                // Clients without ALPN expect HTTP/1.1 on an HTTPS port
+                None | Some(b"http/1.1") => {}
+                // ACME servers validating a challenge only look at the certificate
+                Some(crate::tls::ACME_TLS_ALPN) => return Ok(()),
+                Some(protocol) => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::Unsupported,
+                        format!(
+                            "no handler for the protocol {}",
+                            String::from_utf8_lossy(protocol)
+                        ),
+                    ))
+                }
+            }
+            let client_certificate = crate::tls::client_certificate(&stream);
+            let mut connection = Connection::new(stream, pool).with_peer(peer);
+            if let Some(certificate) = client_certificate {
+                connection = connection.with_client_certificate(certificate);
+            }
+            return crate::handle_stream(Box::new(connection), config).await;
+        }
+        return Ok(());
+    }
+    let connection = Connection::new(stream, pool).with_peer(peer);
+    #[cfg(feature = "grpc")]
+    let mut connection = connection;
+    #[cfg(feature = "grpc")]
+    if let Some(grpc) = &config.grpc {
+        // Clients with prior knowledge of HTTP/2 start with its preface instead of a request
+        let preface = crate::grpc::starts_with_preface(&mut connection);
+        let preface = match config.header_timeout {
+            Some(timeout) => match time::timeout(timeout, preface).await {
+                Ok(preface) => preface?,
+                Err(_) => {
+                    config.stats.record_timeouts(1);
+                    return Ok(());
+                }
+            },
+            None => preface.await?,
+        };
+        if preface {
+            return crate::grpc::serve(connection, grpc, config).await;
+        }
+    }
+    crate::handle_stream(Box::new(connection), config).await
+}
+
+/// A source of connections for [`Server::accept_loop`].
+pub(crate) trait Accept {
+    /// The byte stream of an accepted connection.
+    type Stream: AsyncRead + AsyncWrite + Unpin + Send + 'static;
+
+    /// Waits for the next connection. Dropping the future before it completes must not lose a
+    /// connection, since the accept loop waits on other events at the same time.
+    ///
+    /// # Returns
+    ///
+    /// The stream and the address of the client.
+    ///
+    /// # Errors
+    ///
+    /// Captures IO errors from accepting, which only skip that connection.
+    async fn accept(&mut self) -> io::Result<(Self::Stream, SocketAddr)>;
+
+    /// Starts the task serving one connection, on the worker threads of the runtime unless
+    /// the listener needs it to stay on its own thread.
+    ///
+    /// # Arguments
+    ///
+    /// * `connections`: The tasks of the open connections.
+    /// * `task`: The task serving the connection.
+    fn spawn(
+        &self,
+        connections: &mut JoinSet<()>,
+        task: impl Future<Output = ()> + Send + 'static,
+    ) {
+        connections.spawn(task);
+    }
+}
+
+impl Accept for net::TcpListener {
+    type Stream = net::TcpStream;
+
+    async fn accept(&mut self) -> io::Result<(net::TcpStream, SocketAddr)> {
+        net::TcpListener::accept(self).await
+    }
+}
+
+/// Serves one socket of [`Server::serve_per_core`] on a single-threaded runtime.
+///
+/// # Arguments
+///
+/// * `server`: The server the thread serves for.
+/// * `socket`: The bound socket.
+/// * `secure`: Whether connections start with a TLS handshake.
+///
+/// # Returns
+///
+/// `Ok(())` once the server stopped and every connection of the thread is closed.
+///
+/// # Errors
+///
+/// Captures IO errors from starting the runtime and listening.
+fn serve_thread(server: Server, socket: net::TcpSocket, secure: bool) -> io::Result<()> {
+    let runtime = runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()?;
+    runtime.block_on(async move {
+        let listener = socket.listen(1024)?;
+        server.accept_loop(listener, secure).await
+    })
+}
+
+/// Sockets bound to one address with `SO_REUSEPORT`, one for each thread of
+/// [`Server::serve_per_core`]. Binding apart from serving lets a server started as root bind a
+/// privileged port before it drops its privileges.
+#[derive(Debug)]
+pub struct PerCoreSockets {
+    sockets: Vec<net::TcpSocket>,
+}
+
+impl PerCoreSockets {
+    /// Binds the sockets up front, which reports a taken address before any thread starts.
+    ///
+    /// # Arguments
+    ///
+    /// * `address`: The address every thread listens on. With port 0, the others share the
+    ///   port the first socket got.
+    /// * `threads`: The number of sockets and so threads, or `0` for one per CPU core.
+    ///
+    /// # Returns
+    ///
+    /// The bound sockets, not yet listening.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`io::ErrorKind::Unsupported`] on platforms without `SO_REUSEPORT`, and captures
+    /// IO errors from binding the address.
+    pub fn bind(mut address: SocketAddr, threads: usize) -> io::Result<PerCoreSockets> {
+        let threads = match threads {
+            0 => std::thread::available_parallelism()?.get(),
+            threads => threads,
+        };
+        let sockets = (0..threads)
+            .map(|_| {
+                let socket = reuse_port_socket(address)?;
+                address = socket.local_addr()?;
+                Ok(socket)
+            })
+            .collect::<io::Result<Vec<_>>>()?;
+        Ok(PerCoreSockets { sockets })
+    }
+
+    /// The address the sockets are bound to.
+    ///
+    /// # Errors
+    ///
+    /// Captures IO errors from reading the address.
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        match self.sockets.first() {
+            Some(socket) => socket.local_addr(),
+            None => Err(io::Error::new(
+                io::ErrorKind::NotConnected,
+                "no socket bound",
+            )),
+        }
+    }
+}
+
+/// Binds a socket that other sockets may bind to the same address as well, with the kernel
+/// spreading connections among their listeners.
+#[cfg(unix)]
+fn reuse_port_socket(address: SocketAddr) -> io::Result<net::TcpSocket> {
+    let socket = match address {
+        SocketAddr::V4(_) => net::TcpSocket::new_v4()?,
+        SocketAddr::V6(_) => net::TcpSocket::new_v6()?,
+    };
+    socket.set_reuseaddr(true)?;
+    socket.set_reuseport(true)?;
+    socket.bind(address)?;
+    Ok(socket)
+}
+
+#[cfg(not(unix))]
+fn reuse_port_socket(_address: SocketAddr) -> io::Result<net::TcpSocket> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "serving per core needs SO_REUSEPORT",
+    ))
+}
+
+/// Aborts background tasks started by [`spawn_tasks`].
+fn stop_tasks(tasks: Option<Vec<JoinHandle<()>>>) {
+    for task in tasks.into_iter().flatten() {
+        task.abort();
+    }
+}
+
+/// Starts the background work a configuration needs: purging expired tus uploads, checking
+/// the health of proxy upstreams, watching the TLS certificate for changes, refreshing its
+/// stapled OCSP responses, and renewing it through ACME.
+///
+/// # Arguments
+///
+/// * `config`: The active configuration.
+///
+/// # Returns
+///
+/// The started tasks.
+fn spawn_tasks(config: &Config) -> Vec<JoinHandle<()>> {
+    let mut tasks = Vec::new();
+    if let Some(tus_config) = config.tus.clone() {
+        // Unfinished uploads that nobody asks about again would otherwise stay on disk forever
+        tasks.push(tokio::spawn(async move {
+            let mut interval = time::interval(tus_config.expiration.max(Duration::from_secs(60)));
+            loop {
+                interval.tick().await;
+                if let Err(error) = tus::purge_expired(&tus_config).await {
+                    log::error(format_args!(
+                        "purging expired tus uploads failed: {}",
+                        error
+                    ));
+                }
+            }
+        }));
+    }
+    if let Some(proxy) = &config.proxy {
+        tasks.extend(proxy.spawn_health_checks());
+    }
+    #[cfg(feature = "tls")]
+    if let Some(tls) = &config.tls {
+        tasks.extend(tls.spawn_watch());
+        tasks.extend(tls.spawn_ocsp_refresh());
+    }
+    #[cfg(feature = "acme")]
+    if let (Some(acme), Some(tls)) = (&config.acme, &config.tls) {
+        tasks.push(acme.spawn_renewal(tls.clone()));
+    }
+    tasks
+}
+
+/// Waits until a phase receiver sees [`Phase::Stopped`].
+async fn wait_for_stop(phase: &mut watch::Receiver<Phase>) {
+    while *phase.borrow_and_update() != Phase::Stopped {
+        if phase.changed().await.is_err() {
+            // The server is gone, which stops it as well
+            return;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    /// It switches to a valid configuration, keeps counting, and keeps the old one otherwise
+    #[test]
+    fn reload() {
+        let server = Server::new(Config::default());
+        assert_eq!(
+            io::ErrorKind::Unsupported,
+            server.reload().unwrap_err().kind()
+        );
+        server.stats().record_request();
+        let server = server.with_loader(|| Config::from_toml("max_body_size = 5"));
+        server.reload().unwrap();
+        assert_eq!(5, server.config().max_body_size);
+        assert_eq!(1, server.stats().snapshot().requests);
+        let server = server.with_loader(|| Config::from_toml("max_body_size = -1"));
+        assert!(server.reload().is_err());
+        assert_eq!(5, server.config().max_body_size);
+    }
+
+    /// It counts connections, requests, and responses, and stops accepting once drained
+    #[tokio::test]
+    async fn serve_and_drain() {
+        let server = Server::new(Config::default());
+        let listener = net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let serving = tokio::spawn({
+            let server = server.clone();
+            async move { server.serve(listener).await }
+        });
+        let mut client = net::TcpStream::connect(address).await.unwrap();
+        client
+            .write_all(b"GET /missing HTTP/1.1\r\nConnection: close\r\n\r\n")
+            .await
+            .unwrap();
+        let mut response = String::new();
+        client.read_to_string(&mut response).await.unwrap();
+        assert!(response.starts_with("HTTP/1.1 404 Not Found"));
+
+        server.drain();
+        serving.await.unwrap().unwrap();
+        assert_eq!(Phase::Stopped, server.phase());
+        let stats = server.stats().snapshot();
+        assert_eq!(1, stats.connections_accepted);
+        assert_eq!(0, stats.connections_active);
+        assert_eq!(1, stats.requests);
+        assert_eq!([0, 0, 0, 1, 0], stats.responses);
+        assert!(net::TcpStream::connect(address).await.is_err());
+    }
+
+    /// It runs futures on a runtime built with the thread settings, or on a given runtime
+    #[test]
+    fn block_on() {
+        let spawned_name = || async {
+            tokio::spawn(async { std::thread::current().name().map(String::from) })
+                .await
+                .unwrap()
+        };
+        let server = Server::new(Config::default())
+            .with_worker_threads(2)
+            .with_max_blocking_threads(1)
+            .with_thread_name("worker");
+        let name = server.block_on(spawned_name()).unwrap();
+        assert_eq!(Some("worker".to_string()), name);
+
+        let shared = runtime::Builder::new_multi_thread()
+            .worker_threads(1)
+            .thread_name("shared")
+            .build()
+            .unwrap();
+        let server = server.with_runtime(shared.handle().clone());
+        let name = server.block_on(spawned_name()).unwrap();
+        assert_eq!(Some("shared".to_string()), name);
+    }
+
+    /// It serves connections on several threads sharing one port until shut down
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn per_core() {
+        let taken = net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let server = Server::new(Config::default());
+        let address = taken.local_addr().unwrap();
+        let error = PerCoreSockets::bind(address, 2).unwrap_err();
+        assert_eq!(io::ErrorKind::AddrInUse, error.kind());
+        drop(taken);
+
+        let sockets = PerCoreSockets::bind(address, 2).unwrap();
+        let serving = std::thread::spawn({
+            let server = server.clone();
+            move || server.serve_per_core(sockets)
+        });
+        for _ in 0..4 {
+            let mut client = loop {
+                match net::TcpStream::connect(address).await {
+                    Ok(client) => break client,
+                    Err(_) => time::sleep(Duration::from_millis(10)).await,
+                }
+            };
+            client
+                .write_all(b"GET /missing HTTP/1.1\r\nConnection: close\r\n\r\n")
+                .await
+                .unwrap();
+            let mut response = String::new();
+            client.read_to_string(&mut response).await.unwrap();
+            assert!(response.starts_with("HTTP/1.1 404 Not Found"));
+        }
+        server.shutdown();
+        serving.join().unwrap().unwrap();
+        assert_eq!(4, server.stats().snapshot().requests);
+    }
+}
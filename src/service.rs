@@ -0,0 +1,192 @@
+//! Interop with [tower](https://docs.rs/tower), enabled by the `tower` feature: the [`Router`]
+//! is a [`Service`], and [`Router::layer`] wraps its handlers in any tower [`Layer`], e.g. a
+//! concurrency limit or a timeout.
+
+use crate::request::Request;
+use crate::response::Response;
+use crate::router::{Handler, Router};
+use crate::status::StatusCode;
+use async_trait::async_trait;
+use std::error::Error;
+use std::future::{self, Future};
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use tokio::io;
+use tower::{Layer, Service};
+
+/// The error type that tower middleware such as timeouts report.
+type BoxError = Box<dyn Error + Send + Sync>;
+
+/// The future returned by the services of this module.
+pub type ResponseFuture = Pin<Box<dyn Future<Output = io::Result<Response>> + Send>>;
+
+/// A tower [`Service`] that answers every request with one [`Handler`], the service that layers
+/// passed to [`Router::layer`] wrap.
+#[derive(Clone)]
+pub struct HandlerService {
+    handler: Arc<dyn Handler>,
+}
+
+impl Service<Request> for HandlerService {
+    type Response = Response;
+    type Error = io::Error;
+    type Future = ResponseFuture;
+
+    fn poll_ready(&mut self, _context: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, request: Request) -> ResponseFuture {
+        let handler = Arc::clone(&self.handler);
+        Box::pin(async move { handler.call(request).await })
+    }
+}
+
+/// Answers requests through a layered tower service, cloning it per request as tower services
+/// are driven through `&mut self`.
+struct ServiceHandler<S> {
+    service: S,
+}
+
+#[async_trait]
+impl<S> Handler for ServiceHandler<S>
+where
+    S: Service<Request, Response = Response> + Clone + Send + Sync + 'static,
+    S::Error: Into<BoxError>,
+    S::Future: Send,
+{
+    async fn call(&self, request: Request) -> io::Result<Response> {
+        let mut service = self.service.clone();
+        future::poll_fn(|context| service.poll_ready(context))
+            .await
+            .map_err(into_io_error)?;
+        service.call(request).await.map_err(into_io_error)
+    }
+}
+
+/// Converts a middleware error into the IO errors that handlers report, unwrapping errors that
+/// already are IO errors.
+///
+/// # Arguments
+///
+/// * `error`: The error reported by a tower service.
+///
+/// # Returns
+///
+/// The IO error, of kind [`io::ErrorKind::Other`] unless it was an IO error before.
+fn into_io_error(error: impl Into<BoxError>) -> io::Error {
+    match error.into().downcast::<io::Error>() {
+        Ok(error) => *error,
+        Err(error) => io::Error::other(error),
+    }
+}
+
+impl Router {
+    /// Wraps every handler registered so far in a tower layer. Handlers registered afterwards
+    /// are not wrapped, so a layer only applies to the routes above it.
+    ///
+    /// # Arguments
+    ///
+    /// * `layer`: The middleware, e.g. `tower::limit::ConcurrencyLimitLayer`.
+    ///
+    /// # Returns
+    ///
+    /// The router with wrapped handlers.
+    pub fn layer<L>(self, layer: L) -> Router
+    where
+        L: Layer<HandlerService>,
+        L::Service: Service<Request, Response = Response> + Clone + Send + Sync + 'static,
+        <L::Service as Service<Request>>::Error: Into<BoxError>,
+        <L::Service as Service<Request>>::Future: Send,
+    {
+        self.map_handlers(std::any::type_name::<L>(), |handler| {
+            Arc::new(ServiceHandler {
+                service: layer.layer(HandlerService { handler }),
+            })
+        })
+    }
+}
+
+/// Routes requests like [`Router::dispatch`], answering unmatched ones with 404 Not Found.
+impl Service<Request> for Router {
+    type Response = Response;
+    type Error = io::Error;
+    type Future = ResponseFuture;
+
+    fn poll_ready(&mut self, _context: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, request: Request) -> ResponseFuture {
+        let router = self.clone();
+        Box::pin(async move {
+            Ok(router
+                .dispatch(&request)
+                .await?
+                .unwrap_or_else(|| Response::new(StatusCode::NOT_FOUND, "")))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::method::Method;
+
+    /// Adds a header to every response of the wrapped service.
+    #[derive(Clone)]
+    struct Stamp<S>(S);
+
+    impl<S> Service<Request> for Stamp<S>
+    where
+        S: Service<Request, Response = Response, Error = io::Error, Future = ResponseFuture>,
+    {
+        type Response = Response;
+        type Error = io::Error;
+        type Future = ResponseFuture;
+
+        fn poll_ready(&mut self, context: &mut Context<'_>) -> Poll<io::Result<()>> {
+            self.0.poll_ready(context)
+        }
+
+        fn call(&mut self, request: Request) -> ResponseFuture {
+            let future = self.0.call(request);
+            Box::pin(async move { Ok(future.await?.with_header("X-Stamp", "1")) })
+        }
+    }
+
+    struct StampLayer;
+
+    impl<S> Layer<S> for StampLayer {
+        type Service = Stamp<S>;
+
+        fn layer(&self, service: S) -> Stamp<S> {
+            Stamp(service)
+        }
+    }
+
+    fn get(target: &str) -> Request {
+        Request {
+            method: Method::Get,
+            target: target.parse().unwrap(),
+            ..Request::default()
+        }
+    }
+
+    /// It applies layers to the routes registered before them and serves as a tower service
+    #[tokio::test]
+    async fn layered_router() {
+        let ok = |_| async { Ok(Response::new(StatusCode::OK, "")) };
+        let mut router = Router::new()
+            .route(Method::Get, "/a", ok)
+            .layer(StampLayer)
+            .route(Method::Get, "/b", ok);
+        let response = router.call(get("/a")).await.unwrap();
+        assert_eq!(Some("1"), response.header("X-Stamp"));
+        let response = router.call(get("/b")).await.unwrap();
+        assert_eq!(None, response.header("X-Stamp"));
+        let response = router.call(get("/c")).await.unwrap();
+        assert_eq!(StatusCode::NOT_FOUND, response.status);
+    }
+}
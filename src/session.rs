@@ -0,0 +1,395 @@
+#[cfg(feature = "cookie")]
+use crate::cookie::CookieJar;
+use crate::request::Request;
+use crate::response::Response;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// The name of the cookie carrying the session ID.
+pub const COOKIE_NAME: &str = "session";
+
+/// A one-shot message stored in a session until it is read, see [`crate::flash`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Flash {
+    /// A category for styling, e.g. `success` or `error`.
+    pub level: String,
+    /// The message shown to the user.
+    pub message: String,
+}
+
+/// Values kept for one client between requests.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Session {
+    id: Option<String>,
+    values: BTreeMap<String, String>,
+    flashes: Vec<Flash>,
+    /// When the session was first saved, for the absolute expiration.
+    created: Option<Instant>,
+    /// When a request last loaded the session, for the idle expiration.
+    accessed: Option<Instant>,
+}
+
+impl Session {
+    /// The session ID, or `None` for a session that has not been saved yet.
+    pub fn id(&self) -> Option<&str> {
+        self.id.as_deref()
+    }
+
+    /// Looks up a value.
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.values.get(key).map(String::as_str)
+    }
+
+    /// Stores a value, replacing any previous one.
+    pub fn insert(&mut self, key: &str, value: impl Into<String>) {
+        self.values.insert(key.to_string(), value.into());
+    }
+
+    /// Removes a value.
+    pub fn remove(&mut self, key: &str) -> Option<String> {
+        self.values.remove(key)
+    }
+
+    /// Queues a flash message for the next request that reads flashes.
+    ///
+    /// # Arguments
+    ///
+    /// * `level`: A category for styling, e.g. `success` or `error`.
+    /// * `message`: The message shown to the user.
+    pub fn flash(&mut self, level: &str, message: impl Into<String>) {
+        self.flashes.push(Flash {
+            level: level.to_string(),
+            message: message.into(),
+        });
+    }
+
+    /// Removes and returns the queued flash messages in the order they were added.
+    pub fn take_flashes(&mut self) -> Vec<Flash> {
+        std::mem::take(&mut self.flashes)
+    }
+
+    /// Checks whether the session holds neither values nor flash messages.
+    pub fn is_empty(&self) -> bool {
+        self.values.is_empty() && self.flashes.is_empty()
+    }
+}
+
+/// Keeps sessions in memory, keyed by a random ID sent to clients in the [`COOKIE_NAME`] cookie.
+/// Clones share the same sessions.
+///
+/// Sessions live until they are destroyed unless the store expires them: every request loading
+/// a session renews it for the idle timeout, while the lifetime counts from its creation
+/// however active it is. Handlers call [`SessionStore::rotate`] when the privileges of a
+/// session change, e.g. on login, so that an ID planted or seen before cannot take them over.
+#[derive(Clone, Debug, Default)]
+pub struct SessionStore {
+    sessions: Arc<Mutex<HashMap<String, Session>>>,
+    /// Expires sessions this long after their last request.
+    idle_timeout: Option<Duration>,
+    /// Expires sessions this long after their creation.
+    lifetime: Option<Duration>,
+    /// Signs the session IDs in cookies, see [`SessionStore::with_cookie_jar`].
+    #[cfg(feature = "cookie")]
+    jar: Option<Arc<CookieJar>>,
+}
+
+impl SessionStore {
+    /// Creates an empty store.
+    pub fn new() -> SessionStore {
+        SessionStore::default()
+    }
+
+    /// Expires sessions that no request loaded for a while.
+    ///
+    /// # Arguments
+    ///
+    /// * `timeout`: The time after the last request, e.g. 30 minutes.
+    pub fn with_idle_timeout(mut self, timeout: Duration) -> SessionStore {
+        self.idle_timeout = Some(timeout);
+        self
+    }
+
+    /// Expires sessions a while after their creation whatever their activity, and lets the
+    /// cookie expire then too.
+    ///
+    /// # Arguments
+    ///
+    /// * `lifetime`: The time after the creation, e.g. 12 hours.
+    pub fn with_lifetime(mut self, lifetime: Duration) -> SessionStore {
+        self.lifetime = Some(lifetime);
+        self
+    }
+
+    /// Signs the session IDs sent to clients, so that a cookie only names a session when this
+    /// application made it.
+    ///
+    /// # Arguments
+    ///
+    /// * `jar`: The keys signing the cookies.
+    #[cfg(feature = "cookie")]
+    pub fn with_cookie_jar(mut self, jar: Arc<CookieJar>) -> SessionStore {
+        self.jar = Some(jar);
+        self
+    }
+
+    /// Loads the session named by the request cookie.
+    ///
+    /// # Arguments
+    ///
+    /// * `request`: The incoming request.
+    ///
+    /// # Returns
+    ///
+    /// A copy of the stored session, renewed for the idle timeout, or a new empty session when
+    /// the cookie is missing or names an unknown or expired session.
+    pub fn load(&self, request: &Request) -> Session {
+        self.load_at(request, Instant::now())
+    }
+
+    /// Loads a session like [`SessionStore::load`] at a given time.
+    fn load_at(&self, request: &Request, now: Instant) -> Session {
+        let id = match request.cookie(COOKIE_NAME) {
+            Some(id) => id,
+            None => return Session::default(),
+        };
+        #[cfg(feature = "cookie")]
+        let id = match &self.jar {
+            Some(jar) => match jar.verify(COOKIE_NAME, id) {
+                Some(id) => id,
+                None => return Session::default(),
+            },
+            None => id,
+        };
+        let mut sessions = self.lock();
+        let Some(session) = sessions.get_mut(id) else {
+            return Session::default();
+        };
+        if self.expired(session, now) {
+            sessions.remove(id);
+            return Session::default();
+        }
+        session.accessed = Some(now);
+        session.clone()
+    }
+
+    /// Checks whether a session outlived the idle timeout or the lifetime.
+    fn expired(&self, session: &Session, now: Instant) -> bool {
+        let outlived = |since: Option<Instant>, limit: Option<Duration>| match (since, limit) {
+            (Some(since), Some(limit)) => now.saturating_duration_since(since) >= limit,
+            _ => false,
+        };
+        outlived(session.accessed, self.idle_timeout) || outlived(session.created, self.lifetime)
+    }
+
+    /// Forgets the expired sessions that no request loaded since they expired, e.g. from a
+    /// periodic task, so that abandoned sessions do not pile up.
+    ///
+    /// # Returns
+    ///
+    /// The number of sessions forgotten.
+    pub fn purge_expired(&self) -> usize {
+        let now = Instant::now();
+        let mut sessions = self.lock();
+        let before = sessions.len();
+        sessions.retain(|_, session| !self.expired(session, now));
+        before - sessions.len()
+    }
+
+    /// Gives a session a new ID when its privileges change, e.g. on login or logout, keeping
+    /// its values. The old ID no longer names a session, and saving the session sends the
+    /// cookie with the new ID.
+    ///
+    /// # Arguments
+    ///
+    /// * `session`: The session loaded for the request.
+    pub fn rotate(&self, session: &mut Session) {
+        if let Some(id) = session.id.take() {
+            self.lock().remove(&id);
+        }
+    }
+
+    /// Stores a session loaded with [`SessionStore::load`] and modified by a handler.
+    ///
+    /// # Arguments
+    ///
+    /// * `session`: The session to store.
+    /// * `response`: The response to the request that loaded the session.
+    ///
+    /// # Returns
+    ///
+    /// The response, with a `Set-Cookie` header when the session was new and is not empty.
+    pub fn save(&self, session: Session, response: Response) -> Response {
+        match self.persist(session) {
+            Some(cookie) => response.with_header("Set-Cookie", cookie),
+            None => response,
+        }
+    }
+
+    /// Stores a session like [`SessionStore::save`] for callers that build the response later.
+    ///
+    /// # Arguments
+    ///
+    /// * `session`: The session to store.
+    ///
+    /// # Returns
+    ///
+    /// The `Set-Cookie` value to send when the session was new and is not empty.
+    pub fn persist(&self, mut session: Session) -> Option<String> {
+        if let Some(id) = session.id.clone() {
+            self.lock().insert(id, session);
+            return None;
+        }
+        if session.is_empty() {
+            return None;
+        }
+        let id: String = rand::random::<[u8; 16]>()
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect();
+        let now = Instant::now();
+        session.id = Some(id.clone());
+        session.created.get_or_insert(now);
+        session.accessed = Some(now);
+        self.lock().insert(id.clone(), session);
+        #[cfg(feature = "cookie")]
+        let id = match &self.jar {
+            Some(jar) => jar.sign(COOKIE_NAME, &id),
+            None => id,
+        };
+        let max_age = match self.lifetime {
+            Some(lifetime) => format!("; Max-Age={}", lifetime.as_secs()),
+            None => String::new(),
+        };
+        Some(format!(
+            "{}={}; Path=/; HttpOnly; SameSite=Lax{}",
+            COOKIE_NAME, id, max_age
+        ))
+    }
+
+    /// Forgets a session, e.g. on logout.
+    pub fn destroy(&self, session: &Session) {
+        if let Some(id) = &session.id {
+            self.lock().remove(id);
+        }
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, HashMap<String, Session>> {
+        self.sessions
+            .lock()
+            .unwrap_or_else(|error| error.into_inner())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::status::StatusCode;
+
+    /// Builds a request sending the cookie from a `Set-Cookie` response header.
+    fn follow_cookie(response: &Response) -> Request {
+        let mut request = Request::default();
+        if let Some(value) = response.header("Set-Cookie") {
+            let pair = value.split(';').next().unwrap().to_string();
+            request.headers.append("Cookie", pair);
+        }
+        request
+    }
+
+    /// It only sets a cookie once something was stored, then loads the same values back
+    #[test]
+    fn round_trip() {
+        let store = SessionStore::new();
+        let empty = store.save(
+            store.load(&Request::default()),
+            Response::new(StatusCode::OK, ""),
+        );
+        assert!(empty.headers.is_empty());
+
+        let mut session = store.load(&Request::default());
+        session.insert("user", "ada");
+        let response = store.save(session, Response::new(StatusCode::OK, ""));
+        let request = follow_cookie(&response);
+        let mut session = store.load(&request);
+        assert_eq!(Some("ada"), session.get("user"));
+        assert!(session.id().is_some());
+
+        session.insert("user", "grace");
+        let response = store.save(session, Response::new(StatusCode::OK, ""));
+        assert!(response.headers.is_empty());
+        assert_eq!(Some("grace"), store.load(&request).get("user"));
+    }
+
+    /// It starts a new session for unknown IDs and forgets destroyed sessions
+    #[test]
+    fn unknown_and_destroyed() {
+        let store = SessionStore::new();
+        let mut request = Request::default();
+        request.headers.append("Cookie", "session=forged");
+        assert_eq!(None, store.load(&request).id());
+
+        let mut session = Session::default();
+        session.insert("user", "ada");
+        let request = follow_cookie(&store.save(session, Response::new(StatusCode::OK, "")));
+        store.destroy(&store.load(&request));
+        assert_eq!(None, store.load(&request).get("user"));
+    }
+
+    /// It expires idle and old sessions and gives rotated sessions a new ID
+    #[test]
+    fn expiration_and_rotation() {
+        let minute = Duration::from_secs(60);
+        let store = SessionStore::new()
+            .with_idle_timeout(10 * minute)
+            .with_lifetime(60 * minute);
+        let mut session = Session::default();
+        session.insert("user", "ada");
+        let response = store.save(session, Response::new(StatusCode::OK, ""));
+        assert!(response
+            .header("Set-Cookie")
+            .unwrap()
+            .ends_with("; Max-Age=3600"));
+        let request = follow_cookie(&response);
+        let start = Instant::now();
+        for minutes in [9, 18, 27, 36, 45, 54] {
+            let session = store.load_at(&request, start + minutes * minute);
+            assert_eq!(Some("ada"), session.get("user"), "{}", minutes);
+        }
+        assert_eq!(None, store.load_at(&request, start + 61 * minute).id());
+        assert_eq!(None, store.load(&request).id());
+
+        let mut session = Session::default();
+        session.insert("user", "ada");
+        let request = follow_cookie(&store.save(session, Response::new(StatusCode::OK, "")));
+        assert_eq!(None, store.load_at(&request, start + 11 * minute).id());
+
+        let mut session = Session::default();
+        session.insert("cart", "3");
+        let before = follow_cookie(&store.save(session, Response::new(StatusCode::OK, "")));
+        let mut session = store.load(&before);
+        store.rotate(&mut session);
+        session.insert("user", "ada");
+        let after = follow_cookie(&store.save(session, Response::new(StatusCode::OK, "")));
+        assert_eq!(None, store.load(&before).id());
+        assert_eq!(Some("3"), store.load(&after).get("cart"));
+    }
+
+    /// It only loads sessions named by cookies it signed
+    #[cfg(feature = "cookie")]
+    #[test]
+    fn signed_ids() {
+        let jar = Arc::new(CookieJar::new(vec![crate::cookie::Key::generate().unwrap()]).unwrap());
+        let store = SessionStore::new().with_cookie_jar(jar);
+        let mut session = Session::default();
+        session.insert("user", "ada");
+        let request = follow_cookie(&store.save(session, Response::new(StatusCode::OK, "")));
+        let session = store.load(&request);
+        assert_eq!(Some("ada"), session.get("user"));
+
+        let mut forged = Request::default();
+        forged
+            .headers
+            .append("Cookie", format!("session={}", session.id().unwrap()));
+        assert_eq!(None, store.load(&forged).id());
+    }
+}
@@ -0,0 +1,341 @@
+//! Adaptive load shedding for a server fronting a struggling backend. A [`LoadShedder`] watches
+//! the 99th percentile of recent handler latencies and, while it exceeds a target, answers a
+//! growing share of requests with 503 Service Unavailable right away and refuses expensive
+//! routes entirely. Once the latency is back below the target, or too few requests get through
+//! to measure it, the share shrinks step by step until every request is handled again.
+
+use crate::log;
+use crate::path::{is_below, normalize};
+use crate::request::Request;
+use crate::response::Response;
+use crate::status::StatusCode;
+use std::collections::VecDeque;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// The most latencies kept, so that busy servers do not keep a window of unbounded size.
+const MAX_SAMPLES: usize = 10_000;
+
+/// The state of a [`LoadShedder`].
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct SheddingStats {
+    /// The share of requests shed, from 0 to the maximum.
+    pub fraction: f64,
+    /// The 99th percentile of the latencies at the last adjustment, if there were enough.
+    pub p99: Option<Duration>,
+    /// Requests shed since the server started.
+    pub shed: u64,
+}
+
+/// The latencies observed and the current share of shed requests.
+#[derive(Debug)]
+struct Controller {
+    samples: VecDeque<(Instant, Duration)>,
+    adjusted: Instant,
+    /// How many requests are due to be shed, growing by the fraction with every request so
+    /// that shed requests are spread evenly.
+    debt: f64,
+    stats: SheddingStats,
+}
+
+/// Sheds requests while handlers are slow, see [`crate::config::Config::load_shedding`].
+/// Clones share the observed latencies.
+#[derive(Clone)]
+pub struct LoadShedder {
+    target: Duration,
+    window: Duration,
+    interval: Duration,
+    min_samples: usize,
+    step: f64,
+    max_fraction: f64,
+    expensive: Vec<String>,
+    exempt: Vec<String>,
+    controller: Arc<Mutex<Controller>>,
+}
+
+impl LoadShedder {
+    /// Sheds requests while the 99th percentile of the latencies of the last 10 seconds exceeds
+    /// a target, adjusting the shed share by 10% every second, up to 90%.
+    ///
+    /// # Arguments
+    ///
+    /// * `target`: The highest acceptable 99th percentile.
+    pub fn new(target: Duration) -> LoadShedder {
+        LoadShedder {
+            target,
+            window: Duration::from_secs(10),
+            interval: Duration::from_secs(1),
+            min_samples: 20,
+            step: 0.1,
+            max_fraction: 0.9,
+            expensive: Vec::new(),
+            exempt: Vec::new(),
+            controller: Arc::new(Mutex::new(Controller {
+                samples: VecDeque::new(),
+                adjusted: Instant::now(),
+                debt: 0.0,
+                stats: SheddingStats::default(),
+            })),
+        }
+    }
+
+    /// Computes the percentile over the latencies of this long.
+    pub fn with_window(mut self, window: Duration) -> LoadShedder {
+        self.window = window;
+        self
+    }
+
+    /// Raises or lowers the shed share by `step` every `interval`, capped at `max_fraction`,
+    /// e.g. `1.0` to shed every request that is neither exempt nor expensive.
+    pub fn with_steps(mut self, interval: Duration, step: f64, max_fraction: f64) -> LoadShedder {
+        self.interval = interval;
+        self.step = step.clamp(0.0, 1.0);
+        self.max_fraction = max_fraction.clamp(0.0, 1.0);
+        self
+    }
+
+    /// Refuses every request for this path or below it while shedding, e.g. `/reports`, matched
+    /// by whole segments of the normalized path.
+    pub fn with_expensive(mut self, prefix: impl Into<String>) -> LoadShedder {
+        let prefix = prefix.into();
+        self.expensive.push(normalize(&prefix).unwrap_or(prefix));
+        self
+    }
+
+    /// Never sheds requests for this path or below it, e.g. health checks at `/health`, matched
+    /// by whole segments of the normalized path, so `/healthz` and `/health/../admin` are not
+    /// exempt.
+    pub fn with_exempt(mut self, prefix: impl Into<String>) -> LoadShedder {
+        let prefix = prefix.into();
+        self.exempt.push(normalize(&prefix).unwrap_or(prefix));
+        self
+    }
+
+    /// The current state.
+    pub fn stats(&self) -> SheddingStats {
+        self.lock().stats
+    }
+
+    /// Decides whether to handle a request.
+    ///
+    /// # Returns
+    ///
+    /// `None` to handle it, or the 503 Service Unavailable response shedding it.
+    pub fn check(&self, request: &Request) -> Option<Response> {
+        self.check_at(request, Instant::now())
+    }
+
+    /// Decides whether to handle a request at a point in time, adjusting the shed share first
+    /// when an interval passed without observations, as happens while every request is shed.
+    fn check_at(&self, request: &Request, now: Instant) -> Option<Response> {
+        // Paths that cannot be decoded are neither exempt nor expensive
+        let path = normalize(request.path());
+        let below = |prefixes: &[String]| {
+            path.as_deref()
+                .is_some_and(|path| prefixes.iter().any(|prefix| is_below(path, prefix)))
+        };
+        if below(&self.exempt) {
+            return None;
+        }
+        let mut controller = self.lock();
+        if now.saturating_duration_since(controller.adjusted) >= self.interval {
+            self.adjust(&mut controller, now);
+        }
+        let fraction = controller.stats.fraction;
+        if fraction <= 0.0 {
+            return None;
+        }
+        if !below(&self.expensive) {
+            controller.debt += fraction;
+            if controller.debt < 1.0 {
+                return None;
+            }
+            controller.debt -= 1.0;
+        }
+        controller.stats.shed += 1;
+        Some(
+            Response::new(
+                StatusCode::SERVICE_UNAVAILABLE,
+                "the server is overloaded, please retry later\n",
+            )
+            .with_header("Retry-After", self.interval.as_secs().max(1).to_string()),
+        )
+    }
+
+    /// Records how long a handled request took.
+    pub fn observe(&self, latency: Duration) {
+        self.observe_at(latency, Instant::now());
+    }
+
+    /// Records a latency at a point in time and adjusts the shed share once per interval.
+    fn observe_at(&self, latency: Duration, now: Instant) {
+        let mut controller = self.lock();
+        if controller.samples.len() == MAX_SAMPLES {
+            controller.samples.pop_front();
+        }
+        controller.samples.push_back((now, latency));
+        if now.saturating_duration_since(controller.adjusted) >= self.interval {
+            self.adjust(&mut controller, now);
+        }
+    }
+
+    /// Drops the latencies outside the window and moves the shed share a step towards what the
+    /// 99th percentile of the rest calls for, down when there are too few of them.
+    fn adjust(&self, controller: &mut Controller, now: Instant) {
+        controller.adjusted = now;
+        while controller
+            .samples
+            .front()
+            .is_some_and(|(at, _)| now.saturating_duration_since(*at) > self.window)
+        {
+            controller.samples.pop_front();
+        }
+        let p99 = percentile(&controller.samples, self.min_samples);
+        let before = controller.stats.fraction;
+        controller.stats.p99 = p99;
+        controller.stats.fraction = match p99 {
+            Some(p99) if p99 > self.target => (before + self.step).min(self.max_fraction),
+            _ => (before - self.step).max(0.0),
+        };
+        if controller.stats.fraction < f64::EPSILON {
+            controller.stats.fraction = 0.0;
+            controller.debt = 0.0;
+        }
+        let after = controller.stats.fraction;
+        if before == 0.0 && after > 0.0 {
+            log::warn(format_args!(
+                "shedding load: p99 latency {:?} exceeds {:?}",
+                p99.unwrap_or_default(),
+                self.target
+            ));
+        } else if before > 0.0 && after == 0.0 {
+            log::info("stopped shedding load");
+        }
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Controller> {
+        self.controller
+            .lock()
+            .unwrap_or_else(|error| error.into_inner())
+    }
+}
+
+impl fmt::Debug for LoadShedder {
+    fn fmt(&self, formatter: &mut fmt::Formatter<'_>) -> fmt::Result {
+        formatter
+            .debug_struct("LoadShedder")
+            .field("target", &self.target)
+            .field("window", &self.window)
+            .field("expensive", &self.expensive)
+            .field("exempt", &self.exempt)
+            .finish_non_exhaustive()
+    }
+}
+
+/// The 99th percentile of latencies, or `None` with fewer than `min_samples` of them.
+fn percentile(samples: &VecDeque<(Instant, Duration)>, min_samples: usize) -> Option<Duration> {
+    if samples.is_empty() || samples.len() < min_samples {
+        return None;
+    }
+    let mut latencies: Vec<Duration> = samples.iter().map(|(_, latency)| *latency).collect();
+    let rank = (latencies.len() * 99).div_ceil(100) - 1;
+    Some(*latencies.select_nth_unstable(rank).1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// It sheds a growing share of requests and the expensive routes while the p99 latency
+    /// exceeds the target, and recovers once it is back below
+    #[test]
+    fn load_shedder() {
+        let shedder = LoadShedder::new(Duration::from_millis(100))
+            .with_steps(Duration::from_secs(1), 0.5, 0.5)
+            .with_expensive("/reports")
+            .with_exempt("/health");
+        let request = |path: &str| Request {
+            target: path.parse().unwrap(),
+            ..Request::default()
+        };
+        let start = Instant::now();
+        for _ in 0..20 {
+            shedder.observe_at(Duration::from_millis(500), start);
+        }
+        assert_eq!(None, shedder.check(&request("/reports")));
+        shedder.observe_at(Duration::from_millis(500), start + Duration::from_secs(1));
+        let stats = shedder.stats();
+        assert_eq!(0.5, stats.fraction);
+        assert_eq!(Some(Duration::from_millis(500)), stats.p99);
+
+        let shed = (0..10)
+            .filter(|_| shedder.check(&request("/users")).is_some())
+            .count();
+        assert_eq!(5, shed);
+        let refused = shedder.check(&request("/reports/daily")).unwrap();
+        assert_eq!(StatusCode::SERVICE_UNAVAILABLE, refused.status);
+        assert_eq!(None, shedder.check(&request("/health")));
+        assert_eq!(6, shedder.stats().shed);
+        assert!(shedder.check(&request("/reports/../reports/x")).is_some());
+        assert_eq!(7, shedder.stats().shed);
+
+        let later = start + Duration::from_secs(12);
+        for _ in 0..20 {
+            shedder.observe_at(Duration::from_millis(10), later);
+        }
+        assert_eq!(0.0, shedder.stats().fraction);
+        assert_eq!(None, shedder.check(&request("/reports")));
+    }
+
+    /// It exempts the paths below an exempt prefix by whole segments of the normalized path
+    #[test]
+    fn exempt() {
+        let shedder = LoadShedder::new(Duration::from_millis(100))
+            .with_steps(Duration::from_secs(1), 1.0, 1.0)
+            .with_exempt("/health/");
+        let start = Instant::now();
+        for _ in 0..20 {
+            shedder.observe_at(Duration::from_millis(500), start);
+        }
+        shedder.observe_at(Duration::from_millis(500), start + Duration::from_secs(1));
+        let check = |path: &str| {
+            let request = Request {
+                target: path.parse().unwrap(),
+                ..Request::default()
+            };
+            shedder.check_at(&request, start + Duration::from_secs(1))
+        };
+        assert_eq!(None, check("/health"));
+        assert_eq!(None, check("/health/live"));
+        assert_eq!(None, check("/status/../health"));
+        assert!(check("/healthz-expensive").is_some());
+        assert!(check("/health/../admin").is_some());
+        assert!(check("/health/%2e%2e/admin").is_some());
+        assert!(check("/health/%zz").is_some());
+    }
+
+    /// It recovers from shedding every request although none of them are observed
+    #[test]
+    fn recover() {
+        let shedder = LoadShedder::new(Duration::from_millis(100)).with_steps(
+            Duration::from_secs(1),
+            1.0,
+            1.0,
+        );
+        let request = Request::default();
+        let start = Instant::now();
+        for _ in 0..20 {
+            shedder.observe_at(Duration::from_millis(500), start);
+        }
+        shedder.observe_at(Duration::from_millis(500), start + Duration::from_secs(1));
+        assert_eq!(1.0, shedder.stats().fraction);
+        assert!(shedder
+            .check_at(&request, start + Duration::from_secs(2))
+            .is_some());
+
+        let later = start + Duration::from_secs(12);
+        assert_eq!(None, shedder.check_at(&request, later));
+        assert_eq!(0.0, shedder.stats().fraction);
+    }
+}
@@ -0,0 +1,170 @@
+//! Traffic splitting: a percentage of clients goes to an alternate handler, e.g. the upstreams
+//! of a new backend version, for A/B tests and gradual rollouts. Each client is assigned a
+//! bucket from 0 to 99 that stays the same across requests, and the buckets below the
+//! percentage go to the alternate, so raising the percentage only moves more clients over.
+
+use crate::log;
+use crate::proxy::fnv1a;
+use crate::request::Request;
+use crate::response::Response;
+use crate::router::Handler;
+use async_trait::async_trait;
+use std::sync::Arc;
+use tokio::io;
+
+/// How a client keeps its bucket across requests.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Stickiness {
+    /// By a hash of the client IP address.
+    ClientAddress,
+    /// By a cookie of this name holding a random bucket, set on the first response.
+    Cookie(String),
+}
+
+/// Which share of clients goes to the alternate.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SplitRule {
+    /// The percentage of clients sent to the alternate, from 0 to 100.
+    pub percent: u8,
+    /// How clients keep their bucket.
+    pub stickiness: Stickiness,
+}
+
+impl SplitRule {
+    /// Assigns a request to a bucket.
+    ///
+    /// # Arguments
+    ///
+    /// * `request`: The incoming request.
+    ///
+    /// # Returns
+    ///
+    /// Whether the request goes to the alternate, and the cookie to set when
+    /// [`Stickiness::Cookie`] assigned a new bucket.
+    pub fn assign(&self, request: &Request) -> (bool, Option<String>) {
+        let (bucket, cookie) = match &self.stickiness {
+            Stickiness::ClientAddress => {
+                let bucket = match request.peer {
+                    Some(peer) => fnv1a(peer.ip().to_string().as_bytes()) % 100,
+                    None => rand::random::<u64>() % 100,
+                };
+                (bucket as u8, None)
+            }
+            Stickiness::Cookie(name) => {
+                match request
+                    .cookie(name)
+                    .and_then(|bucket| bucket.parse::<u8>().ok())
+                    .filter(|bucket| *bucket < 100)
+                {
+                    Some(bucket) => (bucket, None),
+                    None => {
+                        let bucket = (rand::random::<u64>() % 100) as u8;
+                        let cookie = format!("{}={}; Path=/; HttpOnly; SameSite=Lax", name, bucket);
+                        (bucket, Some(cookie))
+                    }
+                }
+            }
+        };
+        (bucket < self.percent, cookie)
+    }
+}
+
+/// A handler sending a percentage of clients to an alternate handler and the others to a
+/// primary one, for registering on a [`crate::router::Router`]. The reverse proxy has the same
+/// splitting built in, see [`crate::proxy::Proxy::with_split`].
+#[derive(Clone)]
+pub struct Split {
+    rule: SplitRule,
+    primary: Arc<dyn Handler>,
+    alternate: Arc<dyn Handler>,
+}
+
+impl Split {
+    /// Creates a split.
+    ///
+    /// # Arguments
+    ///
+    /// * `rule`: The share of clients sent to the alternate and how they stick to it.
+    /// * `primary`: The handler for the other clients.
+    /// * `alternate`: The handler for the share of clients.
+    ///
+    /// # Returns
+    ///
+    /// The splitting handler.
+    pub fn new(
+        rule: SplitRule,
+        primary: impl Handler + 'static,
+        alternate: impl Handler + 'static,
+    ) -> Split {
+        Split {
+            rule,
+            primary: Arc::new(primary),
+            alternate: Arc::new(alternate),
+        }
+    }
+}
+
+#[async_trait]
+impl Handler for Split {
+    async fn call(&self, request: Request) -> io::Result<Response> {
+        let (alternate, cookie) = self.rule.assign(&request);
+        let response = if alternate {
+            log::record("split", "alternate");
+            self.alternate.call(request).await?
+        } else {
+            self.primary.call(request).await?
+        };
+        Ok(match cookie {
+            Some(cookie) => response.with_header("Set-Cookie", cookie),
+            None => response,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::status::StatusCode;
+
+    /// It keeps clients in their bucket and sends the buckets below the percentage over
+    #[tokio::test]
+    async fn split() {
+        let rule = SplitRule {
+            percent: 30,
+            stickiness: Stickiness::Cookie("bucket".to_string()),
+        };
+        let cookie = |bucket: &str| Request {
+            headers: [("Cookie", format!("bucket={}", bucket))]
+                .into_iter()
+                .collect(),
+            ..Request::default()
+        };
+        assert_eq!((true, None), rule.assign(&cookie("29")));
+        assert_eq!((false, None), rule.assign(&cookie("30")));
+        let (_, set) = rule.assign(&cookie("100"));
+        assert!(set.unwrap().starts_with("bucket="));
+
+        let split = Split::new(
+            SplitRule {
+                percent: 50,
+                stickiness: Stickiness::ClientAddress,
+            },
+            |_| async { Ok(Response::new(StatusCode::OK, "primary")) },
+            |_| async { Ok(Response::new(StatusCode::OK, "alternate")) },
+        );
+        let mut bodies = Vec::new();
+        for address in ["192.0.2.1:1", "192.0.2.1:2"] {
+            let request = Request {
+                peer: Some(address.parse().unwrap()),
+                ..Request::default()
+            };
+            bodies.push(split.call(request).await.unwrap().body);
+        }
+        assert_eq!(bodies[0], bodies[1]);
+        let everyone = SplitRule {
+            percent: 100,
+            stickiness: Stickiness::ClientAddress,
+        };
+        assert_eq!((true, None), everyone.assign(&Request::default()));
+    }
+}
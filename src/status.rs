@@ -0,0 +1,155 @@
+use std::fmt;
+
+/// An HTTP status code, e.g. [`StatusCode::NOT_FOUND`].
+///
+/// Standard codes are available as constants with their canonical reason phrase; any other
+/// three-digit code can be created through [`StatusCode::from_u16`] and is sent without one.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct StatusCode(u16);
+
+/// Declares the standard status codes and their canonical reason phrases in one table.
+macro_rules! status_codes {
+    ($($name:ident = $code:literal, $reason:literal;)+) => {
+        impl StatusCode {
+            $(
+                #[doc = concat!("`", $code, " ", $reason, "`")]
+                pub const $name: StatusCode = StatusCode($code);
+            )+
+
+            /// The reason phrase that RFC 9110 and its extensions register for the code.
+            ///
+            /// # Returns
+            ///
+            /// The phrase, e.g. `Not Found`, or `None` for an unregistered code.
+            pub fn canonical_reason(&self) -> Option<&'static str> {
+                match self.0 {
+                    $($code => Some($reason),)+
+                    _ => None,
+                }
+            }
+        }
+    };
+}
+
+status_codes! {
+    CONTINUE = 100, "Continue";
+    SWITCHING_PROTOCOLS = 101, "Switching Protocols";
+    OK = 200, "OK";
+    CREATED = 201, "Created";
+    ACCEPTED = 202, "Accepted";
+    NON_AUTHORITATIVE_INFORMATION = 203, "Non-Authoritative Information";
+    NO_CONTENT = 204, "No Content";
+    PARTIAL_CONTENT = 206, "Partial Content";
+    MULTI_STATUS = 207, "Multi-Status";
+    MULTIPLE_CHOICES = 300, "Multiple Choices";
+    MOVED_PERMANENTLY = 301, "Moved Permanently";
+    FOUND = 302, "Found";
+    SEE_OTHER = 303, "See Other";
+    NOT_MODIFIED = 304, "Not Modified";
+    TEMPORARY_REDIRECT = 307, "Temporary Redirect";
+    PERMANENT_REDIRECT = 308, "Permanent Redirect";
+    BAD_REQUEST = 400, "Bad Request";
+    UNAUTHORIZED = 401, "Unauthorized";
+    FORBIDDEN = 403, "Forbidden";
+    NOT_FOUND = 404, "Not Found";
+    METHOD_NOT_ALLOWED = 405, "Method Not Allowed";
+    NOT_ACCEPTABLE = 406, "Not Acceptable";
+    REQUEST_TIMEOUT = 408, "Request Timeout";
+    CONFLICT = 409, "Conflict";
+    GONE = 410, "Gone";
+    LENGTH_REQUIRED = 411, "Length Required";
+    PRECONDITION_FAILED = 412, "Precondition Failed";
+    PAYLOAD_TOO_LARGE = 413, "Payload Too Large";
+    URI_TOO_LONG = 414, "URI Too Long";
+    UNSUPPORTED_MEDIA_TYPE = 415, "Unsupported Media Type";
+    RANGE_NOT_SATISFIABLE = 416, "Range Not Satisfiable";
+    LOCKED = 423, "Locked";
+    TOO_MANY_REQUESTS = 429, "Too Many Requests";
+    REQUEST_HEADER_FIELDS_TOO_LARGE = 431, "Request Header Fields Too Large";
+    INTERNAL_SERVER_ERROR = 500, "Internal Server Error";
+    NOT_IMPLEMENTED = 501, "Not Implemented";
+    BAD_GATEWAY = 502, "Bad Gateway";
+    SERVICE_UNAVAILABLE = 503, "Service Unavailable";
+    GATEWAY_TIMEOUT = 504, "Gateway Timeout";
+    HTTP_VERSION_NOT_SUPPORTED = 505, "HTTP Version Not Supported";
+}
+
+impl StatusCode {
+    /// Creates a status code from its number.
+    ///
+    /// # Arguments
+    ///
+    /// * `code`: The three-digit code, standard or not.
+    ///
+    /// # Returns
+    ///
+    /// The status code, or `None` outside `100..=999`.
+    pub fn from_u16(code: u16) -> Option<StatusCode> {
+        if (100..=999).contains(&code) {
+            Some(StatusCode(code))
+        } else {
+            None
+        }
+    }
+
+    /// The numeric code, e.g. `404`.
+    pub fn as_u16(&self) -> u16 {
+        self.0
+    }
+
+    /// Checks for a `1xx` code.
+    pub fn is_informational(&self) -> bool {
+        (100..200).contains(&self.0)
+    }
+
+    /// Checks for a `2xx` code.
+    pub fn is_success(&self) -> bool {
+        (200..300).contains(&self.0)
+    }
+
+    /// Checks for a `3xx` code.
+    pub fn is_redirection(&self) -> bool {
+        (300..400).contains(&self.0)
+    }
+
+    /// Checks for a `4xx` code.
+    pub fn is_client_error(&self) -> bool {
+        (400..500).contains(&self.0)
+    }
+
+    /// Checks for a `5xx` code.
+    pub fn is_server_error(&self) -> bool {
+        (500..600).contains(&self.0)
+    }
+}
+
+/// Formats the code followed by its canonical reason phrase, if any, as in a status line.
+impl fmt::Display for StatusCode {
+    fn fmt(&self, formatter: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.canonical_reason() {
+            Some(reason) => write!(formatter, "{} {}", self.0, reason),
+            None => write!(formatter, "{}", self.0),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// It formats standard and custom codes and classifies them
+    #[test]
+    fn codes() {
+        assert_eq!("404 Not Found", StatusCode::NOT_FOUND.to_string());
+        let custom = StatusCode::from_u16(599).unwrap();
+        assert_eq!("599", custom.to_string());
+        assert_eq!(None, custom.canonical_reason());
+        assert!(custom.is_server_error());
+        assert!(StatusCode::NO_CONTENT.is_success());
+        assert!(StatusCode::SEE_OTHER.is_redirection());
+        assert!(StatusCode::LOCKED.is_client_error());
+        assert!(StatusCode::CONTINUE.is_informational());
+        assert_eq!(None, StatusCode::from_u16(42));
+        assert_eq!(Some(StatusCode::OK), StatusCode::from_u16(200));
+    }
+}
@@ -0,0 +1,1526 @@
+mod ocsp;
+mod ticket;
+
+use crate::log;
+use crate::request::{ClientCertificate, Request};
+use crate::response::Response;
+use crate::router::{Handler, Router};
+use crate::status::StatusCode;
+use async_trait::async_trait;
+use rustls::crypto::CryptoProvider;
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use rustls::server::{
+    ClientHello, NoServerSessionStorage, ResolvesServerCert, ServerSessionMemoryCache,
+    StoresServerSessions, WebPkiClientVerifier,
+};
+use rustls::sign::CertifiedKey;
+use rustls::{RootCertStore, ServerConfig, SupportedProtocolVersion};
+use std::collections::HashMap;
+use std::fmt;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use ticket::Ticketer;
+pub use ticket::{TicketKeys, SHARED_TICKET_LIFETIME};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::{io, task, time};
+use tokio_rustls::server::TlsStream;
+use tokio_rustls::TlsAcceptor;
+use x509_parser::extensions::GeneralName;
+use x509_parser::prelude::{FromDer, X509Certificate};
+
+/// TLS settings for a listener with a certificate that can be replaced while the server runs.
+///
+/// The certificate chain and private key are read from PEM files. [`Tls::reload`] reads them
+/// again and swaps the rustls configuration in one step: handshakes started afterwards present
+/// the new certificate, while established sessions keep the configuration they were accepted
+/// with. Clones share the same certificate.
+///
+/// Further certificates added with [`Tls::with_host`] are presented to clients asking for their
+/// host name through SNI, so several domains can share one listener. Clients asking for no or
+/// another name see the default certificate.
+///
+/// Certificates set up with [`Tls::with_ocsp_stapling`] carry an OCSP response from their
+/// authority in the handshake, so clients need not ask the authority themselves.
+///
+/// Returning clients resume their session without a full handshake, through a session cache and
+/// session tickets, see [`Tls::with_session_cache`] and [`Tls::with_session_tickets`]. Both
+/// survive reloads.
+///
+/// Clients offering only the `acme-tls/1` protocol are shown the certificate registered with
+/// [`Tls::set_challenge_certificate`] instead, which answers ACME TLS-ALPN-01 challenges.
+#[derive(Clone)]
+pub struct Tls {
+    address: SocketAddr,
+    files: Files,
+    watch_interval: Option<Duration>,
+    handshake_timeout: Option<Duration>,
+    current: Arc<RwLock<Arc<ServerConfig>>>,
+    /// The modification times of the files when they were last read.
+    modified: Arc<Mutex<Option<Vec<SystemTime>>>>,
+    challenges: Challenges,
+    resumption: Resumption,
+}
+
+/// The files, read again on every [`Tls::reload`], and the settings the rustls configuration is
+/// built from.
+#[derive(Clone, Debug)]
+struct Files {
+    certificate: PathBuf,
+    key: PathBuf,
+    hosts: Vec<Host>,
+    /// The CA bundle verifying client certificates and whether clients must present one.
+    client_auth: Option<(PathBuf, ClientAuth)>,
+    /// The application protocols offered through ALPN, most preferred first.
+    protocols: Vec<String>,
+    /// How often the OCSP response stapled to the default certificate is fetched again.
+    ocsp_refresh: Option<Duration>,
+    /// The number of sessions cached for resumption.
+    session_cache: usize,
+    tickets: TicketKeys,
+    /// The lowest and highest protocol versions negotiated.
+    versions: (TlsVersion, TlsVersion),
+    /// The names of the cipher suites offered, most preferred first, or `None` for all.
+    cipher_suites: Option<Vec<String>>,
+    /// The names of the key exchange groups offered, most preferred first, or `None` for all.
+    kx_groups: Option<Vec<String>>,
+}
+
+/// The session cache and rotating ticket keys, kept across reloads so that clients keep
+/// resuming their sessions.
+#[derive(Clone, Debug)]
+struct Resumption {
+    cache: Arc<dyn StoresServerSessions>,
+    /// The keys of [`TicketKeys::Rotating`], unused otherwise.
+    ticketer: Arc<Ticketer>,
+}
+
+/// A certificate presented to clients asking for a host name.
+#[derive(Clone, Debug)]
+struct Host {
+    /// The lowercase host name, or `*.` followed by a domain for its subdomains.
+    name: String,
+    certificate: PathBuf,
+    key: PathBuf,
+    ocsp_refresh: Option<Duration>,
+}
+
+/// Whether clients of a listener verifying client certificates must present one.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ClientAuth {
+    /// Handshakes without a valid client certificate fail.
+    #[default]
+    Required,
+    /// Clients may connect without a certificate, but one they present must be valid.
+    Optional,
+}
+
+impl ClientAuth {
+    /// The lowercase name, e.g. `required`.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ClientAuth::Required => "required",
+            ClientAuth::Optional => "optional",
+        }
+    }
+}
+
+impl FromStr for ClientAuth {
+    type Err = ();
+
+    /// Parses a mode name, ignoring ASCII case.
+    fn from_str(name: &str) -> Result<ClientAuth, ()> {
+        [ClientAuth::Required, ClientAuth::Optional]
+            .into_iter()
+            .find(|mode| mode.as_str().eq_ignore_ascii_case(name.trim()))
+            .ok_or(())
+    }
+}
+
+/// A version of the TLS protocol.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum TlsVersion {
+    /// TLS 1.2, see RFC 5246.
+    Tls12,
+    /// TLS 1.3, see RFC 8446.
+    Tls13,
+}
+
+impl TlsVersion {
+    /// The version number, e.g. `1.2`.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            TlsVersion::Tls12 => "1.2",
+            TlsVersion::Tls13 => "1.3",
+        }
+    }
+
+    /// The rustls protocol version.
+    fn supported(&self) -> &'static SupportedProtocolVersion {
+        match self {
+            TlsVersion::Tls12 => &rustls::version::TLS12,
+            TlsVersion::Tls13 => &rustls::version::TLS13,
+        }
+    }
+}
+
+impl FromStr for TlsVersion {
+    type Err = ();
+
+    /// Parses a version number, with or without a `TLSv` prefix, e.g. `1.3` or `TLSv1.3`.
+    fn from_str(name: &str) -> Result<TlsVersion, ()> {
+        let name = name.trim();
+        let number = match name.get(..4) {
+            Some(prefix) if prefix.eq_ignore_ascii_case("tlsv") => &name[4..],
+            _ => name,
+        };
+        [TlsVersion::Tls12, TlsVersion::Tls13]
+            .into_iter()
+            .find(|version| version.as_str() == number)
+            .ok_or(())
+    }
+}
+
+/// The TLS-ALPN-01 challenge certificates by domain.
+type Challenges = Arc<RwLock<HashMap<String, Arc<CertifiedKey>>>>;
+
+/// The protocol ACME servers offer when validating a TLS-ALPN-01 challenge.
+pub const ACME_TLS_ALPN: &[u8] = b"acme-tls/1";
+
+/// The application protocols the server has a handler for, which [`Tls::with_alpn_protocols`]
+/// may offer, `http/1.1` by default. HTTP/2 is relayed to [`crate::config::Config::grpc`] with
+/// the `grpc` feature.
+#[cfg(not(feature = "grpc"))]
+pub const PROTOCOLS: &[&str] = &["http/1.1"];
+#[cfg(feature = "grpc")]
+pub const PROTOCOLS: &[&str] = &["http/1.1", "h2"];
+
+impl fmt::Debug for Tls {
+    fn fmt(&self, formatter: &mut fmt::Formatter<'_>) -> fmt::Result {
+        formatter
+            .debug_struct("Tls")
+            .field("address", &self.address)
+            .field("certificate", &self.files.certificate)
+            .field("key", &self.files.key)
+            .field("hosts", &self.files.hosts)
+            .field("client_auth", &self.files.client_auth)
+            .field("protocols", &self.files.protocols)
+            .field("ocsp_refresh", &self.files.ocsp_refresh)
+            .field("session_cache", &self.files.session_cache)
+            .field("tickets", &self.files.tickets)
+            .field("versions", &self.files.versions)
+            .field("cipher_suites", &self.files.cipher_suites)
+            .field("kx_groups", &self.files.kx_groups)
+            .field("watch_interval", &self.watch_interval)
+            .field("handshake_timeout", &self.handshake_timeout)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Tls {
+    /// Reads a certificate chain and private key for a listener on `127.0.0.1:7443` that offers
+    /// HTTP/1.1, checks the files for changes every minute, caches 256 sessions, and rotates
+    /// its ticket keys every 6 hours.
+    ///
+    /// # Arguments
+    ///
+    /// * `certificate`: A PEM file with the certificate chain, leaf first.
+    /// * `key`: A PEM file with the private key of the leaf certificate.
+    ///
+    /// # Returns
+    ///
+    /// The settings presenting the certificate.
+    ///
+    /// # Errors
+    ///
+    /// Captures IO errors from reading the files, and returns [`io::ErrorKind::InvalidData`] when
+    /// they hold no usable certificate or key.
+    pub fn new(certificate: impl Into<PathBuf>, key: impl Into<PathBuf>) -> io::Result<Tls> {
+        let files = Files {
+            certificate: certificate.into(),
+            key: key.into(),
+            hosts: Vec::new(),
+            client_auth: None,
+            protocols: vec!["http/1.1".to_string()],
+            ocsp_refresh: None,
+            session_cache: 256,
+            tickets: TicketKeys::default(),
+            versions: (TlsVersion::Tls12, TlsVersion::Tls13),
+            cipher_suites: None,
+            kx_groups: None,
+        };
+        let modified = files.modified().ok();
+        let challenges = Challenges::default();
+        let resumption = Resumption {
+            cache: ServerSessionMemoryCache::new(files.session_cache),
+            ticketer: Arc::new(Ticketer::rotating(ticket::ROTATION)?),
+        };
+        let server_config = files.load(&challenges, &resumption)?;
+        Ok(Tls {
+            address: SocketAddr::from(([127, 0, 0, 1], 7443)),
+            files,
+            watch_interval: Some(Duration::from_secs(60)),
+            handshake_timeout: Some(Duration::from_secs(10)),
+            current: Arc::new(RwLock::new(Arc::new(server_config))),
+            modified: Arc::new(Mutex::new(modified)),
+            challenges,
+            resumption,
+        })
+    }
+
+    /// Adds a certificate presented to clients asking for a host name.
+    ///
+    /// # Arguments
+    ///
+    /// * `name`: The host name, e.g. `example.org`, or `*.example.org` for every direct
+    ///   subdomain without a certificate of its own.
+    /// * `certificate`: A PEM file with the certificate chain, leaf first.
+    /// * `key`: A PEM file with the private key of the leaf certificate.
+    ///
+    /// # Returns
+    ///
+    /// The settings presenting the certificate as well.
+    ///
+    /// # Errors
+    ///
+    /// The errors of [`Tls::new`].
+    pub fn with_host(
+        mut self,
+        name: &str,
+        certificate: impl Into<PathBuf>,
+        key: impl Into<PathBuf>,
+    ) -> io::Result<Tls> {
+        let name = name.trim().to_ascii_lowercase();
+        self.files.hosts.retain(|host| host.name != name);
+        self.files.hosts.push(Host {
+            name,
+            certificate: certificate.into(),
+            key: key.into(),
+            ocsp_refresh: None,
+        });
+        self.reload()?;
+        Ok(self)
+    }
+
+    /// Asks clients for a certificate and verifies it against a CA bundle, making the identity
+    /// available as [`Request::client_certificate`].
+    ///
+    /// # Arguments
+    ///
+    /// * `ca`: A PEM file with the certificates of the authorities issuing client certificates.
+    /// * `mode`: Whether clients must present a certificate.
+    ///
+    /// # Returns
+    ///
+    /// The settings verifying clients.
+    ///
+    /// # Errors
+    ///
+    /// The errors of [`Tls::new`], and [`io::ErrorKind::InvalidData`] when the bundle holds no
+    /// usable certificate.
+    pub fn with_client_auth(mut self, ca: impl Into<PathBuf>, mode: ClientAuth) -> io::Result<Tls> {
+        self.files.client_auth = Some((ca.into(), mode));
+        self.reload()?;
+        Ok(self)
+    }
+
+    /// Sets the application protocols offered to clients through ALPN. Clients that offer none of
+    /// them fail the handshake, while clients not using ALPN at all are served HTTP/1.1.
+    ///
+    /// # Arguments
+    ///
+    /// * `protocols`: The protocol names, most preferred first, e.g. `["http/1.1"]`, or none to
+    ///   skip ALPN, which also rules out ACME TLS-ALPN-01 challenges.
+    ///
+    /// # Returns
+    ///
+    /// The settings offering the protocols.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`io::ErrorKind::Unsupported`] for a protocol missing from [`PROTOCOLS`].
+    pub fn with_alpn_protocols(mut self, protocols: &[&str]) -> io::Result<Tls> {
+        if let Some(protocol) = protocols
+            .iter()
+            .find(|protocol| !PROTOCOLS.contains(protocol))
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                format!("the server cannot serve the protocol {}", protocol),
+            ));
+        }
+        self.files.protocols = protocols
+            .iter()
+            .map(|protocol| protocol.to_string())
+            .collect();
+        self.reload()?;
+        Ok(self)
+    }
+
+    /// Staples an OCSP response to the handshakes presenting a certificate and fetches a fresh
+    /// one from the responder named in the certificate on a schedule, see
+    /// [`Tls::spawn_ocsp_refresh`].
+    ///
+    /// The response is kept next to the certificate chain, e.g. in `cert.ocsp` for `cert.pem`,
+    /// and stapled as soon as it exists. The chain must hold the issuer of the certificate
+    /// after the certificate itself.
+    ///
+    /// # Arguments
+    ///
+    /// * `host`: The name given to [`Tls::with_host`], or `None` for the default certificate.
+    /// * `refresh`: The time between two fetches, which should be well below the validity of
+    ///   the responses, e.g. an hour.
+    ///
+    /// # Returns
+    ///
+    /// The settings stapling responses to the certificate.
+    ///
+    /// # Errors
+    ///
+    /// The errors of [`Tls::reload`], and [`io::ErrorKind::InvalidInput`] when no certificate
+    /// was added for the host.
+    pub fn with_ocsp_stapling(mut self, host: Option<&str>, refresh: Duration) -> io::Result<Tls> {
+        let ocsp_refresh = match host {
+            None => &mut self.files.ocsp_refresh,
+            Some(name) => {
+                let name = name.trim().to_ascii_lowercase();
+                let host = self.files.hosts.iter_mut().find(|host| host.name == name);
+                match host {
+                    Some(host) => &mut host.ocsp_refresh,
+                    None => {
+                        return Err(io::Error::new(
+                            io::ErrorKind::InvalidInput,
+                            format!("no TLS certificate for the host {}", name),
+                        ))
+                    }
+                }
+            }
+        };
+        *ocsp_refresh = Some(refresh);
+        self.reload()?;
+        Ok(self)
+    }
+
+    /// Sets how many sessions are cached for clients resuming them by session ID, or by a
+    /// TLS 1.3 ticket when [`TicketKeys::Disabled`] is used. The cache replaces the current
+    /// one, forgetting its sessions.
+    ///
+    /// # Arguments
+    ///
+    /// * `capacity`: The number of sessions, or `0` to cache none.
+    ///
+    /// # Returns
+    ///
+    /// The settings with the cache.
+    ///
+    /// # Errors
+    ///
+    /// The errors of [`Tls::reload`].
+    pub fn with_session_cache(mut self, capacity: usize) -> io::Result<Tls> {
+        self.files.session_cache = capacity;
+        self.resumption.cache = match capacity {
+            0 => Arc::new(NoServerSessionStorage {}),
+            capacity => ServerSessionMemoryCache::new(capacity),
+        };
+        self.reload()?;
+        Ok(self)
+    }
+
+    /// Sets the keys encrypting session tickets, which let clients resume their session on
+    /// any instance holding the key, without a server-side cache.
+    ///
+    /// # Arguments
+    ///
+    /// * `keys`: Where the keys come from. [`TicketKeys::Shared`] keys are read again with the
+    ///   certificates, so replacing the file rotates them.
+    ///
+    /// # Returns
+    ///
+    /// The settings issuing tickets with the keys.
+    ///
+    /// # Errors
+    ///
+    /// The errors of [`Tls::reload`], and [`io::ErrorKind::InvalidData`] when the key file holds
+    /// no usable key.
+    pub fn with_session_tickets(mut self, keys: TicketKeys) -> io::Result<Tls> {
+        if let TicketKeys::Rotating(rotation) = keys {
+            self.resumption.ticketer = Arc::new(Ticketer::rotating(rotation)?);
+        }
+        self.files.tickets = keys;
+        self.reload()?;
+        Ok(self)
+    }
+
+    /// Limits the protocol versions negotiated with clients, e.g. to TLS 1.2 and later for
+    /// compliance requirements. Both TLS 1.2 and 1.3 are negotiated by default.
+    ///
+    /// # Arguments
+    ///
+    /// * `min`: The lowest version.
+    /// * `max`: The highest version.
+    ///
+    /// # Returns
+    ///
+    /// The settings negotiating the versions.
+    ///
+    /// # Errors
+    ///
+    /// The errors of [`Tls::reload`], and [`io::ErrorKind::InvalidInput`] when `min` is above
+    /// `max` or none of the cipher suites fits the versions.
+    pub fn with_versions(mut self, min: TlsVersion, max: TlsVersion) -> io::Result<Tls> {
+        if min > max {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "the minimum TLS version {} is above the maximum {}",
+                    min.as_str(),
+                    max.as_str()
+                ),
+            ));
+        }
+        self.files.versions = (min, max);
+        self.reload()?;
+        Ok(self)
+    }
+
+    /// Limits the cipher suites offered to clients. All suites of the cryptography provider,
+    /// which are safe, are offered by default.
+    ///
+    /// # Arguments
+    ///
+    /// * `names`: The IANA names of the suites, most preferred first, e.g.
+    ///   `["TLS13_AES_256_GCM_SHA384", "TLS_ECDHE_RSA_WITH_AES_256_GCM_SHA384"]`.
+    ///
+    /// # Returns
+    ///
+    /// The settings offering the suites.
+    ///
+    /// # Errors
+    ///
+    /// The errors of [`Tls::with_versions`], and [`io::ErrorKind::Unsupported`] for a suite the
+    /// provider does not implement.
+    pub fn with_cipher_suites(mut self, names: &[&str]) -> io::Result<Tls> {
+        let all = rustls::crypto::ring::ALL_CIPHER_SUITES.iter();
+        let names = supported_names(names, all.map(|suite| suite.suite().as_str()))?;
+        self.files.cipher_suites = Some(names);
+        self.reload()?;
+        Ok(self)
+    }
+
+    /// Limits the key exchange groups offered to clients. All groups of the cryptography
+    /// provider are offered by default.
+    ///
+    /// # Arguments
+    ///
+    /// * `names`: The IANA names of the groups, most preferred first, e.g.
+    ///   `["X25519", "secp256r1"]`.
+    ///
+    /// # Returns
+    ///
+    /// The settings offering the groups.
+    ///
+    /// # Errors
+    ///
+    /// The errors of [`Tls::with_cipher_suites`].
+    pub fn with_kx_groups(mut self, names: &[&str]) -> io::Result<Tls> {
+        let all = rustls::crypto::ring::ALL_KX_GROUPS.iter();
+        let names = supported_names(names, all.map(|group| group.name().as_str()))?;
+        self.files.kx_groups = Some(names);
+        self.reload()?;
+        Ok(self)
+    }
+
+    /// Sets the address the TLS listener binds to.
+    ///
+    /// # Arguments
+    ///
+    /// * `address`: The address, e.g. `0.0.0.0:443`.
+    ///
+    /// # Returns
+    ///
+    /// The settings with the address.
+    pub fn with_address(mut self, address: SocketAddr) -> Tls {
+        self.address = address;
+        self
+    }
+
+    /// Sets how often [`Tls::spawn_watch`] checks the files for changes.
+    ///
+    /// # Arguments
+    ///
+    /// * `interval`: The time between two checks, or `None` to reload only when asked to.
+    ///
+    /// # Returns
+    ///
+    /// The settings with the interval.
+    pub fn with_watch_interval(mut self, interval: Option<Duration>) -> Tls {
+        self.watch_interval = interval;
+        self
+    }
+
+    /// Sets how long a client may take to complete the handshake, 10 seconds by default, so
+    /// that clients which never send a `ClientHello` do not hold a connection forever.
+    ///
+    /// # Arguments
+    ///
+    /// * `timeout`: The longest handshake, or `None` for no limit.
+    ///
+    /// # Returns
+    ///
+    /// The settings with the timeout.
+    pub fn with_handshake_timeout(mut self, timeout: Option<Duration>) -> Tls {
+        self.handshake_timeout = timeout;
+        self
+    }
+
+    /// The address the TLS listener binds to.
+    pub fn address(&self) -> SocketAddr {
+        self.address
+    }
+
+    /// The rustls configuration handshakes use right now.
+    pub fn server_config(&self) -> Arc<ServerConfig> {
+        Arc::clone(
+            &self
+                .current
+                .read()
+                .unwrap_or_else(|error| error.into_inner()),
+        )
+    }
+
+    /// Reads the certificate and key files again, including those of [`Tls::with_host`], the
+    /// CA bundle of [`Tls::with_client_auth`], the OCSP responses of
+    /// [`Tls::with_ocsp_stapling`], and shared ticket keys, and uses them in new handshakes. The current
+    /// certificates stay in use when a file is invalid.
+    ///
+    /// # Returns
+    ///
+    /// `Ok(())` once the new certificate is in use.
+    ///
+    /// # Errors
+    ///
+    /// The errors of [`Tls::new`].
+    pub fn reload(&self) -> io::Result<()> {
+        let modified = self.files.modified().ok();
+        let server_config = self.files.load(&self.challenges, &self.resumption)?;
+        *self
+            .current
+            .write()
+            .unwrap_or_else(|error| error.into_inner()) = Arc::new(server_config);
+        *self
+            .modified
+            .lock()
+            .unwrap_or_else(|error| error.into_inner()) = modified;
+        Ok(())
+    }
+
+    /// Reloads the certificate when its files changed since they were last read.
+    ///
+    /// # Returns
+    ///
+    /// Whether a changed certificate is now in use.
+    ///
+    /// # Errors
+    ///
+    /// The errors of [`Tls::reload`].
+    pub fn reload_if_modified(&self) -> io::Result<bool> {
+        let current = self.files.modified()?;
+        let unchanged = self
+            .modified
+            .lock()
+            .unwrap_or_else(|error| error.into_inner())
+            .as_ref()
+            == Some(&current);
+        if unchanged {
+            return Ok(false);
+        }
+        self.reload()?;
+        Ok(true)
+    }
+
+    /// Starts checking the files for changes every [`Tls::with_watch_interval`] on a background
+    /// task, logging reloads and failures.
+    ///
+    /// # Returns
+    ///
+    /// The task, or `None` without a watch interval.
+    pub fn spawn_watch(&self) -> Option<task::JoinHandle<()>> {
+        let interval = self.watch_interval?;
+        let tls = self.clone();
+        Some(tokio::spawn(async move {
+            let mut interval = time::interval(interval);
+            interval.tick().await;
+            loop {
+                interval.tick().await;
+                match tls.reload_if_modified() {
+                    Ok(true) => log::info(format_args!(
+                        "reloaded the TLS certificate {}",
+                        tls.files.certificate.display()
+                    )),
+                    Ok(false) => {}
+                    Err(error) => log::error(format_args!(
+                        "keeping the current TLS certificate, {} is invalid: {}",
+                        tls.files.certificate.display(),
+                        error
+                    )),
+                }
+            }
+        }))
+    }
+
+    /// Starts fetching the OCSP responses of the certificates set up with
+    /// [`Tls::with_ocsp_stapling`] on background tasks, one per certificate. Each fetches a
+    /// response right away, then every refresh interval, and retries failures after at most five
+    /// minutes while the previous response stays stapled.
+    ///
+    /// # Returns
+    ///
+    /// The tasks, none without stapling.
+    pub fn spawn_ocsp_refresh(&self) -> Vec<task::JoinHandle<()>> {
+        let hosts = self
+            .files
+            .hosts
+            .iter()
+            .map(|host| (&host.certificate, host.ocsp_refresh));
+        [(&self.files.certificate, self.files.ocsp_refresh)]
+            .into_iter()
+            .chain(hosts)
+            .filter_map(|(certificate, refresh)| Some((certificate.clone(), refresh?)))
+            .map(|(certificate, refresh)| {
+                let tls = self.clone();
+                tokio::spawn(async move {
+                    loop {
+                        let delay = match ocsp::refresh(&certificate).await {
+                            Ok(()) => {
+                                if let Err(error) = tls.reload() {
+                                    log::error(format_args!(
+                                        "stapling the OCSP response of {} failed: {}",
+                                        certificate.display(),
+                                        error
+                                    ));
+                                }
+                                refresh
+                            }
+                            Err(error) => {
+                                log::error(format_args!(
+                                    "fetching the OCSP response of {} failed: {}",
+                                    certificate.display(),
+                                    error
+                                ));
+                                refresh.min(Duration::from_secs(5 * 60))
+                            }
+                        };
+                        time::sleep(delay).await;
+                    }
+                })
+            })
+            .collect()
+    }
+
+    /// Presents a certificate to ACME servers validating a TLS-ALPN-01 challenge for a domain,
+    /// see RFC 8737.
+    ///
+    /// # Arguments
+    ///
+    /// * `domain`: The domain being validated.
+    /// * `certificate`: The self-signed certificate carrying the `acmeIdentifier` extension, or
+    ///   `None` once the challenge is over.
+    pub fn set_challenge_certificate(&self, domain: &str, certificate: Option<Arc<CertifiedKey>>) {
+        let mut challenges = self
+            .challenges
+            .write()
+            .unwrap_or_else(|error| error.into_inner());
+        match certificate {
+            Some(certificate) => challenges.insert(domain.to_string(), certificate),
+            None => challenges.remove(domain),
+        };
+    }
+
+    /// Performs the TLS handshake on an accepted connection with the current certificate,
+    /// within the time of [`Tls::with_handshake_timeout`].
+    ///
+    /// # Arguments
+    ///
+    /// * `stream`: The accepted connection, e.g. a [`tokio::net::TcpStream`], dropped and thereby
+    ///   closed when the handshake fails.
+    ///
+    /// # Returns
+    ///
+    /// The encrypted stream.
+    ///
+    /// # Errors
+    ///
+    /// Captures IO errors and handshake failures, and returns [`io::ErrorKind::TimedOut`] when
+    /// the handshake took too long.
+    pub async fn accept<S: AsyncRead + AsyncWrite + Unpin>(
+        &self,
+        stream: S,
+    ) -> io::Result<TlsStream<S>> {
+        let handshake = TlsAcceptor::from(self.server_config()).accept(stream);
+        let Some(timeout) = self.handshake_timeout else {
+            return handshake.await;
+        };
+        time::timeout(timeout, handshake)
+            .await
+            .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "the TLS handshake timed out"))?
+    }
+}
+
+impl Files {
+    /// Reads the modification times of the files, the default certificate and key first, and
+    /// the OCSP responses last, counting a missing one as never modified.
+    fn modified(&self) -> io::Result<Vec<SystemTime>> {
+        let hosts = self
+            .hosts
+            .iter()
+            .flat_map(|host| [host.certificate.as_path(), host.key.as_path()]);
+        let ca = self.client_auth.iter().map(|(ca, _)| ca.as_path());
+        let ticket_keys = match &self.tickets {
+            TicketKeys::Shared(keys) => Some(keys.as_path()),
+            _ => None,
+        };
+        let staples = self
+            .hosts
+            .iter()
+            .map(|host| (&host.certificate, host.ocsp_refresh))
+            .chain([(&self.certificate, self.ocsp_refresh)])
+            .filter(|(_, refresh)| refresh.is_some())
+            .map(|(certificate, _)| {
+                std::fs::metadata(ocsp::staple_path(certificate))
+                    .and_then(|metadata| metadata.modified())
+                    .unwrap_or(UNIX_EPOCH)
+            });
+        [self.certificate.as_path(), self.key.as_path()]
+            .into_iter()
+            .chain(hosts)
+            .chain(ca)
+            .chain(ticket_keys)
+            .map(|file| std::fs::metadata(file)?.modified())
+            .chain(staples.map(Ok))
+            .collect()
+    }
+
+    /// The protocol versions from the lowest to the highest configured one.
+    fn versions(&self) -> Vec<&'static SupportedProtocolVersion> {
+        let (min, max) = self.versions;
+        [TlsVersion::Tls12, TlsVersion::Tls13]
+            .into_iter()
+            .filter(|version| (min..=max).contains(version))
+            .map(|version| version.supported())
+            .collect()
+    }
+
+    /// Builds a rustls configuration offering the protocols, and `acme-tls/1` to ACME servers,
+    /// from the files.
+    ///
+    /// # Arguments
+    ///
+    /// * `challenges`: The TLS-ALPN-01 challenge certificates, see
+    ///   [`Tls::set_challenge_certificate`].
+    /// * `resumption`: The session cache and rotating ticket keys.
+    ///
+    /// # Returns
+    ///
+    /// The configuration with the configured protocol versions, cipher suites, and key exchange
+    /// groups.
+    ///
+    /// # Errors
+    ///
+    /// The errors of [`Tls::with_client_auth`], [`Tls::with_session_tickets`], and
+    /// [`Tls::with_versions`].
+    fn load(&self, challenges: &Challenges, resumption: &Resumption) -> io::Result<ServerConfig> {
+        let mut provider = rustls::crypto::ring::default_provider();
+        if let Some(names) = &self.cipher_suites {
+            provider.cipher_suites = names
+                .iter()
+                .filter_map(|name| {
+                    let mut all = rustls::crypto::ring::ALL_CIPHER_SUITES.iter();
+                    all.find(|suite| suite.suite().as_str() == Some(name))
+                })
+                .copied()
+                .collect();
+        }
+        if let Some(names) = &self.kx_groups {
+            provider.kx_groups = names
+                .iter()
+                .filter_map(|name| {
+                    let mut all = rustls::crypto::ring::ALL_KX_GROUPS.iter();
+                    all.find(|group| group.name().as_str() == Some(name))
+                })
+                .copied()
+                .collect();
+        }
+        let provider = Arc::new(provider);
+        let default = certified_key(
+            &self.certificate,
+            &self.key,
+            self.ocsp_refresh.is_some(),
+            &provider,
+        )?;
+        let hosts = self
+            .hosts
+            .iter()
+            .map(|host| {
+                let stapled = host.ocsp_refresh.is_some();
+                let certified_key =
+                    certified_key(&host.certificate, &host.key, stapled, &provider)?;
+                Ok((host.name.clone(), Arc::new(certified_key)))
+            })
+            .collect::<io::Result<_>>()?;
+        let builder = ServerConfig::builder_with_provider(Arc::clone(&provider))
+            .with_protocol_versions(&self.versions())
+            .map_err(|error| {
+                io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!(
+                        "the TLS versions, cipher suites, and key exchange groups leave nothing to negotiate: {}",
+                        error
+                    ),
+                )
+            })?;
+        let builder = match &self.client_auth {
+            Some((ca, mode)) => {
+                let mut roots = RootCertStore::empty();
+                let certificates = CertificateDer::pem_file_iter(ca)
+                    .and_then(|certificates| certificates.collect::<Result<Vec<_>, _>>())
+                    .map_err(|error| invalid(ca, &error))?;
+                roots.add_parsable_certificates(certificates);
+                let mut verifier =
+                    WebPkiClientVerifier::builder_with_provider(Arc::new(roots), provider);
+                if *mode == ClientAuth::Optional {
+                    verifier = verifier.allow_unauthenticated();
+                }
+                let verifier = verifier.build().map_err(|error| invalid(ca, &error))?;
+                builder.with_client_cert_verifier(verifier)
+            }
+            None => builder.with_no_client_auth(),
+        };
+        let mut server_config = builder.with_cert_resolver(Arc::new(Resolver {
+            certificate: Arc::new(default),
+            hosts,
+            challenges: Arc::clone(challenges),
+        }));
+        server_config.alpn_protocols = self
+            .protocols
+            .iter()
+            .map(|protocol| protocol.as_bytes().to_vec())
+            .collect();
+        if !server_config.alpn_protocols.is_empty() {
+            server_config.alpn_protocols.push(ACME_TLS_ALPN.to_vec());
+        }
+        server_config.session_storage = Arc::clone(&resumption.cache);
+        match &self.tickets {
+            TicketKeys::Rotating(_) => server_config.ticketer = resumption.ticketer.clone(),
+            TicketKeys::Shared(keys) => server_config.ticketer = Arc::new(Ticketer::shared(keys)?),
+            TicketKeys::Disabled => {}
+        }
+        Ok(server_config)
+    }
+}
+
+/// Chooses the certificate of a handshake.
+#[derive(Debug)]
+struct Resolver {
+    certificate: Arc<CertifiedKey>,
+    /// The certificates of [`Tls::with_host`] by host name.
+    hosts: HashMap<String, Arc<CertifiedKey>>,
+    challenges: Challenges,
+}
+
+impl ResolvesServerCert for Resolver {
+    /// Picks the challenge certificate of the requested domain for ACME servers, the
+    /// certificate of the requested host name, then the one of its parent domain's wildcard,
+    /// and the default certificate for everybody else.
+    fn resolve(&self, hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        let validating = hello
+            .alpn()
+            .is_some_and(|mut protocols| protocols.any(|protocol| protocol == ACME_TLS_ALPN));
+        if !validating {
+            let name = hello.server_name().map(str::to_ascii_lowercase);
+            let host = name.and_then(|name| {
+                self.hosts.get(&name).or_else(|| {
+                    let (_, parent) = name.split_once('.')?;
+                    self.hosts.get(&format!("*.{}", parent))
+                })
+            });
+            return Some(Arc::clone(host.unwrap_or(&self.certificate)));
+        }
+        let challenges = self
+            .challenges
+            .read()
+            .unwrap_or_else(|error| error.into_inner());
+        hello
+            .server_name()
+            .and_then(|domain| challenges.get(domain))
+            .cloned()
+    }
+}
+
+/// Describes a file that holds no usable certificate or key.
+fn invalid(path: &Path, error: &dyn fmt::Display) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("{}: {}", path.display(), error),
+    )
+}
+
+/// Checks that the cryptography provider implements every named algorithm.
+///
+/// # Arguments
+///
+/// * `names`: The names to check, ignoring ASCII case.
+/// * `supported`: The names of the algorithms of the provider.
+///
+/// # Returns
+///
+/// The names as the provider spells them.
+///
+/// # Errors
+///
+/// Returns [`io::ErrorKind::Unsupported`] for a name missing from `supported`.
+fn supported_names<'a>(
+    names: &[&str],
+    supported: impl Iterator<Item = Option<&'a str>> + Clone,
+) -> io::Result<Vec<String>> {
+    names
+        .iter()
+        .map(|name| {
+            supported
+                .clone()
+                .flatten()
+                .find(|supported| supported.eq_ignore_ascii_case(name.trim()))
+                .map(str::to_string)
+                .ok_or_else(|| {
+                    io::Error::new(
+                        io::ErrorKind::Unsupported,
+                        format!("the TLS algorithm {} is not supported", name),
+                    )
+                })
+        })
+        .collect()
+}
+
+/// Reads a certificate chain and the private key of its leaf from PEM files.
+///
+/// # Arguments
+///
+/// * `certificate`: The certificate chain file.
+/// * `key`: The private key file.
+/// * `stapled`: Whether to staple the OCSP response stored next to the chain, if there is one.
+/// * `provider`: The cryptography the key is loaded with.
+///
+/// # Returns
+///
+/// The certificate with its key.
+///
+/// # Errors
+///
+/// The errors of [`Tls::new`].
+fn certified_key(
+    certificate: &Path,
+    key: &Path,
+    stapled: bool,
+    provider: &CryptoProvider,
+) -> io::Result<CertifiedKey> {
+    let chain = CertificateDer::pem_file_iter(certificate)
+        .and_then(|certificates| certificates.collect::<Result<Vec<_>, _>>())
+        .map_err(|error| invalid(certificate, &error))?;
+    if chain.is_empty() {
+        return Err(invalid(certificate, &"no certificate found"));
+    }
+    let private_key = PrivateKeyDer::from_pem_file(key).map_err(|error| invalid(key, &error))?;
+    let mut certified_key = CertifiedKey::from_der(chain, private_key, provider)
+        .map_err(|error| invalid(certificate, &error))?;
+    if stapled {
+        certified_key.ocsp = match std::fs::read(ocsp::staple_path(certificate)) {
+            Ok(response) => Some(response),
+            // Nothing is stapled until the first response is fetched
+            Err(error) if error.kind() == io::ErrorKind::NotFound => None,
+            Err(error) => return Err(error),
+        };
+    }
+    Ok(certified_key)
+}
+
+/// Records the protocol version, cipher suite, and server name of a TLS session as fields of
+/// the current [`log::Span`], e.g. `tls=TLSv1_3 cipher=TLS13_AES_128_GCM_SHA256 sni=example.com`.
+///
+/// # Arguments
+///
+/// * `stream`: The stream after the handshake.
+pub fn record_session<S>(stream: &TlsStream<S>) {
+    let session = stream.get_ref().1;
+    if let Some(version) = session.protocol_version() {
+        log::record("tls", format_args!("{:?}", version));
+    }
+    if let Some(suite) = session.negotiated_cipher_suite() {
+        log::record("cipher", format_args!("{:?}", suite.suite()));
+    }
+    if let Some(name) = session.server_name() {
+        log::record("sni", name);
+    }
+}
+
+/// Reads the identity of a client from the certificate it authenticated with.
+///
+/// # Arguments
+///
+/// * `stream`: The stream after a handshake with [`Tls::with_client_auth`].
+///
+/// # Returns
+///
+/// The identity, or `None` when the client presented no certificate.
+pub fn client_certificate<S>(stream: &TlsStream<S>) -> Option<ClientCertificate> {
+    let der = stream.get_ref().1.peer_certificates()?.first()?;
+    let mut identity = ClientCertificate {
+        subject: String::new(),
+        names: Vec::new(),
+        der: bytes::Bytes::copy_from_slice(der),
+    };
+    // The verifier accepted the certificate, so parsing only fails on exotic encodings
+    if let Ok((_, certificate)) = X509Certificate::from_der(der) {
+        identity.subject = certificate.subject().to_string();
+        if let Ok(Some(names)) = certificate.subject_alternative_name() {
+            identity.names = names
+                .value
+                .general_names
+                .iter()
+                .filter_map(|name| match name {
+                    GeneralName::DNSName(name)
+                    | GeneralName::RFC822Name(name)
+                    | GeneralName::URI(name) => Some(name.to_string()),
+                    _ => None,
+                })
+                .collect();
+        }
+    }
+    Some(identity)
+}
+
+impl Router {
+    /// Requires a verified client certificate for every handler registered so far, answering
+    /// requests without one with 403 Forbidden. Handlers registered afterwards are not wrapped.
+    /// Combined with [`ClientAuth::Optional`], only these routes need mutual TLS.
+    ///
+    /// # Returns
+    ///
+    /// The router with guarded handlers.
+    pub fn client_certificate_required(self) -> Router {
+        self.map_handlers("client-certificate", |handler| {
+            Arc::new(RequireClientCertificate { handler })
+        })
+    }
+}
+
+/// Passes requests with a verified client certificate to a wrapped handler.
+struct RequireClientCertificate {
+    handler: Arc<dyn Handler>,
+}
+
+#[async_trait]
+impl Handler for RequireClientCertificate {
+    async fn call(&self, request: Request) -> io::Result<Response> {
+        if request.client_certificate.is_none() {
+            return Ok(Response::new(StatusCode::FORBIDDEN, ""));
+        }
+        self.handler.call(request).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rustls::pki_types::ServerName;
+    use rustls::{ClientConfig, RootCertStore};
+    use tokio::net;
+
+    /// Writes a new self-signed certificate for `name` over the files.
+    fn write_certificate(certificate: &Path, key: &Path, name: &str) -> CertificateDer<'static> {
+        let generated = rcgen::generate_simple_self_signed(vec![name.to_string()]).unwrap();
+        std::fs::write(certificate, generated.cert.pem()).unwrap();
+        std::fs::write(key, generated.signing_key.serialize_pem()).unwrap();
+        generated.cert.der().clone()
+    }
+
+    /// Accepts TLS connections with the settings and returns the listener address.
+    async fn listen(tls: &Tls) -> SocketAddr {
+        let listener = net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let acceptor = tls.clone();
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                let _ = acceptor.accept(stream).await;
+            }
+        });
+        address
+    }
+
+    /// Connects to a TLS listener asking for `name` and trusting only `root`, and returns the
+    /// peer certificate.
+    async fn handshake(
+        address: SocketAddr,
+        root: CertificateDer<'static>,
+        name: &str,
+        protocol: &[u8],
+    ) -> io::Result<CertificateDer<'static>> {
+        let mut roots = RootCertStore::empty();
+        roots.add(root).unwrap();
+        let mut client =
+            ClientConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+                .with_safe_default_protocol_versions()
+                .unwrap()
+                .with_root_certificates(roots)
+                .with_no_client_auth();
+        client.alpn_protocols = vec![protocol.to_vec()];
+        let stream = net::TcpStream::connect(address).await?;
+        let stream = tokio_rustls::TlsConnector::from(Arc::new(client))
+            .connect(ServerName::try_from(name.to_string()).unwrap(), stream)
+            .await?;
+        Ok(stream.get_ref().1.peer_certificates().unwrap()[0].clone())
+    }
+
+    /// It presents a renewed certificate in new handshakes and keeps the old one when the new
+    /// files are invalid
+    #[tokio::test]
+    async fn reload() {
+        let directory = tempfile::tempdir().unwrap();
+        let certificate = directory.path().join("cert.pem");
+        let key = directory.path().join("key.pem");
+        let first = write_certificate(&certificate, &key, "localhost");
+        let tls = Tls::new(&certificate, &key).unwrap();
+        assert!(!tls.reload_if_modified().unwrap());
+        let address = listen(&tls).await;
+        let connect = |root| handshake(address, root, "localhost", b"http/1.1");
+        assert_eq!(first, connect(first.clone()).await.unwrap());
+
+        let second = write_certificate(&certificate, &key, "localhost");
+        tls.reload().unwrap();
+        assert_eq!(second, connect(second.clone()).await.unwrap());
+
+        std::fs::write(&key, "not a key").unwrap();
+        assert_eq!(io::ErrorKind::InvalidData, tls.reload().unwrap_err().kind());
+        assert_eq!(second, connect(second.clone()).await.unwrap());
+    }
+
+    /// It gives up on a client that never sends a `ClientHello` once the handshake timeout passed
+    #[tokio::test(start_paused = true)]
+    async fn handshake_timeout() {
+        let directory = tempfile::tempdir().unwrap();
+        let certificate = directory.path().join("cert.pem");
+        let key = directory.path().join("key.pem");
+        write_certificate(&certificate, &key, "localhost");
+        let tls = Tls::new(&certificate, &key)
+            .unwrap()
+            .with_handshake_timeout(Some(Duration::from_secs(5)));
+        let (_client, stream) = io::duplex(1024);
+        let started = time::Instant::now();
+        let error = tls.accept(stream).await.unwrap_err();
+        assert_eq!(io::ErrorKind::TimedOut, error.kind());
+        assert!(started.elapsed() >= Duration::from_secs(5));
+    }
+
+    /// It picks the certificate of the host name, then of its wildcard, then the default one
+    #[tokio::test]
+    async fn hosts() {
+        let directory = tempfile::tempdir().unwrap();
+        let file = |name: &str| directory.path().join(name);
+        let default = write_certificate(&file("cert.pem"), &file("key.pem"), "localhost");
+        let exact = write_certificate(&file("a.pem"), &file("a.key"), "a.example.org");
+        let wildcard = write_certificate(&file("w.pem"), &file("w.key"), "*.example.org");
+        let tls = Tls::new(file("cert.pem"), file("key.pem"))
+            .unwrap()
+            .with_host("A.example.org", file("a.pem"), file("a.key"))
+            .unwrap()
+            .with_host("*.example.org", file("w.pem"), file("w.key"))
+            .unwrap();
+        let address = listen(&tls).await;
+        let connect = |root, name| handshake(address, root, name, b"http/1.1");
+
+        assert_eq!(
+            exact,
+            connect(exact.clone(), "a.example.org").await.unwrap()
+        );
+        assert_eq!(
+            wildcard,
+            connect(wildcard.clone(), "b.example.org").await.unwrap()
+        );
+        assert_eq!(
+            default,
+            connect(default.clone(), "localhost").await.unwrap()
+        );
+        assert!(connect(wildcard, "a.b.example.org").await.is_err());
+    }
+
+    /// It offers the configured protocols and presents the challenge certificate only to clients
+    /// offering `acme-tls/1`
+    #[tokio::test]
+    async fn alpn() {
+        let directory = tempfile::tempdir().unwrap();
+        let certificate = directory.path().join("cert.pem");
+        let key = directory.path().join("key.pem");
+        let regular = write_certificate(&certificate, &key, "localhost");
+        let tls = Tls::new(&certificate, &key).unwrap();
+        let challenge = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+        let challenge_der = challenge.cert.der().clone();
+        let certified_key = CertifiedKey::from_der(
+            vec![challenge_der.clone()],
+            PrivateKeyDer::try_from(challenge.signing_key.serialize_der()).unwrap(),
+            &rustls::crypto::ring::default_provider(),
+        )
+        .unwrap();
+        tls.set_challenge_certificate("localhost", Some(Arc::new(certified_key)));
+        let address = listen(&tls).await;
+        let connect = |root, protocol| handshake(address, root, "localhost", protocol);
+
+        assert_eq!(
+            challenge_der,
+            connect(challenge_der.clone(), ACME_TLS_ALPN).await.unwrap()
+        );
+        assert_eq!(
+            regular,
+            connect(regular.clone(), b"http/1.1").await.unwrap()
+        );
+
+        assert!(connect(regular.clone(), b"h2").await.is_err());
+
+        tls.set_challenge_certificate("localhost", None);
+        assert!(connect(challenge_der, ACME_TLS_ALPN).await.is_err());
+
+        let error = tls.clone().with_alpn_protocols(&["spdy/3.1"]).unwrap_err();
+        assert_eq!(io::ErrorKind::Unsupported, error.kind());
+        tls.clone().with_alpn_protocols(&[]).unwrap();
+        assert_eq!(regular, connect(regular.clone(), b"h2").await.unwrap());
+    }
+
+    /// It resumes sessions with tickets from another instance sharing the ticket keys
+    #[tokio::test]
+    async fn resumption() {
+        use rustls::HandshakeKind;
+        use tokio::io::AsyncReadExt;
+
+        let directory = tempfile::tempdir().unwrap();
+        let file = |name: &str| directory.path().join(name);
+        let root = write_certificate(&file("cert.pem"), &file("key.pem"), "localhost");
+        std::fs::write(
+            file("tickets"),
+            "AAECAwQFBgcICQoLDA0ODxAREhMUFRYXGBkaGxwdHh8=\n",
+        )
+        .unwrap();
+        let shared = || {
+            Tls::new(file("cert.pem"), file("key.pem"))
+                .unwrap()
+                .with_session_cache(0)
+                .unwrap()
+                .with_session_tickets(TicketKeys::Shared(file("tickets")))
+                .unwrap()
+        };
+        let first = listen(&shared()).await;
+        let second = listen(&shared()).await;
+        let other = listen(&Tls::new(file("cert.pem"), file("key.pem")).unwrap()).await;
+        let mut roots = RootCertStore::empty();
+        roots.add(root).unwrap();
+        let client = Arc::new(
+            ClientConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+                .with_safe_default_protocol_versions()
+                .unwrap()
+                .with_root_certificates(roots)
+                .with_no_client_auth(),
+        );
+        let connect = |address| {
+            let client = Arc::clone(&client);
+            async move {
+                let stream = net::TcpStream::connect(address).await.unwrap();
+                let mut stream = tokio_rustls::TlsConnector::from(client)
+                    .connect(ServerName::try_from("localhost").unwrap(), stream)
+                    .await
+                    .unwrap();
+                // Receives the tickets sent after the handshake
+                let _ = stream.read(&mut [0; 1]).await;
+                stream.get_ref().1.handshake_kind().unwrap()
+            }
+        };
+
+        assert_eq!(HandshakeKind::Full, connect(first).await);
+        assert_eq!(HandshakeKind::Resumed, connect(second).await);
+        assert_ne!(HandshakeKind::Resumed, connect(other).await);
+
+        std::fs::write(file("tickets"), "not a key\n").unwrap();
+        let error = Tls::new(file("cert.pem"), file("key.pem"))
+            .unwrap()
+            .with_session_tickets(TicketKeys::Shared(file("tickets")))
+            .unwrap_err();
+        assert_eq!(io::ErrorKind::InvalidData, error.kind());
+    }
+
+    /// It negotiates only the configured versions and cipher suites
+    #[tokio::test]
+    async fn versions() {
+        let directory = tempfile::tempdir().unwrap();
+        let file = |name: &str| directory.path().join(name);
+        let root = write_certificate(&file("cert.pem"), &file("key.pem"), "localhost");
+        let tls = Tls::new(file("cert.pem"), file("key.pem")).unwrap();
+        let connect = |address, version: &'static SupportedProtocolVersion| {
+            let mut roots = RootCertStore::empty();
+            roots.add(root.clone()).unwrap();
+            let client = ClientConfig::builder_with_provider(Arc::new(
+                rustls::crypto::ring::default_provider(),
+            ))
+            .with_protocol_versions(&[version])
+            .unwrap()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+            async move {
+                let stream = net::TcpStream::connect(address).await?;
+                let stream = tokio_rustls::TlsConnector::from(Arc::new(client))
+                    .connect(ServerName::try_from("localhost").unwrap(), stream)
+                    .await?;
+                let connection = stream.get_ref().1;
+                Ok::<_, io::Error>(connection.negotiated_cipher_suite().unwrap().suite())
+            }
+        };
+
+        let address = listen(
+            &tls.clone()
+                .with_versions(TlsVersion::Tls13, TlsVersion::Tls13)
+                .unwrap(),
+        )
+        .await;
+        assert!(connect(address, &rustls::version::TLS12).await.is_err());
+        assert!(connect(address, &rustls::version::TLS13).await.is_ok());
+
+        let strict = tls
+            .clone()
+            .with_cipher_suites(&[
+                "tls13_aes_256_gcm_sha384",
+                "TLS_ECDHE_ECDSA_WITH_AES_256_GCM_SHA384",
+            ])
+            .unwrap()
+            .with_kx_groups(&["X25519"])
+            .unwrap();
+        let address = listen(&strict).await;
+        let suite = connect(address, &rustls::version::TLS13).await.unwrap();
+        assert_eq!(Some("TLS13_AES_256_GCM_SHA384"), suite.as_str());
+        let suite = connect(address, &rustls::version::TLS12).await.unwrap();
+        assert_eq!(
+            Some("TLS_ECDHE_ECDSA_WITH_AES_256_GCM_SHA384"),
+            suite.as_str()
+        );
+
+        let error = tls.clone().with_kx_groups(&["X448"]).unwrap_err();
+        assert_eq!(io::ErrorKind::Unsupported, error.kind());
+        let error = tls
+            .clone()
+            .with_versions(TlsVersion::Tls13, TlsVersion::Tls12)
+            .unwrap_err();
+        assert_eq!(io::ErrorKind::InvalidInput, error.kind());
+        let error = strict
+            .with_cipher_suites(&["TLS13_AES_128_GCM_SHA256"])
+            .unwrap()
+            .with_versions(TlsVersion::Tls12, TlsVersion::Tls12)
+            .unwrap_err();
+        assert_eq!(io::ErrorKind::InvalidInput, error.kind());
+    }
+
+    /// It hands verified client identities to handlers and refuses guarded routes without one
+    #[tokio::test]
+    async fn client_auth() {
+        use crate::config::Config;
+        use crate::method::Method;
+        use crate::server::Server;
+        use rcgen::{CertificateParams, CertifiedIssuer, DnType, KeyPair};
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let directory = tempfile::tempdir().unwrap();
+        let file = |name: &str| directory.path().join(name);
+        let server_certificate =
+            write_certificate(&file("cert.pem"), &file("key.pem"), "localhost");
+        let mut ca_params = CertificateParams::new(Vec::new()).unwrap();
+        ca_params.is_ca = rcgen::IsCa::Ca(rcgen::BasicConstraints::Unconstrained);
+        let ca = CertifiedIssuer::self_signed(ca_params, KeyPair::generate().unwrap()).unwrap();
+        std::fs::write(file("ca.pem"), ca.pem()).unwrap();
+        let mut client_params =
+            CertificateParams::new(vec!["alice.example.org".to_string()]).unwrap();
+        client_params
+            .distinguished_name
+            .push(DnType::CommonName, "alice");
+        client_params.extended_key_usages = vec![rcgen::ExtendedKeyUsagePurpose::ClientAuth];
+        let client_key = KeyPair::generate().unwrap();
+        let client_certificate = client_params.signed_by(&client_key, &ca).unwrap();
+
+        let tls = Tls::new(file("cert.pem"), file("key.pem"))
+            .unwrap()
+            .with_client_auth(file("ca.pem"), ClientAuth::Optional)
+            .unwrap();
+        let config = Config {
+            router: Router::new()
+                .route(Method::Get, "/whoami", |request: Request| async move {
+                    let identity = request.client_certificate.unwrap();
+                    let body = format!("{} {}", identity.subject, identity.names.join(","));
+                    Ok(Response::new(StatusCode::OK, body))
+                })
+                .client_certificate_required(),
+            tls: Some(tls),
+            ..Config::default()
+        };
+        let listener = net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let server = Server::new(config);
+        tokio::spawn({
+            let server = server.clone();
+            async move { server.serve_tls(listener).await }
+        });
+        let whoami = |authenticated: bool| {
+            let mut roots = RootCertStore::empty();
+            roots.add(server_certificate.clone()).unwrap();
+            let builder = ClientConfig::builder_with_provider(Arc::new(
+                rustls::crypto::ring::default_provider(),
+            ))
+            .with_safe_default_protocol_versions()
+            .unwrap()
+            .with_root_certificates(roots);
+            let client = if authenticated {
+                builder
+                    .with_client_auth_cert(
+                        vec![client_certificate.der().clone()],
+                        PrivateKeyDer::try_from(client_key.serialize_der()).unwrap(),
+                    )
+                    .unwrap()
+            } else {
+                builder.with_no_client_auth()
+            };
+            async move {
+                let stream = net::TcpStream::connect(address).await?;
+                let mut stream = tokio_rustls::TlsConnector::from(Arc::new(client))
+                    .connect(ServerName::try_from("localhost").unwrap(), stream)
+                    .await?;
+                stream
+                    .write_all(b"GET /whoami HTTP/1.1\r\nConnection: close\r\n\r\n")
+                    .await?;
+                let mut response = String::new();
+                stream.read_to_string(&mut response).await?;
+                Ok::<_, io::Error>(response)
+            }
+        };
+
+        let response = whoami(true).await.unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK"));
+        assert!(response.ends_with("CN=alice alice.example.org"));
+        let response = whoami(false).await.unwrap();
+        assert!(response.starts_with("HTTP/1.1 403 Forbidden"));
+
+        let required = Tls::new(file("cert.pem"), file("key.pem"))
+            .unwrap()
+            .with_client_auth(file("ca.pem"), ClientAuth::Required)
+            .unwrap();
+        server.replace(Config {
+            tls: Some(required),
+            ..Config::default()
+        });
+        assert!(whoami(false).await.is_err());
+        server.shutdown();
+    }
+}
@@ -0,0 +1,278 @@
+use crate::proxy::{read_response, MAX_RESPONSE_SIZE};
+use crate::uri::Uri;
+use bytes::Bytes;
+use ring::digest;
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::CertificateDer;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tokio::io::{self, AsyncWriteExt};
+use tokio::{fs, net, time};
+use x509_parser::extensions::{GeneralName, ParsedExtension};
+use x509_parser::oid_registry::OID_PKIX_ACCESS_DESCRIPTOR_OCSP;
+use x509_parser::prelude::{FromDer, X509Certificate};
+
+/// How long a responder may take to answer.
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The DER encoding of the SHA-1 algorithm identifier, which every responder understands.
+const SHA1: &[u8] = &[
+    0x30, 0x09, 0x06, 0x05, 0x2b, 0x0e, 0x03, 0x02, 0x1a, 0x05, 0x00,
+];
+
+/// The file holding the DER encoded OCSP response stapled to a certificate, next to the
+/// certificate chain with the extension `ocsp`, e.g. `cert.ocsp` for `cert.pem`.
+pub(super) fn staple_path(certificate: &Path) -> PathBuf {
+    certificate.with_extension("ocsp")
+}
+
+/// Fetches a fresh OCSP response for the leaf of a certificate chain and stores it as its
+/// staple, see [`staple_path`].
+///
+/// # Arguments
+///
+/// * `certificate`: A PEM file with the certificate chain, leaf first and its issuer second.
+///
+/// # Errors
+///
+/// Returns [`io::ErrorKind::InvalidData`] when the chain lacks the issuer or the leaf names no
+/// OCSP responder, [`io::ErrorKind::Unsupported`] for a responder not reachable over plain
+/// HTTP, [`io::ErrorKind::Other`] when the responder refuses the request, and captures IO
+/// errors from reading the chain, the exchange, and writing the staple.
+pub(super) async fn refresh(certificate: &Path) -> io::Result<()> {
+    let invalid = |error: &dyn std::fmt::Display| super::invalid(certificate, error);
+    let chain = CertificateDer::pem_file_iter(certificate)
+        .and_then(|certificates| certificates.collect::<Result<Vec<_>, _>>())
+        .map_err(|error| invalid(&error))?;
+    let [leaf, issuer, ..] = chain.as_slice() else {
+        return Err(invalid(&"the chain lacks the issuer certificate"));
+    };
+    let (_, leaf) = X509Certificate::from_der(leaf).map_err(|error| invalid(&error))?;
+    let (_, issuer) = X509Certificate::from_der(issuer).map_err(|error| invalid(&error))?;
+    let responder = responder(&leaf).ok_or_else(|| invalid(&"no OCSP responder"))?;
+    let response = time::timeout(TIMEOUT, post(&responder, &request(&leaf, &issuer)))
+        .await
+        .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "the OCSP responder timed out"))??;
+    match response_status(&response) {
+        Some(0) => {}
+        status => {
+            return Err(io::Error::other(format!(
+                "the OCSP responder {} refused the request with status {:?}",
+                responder, status
+            )))
+        }
+    }
+    let staple = staple_path(certificate);
+    let temporary = staple.with_extension("ocsp.tmp");
+    fs::write(&temporary, &response).await?;
+    fs::rename(&temporary, &staple).await
+}
+
+/// Finds the OCSP responder in the authority information access extension of a certificate.
+fn responder(certificate: &X509Certificate<'_>) -> Option<String> {
+    certificate
+        .iter_extensions()
+        .find_map(|extension| match extension.parsed_extension() {
+            ParsedExtension::AuthorityInfoAccess(access) => access
+                .accessdescs
+                .iter()
+                .find(|access| access.access_method == OID_PKIX_ACCESS_DESCRIPTOR_OCSP)
+                .and_then(|access| match access.access_location {
+                    GeneralName::URI(uri) => Some(uri.to_string()),
+                    _ => None,
+                }),
+            _ => None,
+        })
+}
+
+/// Encodes an OCSP request for the status of one certificate, see RFC 6960.
+///
+/// # Arguments
+///
+/// * `leaf`: The certificate whose status is asked for.
+/// * `issuer`: The certificate of the authority that signed it.
+///
+/// # Returns
+///
+/// The DER encoded request without nonce, so responders may answer from their cache.
+fn request(leaf: &X509Certificate<'_>, issuer: &X509Certificate<'_>) -> Vec<u8> {
+    let sha1 = |bytes: &[u8]| digest::digest(&digest::SHA1_FOR_LEGACY_USE_ONLY, bytes);
+    let name_hash = sha1(leaf.issuer().as_raw());
+    let key_hash = sha1(&issuer.public_key().subject_public_key.data);
+    let certificate_id = [
+        SHA1.to_vec(),
+        der(0x04, name_hash.as_ref()),
+        der(0x04, key_hash.as_ref()),
+        der(0x02, leaf.raw_serial()),
+    ]
+    .concat();
+    // OCSPRequest { TBSRequest { requestList { Request { CertID } } } }
+    let single = der(0x30, &der(0x30, &certificate_id));
+    der(0x30, &der(0x30, &der(0x30, &single)))
+}
+
+/// Encodes a DER value.
+///
+/// # Arguments
+///
+/// * `tag`: The identifier octet, e.g. `0x30` for a sequence.
+/// * `content`: The encoded content.
+fn der(tag: u8, content: &[u8]) -> Vec<u8> {
+    let mut encoded = vec![tag];
+    match u8::try_from(content.len()) {
+        Ok(length) if length < 0x80 => encoded.push(length),
+        _ => {
+            let length = content.len().to_be_bytes();
+            let skip = length.iter().take_while(|byte| **byte == 0).count();
+            encoded.push(0x80 | (length.len() - skip) as u8);
+            encoded.extend_from_slice(&length[skip..]);
+        }
+    }
+    encoded.extend_from_slice(content);
+    encoded
+}
+
+/// Reads the `responseStatus` of an OCSP response, which is `0` for a successful one.
+fn response_status(response: &[u8]) -> Option<u8> {
+    let (&tag, rest) = response.split_first()?;
+    let (&length, rest) = rest.split_first()?;
+    let rest = match length {
+        0x00..=0x7f => rest,
+        long => rest.get(usize::from(long & 0x7f)..)?,
+    };
+    match (tag, rest) {
+        (0x30, [0x0a, 0x01, status, ..]) => Some(*status),
+        _ => None,
+    }
+}
+
+/// Posts an OCSP request to a responder over HTTP.
+///
+/// # Returns
+///
+/// The body of the successful response.
+///
+/// # Errors
+///
+/// Returns [`io::ErrorKind::Unsupported`] for a URL other than `http`,
+/// [`io::ErrorKind::Other`] for a status other than success, and captures IO errors and
+/// malformed responses from the exchange.
+async fn post(url: &str, body: &[u8]) -> io::Result<Bytes> {
+    let uri: Uri = url.parse()?;
+    let authority = match (uri.scheme(), uri.authority()) {
+        (Some("http"), Some(authority)) => authority,
+        _ => {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                format!("the OCSP responder {} is not a plain HTTP URL", url),
+            ))
+        }
+    };
+    let address = if authority.ends_with(']') || !authority.contains(':') {
+        format!("{}:80", authority)
+    } else {
+        authority.to_string()
+    };
+    let mut stream = io::BufReader::new(net::TcpStream::connect(address).await?);
+    let mut path = match uri.path() {
+        "" => "/".to_string(),
+        path => path.to_string(),
+    };
+    if let Some(query) = uri.query() {
+        path = format!("{}?{}", path, query);
+    }
+    let head = format!(
+        "POST {} HTTP/1.1\r\nHost: {}\r\nContent-Type: application/ocsp-request\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        path,
+        authority,
+        body.len()
+    );
+    stream.get_mut().write_all(head.as_bytes()).await?;
+    stream.get_mut().write_all(body).await?;
+    let (response, _) = read_response(&mut stream, false, MAX_RESPONSE_SIZE).await?;
+    if !response.status.is_success() {
+        return Err(io::Error::other(format!(
+            "the OCSP responder {} answered {}",
+            url, response.status
+        )));
+    }
+    Ok(response.body)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rcgen::{CertificateParams, CertifiedIssuer, CustomExtension, KeyPair};
+    use tokio::io::{AsyncBufReadExt, AsyncReadExt};
+
+    /// It asks the responder named by the certificate about its serial number and stores the
+    /// response as the staple
+    #[tokio::test]
+    async fn refresh_staple() {
+        const RESPONSE: &[u8] = &[0x30, 0x03, 0x0a, 0x01, 0x00];
+        let listener = net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let responder = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut stream = io::BufReader::new(stream);
+            let mut head = String::new();
+            while !head.ends_with("\r\n\r\n") {
+                stream.read_line(&mut head).await.unwrap();
+            }
+            let length = head
+                .lines()
+                .find_map(|line| line.strip_prefix("Content-Length: "))
+                .unwrap()
+                .parse()
+                .unwrap();
+            let mut request = vec![0; length];
+            stream.read_exact(&mut request).await.unwrap();
+            let mut stream = stream.into_inner();
+            let response = format!(
+                "HTTP/1.1 200 OK\r\nContent-Type: application/ocsp-response\r\nContent-Length: {}\r\n\r\n",
+                RESPONSE.len()
+            );
+            stream.write_all(response.as_bytes()).await.unwrap();
+            stream.write_all(RESPONSE).await.unwrap();
+            (head, request)
+        });
+
+        let mut ca_params = CertificateParams::new(Vec::new()).unwrap();
+        ca_params.is_ca = rcgen::IsCa::Ca(rcgen::BasicConstraints::Unconstrained);
+        let ca = CertifiedIssuer::self_signed(ca_params, KeyPair::generate().unwrap()).unwrap();
+        let mut params = CertificateParams::new(vec!["localhost".to_string()]).unwrap();
+        let url = format!("http://{}/ocsp", address);
+        let location = [&[0x86, url.len() as u8], url.as_bytes()].concat();
+        let access = der(
+            0x30,
+            &[vec![0x06, 0x08, 0x2b, 6, 1, 5, 5, 7, 0x30, 1], location].concat(),
+        );
+        params.custom_extensions = vec![CustomExtension::from_oid_content(
+            &[1, 3, 6, 1, 5, 5, 7, 1, 1],
+            der(0x30, &access),
+        )];
+        let key = KeyPair::generate().unwrap();
+        let leaf = params.signed_by(&key, &ca).unwrap();
+        let directory = tempfile::tempdir().unwrap();
+        let certificate = directory.path().join("cert.pem");
+        let key_file = directory.path().join("key.pem");
+        std::fs::write(&certificate, leaf.pem() + ca.pem().as_str()).unwrap();
+        std::fs::write(&key_file, key.serialize_pem()).unwrap();
+        let provider = rustls::crypto::ring::default_provider();
+        let stapled = || {
+            super::super::certified_key(&certificate, &key_file, true, &provider)
+                .unwrap()
+                .ocsp
+        };
+        assert_eq!(None, stapled());
+
+        refresh(&certificate).await.unwrap();
+        assert_eq!(RESPONSE, std::fs::read(staple_path(&certificate)).unwrap());
+        assert_eq!(Some(RESPONSE.to_vec()), stapled());
+        let (head, request) = responder.await.unwrap();
+        assert!(head.starts_with("POST /ocsp HTTP/1.1\r\n"));
+        assert!(head.contains("Content-Type: application/ocsp-request\r\n"));
+        let (_, parsed) = X509Certificate::from_der(leaf.der()).unwrap();
+        assert!(request.ends_with(&der(0x02, parsed.raw_serial())));
+    }
+}
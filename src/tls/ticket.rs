@@ -0,0 +1,283 @@
+use base64::Engine;
+use ring::aead::{Aad, LessSafeKey, Nonce, UnboundKey, AES_256_GCM, NONCE_LEN};
+use ring::digest;
+use ring::rand::{SecureRandom, SystemRandom};
+use rustls::server::ProducesTickets;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::sync::RwLock;
+use std::time::{Duration, Instant};
+use tokio::io;
+
+/// How long clients are told tickets encrypted with [`TicketKeys::Shared`] keys stay useful,
+/// and so how long a replaced key should stay in the file.
+pub const SHARED_TICKET_LIFETIME: Duration = Duration::from_secs(12 * 60 * 60);
+
+/// The time between two keys of [`TicketKeys::default`].
+pub(super) const ROTATION: Duration = Duration::from_secs(6 * 60 * 60);
+
+/// Where the keys encrypting TLS session tickets come from. Clients present a ticket to resume
+/// their session without a full handshake.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum TicketKeys {
+    /// Random keys of this instance, replaced every interval. Tickets stay valid for up to two
+    /// intervals, but only on this instance.
+    Rotating(Duration),
+    /// Keys shared by every instance behind a load balancer, read from a file with one base64
+    /// encoded 32-byte key per line, e.g. from `openssl rand -base64 32`. The first key
+    /// encrypts new tickets and all of them decrypt, so a rotation adds a new key on top and
+    /// removes the last one after [`SHARED_TICKET_LIFETIME`].
+    Shared(PathBuf),
+    /// No tickets, clients resume through the session cache of this instance only.
+    Disabled,
+}
+
+impl Default for TicketKeys {
+    /// Rotates random keys every 6 hours.
+    fn default() -> TicketKeys {
+        TicketKeys::Rotating(ROTATION)
+    }
+}
+
+/// Encrypts and decrypts session tickets with AES-256-GCM.
+pub(super) struct Ticketer {
+    /// The time the first key was made and the keys, the first encrypting new tickets.
+    keys: RwLock<(Instant, Vec<Key>)>,
+    /// The time between two new keys, or `None` for shared keys.
+    rotation: Option<Duration>,
+}
+
+/// A ticket key with the name tickets refer to it by.
+struct Key {
+    name: [u8; 16],
+    key: LessSafeKey,
+}
+
+impl Ticketer {
+    /// Starts with a random key replaced every interval.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`io::ErrorKind::Other`] when the system has no randomness.
+    pub(super) fn rotating(rotation: Duration) -> io::Result<Ticketer> {
+        Ok(Ticketer {
+            keys: RwLock::new((Instant::now(), vec![random_key()?])),
+            rotation: Some(rotation),
+        })
+    }
+
+    /// Reads shared keys from a file, see [`TicketKeys::Shared`].
+    ///
+    /// # Errors
+    ///
+    /// Returns [`io::ErrorKind::InvalidData`] when the file holds no key or a line is not a
+    /// base64 encoded 32-byte key, and captures IO errors from reading it.
+    pub(super) fn shared(path: &Path) -> io::Result<Ticketer> {
+        let keys = std::fs::read_to_string(path)?
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty())
+            .map(|line| {
+                base64::engine::general_purpose::STANDARD
+                    .decode(line)
+                    .ok()
+                    .and_then(|secret| key(&secret))
+                    .ok_or_else(|| super::invalid(path, &"not a base64 encoded 32-byte key"))
+            })
+            .collect::<io::Result<Vec<_>>>()?;
+        if keys.is_empty() {
+            return Err(super::invalid(path, &"no ticket key found"));
+        }
+        Ok(Ticketer {
+            keys: RwLock::new((Instant::now(), keys)),
+            rotation: None,
+        })
+    }
+
+    /// Puts a new key in front once the rotation interval passed, keeping the previous one to
+    /// decrypt the tickets it encrypted.
+    fn rotate(&self) {
+        let Some(rotation) = self.rotation else {
+            return;
+        };
+        let due = |keys: &(Instant, Vec<Key>)| keys.0.elapsed() >= rotation;
+        if !due(&self.keys.read().unwrap_or_else(|error| error.into_inner())) {
+            return;
+        }
+        let mut keys = self.keys.write().unwrap_or_else(|error| error.into_inner());
+        if due(&keys) {
+            if let Ok(key) = random_key() {
+                keys.1.insert(0, key);
+                keys.1.truncate(2);
+                keys.0 = Instant::now();
+            }
+        }
+    }
+}
+
+impl fmt::Debug for Ticketer {
+    fn fmt(&self, formatter: &mut fmt::Formatter<'_>) -> fmt::Result {
+        formatter
+            .debug_struct("Ticketer")
+            .field("rotation", &self.rotation)
+            .finish_non_exhaustive()
+    }
+}
+
+impl ProducesTickets for Ticketer {
+    fn enabled(&self) -> bool {
+        true
+    }
+
+    fn lifetime(&self) -> u32 {
+        let lifetime = self.rotation.unwrap_or(SHARED_TICKET_LIFETIME);
+        u32::try_from(lifetime.as_secs()).unwrap_or(u32::MAX)
+    }
+
+    /// Seals the plaintext as the key name, a random nonce, the ciphertext, and the tag.
+    fn encrypt(&self, plain: &[u8]) -> Option<Vec<u8>> {
+        self.rotate();
+        let keys = self.keys.read().unwrap_or_else(|error| error.into_inner());
+        let key = keys.1.first()?;
+        let mut nonce = [0; NONCE_LEN];
+        SystemRandom::new().fill(&mut nonce).ok()?;
+        let mut sealed = plain.to_vec();
+        key.key
+            .seal_in_place_append_tag(
+                Nonce::assume_unique_for_key(nonce),
+                Aad::from(key.name),
+                &mut sealed,
+            )
+            .ok()?;
+        Some([&key.name[..], &nonce, &sealed].concat())
+    }
+
+    fn decrypt(&self, cipher: &[u8]) -> Option<Vec<u8>> {
+        self.rotate();
+        let (name, rest) = cipher.split_at_checked(16)?;
+        let (nonce, sealed) = rest.split_at_checked(NONCE_LEN)?;
+        let keys = self.keys.read().unwrap_or_else(|error| error.into_inner());
+        let key = keys.1.iter().find(|key| key.name == name)?;
+        let mut plain = sealed.to_vec();
+        let length = key
+            .key
+            .open_in_place(
+                Nonce::try_assume_unique_for_key(nonce).ok()?,
+                Aad::from(name),
+                &mut plain,
+            )
+            .ok()?
+            .len();
+        plain.truncate(length);
+        Some(plain)
+    }
+}
+
+/// Makes a ticket key named after the hash of its secret, so every instance sharing the secret
+/// agrees on the name.
+fn key(secret: &[u8]) -> Option<Key> {
+    let key = LessSafeKey::new(UnboundKey::new(&AES_256_GCM, secret).ok()?);
+    let mut name = [0; 16];
+    name.copy_from_slice(&digest::digest(&digest::SHA256, secret).as_ref()[..16]);
+    Some(Key { name, key })
+}
+
+/// Makes a key from a random secret.
+fn random_key() -> io::Result<Key> {
+    let mut secret = [0; 32];
+    SystemRandom::new()
+        .fill(&mut secret)
+        .map_err(|_| io::Error::other("no randomness for a ticket key"))?;
+    key(&secret).ok_or_else(|| io::Error::other("invalid ticket key"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Makes the rotation of a ticketer due.
+    fn expire(ticketer: &Ticketer) {
+        let mut keys = ticketer.keys.write().unwrap();
+        keys.0 = Instant::now() - ticketer.rotation.unwrap();
+    }
+
+    fn name(ticket: &[u8]) -> &[u8] {
+        &ticket[..16]
+    }
+
+    /// It decrypts the tickets it encrypted and refuses altered or truncated ones
+    #[test]
+    fn round_trip() {
+        let ticketer = Ticketer::rotating(ROTATION).unwrap();
+        assert_eq!(6 * 60 * 60, ticketer.lifetime());
+        let ticket = ticketer.encrypt(b"session").unwrap();
+        assert_eq!(Some(b"session".to_vec()), ticketer.decrypt(&ticket));
+        let mut altered = ticket.clone();
+        *altered.last_mut().unwrap() ^= 1;
+        assert_eq!(None, ticketer.decrypt(&altered));
+        assert_eq!(None, ticketer.decrypt(&ticket[..20]));
+    }
+
+    /// It encrypts with a new key once the interval passed and still decrypts with the
+    /// previous one
+    #[test]
+    fn rotation() {
+        let ticketer = Ticketer::rotating(ROTATION).unwrap();
+        let first = ticketer.encrypt(b"first").unwrap();
+        assert_eq!(name(&first), name(&ticketer.encrypt(b"again").unwrap()));
+
+        expire(&ticketer);
+        let second = ticketer.encrypt(b"second").unwrap();
+        assert_ne!(name(&first), name(&second));
+        assert_eq!(Some(b"first".to_vec()), ticketer.decrypt(&first));
+        assert_eq!(Some(b"second".to_vec()), ticketer.decrypt(&second));
+    }
+
+    /// It refuses tickets of a key two rotations old
+    #[test]
+    fn grace_window() {
+        let ticketer = Ticketer::rotating(ROTATION).unwrap();
+        let first = ticketer.encrypt(b"first").unwrap();
+        expire(&ticketer);
+        let second = ticketer.encrypt(b"second").unwrap();
+        expire(&ticketer);
+        assert_eq!(None, ticketer.decrypt(&first));
+        assert_eq!(Some(b"second".to_vec()), ticketer.decrypt(&second));
+        assert_eq!(2, ticketer.keys.read().unwrap().1.len());
+    }
+
+    /// It encrypts with the first shared key and decrypts with every one, across instances
+    #[test]
+    fn shared() {
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("tickets");
+        let old = "AAECAwQFBgcICQoLDA0ODxAREhMUFRYXGBkaGxwdHh8=";
+        let new = "HxwdHhsaGRgXFhUUExIREA8ODQwLCgkIBwYFBAMCAQA=";
+        std::fs::write(&path, format!("{}\n", old)).unwrap();
+        let before = Ticketer::shared(&path).unwrap();
+        assert_eq!(12 * 60 * 60, before.lifetime());
+        let ticket = before.encrypt(b"session").unwrap();
+
+        std::fs::write(&path, format!("{}\n\n{}\n", new, old)).unwrap();
+        let during = Ticketer::shared(&path).unwrap();
+        assert_eq!(Some(b"session".to_vec()), during.decrypt(&ticket));
+        assert_ne!(name(&ticket), name(&during.encrypt(b"session").unwrap()));
+
+        std::fs::write(&path, format!("{}\n", new)).unwrap();
+        let after = Ticketer::shared(&path).unwrap();
+        assert_eq!(None, after.decrypt(&ticket));
+    }
+
+    /// It refuses files without keys or with malformed ones
+    #[test]
+    fn invalid_files() {
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("tickets");
+        std::fs::write(&path, "\n").unwrap();
+        let error = Ticketer::shared(&path).unwrap_err();
+        assert_eq!(io::ErrorKind::InvalidData, error.kind());
+        std::fs::write(&path, "c2hvcnQ=\n").unwrap();
+        let error = Ticketer::shared(&path).unwrap_err();
+        assert_eq!(io::ErrorKind::InvalidData, error.kind());
+    }
+}
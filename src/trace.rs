@@ -0,0 +1,388 @@
+//! W3C Trace Context: continuing the trace of a `traceparent` header in the spans of a request
+//! and passing it on to upstreams, so distributed traces run through the server.
+
+use crate::header::HeaderMap;
+use std::fmt;
+use std::str::FromStr;
+
+/// The position of a request in a distributed trace.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TraceContext {
+    /// The trace the request belongs to, the same on every hop.
+    pub trace_id: [u8; 16],
+    /// The span of the server handling the request, which upstreams see as their parent.
+    pub span_id: [u8; 8],
+    /// The span of the caller, `None` when the trace starts at the server.
+    pub parent_id: Option<[u8; 8]>,
+    /// Whether the trace is recorded, the `sampled` trace flag: the decision of the caller until
+    /// the [`Sampling`] of the server made its own.
+    pub sampled: bool,
+    /// The vendor-specific `tracestate` header, passed on unchanged.
+    pub state: Option<String>,
+}
+
+impl TraceContext {
+    /// Starts a new trace.
+    ///
+    /// # Returns
+    ///
+    /// A sampled context with random identifiers.
+    pub fn new() -> TraceContext {
+        TraceContext {
+            trace_id: nonzero(rand::random),
+            span_id: nonzero(rand::random),
+            parent_id: None,
+            sampled: true,
+            state: None,
+        }
+    }
+
+    /// Continues the trace of the `traceparent` and `tracestate` headers of a request, or
+    /// starts a new one when `traceparent` is missing or malformed.
+    ///
+    /// # Arguments
+    ///
+    /// * `headers`: The request headers.
+    ///
+    /// # Returns
+    ///
+    /// The context with a new span of the server.
+    pub fn from_headers(headers: &HeaderMap) -> TraceContext {
+        let Some((trace_id, parent_id, flags)) = headers.get("traceparent").and_then(parse) else {
+            return TraceContext::new();
+        };
+        let state: Vec<&str> = headers
+            .get_all("tracestate")
+            .map(str::trim)
+            .filter(|value| !value.is_empty())
+            .collect();
+        TraceContext {
+            trace_id,
+            span_id: nonzero(rand::random),
+            parent_id: Some(parent_id),
+            sampled: flags & 1 == 1,
+            state: (!state.is_empty()).then(|| state.join(",")),
+        }
+    }
+
+    /// The trace identifier in hexadecimal, e.g. `4bf92f3577b34da6a3ce929d0e0e4736`.
+    pub fn trace_id(&self) -> Hex<'_> {
+        Hex(&self.trace_id)
+    }
+
+    /// The span identifier of the server in hexadecimal, e.g. `00f067aa0ba902b7`.
+    pub fn span_id(&self) -> Hex<'_> {
+        Hex(&self.span_id)
+    }
+
+    /// Sets the `traceparent` and `tracestate` headers of a request the server sends, making
+    /// its span the parent of the receiver.
+    ///
+    /// # Arguments
+    ///
+    /// * `headers`: The headers of the outgoing request, whose trace headers are replaced.
+    pub fn inject(&self, headers: &mut HeaderMap) {
+        headers.insert("traceparent", self.to_string());
+        headers.remove("tracestate");
+        if let Some(state) = &self.state {
+            headers.append("tracestate", state.as_str());
+        }
+    }
+}
+
+impl Default for TraceContext {
+    fn default() -> TraceContext {
+        TraceContext::new()
+    }
+}
+
+impl fmt::Display for TraceContext {
+    /// Formats the `traceparent` header of the server span, e.g.
+    /// `00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01`.
+    fn fmt(&self, formatter: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            formatter,
+            "00-{}-{}-{:02x}",
+            self.trace_id(),
+            self.span_id(),
+            u8::from(self.sampled)
+        )
+    }
+}
+
+/// Decides whether the server records a trace, setting the `sampled` flag it passes on.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Sampler {
+    /// Records every trace.
+    Always,
+    /// Records no trace.
+    Never,
+    /// Records this share of traces, between `0.0` and `1.0`, chosen by the trace identifier so
+    /// every server sampling the same share agrees on a trace.
+    Ratio(f64),
+    /// Follows the `sampled` flag of the caller, and the wrapped sampler for traces starting at
+    /// the server.
+    ParentBased(Box<Sampler>),
+}
+
+impl Sampler {
+    /// Decides whether a trace is recorded.
+    ///
+    /// # Arguments
+    ///
+    /// * `context`: The context of a request, whose `sampled` flag is the decision of the
+    ///   caller when it has a parent.
+    ///
+    /// # Returns
+    ///
+    /// `true` to record the trace.
+    pub fn sample(&self, context: &TraceContext) -> bool {
+        match self {
+            Sampler::Always => true,
+            Sampler::Never => false,
+            Sampler::Ratio(ratio) => {
+                // The low half of the identifier is random even when the high half is not
+                let mut low = [0; 8];
+                low.copy_from_slice(&context.trace_id[8..]);
+                (u64::from_be_bytes(low) as f64) < ratio * u64::MAX as f64
+            }
+            Sampler::ParentBased(root) => match context.parent_id {
+                Some(_) => context.sampled,
+                None => root.sample(context),
+            },
+        }
+    }
+}
+
+impl Default for Sampler {
+    /// Follows the caller and records traces starting at the server, like OpenTelemetry.
+    fn default() -> Sampler {
+        Sampler::ParentBased(Box::new(Sampler::Always))
+    }
+}
+
+impl FromStr for Sampler {
+    type Err = ();
+
+    /// Parses `always`, `never`, `ratio:<share>`, e.g. `ratio:0.1`, or one of those prefixed
+    /// with `parent:`, e.g. `parent:ratio:0.1`.
+    fn from_str(value: &str) -> Result<Sampler, ()> {
+        let value = value.trim();
+        if let Some(root) = value.strip_prefix("parent:") {
+            return Ok(Sampler::ParentBased(Box::new(root.parse()?)));
+        }
+        match value {
+            "always" => Ok(Sampler::Always),
+            "never" => Ok(Sampler::Never),
+            _ => value
+                .strip_prefix("ratio:")
+                .and_then(|ratio| ratio.parse().ok())
+                .filter(|ratio| (0.0..=1.0).contains(ratio))
+                .map(Sampler::Ratio)
+                .ok_or(()),
+        }
+    }
+}
+
+/// The samplers of the server: one for every request, overridden for some path prefixes, e.g.
+/// to record no traces of health checks.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Sampling {
+    default: Sampler,
+    routes: Vec<(String, Sampler)>,
+}
+
+impl Sampling {
+    /// Creates the sampling of a server.
+    ///
+    /// # Arguments
+    ///
+    /// * `default`: The sampler of requests outside of the routes.
+    ///
+    /// # Returns
+    ///
+    /// The sampling without routes.
+    pub fn new(default: Sampler) -> Sampling {
+        Sampling {
+            default,
+            routes: Vec::new(),
+        }
+    }
+
+    /// Samples the requests under a path prefix differently, the longest matching prefix
+    /// winning.
+    ///
+    /// # Arguments
+    ///
+    /// * `route`: The path prefix, e.g. `/health`, which also matches `/health/live`.
+    /// * `sampler`: The sampler of those requests.
+    ///
+    /// # Returns
+    ///
+    /// The sampling with the route.
+    pub fn with_route(mut self, route: impl Into<String>, sampler: Sampler) -> Sampling {
+        self.routes.push((route.into(), sampler));
+        self
+    }
+
+    /// Decides whether the trace of a request is recorded.
+    ///
+    /// # Arguments
+    ///
+    /// * `path`: The path of the request.
+    /// * `context`: Its trace context.
+    ///
+    /// # Returns
+    ///
+    /// `true` to record the trace.
+    pub fn sample(&self, path: &str, context: &TraceContext) -> bool {
+        self.routes
+            .iter()
+            .filter(|(route, _)| {
+                path.strip_prefix(route.trim_end_matches('/'))
+                    .is_some_and(|rest| rest.is_empty() || rest.starts_with('/'))
+            })
+            .max_by_key(|(route, _)| route.trim_end_matches('/').len())
+            .map_or(&self.default, |(_, sampler)| sampler)
+            .sample(context)
+    }
+}
+
+/// Formats bytes as lowercase hexadecimal, e.g. a trace identifier in a log field.
+pub struct Hex<'a>(pub &'a [u8]);
+
+impl fmt::Display for Hex<'_> {
+    fn fmt(&self, formatter: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0
+            .iter()
+            .try_for_each(|byte| write!(formatter, "{:02x}", byte))
+    }
+}
+
+/// Parses a `traceparent` header into the trace identifier, the parent span, and the flags.
+/// Versions after `00` are read by their first four fields, as the specification asks.
+fn parse(value: &str) -> Option<([u8; 16], [u8; 8], u8)> {
+    let mut fields = value.trim().split('-');
+    let version = fields.next()?;
+    let trace_id = decode::<16>(fields.next()?)?;
+    let parent_id = decode::<8>(fields.next()?)?;
+    let [flags] = decode::<1>(fields.next()?)?;
+    let [version] = decode::<1>(version)?;
+    if version == 0xff
+        || (version == 0 && fields.next().is_some())
+        || trace_id == [0; 16]
+        || parent_id == [0; 8]
+    {
+        return None;
+    }
+    Some((trace_id, parent_id, flags))
+}
+
+/// Decodes exactly `N` bytes of lowercase hexadecimal.
+fn decode<const N: usize>(text: &str) -> Option<[u8; N]> {
+    let lowercase = |byte: u8| byte.is_ascii_digit() || (b'a'..=b'f').contains(&byte);
+    if text.len() != N * 2 || !text.bytes().all(lowercase) {
+        return None;
+    }
+    let mut bytes = [0; N];
+    for (index, byte) in bytes.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(text.get(index * 2..index * 2 + 2)?, 16).ok()?;
+    }
+    Some(bytes)
+}
+
+/// Draws identifiers until one is not all zeros, which the specification forbids.
+fn nonzero<const N: usize>(random: impl Fn() -> [u8; N]) -> [u8; N] {
+    loop {
+        let id = random();
+        if id != [0; N] {
+            return id;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// It continues a valid trace with a new span and starts over on malformed headers
+    #[test]
+    fn propagate() {
+        let mut headers = HeaderMap::new();
+        headers.append(
+            "traceparent",
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01",
+        );
+        headers.append("tracestate", "rojo=00f067aa0ba902b7");
+        headers.append("tracestate", "congo=t61rcWkgMzE");
+        let context = TraceContext::from_headers(&headers);
+        assert_eq!(
+            "4bf92f3577b34da6a3ce929d0e0e4736",
+            context.trace_id().to_string()
+        );
+        assert_eq!(
+            Some([0x00, 0xf0, 0x67, 0xaa, 0x0b, 0xa9, 0x02, 0xb7]),
+            context.parent_id
+        );
+        assert!(context.sampled);
+
+        let mut upstream = headers.clone();
+        context.inject(&mut upstream);
+        let traceparent = upstream.get("traceparent").unwrap();
+        assert!(traceparent.starts_with("00-4bf92f3577b34da6a3ce929d0e0e4736-"));
+        assert!(traceparent.ends_with("-01"));
+        assert!(!traceparent.contains("00f067aa0ba902b7"));
+        assert_eq!(
+            vec!["rojo=00f067aa0ba902b7,congo=t61rcWkgMzE"],
+            upstream.get_all("tracestate").collect::<Vec<_>>()
+        );
+
+        for malformed in [
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7",
+            "00-00000000000000000000000000000000-00f067aa0ba902b7-01",
+            "00-4BF92F3577B34DA6A3CE929D0E0E4736-00f067aa0ba902b7-01",
+            "ff-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01",
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01-extra",
+        ] {
+            let mut headers = HeaderMap::new();
+            headers.append("traceparent", malformed);
+            headers.append("tracestate", "rojo=1");
+            let context = TraceContext::from_headers(&headers);
+            assert_eq!(None, context.parent_id, "{}", malformed);
+            assert_eq!(None, context.state);
+        }
+        let mut headers = HeaderMap::new();
+        headers.append(
+            "traceparent",
+            "01-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-00-future",
+        );
+        assert!(!TraceContext::from_headers(&headers).sampled);
+    }
+
+    /// It samples by ratio, follows the parent, and overrides the sampler under routes
+    #[test]
+    fn sample() {
+        let mut context = TraceContext::new();
+        context.trace_id[8..].copy_from_slice(&(u64::MAX / 4).to_be_bytes());
+        assert!("ratio:0.3".parse::<Sampler>().unwrap().sample(&context));
+        assert!(!"ratio:0.2".parse::<Sampler>().unwrap().sample(&context));
+
+        let parent: Sampler = "parent:never".parse().unwrap();
+        assert!(!parent.sample(&context));
+        context.parent_id = Some([1; 8]);
+        assert!(parent.sample(&context));
+        context.sampled = false;
+        assert!(!parent.sample(&context));
+        assert!(Sampler::Always.sample(&context));
+
+        let sampling = Sampling::new(Sampler::Always)
+            .with_route("/health", Sampler::Never)
+            .with_route("/health/deep/", Sampler::Always);
+        assert!(!sampling.sample("/health/live", &context));
+        assert!(sampling.sample("/health/deep", &context));
+        assert!(sampling.sample("/healthy", &context));
+        for invalid in ["sometimes", "ratio:2", "ratio:", "parent:"] {
+            assert_eq!(Err(()), invalid.parse::<Sampler>(), "{}", invalid);
+        }
+    }
+}
@@ -0,0 +1,673 @@
+use crate::body::BodyReader;
+use crate::config::TusConfig;
+use crate::method::Method;
+use crate::request::Request;
+use crate::response::Response;
+use crate::status::StatusCode;
+use crate::StreamAdapter;
+use std::collections::BTreeSet;
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::io::AsyncWriteExt;
+use tokio::{fs, io};
+
+/// The only protocol version this server speaks.
+pub const VERSION: &str = "1.0.0";
+
+/// The protocol extensions advertised through `Tus-Extension`.
+pub const EXTENSIONS: &str = "creation,expiration";
+
+/// The size of each chunk appended by a PATCH.
+const CHUNK_SIZE: usize = 8 * 1024;
+
+/// Uploads currently receiving a PATCH, so concurrent appends cannot interleave.
+static APPENDING: Mutex<BTreeSet<String>> = Mutex::new(BTreeSet::new());
+
+/// Checks whether a request belongs to the tus endpoint.
+///
+/// # Arguments
+///
+/// * `request`: The incoming request.
+/// * `config`: The tus settings.
+///
+/// # Returns
+///
+/// True for OPTIONS, POST, HEAD, and PATCH requests at or below the tus route.
+pub fn matches(request: &Request, config: &TusConfig) -> bool {
+    matches!(
+        request.method,
+        Method::Options | Method::Post | Method::Head | Method::Patch
+    ) && upload_id(request, config).is_some()
+}
+
+/// Answers a tus request: OPTIONS for discovery, POST at the route to create an upload, HEAD on
+/// an upload to query its offset, and PATCH on an upload to append to it.
+///
+/// # Arguments
+///
+/// * `request`: A request accepted by [`matches`].
+/// * `stream`: The stream positioned at the start of the body.
+/// * `config`: The tus settings.
+///
+/// # Returns
+///
+/// The protocol response, including 412 when `Tus-Resumable` is missing or unsupported.
+///
+/// # Errors
+///
+/// Captures IO errors from reading the body and from reading or writing upload files.
+pub async fn handle(
+    request: &Request,
+    stream: &mut dyn StreamAdapter,
+    config: &TusConfig,
+) -> io::Result<Response> {
+    if request.method == Method::Options {
+        return Ok(Response::new(StatusCode::NO_CONTENT, "")
+            .with_header("Tus-Resumable", VERSION)
+            .with_header("Tus-Version", VERSION)
+            .with_header("Tus-Extension", EXTENSIONS)
+            .with_header("Tus-Max-Size", config.max_size.to_string()));
+    }
+    if request.header("Tus-Resumable") != Some(VERSION) {
+        return Ok(
+            Response::new(StatusCode::PRECONDITION_FAILED, "").with_header("Tus-Version", VERSION)
+        );
+    }
+    let id = upload_id(request, config).unwrap_or_default();
+    let response = match (&request.method, id.is_empty()) {
+        (Method::Post, true) => create(request, config).await?,
+        (Method::Head, false) => status(id, config).await?,
+        (Method::Patch, false) => append(request, stream, id, config).await?,
+        _ => Response::new(StatusCode::METHOD_NOT_ALLOWED, ""),
+    };
+    Ok(response.with_header("Tus-Resumable", VERSION))
+}
+
+/// Removes unfinished uploads whose expiration has passed.
+///
+/// # Arguments
+///
+/// * `config`: The tus settings locating the upload directory.
+///
+/// # Returns
+///
+/// The number of removed uploads.
+///
+/// # Errors
+///
+/// Captures IO errors from listing the directory or removing files.
+pub async fn purge_expired(config: &TusConfig) -> io::Result<usize> {
+    let mut entries = match fs::read_dir(&config.directory).await {
+        Ok(entries) => entries,
+        Err(error) if error.kind() == io::ErrorKind::NotFound => return Ok(0),
+        Err(error) => return Err(error),
+    };
+    let mut removed = 0;
+    while let Some(entry) = entries.next_entry().await? {
+        let name = entry.file_name().to_string_lossy().into_owned();
+        let id = match name.strip_suffix(".info") {
+            Some(id) if is_valid_id(id) => id.to_string(),
+            _ => continue,
+        };
+        if let Some(upload) = Upload::load(&id, config).await? {
+            if upload.is_expired().await? {
+                upload.remove().await?;
+                removed += 1;
+            }
+        }
+    }
+    Ok(removed)
+}
+
+/// Finds the upload addressed by the request path.
+///
+/// # Arguments
+///
+/// * `request`: The incoming request.
+/// * `config`: The tus settings.
+///
+/// # Returns
+///
+/// An empty string for the route itself, the upload ID for paths one segment below it, or
+/// `None` for unrelated paths.
+fn upload_id<'a>(request: &'a Request, config: &TusConfig) -> Option<&'a str> {
+    let rest = request
+        .path()
+        .strip_prefix(config.route.trim_end_matches('/'))?;
+    match rest.strip_prefix('/') {
+        Some(id) if !id.contains('/') => Some(id),
+        None if rest.is_empty() => Some(rest),
+        _ => None,
+    }
+}
+
+/// Checks whether `id` has the shape of an ID generated by [`create`].
+fn is_valid_id(id: &str) -> bool {
+    id.len() == 32 && id.bytes().all(|byte| byte.is_ascii_hexdigit())
+}
+
+/// Creates an empty upload of the announced `Upload-Length`.
+///
+/// # Arguments
+///
+/// * `request`: The POST request.
+/// * `config`: The tus settings.
+///
+/// # Returns
+///
+/// 201 with the upload `Location`, 400 without a valid `Upload-Length`, or 413 above the limit.
+///
+/// # Errors
+///
+/// Captures IO errors from creating the upload files.
+async fn create(request: &Request, config: &TusConfig) -> io::Result<Response> {
+    let length: u64 = match request.header("Upload-Length").map(str::parse) {
+        Some(Ok(length)) => length,
+        _ => {
+            return Ok(Response::new(
+                StatusCode::BAD_REQUEST,
+                "Missing or invalid Upload-Length\n",
+            ))
+        }
+    };
+    if length > config.max_size {
+        return Ok(Response::new(StatusCode::PAYLOAD_TOO_LARGE, ""));
+    }
+    let upload = Upload {
+        id: rand::random::<[u8; 16]>()
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect(),
+        directory: config.directory.clone(),
+        length,
+        metadata: request.header("Upload-Metadata").map(str::to_string),
+        expires: SystemTime::now() + config.expiration,
+    };
+    fs::create_dir_all(&config.directory).await?;
+    fs::File::create(upload.data_path()).await?;
+    upload.save().await?;
+    Ok(Response::new(StatusCode::CREATED, "")
+        .with_header(
+            "Location",
+            format!("{}/{}", config.route.trim_end_matches('/'), upload.id),
+        )
+        .with_header("Upload-Expires", httpdate::fmt_http_date(upload.expires)))
+}
+
+/// Reports the offset and length of an upload.
+///
+/// # Arguments
+///
+/// * `id`: The upload ID.
+/// * `config`: The tus settings.
+///
+/// # Returns
+///
+/// 200 with `Upload-Offset` and `Upload-Length`, 404 for unknown uploads, or 410 for expired
+/// ones, which are removed.
+///
+/// # Errors
+///
+/// Captures IO errors from reading or removing the upload files.
+async fn status(id: &str, config: &TusConfig) -> io::Result<Response> {
+    let upload = match Upload::find(id, config).await? {
+        Ok(upload) => upload,
+        Err(response) => return Ok(response),
+    };
+    let offset = upload.offset().await?;
+    let mut response = Response::new(StatusCode::OK, "")
+        .with_header("Upload-Offset", offset.to_string())
+        .with_header("Upload-Length", upload.length.to_string())
+        .with_header("Cache-Control", "no-store");
+    if let Some(metadata) = &upload.metadata {
+        response = response.with_header("Upload-Metadata", metadata.clone());
+    }
+    if offset < upload.length {
+        response = response.with_header("Upload-Expires", httpdate::fmt_http_date(upload.expires));
+    }
+    Ok(response)
+}
+
+/// Appends a PATCH body at the current offset of an upload and extends its expiration.
+///
+/// Bytes are persisted as they arrive, so a connection dropped mid-body still advances the
+/// offset that a following HEAD reports.
+///
+/// # Arguments
+///
+/// * `request`: The PATCH request.
+/// * `stream`: The stream positioned at the start of the body.
+/// * `id`: The upload ID.
+/// * `config`: The tus settings.
+///
+/// # Returns
+///
+/// 204 with the new `Upload-Offset`, or 409 when `Upload-Offset` does not match, 411 without a
+/// `Content-Length`, 413 past the upload length, 415 for the wrong `Content-Type`, 423 while
+/// another PATCH is in progress, and 404 or 410 as for HEAD.
+///
+/// # Errors
+///
+/// Captures IO errors from reading the body and from writing the upload files.
+async fn append(
+    request: &Request,
+    stream: &mut dyn StreamAdapter,
+    id: &str,
+    config: &TusConfig,
+) -> io::Result<Response> {
+    if request.header("Content-Type") != Some("application/offset+octet-stream") {
+        return Ok(Response::new(StatusCode::UNSUPPORTED_MEDIA_TYPE, ""));
+    }
+    let expected: u64 = match request.header("Upload-Offset").map(str::parse) {
+        Some(Ok(offset)) => offset,
+        _ => {
+            return Ok(Response::new(
+                StatusCode::BAD_REQUEST,
+                "Missing or invalid Upload-Offset\n",
+            ))
+        }
+    };
+    let length: u64 = match request.header("Content-Length").map(str::parse) {
+        Some(Ok(length)) => length,
+        _ => return Ok(Response::new(StatusCode::LENGTH_REQUIRED, "")),
+    };
+    let mut upload = match Upload::find(id, config).await? {
+        Ok(upload) => upload,
+        Err(response) => return Ok(response),
+    };
+    let _lock = match AppendLock::acquire(id) {
+        Some(lock) => lock,
+        None => return Ok(Response::new(StatusCode::LOCKED, "")),
+    };
+    let offset = upload.offset().await?;
+    if offset != expected {
+        return Ok(Response::new(StatusCode::CONFLICT, "")
+            .with_header("Upload-Offset", offset.to_string()));
+    }
+    if offset
+        .checked_add(length)
+        .is_none_or(|end| end > upload.length)
+    {
+        return Ok(Response::new(StatusCode::PAYLOAD_TOO_LARGE, ""));
+    }
+
+    let mut file = fs::OpenOptions::new()
+        .append(true)
+        .open(upload.data_path())
+        .await?;
+    let mut body = BodyReader::new(stream, length);
+    let mut chunk = vec![0; CHUNK_SIZE];
+    let result = loop {
+        match body.read(&mut chunk).await {
+            Ok(0) => break Ok(()),
+            Ok(count) => file.write_all(&chunk[..count]).await?,
+            Err(error) => break Err(error),
+        }
+    };
+    file.flush().await?;
+    file.sync_data().await?;
+    upload.expires = SystemTime::now() + config.expiration;
+    upload.save().await?;
+    result?;
+
+    let offset = offset + length;
+    let mut response =
+        Response::new(StatusCode::NO_CONTENT, "").with_header("Upload-Offset", offset.to_string());
+    if offset < upload.length {
+        response = response.with_header("Upload-Expires", httpdate::fmt_http_date(upload.expires));
+    }
+    Ok(response)
+}
+
+/// Marks an upload as receiving a PATCH until dropped.
+struct AppendLock(String);
+
+impl AppendLock {
+    /// Takes the lock for `id`, or returns `None` while another PATCH holds it.
+    fn acquire(id: &str) -> Option<AppendLock> {
+        let mut appending = APPENDING.lock().unwrap_or_else(|error| error.into_inner());
+        if appending.insert(id.to_string()) {
+            Some(AppendLock(id.to_string()))
+        } else {
+            None
+        }
+    }
+}
+
+impl Drop for AppendLock {
+    fn drop(&mut self) {
+        let mut appending = APPENDING.lock().unwrap_or_else(|error| error.into_inner());
+        appending.remove(&self.0);
+    }
+}
+
+/// An upload as recorded in its `.info` file. Its data lives in a file named by its ID.
+struct Upload {
+    id: String,
+    directory: PathBuf,
+    length: u64,
+    metadata: Option<String>,
+    expires: SystemTime,
+}
+
+impl Upload {
+    /// Loads an upload that is neither unknown nor expired.
+    ///
+    /// # Arguments
+    ///
+    /// * `id`: The upload ID from the request path.
+    /// * `config`: The tus settings.
+    ///
+    /// # Returns
+    ///
+    /// The upload, or the 404 or 410 response to send instead. Expired uploads are removed.
+    ///
+    /// # Errors
+    ///
+    /// Captures IO errors from reading or removing the upload files.
+    async fn find(id: &str, config: &TusConfig) -> io::Result<Result<Upload, Response>> {
+        if !is_valid_id(id) {
+            return Ok(Err(Response::new(StatusCode::NOT_FOUND, "")));
+        }
+        match Upload::load(id, config).await? {
+            None => Ok(Err(Response::new(StatusCode::NOT_FOUND, ""))),
+            Some(upload) if upload.is_expired().await? => {
+                upload.remove().await?;
+                Ok(Err(Response::new(StatusCode::GONE, "")))
+            }
+            Some(upload) => Ok(Ok(upload)),
+        }
+    }
+
+    /// Reads the `.info` file of an upload.
+    ///
+    /// # Arguments
+    ///
+    /// * `id`: A valid upload ID.
+    /// * `config`: The tus settings.
+    ///
+    /// # Returns
+    ///
+    /// The upload, or `None` if it does not exist.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`io::ErrorKind::InvalidData`] for a corrupt `.info` file and captures other IO
+    /// errors from reading it.
+    async fn load(id: &str, config: &TusConfig) -> io::Result<Option<Upload>> {
+        let path = config.directory.join(format!("{}.info", id));
+        let info = match fs::read_to_string(&path).await {
+            Ok(info) => info,
+            Err(error) if error.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(error) => return Err(error),
+        };
+        let mut length = None;
+        let mut expires = None;
+        let mut metadata = None;
+        for line in info.lines() {
+            match line.split_once('=') {
+                Some(("length", value)) => length = value.parse().ok(),
+                Some(("expires", value)) => expires = value.parse().ok(),
+                Some(("metadata", value)) => metadata = Some(value.to_string()),
+                _ => {}
+            }
+        }
+        match (length, expires) {
+            (Some(length), Some(expires)) => Ok(Some(Upload {
+                id: id.to_string(),
+                directory: config.directory.clone(),
+                length,
+                metadata,
+                expires: UNIX_EPOCH + Duration::from_secs(expires),
+            })),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("corrupt tus upload info: {}", path.display()),
+            )),
+        }
+    }
+
+    /// Writes the `.info` file of the upload.
+    async fn save(&self) -> io::Result<()> {
+        let expires = self
+            .expires
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        let mut info = format!("length={}\nexpires={}\n", self.length, expires);
+        if let Some(metadata) = &self.metadata {
+            info.push_str(&format!("metadata={}\n", metadata));
+        }
+        fs::write(self.info_path(), info).await
+    }
+
+    /// The number of bytes received so far.
+    async fn offset(&self) -> io::Result<u64> {
+        Ok(fs::metadata(self.data_path()).await?.len())
+    }
+
+    /// Checks whether the upload is unfinished and past its expiration.
+    async fn is_expired(&self) -> io::Result<bool> {
+        Ok(SystemTime::now() >= self.expires && self.offset().await? < self.length)
+    }
+
+    /// Removes the data and `.info` files of the upload.
+    async fn remove(&self) -> io::Result<()> {
+        for path in [self.data_path(), self.info_path()] {
+            match fs::remove_file(path).await {
+                Err(error) if error.kind() != io::ErrorKind::NotFound => return Err(error),
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    fn data_path(&self) -> PathBuf {
+        self.directory.join(&self.id)
+    }
+
+    fn info_path(&self) -> PathBuf {
+        self.directory.join(format!("{}.info", self.id))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use async_trait::async_trait;
+    use std::path::Path;
+    use tokio::io::AsyncReadExt;
+
+    /// Serves a fixed body.
+    struct BodyStream(&'static [u8]);
+
+    #[async_trait]
+    impl StreamAdapter for BodyStream {
+        async fn read_request(&mut self) -> io::Result<Request> {
+            Ok(Request::default())
+        }
+
+        async fn read_body(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.0.read(buf).await
+        }
+
+        async fn write_response(&mut self, _response: &[u8]) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn config(directory: &Path, expiration: Duration) -> TusConfig {
+        TusConfig {
+            route: "/files".to_string(),
+            directory: directory.to_path_buf(),
+            max_size: 100,
+            expiration,
+        }
+    }
+
+    /// Builds a request that carries `Tus-Resumable` in addition to `headers`.
+    fn request(method: &str, target: &str, headers: &[(&str, &str)]) -> Request {
+        let mut request = Request {
+            method: method.parse().unwrap(),
+            target: target.parse().unwrap(),
+            headers: [("Tus-Resumable", VERSION)].into_iter().collect(),
+            ..Request::default()
+        };
+        for (name, value) in headers {
+            request.headers.append(*name, *value);
+        }
+        request
+    }
+
+    fn header<'a>(response: &'a Response, name: &str) -> Option<&'a str> {
+        response.header(name)
+    }
+
+    /// Sends a PATCH with `body` at `offset` and returns the response.
+    async fn patch(
+        target: &str,
+        offset: &str,
+        body: &'static [u8],
+        config: &TusConfig,
+    ) -> Response {
+        let length = body.len().to_string();
+        let request = request(
+            "PATCH",
+            target,
+            &[
+                ("Content-Type", "application/offset+octet-stream"),
+                ("Upload-Offset", offset),
+                ("Content-Length", &length),
+            ],
+        );
+        handle(&request, &mut BodyStream(body), config)
+            .await
+            .unwrap()
+    }
+
+    /// It creates an upload, appends to it in two PATCHes, and rejects a stale offset
+    #[tokio::test]
+    async fn create_and_resume() {
+        let directory = tempfile::tempdir().unwrap();
+        let config = config(directory.path(), Duration::from_secs(60));
+        let post = request(
+            "POST",
+            "/files",
+            &[
+                ("Upload-Length", "11"),
+                ("Upload-Metadata", "filename YS50eHQ="),
+            ],
+        );
+        let created = handle(&post, &mut BodyStream(b""), &config).await.unwrap();
+        assert_eq!(StatusCode::CREATED, created.status);
+        let location = header(&created, "Location").unwrap().to_string();
+        assert!(header(&created, "Upload-Expires").is_some());
+
+        let first = patch(&location, "0", b"hello", &config).await;
+        assert_eq!(StatusCode::NO_CONTENT, first.status);
+        assert_eq!(Some("5"), header(&first, "Upload-Offset"));
+        let stale = patch(&location, "0", b" world", &config).await;
+        assert_eq!(StatusCode::CONFLICT, stale.status);
+
+        let head = request("HEAD", &location, &[]);
+        let status = handle(&head, &mut BodyStream(b""), &config).await.unwrap();
+        assert_eq!(Some("5"), header(&status, "Upload-Offset"));
+        assert_eq!(Some("11"), header(&status, "Upload-Length"));
+        assert_eq!(
+            Some("filename YS50eHQ="),
+            header(&status, "Upload-Metadata")
+        );
+
+        let last = patch(&location, "5", b" world", &config).await;
+        assert_eq!(Some("11"), header(&last, "Upload-Offset"));
+        assert_eq!(None, header(&last, "Upload-Expires"));
+        let id = location.rsplit('/').next().unwrap();
+        assert_eq!(
+            "hello world",
+            std::fs::read_to_string(directory.path().join(id)).unwrap()
+        );
+        let overflow = patch(&location, "11", b"!", &config).await;
+        assert_eq!(StatusCode::PAYLOAD_TOO_LARGE, overflow.status);
+        let wrapping = request(
+            "PATCH",
+            &location,
+            &[
+                ("Content-Type", "application/offset+octet-stream"),
+                ("Upload-Offset", "11"),
+                ("Content-Length", "18446744073709551615"),
+            ],
+        );
+        let response = handle(&wrapping, &mut BodyStream(b""), &config)
+            .await
+            .unwrap();
+        assert_eq!(StatusCode::PAYLOAD_TOO_LARGE, response.status);
+    }
+
+    /// It requires the protocol version, a known upload, and the offset content type
+    #[tokio::test]
+    async fn rejections() {
+        let directory = tempfile::tempdir().unwrap();
+        let config = config(directory.path(), Duration::from_secs(60));
+        let mut unversioned = request("POST", "/files", &[("Upload-Length", "1")]);
+        unversioned.headers.remove("Tus-Resumable");
+        let response = handle(&unversioned, &mut BodyStream(b""), &config)
+            .await
+            .unwrap();
+        assert_eq!(StatusCode::PRECONDITION_FAILED, response.status);
+
+        let unknown = request("HEAD", "/files/0123456789abcdef0123456789abcdef", &[]);
+        let response = handle(&unknown, &mut BodyStream(b""), &config)
+            .await
+            .unwrap();
+        assert_eq!(StatusCode::NOT_FOUND, response.status);
+
+        let wrong_type = request("PATCH", "/files/x", &[("Content-Type", "text/plain")]);
+        let response = handle(&wrong_type, &mut BodyStream(b""), &config)
+            .await
+            .unwrap();
+        assert_eq!(StatusCode::UNSUPPORTED_MEDIA_TYPE, response.status);
+
+        let large = request("POST", "/files", &[("Upload-Length", "101")]);
+        let response = handle(&large, &mut BodyStream(b""), &config).await.unwrap();
+        assert_eq!(StatusCode::PAYLOAD_TOO_LARGE, response.status);
+    }
+
+    /// It answers expired uploads with 410 and purges them from disk
+    #[tokio::test]
+    async fn expiration() {
+        let directory = tempfile::tempdir().unwrap();
+        let config = config(directory.path(), Duration::ZERO);
+        for _ in 0..2 {
+            let post = request("POST", "/files", &[("Upload-Length", "4")]);
+            handle(&post, &mut BodyStream(b""), &config).await.unwrap();
+        }
+        let id = std::fs::read_dir(directory.path())
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+            .find(|name| !name.ends_with(".info"))
+            .unwrap();
+        let head = request("HEAD", &format!("/files/{}", id), &[]);
+        let response = handle(&head, &mut BodyStream(b""), &config).await.unwrap();
+        assert_eq!(StatusCode::GONE, response.status);
+        assert_eq!(1, purge_expired(&config).await.unwrap());
+        assert_eq!(0, std::fs::read_dir(directory.path()).unwrap().count());
+    }
+
+    /// It advertises the version, extensions, and size limit without requiring `Tus-Resumable`
+    #[tokio::test]
+    async fn options() {
+        let directory = tempfile::tempdir().unwrap();
+        let config = config(directory.path(), Duration::from_secs(60));
+        assert!(matches(&request("OPTIONS", "/files", &[]), &config));
+        assert!(!matches(&request("GET", "/files", &[]), &config));
+        let mut options = request("OPTIONS", "/files", &[]);
+        options.headers.clear();
+        let response = handle(&options, &mut BodyStream(b""), &config)
+            .await
+            .unwrap();
+        assert_eq!(StatusCode::NO_CONTENT, response.status);
+        assert_eq!(Some(EXTENSIONS), header(&response, "Tus-Extension"));
+        assert_eq!(Some("100"), header(&response, "Tus-Max-Size"));
+    }
+}
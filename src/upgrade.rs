@@ -0,0 +1,235 @@
+//! Upgrades the binary without dropping connections: on `SIGUSR2` the running process starts
+//! the new binary with its listening sockets, the new process adopts them instead of binding,
+//! and once it reports ready the old process drains. Connections arriving in between wait in
+//! the backlog of the shared sockets.
+
+use crate::log;
+use std::collections::HashMap;
+use std::io::{Read, Write};
+use std::net::SocketAddr;
+use std::os::fd::{AsRawFd, BorrowedFd, FromRawFd, OwnedFd, RawFd};
+use std::os::unix::process::CommandExt;
+use std::process::Command;
+use std::time::Duration;
+use tokio::{io, net, task, time};
+
+/// The environment variable passing listening sockets to the new binary, as comma-separated
+/// `name=descriptor` entries, e.g. `http=5,admin=6`.
+pub const LISTENERS_VAR: &str = "WEB_SERVER_LISTENERS";
+
+/// The environment variable passing the descriptor the new binary reports ready on.
+pub const READY_VAR: &str = "WEB_SERVER_UPGRADE_READY";
+
+/// How long the old process waits for the new one to report ready before giving up.
+pub const READY_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// The sockets and readiness pipe a process got from the process that upgraded to it.
+#[derive(Debug, Default)]
+pub struct Inherited {
+    listeners: HashMap<String, std::net::TcpListener>,
+    ready: Option<std::io::PipeWriter>,
+}
+
+impl Inherited {
+    /// Adopts the descriptors named by [`LISTENERS_VAR`] and [`READY_VAR`]. Call it once at
+    /// startup, since it takes ownership of the descriptors.
+    ///
+    /// # Returns
+    ///
+    /// The inherited sockets, none when the process was not started by an upgrade.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`io::ErrorKind::InvalidInput`] for a malformed entry, and captures IO errors
+    /// from descriptors that are not open.
+    pub fn from_env() -> io::Result<Inherited> {
+        Inherited::new(
+            std::env::var(LISTENERS_VAR).ok().as_deref(),
+            std::env::var(READY_VAR).ok().as_deref(),
+        )
+    }
+
+    fn new(listeners: Option<&str>, ready: Option<&str>) -> io::Result<Inherited> {
+        let mut inherited = Inherited::default();
+        for entry in listeners.into_iter().flat_map(|value| value.split(',')) {
+            let (name, descriptor) = entry
+                .split_once('=')
+                .and_then(|(name, descriptor)| Some((name, descriptor.parse().ok()?)))
+                .ok_or_else(|| invalid(LISTENERS_VAR, entry))?;
+            let listener = std::net::TcpListener::from(adopt(descriptor)?);
+            inherited.listeners.insert(name.to_string(), listener);
+        }
+        if let Some(descriptor) = ready {
+            let descriptor = descriptor
+                .parse()
+                .map_err(|_| invalid(READY_VAR, descriptor))?;
+            inherited.ready = Some(std::io::PipeWriter::from(adopt(descriptor)?));
+        }
+        Ok(inherited)
+    }
+
+    /// Takes the inherited listener of a name, or binds a new one.
+    ///
+    /// # Arguments
+    ///
+    /// * `name`: The name the old process passed the listener by, e.g. `http`.
+    /// * `address`: The address to bind without an inherited listener.
+    ///
+    /// # Returns
+    ///
+    /// The listener, which may have been bound to another address by the old process.
+    ///
+    /// # Errors
+    ///
+    /// Captures IO errors from binding or registering the listener.
+    pub async fn listen(
+        &mut self,
+        name: &str,
+        address: SocketAddr,
+    ) -> io::Result<net::TcpListener> {
+        match self.listeners.remove(name) {
+            Some(listener) => {
+                listener.set_nonblocking(true)?;
+                net::TcpListener::from_std(listener)
+            }
+            None => net::TcpListener::bind(address).await,
+        }
+    }
+
+    /// Tells the old process that this one serves, so it starts draining. Does nothing when
+    /// the process was not started by an upgrade.
+    ///
+    /// # Errors
+    ///
+    /// Captures IO errors from writing to the pipe.
+    pub fn ready(&mut self) -> io::Result<()> {
+        match self.ready.take() {
+            Some(mut ready) => ready.write_all(&[1]),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Starts the current executable again with the same arguments and the listening sockets, and
+/// waits until it reports ready through [`Inherited::ready`].
+///
+/// # Arguments
+///
+/// * `listeners`: The listening sockets to pass by name, which the new process takes with
+///   [`Inherited::listen`].
+///
+/// # Returns
+///
+/// `Ok(())` once the new process serves, when the caller should drain.
+///
+/// # Errors
+///
+/// Returns [`io::ErrorKind::TimedOut`] when the new process was not ready within
+/// [`READY_TIMEOUT`], in which case it is killed, [`io::ErrorKind::Other`] when it exited
+/// first, and captures IO errors from starting it.
+pub async fn upgrade(listeners: &[(&str, BorrowedFd<'_>)]) -> io::Result<()> {
+    let (mut reader, writer) = std::io::pipe()?;
+    let mut descriptors: Vec<RawFd> = listeners
+        .iter()
+        .map(|(_, listener)| listener.as_raw_fd())
+        .collect();
+    descriptors.push(writer.as_raw_fd());
+    let names = listeners
+        .iter()
+        .map(|(name, listener)| format!("{}={}", name, listener.as_raw_fd()))
+        .collect::<Vec<_>>()
+        .join(",");
+    let mut command = Command::new(std::env::current_exe()?);
+    command
+        .args(std::env::args_os().skip(1))
+        .env(LISTENERS_VAR, names)
+        .env(READY_VAR, writer.as_raw_fd().to_string());
+    // SAFETY: `fcntl` is async-signal-safe, and the descriptors stay open until the spawn
+    // returned, so the forked child only changes flags of its own copies
+    unsafe {
+        command.pre_exec(move || {
+            for descriptor in &descriptors {
+                let flags = libc::fcntl(*descriptor, libc::F_GETFD);
+                if flags < 0
+                    || libc::fcntl(*descriptor, libc::F_SETFD, flags & !libc::FD_CLOEXEC) < 0
+                {
+                    return Err(std::io::Error::last_os_error());
+                }
+            }
+            Ok(())
+        });
+    }
+    let mut child = command.spawn()?;
+    // Only the new process may hold the writing end, so its exit ends the read below
+    drop(writer);
+    log::info(format_args!("started process {} to upgrade to", child.id()));
+    let ready = task::spawn_blocking(move || reader.read(&mut [0]));
+    match time::timeout(READY_TIMEOUT, ready).await {
+        Ok(Ok(Ok(1))) => Ok(()),
+        Ok(Ok(Ok(_))) => Err(io::Error::other(
+            "the new process exited before it was ready",
+        )),
+        Ok(Ok(Err(error))) => Err(error),
+        Ok(Err(error)) => Err(error.into()),
+        Err(_) => {
+            child.kill()?;
+            child.wait()?;
+            Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                "the new process was not ready in time",
+            ))
+        }
+    }
+}
+
+/// Takes ownership of an inherited descriptor and keeps it from leaking into child processes,
+/// e.g. CGI scripts.
+fn adopt(descriptor: RawFd) -> io::Result<OwnedFd> {
+    // SAFETY: `fcntl` only reads and sets the flags of the descriptor, and fails for one that
+    // is not open
+    let flags = unsafe { libc::fcntl(descriptor, libc::F_GETFD) };
+    if flags < 0 || unsafe { libc::fcntl(descriptor, libc::F_SETFD, flags | libc::FD_CLOEXEC) } < 0
+    {
+        return Err(io::Error::last_os_error());
+    }
+    // SAFETY: The descriptor is open, and the old process passed it to this one alone
+    Ok(unsafe { OwnedFd::from_raw_fd(descriptor) })
+}
+
+fn invalid(variable: &str, value: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidInput,
+        format!("{} has an invalid entry {:?}", variable, value),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::fd::IntoRawFd;
+
+    /// It adopts the sockets and pipe named by the variables and rejects malformed entries
+    #[tokio::test]
+    async fn inherit() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let (mut reader, writer) = std::io::pipe().unwrap();
+        let listeners = format!("http={}", listener.into_raw_fd());
+        let ready = writer.into_raw_fd().to_string();
+        let mut inherited = Inherited::new(Some(&listeners), Some(&ready)).unwrap();
+        let unused = SocketAddr::from(([127, 0, 0, 1], 0));
+        let listener = inherited.listen("http", unused).await.unwrap();
+        assert_eq!(address, listener.local_addr().unwrap());
+        let other = inherited.listen("http", unused).await.unwrap();
+        assert_ne!(address, other.local_addr().unwrap());
+        inherited.ready().unwrap();
+        drop(inherited);
+        let mut signal = Vec::new();
+        reader.read_to_end(&mut signal).unwrap();
+        assert_eq!(vec![1], signal);
+
+        for listeners in ["http", "http=x", "http=-1"] {
+            assert!(Inherited::new(Some(listeners), None).is_err());
+        }
+    }
+}
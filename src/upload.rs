@@ -0,0 +1,638 @@
+use crate::body::BodyReader;
+use crate::config::UploadConfig;
+use crate::method::Method;
+use crate::path;
+use crate::request::Request;
+use crate::response::Response;
+use crate::status::StatusCode;
+use crate::StreamAdapter;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use tokio::io::AsyncWriteExt;
+use tokio::{fs, io};
+
+/// The size of each chunk read from the stream and written to disk.
+const CHUNK_SIZE: usize = 8 * 1024;
+
+/// The largest accepted block of headers for one multipart part.
+const MAX_PART_HEADERS: usize = 8 * 1024;
+
+/// Distinguishes temporary files written concurrently by the same process.
+static TEMPORARY_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+/// Checks whether a request is an upload handled by [`handle`].
+///
+/// # Arguments
+///
+/// * `request`: The incoming request.
+/// * `config`: The upload settings.
+///
+/// # Returns
+///
+/// True for PUT or POST requests at or below the upload route.
+pub fn matches(request: &Request, config: &UploadConfig) -> bool {
+    matches!(request.method, Method::Put | Method::Post) && relative_path(request, config).is_some()
+}
+
+/// Streams an upload to files below the upload directory.
+///
+/// A PUT, or a POST that is not `multipart/form-data`, writes its body to the file named by the
+/// path below the upload route. A multipart POST writes every part that carries a file name into
+/// the directory named by that path. Every file is written next to its target under a temporary
+/// name and renamed once complete, so readers never observe partial uploads.
+///
+/// # Arguments
+///
+/// * `request`: A request accepted by [`matches`].
+/// * `stream`: The stream positioned at the start of the body.
+/// * `config`: The upload settings.
+///
+/// # Returns
+///
+/// 201 when files were created, 204 when a PUT replaced a file, 400 for invalid paths, paths
+/// through symbolic links, which may lead outside the upload directory, or malformed multipart
+/// bodies, 411 without a `Content-Length`, or 413 above the size limit.
+///
+/// # Errors
+///
+/// Captures IO errors from reading the body and from writing or renaming files.
+pub async fn handle(
+    request: &Request,
+    stream: &mut dyn StreamAdapter,
+    config: &UploadConfig,
+) -> io::Result<Response> {
+    let length = match request.header("Content-Length") {
+        Some(value) if request.header("Transfer-Encoding").is_none() => match value.parse() {
+            Ok(length) => length,
+            Err(_) => {
+                return Ok(Response::new(
+                    StatusCode::BAD_REQUEST,
+                    "Invalid Content-Length\n",
+                ))
+            }
+        },
+        _ => return Ok(Response::new(StatusCode::LENGTH_REQUIRED, "")),
+    };
+    if length > config.max_size {
+        return Ok(Response::new(StatusCode::PAYLOAD_TOO_LARGE, ""));
+    }
+    let target = match relative_path(request, config)
+        .and_then(|relative| path::resolve(&config.directory, relative))
+    {
+        Some(target) if !path::through_symlink(&config.directory, &target).await? => target,
+        _ => {
+            return Ok(Response::new(
+                StatusCode::BAD_REQUEST,
+                "Invalid upload path\n",
+            ))
+        }
+    };
+
+    let mut body = BodyReader::new(stream, length);
+    let boundary = request
+        .header("Content-Type")
+        .filter(|value| value.starts_with("multipart/form-data"))
+        .and_then(|value| parameter(value, "boundary"));
+    let result = match boundary {
+        Some(boundary) if request.method == Method::Post => {
+            save_multipart(&mut body, &boundary, &target).await
+        }
+        _ if target == config.directory => {
+            return Ok(Response::new(
+                StatusCode::BAD_REQUEST,
+                "Missing file name\n",
+            ))
+        }
+        _ => save_body(&mut body, &target).await,
+    };
+    match result {
+        Ok(Saved::Created(names)) => {
+            let mut listing = names.join("\n");
+            listing.push('\n');
+            Ok(Response::new(StatusCode::CREATED, listing)
+                .with_header("Content-Type", "text/plain; charset=utf-8"))
+        }
+        Ok(Saved::Replaced) => Ok(Response::new(StatusCode::NO_CONTENT, "")),
+        Err(error) if error.kind() == io::ErrorKind::InvalidData => Ok(Response::new(
+            StatusCode::BAD_REQUEST,
+            format!("{}\n", error),
+        )),
+        Err(error) => Err(error),
+    }
+}
+
+/// The outcome of a successful upload.
+enum Saved {
+    /// New files were created, listed by name.
+    Created(Vec<String>),
+    /// An existing file was replaced by a PUT.
+    Replaced,
+}
+
+/// Finds the part of the request path below the upload route.
+///
+/// # Arguments
+///
+/// * `request`: The incoming request.
+/// * `config`: The upload settings.
+///
+/// # Returns
+///
+/// The remaining percent-encoded path, possibly empty, or `None` outside the upload route.
+fn relative_path<'a>(request: &'a Request, config: &UploadConfig) -> Option<&'a str> {
+    let rest = request
+        .path()
+        .strip_prefix(config.route.trim_end_matches('/'))?;
+    if rest.is_empty() || rest.starts_with('/') {
+        Some(rest)
+    } else {
+        None
+    }
+}
+
+/// Extracts a `key=value` parameter from a header value such as
+/// `multipart/form-data; boundary=abc`.
+///
+/// # Arguments
+///
+/// * `value`: The header value.
+/// * `key`: The parameter name, compared ignoring ASCII case.
+///
+/// # Returns
+///
+/// The parameter value without surrounding quotes.
+fn parameter(value: &str, key: &str) -> Option<String> {
+    value.split(';').skip(1).find_map(|parameter| {
+        let (name, value) = parameter.split_once('=')?;
+        if name.trim().eq_ignore_ascii_case(key) {
+            Some(value.trim().trim_matches('"').to_string())
+        } else {
+            None
+        }
+    })
+}
+
+/// Writes the whole body to `target`.
+///
+/// # Arguments
+///
+/// * `body`: The request body.
+/// * `target`: The file to create or replace.
+///
+/// # Returns
+///
+/// Whether the file was created or replaced.
+///
+/// # Errors
+///
+/// Captures IO errors from reading the body and from writing or renaming the file.
+async fn save_body(body: &mut BodyReader<'_>, target: &Path) -> io::Result<Saved> {
+    let existed = fs::metadata(target).await.is_ok();
+    let mut file = PartialFile::create(target).await?;
+    let mut chunk = vec![0; CHUNK_SIZE];
+    loop {
+        let count = match body.read(&mut chunk).await {
+            Ok(count) => count,
+            Err(error) => return Err(file.abort(error).await),
+        };
+        if count == 0 {
+            break;
+        }
+        if let Err(error) = file.write(&chunk[..count]).await {
+            return Err(file.abort(error).await);
+        }
+    }
+    file.commit().await?;
+    if existed {
+        Ok(Saved::Replaced)
+    } else {
+        let name = target.file_name().unwrap_or_default();
+        Ok(Saved::Created(vec![name.to_string_lossy().into_owned()]))
+    }
+}
+
+/// Where [`save_multipart`] is within a multipart body.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Position {
+    /// Before the first delimiter.
+    Preamble,
+    /// Right after a delimiter, before either `\r\n` or the closing `--`.
+    Delimiter,
+    /// Inside the headers of a part.
+    Headers,
+    /// Inside the content of a part.
+    Content,
+    /// After the closing delimiter.
+    Epilogue,
+}
+
+/// Writes every file part of a multipart body into `directory`, keeping at most one chunk plus
+/// a delimiter in memory.
+///
+/// # Arguments
+///
+/// * `body`: The request body.
+/// * `boundary`: The boundary from the `Content-Type` header.
+/// * `directory`: The directory to write files into, created when missing.
+///
+/// # Returns
+///
+/// The names of the created files.
+///
+/// # Errors
+///
+/// Returns [`io::ErrorKind::InvalidData`] for truncated or malformed bodies and captures IO
+/// errors from reading the body and from writing or renaming files.
+async fn save_multipart(
+    body: &mut BodyReader<'_>,
+    boundary: &str,
+    directory: &Path,
+) -> io::Result<Saved> {
+    let delimiter = format!("\r\n--{}", boundary).into_bytes();
+    // The first delimiter may start the body without a preceding line break.
+    let mut buffer = b"\r\n".to_vec();
+    let mut chunk = vec![0; CHUNK_SIZE];
+    let mut position = Position::Preamble;
+    let mut current: Option<PartialFile> = None;
+    let mut names = Vec::new();
+
+    loop {
+        let progressed = match position {
+            Position::Preamble => match find(&buffer, &delimiter) {
+                Some(index) => {
+                    buffer.drain(..index + delimiter.len());
+                    position = Position::Delimiter;
+                    true
+                }
+                None => {
+                    let keep = buffer.len().min(delimiter.len() - 1);
+                    buffer.drain(..buffer.len() - keep);
+                    false
+                }
+            },
+            Position::Delimiter if buffer.len() >= 2 => {
+                if buffer.starts_with(b"--") {
+                    position = Position::Epilogue;
+                } else if buffer.starts_with(b"\r\n") {
+                    buffer.drain(..2);
+                    position = Position::Headers;
+                } else {
+                    return Err(invalid("malformed multipart delimiter"));
+                }
+                true
+            }
+            Position::Headers => {
+                let end = if buffer.starts_with(b"\r\n") {
+                    Some((0, 2))
+                } else {
+                    find(&buffer, b"\r\n\r\n").map(|index| (index, index + 4))
+                };
+                match end {
+                    Some((index, skip)) => {
+                        let headers = String::from_utf8_lossy(&buffer[..index]).into_owned();
+                        buffer.drain(..skip);
+                        if let Some(name) = part_file_name(&headers) {
+                            fs::create_dir_all(directory).await?;
+                            current = Some(PartialFile::create(&directory.join(&name)).await?);
+                            names.push(name);
+                        }
+                        position = Position::Content;
+                        true
+                    }
+                    None if buffer.len() > MAX_PART_HEADERS => {
+                        return Err(invalid("multipart headers too large"))
+                    }
+                    None => false,
+                }
+            }
+            Position::Content => {
+                let (write, consumed, done) = match find(&buffer, &delimiter) {
+                    Some(index) => (index, index + delimiter.len(), true),
+                    None => {
+                        let write = buffer.len().saturating_sub(delimiter.len() - 1);
+                        (write, write, false)
+                    }
+                };
+                if let Some(file) = current.as_mut() {
+                    if let Err(error) = file.write(&buffer[..write]).await {
+                        return Err(current.take().unwrap().abort(error).await);
+                    }
+                }
+                buffer.drain(..consumed);
+                if done {
+                    if let Some(file) = current.take() {
+                        file.commit().await?;
+                    }
+                    position = Position::Delimiter;
+                }
+                done
+            }
+            Position::Delimiter | Position::Epilogue => false,
+        };
+        if progressed {
+            continue;
+        }
+
+        let count = match body.read(&mut chunk).await {
+            Ok(count) => count,
+            Err(error) => return Err(abort(current, error).await),
+        };
+        if count == 0 {
+            if position == Position::Epilogue {
+                return Ok(Saved::Created(names));
+            }
+            return Err(abort(current, invalid("truncated multipart body")).await);
+        }
+        if position != Position::Epilogue {
+            buffer.extend_from_slice(&chunk[..count]);
+        }
+    }
+}
+
+/// Aborts the file being written, if any, and passes `error` through.
+async fn abort(current: Option<PartialFile>, error: io::Error) -> io::Error {
+    match current {
+        Some(file) => file.abort(error).await,
+        None => error,
+    }
+}
+
+/// Builds an [`io::ErrorKind::InvalidData`] error for a malformed body.
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_string())
+}
+
+/// Finds the first occurrence of `needle` in `haystack`.
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack
+        .windows(needle.len())
+        .position(|window| window == needle)
+}
+
+/// Extracts a safe file name from the `Content-Disposition` header of a part.
+///
+/// # Arguments
+///
+/// * `headers`: The raw headers of one part.
+///
+/// # Returns
+///
+/// The last path segment of the `filename` parameter, or `None` for parts that are not files
+/// or whose name is empty, `.`, or `..`.
+fn part_file_name(headers: &str) -> Option<String> {
+    let disposition = headers.lines().find_map(|line| {
+        let (name, value) = line.split_once(':')?;
+        if name.trim().eq_ignore_ascii_case("Content-Disposition") {
+            Some(value.trim())
+        } else {
+            None
+        }
+    })?;
+    let file_name = parameter(disposition, "filename")?;
+    let name = file_name.rsplit(['/', '\\']).next().unwrap_or_default();
+    match name {
+        "" | "." | ".." => None,
+        name => Some(name.to_string()),
+    }
+}
+
+/// A file being written under a temporary name next to where it will end up.
+struct PartialFile {
+    file: fs::File,
+    temporary: PathBuf,
+    target: PathBuf,
+}
+
+impl PartialFile {
+    /// Creates the temporary file for `target`, creating missing parent directories.
+    ///
+    /// # Arguments
+    ///
+    /// * `target`: The path the file is renamed to by [`PartialFile::commit`].
+    ///
+    /// # Returns
+    ///
+    /// The open temporary file.
+    ///
+    /// # Errors
+    ///
+    /// Captures IO errors from creating directories or the file.
+    async fn create(target: &Path) -> io::Result<PartialFile> {
+        let parent = target.parent().unwrap_or_else(|| Path::new("."));
+        fs::create_dir_all(parent).await?;
+        let name = target.file_name().unwrap_or_default().to_string_lossy();
+        let temporary = parent.join(format!(
+            ".{}.{}.{}.part",
+            name,
+            std::process::id(),
+            TEMPORARY_COUNTER.fetch_add(1, Ordering::Relaxed)
+        ));
+        let file = fs::File::create(&temporary).await?;
+        Ok(PartialFile {
+            file,
+            temporary,
+            target: target.to_path_buf(),
+        })
+    }
+
+    /// Appends bytes to the temporary file.
+    async fn write(&mut self, bytes: &[u8]) -> io::Result<()> {
+        self.file.write_all(bytes).await
+    }
+
+    /// Flushes the temporary file to disk and renames it onto the target.
+    async fn commit(mut self) -> io::Result<()> {
+        self.file.flush().await?;
+        self.file.sync_all().await?;
+        fs::rename(&self.temporary, &self.target).await
+    }
+
+    /// Removes the temporary file and passes `error` through.
+    async fn abort(self, error: io::Error) -> io::Error {
+        let _ = fs::remove_file(&self.temporary).await;
+        error
+    }
+}
+
+/// Removes the temporary file of an upload cut off before [`PartialFile::commit`], e.g. by
+/// [`crate::config::Config::request_timeout`]. After a commit or an abort there is nothing left
+/// to remove.
+impl Drop for PartialFile {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.temporary);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use async_trait::async_trait;
+    use tokio::io::AsyncReadExt;
+
+    /// Serves a body at most seven bytes at a time to exercise chunk boundaries.
+    struct BodyStream(&'static [u8]);
+
+    #[async_trait]
+    impl StreamAdapter for BodyStream {
+        async fn read_request(&mut self) -> io::Result<Request> {
+            Ok(Request::default())
+        }
+
+        async fn read_body(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let limit = buf.len().min(7);
+            self.0.read(&mut buf[..limit]).await
+        }
+
+        async fn write_response(&mut self, _response: &[u8]) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn config(directory: &Path) -> UploadConfig {
+        UploadConfig {
+            route: "/upload".to_string(),
+            directory: directory.to_path_buf(),
+            max_size: 1024,
+        }
+    }
+
+    fn request(method: &str, target: &str, headers: &[(&str, &str)]) -> Request {
+        Request {
+            method: method.parse().unwrap(),
+            target: target.parse().unwrap(),
+            headers: headers.iter().copied().collect(),
+            ..Request::default()
+        }
+    }
+
+    /// It matches PUT and POST at or below the route only
+    #[test]
+    fn matching() {
+        let config = config(Path::new("/tmp"));
+        assert!(matches(&request("PUT", "/upload/a.txt", &[]), &config));
+        assert!(matches(&request("POST", "/upload", &[]), &config));
+        assert!(!matches(&request("GET", "/upload/a.txt", &[]), &config));
+        assert!(!matches(&request("PUT", "/uploads/a.txt", &[]), &config));
+    }
+
+    /// It writes a PUT body to the named file and replaces it on a second PUT
+    #[tokio::test]
+    async fn put_creates_then_replaces() {
+        let directory = tempfile::tempdir().unwrap();
+        let put = request("PUT", "/upload/sub/a%20b.txt", &[("Content-Length", "11")]);
+        let response = handle(
+            &put,
+            &mut BodyStream(b"hello world"),
+            &config(directory.path()),
+        )
+        .await
+        .unwrap();
+        assert_eq!(StatusCode::CREATED, response.status);
+        let target = directory.path().join("sub").join("a b.txt");
+        assert_eq!("hello world", std::fs::read_to_string(&target).unwrap());
+
+        let put = request("PUT", "/upload/sub/a%20b.txt", &[("Content-Length", "3")]);
+        let response = handle(&put, &mut BodyStream(b"bye"), &config(directory.path()))
+            .await
+            .unwrap();
+        assert_eq!(StatusCode::NO_CONTENT, response.status);
+        assert_eq!("bye", std::fs::read_to_string(&target).unwrap());
+        assert_eq!(
+            1,
+            std::fs::read_dir(target.parent().unwrap()).unwrap().count()
+        );
+    }
+
+    /// It refuses bodies without a length or above the limit, and paths that escape the directory
+    #[tokio::test]
+    async fn rejections() {
+        let directory = tempfile::tempdir().unwrap();
+        let config = config(directory.path());
+        let missing = request("PUT", "/upload/a", &[]);
+        let response = handle(&missing, &mut BodyStream(b""), &config)
+            .await
+            .unwrap();
+        assert_eq!(StatusCode::LENGTH_REQUIRED, response.status);
+        let large = request("PUT", "/upload/a", &[("Content-Length", "2048")]);
+        let response = handle(&large, &mut BodyStream(b""), &config).await.unwrap();
+        assert_eq!(StatusCode::PAYLOAD_TOO_LARGE, response.status);
+        let escape = request("PUT", "/upload/../a", &[("Content-Length", "0")]);
+        let response = handle(&escape, &mut BodyStream(b""), &config)
+            .await
+            .unwrap();
+        assert_eq!(StatusCode::BAD_REQUEST, response.status);
+    }
+
+    /// It refuses paths through symbolic links, which may lead outside the directory
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn symlinks() {
+        let outside = tempfile::tempdir().unwrap();
+        let directory = tempfile::tempdir().unwrap();
+        std::os::unix::fs::symlink(outside.path(), directory.path().join("link")).unwrap();
+        let put = request("PUT", "/upload/link/a", &[("Content-Length", "3")]);
+        let response = handle(&put, &mut BodyStream(b"bye"), &config(directory.path()))
+            .await
+            .unwrap();
+        assert_eq!(StatusCode::BAD_REQUEST, response.status);
+        assert_eq!(0, std::fs::read_dir(outside.path()).unwrap().count());
+    }
+
+    /// It streams every file part of a multipart body and skips plain fields
+    #[tokio::test]
+    async fn multipart_parts() {
+        let directory = tempfile::tempdir().unwrap();
+        let body: &'static [u8] = b"preamble\r\n--xyz\r\n\
+Content-Disposition: form-data; name=\"note\"\r\n\r\nignored\r\n--xyz\r\n\
+Content-Disposition: form-data; name=\"a\"; filename=\"C:\\\\docs\\\\one.txt\"\r\n\
+Content-Type: text/plain\r\n\r\nfirst\r\n--xy line\r\n--xyz\r\n\
+Content-Disposition: form-data; name=\"b\"; filename=\"two.bin\"\r\n\r\n\
+second\r\n--xyz--\r\nepilogue";
+        let length = body.len().to_string();
+        let post = request(
+            "POST",
+            "/upload/files",
+            &[
+                ("Content-Length", &length),
+                ("Content-Type", "multipart/form-data; boundary=\"xyz\""),
+            ],
+        );
+        let response = handle(&post, &mut BodyStream(body), &config(directory.path()))
+            .await
+            .unwrap();
+        assert_eq!(StatusCode::CREATED, response.status);
+        assert_eq!(b"one.txt\ntwo.bin\n".to_vec(), response.body);
+        let files = directory.path().join("files");
+        assert_eq!(
+            "first\r\n--xy line",
+            std::fs::read_to_string(files.join("one.txt")).unwrap()
+        );
+        assert_eq!(
+            "second",
+            std::fs::read_to_string(files.join("two.bin")).unwrap()
+        );
+        assert_eq!(2, std::fs::read_dir(files).unwrap().count());
+    }
+
+    /// It reports a truncated multipart body and leaves no partial file behind
+    #[tokio::test]
+    async fn truncated_multipart() {
+        let directory = tempfile::tempdir().unwrap();
+        let body: &'static [u8] =
+            b"--xyz\r\nContent-Disposition: form-data; filename=\"a.txt\"\r\n\r\npartial";
+        let length = body.len().to_string();
+        let post = request(
+            "POST",
+            "/upload",
+            &[
+                ("Content-Length", &length),
+                ("Content-Type", "multipart/form-data; boundary=xyz"),
+            ],
+        );
+        let response = handle(&post, &mut BodyStream(body), &config(directory.path()))
+            .await
+            .unwrap();
+        assert_eq!(StatusCode::BAD_REQUEST, response.status);
+        assert_eq!(0, std::fs::read_dir(directory.path()).unwrap().count());
+    }
+}
@@ -0,0 +1,262 @@
+use crate::path;
+use std::fmt;
+use std::str::FromStr;
+use tokio::io;
+
+/// A parsed request target in origin form (`/a?b`), absolute form (`http://host/a?b`),
+/// authority form (`host:443`, sent with CONNECT), or asterisk form (`*`, sent with OPTIONS).
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Uri {
+    scheme: Option<String>,
+    authority: Option<String>,
+    path: String,
+    query: Option<String>,
+}
+
+impl Uri {
+    /// The scheme of an absolute-form target, e.g. `http`.
+    pub fn scheme(&self) -> Option<&str> {
+        self.scheme.as_deref()
+    }
+
+    /// The authority of an absolute-form or authority-form target, e.g. `example.com:8080`.
+    pub fn authority(&self) -> Option<&str> {
+        self.authority.as_deref()
+    }
+
+    /// The still percent-encoded path, e.g. `/a%20b`. Absolute-form targets without a path have
+    /// the path `/`; authority-form targets have an empty path.
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    /// The still percent-encoded query without its `?`, e.g. `x=1&y=2`.
+    pub fn query(&self) -> Option<&str> {
+        self.query.as_deref()
+    }
+
+    /// The path with `%XX` escapes decoded.
+    ///
+    /// # Returns
+    ///
+    /// The decoded path, or `None` when an escape is malformed or the result is not UTF-8.
+    pub fn decoded_path(&self) -> Option<String> {
+        path::percent_decode(&self.path)
+    }
+
+    /// Splits the query into `name=value` pairs, decoding escapes and `+` as a space as HTML
+    /// forms encode them.
+    ///
+    /// # Returns
+    ///
+    /// The pairs in order, skipping any that cannot be decoded. A pair without `=` has an empty
+    /// value.
+    pub fn query_pairs(&self) -> Vec<(String, String)> {
+        let query = match &self.query {
+            Some(query) => query,
+            None => return Vec::new(),
+        };
+        query
+            .split('&')
+            .filter(|pair| !pair.is_empty())
+            .filter_map(|pair| {
+                let (name, value) = pair.split_once('=').unwrap_or((pair, ""));
+                Some((decode_component(name)?, decode_component(value)?))
+            })
+            .collect()
+    }
+
+    /// Finds the first value of a query parameter.
+    ///
+    /// # Arguments
+    ///
+    /// * `name`: The decoded parameter name, compared exactly.
+    ///
+    /// # Returns
+    ///
+    /// The decoded value, if the parameter is present.
+    pub fn query_param(&self, name: &str) -> Option<String> {
+        self.query_pairs()
+            .into_iter()
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| value)
+    }
+
+    /// Replaces the path and query, keeping the scheme and authority of an absolute form.
+    ///
+    /// # Arguments
+    ///
+    /// * `path_and_query`: The new origin form, e.g. `/new?page=2`.
+    ///
+    /// # Returns
+    ///
+    /// The new target.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`io::ErrorKind::InvalidData`] when `path_and_query` is not an origin form.
+    pub fn with_path_and_query(&self, path_and_query: &str) -> io::Result<Uri> {
+        if !path_and_query.starts_with('/') {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("invalid request target: {}", path_and_query),
+            ));
+        }
+        let uri: Uri = path_and_query.parse()?;
+        Ok(Uri {
+            scheme: self.scheme.clone(),
+            authority: self.authority.clone(),
+            ..uri
+        })
+    }
+}
+
+/// Decodes a query component, where `+` stands for a space.
+///
+/// # Arguments
+///
+/// * `component`: A percent-encoded name or value.
+///
+/// # Returns
+///
+/// The decoded text, or `None` when an escape is malformed or the result is not UTF-8.
+fn decode_component(component: &str) -> Option<String> {
+    path::percent_decode(&component.replace('+', " "))
+}
+
+/// Parses a request target. A fragment, which clients must not send, is dropped.
+///
+/// # Errors
+///
+/// Returns [`io::ErrorKind::InvalidData`] for an empty target, whitespace or control
+/// characters, or an absolute form with an invalid scheme or without an authority.
+impl FromStr for Uri {
+    type Err = io::Error;
+
+    fn from_str(target: &str) -> io::Result<Uri> {
+        let invalid = || {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("invalid request target: {}", target),
+            )
+        };
+        if target.is_empty() || target.bytes().any(|byte| byte <= b' ' || byte == 0x7f) {
+            return Err(invalid());
+        }
+        let target = target.split_once('#').map_or(target, |(target, _)| target);
+        if target == "*" {
+            return Ok(Uri {
+                path: target.to_string(),
+                ..Uri::default()
+            });
+        }
+        let (scheme, rest) = match target.split_once("://") {
+            Some((scheme, rest)) if !target.starts_with('/') => {
+                let valid = scheme.starts_with(|c: char| c.is_ascii_alphabetic())
+                    && scheme
+                        .chars()
+                        .all(|c| c.is_ascii_alphanumeric() || "+-.".contains(c));
+                if !valid {
+                    return Err(invalid());
+                }
+                (Some(scheme.to_ascii_lowercase()), rest)
+            }
+            _ => (None, target),
+        };
+        let (rest, query) = match rest.split_once('?') {
+            Some((rest, query)) => (rest, Some(query.to_string())),
+            None => (rest, None),
+        };
+        if scheme.is_none() && !rest.starts_with('/') {
+            return Ok(Uri {
+                authority: Some(rest.to_string()),
+                query,
+                ..Uri::default()
+            });
+        }
+        let (authority, path) = match scheme {
+            Some(_) => {
+                let split = rest.find('/').unwrap_or(rest.len());
+                if split == 0 {
+                    return Err(invalid());
+                }
+                let path = if split == rest.len() {
+                    "/"
+                } else {
+                    &rest[split..]
+                };
+                (Some(rest[..split].to_string()), path)
+            }
+            None => (None, rest),
+        };
+        Ok(Uri {
+            scheme,
+            authority,
+            path: path.to_string(),
+            query,
+        })
+    }
+}
+
+/// Formats the target as it was parsed, without a fragment.
+impl fmt::Display for Uri {
+    fn fmt(&self, formatter: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match (&self.scheme, &self.authority) {
+            (Some(scheme), Some(authority)) => {
+                write!(formatter, "{}://{}{}", scheme, authority, self.path)?
+            }
+            (None, Some(authority)) => write!(formatter, "{}", authority)?,
+            _ => write!(formatter, "{}", self.path)?,
+        }
+        match &self.query {
+            Some(query) => write!(formatter, "?{}", query),
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// It parses every request target form
+    #[test]
+    fn forms() {
+        let uri: Uri = "/a%20b?x=1#top".parse().unwrap();
+        assert_eq!((None, None), (uri.scheme(), uri.authority()));
+        assert_eq!(("/a%20b", Some("x=1")), (uri.path(), uri.query()));
+        assert_eq!(Some("/a b".to_string()), uri.decoded_path());
+        assert_eq!("/a%20b?x=1", uri.to_string());
+
+        let uri: Uri = "HTTP://example.com:8080?q".parse().unwrap();
+        assert_eq!(Some("http"), uri.scheme());
+        assert_eq!(Some("example.com:8080"), uri.authority());
+        assert_eq!(("/", Some("q")), (uri.path(), uri.query()));
+
+        let uri: Uri = "example.com:443".parse().unwrap();
+        assert_eq!(Some("example.com:443"), uri.authority());
+        assert_eq!("", uri.path());
+        assert_eq!("*", "*".parse::<Uri>().unwrap().path());
+
+        assert!("".parse::<Uri>().is_err());
+        assert!("/a b".parse::<Uri>().is_err());
+        assert!("http:///a".parse::<Uri>().is_err());
+        assert!("1http://a/".parse::<Uri>().is_err());
+    }
+
+    /// It decodes query pairs as forms encode them
+    #[test]
+    fn query_pairs() {
+        let uri: Uri = "/search?q=a+b%26c&empty&q=2&bad=%zz".parse().unwrap();
+        assert_eq!(
+            vec![
+                ("q".to_string(), "a b&c".to_string()),
+                ("empty".to_string(), String::new()),
+                ("q".to_string(), "2".to_string()),
+            ],
+            uri.query_pairs()
+        );
+        assert_eq!(Some("a b&c".to_string()), uri.query_param("q"));
+        assert_eq!(None, uri.query_param("missing"));
+    }
+}
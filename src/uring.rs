@@ -0,0 +1,255 @@
+use crate::log;
+use crate::server::{Accept, PerCoreSockets, Server};
+use std::net::{Shutdown, SocketAddr};
+use std::path::Path;
+use tokio::io::{self, AsyncReadExt, AsyncWriteExt, DuplexStream};
+use tokio::sync::mpsc;
+use tokio::task::{JoinHandle, JoinSet};
+use tokio::{fs, net};
+use tokio_uring::buf::BoundedBuf;
+
+/// The size of the buffers moving bytes between a socket and its connection, and of the
+/// chunks files are read in.
+const BUFFER_SIZE: usize = 16 * 1024;
+
+tokio::task_local! {
+    /// Set for the tasks serving connections of [`Server::serve_uring`], whose files are read
+    /// through io_uring as well.
+    static ON_RING: ();
+}
+
+impl Server {
+    /// Serves connections like [`Server::serve_per_core`], but with an io_uring runtime per
+    /// thread instead of epoll: sockets accept, read, and write through submission queues, and
+    /// so do reads of static pages. Each connection is bridged to the handlers through an
+    /// in-memory pipe, since io_uring sockets must stay on the thread that accepted them.
+    /// Blocks until every thread returns, so call it outside an async context.
+    ///
+    /// # Arguments
+    ///
+    /// * `sockets`: The sockets bound for the threads, one thread each.
+    ///
+    /// # Returns
+    ///
+    /// `Ok(())` once the server stopped and every connection is closed.
+    ///
+    /// # Errors
+    ///
+    /// The errors of [`Server::serve_per_core`], and captures IO errors from setting up the
+    /// rings, e.g. on kernels before 5.10 or with io_uring disabled.
+    pub fn serve_uring(&self, sockets: PerCoreSockets) -> io::Result<()> {
+        self.per_core(sockets, false, serve_thread)
+    }
+
+    /// Serves TLS connections like [`Server::serve_tls_per_core`] through io_uring, see
+    /// [`Server::serve_uring`].
+    ///
+    /// # Arguments
+    ///
+    /// * `sockets`: The sockets bound for the threads, see [`crate::tls::Tls::address`].
+    ///
+    /// # Returns
+    ///
+    /// `Ok(())` once the server stopped and every connection is closed.
+    ///
+    /// # Errors
+    ///
+    /// The errors of [`Server::serve_uring`].
+    #[cfg(feature = "tls")]
+    pub fn serve_tls_uring(&self, sockets: PerCoreSockets) -> io::Result<()> {
+        self.per_core(sockets, true, serve_thread)
+    }
+}
+
+/// Serves one socket of [`Server::serve_uring`] on an io_uring runtime.
+///
+/// # Errors
+///
+/// Captures IO errors from starting the runtime and listening.
+fn serve_thread(server: Server, socket: net::TcpSocket, secure: bool) -> io::Result<()> {
+    tokio_uring::start(async move {
+        let listener = socket.listen(1024)?.into_std()?;
+        let listener = Listener::new(tokio_uring::net::TcpListener::from_std(listener));
+        server.accept_loop(listener, secure).await
+    })
+}
+
+/// A listener accepting through io_uring.
+struct Listener {
+    /// The connections accepted by the task of [`Listener::new`].
+    accepted: mpsc::Receiver<io::Result<(DuplexStream, SocketAddr)>>,
+    acceptor: JoinHandle<()>,
+}
+
+impl Listener {
+    /// Accepts connections in a task of their own. An accept dropped before it completes stays
+    /// queued on the ring and would take a connection nobody serves, so the accept loop waits
+    /// on a channel instead.
+    fn new(listener: tokio_uring::net::TcpListener) -> Listener {
+        let (sender, accepted) = mpsc::channel(1);
+        let acceptor = tokio_uring::spawn(async move {
+            loop {
+                let accepted = listener.accept().await.map(|(stream, peer)| {
+                    let (near, far) = io::duplex(BUFFER_SIZE);
+                    tokio_uring::spawn(async move {
+                        if let Err(error) = pump(&stream, far).await {
+                            log::debug(format_args!("connection from {} ended: {}", peer, error));
+                        }
+                    });
+                    (near, peer)
+                });
+                if sender.send(accepted).await.is_err() {
+                    return;
+                }
+            }
+        });
+        Listener { accepted, acceptor }
+    }
+}
+
+impl Drop for Listener {
+    fn drop(&mut self) {
+        self.acceptor.abort();
+    }
+}
+
+impl Accept for Listener {
+    type Stream = DuplexStream;
+
+    async fn accept(&mut self) -> io::Result<(DuplexStream, SocketAddr)> {
+        match self.accepted.recv().await {
+            Some(accepted) => accepted,
+            None => Err(io::Error::other("the acceptor stopped")),
+        }
+    }
+
+    /// Keeps the task on this thread, whose ring reads the files it serves.
+    fn spawn(
+        &self,
+        connections: &mut JoinSet<()>,
+        task: impl std::future::Future<Output = ()> + Send + 'static,
+    ) {
+        connections.spawn_local(ON_RING.scope((), task));
+    }
+}
+
+/// Moves bytes between a socket and the pipe its connection is served through.
+///
+/// # Arguments
+///
+/// * `stream`: The accepted socket.
+/// * `pipe`: The end of the pipe opposite the served connection.
+///
+/// # Returns
+///
+/// `Ok(())` once the served connection closed its end and everything it wrote was sent.
+///
+/// # Errors
+///
+/// Captures IO errors from the socket.
+async fn pump(stream: &tokio_uring::net::TcpStream, pipe: DuplexStream) -> io::Result<()> {
+    let (mut reader, mut writer) = io::split(pipe);
+    let incoming = async {
+        let mut buffer = Vec::with_capacity(BUFFER_SIZE);
+        loop {
+            buffer.clear();
+            let (result, filled) = stream.read(buffer).await;
+            buffer = filled;
+            if result? == 0 {
+                return writer.shutdown().await;
+            }
+            writer.write_all(&buffer).await?;
+        }
+    };
+    let outgoing = async {
+        let mut buffer = vec![0; BUFFER_SIZE];
+        loop {
+            let count = reader.read(&mut buffer).await?;
+            if count == 0 {
+                return stream.shutdown(Shutdown::Write);
+            }
+            let (result, written) = stream.write_all(buffer.slice(..count)).await;
+            buffer = written.into_inner();
+            result?;
+        }
+    };
+    // A client that stopped sending may still be waiting for the response
+    tokio::select! {
+        result = outgoing => result,
+        Err(error) = incoming => Err(error),
+    }
+}
+
+/// Reads a file as text, through io_uring when called from a connection of
+/// [`Server::serve_uring`] and through [`fs::read_to_string`] otherwise.
+///
+/// # Errors
+///
+/// Returns [`io::ErrorKind::InvalidData`] when the file is not UTF-8, and captures IO errors
+/// from reading it.
+pub(crate) async fn read_to_string(path: &Path) -> io::Result<String> {
+    if ON_RING.try_with(|_| ()).is_err() {
+        return fs::read_to_string(path).await;
+    }
+    // Operations on the ring are not `Send`, so they run in a task of their own
+    let path = path.to_path_buf();
+    let contents = tokio::task::spawn_local(async move {
+        let file = tokio_uring::fs::File::open(path).await?;
+        let mut contents = Vec::new();
+        let mut buffer = Vec::with_capacity(BUFFER_SIZE);
+        loop {
+            buffer.clear();
+            let (result, filled) = file.read_at(buffer, contents.len() as u64).await;
+            buffer = filled;
+            if result? == 0 {
+                break;
+            }
+            contents.extend_from_slice(&buffer);
+        }
+        file.close().await?;
+        Ok::<_, io::Error>(contents)
+    })
+    .await??;
+    String::from_utf8(contents).map_err(|error| io::Error::new(io::ErrorKind::InvalidData, error))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use std::time::Duration;
+    use tokio::time;
+
+    /// It serves static pages read through io_uring until shut down
+    #[tokio::test]
+    async fn serve_uring() {
+        let config = Config::default();
+        let hello = std::fs::read_to_string(config.document_root.join("hello.html")).unwrap();
+        let server = Server::new(config);
+        let sockets = PerCoreSockets::bind(SocketAddr::from(([127, 0, 0, 1], 0)), 2).unwrap();
+        let address = sockets.local_addr().unwrap();
+        let serving = std::thread::spawn({
+            let server = server.clone();
+            move || server.serve_uring(sockets)
+        });
+        for _ in 0..4 {
+            let mut client = loop {
+                match net::TcpStream::connect(address).await {
+                    Ok(client) => break client,
+                    Err(_) => time::sleep(Duration::from_millis(10)).await,
+                }
+            };
+            client
+                .write_all(b"GET / HTTP/1.1\r\nConnection: close\r\n\r\n")
+                .await
+                .unwrap();
+            let mut response = String::new();
+            client.read_to_string(&mut response).await.unwrap();
+            assert!(response.starts_with("HTTP/1.1 200 OK"));
+            assert!(response.ends_with(&hello));
+        }
+        server.shutdown();
+        serving.join().unwrap().unwrap();
+        assert_eq!(4, server.stats().snapshot().requests);
+    }
+}
@@ -0,0 +1,99 @@
+use std::fmt;
+use std::str::FromStr;
+use tokio::io;
+
+/// An HTTP protocol version, ordered from oldest to newest.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Version {
+    /// `HTTP/1.0`, whose connections close after each response unless kept alive explicitly.
+    Http10,
+    /// `HTTP/1.1`, assumed when no request line was parsed.
+    #[default]
+    Http11,
+    /// `HTTP/2`, which is only spoken after a connection handshake selects it.
+    Http2,
+    /// `HTTP/3`, which is only spoken over QUIC.
+    Http3,
+}
+
+impl Version {
+    /// The version as written in a request or status line, e.g. `HTTP/1.1`.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Version::Http10 => "HTTP/1.0",
+            Version::Http11 => "HTTP/1.1",
+            Version::Http2 => "HTTP/2",
+            Version::Http3 => "HTTP/3",
+        }
+    }
+
+    /// Checks whether messages of this version use the text framing of HTTP/1.x, so a request
+    /// of this version can be answered on the connection it was read from.
+    pub fn is_http1(&self) -> bool {
+        matches!(self, Version::Http10 | Version::Http11)
+    }
+
+    /// Checks whether connections stay open after a response unless either side asks to close
+    /// them, as from HTTP/1.1 on.
+    pub fn is_persistent_by_default(&self) -> bool {
+        *self >= Version::Http11
+    }
+}
+
+/// Parses the version of a request line. Later HTTP/1 minor versions are read as HTTP/1.1,
+/// the highest minor version the server supports.
+///
+/// # Errors
+///
+/// Returns [`io::ErrorKind::InvalidData`] for anything but `HTTP/` and a known major version.
+impl FromStr for Version {
+    type Err = io::Error;
+
+    fn from_str(value: &str) -> io::Result<Version> {
+        let invalid = || {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("invalid HTTP version: {}", value),
+            )
+        };
+        let number = value.strip_prefix("HTTP/").ok_or_else(invalid)?;
+        let (major, minor) = number.split_once('.').unwrap_or((number, "0"));
+        if minor.is_empty() || !minor.bytes().all(|byte| byte.is_ascii_digit()) {
+            return Err(invalid());
+        }
+        match (major, minor) {
+            ("1", "0") => Ok(Version::Http10),
+            ("1", _) => Ok(Version::Http11),
+            ("2", "0") => Ok(Version::Http2),
+            ("3", "0") => Ok(Version::Http3),
+            _ => Err(invalid()),
+        }
+    }
+}
+
+impl fmt::Display for Version {
+    fn fmt(&self, formatter: &mut fmt::Formatter<'_>) -> fmt::Result {
+        formatter.write_str(self.as_str())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// It parses known versions and rejects anything else
+    #[test]
+    fn parse() {
+        assert_eq!(Version::Http10, "HTTP/1.0".parse().unwrap());
+        assert_eq!(Version::Http11, "HTTP/1.1".parse().unwrap());
+        assert_eq!(Version::Http11, "HTTP/1.2".parse().unwrap());
+        assert_eq!(Version::Http2, "HTTP/2.0".parse().unwrap());
+        assert_eq!(Version::Http2, "HTTP/2".parse().unwrap());
+        assert_eq!(Version::Http3, "HTTP/3".parse().unwrap());
+        assert!("HTTP/4".parse::<Version>().is_err());
+        assert!("HTTP/1.".parse::<Version>().is_err());
+        assert!("http/1.1".parse::<Version>().is_err());
+        assert!(!Version::Http10.is_persistent_by_default());
+        assert!(!Version::Http2.is_http1());
+    }
+}
@@ -0,0 +1,1143 @@
+//! WebAssembly plugins that inspect and change requests and responses, loaded at runtime so that
+//! operators can add routing and transformation logic without recompiling the server. Plugins
+//! follow the HTTP header callbacks of the proxy-wasm ABI 0.2, which the proxy-wasm SDKs for
+//! Rust, Go, and C++ compile to, so filters written for Envoy run unchanged as long as they only
+//! use the header maps, local responses, logging, and their configuration. Host functions
+//! outside that subset trap when called, and every callback runs within a budget of fuel.
+
+use crate::log;
+use crate::request::Request;
+use crate::response::Response;
+use crate::status::StatusCode;
+use std::fmt;
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::io;
+use wasmtime::{Caller, Engine, Extern, Linker, Module, Store};
+
+/// The context of the plugin as a whole, the parent of the context of every request.
+const ROOT_CONTEXT: i32 = 1;
+
+/// The header maps of the ABI.
+const REQUEST_HEADERS: i32 = 0;
+const RESPONSE_HEADERS: i32 = 2;
+
+/// The buffers of the ABI holding the configuration.
+const VM_CONFIGURATION: i32 = 6;
+const PLUGIN_CONFIGURATION: i32 = 7;
+
+/// The status codes of the ABI returned by host functions.
+const OK: i32 = 0;
+const NOT_FOUND: i32 = 1;
+const BAD_ARGUMENT: i32 = 2;
+const INVALID_MEMORY_ACCESS: i32 = 6;
+
+/// The fuel a callback may consume by default, roughly one unit per instruction.
+const MAX_FUEL: u64 = 10_000_000;
+
+/// A plugin compiled from a proxy-wasm module, see [`crate::config::Config::plugins`].
+///
+/// The module exports `proxy_abi_version_0_2_0` or `proxy_abi_version_0_2_1`, its memory, and
+/// `proxy_on_memory_allocate`, and may export `proxy_on_vm_start`, `proxy_on_configure`,
+/// `proxy_on_context_create`, `proxy_on_request_headers`, `proxy_on_response_headers`,
+/// `proxy_on_done`, and `proxy_on_delete`. Request headers start with the `:method`, `:path`,
+/// `:authority`, and `:scheme` pseudo-headers and responses with `:status`. A plugin answers a
+/// request itself with `proxy_send_local_response`, while pausing is not supported and acts
+/// like continuing. Clones share one instance, which handles one callback at a time and is
+/// started again after a trap.
+#[derive(Clone)]
+pub struct WasmPlugin {
+    name: String,
+    engine: Engine,
+    module: Module,
+    configuration: Arc<[u8]>,
+    max_fuel: u64,
+    state: Arc<Mutex<State>>,
+}
+
+/// The running instance of a plugin, if any.
+#[derive(Default)]
+struct State {
+    vm: Option<Vm>,
+    /// Counts the instances started, so that contexts of an earlier one are left alone.
+    generation: u64,
+}
+
+struct Vm {
+    store: Store<Host>,
+    instance: wasmtime::Instance,
+    next_context: i32,
+    generation: u64,
+}
+
+/// The data host functions work on during a callback.
+#[derive(Default)]
+struct Host {
+    name: String,
+    configuration: Arc<[u8]>,
+    /// The header map of the current callback, pseudo-headers first, in their original case.
+    headers: Vec<(String, String)>,
+    map_type: i32,
+    changed: bool,
+    local_response: Option<Response>,
+}
+
+impl WasmPlugin {
+    /// Compiles a module, which starts without configuration when it first handles a request.
+    ///
+    /// # Arguments
+    ///
+    /// * `name`: The name of the plugin in logs.
+    /// * `wasm`: The module in the binary or the text format.
+    ///
+    /// # Returns
+    ///
+    /// The plugin.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`io::ErrorKind::InvalidData`] when the module does not compile or does not
+    /// follow the proxy-wasm ABI 0.2.
+    pub fn new(name: impl Into<String>, wasm: &[u8]) -> io::Result<WasmPlugin> {
+        let mut config = wasmtime::Config::new();
+        config.consume_fuel(true);
+        let engine = wasmtime::Engine::new(&config).map_err(invalid_data)?;
+        let module = Module::new(&engine, wasm).map_err(invalid_data)?;
+        if !module.exports().any(|export| {
+            matches!(
+                export.name(),
+                "proxy_abi_version_0_2_0" | "proxy_abi_version_0_2_1"
+            )
+        }) {
+            return Err(invalid_data(
+                "the module does not export a proxy-wasm ABI 0.2 version",
+            ));
+        }
+        Ok(WasmPlugin {
+            name: name.into(),
+            engine,
+            module,
+            configuration: Arc::from(Vec::new()),
+            max_fuel: MAX_FUEL,
+            state: Arc::new(Mutex::new(State::default())),
+        })
+    }
+
+    /// Reads and compiles the module in a file, named after the file.
+    ///
+    /// # Errors
+    ///
+    /// Returns the errors of [`WasmPlugin::new`] and captures IO errors from reading the file.
+    pub fn open(path: &Path) -> io::Result<WasmPlugin> {
+        let wasm = std::fs::read(path).map_err(|error| {
+            io::Error::new(
+                error.kind(),
+                format!("reading {} failed: {}", path.display(), error),
+            )
+        })?;
+        let name = path.file_stem().unwrap_or_default().to_string_lossy();
+        WasmPlugin::new(name, &wasm)
+    }
+
+    /// Starts the plugin with a configuration, which it reads as both its VM and its plugin
+    /// configuration.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`io::ErrorKind::InvalidData`] when the plugin fails to start or rejects the
+    /// configuration.
+    pub fn with_configuration(
+        mut self,
+        configuration: impl Into<Vec<u8>>,
+    ) -> io::Result<WasmPlugin> {
+        self.configuration = Arc::from(configuration.into());
+        self.restart()?;
+        Ok(self)
+    }
+
+    /// Sets the fuel a callback may consume before it traps, answering its request with 500
+    /// Internal Server Error.
+    pub fn with_max_fuel(mut self, max_fuel: u64) -> WasmPlugin {
+        self.max_fuel = max_fuel;
+        self
+    }
+
+    /// Runs `proxy_on_request_headers` on a request before it is handled.
+    ///
+    /// # Arguments
+    ///
+    /// * `request`: The request, changed in place by the plugin.
+    ///
+    /// # Returns
+    ///
+    /// The context of the request, which [`PluginContext::on_response`] passes to the response,
+    /// and the local response the plugin answered with, if any. That is 500 Internal Server
+    /// Error when the plugin trapped or changed the request into an invalid one.
+    pub fn on_request(&self, request: &mut Request) -> (PluginContext, Option<Response>) {
+        let mut state = self.lock();
+        let mut context = PluginContext {
+            plugin: self.clone(),
+            id: None,
+        };
+        let Some(vm) = self.vm(&mut state) else {
+            return (
+                context,
+                Some(Response::new(StatusCode::INTERNAL_SERVER_ERROR, "")),
+            );
+        };
+        let id = vm.next_context;
+        vm.next_context = id.checked_add(1).unwrap_or(ROOT_CONTEXT + 1);
+        context.id = Some((vm.generation, id));
+        let headers = request_headers(request);
+        let count = headers.len() as i32;
+        let end_of_stream = i32::from(request.content_length() == 0);
+        let result = self.callback(vm, REQUEST_HEADERS, headers, |vm| {
+            call::<_, ()>(vm, "proxy_on_context_create", (id, ROOT_CONTEXT))?;
+            call::<_, i32>(vm, "proxy_on_request_headers", (id, count, end_of_stream))?;
+            Ok(())
+        });
+        let host = match result {
+            Ok(host) => host,
+            Err(error) => {
+                self.failed(&mut state, "proxy_on_request_headers", &error);
+                context.id = None;
+                return (
+                    context,
+                    Some(Response::new(StatusCode::INTERNAL_SERVER_ERROR, "")),
+                );
+            }
+        };
+        if host.local_response.is_some() {
+            return (context, host.local_response);
+        }
+        if host.changed {
+            if let Err(error) = apply_request(host.headers, request) {
+                log::error(format_args!("the plugin {} {}", self.name, error));
+                return (
+                    context,
+                    Some(Response::new(StatusCode::INTERNAL_SERVER_ERROR, "")),
+                );
+            }
+        }
+        (context, None)
+    }
+
+    /// Runs a callback with a header map, fresh fuel, and no local response.
+    ///
+    /// # Returns
+    ///
+    /// The host data after the callback, with the header map as the plugin left it.
+    fn callback(
+        &self,
+        vm: &mut Vm,
+        map_type: i32,
+        headers: Vec<(String, String)>,
+        run: impl FnOnce(&mut Vm) -> wasmtime::Result<()>,
+    ) -> wasmtime::Result<Host> {
+        {
+            let host = vm.store.data_mut();
+            host.headers = headers;
+            host.map_type = map_type;
+            host.changed = false;
+            host.local_response = None;
+        }
+        vm.store.set_fuel(self.max_fuel)?;
+        run(vm)?;
+        let host = vm.store.data_mut();
+        Ok(Host {
+            headers: std::mem::take(&mut host.headers),
+            changed: host.changed,
+            local_response: host.local_response.take(),
+            ..Host::default()
+        })
+    }
+
+    /// The running instance, started when there is none.
+    fn vm<'a>(&self, state: &'a mut State) -> Option<&'a mut Vm> {
+        if state.vm.is_none() {
+            match self.start(state.generation + 1) {
+                Ok(vm) => {
+                    state.generation += 1;
+                    state.vm = Some(vm);
+                }
+                Err(error) => {
+                    log::error(format_args!(
+                        "starting the plugin {} failed: {}",
+                        self.name, error
+                    ));
+                    return None;
+                }
+            }
+        }
+        state.vm.as_mut()
+    }
+
+    /// Replaces the running instance with a new one.
+    fn restart(&mut self) -> io::Result<()> {
+        let mut state = self.lock();
+        let vm = self.start(state.generation + 1).map_err(invalid_data)?;
+        state.generation += 1;
+        state.vm = Some(vm);
+        Ok(())
+    }
+
+    /// Instantiates the module and runs its start callbacks.
+    fn start(&self, generation: u64) -> wasmtime::Result<Vm> {
+        let host = Host {
+            name: self.name.clone(),
+            configuration: Arc::clone(&self.configuration),
+            ..Host::default()
+        };
+        let mut store = Store::new(&self.engine, host);
+        store.set_fuel(self.max_fuel)?;
+        let mut linker = Linker::new(&self.engine);
+        define_host_functions(&mut linker)?;
+        linker.define_unknown_imports_as_traps(&self.module)?;
+        let instance = linker.instantiate(&mut store, &self.module)?;
+        let mut vm = Vm {
+            store,
+            instance,
+            next_context: ROOT_CONTEXT + 1,
+            generation,
+        };
+        // Modules built for WASI initialize their runtime in one of these
+        for initialize in ["_initialize", "_start"] {
+            if has_export(&mut vm, initialize) {
+                call::<_, ()>(&mut vm, initialize, ())?;
+                break;
+            }
+        }
+        call::<_, ()>(&mut vm, "proxy_on_context_create", (ROOT_CONTEXT, 0))?;
+        let size = self.configuration.len() as i32;
+        for (callback, what) in [
+            ("proxy_on_vm_start", "failed to start"),
+            ("proxy_on_configure", "rejected its configuration"),
+        ] {
+            if has_export(&mut vm, callback)
+                && call::<_, i32>(&mut vm, callback, (ROOT_CONTEXT, size))? == 0
+            {
+                wasmtime::bail!("the plugin {}", what);
+            }
+        }
+        Ok(vm)
+    }
+
+    /// Logs a failed callback and drops the instance, which may be left in any state.
+    fn failed(&self, state: &mut State, callback: &str, error: &wasmtime::Error) {
+        log::error(format_args!(
+            "the plugin {} failed in {}: {:#}",
+            self.name, callback, error
+        ));
+        state.vm = None;
+    }
+
+    fn lock(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap_or_else(|error| error.into_inner())
+    }
+}
+
+/// Parses `<path>` optionally followed by a space and the configuration, e.g.
+/// `/etc/web_server/filter.wasm {"deny": ["/admin"]}`, and starts the plugin.
+impl FromStr for WasmPlugin {
+    type Err = io::Error;
+
+    fn from_str(plugin: &str) -> io::Result<WasmPlugin> {
+        let (path, configuration) = plugin.trim().split_once(' ').unwrap_or((plugin.trim(), ""));
+        WasmPlugin::open(Path::new(path))?.with_configuration(configuration.trim())
+    }
+}
+
+impl fmt::Debug for WasmPlugin {
+    fn fmt(&self, formatter: &mut fmt::Formatter<'_>) -> fmt::Result {
+        formatter
+            .debug_struct("WasmPlugin")
+            .field("name", &self.name)
+            .field("max_fuel", &self.max_fuel)
+            .finish_non_exhaustive()
+    }
+}
+
+/// The context of a request in a plugin, which is done once it is dropped.
+pub struct PluginContext {
+    plugin: WasmPlugin,
+    /// The generation of the instance and the context ID, `None` once the instance failed.
+    id: Option<(u64, i32)>,
+}
+
+impl PluginContext {
+    /// Runs `proxy_on_response_headers` on the response to the request of this context.
+    ///
+    /// # Arguments
+    ///
+    /// * `response`: The response.
+    ///
+    /// # Returns
+    ///
+    /// The response as changed by the plugin, or 500 Internal Server Error when the plugin
+    /// trapped or changed the status into an invalid one.
+    pub fn on_response(&mut self, mut response: Response) -> Response {
+        let plugin = &self.plugin;
+        let mut state = plugin.lock();
+        let Some(vm) = self.vm(&mut state) else {
+            return response;
+        };
+        let Some((_, id)) = self.id else {
+            return response;
+        };
+        let headers = response_headers(&response);
+        let count = headers.len() as i32;
+        let result = plugin.callback(vm, RESPONSE_HEADERS, headers, |vm| {
+            call::<_, i32>(vm, "proxy_on_response_headers", (id, count, 0))?;
+            Ok(())
+        });
+        match result {
+            Ok(host) if host.changed => match apply_response(host.headers, &mut response) {
+                Ok(()) => response,
+                Err(error) => {
+                    log::error(format_args!("the plugin {} {}", plugin.name, error));
+                    Response::new(StatusCode::INTERNAL_SERVER_ERROR, "")
+                }
+            },
+            Ok(_) => response,
+            Err(error) => {
+                plugin.failed(&mut state, "proxy_on_response_headers", &error);
+                self.id = None;
+                Response::new(StatusCode::INTERNAL_SERVER_ERROR, "")
+            }
+        }
+    }
+
+    /// The instance of this context, unless it was replaced since.
+    fn vm<'a>(&self, state: &'a mut State) -> Option<&'a mut Vm> {
+        let (generation, _) = self.id?;
+        state.vm.as_mut().filter(|vm| vm.generation == generation)
+    }
+}
+
+impl Drop for PluginContext {
+    fn drop(&mut self) {
+        let Some((_, id)) = self.id else {
+            return;
+        };
+        let plugin = &self.plugin;
+        let mut state = plugin.lock();
+        let Some(vm) = self.vm(&mut state) else {
+            return;
+        };
+        let result = vm.store.set_fuel(plugin.max_fuel).and_then(|()| {
+            call::<_, i32>(vm, "proxy_on_done", (id,))?;
+            call::<_, ()>(vm, "proxy_on_delete", (id,))
+        });
+        if let Err(error) = result {
+            plugin.failed(&mut state, "proxy_on_done", &error);
+        }
+    }
+}
+
+/// Runs the request callbacks of plugins in order until one of them answers the request.
+///
+/// # Returns
+///
+/// The contexts of the plugins that saw the request, and the response of the one that answered
+/// it, if any.
+pub(crate) fn on_request(
+    plugins: &[WasmPlugin],
+    request: &mut Request,
+) -> (Vec<PluginContext>, Option<Response>) {
+    let mut contexts = Vec::new();
+    for plugin in plugins {
+        let (context, response) = plugin.on_request(request);
+        contexts.push(context);
+        if response.is_some() {
+            return (contexts, response);
+        }
+    }
+    (contexts, None)
+}
+
+/// Runs the response callbacks of the plugins that saw the request in reverse order.
+pub(crate) fn on_response(contexts: Vec<PluginContext>, mut response: Response) -> Response {
+    for mut context in contexts.into_iter().rev() {
+        response = context.on_response(response);
+    }
+    response
+}
+
+/// Calls an export of the plugin, which counts as doing nothing when it is missing.
+fn call<Params, Results>(vm: &mut Vm, name: &str, params: Params) -> wasmtime::Result<Results>
+where
+    Params: wasmtime::WasmParams,
+    Results: wasmtime::WasmResults + Default,
+{
+    if !has_export(vm, name) {
+        return Ok(Results::default());
+    }
+    vm.instance
+        .get_typed_func::<Params, Results>(&mut vm.store, name)?
+        .call(&mut vm.store, params)
+}
+
+fn has_export(vm: &mut Vm, name: &str) -> bool {
+    vm.instance.get_func(&mut vm.store, name).is_some()
+}
+
+fn request_headers(request: &Request) -> Vec<(String, String)> {
+    let mut headers = vec![
+        (":method".to_string(), request.method.as_str().to_string()),
+        (":path".to_string(), request.target.to_string()),
+        (
+            ":authority".to_string(),
+            request.header("Host").unwrap_or_default().to_string(),
+        ),
+        (":scheme".to_string(), "http".to_string()),
+    ];
+    headers.extend(
+        request
+            .headers
+            .iter()
+            .filter(|(name, _)| !name.eq_ignore_ascii_case("Host"))
+            .map(|(name, value)| (name.to_string(), value.to_string())),
+    );
+    headers
+}
+
+fn response_headers(response: &Response) -> Vec<(String, String)> {
+    let mut headers = vec![(":status".to_string(), response.status.as_u16().to_string())];
+    headers.extend(
+        response
+            .headers
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.to_string())),
+    );
+    headers
+}
+
+/// Writes a header map changed by the plugin back to the request.
+fn apply_request(headers: Vec<(String, String)>, request: &mut Request) -> Result<(), String> {
+    let mut map = crate::header::HeaderMap::new();
+    for (name, value) in headers {
+        match name.as_str() {
+            ":method" if value != request.method.as_str() => {
+                request.method = value
+                    .parse()
+                    .map_err(|_| format!("set the invalid method {:?}", value))?;
+            }
+            ":path" if value != request.target.to_string() => {
+                request.target = value
+                    .parse()
+                    .map_err(|_| format!("set the invalid path {:?}", value))?;
+            }
+            ":authority" if !value.is_empty() => map.insert("Host", value),
+            _ if name.starts_with(':') => {}
+            _ => map.append(name, value),
+        }
+    }
+    request.headers = map;
+    Ok(())
+}
+
+/// Writes a header map changed by the plugin back to the response.
+fn apply_response(headers: Vec<(String, String)>, response: &mut Response) -> Result<(), String> {
+    let mut map = crate::header::HeaderMap::new();
+    for (name, value) in headers {
+        match name.as_str() {
+            ":status" => {
+                response.status = value
+                    .parse()
+                    .ok()
+                    .and_then(StatusCode::from_u16)
+                    .ok_or_else(|| format!("set the invalid status {:?}", value))?;
+            }
+            _ if name.starts_with(':') => {}
+            _ => map.append(name, value),
+        }
+    }
+    response.headers = map;
+    Ok(())
+}
+
+/// Defines the host functions of the ABI subset and the WASI functions that plugin runtimes
+/// call while starting.
+fn define_host_functions(linker: &mut Linker<Host>) -> wasmtime::Result<()> {
+    linker.func_wrap(
+        "env",
+        "proxy_log",
+        |mut caller: Caller<'_, Host>, level: i32, data: i32, size: i32| {
+            let Some(message) = read_string(&mut caller, data, size) else {
+                return INVALID_MEMORY_ACCESS;
+            };
+            let name = &caller.data().name;
+            let message = format_args!("the plugin {} logged: {}", name, message);
+            match level {
+                0..=1 => log::debug(message),
+                2 => log::info(message),
+                3 => log::warn(message),
+                _ => log::error(message),
+            }
+            OK
+        },
+    )?;
+    linker.func_wrap(
+        "env",
+        "proxy_get_header_map_value",
+        |mut caller: Caller<'_, Host>,
+         map_type: i32,
+         key_data: i32,
+         key_size: i32,
+         return_data: i32,
+         return_size: i32| {
+            let Some(key) = read_string(&mut caller, key_data, key_size) else {
+                return Ok(INVALID_MEMORY_ACCESS);
+            };
+            let host = caller.data();
+            if map_type != host.map_type {
+                return Ok(NOT_FOUND);
+            }
+            let Some((_, value)) = host
+                .headers
+                .iter()
+                .find(|(name, _)| name.eq_ignore_ascii_case(&key))
+            else {
+                return Ok(NOT_FOUND);
+            };
+            let value = value.clone().into_bytes();
+            write_out(&mut caller, &value, return_data, return_size)
+        },
+    )?;
+    linker.func_wrap(
+        "env",
+        "proxy_get_header_map_pairs",
+        |mut caller: Caller<'_, Host>, map_type: i32, return_data: i32, return_size: i32| {
+            let host = caller.data();
+            if map_type != host.map_type {
+                return Ok(NOT_FOUND);
+            }
+            let pairs = serialize_pairs(&host.headers);
+            write_out(&mut caller, &pairs, return_data, return_size)
+        },
+    )?;
+    linker.func_wrap(
+        "env",
+        "proxy_set_header_map_pairs",
+        |mut caller: Caller<'_, Host>, map_type: i32, data: i32, size: i32| {
+            let Some(bytes) = read(&mut caller, data, size) else {
+                return INVALID_MEMORY_ACCESS;
+            };
+            let Some(pairs) = deserialize_pairs(&bytes) else {
+                return BAD_ARGUMENT;
+            };
+            let host = caller.data_mut();
+            if map_type != host.map_type {
+                return NOT_FOUND;
+            }
+            host.headers = pairs;
+            host.changed = true;
+            OK
+        },
+    )?;
+    for (function, replace) in [
+        ("proxy_add_header_map_value", false),
+        ("proxy_replace_header_map_value", true),
+    ] {
+        linker.func_wrap(
+            "env",
+            function,
+            move |mut caller: Caller<'_, Host>,
+                  map_type: i32,
+                  key_data: i32,
+                  key_size: i32,
+                  value_data: i32,
+                  value_size: i32| {
+                let (Some(key), Some(value)) = (
+                    read_string(&mut caller, key_data, key_size),
+                    read_string(&mut caller, value_data, value_size),
+                ) else {
+                    return INVALID_MEMORY_ACCESS;
+                };
+                if key.is_empty() || breaks_line(&key) || breaks_line(&value) {
+                    return BAD_ARGUMENT;
+                }
+                let host = caller.data_mut();
+                if map_type != host.map_type {
+                    return NOT_FOUND;
+                }
+                let position = host
+                    .headers
+                    .iter()
+                    .position(|(name, _)| name.eq_ignore_ascii_case(&key));
+                match position.filter(|_| replace) {
+                    Some(position) => {
+                        host.headers[position].1 = value;
+                        let mut index = 0;
+                        host.headers.retain(|(name, _)| {
+                            index += 1;
+                            index - 1 <= position || !name.eq_ignore_ascii_case(&key)
+                        });
+                    }
+                    None => host.headers.push((key, value)),
+                }
+                host.changed = true;
+                OK
+            },
+        )?;
+    }
+    linker.func_wrap(
+        "env",
+        "proxy_remove_header_map_value",
+        |mut caller: Caller<'_, Host>, map_type: i32, key_data: i32, key_size: i32| {
+            let Some(key) = read_string(&mut caller, key_data, key_size) else {
+                return INVALID_MEMORY_ACCESS;
+            };
+            let host = caller.data_mut();
+            if map_type != host.map_type {
+                return NOT_FOUND;
+            }
+            host.headers
+                .retain(|(name, _)| !name.eq_ignore_ascii_case(&key));
+            host.changed = true;
+            OK
+        },
+    )?;
+    linker.func_wrap(
+        "env",
+        "proxy_send_local_response",
+        |mut caller: Caller<'_, Host>,
+         status: i32,
+         _details_data: i32,
+         _details_size: i32,
+         body_data: i32,
+         body_size: i32,
+         headers_data: i32,
+         headers_size: i32,
+         _grpc_status: i32| {
+            let (Some(body), Some(headers)) = (
+                read(&mut caller, body_data, body_size),
+                read(&mut caller, headers_data, headers_size),
+            ) else {
+                return INVALID_MEMORY_ACCESS;
+            };
+            let status = u16::try_from(status).ok().and_then(StatusCode::from_u16);
+            let (Some(status), Some(headers)) = (status, deserialize_pairs(&headers)) else {
+                return BAD_ARGUMENT;
+            };
+            let mut response = Response::new(status, body);
+            for (name, value) in headers {
+                response = response.with_header(&name, value);
+            }
+            caller.data_mut().local_response = Some(response);
+            OK
+        },
+    )?;
+    linker.func_wrap(
+        "env",
+        "proxy_get_buffer_bytes",
+        |mut caller: Caller<'_, Host>,
+         buffer_type: i32,
+         start: i32,
+         max_size: i32,
+         return_data: i32,
+         return_size: i32| {
+            if !matches!(buffer_type, VM_CONFIGURATION | PLUGIN_CONFIGURATION) {
+                return Ok(NOT_FOUND);
+            }
+            let configuration = Arc::clone(&caller.data().configuration);
+            let start = (start as u32 as usize).min(configuration.len());
+            let end = start
+                .saturating_add(max_size as u32 as usize)
+                .min(configuration.len());
+            write_out(
+                &mut caller,
+                &configuration[start..end],
+                return_data,
+                return_size,
+            )
+        },
+    )?;
+    linker.func_wrap(
+        "env",
+        "proxy_get_property",
+        |_: Caller<'_, Host>, _: i32, _: i32, _: i32, _: i32| NOT_FOUND,
+    )?;
+    linker.func_wrap("env", "proxy_set_effective_context", |_: i32| OK)?;
+    linker.func_wrap("env", "proxy_set_tick_period_milliseconds", |_: i32| OK)?;
+    linker.func_wrap(
+        "env",
+        "proxy_get_current_time_nanoseconds",
+        |mut caller: Caller<'_, Host>, return_time: i32| write(&mut caller, return_time, &now()),
+    )?;
+
+    // Just enough of WASI for the runtimes of the SDKs: no arguments, no environment, and
+    // output that is discarded
+    let wasi = "wasi_snapshot_preview1";
+    for function in ["args_sizes_get", "environ_sizes_get"] {
+        linker.func_wrap(
+            wasi,
+            function,
+            |mut caller: Caller<'_, Host>, count: i32, size: i32| match write(
+                &mut caller,
+                count,
+                &[0; 4],
+            ) {
+                OK => write(&mut caller, size, &[0; 4]),
+                error => error,
+            },
+        )?;
+    }
+    for function in ["args_get", "environ_get"] {
+        linker.func_wrap(wasi, function, |_: i32, _: i32| OK)?;
+    }
+    linker.func_wrap(
+        wasi,
+        "clock_time_get",
+        |mut caller: Caller<'_, Host>, _: i32, _: i64, return_time: i32| {
+            write(&mut caller, return_time, &now())
+        },
+    )?;
+    linker.func_wrap(
+        wasi,
+        "random_get",
+        |mut caller: Caller<'_, Host>, data: i32, size: i32| {
+            let mut bytes = vec![0; (size as u32 as usize).min(64 * 1024)];
+            rand::RngCore::fill_bytes(&mut rand::thread_rng(), &mut bytes);
+            write(&mut caller, data, &bytes)
+        },
+    )?;
+    linker.func_wrap(
+        wasi,
+        "fd_write",
+        |mut caller: Caller<'_, Host>, _: i32, vectors: i32, count: i32, written: i32| {
+            let Some(vectors) = read(&mut caller, vectors, count.saturating_mul(8)) else {
+                return INVALID_MEMORY_ACCESS;
+            };
+            let total: u32 = vectors
+                .chunks_exact(8)
+                .map(|vector| u32::from_le_bytes([vector[4], vector[5], vector[6], vector[7]]))
+                .fold(0, u32::saturating_add);
+            write(&mut caller, written, &total.to_le_bytes())
+        },
+    )?;
+    linker.func_wrap(wasi, "proc_exit", |code: i32| -> wasmtime::Result<()> {
+        wasmtime::bail!("the plugin exited with {}", code)
+    })?;
+    Ok(())
+}
+
+/// The current time in nanoseconds since the Unix epoch, little-endian.
+fn now() -> [u8; 8] {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    u64::try_from(now.as_nanos())
+        .unwrap_or(u64::MAX)
+        .to_le_bytes()
+}
+
+/// Copies bytes out of the memory of the plugin.
+fn read(caller: &mut Caller<'_, Host>, data: i32, size: i32) -> Option<Vec<u8>> {
+    let memory = caller.get_export("memory")?.into_memory()?;
+    let start = data as u32 as usize;
+    let end = start.checked_add(size as u32 as usize)?;
+    memory.data(&caller).get(start..end).map(<[u8]>::to_vec)
+}
+
+fn read_string(caller: &mut Caller<'_, Host>, data: i32, size: i32) -> Option<String> {
+    read(caller, data, size).map(|bytes| String::from_utf8_lossy(&bytes).into_owned())
+}
+
+/// Copies bytes into the memory of the plugin at an address it chose.
+///
+/// # Returns
+///
+/// The status of the ABI, [`INVALID_MEMORY_ACCESS`] when the bytes do not fit.
+fn write(caller: &mut Caller<'_, Host>, data: i32, bytes: &[u8]) -> i32 {
+    let Some(memory) = caller.get_export("memory").and_then(Extern::into_memory) else {
+        return INVALID_MEMORY_ACCESS;
+    };
+    match memory.write(caller, data as u32 as usize, bytes) {
+        Ok(()) => OK,
+        Err(_) => INVALID_MEMORY_ACCESS,
+    }
+}
+
+/// Hands bytes to the plugin in memory it allocates, writing their address and size to the
+/// return pointers.
+///
+/// # Errors
+///
+/// Propagates the trap of the allocation.
+fn write_out(
+    caller: &mut Caller<'_, Host>,
+    bytes: &[u8],
+    return_data: i32,
+    return_size: i32,
+) -> wasmtime::Result<i32> {
+    let Some(allocate) = caller
+        .get_export("proxy_on_memory_allocate")
+        .or_else(|| caller.get_export("malloc"))
+        .and_then(Extern::into_func)
+    else {
+        return Ok(INVALID_MEMORY_ACCESS);
+    };
+    let size = i32::try_from(bytes.len()).map_err(wasmtime::Error::msg)?;
+    let data = allocate
+        .typed::<i32, i32>(&caller)?
+        .call(&mut *caller, size)?;
+    for (address, bytes) in [
+        (data, bytes),
+        (return_data, &data.to_le_bytes()[..]),
+        (return_size, &size.to_le_bytes()[..]),
+    ] {
+        let status = write(caller, address, bytes);
+        if status != OK {
+            return Ok(status);
+        }
+    }
+    Ok(OK)
+}
+
+/// Encodes a header map as the ABI does: the number of pairs, the sizes of every name and
+/// value, and then the names and values, each followed by a zero byte. Names are lowercase.
+fn serialize_pairs(headers: &[(String, String)]) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    bytes.extend_from_slice(&(headers.len() as u32).to_le_bytes());
+    for (name, value) in headers {
+        bytes.extend_from_slice(&(name.len() as u32).to_le_bytes());
+        bytes.extend_from_slice(&(value.len() as u32).to_le_bytes());
+    }
+    for (name, value) in headers {
+        bytes.extend_from_slice(name.to_ascii_lowercase().as_bytes());
+        bytes.push(0);
+        bytes.extend_from_slice(value.as_bytes());
+        bytes.push(0);
+    }
+    bytes
+}
+
+/// Decodes a header map encoded by [`serialize_pairs`].
+///
+/// # Returns
+///
+/// The pairs, or `None` when the encoding is cut short or a pair breaks the line.
+fn deserialize_pairs(bytes: &[u8]) -> Option<Vec<(String, String)>> {
+    if bytes.is_empty() {
+        return Some(Vec::new());
+    }
+    let word = |offset: usize| -> Option<usize> {
+        let word = bytes.get(offset..offset.checked_add(4)?)?;
+        Some(u32::from_le_bytes(word.try_into().ok()?) as usize)
+    };
+    let count = word(0)?;
+    let mut text = count.checked_mul(8)?.checked_add(4)?;
+    let mut pairs = Vec::new();
+    for index in 0..count {
+        let mut pair = Vec::with_capacity(2);
+        for size in [word(4 + index * 8)?, word(8 + index * 8)?] {
+            let end = text.checked_add(size)?;
+            pair.push(String::from_utf8_lossy(bytes.get(text..end)?).into_owned());
+            text = end + 1;
+        }
+        let value = pair.pop()?;
+        let name = pair.pop()?;
+        if name.is_empty() || breaks_line(&name) || breaks_line(&value) {
+            return None;
+        }
+        pairs.push((name, value));
+    }
+    Some(pairs)
+}
+
+fn breaks_line(text: &str) -> bool {
+    text.contains(['\r', '\n'])
+}
+
+fn invalid_data(error: impl fmt::Display) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("{:#}", error))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Denies requests with `x-deny`, tags the others with its configuration, rewrites their
+    /// path, and names itself in responses.
+    const FILTER: &str = r#"(module
+        (import "env" "proxy_get_header_map_value"
+            (func $get (param i32 i32 i32 i32 i32) (result i32)))
+        (import "env" "proxy_add_header_map_value"
+            (func $add (param i32 i32 i32 i32 i32) (result i32)))
+        (import "env" "proxy_replace_header_map_value"
+            (func $replace (param i32 i32 i32 i32 i32) (result i32)))
+        (import "env" "proxy_remove_header_map_value"
+            (func $remove (param i32 i32 i32) (result i32)))
+        (import "env" "proxy_send_local_response"
+            (func $respond (param i32 i32 i32 i32 i32 i32 i32 i32) (result i32)))
+        (import "env" "proxy_get_buffer_bytes"
+            (func $buffer (param i32 i32 i32 i32 i32) (result i32)))
+        (import "env" "proxy_http_call"
+            (func $unsupported (param i32 i32 i32 i32 i32 i32 i32 i32 i32 i32) (result i32)))
+        (memory (export "memory") 1)
+        (global $heap (mut i32) (i32.const 4096))
+        (data (i32.const 100) "x-deny")
+        (data (i32.const 110) "denied")
+        (data (i32.const 120) "x-plugin")
+        (data (i32.const 130) "x-remove")
+        (data (i32.const 140) "server")
+        (data (i32.const 150) "wasm")
+        (data (i32.const 160) ":path")
+        (data (i32.const 170) "/rewritten")
+        (func (export "proxy_abi_version_0_2_1"))
+        (func (export "proxy_on_memory_allocate") (param $size i32) (result i32)
+            (global.get $heap)
+            (global.set $heap (i32.add (global.get $heap) (local.get $size))))
+        (func (export "proxy_on_request_headers") (param i32 i32 i32) (result i32)
+            (if (i32.eqz (call $get (i32.const 0) (i32.const 100) (i32.const 6)
+                    (i32.const 200) (i32.const 204)))
+                (then
+                    (drop (call $respond (i32.const 403) (i32.const 0) (i32.const 0)
+                        (i32.const 110) (i32.const 6) (i32.const 0) (i32.const 0)
+                        (i32.const -1)))
+                    (return (i32.const 1))))
+            (if (i32.eqz (call $buffer (i32.const 7) (i32.const 0) (i32.const 1024)
+                    (i32.const 200) (i32.const 204)))
+                (then
+                    (drop (call $add (i32.const 0) (i32.const 120) (i32.const 8)
+                        (i32.load (i32.const 200)) (i32.load (i32.const 204))))))
+            (drop (call $remove (i32.const 0) (i32.const 130) (i32.const 8)))
+            (drop (call $replace (i32.const 0) (i32.const 160) (i32.const 5)
+                (i32.const 170) (i32.const 10)))
+            (i32.const 0))
+        (func (export "proxy_on_response_headers") (param i32 i32 i32) (result i32)
+            (drop (call $replace (i32.const 2) (i32.const 140) (i32.const 6)
+                (i32.const 150) (i32.const 4)))
+            (i32.const 0)))"#;
+
+    fn request(headers: &[(&str, &str)]) -> Request {
+        Request {
+            method: crate::method::Method::Get,
+            target: "/original?x=1".parse().unwrap(),
+            headers: headers.iter().copied().collect(),
+            ..Request::default()
+        }
+    }
+
+    fn filter() -> WasmPlugin {
+        WasmPlugin::new("filter", FILTER.as_bytes())
+            .unwrap()
+            .with_configuration("tagged")
+            .unwrap()
+    }
+
+    /// A plugin whose request callback runs the instructions.
+    fn plugin(name: &str, instructions: &str) -> WasmPlugin {
+        let wat = format!(
+            r#"(module
+                (import "env" "proxy_http_call"
+                    (func $unsupported
+                        (param i32 i32 i32 i32 i32 i32 i32 i32 i32 i32) (result i32)))
+                (memory (export "memory") 1)
+                (func (export "proxy_abi_version_0_2_0"))
+                (func (export "proxy_on_request_headers") (param i32 i32 i32) (result i32)
+                    {}
+                    (i32.const 0)))"#,
+            instructions
+        );
+        WasmPlugin::new(name, wat.as_bytes()).unwrap()
+    }
+
+    /// It answers requests with the local response of the plugin, changed by its response
+    /// callback
+    #[test]
+    fn local_response() {
+        let plugin = filter();
+        let (context, response) = plugin.on_request(&mut request(&[("X-Deny", "1")]));
+        let response = on_response(vec![context], response.unwrap());
+        assert_eq!(StatusCode::FORBIDDEN, response.status);
+        assert_eq!("denied", response.body);
+        assert_eq!(Some("wasm"), response.header("server"));
+    }
+
+    /// It lets the plugin add, remove, and replace request headers and the path, with its
+    /// configuration
+    #[test]
+    fn request_headers() {
+        let plugin = filter();
+        let mut changed = request(&[("Host", "example"), ("X-Remove", "1"), ("Accept", "*/*")]);
+        let (_, response) = super::on_request(std::slice::from_ref(&plugin), &mut changed);
+        assert_eq!(None, response);
+        assert_eq!("/rewritten", changed.target.to_string());
+        assert_eq!(
+            vec![
+                ("Host", "example"),
+                ("Accept", "*/*"),
+                ("x-plugin", "tagged")
+            ],
+            changed.headers.iter().collect::<Vec<_>>()
+        );
+    }
+
+    /// It lets the plugin replace response headers
+    #[test]
+    fn response_headers() {
+        let plugin = filter();
+        let (contexts, _) = super::on_request(std::slice::from_ref(&plugin), &mut request(&[]));
+        let response = Response::new(StatusCode::OK, "").with_header("Server", "web_server");
+        let response = on_response(contexts, response);
+        assert_eq!(
+            vec![("Server", "wasm")],
+            response.headers.iter().collect::<Vec<_>>()
+        );
+    }
+
+    /// It answers 500 when a callback runs out of fuel and starts the plugin again for the
+    /// next request
+    #[test]
+    fn fuel() {
+        let plugin = plugin("looping", "(loop $forever (br $forever))").with_max_fuel(10_000);
+        for _ in 0..2 {
+            let (_, response) = plugin.on_request(&mut request(&[]));
+            assert_eq!(StatusCode::INTERNAL_SERVER_ERROR, response.unwrap().status);
+        }
+        assert_eq!(2, plugin.lock().generation);
+    }
+
+    /// It answers 500 when the plugin traps and starts it again for the next request
+    #[test]
+    fn trap() {
+        let plugin = plugin("trapping", "(unreachable)");
+        let (context, response) = plugin.on_request(&mut request(&[]));
+        assert_eq!(StatusCode::INTERNAL_SERVER_ERROR, response.unwrap().status);
+        assert_eq!(None, context.id);
+        assert!(plugin.lock().vm.is_none());
+        let (_, response) = plugin.on_request(&mut request(&[]));
+        assert_eq!(StatusCode::INTERNAL_SERVER_ERROR, response.unwrap().status);
+        assert_eq!(2, plugin.lock().generation);
+    }
+
+    /// It traps plugins calling host functions outside the supported subset
+    #[test]
+    fn unsupported_host_function() {
+        let plugin = plugin(
+            "calling",
+            "(drop (call $unsupported (i32.const 0) (i32.const 0) (i32.const 0) (i32.const 0)
+                (i32.const 0) (i32.const 0) (i32.const 0) (i32.const 0) (i32.const 0)
+                (i32.const 0)))",
+        );
+        let (_, response) = plugin.on_request(&mut request(&[]));
+        assert_eq!(StatusCode::INTERNAL_SERVER_ERROR, response.unwrap().status);
+    }
+
+    /// It rejects modules that do not follow the ABI
+    #[test]
+    fn invalid_modules() {
+        for wasm in ["(module)", "not wasm"] {
+            assert_eq!(
+                io::ErrorKind::InvalidData,
+                WasmPlugin::new("invalid", wasm.as_bytes())
+                    .unwrap_err()
+                    .kind()
+            );
+        }
+    }
+}
@@ -0,0 +1,597 @@
+use crate::config::WebDavConfig;
+use crate::method::Method;
+use crate::path;
+use crate::request::Request;
+use crate::response::Response;
+use crate::status::StatusCode;
+use crate::uri::Uri;
+use base64::Engine;
+use std::fs::Metadata;
+use std::path::{Path, PathBuf};
+use tokio::{fs, io};
+
+/// The methods answered by [`handle`] when WebDAV is enabled.
+pub const METHODS: [&str; 5] = ["PROPFIND", "MKCOL", "MOVE", "COPY", "DELETE"];
+
+/// Checks whether a request method belongs to the WebDAV share.
+///
+/// # Arguments
+///
+/// * `method`: The request method.
+///
+/// # Returns
+///
+/// True if `method` is one of [`METHODS`].
+pub fn is_webdav_method(method: &Method) -> bool {
+    METHODS.contains(&method.as_str())
+}
+
+/// Answers a WebDAV request against the document root after checking Basic authentication.
+/// Paths through symbolic links are refused, as the links may lead outside the root.
+///
+/// # Arguments
+///
+/// * `request`: A request whose method is one of [`METHODS`].
+/// * `config`: The credentials guarding the share.
+/// * `root`: The document root that request paths resolve below.
+///
+/// # Returns
+///
+/// The response to send, including client errors such as 401, 404, or 409.
+///
+/// # Errors
+///
+/// Captures IO errors from the file system other than the ones mapped to client errors.
+pub async fn handle(request: &Request, config: &WebDavConfig, root: &Path) -> io::Result<Response> {
+    if !is_authorized(request, config) {
+        return Ok(Response::new(StatusCode::UNAUTHORIZED, "")
+            .with_header("WWW-Authenticate", "Basic realm=\"WebDAV\""));
+    }
+    let source = match path::resolve(root, request.path()) {
+        Some(source) if !path::through_symlink(root, &source).await? => source,
+        _ => return Ok(Response::new(StatusCode::FORBIDDEN, "")),
+    };
+    match request.method.as_str() {
+        "PROPFIND" => propfind(request, &source).await,
+        "MKCOL" => mkcol(request, &source).await,
+        "DELETE" => delete(&source, root).await,
+        "MOVE" => transfer(request, &source, root, Transfer::Move).await,
+        "COPY" => transfer(request, &source, root, Transfer::Copy).await,
+        _ => Ok(Response::new(StatusCode::METHOD_NOT_ALLOWED, "")),
+    }
+}
+
+/// Compares the Basic credentials of a request against the configured ones.
+///
+/// # Arguments
+///
+/// * `request`: The incoming request.
+/// * `config`: The expected credentials.
+///
+/// # Returns
+///
+/// True if the `Authorization` header carries the expected user name and password.
+fn is_authorized(request: &Request, config: &WebDavConfig) -> bool {
+    let encoded = match request
+        .header("Authorization")
+        .and_then(|value| value.strip_prefix("Basic "))
+    {
+        Some(encoded) => encoded.trim(),
+        None => return false,
+    };
+    let decoded = match base64::engine::general_purpose::STANDARD.decode(encoded) {
+        Ok(decoded) => decoded,
+        Err(_) => return false,
+    };
+    let expected = format!("{}:{}", config.username, config.password);
+    crate::constant_time_eq(&decoded, expected.as_bytes())
+}
+
+/// Lists properties of a resource and, for `Depth: 1`, of its direct children.
+///
+/// # Arguments
+///
+/// * `request`: The PROPFIND request. Its body is ignored, which RFC 4918 treats as `allprop`.
+/// * `source`: The resolved file system path.
+///
+/// # Returns
+///
+/// A 207 multistatus response, 404 if `source` is missing, or 403 for `Depth: infinity`.
+///
+/// # Errors
+///
+/// Captures IO errors from reading metadata or directory entries.
+async fn propfind(request: &Request, source: &Path) -> io::Result<Response> {
+    let depth = request.header("Depth").unwrap_or("infinity");
+    if depth != "0" && depth != "1" {
+        return Ok(Response::new(
+            StatusCode::FORBIDDEN,
+            "<?xml version=\"1.0\" encoding=\"utf-8\"?>\
+<D:error xmlns:D=\"DAV:\"><D:propfind-finite-depth/></D:error>",
+        )
+        .with_header("Content-Type", "application/xml; charset=utf-8"));
+    }
+    let metadata = match fs::metadata(source).await {
+        Ok(metadata) => metadata,
+        Err(error) if error.kind() == io::ErrorKind::NotFound => {
+            return Ok(Response::new(StatusCode::NOT_FOUND, ""))
+        }
+        Err(error) => return Err(error),
+    };
+
+    let decoded = path::percent_decode(request.path()).unwrap_or_default();
+    let mut href = format!("/{}", decoded.trim_matches('/'));
+    if metadata.is_dir() && href != "/" {
+        href.push('/');
+    }
+    let mut body = String::from(
+        "<?xml version=\"1.0\" encoding=\"utf-8\"?>\n<D:multistatus xmlns:D=\"DAV:\">\n",
+    );
+    body.push_str(&property_response(&href, &metadata));
+    if depth == "1" && metadata.is_dir() {
+        let mut entries = fs::read_dir(source).await?;
+        while let Some(entry) = entries.next_entry().await? {
+            let metadata = entry.metadata().await?;
+            let mut child = format!("{}{}", href, entry.file_name().to_string_lossy());
+            if metadata.is_dir() {
+                child.push('/');
+            }
+            body.push_str(&property_response(&child, &metadata));
+        }
+    }
+    body.push_str("</D:multistatus>\n");
+    Ok(Response::new(StatusCode::MULTI_STATUS, body)
+        .with_header("Content-Type", "application/xml; charset=utf-8"))
+}
+
+/// Renders one `<D:response>` element of a multistatus body.
+///
+/// # Arguments
+///
+/// * `href`: The decoded path of the resource, ending in `/` for collections.
+/// * `metadata`: The metadata of the resource.
+///
+/// # Returns
+///
+/// The XML fragment describing the resource.
+fn property_response(href: &str, metadata: &Metadata) -> String {
+    let name = href
+        .trim_end_matches('/')
+        .rsplit('/')
+        .next()
+        .unwrap_or_default();
+    let mut properties = format!("<D:displayname>{}</D:displayname>", escape_xml(name));
+    if metadata.is_dir() {
+        properties.push_str("<D:resourcetype><D:collection/></D:resourcetype>");
+    } else {
+        properties.push_str("<D:resourcetype/>");
+        properties.push_str(&format!(
+            "<D:getcontentlength>{}</D:getcontentlength>",
+            metadata.len()
+        ));
+    }
+    if let Ok(modified) = metadata.modified() {
+        properties.push_str(&format!(
+            "<D:getlastmodified>{}</D:getlastmodified>",
+            httpdate::fmt_http_date(modified)
+        ));
+    }
+    format!(
+        "<D:response><D:href>{}</D:href><D:propstat><D:prop>{}</D:prop>\
+<D:status>HTTP/1.1 200 OK</D:status></D:propstat></D:response>\n",
+        escape_xml(&path::percent_encode(href)),
+        properties
+    )
+}
+
+/// Escapes the characters that cannot appear verbatim in XML text.
+fn escape_xml(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// Creates a collection.
+///
+/// # Arguments
+///
+/// * `request`: The MKCOL request, which must not carry a body.
+/// * `source`: The resolved path of the collection to create.
+///
+/// # Returns
+///
+/// 201 when created, 405 if something already exists, 409 if the parent is missing, or 415
+/// when a body was sent.
+///
+/// # Errors
+///
+/// Captures IO errors from creating the directory other than a missing parent.
+async fn mkcol(request: &Request, source: &Path) -> io::Result<Response> {
+    if request.content_length() > 0 {
+        return Ok(Response::new(StatusCode::UNSUPPORTED_MEDIA_TYPE, ""));
+    }
+    if fs::metadata(source).await.is_ok() {
+        return Ok(Response::new(StatusCode::METHOD_NOT_ALLOWED, ""));
+    }
+    match fs::create_dir(source).await {
+        Ok(()) => Ok(Response::new(StatusCode::CREATED, "")),
+        Err(error) if error.kind() == io::ErrorKind::NotFound => {
+            Ok(Response::new(StatusCode::CONFLICT, ""))
+        }
+        Err(error) => Err(error),
+    }
+}
+
+/// Deletes a file or, recursively, a collection.
+///
+/// # Arguments
+///
+/// * `source`: The resolved path to delete.
+/// * `root`: The document root, which can never be deleted.
+///
+/// # Returns
+///
+/// 204 when deleted, 403 for the document root, or 404 if `source` is missing.
+///
+/// # Errors
+///
+/// Captures IO errors from removing files or directories.
+async fn delete(source: &Path, root: &Path) -> io::Result<Response> {
+    if source == root {
+        return Ok(Response::new(StatusCode::FORBIDDEN, ""));
+    }
+    match remove(source).await {
+        Ok(()) => Ok(Response::new(StatusCode::NO_CONTENT, "")),
+        Err(error) if error.kind() == io::ErrorKind::NotFound => {
+            Ok(Response::new(StatusCode::NOT_FOUND, ""))
+        }
+        Err(error) => Err(error),
+    }
+}
+
+/// Removes a file, or a directory with everything inside it.
+async fn remove(target: &Path) -> io::Result<()> {
+    if fs::metadata(target).await?.is_dir() {
+        fs::remove_dir_all(target).await
+    } else {
+        fs::remove_file(target).await
+    }
+}
+
+/// Distinguishes the two methods sharing [`transfer`].
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Transfer {
+    Move,
+    Copy,
+}
+
+/// Moves or copies a resource to the path in the `Destination` header.
+///
+/// # Arguments
+///
+/// * `request`: The MOVE or COPY request.
+/// * `source`: The resolved path of the resource.
+/// * `root`: The document root that the destination must also resolve below.
+/// * `kind`: Whether to move or copy.
+///
+/// # Returns
+///
+/// 201 when the destination was created, 204 when it was overwritten, or a client error for a
+/// missing or invalid destination, a destination inside the source or containing it, which
+/// overwriting would delete, a missing source, a missing destination parent, or an existing
+/// destination with `Overwrite: F`.
+///
+/// # Errors
+///
+/// Captures IO errors from renaming, copying, or removing files.
+async fn transfer(
+    request: &Request,
+    source: &Path,
+    root: &Path,
+    kind: Transfer,
+) -> io::Result<Response> {
+    let destination = match request
+        .header("Destination")
+        .and_then(|value| value.parse::<Uri>().ok())
+    {
+        Some(destination) => destination,
+        None => return Ok(Response::new(StatusCode::BAD_REQUEST, "")),
+    };
+    let destination = match path::resolve(root, destination.path()) {
+        Some(destination) if !path::through_symlink(root, &destination).await? => destination,
+        _ => return Ok(Response::new(StatusCode::FORBIDDEN, "")),
+    };
+    if source == root
+        || destination == root
+        || destination.starts_with(source)
+        || source.starts_with(&destination)
+    {
+        return Ok(Response::new(StatusCode::FORBIDDEN, ""));
+    }
+    if fs::metadata(source).await.is_err() {
+        return Ok(Response::new(StatusCode::NOT_FOUND, ""));
+    }
+    let parent_exists = match destination.parent() {
+        Some(parent) => fs::metadata(parent).await.is_ok_and(|m| m.is_dir()),
+        None => false,
+    };
+    if !parent_exists {
+        return Ok(Response::new(StatusCode::CONFLICT, ""));
+    }
+
+    let overwrite = !request
+        .header("Overwrite")
+        .is_some_and(|value| value.eq_ignore_ascii_case("F"));
+    let existed = fs::metadata(&destination).await.is_ok();
+    if existed {
+        if !overwrite {
+            return Ok(Response::new(StatusCode::PRECONDITION_FAILED, ""));
+        }
+        remove(&destination).await?;
+    }
+    match kind {
+        Transfer::Move => fs::rename(source, &destination).await?,
+        Transfer::Copy => copy_recursive(source, &destination).await?,
+    }
+    Ok(Response::new(
+        if existed {
+            StatusCode::NO_CONTENT
+        } else {
+            StatusCode::CREATED
+        },
+        "",
+    ))
+}
+
+/// Copies a file, or a directory tree one level at a time. Symbolic links inside the tree are
+/// skipped, since copying their targets could expose files outside the root.
+///
+/// # Arguments
+///
+/// * `source`: The file or directory to copy.
+/// * `destination`: A path that does not exist yet.
+///
+/// # Errors
+///
+/// Captures IO errors from reading, creating, or copying entries.
+async fn copy_recursive(source: &Path, destination: &Path) -> io::Result<()> {
+    if !fs::metadata(source).await?.is_dir() {
+        return fs::copy(source, destination).await.map(|_| ());
+    }
+    let mut pending: Vec<(PathBuf, PathBuf)> =
+        vec![(source.to_path_buf(), destination.to_path_buf())];
+    while let Some((from, to)) = pending.pop() {
+        fs::create_dir(&to).await?;
+        let mut entries = fs::read_dir(&from).await?;
+        while let Some(entry) = entries.next_entry().await? {
+            let target = to.join(entry.file_name());
+            let file_type = entry.file_type().await?;
+            if file_type.is_dir() {
+                pending.push((entry.path(), target));
+            } else if !file_type.is_symlink() {
+                fs::copy(entry.path(), target).await?;
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config() -> WebDavConfig {
+        WebDavConfig {
+            username: "user".to_string(),
+            password: "secret".to_string(),
+        }
+    }
+
+    /// Builds a request carrying valid credentials for [`config`].
+    fn request(method: &str, target: &str, headers: &[(&str, &str)]) -> Request {
+        let mut request = Request {
+            method: method.parse().unwrap(),
+            target: target.parse().unwrap(),
+            headers: [("Authorization", "Basic dXNlcjpzZWNyZXQ=")]
+                .into_iter()
+                .collect(),
+            ..Request::default()
+        };
+        for (name, value) in headers {
+            request.headers.append(*name, *value);
+        }
+        request
+    }
+
+    /// It rejects requests without credentials and asks for Basic authentication
+    #[tokio::test]
+    async fn unauthorized() {
+        let root = tempfile::tempdir().unwrap();
+        let mut request = request("PROPFIND", "/", &[("Depth", "0")]);
+        request.headers.clear();
+        let response = handle(&request, &config(), root.path()).await.unwrap();
+        assert_eq!(StatusCode::UNAUTHORIZED, response.status);
+        assert!(response.headers.contains("WWW-Authenticate"));
+        // user:secreT
+        request
+            .headers
+            .append("Authorization", "Basic dXNlcjpzZWNyZVQ=");
+        let response = handle(&request, &config(), root.path()).await.unwrap();
+        assert_eq!(StatusCode::UNAUTHORIZED, response.status);
+    }
+
+    /// It lists the children of a collection with `Depth: 1`
+    #[tokio::test]
+    async fn propfind_children() {
+        let root = tempfile::tempdir().unwrap();
+        std::fs::write(root.path().join("a b.txt"), "hello").unwrap();
+        std::fs::create_dir(root.path().join("dir")).unwrap();
+        let request = request("PROPFIND", "/", &[("Depth", "1")]);
+        let response = handle(&request, &config(), root.path()).await.unwrap();
+        let body = String::from_utf8(response.body.to_vec()).unwrap();
+        assert_eq!(StatusCode::MULTI_STATUS, response.status);
+        assert!(body.contains("<D:href>/a%20b.txt</D:href>"));
+        assert!(body.contains("<D:getcontentlength>5</D:getcontentlength>"));
+        assert!(body.contains("<D:href>/dir/</D:href>"));
+    }
+
+    /// It refuses infinite depth and reports missing resources
+    #[tokio::test]
+    async fn propfind_errors() {
+        let root = tempfile::tempdir().unwrap();
+        let infinite = request("PROPFIND", "/", &[]);
+        let response = handle(&infinite, &config(), root.path()).await.unwrap();
+        assert_eq!(StatusCode::FORBIDDEN, response.status);
+        let missing = request("PROPFIND", "/missing", &[("Depth", "0")]);
+        let response = handle(&missing, &config(), root.path()).await.unwrap();
+        assert_eq!(StatusCode::NOT_FOUND, response.status);
+    }
+
+    /// It creates a collection once and conflicts when the parent is missing
+    #[tokio::test]
+    async fn mkcol_statuses() {
+        let root = tempfile::tempdir().unwrap();
+        let create = request("MKCOL", "/new", &[]);
+        assert_eq!(
+            StatusCode::CREATED,
+            handle(&create, &config(), root.path())
+                .await
+                .unwrap()
+                .status
+        );
+        assert_eq!(
+            StatusCode::METHOD_NOT_ALLOWED,
+            handle(&create, &config(), root.path())
+                .await
+                .unwrap()
+                .status
+        );
+        let orphan = request("MKCOL", "/missing/new", &[]);
+        assert_eq!(
+            StatusCode::CONFLICT,
+            handle(&orphan, &config(), root.path())
+                .await
+                .unwrap()
+                .status
+        );
+        assert!(root.path().join("new").is_dir());
+    }
+
+    /// It copies a tree, refuses to overwrite with `Overwrite: F`, then moves over the copy
+    #[tokio::test]
+    async fn copy_and_move() {
+        let root = tempfile::tempdir().unwrap();
+        std::fs::create_dir(root.path().join("src")).unwrap();
+        std::fs::write(root.path().join("src").join("file"), "data").unwrap();
+
+        let copy = request("COPY", "/src", &[("Destination", "http://host/dst")]);
+        assert_eq!(
+            StatusCode::CREATED,
+            handle(&copy, &config(), root.path()).await.unwrap().status
+        );
+        assert_eq!(
+            "data",
+            std::fs::read_to_string(root.path().join("dst").join("file")).unwrap()
+        );
+
+        let refuse = request(
+            "COPY",
+            "/src",
+            &[("Destination", "/dst"), ("Overwrite", "F")],
+        );
+        assert_eq!(
+            StatusCode::PRECONDITION_FAILED,
+            handle(&refuse, &config(), root.path())
+                .await
+                .unwrap()
+                .status
+        );
+
+        let moved = request("MOVE", "/src", &[("Destination", "/dst")]);
+        assert_eq!(
+            StatusCode::NO_CONTENT,
+            handle(&moved, &config(), root.path()).await.unwrap().status
+        );
+        assert!(!root.path().join("src").exists());
+        assert!(root.path().join("dst").join("file").exists());
+
+        let onto_parent = request("MOVE", "/dst/file", &[("Destination", "/dst")]);
+        assert_eq!(
+            StatusCode::FORBIDDEN,
+            handle(&onto_parent, &config(), root.path())
+                .await
+                .unwrap()
+                .status
+        );
+        assert!(root.path().join("dst").join("file").exists());
+    }
+
+    /// It refuses paths and destinations through symbolic links and does not copy links
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn symlinks() {
+        let outside = tempfile::tempdir().unwrap();
+        std::fs::write(outside.path().join("secret"), "data").unwrap();
+        let root = tempfile::tempdir().unwrap();
+        std::os::unix::fs::symlink(outside.path(), root.path().join("link")).unwrap();
+        std::fs::create_dir(root.path().join("dir")).unwrap();
+        std::fs::write(root.path().join("dir").join("file"), "data").unwrap();
+        std::os::unix::fs::symlink(
+            outside.path().join("secret"),
+            root.path().join("dir").join("secret"),
+        )
+        .unwrap();
+        let status = |request: Request| {
+            let root = root.path().to_path_buf();
+            async move { handle(&request, &config(), &root).await.unwrap().status }
+        };
+
+        let moved = request("MOVE", "/link/secret", &[("Destination", "/stolen")]);
+        assert_eq!(StatusCode::FORBIDDEN, status(moved).await);
+        let listed = request("PROPFIND", "/link", &[("Depth", "1")]);
+        assert_eq!(StatusCode::FORBIDDEN, status(listed).await);
+        let into = request("COPY", "/dir", &[("Destination", "/link/dir")]);
+        assert_eq!(StatusCode::FORBIDDEN, status(into).await);
+        let deleted = request("DELETE", "/link", &[]);
+        assert_eq!(StatusCode::FORBIDDEN, status(deleted).await);
+        assert!(outside.path().join("secret").exists());
+        assert!(!outside.path().join("dir").exists());
+
+        let copy = request("COPY", "/dir", &[("Destination", "/copy")]);
+        assert_eq!(StatusCode::CREATED, status(copy).await);
+        assert!(root.path().join("copy").join("file").exists());
+        assert!(!root.path().join("copy").join("secret").exists());
+    }
+
+    /// It deletes a collection recursively but never the document root
+    #[tokio::test]
+    async fn delete_statuses() {
+        let root = tempfile::tempdir().unwrap();
+        std::fs::create_dir(root.path().join("dir")).unwrap();
+        std::fs::write(root.path().join("dir").join("file"), "data").unwrap();
+        let delete = request("DELETE", "/dir", &[]);
+        assert_eq!(
+            StatusCode::NO_CONTENT,
+            handle(&delete, &config(), root.path())
+                .await
+                .unwrap()
+                .status
+        );
+        assert_eq!(
+            StatusCode::NOT_FOUND,
+            handle(&delete, &config(), root.path())
+                .await
+                .unwrap()
+                .status
+        );
+        let delete_root = request("DELETE", "/", &[]);
+        assert_eq!(
+            StatusCode::FORBIDDEN,
+            handle(&delete_root, &config(), root.path())
+                .await
+                .unwrap()
+                .status
+        );
+    }
+}